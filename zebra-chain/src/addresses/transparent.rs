@@ -29,6 +29,15 @@ mod magics {
     }
 }
 
+/// The script opcodes used by P2PKH and P2SH output scripts.
+mod opcodes {
+    pub const OP_DUP: u8 = 0x76;
+    pub const OP_EQUAL: u8 = 0x87;
+    pub const OP_EQUALVERIFY: u8 = 0x88;
+    pub const OP_HASH160: u8 = 0xa9;
+    pub const OP_CHECKSIG: u8 = 0xac;
+}
+
 /// Transparent Zcash Addresses
 ///
 /// In Bitcoin a single byte is used for the version field identifying
@@ -202,6 +211,92 @@ impl TransparentAddress {
     }
 }
 
+impl TransparentAddress {
+    /// Returns the address paid by the output `script` on `network`.
+    ///
+    /// Returns `None` if the script is not a P2PKH or P2SH script. These are
+    /// the same outputs that `zcashd` attributes to addresses in its address
+    /// index.
+    pub fn from_output_script(script: &Script, network: Network) -> Option<Self> {
+        match ScriptKind::classify(script) {
+            ScriptKind::PayToPublicKeyHash { pub_key_hash } => Some(Self::PayToPublicKeyHash {
+                network,
+                pub_key_hash,
+            }),
+            ScriptKind::PayToScriptHash { script_hash } => Some(Self::PayToScriptHash {
+                network,
+                script_hash,
+            }),
+            ScriptKind::NonStandard => None,
+        }
+    }
+
+    /// Returns the standard output script that pays to this address.
+    pub fn output_script(&self) -> Script {
+        use self::opcodes::*;
+
+        let mut script = Vec::new();
+        match self {
+            TransparentAddress::PayToPublicKeyHash { pub_key_hash, .. } => {
+                script.extend_from_slice(&[OP_DUP, OP_HASH160, 20]);
+                script.extend_from_slice(pub_key_hash);
+                script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
+            }
+            TransparentAddress::PayToScriptHash { script_hash, .. } => {
+                script.extend_from_slice(&[OP_HASH160, 20]);
+                script.extend_from_slice(script_hash);
+                script.push(OP_EQUAL);
+            }
+        }
+        Script(script)
+    }
+}
+
+/// The kind of a transparent output script, for address attribution.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScriptKind {
+    /// `OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG`
+    PayToPublicKeyHash {
+        /// The 20-byte public key hash
+        pub_key_hash: [u8; 20],
+    },
+    /// `OP_HASH160 <script_hash> OP_EQUAL`
+    PayToScriptHash {
+        /// The 20-byte script hash
+        script_hash: [u8; 20],
+    },
+    /// Any other script
+    ///
+    /// These outputs are not attributed to an address, even if their script
+    /// contains a public key.
+    NonStandard,
+}
+
+impl ScriptKind {
+    /// Classify the output `script`.
+    ///
+    /// Scripts must match the standard templates exactly, including their
+    /// push opcodes, to be attributed to an address.
+    pub fn classify(script: &Script) -> Self {
+        use self::opcodes::*;
+
+        let mut hash = [0; 20];
+        match script.0.as_slice() {
+            [OP_DUP, OP_HASH160, 20, payload @ .., OP_EQUALVERIFY, OP_CHECKSIG]
+                if payload.len() == 20 =>
+            {
+                hash.copy_from_slice(payload);
+                ScriptKind::PayToPublicKeyHash { pub_key_hash: hash }
+            }
+            [OP_HASH160, 20, payload @ .., OP_EQUAL] if payload.len() == 20 => {
+                hash.copy_from_slice(payload);
+                ScriptKind::PayToScriptHash { script_hash: hash }
+            }
+            _ => ScriptKind::NonStandard,
+        }
+    }
+}
+
 #[cfg(test)]
 impl TransparentAddress {
     fn p2pkh_strategy() -> impl Strategy<Value = Self> {
@@ -280,6 +375,62 @@ mod tests {
         assert_eq!(format!("{}", t_addr), "t3Vz22vK5z2LcKEdg16Yv4FFneEL1zg9ojd");
     }
 
+    #[test]
+    fn output_script() {
+        let t_addr: TransparentAddress = "t3Vz22vK5z2LcKEdg16Yv4FFneEL1zg9ojd".parse().unwrap();
+        let script = t_addr.output_script();
+
+        assert_eq!(
+            hex::encode(&script.0),
+            "a9147d46a730d31f97b1930d3368a967c309bd4d136a87"
+        );
+        assert_eq!(
+            TransparentAddress::from_output_script(&script, Network::Mainnet),
+            Some(t_addr)
+        );
+    }
+
+    #[test]
+    fn nonstandard_scripts() {
+        use super::opcodes::*;
+
+        // Pay to public key outputs aren't attributed to the public key hash
+        let mut p2pk = vec![33];
+        p2pk.extend_from_slice(&[2; 33]);
+        p2pk.push(OP_CHECKSIG);
+
+        // Hashes must be pushed with a single byte length opcode
+        let mut long_p2sh = vec![OP_HASH160, 0x4c, 20];
+        long_p2sh.extend_from_slice(&[0; 20]);
+        long_p2sh.push(OP_EQUAL);
+
+        for script in vec![Vec::new(), p2pk, long_p2sh, vec![OP_HASH160, 0, OP_EQUAL]] {
+            assert_eq!(
+                ScriptKind::classify(&Script(script.clone())),
+                ScriptKind::NonStandard,
+                "{}",
+                hex::encode(&script)
+            );
+        }
+    }
+
+    #[test]
+    fn founders_reward_address() {
+        use crate::block::Block;
+
+        let block = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+            .expect("block test vector should deserialize");
+        let addresses: Vec<_> = block.transactions[0]
+            .outputs()
+            .filter_map(|output| {
+                TransparentAddress::from_output_script(&output.pk_script, Network::Mainnet)
+            })
+            .map(|t_addr| t_addr.to_string())
+            .collect();
+
+        assert!(addresses.contains(&"t3Vz22vK5z2LcKEdg16Yv4FFneEL1zg9ojd".to_string()));
+    }
+
     #[test]
     fn debug() {
         let t_addr: TransparentAddress = "t3Vz22vK5z2LcKEdg16Yv4FFneEL1zg9ojd".parse().unwrap();
@@ -305,4 +456,16 @@ proptest! {
 
         prop_assert_eq![taddr, taddr2];
     }
+
+    #[test]
+    fn transparent_address_script_roundtrip(taddr in any::<TransparentAddress>()) {
+        let network = match taddr {
+            TransparentAddress::PayToScriptHash { network, .. } => network,
+            TransparentAddress::PayToPublicKeyHash { network, .. } => network,
+        };
+
+        let taddr2 = TransparentAddress::from_output_script(&taddr.output_script(), network);
+
+        prop_assert_eq![Some(taddr), taddr2];
+    }
 }
@@ -9,12 +9,14 @@ mod serialize;
 mod tests;
 
 use serde::{Deserialize, Serialize};
-use std::{error, sync::Arc};
+use std::{error, io, sync::Arc};
 
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
-use crate::transaction::Transaction;
+use crate::merkle_tree::MerkleTreeRootHash;
+use crate::serialization::{Cached, SerializationError, ZcashDeserialize, ZcashSerialize};
+use crate::transaction::{Transaction, TransactionHash};
 use crate::types::BlockHeight;
 
 pub use hash::BlockHeaderHash;
@@ -33,6 +35,18 @@ pub struct Block {
     pub header: BlockHeader,
     /// The block transactions.
     pub transactions: Vec<Arc<Transaction>>,
+    /// The size of the serialized block, if it was deserialized.
+    ///
+    /// Use `Cached::default()` when constructing or modifying a block.
+    #[serde(skip)]
+    #[cfg_attr(test, proptest(value = "Cached::default()"))]
+    pub size: Cached<usize>,
+    /// The serialized block, if it was parsed with `Block::from_bytes`.
+    ///
+    /// Use `Cached::default()` when constructing or modifying a block.
+    #[serde(skip)]
+    #[cfg_attr(test, proptest(value = "Cached::default()"))]
+    pub bytes: Cached<Arc<[u8]>>,
 }
 
 /// The maximum size of a Zcash block, in bytes.
@@ -48,6 +62,31 @@ pub const MAX_BLOCK_BYTES: u64 = 2_000_000;
 type Error = Box<dyn error::Error + Send + Sync + 'static>;
 
 impl Block {
+    /// Deserialize a block from `bytes`, and keep a copy of its bytes, so the
+    /// block never has to be serialized again.
+    ///
+    /// Any bytes after the block are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SerializationError> {
+        let mut reader = io::Cursor::new(bytes);
+        let mut block = Block::zcash_deserialize(&mut reader)?;
+        let len = reader.position() as usize;
+        block.bytes = Cached::new(bytes[..len].into());
+
+        Ok(block)
+    }
+
+    /// Returns the size of the serialized block, in bytes.
+    ///
+    /// This is the size used for the `MAX_BLOCK_BYTES` limit. The size of a
+    /// deserialized block is recorded when it is parsed, so it isn't
+    /// serialized again.
+    pub fn serialized_size(&self) -> usize {
+        match self.size.get() {
+            Some(size) => *size,
+            None => self.zcash_serialized_size(),
+        }
+    }
+
     /// Return the block height reported in the coinbase transaction, if any.
     pub fn coinbase_height(&self) -> Option<BlockHeight> {
         use crate::transaction::TransparentInput;
@@ -89,6 +128,16 @@ impl Block {
     pub fn hash(&self) -> BlockHeaderHash {
         BlockHeaderHash::from(self)
     }
+
+    /// Compute the merkle root of the block's transactions.
+    ///
+    /// For valid blocks, this is the header's `merkle_root_hash`.
+    pub fn merkle_root(&self) -> MerkleTreeRootHash {
+        self.transactions
+            .iter()
+            .map(|tx| TransactionHash::from(tx.as_ref().clone()))
+            .collect()
+    }
 }
 
 impl<'a> From<&'a Block> for BlockHeaderHash {
@@ -45,8 +45,9 @@ pub struct BlockHeader {
     /// protocol. See ZIP-221 for details.
     // TODO:
     //   - replace with an unspecified HistoryRootHash type?
-    // Note that the NetworkUpgrade list is in zebra-consensus, so we can't
-    // parse this field into a HistoryRootHash enum in zebra-chain.
+    // Note that the block height is in the coinbase transaction, so we can't
+    // use the NetworkUpgrade list to parse this field into a HistoryRootHash
+    // enum when deserializing the header.
     pub final_sapling_root_hash: SaplingNoteTreeRootHash,
 
     /// The block timestamp is a Unix epoch time (UTC) when the miner
@@ -6,7 +6,9 @@ use crate::equihash_solution::EquihashSolution;
 use crate::merkle_tree::MerkleTreeRootHash;
 use crate::note_commitment_tree::SaplingNoteTreeRootHash;
 use crate::serialization::ZcashDeserializeInto;
-use crate::serialization::{ReadZcashExt, SerializationError, ZcashDeserialize, ZcashSerialize};
+use crate::serialization::{
+    Cached, CountingReader, ReadZcashExt, SerializationError, ZcashDeserialize, ZcashSerialize,
+};
 
 use super::Block;
 use super::BlockHeader;
@@ -90,10 +92,12 @@ impl ZcashSerialize for Block {
 impl ZcashDeserialize for Block {
     fn zcash_deserialize<R: io::Read>(reader: R) -> Result<Self, SerializationError> {
         // If the limit is reached, we'll get an UnexpectedEof error
-        let limited_reader = &mut reader.take(MAX_BLOCK_BYTES);
+        let limited_reader = &mut CountingReader::new(reader.take(MAX_BLOCK_BYTES));
         Ok(Block {
             header: limited_reader.zcash_deserialize_into()?,
             transactions: limited_reader.zcash_deserialize_into()?,
+            size: Cached::new(limited_reader.count()),
+            bytes: Cached::default(),
         })
     }
 }
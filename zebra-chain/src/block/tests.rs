@@ -1,7 +1,7 @@
 use super::*;
 
 use crate::equihash_solution::EquihashSolution;
-use crate::merkle_tree::MerkleTreeRootHash;
+use crate::merkle_tree::{MerklePath, MerkleTreeRootHash};
 use crate::note_commitment_tree::SaplingNoteTreeRootHash;
 use crate::serialization::{
     SerializationError, ZcashDeserialize, ZcashDeserializeInto, ZcashSerialize,
@@ -125,6 +125,58 @@ fn deserialize_block() {
         .expect("block test vector should deserialize");
 }
 
+#[test]
+fn merkle_root_matches_header() {
+    for bytes in &[
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_434873_BYTES[..],
+    ] {
+        let block = bytes
+            .zcash_deserialize_into::<Block>()
+            .expect("block test vector should deserialize");
+
+        assert_eq!(block.merkle_root(), block.header.merkle_root_hash);
+    }
+}
+
+#[test]
+fn merkle_paths_match_header() {
+    for bytes in &[
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_434873_BYTES[..],
+    ] {
+        let block = bytes
+            .zcash_deserialize_into::<Block>()
+            .expect("block test vector should deserialize");
+        let hashes: Vec<_> = block
+            .transactions
+            .iter()
+            .map(|tx| TransactionHash::from(tx.as_ref().clone()))
+            .collect();
+
+        for (index, hash) in hashes.iter().enumerate() {
+            let path = MerklePath::new(&hashes, index).expect("index is in range");
+            assert_eq!(path.root(*hash), block.header.merkle_root_hash);
+        }
+        assert_eq!(MerklePath::new(&hashes, hashes.len()), None);
+    }
+}
+
+proptest! {
+    #[test]
+    fn merkle_paths_match_root(hashes in prop::collection::vec(any::<TransactionHash>(), 1..20)) {
+        let root: MerkleTreeRootHash = hashes.iter().cloned().collect();
+
+        for (index, hash) in hashes.iter().enumerate() {
+            let path = MerklePath::new(&hashes, index).expect("index is in range");
+            prop_assert_eq!(path.root(*hash), root);
+        }
+    }
+}
+
 #[test]
 fn block_limits_multi_tx() {
     // Test multiple small transactions to fill a block max size
@@ -214,11 +266,14 @@ proptest! {
     #[test]
     fn block_roundtrip(block in any::<Block>()) {
         let bytes = block.zcash_serialize_to_vec()?;
+        prop_assert_eq![block.serialized_size(), bytes.len()];
         let bytes = &mut bytes.as_slice();
 
         // Check the block size limit
         if bytes.len() <= MAX_BLOCK_BYTES as _ {
-            let other_block = bytes.zcash_deserialize_into()?;
+            let other_block: Block = bytes.zcash_deserialize_into()?;
+            // The size is cached when the block is deserialized
+            prop_assert_eq![other_block.size.get(), Some(&block.serialized_size())];
 
             prop_assert_eq![block, other_block];
         } else {
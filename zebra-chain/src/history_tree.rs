@@ -0,0 +1,334 @@
+//! The chain history tree, from ZIP-221.
+//!
+//! After Heartwood activation, each block header commits to a Merkle Mountain
+//! Range (MMR) containing the previous blocks in the current network upgrade.
+//! Each leaf summarises a block, and each parent node summarises the blocks in
+//! its subtree. When a new network upgrade activates, the tree starts again,
+//! using the new consensus branch id.
+//!
+//! Zebra only stores the peaks of the MMR: the roots of its complete
+//! subtrees, from left to right. That is enough to append new blocks, and
+//! calculate the root of the tree.
+//!
+//! https://zips.z.cash/zip-0221
+#![allow(clippy::unit_arg)]
+
+#[cfg(test)]
+mod tests;
+
+use std::{fmt, io};
+
+use crate::{
+    parameters::ConsensusBranchId,
+    serialization::{
+        ReadZcashExt, SerializationError, WriteZcashExt, ZcashDeserialize, ZcashSerialize,
+    },
+};
+
+/// The personalization prefix for history tree node hashes.
+///
+/// The consensus branch id is appended, in little-endian order.
+const PERSONALIZATION_PREFIX: &[u8; 12] = b"ZcashHistory";
+
+/// The summary of a subtree of blocks, in a history tree.
+///
+/// Leaf nodes summarise a single block.
+#[derive(Clone, Eq, PartialEq)]
+pub struct NodeData {
+    /// The consensus branch id of the network upgrade that the blocks are in
+    ///
+    /// Not serialized, it is used to personalize node hashes instead.
+    pub consensus_branch_id: ConsensusBranchId,
+    /// The block hash for leaf nodes, or the hash of the child nodes for
+    /// parent nodes
+    pub subtree_commitment: [u8; 32],
+    /// The time of the first block in the subtree
+    pub start_time: u32,
+    /// The time of the last block in the subtree
+    pub end_time: u32,
+    /// The compact difficulty threshold of the first block in the subtree
+    pub start_target: u32,
+    /// The compact difficulty threshold of the last block in the subtree
+    pub end_target: u32,
+    /// The Sapling note commitment tree root after the first block in the
+    /// subtree
+    pub start_sapling_root: [u8; 32],
+    /// The Sapling note commitment tree root after the last block in the
+    /// subtree
+    pub end_sapling_root: [u8; 32],
+    /// The total work of the blocks in the subtree
+    ///
+    /// ZIP-221 uses a 256-bit integer, but the total work of all the blocks
+    /// in the chain fits in 128 bits.
+    pub subtree_total_work: u128,
+    /// The height of the first block in the subtree
+    pub start_height: u64,
+    /// The height of the last block in the subtree
+    pub end_height: u64,
+    /// The number of transactions with Sapling spends or outputs in the
+    /// subtree
+    pub sapling_tx: u64,
+}
+
+impl fmt::Debug for NodeData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NodeData")
+            .field("consensus_branch_id", &self.consensus_branch_id)
+            .field("subtree_commitment", &hex::encode(&self.subtree_commitment))
+            .field("start_height", &self.start_height)
+            .field("end_height", &self.end_height)
+            .field("subtree_total_work", &self.subtree_total_work)
+            .field("sapling_tx", &self.sapling_tx)
+            .finish()
+    }
+}
+
+impl NodeData {
+    /// Returns the parent node of the `left` and `right` subtrees.
+    ///
+    /// Fails if the nodes are in different network upgrades.
+    pub fn combine(left: &NodeData, right: &NodeData) -> Result<NodeData, Error> {
+        if left.consensus_branch_id != right.consensus_branch_id {
+            return Err(Error::BranchIdMismatch);
+        }
+
+        let mut children = Vec::new();
+        left.zcash_serialize(&mut children)
+            .expect("writing to a Vec never fails");
+        right
+            .zcash_serialize(&mut children)
+            .expect("writing to a Vec never fails");
+
+        Ok(NodeData {
+            consensus_branch_id: left.consensus_branch_id,
+            subtree_commitment: hash(left.consensus_branch_id, &children),
+            start_time: left.start_time,
+            end_time: right.end_time,
+            start_target: left.start_target,
+            end_target: right.end_target,
+            start_sapling_root: left.start_sapling_root,
+            end_sapling_root: right.end_sapling_root,
+            subtree_total_work: left
+                .subtree_total_work
+                .checked_add(right.subtree_total_work)
+                .ok_or(Error::WorkOverflow)?,
+            start_height: left.start_height,
+            end_height: right.end_height,
+            sapling_tx: left.sapling_tx + right.sapling_tx,
+        })
+    }
+
+    /// Returns the hash of this node.
+    ///
+    /// The hash of the root node is the `hashChainHistoryRoot` in block
+    /// headers.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut bytes = Vec::new();
+        self.zcash_serialize(&mut bytes)
+            .expect("writing to a Vec never fails");
+        hash(self.consensus_branch_id, &bytes)
+    }
+
+    /// Deserialize a node from `reader`, in the network upgrade with
+    /// `consensus_branch_id`.
+    pub fn zcash_deserialize_with_branch_id<R: io::Read>(
+        mut reader: R,
+        consensus_branch_id: ConsensusBranchId,
+    ) -> Result<Self, SerializationError> {
+        let subtree_commitment = reader.read_32_bytes()?;
+        let start_time = u32::from_le_bytes(reader.read_4_bytes()?);
+        let end_time = u32::from_le_bytes(reader.read_4_bytes()?);
+        let start_target = u32::from_le_bytes(reader.read_4_bytes()?);
+        let end_target = u32::from_le_bytes(reader.read_4_bytes()?);
+        let start_sapling_root = reader.read_32_bytes()?;
+        let end_sapling_root = reader.read_32_bytes()?;
+
+        let work = reader.read_32_bytes()?;
+        if work[16..].iter().any(|&byte| byte != 0) {
+            return Err(SerializationError::Parse(
+                "history tree node work is larger than 128 bits",
+            ));
+        }
+        let mut low_work = [0; 16];
+        low_work.copy_from_slice(&work[..16]);
+
+        Ok(NodeData {
+            consensus_branch_id,
+            subtree_commitment,
+            start_time,
+            end_time,
+            start_target,
+            end_target,
+            start_sapling_root,
+            end_sapling_root,
+            subtree_total_work: u128::from_le_bytes(low_work),
+            start_height: reader.read_compactsize()?,
+            end_height: reader.read_compactsize()?,
+            sapling_tx: reader.read_compactsize()?,
+        })
+    }
+}
+
+/// Serialize the node in the ZIP-221 format, which is used for hashing.
+///
+/// The consensus branch id is not included.
+impl ZcashSerialize for NodeData {
+    fn zcash_serialize<W: io::Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        writer.write_all(&self.subtree_commitment)?;
+        writer.write_all(&self.start_time.to_le_bytes())?;
+        writer.write_all(&self.end_time.to_le_bytes())?;
+        writer.write_all(&self.start_target.to_le_bytes())?;
+        writer.write_all(&self.end_target.to_le_bytes())?;
+        writer.write_all(&self.start_sapling_root)?;
+        writer.write_all(&self.end_sapling_root)?;
+        writer.write_all(&self.subtree_total_work.to_le_bytes())?;
+        writer.write_all(&[0; 16])?;
+        writer.write_compactsize(self.start_height)?;
+        writer.write_compactsize(self.end_height)?;
+        writer.write_compactsize(self.sapling_tx)?;
+        Ok(())
+    }
+}
+
+/// BLAKE2b-256("ZcashHistory" || consensus_branch_id, bytes)
+fn hash(consensus_branch_id: ConsensusBranchId, bytes: &[u8]) -> [u8; 32] {
+    let mut personalization = [0; 16];
+    personalization[..12].copy_from_slice(PERSONALIZATION_PREFIX);
+    personalization[12..].copy_from_slice(&u32::from(consensus_branch_id).to_le_bytes());
+
+    let hash = blake2b_simd::Params::new()
+        .hash_length(32)
+        .personal(&personalization)
+        .hash(bytes);
+
+    let mut output = [0; 32];
+    output.copy_from_slice(hash.as_bytes());
+    output
+}
+
+/// The history tree for the blocks in a network upgrade.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryTree {
+    /// The consensus branch id of the network upgrade
+    consensus_branch_id: ConsensusBranchId,
+    /// The number of blocks in the tree
+    size: u64,
+    /// The roots of the complete subtrees, from left to right
+    ///
+    /// There is a peak for each bit that is set in `size`. Larger subtrees
+    /// are on the left.
+    peaks: Vec<NodeData>,
+}
+
+impl HistoryTree {
+    /// Returns an empty history tree for the network upgrade with
+    /// `consensus_branch_id`.
+    pub fn new(consensus_branch_id: ConsensusBranchId) -> Self {
+        Self {
+            consensus_branch_id,
+            size: 0,
+            peaks: Vec::new(),
+        }
+    }
+
+    /// Returns the consensus branch id of the network upgrade for this tree.
+    pub fn consensus_branch_id(&self) -> ConsensusBranchId {
+        self.consensus_branch_id
+    }
+
+    /// Returns the number of blocks in the tree.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Append the summary of the next block, `leaf`, to the tree.
+    pub fn append(&mut self, leaf: NodeData) -> Result<(), Error> {
+        if leaf.consensus_branch_id != self.consensus_branch_id {
+            return Err(Error::BranchIdMismatch);
+        }
+        // Check the total work of the whole tree, so bagging the peaks can't
+        // overflow
+        self.peaks
+            .iter()
+            .try_fold(leaf.subtree_total_work, |total, peak| {
+                total.checked_add(peak.subtree_total_work)
+            })
+            .ok_or(Error::WorkOverflow)?;
+
+        // Merge the new leaf with the equal-sized subtrees on its left, like
+        // incrementing a binary counter
+        let mut node = leaf;
+        let mut level = 0;
+        while self.size & (1 << level) != 0 {
+            let left = self
+                .peaks
+                .pop()
+                .expect("there is a peak for each set bit in the size");
+            node = NodeData::combine(&left, &node)?;
+            level += 1;
+        }
+        self.peaks.push(node);
+        self.size += 1;
+
+        Ok(())
+    }
+
+    /// Returns the root node of the tree, or `None` if the tree is empty.
+    ///
+    /// The root node is calculated by bagging the peaks from left to right.
+    pub fn root(&self) -> Option<NodeData> {
+        let mut peaks = self.peaks.iter();
+        let first = peaks.next()?.clone();
+
+        Some(peaks.fold(first, |root, peak| {
+            NodeData::combine(&root, peak)
+                .expect("peaks have the same branch id, and the total work fits")
+        }))
+    }
+
+    /// Returns the hash of the root node, or all zeroes if the tree is empty.
+    ///
+    /// This is the `hashChainHistoryRoot` in the header of the next block.
+    pub fn hash(&self) -> [u8; 32] {
+        self.root().map(|root| root.hash()).unwrap_or([0; 32])
+    }
+}
+
+impl ZcashSerialize for HistoryTree {
+    fn zcash_serialize<W: io::Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        writer.write_all(&u32::from(self.consensus_branch_id).to_le_bytes())?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        for peak in &self.peaks {
+            peak.zcash_serialize(&mut writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl ZcashDeserialize for HistoryTree {
+    fn zcash_deserialize<R: io::Read>(mut reader: R) -> Result<Self, SerializationError> {
+        let consensus_branch_id = u32::from_le_bytes(reader.read_4_bytes()?).into();
+        let mut size = [0; 8];
+        reader.read_exact(&mut size)?;
+        let size = u64::from_le_bytes(size);
+
+        let peaks = (0..size.count_ones())
+            .map(|_| NodeData::zcash_deserialize_with_branch_id(&mut reader, consensus_branch_id))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            consensus_branch_id,
+            size,
+            peaks,
+        })
+    }
+}
+
+/// Errors that can be returned when updating a history tree
+#[derive(thiserror::Error, Debug, displaydoc::Display, Clone, PartialEq)]
+pub enum Error {
+    /// history tree nodes are from different network upgrades
+    BranchIdMismatch,
+    /// history tree work is larger than 128 bits
+    WorkOverflow,
+}
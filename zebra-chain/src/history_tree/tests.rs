@@ -0,0 +1,95 @@
+use super::*;
+
+use crate::{parameters::NetworkUpgrade, serialization::ZcashDeserializeInto};
+
+fn branch_id() -> ConsensusBranchId {
+    NetworkUpgrade::Heartwood
+        .branch_id()
+        .expect("Heartwood has a branch id")
+}
+
+/// Returns a leaf node for a block at `height`.
+fn leaf(height: u64) -> NodeData {
+    NodeData {
+        consensus_branch_id: branch_id(),
+        subtree_commitment: [height as u8; 32],
+        start_time: 1_600_000_000 + height as u32,
+        end_time: 1_600_000_000 + height as u32,
+        start_target: 0x1c01_0000,
+        end_target: 0x1c01_0000,
+        start_sapling_root: [0xf0 ^ height as u8; 32],
+        end_sapling_root: [0xf0 ^ height as u8; 32],
+        subtree_total_work: 1 << 40,
+        start_height: height,
+        end_height: height,
+        sapling_tx: height % 2,
+    }
+}
+
+fn combine(left: &NodeData, right: &NodeData) -> NodeData {
+    NodeData::combine(left, right).expect("nodes are compatible")
+}
+
+#[test]
+fn empty_tree_hash_is_zero() {
+    let tree = HistoryTree::new(branch_id());
+    assert_eq!(tree.root(), None);
+    assert_eq!(tree.hash(), [0; 32]);
+}
+
+#[test]
+fn peaks_are_bagged_left_to_right() -> Result<(), Error> {
+    let leaves: Vec<_> = (0..7).map(leaf).collect();
+    let mut tree = HistoryTree::new(branch_id());
+    for leaf in &leaves {
+        tree.append(leaf.clone())?;
+    }
+
+    let left = combine(
+        &combine(&leaves[0], &leaves[1]),
+        &combine(&leaves[2], &leaves[3]),
+    );
+    let middle = combine(&leaves[4], &leaves[5]);
+    let root = combine(&combine(&left, &middle), &leaves[6]);
+
+    assert_eq!(tree.size(), 7);
+    assert_eq!(tree.root(), Some(root.clone()));
+    assert_eq!(tree.hash(), root.hash());
+    assert_eq!(root.start_height, 0);
+    assert_eq!(root.end_height, 6);
+    assert_eq!(root.subtree_total_work, 7 << 40);
+    assert_eq!(root.sapling_tx, 3);
+    assert_eq!(root.start_sapling_root, leaves[0].start_sapling_root);
+    assert_eq!(root.end_sapling_root, leaves[6].end_sapling_root);
+
+    Ok(())
+}
+
+#[test]
+fn branch_ids_must_match() {
+    let mut tree = HistoryTree::new(branch_id());
+    let canopy_leaf = NodeData {
+        consensus_branch_id: NetworkUpgrade::Canopy
+            .branch_id()
+            .expect("Canopy has a branch id"),
+        ..leaf(0)
+    };
+
+    assert_eq!(tree.append(canopy_leaf), Err(Error::BranchIdMismatch));
+    assert_eq!(tree.size(), 0);
+}
+
+#[test]
+fn history_tree_roundtrip() -> Result<(), SerializationError> {
+    let mut tree = HistoryTree::new(branch_id());
+    for height in 0..13 {
+        tree.append(leaf(height)).expect("leaves are compatible");
+
+        let bytes = tree.zcash_serialize_to_vec()?;
+        let other: HistoryTree = bytes.zcash_deserialize_into()?;
+        assert_eq!(other, tree);
+        assert_eq!(other.hash(), tree.hash());
+    }
+
+    Ok(())
+}
@@ -9,17 +9,19 @@
 #[macro_use]
 extern crate serde;
 
-mod merkle_tree;
 mod serde_helpers;
 mod sha256d_writer;
 
 pub mod addresses;
 pub mod block;
 pub mod equihash_solution;
+pub mod history_tree;
 pub mod keys;
+pub mod merkle_tree;
 pub mod note_commitment_tree;
 pub mod notes;
 pub mod nullifier;
+pub mod parameters;
 pub mod proofs;
 pub mod serialization;
 pub mod transaction;
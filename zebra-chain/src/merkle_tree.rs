@@ -2,14 +2,17 @@
 //! node values.
 #![allow(clippy::unit_arg)]
 
-use std::{fmt, io};
+use std::{
+    fmt,
+    io::{self, Write},
+};
 
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
 use crate::serialization::{SerializationError, ZcashDeserialize, ZcashSerialize};
 use crate::sha256d_writer::Sha256dWriter;
-use crate::transaction::Transaction;
+use crate::transaction::{Transaction, TransactionHash};
 
 /// A binary hash tree of SHA256d (two rounds of SHA256) hashes for
 /// node values.
@@ -46,6 +49,130 @@ impl From<MerkleTree<Transaction>> for MerkleTreeRootHash {
     }
 }
 
+impl std::iter::FromIterator<TransactionHash> for MerkleTreeRootHash {
+    /// Computes the merkle root of a block's transaction hashes, in block
+    /// order.
+    ///
+    /// Each layer of the tree hashes pairs of nodes with SHA256d. If a layer
+    /// has an odd number of nodes, its last node is paired with itself. An
+    /// empty list of hashes has an all-zeroes root.
+    fn from_iter<I>(hashes: I) -> Self
+    where
+        I: IntoIterator<Item = TransactionHash>,
+    {
+        let mut layer: Vec<[u8; 32]> = hashes.into_iter().map(|hash| hash.0).collect();
+        if layer.is_empty() {
+            return Self([0; 32]);
+        }
+
+        while layer.len() > 1 {
+            layer = next_layer(&layer);
+        }
+
+        Self(layer[0])
+    }
+}
+
+/// Returns the parent layer of `layer`, in a merkle tree of transaction
+/// hashes.
+///
+/// If `layer` has an odd number of nodes, its last node is paired with
+/// itself.
+fn next_layer(layer: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    layer
+        .chunks(2)
+        .map(|pair| {
+            let left = &pair[0];
+            let right = pair.last().expect("chunks are not empty");
+            hash_pair(left, right)
+        })
+        .collect()
+}
+
+/// SHA256d(left || right)
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hash_writer = Sha256dWriter::default();
+    hash_writer
+        .write_all(left)
+        .expect("Sha256dWriter is infallible");
+    hash_writer
+        .write_all(right)
+        .expect("Sha256dWriter is infallible");
+    hash_writer.finish()
+}
+
+/// A merkle branch, which proves that a transaction is in a block.
+///
+/// Light clients can check that a transaction is in a block by calculating
+/// the root of the path, and comparing it with the `merkle_root_hash` in the
+/// block header.
+#[derive(Clone, Eq, PartialEq)]
+pub struct MerklePath {
+    /// The index of the transaction in the block
+    pub index: u32,
+    /// The sibling of each node on the path from the transaction to the
+    /// root, starting with the sibling of the transaction
+    pub branch: Vec<[u8; 32]>,
+}
+
+impl MerklePath {
+    /// Returns the merkle path for the transaction at `index` in `hashes`,
+    /// which are a block's transaction hashes, in block order.
+    ///
+    /// Returns `None` if `index` is out of range.
+    pub fn new(hashes: &[TransactionHash], index: usize) -> Option<Self> {
+        if index >= hashes.len() {
+            return None;
+        }
+
+        let mut layer: Vec<[u8; 32]> = hashes.iter().map(|hash| hash.0).collect();
+        let mut position = index;
+        let mut branch = Vec::new();
+        while layer.len() > 1 {
+            // The last node in an odd layer is its own sibling
+            let sibling = layer.get(position ^ 1).unwrap_or(&layer[position]);
+            branch.push(*sibling);
+
+            layer = next_layer(&layer);
+            position /= 2;
+        }
+
+        Some(Self {
+            index: index as u32,
+            branch,
+        })
+    }
+
+    /// Returns the root of the merkle tree that contains the transaction
+    /// with `hash` at this path.
+    pub fn root(&self, hash: TransactionHash) -> MerkleTreeRootHash {
+        let mut node = hash.0;
+        let mut position = self.index;
+        for sibling in &self.branch {
+            node = if position & 1 == 0 {
+                hash_pair(&node, sibling)
+            } else {
+                hash_pair(sibling, &node)
+            };
+            position /= 2;
+        }
+
+        MerkleTreeRootHash(node)
+    }
+}
+
+impl fmt::Debug for MerklePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MerklePath")
+            .field("index", &self.index)
+            .field(
+                "branch",
+                &self.branch.iter().map(hex::encode).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
 impl fmt::Debug for MerkleTreeRootHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_tuple("MerkleTreeRootHash")
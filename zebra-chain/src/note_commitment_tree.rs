@@ -9,22 +9,202 @@
 //! append-only.
 //!
 //! A root of a note commitment tree is associated with each treestate.
+//!
+//! Zebra only stores the frontier of each tree: the roots of the complete
+//! subtrees on the path to the next leaf. That is enough to append new note
+//! commitments, and calculate the root of the tree.
 #![allow(clippy::unit_arg)]
 
-use std::{fmt, io};
+mod sapling;
+mod sprout;
+
+#[cfg(test)]
+mod tests;
+
+use std::{fmt, io, marker::PhantomData};
 
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
-use crate::serialization::{SerializationError, ZcashDeserialize, ZcashSerialize};
+use crate::serialization::{ReadZcashExt, SerializationError, ZcashDeserialize, ZcashSerialize};
+
+pub use sapling::Sapling;
+pub use sprout::Sprout;
+
+/// The hash function and depth of a note commitment tree.
+pub trait NoteTreeHasher: Copy + fmt::Debug + Eq {
+    /// The depth of the tree
+    const DEPTH: usize;
+
+    /// The leaf value for unused leaves, _Uncommitted_ in the spec
+    fn uncommitted() -> [u8; 32];
 
-// XXX: Depending on if we implement SproutNoteCommitmentTree or
-// similar, it may be worth it to define a NoteCommitmentTree trait.
+    /// Hash the `left` and `right` child nodes at `level` to get their
+    /// parent node. Leaves are at level 0.
+    fn combine(level: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32];
+
+    /// The roots of empty subtrees, by level, from the uncommitted leaf at
+    /// level 0 to the empty tree root at level `DEPTH`.
+    fn empty_roots() -> &'static [[u8; 32]];
+}
+
+/// Returns the empty subtree roots for `H`, by level.
+///
+/// Used to implement `NoteTreeHasher::empty_roots`, which should only call
+/// this function once.
+fn calculate_empty_roots<H: NoteTreeHasher>() -> Vec<[u8; 32]> {
+    let mut roots = vec![H::uncommitted()];
+    for level in 0..H::DEPTH {
+        let root = H::combine(level, &roots[level], &roots[level]);
+        roots.push(root);
+    }
+    roots
+}
+
+/// An incremental note commitment tree, using the hash function `H`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NoteCommitmentTree<H> {
+    /// The number of leaves in the tree
+    size: u64,
+    /// The roots of the complete subtrees on the left of the next leaf, by
+    /// level
+    ///
+    /// There is a subtree root at `level` if bit `level` of `size` is set.
+    /// If the tree is full, the root of the tree is at level `DEPTH`.
+    frontier: Vec<Option<[u8; 32]>>,
+    _hasher: PhantomData<H>,
+}
+
+/// Sprout Note Commitment Tree
+pub type SproutNoteCommitmentTree = NoteCommitmentTree<Sprout>;
 
 /// Sapling Note Commitment Tree
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
-#[cfg_attr(test, derive(Arbitrary))]
-pub struct SaplingNoteCommitmentTree;
+pub type SaplingNoteCommitmentTree = NoteCommitmentTree<Sapling>;
+
+impl<H: NoteTreeHasher> Default for NoteCommitmentTree<H> {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            frontier: vec![None; H::DEPTH + 1],
+            _hasher: PhantomData,
+        }
+    }
+}
+
+impl<H: NoteTreeHasher> NoteCommitmentTree<H> {
+    /// The maximum number of leaves in the tree.
+    pub const MAX_SIZE: u64 = 1 << H::DEPTH;
+
+    /// Returns the number of note commitments in the tree.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Append `commitment` to the tree.
+    pub fn append(&mut self, commitment: [u8; 32]) -> Result<(), Error> {
+        self.append_with_subtree(commitment, H::DEPTH).map(|_| ())
+    }
+
+    /// Append `commitment` to the tree.
+    ///
+    /// If `commitment` is the last leaf of a subtree whose root is at
+    /// `subtree_level`, returns the root of that subtree.
+    pub fn append_with_subtree(
+        &mut self,
+        commitment: [u8; 32],
+        subtree_level: usize,
+    ) -> Result<Option<[u8; 32]>, Error> {
+        if self.size >= Self::MAX_SIZE {
+            return Err(Error::Full);
+        }
+
+        // Merge the new leaf with the complete subtrees on its left, like
+        // incrementing a binary counter
+        let mut node = commitment;
+        let mut level = 0;
+        let mut subtree_root = None;
+        loop {
+            if level == subtree_level {
+                subtree_root = Some(node);
+            }
+            match self.frontier[level].take() {
+                Some(left) => node = H::combine(level, &left, &node),
+                None => break,
+            }
+            level += 1;
+        }
+        self.frontier[level] = Some(node);
+        self.size += 1;
+
+        Ok(subtree_root)
+    }
+
+    /// Returns the root of the tree.
+    pub fn root(&self) -> [u8; 32] {
+        if let Some(root) = self.frontier[H::DEPTH] {
+            return root;
+        }
+        let empty_roots = H::empty_roots();
+
+        // The root of the rightmost subtree at each level, or `None` if it
+        // is empty
+        let mut node = None;
+        for (level, left) in self.frontier[..H::DEPTH].iter().enumerate() {
+            node = match (left, node) {
+                (Some(left), right) => Some(H::combine(
+                    level,
+                    left,
+                    right.as_ref().unwrap_or(&empty_roots[level]),
+                )),
+                (None, Some(node)) => Some(H::combine(level, &node, &empty_roots[level])),
+                (None, None) => None,
+            };
+        }
+
+        node.unwrap_or(empty_roots[H::DEPTH])
+    }
+}
+
+impl<H: NoteTreeHasher> ZcashSerialize for NoteCommitmentTree<H> {
+    fn zcash_serialize<W: io::Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        writer.write_all(&self.size.to_le_bytes())?;
+        for node in self.frontier.iter().flatten() {
+            writer.write_all(node)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: NoteTreeHasher> ZcashDeserialize for NoteCommitmentTree<H> {
+    fn zcash_deserialize<R: io::Read>(mut reader: R) -> Result<Self, SerializationError> {
+        let mut size = [0; 8];
+        reader.read_exact(&mut size)?;
+        let size = u64::from_le_bytes(size);
+        if size > Self::MAX_SIZE {
+            return Err(SerializationError::Parse(
+                "note commitment tree size is larger than the maximum size",
+            ));
+        }
+
+        let mut tree = Self {
+            size,
+            ..Self::default()
+        };
+        for (level, node) in tree.frontier.iter_mut().enumerate() {
+            if size & (1 << level) != 0 {
+                *node = Some(reader.read_32_bytes()?);
+            }
+        }
+        Ok(tree)
+    }
+}
+
+/// Errors that can be returned when updating a note commitment tree
+#[derive(thiserror::Error, Debug, displaydoc::Display, Clone, PartialEq)]
+pub enum Error {
+    /// note commitment tree is full
+    Full,
+}
 
 /// Sapling note commitment tree root node hash.
 ///
@@ -44,37 +224,8 @@ impl fmt::Debug for SaplingNoteTreeRootHash {
     }
 }
 
-impl From<SaplingNoteCommitmentTree> for SaplingNoteTreeRootHash {
-    fn from(_tree: SaplingNoteCommitmentTree) -> Self {
-        // TODO: The Sapling note commitment tree requires a Pedersen
-        // hash function, not SHA256.
-
-        // let mut hash_writer = Sha256dWriter::default();
-        // sapling_note_commitment_tree
-        //     .zcash_serialize(&mut hash_writer)
-        //     .expect("A Sapling note commitment tree must serialize.");
-        // Self(hash_writer.finish())
-
-        unimplemented!();
-    }
-}
-
-impl SaplingNoteCommitmentTree {
-    /// Get the Jubjub-based Pedersen hash of root node of this merkle
-    /// tree of commitment notes.
-    pub fn hash(&self) -> [u8; 32] {
-        unimplemented!();
-    }
-}
-
-impl ZcashSerialize for SaplingNoteCommitmentTree {
-    fn zcash_serialize<W: io::Write>(&self, _writer: W) -> Result<(), io::Error> {
-        unimplemented!();
-    }
-}
-
-impl ZcashDeserialize for SaplingNoteCommitmentTree {
-    fn zcash_deserialize<R: io::Read>(_reader: R) -> Result<Self, SerializationError> {
-        unimplemented!();
+impl From<&SaplingNoteCommitmentTree> for SaplingNoteTreeRootHash {
+    fn from(tree: &SaplingNoteCommitmentTree) -> Self {
+        Self(tree.root())
     }
 }
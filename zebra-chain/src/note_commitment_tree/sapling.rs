@@ -0,0 +1,130 @@
+//! The Sapling note commitment tree hash function.
+
+use lazy_static::lazy_static;
+
+use super::{calculate_empty_roots, NoteTreeHasher};
+use crate::keys::sapling::find_group_hash;
+
+/// The number of 3-bit chunks in each Pedersen hash segment, _c_ in the
+/// spec.
+const CHUNKS_PER_SEGMENT: usize = 63;
+
+/// The number of bits of each node that are hashed, _l_MerkleSapling_ in the
+/// spec.
+const NODE_BITS: usize = 255;
+
+/// The number of Pedersen hash generators needed to hash two tree nodes.
+///
+/// Each node hash contains a 6-bit level and two 255-bit nodes.
+const GENERATOR_COUNT: usize =
+    (6 + 2 * NODE_BITS + 3 * CHUNKS_PER_SEGMENT - 1) / (3 * CHUNKS_PER_SEGMENT);
+
+lazy_static! {
+    /// The Pedersen hash generators for the "Zcash_PH" personalization.
+    static ref GENERATORS: Vec<jubjub::ExtendedPoint> = (0..GENERATOR_COUNT as u32)
+        .map(|i| find_group_hash(*b"Zcash_PH", &i.to_le_bytes()))
+        .collect();
+
+    static ref EMPTY_ROOTS: Vec<[u8; 32]> = calculate_empty_roots::<Sapling>();
+}
+
+/// Marker type for the Sapling note commitment tree, which has depth 32, and
+/// uses a Pedersen hash on the Jubjub curve.
+///
+/// https://zips.z.cash/protocol/protocol.pdf#merklecrh
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sapling {}
+
+impl NoteTreeHasher for Sapling {
+    const DEPTH: usize = 32;
+
+    /// Uncommitted^Sapling := I2LEBSP_255(1)
+    fn uncommitted() -> [u8; 32] {
+        let mut leaf = [0; 32];
+        leaf[0] = 1;
+        leaf
+    }
+
+    /// MerkleCRH^Sapling(layer, left, right) :=
+    ///   PedersenHash("Zcash_PH", l || left || right)
+    ///
+    /// where l is the 6-bit level of the child nodes, counting up from the
+    /// leaves, and left and right are the low 255 bits of each node.
+    fn combine(level: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let level_bits = (0..6).map(|i| (level >> i) & 1 == 1);
+        let bits = level_bits
+            .chain(le_bits(left).take(NODE_BITS))
+            .chain(le_bits(right).take(NODE_BITS));
+
+        let point = pedersen_hash_to_point(bits);
+        jubjub::AffinePoint::from(point).get_u().to_bytes()
+    }
+
+    fn empty_roots() -> &'static [[u8; 32]] {
+        &EMPTY_ROOTS
+    }
+}
+
+/// Returns the bits of `bytes`, in little-endian order.
+fn le_bits(bytes: &[u8; 32]) -> impl Iterator<Item = bool> + '_ {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1 == 1))
+}
+
+/// PedersenHashToPoint("Zcash_PH", bits)
+///
+/// The bits are padded with zeroes to a multiple of 3 bits, then split into
+/// segments of up to 63 3-bit chunks. Each segment is encoded as a scalar,
+/// and multiplied by its generator.
+///
+/// https://zips.z.cash/protocol/protocol.pdf#concretepedersenhash
+fn pedersen_hash_to_point(bits: impl Iterator<Item = bool>) -> jubjub::ExtendedPoint {
+    let mut bits = bits.peekable();
+    let mut result = jubjub::ExtendedPoint::identity();
+
+    for generator in GENERATORS.iter() {
+        if bits.peek().is_none() {
+            break;
+        }
+
+        // The encoding of this segment, <M_i> in the spec
+        let mut acc = jubjub::Fr::zero();
+        // 2^(4 * (j - 1)) for the current chunk j
+        let mut cur = jubjub::Fr::one();
+        for _ in 0..CHUNKS_PER_SEGMENT {
+            // The last chunk is padded with zero bits
+            let s0 = bits.next().unwrap_or(false);
+            let s1 = bits.next().unwrap_or(false);
+            let s2 = bits.next().unwrap_or(false);
+
+            // enc(m_j) = (1 - 2 * s2) * (1 + s0 + 2 * s1)
+            let mut tmp = cur;
+            if s0 {
+                tmp += cur;
+            }
+            cur = cur.double();
+            if s1 {
+                tmp += cur;
+            }
+            if s2 {
+                tmp = -tmp;
+            }
+            acc += tmp;
+            cur = cur.double().double().double();
+
+            if bits.peek().is_none() {
+                break;
+            }
+        }
+
+        result += generator * acc;
+    }
+
+    assert!(
+        bits.peek().is_none(),
+        "Sapling tree node hashes have a fixed number of bits"
+    );
+
+    result
+}
@@ -0,0 +1,56 @@
+//! The Sprout note commitment tree hash function.
+
+use lazy_static::lazy_static;
+
+use super::{calculate_empty_roots, NoteTreeHasher};
+
+/// The SHA-256 initial hash value.
+const SHA256_IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+lazy_static! {
+    static ref EMPTY_ROOTS: Vec<[u8; 32]> = calculate_empty_roots::<Sprout>();
+}
+
+/// Marker type for the Sprout note commitment tree, which has depth 29, and
+/// uses the SHA-256 compression function.
+///
+/// https://zips.z.cash/protocol/protocol.pdf#merklecrh
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sprout {}
+
+impl NoteTreeHasher for Sprout {
+    const DEPTH: usize = 29;
+
+    fn uncommitted() -> [u8; 32] {
+        [0; 32]
+    }
+
+    /// MerkleCRH^Sprout(layer, left, right) := SHA256Compress(left || right)
+    fn combine(_level: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut block = [0; 64];
+        block[..32].copy_from_slice(left);
+        block[32..].copy_from_slice(right);
+
+        let mut state = SHA256_IV;
+        sha2::compress256(&mut state, &block);
+
+        let mut hash = [0; 32];
+        for (bytes, word) in hash.chunks_mut(4).zip(state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    fn empty_roots() -> &'static [[u8; 32]] {
+        &EMPTY_ROOTS
+    }
+}
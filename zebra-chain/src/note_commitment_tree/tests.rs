@@ -0,0 +1,145 @@
+use super::*;
+
+use crate::serialization::ZcashDeserializeInto;
+
+use lazy_static::lazy_static;
+use proptest::prelude::*;
+
+/// A small tree, so the naive root calculation is fast, and the tree can
+/// be filled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Small {}
+
+lazy_static! {
+    static ref SMALL_EMPTY_ROOTS: Vec<[u8; 32]> = calculate_empty_roots::<Small>();
+}
+
+impl NoteTreeHasher for Small {
+    const DEPTH: usize = 4;
+
+    fn uncommitted() -> [u8; 32] {
+        Sprout::uncommitted()
+    }
+
+    fn combine(level: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        Sprout::combine(level, left, right)
+    }
+
+    fn empty_roots() -> &'static [[u8; 32]] {
+        &SMALL_EMPTY_ROOTS
+    }
+}
+
+/// Calculate the root of a tree containing `leaves`, by hashing every
+/// level of the tree.
+fn naive_root<H: NoteTreeHasher>(leaves: &[[u8; 32]]) -> [u8; 32] {
+    let mut nodes = leaves.to_vec();
+    nodes.resize(1 << H::DEPTH, H::uncommitted());
+    for level in 0..H::DEPTH {
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| H::combine(level, &pair[0], &pair[1]))
+            .collect();
+    }
+    nodes[0]
+}
+
+fn hex_root(hex: &str) -> [u8; 32] {
+    let mut root = [0; 32];
+    hex::decode_to_slice(hex, &mut root[..]).expect("test vectors are valid hex");
+    root
+}
+
+#[test]
+fn sprout_empty_roots() {
+    let roots = Sprout::empty_roots();
+    assert_eq!(roots.len(), Sprout::DEPTH + 1);
+    assert_eq!(roots[0], [0; 32]);
+    assert_eq!(
+        roots[1],
+        hex_root("da5698be17b9b46962335799779fbeca8ce5d491c0d26243bafef9ea1837a9d8")
+    );
+    assert_eq!(
+        roots[29],
+        hex_root("d7c612c817793191a1e68652121876d6b3bde40f4fa52bc314145ce6e5cdd259")
+    );
+    assert_eq!(SproutNoteCommitmentTree::default().root(), roots[29]);
+}
+
+#[test]
+fn sapling_empty_roots() {
+    let roots = Sapling::empty_roots();
+    assert_eq!(roots.len(), Sapling::DEPTH + 1);
+    assert_eq!(
+        roots[0],
+        hex_root("0100000000000000000000000000000000000000000000000000000000000000")
+    );
+    assert_eq!(
+        roots[1],
+        hex_root("817de36ab2d57feb077634bca77819c8e0bd298c04f6fed0e6a83cc1356ca155")
+    );
+    assert_eq!(
+        roots[2],
+        hex_root("ffe9fc03f18b176c998806439ff0bb8ad193afdb27b2ccbc88856916dd804e34")
+    );
+    assert_eq!(
+        roots[32],
+        hex_root("fbc2f4300c01f0b7820d00e3347c8da4ee614674376cbc45359daa54f9b5493e")
+    );
+    assert_eq!(SaplingNoteCommitmentTree::default().root(), roots[32]);
+}
+
+#[test]
+fn small_tree_fills_up() {
+    let mut tree = NoteCommitmentTree::<Small>::default();
+    let leaves: Vec<[u8; 32]> = (0..16u8).map(|i| [i + 1; 32]).collect();
+
+    for (count, leaf) in leaves.iter().enumerate() {
+        assert_eq!(tree.root(), naive_root::<Small>(&leaves[..count]));
+        tree.append(*leaf).expect("tree is not full");
+    }
+
+    assert_eq!(tree.size(), NoteCommitmentTree::<Small>::MAX_SIZE);
+    assert_eq!(tree.root(), naive_root::<Small>(&leaves));
+    assert_eq!(tree.append([0xff; 32]), Err(Error::Full));
+    assert_eq!(tree.root(), naive_root::<Small>(&leaves));
+}
+
+#[test]
+fn small_tree_completes_subtrees() {
+    let mut tree = NoteCommitmentTree::<Small>::default();
+    let leaves: Vec<[u8; 32]> = (0..16u8).map(|i| [i + 1; 32]).collect();
+
+    for (index, leaf) in leaves.iter().enumerate() {
+        let subtree_root = tree
+            .append_with_subtree(*leaf, 2)
+            .expect("tree is not full");
+
+        if index % 4 == 3 {
+            let subtree = &leaves[index - 3..=index];
+            let left = Small::combine(0, &subtree[0], &subtree[1]);
+            let right = Small::combine(0, &subtree[2], &subtree[3]);
+            assert_eq!(subtree_root, Some(Small::combine(1, &left, &right)));
+        } else {
+            assert_eq!(subtree_root, None);
+        }
+    }
+
+    assert_eq!(tree.root(), naive_root::<Small>(&leaves));
+}
+
+proptest! {
+    #[test]
+    fn small_tree_roundtrip(leaves in prop::collection::vec(any::<[u8; 32]>(), 0..=16)) {
+        let mut tree = NoteCommitmentTree::<Small>::default();
+        for leaf in &leaves {
+            tree.append(*leaf).expect("tree is not full");
+        }
+        prop_assert_eq![tree.root(), naive_root::<Small>(&leaves)];
+
+        let bytes = tree.zcash_serialize_to_vec()?;
+        prop_assert_eq![bytes.len(), 8 + 32 * tree.size().count_ones() as usize];
+        let other: NoteCommitmentTree<Small> = bytes.zcash_deserialize_into()?;
+        prop_assert_eq![other, tree];
+    }
+}
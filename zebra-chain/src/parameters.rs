@@ -0,0 +1,12 @@
+//! The network upgrade parameters for each Zcash network.
+//!
+//! Network upgrades are defined in zebra-chain, so chain data structures and
+//! the state can use activation heights and consensus branch ids. The other
+//! consensus parameters are in zebra-consensus.
+
+pub mod network_upgrade;
+
+pub use network_upgrade::*;
+
+#[cfg(test)]
+mod tests;
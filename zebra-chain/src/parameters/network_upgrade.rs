@@ -5,8 +5,8 @@ use NetworkUpgrade::*;
 use std::collections::{BTreeMap, HashMap};
 use std::ops::Bound::*;
 
-use zebra_chain::types::BlockHeight;
-use zebra_chain::{Network, Network::*};
+use crate::types::BlockHeight;
+use crate::{Network, Network::*};
 
 /// A Zcash network upgrade.
 ///
@@ -170,3 +170,15 @@ impl ConsensusBranchId {
         NetworkUpgrade::current(network, height).branch_id()
     }
 }
+
+impl From<u32> for ConsensusBranchId {
+    fn from(branch_id: u32) -> Self {
+        ConsensusBranchId(branch_id)
+    }
+}
+
+impl From<ConsensusBranchId> for u32 {
+    fn from(branch_id: ConsensusBranchId) -> Self {
+        branch_id.0
+    }
+}
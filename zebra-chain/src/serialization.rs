@@ -6,8 +6,8 @@
 //! `ReadZcashExt`, extension traits for `io::Read` and `io::Write` with utility functions
 //! for reading and writing data (e.g., the Bitcoin variable-integer format).
 
-use std::io;
 use std::net::{IpAddr, SocketAddr};
+use std::{fmt, io};
 
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use thiserror::Error;
@@ -57,6 +57,98 @@ pub trait ZcashSerialize: Sized {
         self.zcash_serialize(&mut data)?;
         Ok(data)
     }
+
+    /// Returns the size of the canonical serialization of `self`, in bytes.
+    ///
+    /// The serialized bytes are counted, rather than collected into a buffer.
+    fn zcash_serialized_size(&self) -> usize {
+        let mut counter = SizeCounter(0);
+        self.zcash_serialize(&mut counter)
+            .expect("counting serialized bytes never fails");
+        counter.0
+    }
+}
+
+/// A writer that discards its data, counting the number of bytes written.
+struct SizeCounter(usize);
+
+impl io::Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader that counts the number of bytes read from it.
+pub(crate) struct CountingReader<R> {
+    reader: R,
+    count: usize,
+}
+
+impl<R> CountingReader<R> {
+    /// Count the bytes read from `reader`.
+    pub(crate) fn new(reader: R) -> Self {
+        CountingReader { reader, count: 0 }
+    }
+
+    /// Returns the number of bytes read so far.
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.count += len;
+        Ok(len)
+    }
+}
+
+/// A value derived from the serialization of a block or transaction, which
+/// is kept with it, so it doesn't have to be computed again.
+///
+/// Cached values are ignored when blocks and transactions are compared,
+/// logged, or serialized with serde. Code that modifies a block or
+/// transaction after deserializing it must reset its cached values to
+/// `Cached::default()`.
+#[derive(Clone)]
+pub struct Cached<T>(Option<T>);
+
+impl<T> Cached<T> {
+    /// Cache `value`.
+    pub fn new(value: T) -> Self {
+        Cached(Some(value))
+    }
+
+    /// Returns the cached value, if there is one.
+    pub fn get(&self) -> Option<&T> {
+        self.0.as_ref()
+    }
+}
+
+impl<T> Default for Cached<T> {
+    fn default() -> Self {
+        Cached(None)
+    }
+}
+
+impl<T> PartialEq for Cached<T> {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl<T> Eq for Cached<T> {}
+
+impl<T> fmt::Debug for Cached<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Cached" } else { "Empty" })
+    }
 }
 
 /// Consensus-critical serialization for Zcash.
@@ -4,7 +4,7 @@ use std::sync::Arc;
 
 use crate::{
     block::{Block, BlockHeader, MAX_BLOCK_BYTES},
-    serialization::{ZcashDeserialize, ZcashSerialize},
+    serialization::{Cached, ZcashDeserialize, ZcashSerialize},
     transaction::{Transaction, TransparentInput, TransparentOutput},
     types::LockTime,
 };
@@ -64,6 +64,8 @@ fn multi_transaction_block(oversized: bool) -> Block {
     Block {
         header,
         transactions,
+        size: Cached::default(),
+        bytes: Cached::default(),
     }
 }
 
@@ -120,6 +122,7 @@ fn single_transaction_block(oversized: bool) -> Block {
         inputs,
         outputs,
         lock_time,
+        size: Cached::default(),
     };
 
     // Put the big transaction into a block
@@ -127,5 +130,7 @@ fn single_transaction_block(oversized: bool) -> Block {
     Block {
         header,
         transactions,
+        size: Cached::default(),
+        bytes: Cached::default(),
     }
 }
@@ -17,6 +17,7 @@ pub use shielded_data::{Output, ShieldedData, Spend};
 pub use transparent::{CoinbaseData, OutPoint, TransparentInput, TransparentOutput};
 
 use crate::proofs::{Bctv14Proof, Groth16Proof};
+use crate::serialization::{Cached, ZcashSerialize};
 use crate::types::{amount::Amount, BlockHeight, LockTime};
 
 /// A Zcash transaction.
@@ -43,6 +44,12 @@ pub enum Transaction {
         /// The earliest time or block height that this transaction can be added to the
         /// chain.
         lock_time: LockTime,
+        /// The size of the serialized transaction, if it was deserialized.
+        ///
+        /// Use `Cached::default()` when constructing or modifying a
+        /// transaction.
+        #[serde(skip)]
+        size: Cached<usize>,
     },
     /// A Sprout transaction (`version = 2`).
     V2 {
@@ -55,6 +62,12 @@ pub enum Transaction {
         lock_time: LockTime,
         /// The JoinSplit data for this transaction, if any.
         joinsplit_data: Option<JoinSplitData<Bctv14Proof>>,
+        /// The size of the serialized transaction, if it was deserialized.
+        ///
+        /// Use `Cached::default()` when constructing or modifying a
+        /// transaction.
+        #[serde(skip)]
+        size: Cached<usize>,
     },
     /// An Overwinter transaction (`version = 3`).
     V3 {
@@ -69,6 +82,12 @@ pub enum Transaction {
         expiry_height: BlockHeight,
         /// The JoinSplit data for this transaction, if any.
         joinsplit_data: Option<JoinSplitData<Bctv14Proof>>,
+        /// The size of the serialized transaction, if it was deserialized.
+        ///
+        /// Use `Cached::default()` when constructing or modifying a
+        /// transaction.
+        #[serde(skip)]
+        size: Cached<usize>,
     },
     /// A Sapling transaction (`version = 4`).
     V4 {
@@ -87,10 +106,34 @@ pub enum Transaction {
         shielded_data: Option<ShieldedData>,
         /// The JoinSplit data for this transaction, if any.
         joinsplit_data: Option<JoinSplitData<Groth16Proof>>,
+        /// The size of the serialized transaction, if it was deserialized.
+        ///
+        /// Use `Cached::default()` when constructing or modifying a
+        /// transaction.
+        #[serde(skip)]
+        size: Cached<usize>,
     },
 }
 
 impl Transaction {
+    /// Returns the size of the serialized transaction, in bytes.
+    ///
+    /// The size of a deserialized transaction is recorded when it is parsed,
+    /// so it isn't serialized again.
+    pub fn serialized_size(&self) -> usize {
+        let size = match self {
+            Transaction::V1 { size, .. }
+            | Transaction::V2 { size, .. }
+            | Transaction::V3 { size, .. }
+            | Transaction::V4 { size, .. } => size,
+        };
+
+        match size.get() {
+            Some(size) => *size,
+            None => self.zcash_serialized_size(),
+        }
+    }
+
     /// Iterate over the transparent inputs of this transaction, if any.
     pub fn inputs(&self) -> impl Iterator<Item = &TransparentInput> {
         match self {
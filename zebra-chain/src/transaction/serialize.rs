@@ -11,7 +11,8 @@ use std::{
 use crate::notes;
 use crate::proofs::ZkSnarkProof;
 use crate::serialization::{
-    ReadZcashExt, SerializationError, WriteZcashExt, ZcashDeserialize, ZcashSerialize,
+    Cached, CountingReader, ReadZcashExt, SerializationError, WriteZcashExt, ZcashDeserialize,
+    ZcashSerialize,
 };
 use crate::types::Script;
 
@@ -105,7 +106,7 @@ fn parse_coinbase_height(
     }
 }
 
-fn coinbase_height_len(height: BlockHeight) -> usize {
+pub(super) fn coinbase_height_len(height: BlockHeight) -> usize {
     // We can't write this as a match statement on stable until exclusive range
     // guards are stabilized.
     if let 0 = height.0 {
@@ -386,6 +387,7 @@ impl ZcashSerialize for Transaction {
                 inputs,
                 outputs,
                 lock_time,
+                size: _,
             } => {
                 writer.write_u32::<LittleEndian>(1)?;
                 inputs.zcash_serialize(&mut writer)?;
@@ -397,6 +399,7 @@ impl ZcashSerialize for Transaction {
                 outputs,
                 lock_time,
                 joinsplit_data,
+                size: _,
             } => {
                 writer.write_u32::<LittleEndian>(2)?;
                 inputs.zcash_serialize(&mut writer)?;
@@ -414,6 +417,7 @@ impl ZcashSerialize for Transaction {
                 lock_time,
                 expiry_height,
                 joinsplit_data,
+                size: _,
             } => {
                 // Write version 3 and set the fOverwintered bit.
                 writer.write_u32::<LittleEndian>(3 | (1 << 31))?;
@@ -436,6 +440,7 @@ impl ZcashSerialize for Transaction {
                 value_balance,
                 shielded_data,
                 joinsplit_data,
+                size: _,
             } => {
                 // Write version 4 and set the fOverwintered bit.
                 writer.write_u32::<LittleEndian>(4 | (1 << 31))?;
@@ -488,7 +493,9 @@ impl ZcashSerialize for Transaction {
 }
 
 impl ZcashDeserialize for Transaction {
-    fn zcash_deserialize<R: io::Read>(mut reader: R) -> Result<Self, SerializationError> {
+    fn zcash_deserialize<R: io::Read>(reader: R) -> Result<Self, SerializationError> {
+        // Count the bytes read, so the serialized size is cached
+        let mut reader = CountingReader::new(reader);
         let (version, overwintered) = {
             const LOW_31_BITS: u32 = (1 << 31) - 1;
             let header = reader.read_u32::<LittleEndian>()?;
@@ -501,6 +508,7 @@ impl ZcashDeserialize for Transaction {
                 inputs: Vec::zcash_deserialize(&mut reader)?,
                 outputs: Vec::zcash_deserialize(&mut reader)?,
                 lock_time: LockTime::zcash_deserialize(&mut reader)?,
+                size: Cached::new(reader.count()),
             }),
             (2, false) => {
                 // Version 2 transactions use Sprout-on-BCTV14.
@@ -510,6 +518,7 @@ impl ZcashDeserialize for Transaction {
                     outputs: Vec::zcash_deserialize(&mut reader)?,
                     lock_time: LockTime::zcash_deserialize(&mut reader)?,
                     joinsplit_data: OptV2JSD::zcash_deserialize(&mut reader)?,
+                    size: Cached::new(reader.count()),
                 })
             }
             (3, true) => {
@@ -527,6 +536,7 @@ impl ZcashDeserialize for Transaction {
                     lock_time: LockTime::zcash_deserialize(&mut reader)?,
                     expiry_height: BlockHeight(reader.read_u32::<LittleEndian>()?),
                     joinsplit_data: OptV3JSD::zcash_deserialize(&mut reader)?,
+                    size: Cached::new(reader.count()),
                 })
             }
             (4, true) => {
@@ -583,6 +593,7 @@ impl ZcashDeserialize for Transaction {
                     value_balance,
                     shielded_data,
                     joinsplit_data,
+                    size: Cached::new(reader.count()),
                 })
             }
             (_, _) => Err(SerializationError::Parse("bad tx header")),
@@ -1,7 +1,7 @@
 use proptest::{arbitrary::any, collection::vec, option, prelude::*};
 
 use crate::{
-    serialization::{ZcashDeserialize, ZcashDeserializeInto, ZcashSerialize},
+    serialization::{Cached, ZcashDeserialize, ZcashDeserializeInto, ZcashSerialize},
     types::LockTime,
 };
 
@@ -20,6 +20,7 @@ impl Transaction {
                 inputs,
                 outputs,
                 lock_time,
+                size: Cached::default(),
             })
             .boxed()
     }
@@ -37,6 +38,7 @@ impl Transaction {
                     outputs,
                     lock_time,
                     joinsplit_data,
+                    size: Cached::default(),
                 },
             )
             .boxed()
@@ -57,6 +59,7 @@ impl Transaction {
                     lock_time,
                     expiry_height,
                     joinsplit_data,
+                    size: Cached::default(),
                 },
             )
             .boxed()
@@ -89,6 +92,7 @@ impl Transaction {
                     value_balance,
                     shielded_data,
                     joinsplit_data,
+                    size: Cached::default(),
                 },
             )
             .boxed()
@@ -111,7 +115,12 @@ proptest! {
     #[test]
     fn transaction_roundtrip(tx in any::<Transaction>()) {
         let data = tx.zcash_serialize_to_vec().expect("tx should serialize");
-        let tx2 = data.zcash_deserialize_into().expect("randomized tx should deserialize");
+        prop_assert_eq![tx.serialized_size(), data.len()];
+        let tx2: Transaction = data
+            .zcash_deserialize_into()
+            .expect("randomized tx should deserialize");
+        // The size is cached when the transaction is deserialized
+        prop_assert_eq![tx2.serialized_size(), data.len()];
 
         prop_assert_eq![tx, tx2];
     }
@@ -14,15 +14,39 @@ use super::TransactionHash;
 /// Arbitrary data inserted by miners into a coinbase transaction.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct CoinbaseData(
-    /// Invariant: this vec, together with the coinbase height, must be less than
+    /// Invariant: this vec, together with the coinbase height, must be at most
     /// 100 bytes. We enforce this by only constructing CoinbaseData fields by
-    /// parsing blocks with 100-byte data fields. When we implement block
-    /// creation, we should provide a constructor for the coinbase data field
-    /// that restricts it to 95 = 100 -1 -4 bytes (safe for any block height up
-    /// to 500_000_000).
+    /// parsing blocks with 100-byte data fields, or using `CoinbaseData::new`,
+    /// which checks the length.
     pub(super) Vec<u8>,
 );
 
+impl CoinbaseData {
+    /// The minimum length of a coinbase script, including the height.
+    pub const MIN_SCRIPT_LEN: usize = 2;
+
+    /// The maximum length of a coinbase script, including the height.
+    pub const MAX_SCRIPT_LEN: usize = 100;
+
+    /// Returns the coinbase data for a block at `height`, if `data` and the
+    /// encoded height are a valid coinbase script length.
+    pub fn new(height: BlockHeight, data: Vec<u8>) -> Option<Self> {
+        let len = Self::script_len(height, data.len());
+
+        if (Self::MIN_SCRIPT_LEN..=Self::MAX_SCRIPT_LEN).contains(&len) {
+            Some(CoinbaseData(data))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the length of the coinbase script for a block at `height`,
+    /// with `data_len` bytes of coinbase data.
+    pub fn script_len(height: BlockHeight, data_len: usize) -> usize {
+        super::serialize::coinbase_height_len(height) + data_len
+    }
+}
+
 impl AsRef<[u8]> for CoinbaseData {
     fn as_ref(&self) -> &[u8] {
         self.0.as_ref()
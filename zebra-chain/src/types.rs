@@ -11,6 +11,7 @@ use std::{
 };
 
 pub mod amount;
+pub mod value_pool;
 
 /// A u32 which represents a block height value.
 ///
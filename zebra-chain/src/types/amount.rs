@@ -1,8 +1,9 @@
 //! Module of types for working with validated zatoshi Amounts
 use std::{
     convert::{TryFrom, TryInto},
+    iter::Sum,
     marker::PhantomData,
-    ops::RangeInclusive,
+    ops::{Neg, RangeInclusive},
 };
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -23,6 +24,16 @@ impl<C> Amount<C> {
     }
 }
 
+impl<C> Amount<C>
+where
+    C: AmountConstraint,
+{
+    /// Returns a zero amount, which is valid under every constraint
+    pub fn zero() -> Self {
+        Self(0, PhantomData)
+    }
+}
+
 impl<C> std::ops::Add<Amount<C>> for Amount<C>
 where
     C: AmountConstraint,
@@ -30,7 +41,7 @@ where
     type Output = Result<Amount<C>>;
 
     fn add(self, rhs: Amount<C>) -> Self::Output {
-        let value = self.0 + rhs.0;
+        let value = self.0.checked_add(rhs.0).ok_or(Error::Overflow)?;
         value.try_into()
     }
 }
@@ -76,7 +87,7 @@ where
     type Output = Result<Amount<C>>;
 
     fn sub(self, rhs: Amount<C>) -> Self::Output {
-        let value = self.0 - rhs.0;
+        let value = self.0.checked_sub(rhs.0).ok_or(Error::Overflow)?;
         value.try_into()
     }
 }
@@ -115,6 +126,39 @@ where
     }
 }
 
+impl Neg for Amount<NegativeAllowed> {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        // The valid range is symmetric, so negation is always valid
+        Self(-self.0, PhantomData)
+    }
+}
+
+/// Sums amounts, checking the total against the constraint.
+///
+/// Only the total has to be valid, so partial sums can temporarily be out of
+/// range.
+impl<C> Sum<Amount<C>> for Result<Amount<C>>
+where
+    C: AmountConstraint,
+{
+    fn sum<I: Iterator<Item = Amount<C>>>(mut iter: I) -> Self {
+        let total = iter.try_fold(0i64, |total, amount| total.checked_add(amount.0));
+        total.ok_or(Error::Overflow)?.try_into()
+    }
+}
+
+impl<'amt, C> Sum<&'amt Amount<C>> for Result<Amount<C>>
+where
+    Amount<C>: Copy,
+    C: AmountConstraint + 'amt,
+{
+    fn sum<I: Iterator<Item = &'amt Amount<C>>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
 impl<C> From<Amount<C>> for i64 {
     fn from(amount: Amount<C>) -> Self {
         amount.0
@@ -178,6 +222,8 @@ pub enum Error {
         value: u64,
         source: std::num::TryFromIntError,
     },
+    /// i64 overflow in zatoshi Amount arithmetic
+    Overflow,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -387,6 +433,29 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn sum_checks_total() -> Result<()> {
+        zebra_test::init();
+        let max = Amount::<NonNegative>::try_from(MAX_MONEY)?;
+        let one = Amount::<NonNegative>::try_from(1)?;
+
+        let total: Result<Amount<NonNegative>, Error> = vec![one, one, one].iter().sum();
+        assert_eq!(total?, Amount::try_from(3)?);
+
+        let total: Result<Amount<NonNegative>, Error> = vec![max, one].into_iter().sum();
+        total.expect_err("sum above MAX_MONEY should be rejected");
+
+        // Partial sums can be out of range, as long as the total is valid
+        let max: Amount = max.constrain()?;
+        let total: Result<Amount, Error> = vec![max, max, -max].into_iter().sum();
+        assert_eq!(total?, max);
+
+        let empty: Result<Amount, Error> = Vec::new().into_iter().sum();
+        assert_eq!(empty?, Amount::zero());
+
+        Ok(())
+    }
+
     #[test]
     fn deserialize_checks_bounds() -> Result<()> {
         let big = MAX_MONEY * 2;
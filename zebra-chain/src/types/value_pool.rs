@@ -0,0 +1,183 @@
+//! Pool-tagged zatoshi amounts, for tracking the chain value pools.
+//!
+//! A [`PoolValue`] is an [`Amount`] tagged with the value pool it belongs to,
+//! so values from different pools can't be added together by mistake.
+use std::{fmt, marker::PhantomData};
+
+use super::amount::{self, Amount, AmountConstraint, NegativeAllowed, NonNegative};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A chain value pool.
+pub trait ValuePool: Copy + fmt::Debug + Eq {
+    /// The name of the pool, used in errors
+    const NAME: &'static str;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Marker type for the transparent value pool, which contains the value of
+/// unspent transparent outputs
+pub enum Transparent {}
+
+impl ValuePool for Transparent {
+    const NAME: &'static str = "transparent";
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Marker type for the Sprout shielded value pool
+pub enum Sprout {}
+
+impl ValuePool for Sprout {
+    const NAME: &'static str = "Sprout";
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Marker type for the Sapling shielded value pool
+pub enum Sapling {}
+
+impl ValuePool for Sapling {
+    const NAME: &'static str = "Sapling";
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Marker type for the Orchard shielded value pool
+pub enum Orchard {}
+
+impl ValuePool for Orchard {
+    const NAME: &'static str = "Orchard";
+}
+
+/// An amount of zatoshis in the value pool `P`.
+///
+/// Pool balances use the default `NonNegative` constraint. Changes to a pool,
+/// and running totals that can temporarily be negative, use
+/// `NegativeAllowed`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PoolValue<P, C = NonNegative>(Amount<C>, PhantomData<P>);
+
+impl<P, C> PoolValue<P, C>
+where
+    P: ValuePool,
+    C: AmountConstraint,
+{
+    /// Tag `amount` as a value in the pool `P`
+    pub fn new(amount: Amount<C>) -> Self {
+        Self(amount, PhantomData)
+    }
+
+    /// Returns a zero value in the pool `P`
+    pub fn zero() -> Self {
+        Self::new(Amount::zero())
+    }
+
+    /// Returns the untagged amount
+    pub fn amount(self) -> Amount<C> {
+        self.0
+    }
+
+    /// Convert this value to a different constraint, if it satisfies the new
+    /// constraint
+    pub fn constrain<C2>(self) -> Result<PoolValue<P, C2>>
+    where
+        C2: AmountConstraint,
+    {
+        self.0
+            .constrain()
+            .map(PoolValue::new)
+            .map_err(Error::new::<P>)
+    }
+}
+
+impl<P, C> std::ops::Add<PoolValue<P, C>> for PoolValue<P, C>
+where
+    P: ValuePool,
+    C: AmountConstraint,
+{
+    type Output = Result<PoolValue<P, C>>;
+
+    fn add(self, rhs: PoolValue<P, C>) -> Self::Output {
+        (self.0 + rhs.0)
+            .map(PoolValue::new)
+            .map_err(Error::new::<P>)
+    }
+}
+
+impl<P, C> std::ops::Sub<PoolValue<P, C>> for PoolValue<P, C>
+where
+    P: ValuePool,
+    C: AmountConstraint,
+{
+    type Output = Result<PoolValue<P, C>>;
+
+    fn sub(self, rhs: PoolValue<P, C>) -> Self::Output {
+        (self.0 - rhs.0)
+            .map(PoolValue::new)
+            .map_err(Error::new::<P>)
+    }
+}
+
+impl<P> std::ops::Neg for PoolValue<P, NegativeAllowed>
+where
+    P: ValuePool,
+{
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self::new(-self.0)
+    }
+}
+
+#[derive(thiserror::Error, Debug, displaydoc::Display, Clone, PartialEq)]
+#[allow(missing_docs)]
+/// Errors that can be returned by `PoolValue` arithmetic
+pub enum Error {
+    /// invalid {pool} value pool amount: {source}
+    Pool {
+        pool: &'static str,
+        source: amount::Error,
+    },
+}
+
+impl Error {
+    fn new<P: ValuePool>(source: amount::Error) -> Self {
+        Error::Pool {
+            pool: P::NAME,
+            source,
+        }
+    }
+
+    /// Returns the name of the value pool that caused this error
+    pub fn pool(&self) -> &'static str {
+        match self {
+            Error::Pool { pool, .. } => *pool,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use color_eyre::eyre::Result;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn pool_values_are_checked() -> Result<()> {
+        zebra_test::init();
+        let one = PoolValue::<Sapling>::new(Amount::try_from(1)?);
+        let two = PoolValue::<Sapling>::new(Amount::try_from(2)?);
+
+        assert_eq!((one + one)?, two);
+        let error = (one - two).expect_err("pool balances can't be negative");
+        assert_eq!(error.pool(), "Sapling");
+
+        // Running totals can be negative, then checked when they are done
+        let change: PoolValue<Sapling, NegativeAllowed> = one.constrain()?;
+        let total = (change - two.constrain()?)?;
+        assert_eq!(total, -change);
+        total
+            .constrain::<NonNegative>()
+            .expect_err("negative totals aren't valid pool balances");
+
+        Ok(())
+    }
+}
@@ -46,6 +46,36 @@ where
 // TODO(jlusby): Error = Report ?
 type Error = Box<dyn error::Error + Send + Sync + 'static>;
 
+/// The version of the block verification rules.
+///
+/// Blocks that fail verification are recorded in the state with this version.
+/// Increment it when a block rule is fixed or relaxed, so the recorded
+/// failures are ignored, and those blocks are verified again.
+pub const VERIFIER_VERSION: u32 = 1;
+
+/// A block broke a consensus rule, and will always break it.
+///
+/// The chain verifier records these failures in the state, so the block is
+/// rejected without being verified again if it is downloaded again.
+///
+/// Blocks that are too far in the future can become valid later, so they
+/// don't cause this error. Neither do invalid Equihash solutions: they are
+/// cheap to create, and cheap to check again.
+#[derive(Clone, Debug, thiserror::Error, Eq, PartialEq)]
+#[error("{rule}")]
+pub struct InvalidBlock {
+    /// The consensus rule that the block broke
+    pub rule: String,
+}
+
+impl InvalidBlock {
+    fn new(rule: impl ToString) -> Self {
+        Self {
+            rule: rule.to_string(),
+        }
+    }
+}
+
 /// The BlockVerifier service implementation.
 ///
 /// The state service is only used for contextual verification.
@@ -81,21 +111,23 @@ where
             let now = Utc::now();
             block.header.is_time_valid_at(now)?;
             block.header.is_equihash_solution_valid()?;
-            block.is_coinbase_first()?;
+            block.is_coinbase_first().map_err(InvalidBlock::new)?;
 
             // These checks only apply to generated blocks. We check the block
             // height for parsed blocks when we deserialize them.
             let height = block
                 .coinbase_height()
-                .ok_or("Invalid block: missing block height")?;
+                .ok_or_else(|| InvalidBlock::new("Invalid block: missing block height"))?;
             if height > BlockHeight::MAX {
-                Err("Invalid block height: greater than the maximum height.")?;
+                Err(InvalidBlock::new(
+                    "Invalid block height: greater than the maximum height.",
+                ))?;
             }
 
             // As a temporary solution for chain gaps, wait for the previous block,
             // and check its height.
             // TODO:
-            //   - Add a previous block height and hash constraint to the AddBlock request,
+            //   - Add a previous block height and hash constraint to the CommitBlock request,
             //     so that we can verify in parallel, then check constraints before committing
             //
             // Skip contextual checks for the genesis block
@@ -111,7 +143,9 @@ where
 
                 let previous_height = previous_block.coinbase_height().unwrap();
                 if height.0 != previous_height.0 + 1 {
-                    Err("Invalid block height: must be 1 more than the previous block height.")?;
+                    Err(InvalidBlock::new(
+                        "Invalid block height: must be 1 more than the previous block height.",
+                    ))?;
                 }
             }
 
@@ -171,7 +205,7 @@ where
                 // Busy-waiting is only a temporary solution to waiting for blocks.
                 // TODO:
                 //   - Get an AwaitBlock future from the state
-                //   - Replace with AddBlock constraints
+                //   - Replace with CommitBlock constraints
                 None => {
                     tracing::debug!(?height, ?hash, "Waiting for state to have block");
                     time::delay_for(Duration::from_secs(2)).await
@@ -7,7 +7,7 @@ use color_eyre::eyre::{eyre, Report};
 
 use zebra_chain::block::Block;
 use zebra_chain::block::BlockHeader;
-use zebra_chain::serialization::ZcashDeserialize;
+use zebra_chain::serialization::{Cached, ZcashDeserialize};
 use zebra_chain::transaction::Transaction;
 
 #[tokio::test]
@@ -135,6 +135,8 @@ async fn coinbase() -> Result<(), Report> {
     let block = Block {
         header,
         transactions: Vec::new(),
+        size: Cached::default(),
+        bytes: Cached::default(),
     };
 
     // Error: no coinbase transaction in block
@@ -152,6 +154,8 @@ async fn coinbase() -> Result<(), Report> {
     let block = Block {
         header,
         transactions,
+        size: Cached::default(),
+        bytes: Cached::default(),
     };
 
     // Error: no coinbase transaction in block
@@ -172,13 +176,16 @@ async fn coinbase() -> Result<(), Report> {
 
     // Add another coinbase transaction to block
     block.transactions.push(coinbase_transaction);
+    block.size = Cached::default();
     assert_eq!(block.transactions.len(), 2);
 
     // Error: coinbase input found in additional transaction
-    ready_verifier_service
+    let error = ready_verifier_service
         .call(Arc::new(block))
         .await
         .expect_err("fail with coinbase input found in additional transaction");
+    // The failure is recorded by the chain verifier
+    assert!(error.downcast_ref::<InvalidBlock>().is_some());
 
     Ok(())
 }
@@ -14,6 +14,7 @@
 #[cfg(test)]
 mod tests;
 
+use crate::block::{InvalidBlock, VERIFIER_VERSION};
 use crate::checkpoint::CheckpointVerifier;
 
 use futures_util::FutureExt;
@@ -24,12 +25,13 @@ use std::{
     sync::Arc,
     task::{Context, Poll},
 };
-use tower::{buffer::Buffer, Service, ServiceExt};
+use tower::{buffer::Buffer, layer::Layer, Service, ServiceExt};
 use tracing_futures::Instrument;
 
 use zebra_chain::block::{Block, BlockHeaderHash};
 use zebra_chain::types::BlockHeight;
 use zebra_chain::Network;
+use zebra_state::layers::LayerConfig;
 
 /// The maximum expected gap between blocks.
 ///
@@ -91,10 +93,11 @@ where
         let mut state_service = self.state_service.clone();
         let max_checkpoint_height = self.max_checkpoint_height;
 
+        let hash = BlockHeaderHash::from(block.as_ref());
         let span = tracing::debug_span!(
             "block_verify",
             height = ?block.coinbase_height(),
-            hash = ?BlockHeaderHash::from(block.as_ref())
+            ?hash
         );
         let height = block.coinbase_height();
 
@@ -117,15 +120,20 @@ where
             // TODO(teor): for post-sapling checkpoint blocks, allow callers
             //             to use BlockVerifier, CheckpointVerifier, or both.
 
+            if let Some(rule) = failed_block_rule(&mut state_service, hash).await? {
+                tracing::debug!(%rule, "block previously failed verification");
+                metrics::counter!("chain.failed_block.rejected.count", 1);
+                Err(InvalidBlock { rule })?;
+            }
+
             // Call a verifier based on the block height and checkpoints.
-            match height {
-                Some(height) if (height <= max_checkpoint_height) => {
-                    checkpoint_verifier
-                        .ready_and()
-                        .await?
-                        .call(block.clone())
-                        .await?
-                }
+            let verified = match height {
+                Some(height) if (height <= max_checkpoint_height) => checkpoint_verifier
+                    .ready_and()
+                    .await?
+                    .call(block.clone())
+                    .await
+                    .map(|_| true),
                 _ => {
                     // Temporary trace, for identifying early high blocks.
                     // We think the downloader or sync service should reject these blocks
@@ -137,22 +145,49 @@ where
                         .ready_and()
                         .await?
                         .call(block.clone())
-                        .await?
+                        .await
+                        .map(|_| false)
+                }
+            };
+            let is_finalized = match verified {
+                Ok(is_finalized) => is_finalized,
+                Err(error) => {
+                    if let Some(invalid) = error.downcast_ref::<InvalidBlock>() {
+                        record_failed_block(&mut state_service, hash, invalid.rule.clone()).await;
+                    }
+                    return Err(error);
                 }
             };
 
             // TODO(teor):
             //   - handle chain reorgs
-            //   - adjust state_service "unique block height" conditions
 
-            let add_block = state_service
-                .ready_and()
-                .await?
-                .call(zebra_state::Request::AddBlock { block });
+            // Use the bytes the block was downloaded as, or serialize the
+            // block here, so the state's single writer task doesn't have to
+            let block = match block.bytes.get() {
+                Some(bytes) => zebra_state::SemanticallyVerifiedBlock::with_bytes(
+                    block.clone(),
+                    bytes.clone(),
+                )?,
+                None => zebra_state::SemanticallyVerifiedBlock::new(block)?,
+            };
 
-            match add_block.await? {
-                zebra_state::Response::Added { hash } => Ok(hash),
-                _ => Err("adding block to zebra-state failed".into()),
+            // Checkpointed blocks are final, so they skip the state's
+            // contextual checks
+            let request = if is_finalized {
+                zebra_state::Request::CommitFinalizedBlock { block }
+            } else {
+                zebra_state::Request::CommitBlock { block }
+            };
+            let commit_block = state_service.ready_and().await?.call(request);
+
+            match commit_block.await? {
+                zebra_state::Response::Committed { hash } => Ok(hash),
+                zebra_state::Response::AlreadyCommitted { hash } => {
+                    tracing::debug!(?hash, "block was already committed");
+                    Ok(hash)
+                }
+                _ => Err("committing block to zebra-state failed".into()),
             }
         }
         .instrument(span)
@@ -160,6 +195,50 @@ where
     }
 }
 
+/// Returns the consensus rule broken by the block with `hash`, if `state`
+/// has recorded a failure for this verifier version.
+async fn failed_block_rule<S>(state: &mut S, hash: BlockHeaderHash) -> Result<Option<String>, Error>
+where
+    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = Error>,
+{
+    let request = zebra_state::Request::FailedBlock {
+        hash,
+        verifier_version: VERIFIER_VERSION,
+    };
+
+    match state.ready_and().await?.call(request).await? {
+        zebra_state::Response::FailedBlock(rule) => Ok(rule),
+        _ => unreachable!("FailedBlock request can only result in Response::FailedBlock"),
+    }
+}
+
+/// Record that the block with `hash` broke `rule` in `state`.
+///
+/// The record only speeds up later rejections of the block, so errors are
+/// logged, rather than returned.
+async fn record_failed_block<S>(state: &mut S, hash: BlockHeaderHash, rule: String)
+where
+    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = Error>,
+{
+    let request = zebra_state::Request::RecordFailedBlock {
+        hash,
+        verifier_version: VERIFIER_VERSION,
+        rule,
+    };
+
+    let result = match state.ready_and().await {
+        Ok(state) => state.call(request).await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        tracing::warn!(
+            ?error,
+            ?hash,
+            "failed to record a block that failed verification"
+        );
+    }
+}
+
 /// Return a chain verification service, using `network` and `state_service`.
 ///
 /// Gets the initial tip from the state service, and uses it to create a block
@@ -189,6 +268,33 @@ where
         + Clone
         + 'static,
     S::Future: Send + 'static,
+{
+    init_with_layers(network, state_service, &LayerConfig::default()).await
+}
+
+/// Return a chain verification service, using `network` and `state_service`,
+/// wrapped in the layers in `layers`.
+///
+/// The layers are named `"verifier"` in metrics and tracing spans. See
+/// [`init`] for details.
+pub async fn init_with_layers<S>(
+    network: Network,
+    state_service: S,
+    layers: &LayerConfig,
+) -> impl Service<
+    Arc<Block>,
+    Response = BlockHeaderHash,
+    Error = Error,
+    Future = impl Future<Output = Result<BlockHeaderHash, Error>>,
+> + Send
+       + Clone
+       + 'static
+where
+    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = Error>
+        + Send
+        + Clone
+        + 'static,
+    S::Future: Send + 'static,
 {
     let initial_tip = zebra_state::initial_tip(state_service.clone())
         .await
@@ -205,7 +311,7 @@ where
     let block_verifier = crate::block::init(state_service.clone());
     let checkpoint_verifier = CheckpointVerifier::new(network, initial_tip);
 
-    init_from_verifiers(block_verifier, checkpoint_verifier, state_service)
+    layered_from_verifiers(block_verifier, checkpoint_verifier, state_service, layers)
 }
 
 /// Return a chain verification service, using the provided verifier and state
@@ -243,6 +349,38 @@ where
         + Clone
         + 'static,
     S::Future: Send + 'static,
+{
+    layered_from_verifiers(
+        block_verifier,
+        checkpoint_verifier,
+        state_service,
+        &LayerConfig::default(),
+    )
+}
+
+/// Return a chain verification service, using the provided verifier and state
+/// services, wrapped in the layers in `layers`.
+fn layered_from_verifiers<BV, S>(
+    block_verifier: BV,
+    checkpoint_verifier: CheckpointVerifier,
+    state_service: S,
+    layers: &LayerConfig,
+) -> impl Service<
+    Arc<Block>,
+    Response = BlockHeaderHash,
+    Error = Error,
+    Future = impl Future<Output = Result<BlockHeaderHash, Error>>,
+> + Send
+       + Clone
+       + 'static
+where
+    BV: Service<Arc<Block>, Response = BlockHeaderHash, Error = Error> + Send + Clone + 'static,
+    BV::Future: Send + 'static,
+    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = Error>
+        + Send
+        + Clone
+        + 'static,
+    S::Future: Send + 'static,
 {
     let max_checkpoint_height = checkpoint_verifier.list().max_height();
     tracing::debug!(
@@ -253,17 +391,14 @@ where
     // Wrap the checkpoint verifier in a buffer, so we can share it
     let checkpoint_verifier = Buffer::new(checkpoint_verifier, 1);
 
-    Buffer::new(
-        ChainVerifier {
-            block_verifier,
-            checkpoint_verifier,
-            max_checkpoint_height,
-            state_service,
-            // We haven't actually got the genesis block yet, but that's ok,
-            // because this field is only used for debugging unexpected high
-            // blocks.
-            last_block_height: BlockHeight(0),
-        },
-        1,
-    )
+    layers.layers("verifier").layer(ChainVerifier {
+        block_verifier,
+        checkpoint_verifier,
+        max_checkpoint_height,
+        state_service,
+        // We haven't actually got the genesis block yet, but that's ok,
+        // because this field is only used for debugging unexpected high
+        // blocks.
+        last_block_height: BlockHeight(0),
+    })
 }
@@ -13,7 +13,7 @@ use tower::{Service, ServiceExt};
 use tracing_futures::Instrument;
 
 use zebra_chain::block::{Block, BlockHeader};
-use zebra_chain::serialization::ZcashDeserialize;
+use zebra_chain::serialization::{Cached, ZcashDeserialize};
 use zebra_chain::Network::{self, *};
 
 /// The timeout we apply to each verify future during testing.
@@ -36,6 +36,8 @@ pub fn block_no_transactions() -> Block {
     Block {
         header: BlockHeader::zcash_deserialize(&zebra_test::vectors::DUMMY_HEADER[..]).unwrap(),
         transactions: Vec::new(),
+        size: Cached::default(),
+        bytes: Cached::default(),
     }
 }
 
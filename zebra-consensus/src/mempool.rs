@@ -9,6 +9,48 @@
 //!
 //! The mempool is provided via a `tower::Service`, to support backpressure and batch
 //! verification.
+//!
+//! Before transactions are added to the mempool, they are checked against the
+//! node's configurable `RelayPolicy`.
+//!
+//! Block template dry runs select mempool transactions for a template, and
+//! record why each transaction was included or excluded.
+//!
+//! Coinbase transactions for templates are built with miner-supplied script
+//! data, such as pool tags and extra nonce space, and multiple payouts.
+
+use serde::{Deserialize, Serialize};
+
+mod coinbase;
+mod policy;
+mod template;
+
+pub use coinbase::{CoinbaseBuilder, CoinbaseError, CoinbaseTemplate};
+pub use policy::{PolicyError, RelayPolicy};
+pub use template::{
+    dry_run, Candidate, TemplateDecision, TemplateDiff, TemplateEntry, TemplateReport,
+};
+
+/// Configuration for the mempool.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Config {
+    /// The relay policy for transactions admitted to the mempool, and for
+    /// transactions submitted to the RPC endpoint.
+    ///
+    /// The relay policy is never applied to transactions in blocks.
+    pub relay_policy: RelayPolicy,
+
+    /// Log why each mempool transaction was included in, or excluded from,
+    /// each block template.
+    ///
+    /// Included transactions are logged at debug level, and excluded
+    /// transactions are logged at info level.
+    ///
+    /// Zebra doesn't generate block templates yet, so this setting only
+    /// applies to dry runs.
+    pub log_template_decisions: bool,
+}
 
 /// Mempool state.
 ///
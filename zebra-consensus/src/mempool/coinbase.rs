@@ -0,0 +1,293 @@
+//! Coinbase transaction construction for block templates.
+//!
+//! Mining pools put their own data in the coinbase script, such as a pool tag
+//! and extra nonce space, and split the block reward between several payout
+//! outputs. The builder checks the coinbase script length, and pays every
+//! required output, such as funding stream outputs, before the payouts.
+
+use std::convert::TryFrom;
+
+use thiserror::Error;
+
+use zebra_chain::{
+    serialization::Cached,
+    transaction::{CoinbaseData, Transaction, TransparentInput, TransparentOutput},
+    types::{
+        amount::{Amount, NonNegative},
+        BlockHeight, LockTime, Script,
+    },
+};
+
+/// A coinbase transaction that can't be built.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum CoinbaseError {
+    /// The pool tag and extra nonce don't fit in the coinbase script.
+    #[error("coinbase script length {len} bytes is outside the valid range of 2 to 100 bytes")]
+    ScriptLength {
+        /// The length of the coinbase script, including the encoded height.
+        len: usize,
+    },
+
+    /// The coinbase transaction has no payout outputs.
+    #[error("coinbase transaction has no payout outputs")]
+    NoPayouts,
+
+    /// The required outputs and payouts are more than the block reward.
+    #[error("coinbase outputs pay {paid} zatoshis, but the block reward is {reward} zatoshis")]
+    ExceedsReward {
+        /// The total value of the required outputs and payouts.
+        paid: u64,
+        /// The block subsidy plus transaction fees.
+        reward: u64,
+    },
+
+    /// The extra nonce is not the length reserved in the template.
+    #[error("extra nonce is {len} bytes, but {reserved} bytes were reserved")]
+    ExtraNonceLength {
+        /// The length of the supplied extra nonce.
+        len: usize,
+        /// The reserved extra nonce length.
+        reserved: usize,
+    },
+}
+
+/// A builder for coinbase transactions with miner-supplied script data and
+/// outputs.
+///
+/// Builds Sapling (v4) coinbase transactions.
+#[derive(Clone, Debug)]
+pub struct CoinbaseBuilder {
+    height: BlockHeight,
+    reward: Amount<NonNegative>,
+    pool_tag: Vec<u8>,
+    extra_nonce_len: usize,
+    required_outputs: Vec<TransparentOutput>,
+    payouts: Vec<TransparentOutput>,
+}
+
+impl CoinbaseBuilder {
+    /// Returns a builder for the coinbase transaction of the block at
+    /// `height`.
+    ///
+    /// `reward` is the block subsidy plus the fees of the transactions in the
+    /// block.
+    pub fn new(height: BlockHeight, reward: Amount<NonNegative>) -> Self {
+        Self {
+            height,
+            reward,
+            pool_tag: Vec::new(),
+            extra_nonce_len: 0,
+            required_outputs: Vec::new(),
+            payouts: Vec::new(),
+        }
+    }
+
+    /// Put `pool_tag` in the coinbase script, after the block height.
+    pub fn pool_tag(mut self, pool_tag: impl Into<Vec<u8>>) -> Self {
+        self.pool_tag = pool_tag.into();
+        self
+    }
+
+    /// Reserve `len` bytes of extra nonce space in the coinbase script, after
+    /// the pool tag.
+    pub fn extra_nonce_len(mut self, len: usize) -> Self {
+        self.extra_nonce_len = len;
+        self
+    }
+
+    /// Add an output that the coinbase transaction must pay, such as a
+    /// funding stream output.
+    ///
+    /// Required outputs are paid before the payouts.
+    pub fn required_output(mut self, pk_script: Script, value: Amount<NonNegative>) -> Self {
+        self.required_outputs
+            .push(TransparentOutput { value, pk_script });
+        self
+    }
+
+    /// Add a payout of `value` to `pk_script`.
+    pub fn payout(mut self, pk_script: Script, value: Amount<NonNegative>) -> Self {
+        self.payouts.push(TransparentOutput { value, pk_script });
+        self
+    }
+
+    /// Check the coinbase script and outputs, and return a template for the
+    /// coinbase transaction.
+    pub fn build(self) -> Result<CoinbaseTemplate, CoinbaseError> {
+        // Check the script with the largest extra nonce
+        let data = self.script_data(&vec![0xff; self.extra_nonce_len]);
+        if CoinbaseData::new(self.height, data.clone()).is_none() {
+            return Err(CoinbaseError::ScriptLength {
+                len: CoinbaseData::script_len(self.height, data.len()),
+            });
+        }
+
+        if self.payouts.is_empty() {
+            return Err(CoinbaseError::NoPayouts);
+        }
+
+        let reward = u64::from(self.reward);
+        let paid = self
+            .required_outputs
+            .iter()
+            .chain(&self.payouts)
+            .map(|output| u64::from(output.value))
+            .sum();
+        if paid > reward {
+            return Err(CoinbaseError::ExceedsReward { paid, reward });
+        }
+
+        Ok(CoinbaseTemplate { builder: self })
+    }
+
+    /// Returns the coinbase script data after the height, with `extra_nonce`.
+    fn script_data(&self, extra_nonce: &[u8]) -> Vec<u8> {
+        let mut data = self.pool_tag.clone();
+        data.extend_from_slice(extra_nonce);
+        data
+    }
+}
+
+/// A checked coinbase transaction, with space for an extra nonce.
+#[derive(Clone, Debug)]
+pub struct CoinbaseTemplate {
+    builder: CoinbaseBuilder,
+}
+
+impl CoinbaseTemplate {
+    /// Returns the length of the extra nonce, in bytes.
+    pub fn extra_nonce_len(&self) -> usize {
+        self.builder.extra_nonce_len
+    }
+
+    /// Returns the coinbase transaction with `extra_nonce` in the reserved
+    /// extra nonce space.
+    pub fn transaction(&self, extra_nonce: &[u8]) -> Result<Transaction, CoinbaseError> {
+        let builder = &self.builder;
+        if extra_nonce.len() != builder.extra_nonce_len {
+            return Err(CoinbaseError::ExtraNonceLength {
+                len: extra_nonce.len(),
+                reserved: builder.extra_nonce_len,
+            });
+        }
+
+        let data = CoinbaseData::new(builder.height, builder.script_data(extra_nonce))
+            .expect("script length was checked by the builder");
+        let outputs = builder
+            .required_outputs
+            .iter()
+            .chain(&builder.payouts)
+            .cloned()
+            .collect();
+
+        Ok(Transaction::V4 {
+            inputs: vec![TransparentInput::Coinbase {
+                height: builder.height,
+                data,
+                sequence: u32::MAX,
+            }],
+            outputs,
+            lock_time: LockTime::Height(BlockHeight(0)),
+            // Coinbase transactions don't expire
+            expiry_height: BlockHeight(0),
+            value_balance: Amount::try_from(0i64).expect("zero is a valid amount"),
+            shielded_data: None,
+            joinsplit_data: None,
+            size: Cached::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use zebra_chain::serialization::{ZcashDeserialize, ZcashSerialize};
+
+    fn amount(value: u64) -> Amount<NonNegative> {
+        Amount::try_from(value).unwrap()
+    }
+
+    #[test]
+    fn coinbase_with_pool_tag_and_payouts() {
+        zebra_test::init();
+
+        let height = BlockHeight(1_000_000);
+        let template = CoinbaseBuilder::new(height, amount(1_000))
+            .pool_tag(&b"/zebra pool/"[..])
+            .extra_nonce_len(8)
+            .required_output(Script(vec![0xa9]), amount(200))
+            .payout(Script(vec![0x76]), amount(500))
+            .payout(Script(vec![0x77]), amount(300))
+            .build()
+            .expect("coinbase is valid");
+
+        let tx = template
+            .transaction(&[1, 2, 3, 4, 5, 6, 7, 8])
+            .expect("extra nonce has the reserved length");
+        let values: Vec<u64> = tx.outputs().map(|output| u64::from(output.value)).collect();
+        assert_eq!(values, vec![200, 500, 300]);
+        assert!(tx.contains_coinbase_input());
+
+        // The coinbase round-trips, with the height and data intact
+        let mut bytes = Vec::new();
+        tx.zcash_serialize(&mut bytes).unwrap();
+        let parsed = Transaction::zcash_deserialize(&bytes[..]).unwrap();
+        assert_eq!(parsed, tx);
+        match parsed.inputs().next() {
+            Some(TransparentInput::Coinbase {
+                height: parsed_height,
+                data,
+                ..
+            }) => {
+                assert_eq!(*parsed_height, height);
+                assert_eq!(
+                    data.as_ref(),
+                    &b"/zebra pool/\x01\x02\x03\x04\x05\x06\x07\x08"[..]
+                );
+            }
+            input => panic!("unexpected input: {:?}", input),
+        }
+
+        assert_eq!(
+            template.transaction(&[1]),
+            Err(CoinbaseError::ExtraNonceLength {
+                len: 1,
+                reserved: 8
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_coinbases_are_rejected() {
+        zebra_test::init();
+
+        let height = BlockHeight(1_000_000);
+        let payout = |builder: CoinbaseBuilder| builder.payout(Script(vec![0x76]), amount(100));
+
+        assert_eq!(
+            payout(CoinbaseBuilder::new(height, amount(1_000)))
+                .pool_tag(vec![0; 90])
+                .extra_nonce_len(8)
+                .build()
+                .unwrap_err(),
+            CoinbaseError::ScriptLength { len: 102 }
+        );
+        assert_eq!(
+            CoinbaseBuilder::new(height, amount(1_000))
+                .build()
+                .unwrap_err(),
+            CoinbaseError::NoPayouts
+        );
+        assert_eq!(
+            payout(CoinbaseBuilder::new(height, amount(1_000)))
+                .required_output(Script(vec![0xa9]), amount(950))
+                .build()
+                .unwrap_err(),
+            CoinbaseError::ExceedsReward {
+                paid: 1_050,
+                reward: 1_000
+            }
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Transaction relay policy for Zebra's mempool.
+//!
+//! Relay policy rules are local node policy, not consensus rules. They are
+//! only applied when admitting transactions to the mempool, and never applied
+//! to transactions in blocks.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use zebra_chain::{
+    addresses::transparent::ScriptKind,
+    transaction::{Transaction, TransparentInput},
+    types::{
+        amount::{Amount, NonNegative},
+        Script,
+    },
+};
+
+/// The maximum size of a standard transparent input script, in bytes.
+const MAX_STANDARD_SCRIPT_SIG_SIZE: usize = 1650;
+
+/// The maximum size of a standard null data output script, in bytes.
+const MAX_NULL_DATA_SCRIPT_SIZE: usize = 83;
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1NEGATE: u8 = 0x4f;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_RETURN: u8 = 0x6a;
+
+/// Configurable relay policy for mempool transactions.
+///
+/// Each rule can be disabled individually. The defaults are conservative, and
+/// match `zcashd`'s default relay policy.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RelayPolicy {
+    /// The minimum fee rate, in zatoshis per 1000 bytes.
+    ///
+    /// If `None`, transactions with any fee are relayed.
+    pub min_fee_rate: Option<u64>,
+
+    /// The minimum value of a transparent output, in zatoshis.
+    ///
+    /// Outputs below this value are rejected as dust. Null data outputs are
+    /// exempt. If `None`, outputs of any value are relayed.
+    pub dust_threshold: Option<u64>,
+
+    /// The maximum serialized size of a transaction, in bytes.
+    ///
+    /// If `None`, transactions up to the consensus size limit are relayed.
+    pub max_tx_size: Option<u64>,
+
+    /// Only relay transactions with standard transparent scripts?
+    ///
+    /// Standard output scripts are P2PKH, P2SH, and small null data scripts.
+    /// Standard input scripts are small push-only scripts.
+    pub standard_scripts: bool,
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        Self {
+            min_fee_rate: Some(100),
+            dust_threshold: Some(54),
+            max_tx_size: Some(100_000),
+            standard_scripts: true,
+        }
+    }
+}
+
+/// A transaction rejected by the relay policy.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum PolicyError {
+    /// The transaction is larger than `max_tx_size`.
+    #[error("transaction size {size} bytes exceeds the relay policy maximum of {max_tx_size} bytes")]
+    TooLarge {
+        /// The serialized size of the transaction.
+        size: u64,
+        /// The configured maximum size.
+        max_tx_size: u64,
+    },
+
+    /// The transaction's fee rate is below `min_fee_rate`.
+    #[error("fee {fee} zatoshis for {size} bytes is below the relay policy minimum of {min_fee_rate} zatoshis per 1000 bytes")]
+    FeeTooLow {
+        /// The fee paid by the transaction.
+        fee: u64,
+        /// The serialized size of the transaction.
+        size: u64,
+        /// The configured minimum fee rate.
+        min_fee_rate: u64,
+    },
+
+    /// A transparent output's value is below `dust_threshold`.
+    #[error("output {index} value {value} zatoshis is below the relay policy dust threshold of {dust_threshold} zatoshis")]
+    Dust {
+        /// The index of the output.
+        index: usize,
+        /// The value of the output.
+        value: u64,
+        /// The configured dust threshold.
+        dust_threshold: u64,
+    },
+
+    /// A transparent output script is non-standard.
+    #[error("output {index} has a non-standard script")]
+    NonStandardOutputScript {
+        /// The index of the output.
+        index: usize,
+    },
+
+    /// A transparent input script is non-standard.
+    #[error("input {index} has a non-standard script")]
+    NonStandardInputScript {
+        /// The index of the input.
+        index: usize,
+    },
+}
+
+impl RelayPolicy {
+    /// A relay policy that disables every rule.
+    pub fn permissive() -> Self {
+        Self {
+            min_fee_rate: None,
+            dust_threshold: None,
+            max_tx_size: None,
+            standard_scripts: false,
+        }
+    }
+
+    /// Check `tx`, which pays `fee`, against this relay policy.
+    ///
+    /// The caller is responsible for calculating the fee, because it depends
+    /// on the values of the outputs spent by `tx`.
+    pub fn check(&self, tx: &Transaction, fee: Amount<NonNegative>) -> Result<(), PolicyError> {
+        let size = tx.serialized_size() as u64;
+
+        if let Some(max_tx_size) = self.max_tx_size {
+            if size > max_tx_size {
+                return Err(PolicyError::TooLarge { size, max_tx_size });
+            }
+        }
+
+        if let Some(min_fee_rate) = self.min_fee_rate {
+            let fee = u64::from(fee);
+            // Compare `fee / size < min_fee_rate / 1000` without rounding
+            if u128::from(fee) * 1000 < u128::from(min_fee_rate) * u128::from(size) {
+                return Err(PolicyError::FeeTooLow {
+                    fee,
+                    size,
+                    min_fee_rate,
+                });
+            }
+        }
+
+        for (index, output) in tx.outputs().enumerate() {
+            let script = &output.pk_script;
+
+            if self.standard_scripts && !is_standard_output_script(script) {
+                return Err(PolicyError::NonStandardOutputScript { index });
+            }
+
+            if let Some(dust_threshold) = self.dust_threshold {
+                let value = u64::from(output.value);
+                if value < dust_threshold && !is_null_data(&script.0) {
+                    return Err(PolicyError::Dust {
+                        index,
+                        value,
+                        dust_threshold,
+                    });
+                }
+            }
+        }
+
+        if self.standard_scripts {
+            for (index, input) in tx.inputs().enumerate() {
+                let is_standard = match input {
+                    TransparentInput::PrevOut { script, .. } => {
+                        script.0.len() <= MAX_STANDARD_SCRIPT_SIG_SIZE && is_push_only(&script.0)
+                    }
+                    // Coinbase transactions are never relayed
+                    TransparentInput::Coinbase { .. } => false,
+                };
+
+                if !is_standard {
+                    return Err(PolicyError::NonStandardInputScript { index });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Is `script` a P2PKH, P2SH, or null data output script?
+fn is_standard_output_script(script: &Script) -> bool {
+    ScriptKind::classify(script) != ScriptKind::NonStandard || is_null_data(&script.0)
+}
+
+/// Is `script` a small, push-only null data output script?
+fn is_null_data(script: &[u8]) -> bool {
+    script.len() <= MAX_NULL_DATA_SCRIPT_SIZE
+        && script.first() == Some(&OP_RETURN)
+        && is_push_only(&script[1..])
+}
+
+/// Does `script` only contain data push opcodes?
+fn is_push_only(script: &[u8]) -> bool {
+    let mut rest = script;
+
+    while let Some((&opcode, tail)) = rest.split_first() {
+        let (len, tail) = match opcode {
+            OP_0 | OP_1NEGATE | OP_1..=OP_16 => (0, tail),
+            0x01..=0x4b => (opcode as usize, tail),
+            OP_PUSHDATA1 if !tail.is_empty() => (tail[0] as usize, &tail[1..]),
+            OP_PUSHDATA2 if tail.len() >= 2 => {
+                (u16::from_le_bytes([tail[0], tail[1]]) as usize, &tail[2..])
+            }
+            OP_PUSHDATA4 if tail.len() >= 4 => (
+                u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) as usize,
+                &tail[4..],
+            ),
+            _ => return false,
+        };
+
+        if tail.len() < len {
+            return false;
+        }
+        rest = &tail[len..];
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use zebra_chain::{
+        addresses::transparent::TransparentAddress,
+        serialization::Cached,
+        transaction::TransparentOutput,
+        types::{BlockHeight, LockTime},
+        Network,
+    };
+
+    const OP_DUP: u8 = 0x76;
+    const OP_CHECKSIG: u8 = 0xac;
+
+    fn p2pkh_script() -> Script {
+        TransparentAddress::PayToPublicKeyHash {
+            network: Network::Mainnet,
+            pub_key_hash: [0; 20],
+        }
+        .output_script()
+    }
+
+    fn transaction(value: u64, pk_script: Script) -> Transaction {
+        Transaction::V1 {
+            inputs: Vec::new(),
+            outputs: vec![TransparentOutput {
+                value: Amount::try_from(value).unwrap(),
+                pk_script,
+            }],
+            lock_time: LockTime::Height(BlockHeight(0)),
+            size: Cached::default(),
+        }
+    }
+
+    fn fee(value: u64) -> Amount<NonNegative> {
+        Amount::try_from(value).unwrap()
+    }
+
+    #[test]
+    fn default_policy() {
+        zebra_test::init();
+
+        let policy = RelayPolicy::default();
+
+        let tx = transaction(10_000, p2pkh_script());
+        assert_eq!(policy.check(&tx, fee(1_000)), Ok(()));
+        assert!(matches!(
+            policy.check(&tx, fee(0)),
+            Err(PolicyError::FeeTooLow { .. })
+        ));
+
+        let tx = transaction(1, p2pkh_script());
+        assert_eq!(
+            policy.check(&tx, fee(1_000)),
+            Err(PolicyError::Dust {
+                index: 0,
+                value: 1,
+                dust_threshold: 54
+            })
+        );
+
+        let tx = transaction(10_000, Script(vec![OP_CHECKSIG]));
+        assert_eq!(
+            policy.check(&tx, fee(1_000)),
+            Err(PolicyError::NonStandardOutputScript { index: 0 })
+        );
+
+        // Null data outputs are exempt from the dust threshold
+        let tx = transaction(0, Script(vec![OP_RETURN, 2, 0xab, 0xcd]));
+        assert_eq!(policy.check(&tx, fee(1_000)), Ok(()));
+    }
+
+    #[test]
+    fn permissive_policy() {
+        zebra_test::init();
+
+        let policy = RelayPolicy::permissive();
+
+        let tx = transaction(1, Script(vec![OP_CHECKSIG]));
+        assert_eq!(policy.check(&tx, fee(0)), Ok(()));
+    }
+
+    #[test]
+    fn push_only_scripts() {
+        zebra_test::init();
+
+        assert!(is_push_only(&[]));
+        assert!(is_push_only(&[OP_0, OP_1, OP_16, 2, 0xab, 0xcd]));
+        assert!(is_push_only(&[OP_PUSHDATA1, 1, 0xab]));
+        assert!(!is_push_only(&[OP_PUSHDATA1, 2, 0xab]));
+        assert!(!is_push_only(&[OP_DUP]));
+    }
+}
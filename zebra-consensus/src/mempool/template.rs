@@ -0,0 +1,440 @@
+//! Block template dry runs for Zebra's mempool.
+//!
+//! A dry run selects mempool transactions for a block template, and records
+//! why each transaction was included or excluded. Pool operators can log the
+//! decisions, or compare them with a `zcashd` template, to debug revenue
+//! differences.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use serde::Serialize;
+
+use zebra_chain::{
+    transaction::{Transaction, TransactionHash, TransparentInput},
+    types::{
+        amount::{Amount, NonNegative},
+        BlockHeight,
+    },
+};
+
+/// A mempool transaction that can be added to a block template.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    /// The transaction.
+    pub transaction: Arc<Transaction>,
+    /// The hash of `transaction`.
+    pub hash: TransactionHash,
+    /// The fee paid by `transaction`.
+    pub fee: Amount<NonNegative>,
+}
+
+impl Candidate {
+    /// Returns the serialized size of the transaction, in bytes.
+    fn size(&self) -> u64 {
+        self.transaction.serialized_size() as u64
+    }
+
+    /// Compare the fee rates of `self` and `other`, without rounding.
+    fn cmp_fee_rate(&self, other: &Candidate) -> Ordering {
+        let rate = u128::from(u64::from(self.fee)) * u128::from(other.size());
+        let other_rate = u128::from(u64::from(other.fee)) * u128::from(self.size());
+        rate.cmp(&other_rate)
+    }
+}
+
+/// Why a transaction was included in, or excluded from, a block template.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum TemplateDecision {
+    /// The transaction was included.
+    Included,
+    /// The transaction can't be mined at the template height.
+    Expired {
+        /// The last height the transaction can be mined at.
+        expiry_height: BlockHeight,
+    },
+    /// The transaction didn't fit in the space left in the template.
+    SizeLimit {
+        /// The remaining template space, in bytes.
+        remaining: u64,
+    },
+    /// The transaction spends an output of a mempool transaction that wasn't
+    /// included.
+    MissingDependency {
+        /// The excluded mempool transaction.
+        parent: TransactionHash,
+    },
+}
+
+/// The decision for a single transaction in a block template dry run.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TemplateEntry {
+    /// The transaction hash.
+    pub hash: TransactionHash,
+    /// The rank of the transaction's fee rate in the mempool, starting at 1
+    /// for the highest fee rate.
+    pub rank: usize,
+    /// The serialized size of the transaction, in bytes.
+    pub size: u64,
+    /// The fee paid by the transaction, in zatoshis.
+    pub fee: u64,
+    /// Why the transaction was included or excluded.
+    pub decision: TemplateDecision,
+}
+
+/// The result of a block template dry run.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TemplateReport {
+    /// The height of the template block.
+    pub height: BlockHeight,
+    /// The decision for every mempool transaction, in fee rate order.
+    pub entries: Vec<TemplateEntry>,
+    /// The included transactions, in template order.
+    ///
+    /// Transactions are always after the mempool transactions they spend.
+    pub transactions: Vec<TransactionHash>,
+    /// The total size of the included transactions, in bytes.
+    pub size: u64,
+    /// The total fees of the included transactions, in zatoshis.
+    pub fees: u64,
+}
+
+/// The differences between a dry run template and another template.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct TemplateDiff {
+    /// Transactions in the other template that the dry run didn't include,
+    /// with the dry run decision.
+    ///
+    /// The decision is `None` if the transaction isn't in the mempool.
+    pub missing: Vec<(TransactionHash, Option<TemplateDecision>)>,
+    /// Transactions included by the dry run that aren't in the other
+    /// template.
+    pub extra: Vec<TransactionHash>,
+}
+
+/// Select transactions from `candidates` for a block template at `height`,
+/// recording why each transaction was included or excluded.
+///
+/// Transactions are selected in fee rate order, until the template reaches
+/// `max_size` bytes. Callers should reserve space for the block header and
+/// coinbase transaction.
+pub fn dry_run(candidates: &[Candidate], height: BlockHeight, max_size: u64) -> TemplateReport {
+    let mut ranked: Vec<&Candidate> = candidates.iter().collect();
+    ranked.sort_by(|a, b| b.cmp_fee_rate(a).then_with(|| a.hash.0.cmp(&b.hash.0)));
+
+    let in_mempool: HashSet<TransactionHash> = candidates.iter().map(|c| c.hash).collect();
+    let mut included: HashMap<TransactionHash, bool> = HashMap::new();
+    let mut decisions: Vec<Option<TemplateDecision>> = vec![None; ranked.len()];
+    let mut transactions = Vec::new();
+    let mut size = 0;
+    let mut fees = 0;
+
+    // Transactions that spend undecided mempool transactions are deferred
+    // until their parents are decided
+    let mut pending: Vec<usize> = (0..ranked.len()).collect();
+    while !pending.is_empty() {
+        let mut deferred = Vec::new();
+        for &index in &pending {
+            let candidate = ranked[index];
+            let parents = mempool_parents(candidate, &in_mempool);
+
+            let decision = if let Some(expiry_height) = expiry(candidate, height) {
+                TemplateDecision::Expired { expiry_height }
+            } else if let Some(&parent) = parents
+                .iter()
+                .find(|parent| included.get(*parent) == Some(&false))
+            {
+                TemplateDecision::MissingDependency { parent }
+            } else if parents.iter().any(|parent| !included.contains_key(parent)) {
+                deferred.push(index);
+                continue;
+            } else if size + candidate.size() > max_size {
+                TemplateDecision::SizeLimit {
+                    remaining: max_size - size,
+                }
+            } else {
+                size += candidate.size();
+                fees += u64::from(candidate.fee);
+                transactions.push(candidate.hash);
+                TemplateDecision::Included
+            };
+
+            included.insert(candidate.hash, decision == TemplateDecision::Included);
+            decisions[index] = Some(decision);
+        }
+
+        // If no transactions were decided, the remaining parents are never
+        // decided, because they spend each other
+        if deferred.len() == pending.len() {
+            for &index in &deferred {
+                let candidate = ranked[index];
+                let parent = mempool_parents(candidate, &in_mempool)
+                    .into_iter()
+                    .find(|parent| !included.contains_key(parent))
+                    .expect("deferred transactions have undecided parents");
+                decisions[index] = Some(TemplateDecision::MissingDependency { parent });
+            }
+            break;
+        }
+        pending = deferred;
+    }
+
+    let entries = ranked
+        .iter()
+        .zip(decisions)
+        .enumerate()
+        .map(|(index, (candidate, decision))| TemplateEntry {
+            hash: candidate.hash,
+            rank: index + 1,
+            size: candidate.size(),
+            fee: u64::from(candidate.fee),
+            decision: decision.expect("every transaction has a decision"),
+        })
+        .collect();
+
+    TemplateReport {
+        height,
+        entries,
+        transactions,
+        size,
+        fees,
+    }
+}
+
+/// Returns the expiry height of `candidate`, if it can't be mined at
+/// `height`.
+fn expiry(candidate: &Candidate, height: BlockHeight) -> Option<BlockHeight> {
+    match candidate.transaction.expiry_height() {
+        // An expiry height of zero means the transaction never expires
+        Some(expiry_height) if expiry_height.0 != 0 && height > expiry_height => {
+            Some(expiry_height)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the mempool transactions spent by `candidate`.
+fn mempool_parents(
+    candidate: &Candidate,
+    in_mempool: &HashSet<TransactionHash>,
+) -> Vec<TransactionHash> {
+    let mut parents = Vec::new();
+    for input in candidate.transaction.inputs() {
+        if let TransparentInput::PrevOut { outpoint, .. } = input {
+            if outpoint.hash != candidate.hash
+                && in_mempool.contains(&outpoint.hash)
+                && !parents.contains(&outpoint.hash)
+            {
+                parents.push(outpoint.hash);
+            }
+        }
+    }
+    parents
+}
+
+impl TemplateReport {
+    /// Returns the differences between this template and the template
+    /// containing `other` transactions, such as a `zcashd` template.
+    pub fn diff(&self, other: &[TransactionHash]) -> TemplateDiff {
+        let ours: HashSet<_> = self.transactions.iter().collect();
+        let theirs: HashSet<_> = other.iter().collect();
+
+        let missing = other
+            .iter()
+            .filter(|hash| !ours.contains(hash))
+            .map(|hash| {
+                let decision = self
+                    .entries
+                    .iter()
+                    .find(|entry| entry.hash == *hash)
+                    .map(|entry| entry.decision.clone());
+                (*hash, decision)
+            })
+            .collect();
+        let extra = self
+            .transactions
+            .iter()
+            .filter(|hash| !theirs.contains(hash))
+            .cloned()
+            .collect();
+
+        TemplateDiff { missing, extra }
+    }
+
+    /// Log the decision for every transaction, then a summary of the
+    /// template.
+    pub fn log(&self) {
+        for entry in &self.entries {
+            if entry.decision == TemplateDecision::Included {
+                tracing::debug!(
+                    hash = ?entry.hash,
+                    rank = entry.rank,
+                    size = entry.size,
+                    fee = entry.fee,
+                    "included transaction in block template"
+                );
+            } else {
+                tracing::info!(
+                    hash = ?entry.hash,
+                    rank = entry.rank,
+                    size = entry.size,
+                    fee = entry.fee,
+                    decision = ?entry.decision,
+                    "excluded transaction from block template"
+                );
+            }
+        }
+
+        tracing::info!(
+            height = ?self.height,
+            transactions = self.transactions.len(),
+            excluded = self.entries.len() - self.transactions.len(),
+            size = self.size,
+            fees = self.fees,
+            "assembled block template dry run"
+        );
+    }
+}
+
+impl TemplateDiff {
+    /// Log each transaction that is only in one of the templates.
+    pub fn log(&self) {
+        for (hash, decision) in &self.missing {
+            tracing::info!(
+                ?hash,
+                ?decision,
+                "transaction in other template was not included in the dry run"
+            );
+        }
+        for hash in &self.extra {
+            tracing::info!(
+                ?hash,
+                "transaction in the dry run was not included in other template"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use zebra_chain::{
+        serialization::Cached,
+        transaction::{OutPoint, TransparentOutput},
+        types::{LockTime, Script},
+    };
+
+    fn candidate(id: u8, fee: u64, parent: Option<u8>, expiry_height: u32) -> Candidate {
+        let inputs = parent
+            .map(|parent| TransparentInput::PrevOut {
+                outpoint: OutPoint {
+                    hash: TransactionHash([parent; 32]),
+                    index: 0,
+                },
+                script: Script(Vec::new()),
+                sequence: 0,
+            })
+            .into_iter()
+            .collect();
+        let transaction = Transaction::V3 {
+            inputs,
+            outputs: vec![TransparentOutput {
+                value: Amount::try_from(1_000u64).unwrap(),
+                pk_script: Script(Vec::new()),
+            }],
+            lock_time: LockTime::Height(BlockHeight(0)),
+            expiry_height: BlockHeight(expiry_height),
+            joinsplit_data: None,
+            size: Cached::default(),
+        };
+
+        Candidate {
+            transaction: Arc::new(transaction),
+            hash: TransactionHash([id; 32]),
+            fee: Amount::try_from(fee).unwrap(),
+        }
+    }
+
+    fn decision(report: &TemplateReport, id: u8) -> &TemplateDecision {
+        &report
+            .entries
+            .iter()
+            .find(|entry| entry.hash == TransactionHash([id; 32]))
+            .expect("transaction is in the report")
+            .decision
+    }
+
+    #[test]
+    fn dry_run_records_decisions() {
+        zebra_test::init();
+
+        let size = candidate(1, 0, None, 0).size();
+        let candidates = vec![
+            candidate(1, 500, None, 0),
+            // Waits for its lower fee rate parent, then doesn't fit
+            candidate(2, 400, Some(3), 0),
+            candidate(3, 100, None, 0),
+            candidate(4, 300, None, 99),
+            candidate(5, 200, None, 0),
+            candidate(6, 50, Some(5), 0),
+        ];
+
+        let report = dry_run(&candidates, BlockHeight(100), 3 * size);
+        assert_eq!(decision(&report, 1), &TemplateDecision::Included);
+        assert_eq!(
+            decision(&report, 4),
+            &TemplateDecision::Expired {
+                expiry_height: BlockHeight(99)
+            }
+        );
+        assert_eq!(decision(&report, 5), &TemplateDecision::Included);
+        assert_eq!(decision(&report, 3), &TemplateDecision::Included);
+        assert_eq!(
+            decision(&report, 2),
+            &TemplateDecision::SizeLimit { remaining: 0 }
+        );
+        assert_eq!(
+            decision(&report, 6),
+            &TemplateDecision::SizeLimit { remaining: 0 }
+        );
+        assert_eq!(report.entries[0].hash, TransactionHash([1; 32]));
+        assert_eq!(report.fees, 800);
+
+        let zcashd = [TransactionHash([1; 32]), TransactionHash([4; 32])];
+        let diff = report.diff(&zcashd);
+        assert_eq!(
+            diff.missing,
+            vec![(
+                TransactionHash([4; 32]),
+                Some(TemplateDecision::Expired {
+                    expiry_height: BlockHeight(99)
+                })
+            )]
+        );
+        assert_eq!(
+            diff.extra,
+            vec![TransactionHash([5; 32]), TransactionHash([3; 32])]
+        );
+    }
+
+    #[test]
+    fn dry_run_excludes_children_of_excluded_transactions() {
+        zebra_test::init();
+
+        let candidates = vec![candidate(1, 500, Some(2), 0), candidate(2, 100, None, 1)];
+
+        let report = dry_run(&candidates, BlockHeight(10), 1_000_000);
+        assert_eq!(
+            decision(&report, 1),
+            &TemplateDecision::MissingDependency {
+                parent: TransactionHash([2; 32])
+            }
+        );
+        assert!(report.transactions.is_empty());
+    }
+}
@@ -8,10 +8,14 @@
 //!
 //! Typically, consensus parameters are accessed via a function that takes a
 //! `Network` and `BlockHeight`.
+//!
+//! The network upgrade parameters are defined in zebra-chain, and re-exported
+//! here.
 
 pub mod genesis;
 pub mod minimum_difficulty;
-pub mod network_upgrade;
+
+pub use zebra_chain::parameters::network_upgrade;
 
 pub use genesis::*;
 pub use minimum_difficulty::*;
@@ -0,0 +1,7 @@
+//! Fuzz the inbound handshake, using the input as the remote peer's byte stream.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = zebra_network::fuzz::handshake(data);
+});
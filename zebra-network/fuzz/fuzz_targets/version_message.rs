@@ -0,0 +1,7 @@
+//! Fuzz `version` message body parsing, and re-encoding of parsed messages.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = zebra_network::fuzz::version_message(data);
+});
@@ -10,6 +10,8 @@ use std::{
 use chrono::{DateTime, Utc};
 use tracing::Span;
 
+use zebra_chain::types::BlockHeight;
+
 use crate::{
     constants,
     types::{MetaAddr, PeerServices},
@@ -21,6 +23,8 @@ use crate::{
 pub struct AddressBook {
     by_addr: HashMap<SocketAddr, (DateTime<Utc>, PeerServices)>,
     by_time: BTreeSet<MetaAddr>,
+    /// The best block height each peer advertised in its handshake.
+    heights: HashMap<SocketAddr, BlockHeight>,
     span: Span,
 }
 
@@ -31,6 +35,7 @@ impl AddressBook {
         AddressBook {
             by_addr: HashMap::default(),
             by_time: BTreeSet::default(),
+            heights: HashMap::default(),
             span,
         }
     }
@@ -118,6 +123,31 @@ impl AddressBook {
         }
     }
 
+    /// Record the best block `height` that the peer at `addr` advertised in
+    /// its handshake, and forget the heights of disconnected peers.
+    pub fn record_height(&mut self, addr: SocketAddr, height: BlockHeight) {
+        let _guard = self.span.enter();
+        let cutoff = AddressBook::cutoff_time();
+        let by_addr = &self.by_addr;
+        self.heights.retain(
+            |addr, _| matches!(by_addr.get(addr), Some((last_seen, _)) if *last_seen > cutoff),
+        );
+        self.heights.insert(addr, height);
+    }
+
+    /// Return an iterator over the best block heights advertised by peers
+    /// that could potentially be connected.
+    ///
+    /// Peers advertise their height when they connect, so the heights of
+    /// long-lived connections can be behind the peer's current tip.
+    pub fn peer_heights<'a>(&'a self) -> impl Iterator<Item = BlockHeight> + 'a {
+        let _guard = self.span.enter();
+        self.heights
+            .iter()
+            .filter(move |(addr, _)| self.is_potentially_connected(addr))
+            .map(|(_, height)| *height)
+    }
+
     /// Return an iterator over all peers, ordered from most recently seen to
     /// least recently seen.
     pub fn peers<'a>(&'a self) -> impl Iterator<Item = MetaAddr> + 'a {
@@ -202,3 +232,37 @@ impl<'a> Iterator for Drain<'a> {
         Some(next_item)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    #[test]
+    fn peer_heights_are_only_returned_for_live_peers() {
+        let mut book = AddressBook::new(Span::none());
+        let live: SocketAddr = "192.0.2.1:8233".parse().unwrap();
+        let disconnected: SocketAddr = "192.0.2.2:8233".parse().unwrap();
+
+        book.update(MetaAddr {
+            addr: live,
+            services: PeerServices::NODE_NETWORK,
+            last_seen: Utc::now(),
+        });
+        book.update(MetaAddr {
+            addr: disconnected,
+            services: PeerServices::NODE_NETWORK,
+            last_seen: Utc::now() - Duration::days(1),
+        });
+        book.record_height(disconnected, BlockHeight(100));
+        book.record_height(live, BlockHeight(200));
+
+        assert_eq!(
+            book.peer_heights().collect::<Vec<_>>(),
+            vec![BlockHeight(200)]
+        );
+        // Heights of disconnected peers are forgotten
+        assert!(!book.heights.contains_key(&disconnected));
+    }
+}
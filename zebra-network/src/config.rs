@@ -7,6 +7,8 @@ use std::{
 
 use zebra_chain::Network;
 
+use crate::protocol::types::PeerServices;
+
 /// Configuration for networking code.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, default)]
@@ -14,6 +16,20 @@ pub struct Config {
     /// The address on which this node should listen for connections.
     pub listen_addr: SocketAddr,
 
+    /// Additional addresses on which this node should listen for
+    /// connections.
+    ///
+    /// For example, set `listen_addr` to `0.0.0.0:8233` and add `[::]:8233`
+    /// here, to listen for IPv4 and IPv6 connections.
+    pub additional_listen_addrs: Vec<SocketAddr>,
+
+    /// The address that this node advertises to its peers.
+    ///
+    /// Set this to the node's public address and port if it is behind NAT,
+    /// so that peers can connect to it. If `None`, the node doesn't advertise
+    /// a reachable address.
+    pub external_addr: Option<SocketAddr>,
+
     /// The network to connect to.
     pub network: Network,
 
@@ -31,9 +47,43 @@ pub struct Config {
     /// The outgoing request buffer size for the peer set.
     pub peerset_request_buffer_size: usize,
 
+    /// The buffer size for requests from peers, such as requests for
+    /// historical blocks and headers.
+    ///
+    /// When the buffer is full, peer connections wait before sending more
+    /// requests to the node.
+    pub inbound_request_buffer_size: usize,
+
     /// The initial target size for the peer set.
     pub peerset_initial_target_size: usize,
 
+    /// The minimum number of blocks requested from a peer in each `getdata`
+    /// message.
+    ///
+    /// Each peer starts at the minimum. The batch size grows when the peer
+    /// responds quickly, and shrinks when its requests time out.
+    pub min_block_batch_size: usize,
+
+    /// The maximum number of blocks requested from a peer in each `getdata`
+    /// message.
+    pub max_block_batch_size: usize,
+
+    /// Replace peer IP addresses in logs, spans, and error reports with a
+    /// hash of the address and a random per-run salt.
+    pub redact_peer_addrs: bool,
+
+    /// Label peer metrics with unredacted peer addresses, even if
+    /// `redact_peer_addrs` is set.
+    pub metrics_peer_addrs: bool,
+
+    /// The services that this node advertises to its peers.
+    ///
+    /// This isn't read from the config file, because the services depend on
+    /// the blocks the node can serve. Nodes that serve every block advertise
+    /// `NODE_NETWORK`, and pruned nodes advertise `NODE_NETWORK_LIMITED`.
+    #[serde(skip)]
+    pub services: PeerServices,
+
     // Note: due to the way this is rendered by the toml
     // serializer, the Duration fields should come last.
     /// The default RTT estimate for peer responses, used in load-balancing.
@@ -58,6 +108,11 @@ impl Config {
             .collect()
     }
 
+    /// Get every address that this node should listen on.
+    pub fn listen_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.listen_addr).chain(self.additional_listen_addrs.iter().cloned())
+    }
+
     /// Get the initial seed peers based on the configured network.
     pub fn initial_peers(&self) -> HashSet<SocketAddr> {
         match self.network {
@@ -92,6 +147,8 @@ impl Default for Config {
             listen_addr: "0.0.0.0:8233"
                 .parse()
                 .expect("Hardcoded address should be parseable"),
+            additional_listen_addrs: Vec::new(),
+            external_addr: None,
             user_agent: crate::constants::USER_AGENT.to_owned(),
             network: Network::Mainnet,
             initial_mainnet_peers: mainnet_peers,
@@ -99,9 +156,15 @@ impl Default for Config {
             ewma_default_rtt: Duration::from_secs(1),
             ewma_decay_time: Duration::from_secs(60),
             peerset_request_buffer_size: 10,
+            inbound_request_buffer_size: 1,
             handshake_timeout: Duration::from_secs(4),
             new_peer_interval: Duration::from_secs(60),
             peerset_initial_target_size: 50,
+            min_block_batch_size: 1,
+            max_block_batch_size: 16,
+            redact_peer_addrs: false,
+            metrics_peer_addrs: false,
+            services: PeerServices::NODE_NETWORK,
         }
     }
 }
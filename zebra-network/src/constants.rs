@@ -10,6 +10,12 @@ use zebra_consensus::parameters::NetworkUpgrade::{self, *};
 /// The timeout for requests made to a remote peer.
 pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Peers that send a batch of blocks within this time get larger batches.
+///
+/// This is half of `REQUEST_TIMEOUT`, so larger batches are unlikely to time
+/// out.
+pub const TIMELY_BLOCK_BATCH: Duration = Duration::from_secs(5);
+
 /// We expect to receive a message from a live peer at least once in this time duration.
 ///
 /// This is the sum of:
@@ -27,12 +33,36 @@ pub const LIVE_PEER_DURATION: Duration = Duration::from_secs(60 + 10 + 10 + 10);
 /// connected peer.
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
 
+/// The delay before the first restart of a failed peer crawler.
+pub const MIN_CRAWLER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay between restarts of a failed peer crawler.
+///
+/// Crawlers that run for longer than this delay are restarted after
+/// `MIN_CRAWLER_RESTART_DELAY` again.
+pub const MAX_CRAWLER_RESTART_DELAY: Duration = Duration::from_secs(60);
+
 /// Truncate timestamps in outbound address messages to this time interval.
 ///
 /// This is intended to prevent a peer from learning exactly when we received
 /// messages from each of our peers.
 pub const TIMESTAMP_TRUNCATION_SECONDS: i64 = 30 * 60;
 
+/// The maximum number of block headers in a `headers` message.
+///
+/// This is the `getheaders` response limit in `zcashd`. Peers that send
+/// more headers are misbehaving.
+pub const MAX_HEADERS_PER_MESSAGE: usize = 160;
+
+/// Blocks that are more than this many blocks below a peer's advertised best
+/// block height are historical for that peer.
+///
+/// Peers that advertise `NODE_NETWORK_LIMITED` instead of `NODE_NETWORK` only
+/// serve recent blocks, so the peer set prefers `NODE_NETWORK` peers for
+/// historical blocks. This is the recent block limit for pruned peers in
+/// BIP 159.
+pub const HISTORICAL_BLOCK_DEPTH: u32 = 288;
+
 /// The User-Agent string provided by the node.
 pub const USER_AGENT: &str = "🦓 Zebra 3.0.0-alpha.0 🦓";
 
@@ -74,4 +104,10 @@ mod tests {
 
         assert_eq!(LIVE_PEER_DURATION, constructed_live_peer_duration);
     }
+
+    /// Timely block batches must arrive well before the request times out.
+    #[test]
+    fn ensure_timely_block_batch_is_half_the_request_timeout() {
+        assert_eq!(TIMELY_BLOCK_BATCH * 2, REQUEST_TIMEOUT);
+    }
 }
@@ -0,0 +1,156 @@
+//! Entry points for the fuzz targets in `zebra-network/fuzz`.
+//!
+//! These functions feed attacker-controlled bytes to the message codec and
+//! the handshake. They are not part of the stable API.
+
+use std::{
+    net::Shutdown,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use futures::channel::mpsc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime,
+    time::timeout,
+};
+use tokio_util::codec::{Decoder, Encoder};
+use tower::{service_fn, Service};
+
+use zebra_chain::types::Sha256dChecksum;
+
+use crate::{
+    constants, peer::Handshake, protocol::external::Codec, AddressBook, BoxedStdError, Config,
+    Request, Response,
+};
+
+/// The maximum time a fuzzed handshake can take.
+///
+/// The remote end closes the connection after sending the fuzz input, so
+/// handshakes usually finish much faster than this.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The length of a message header.
+const HEADER_LEN: usize = 24;
+
+/// Decode `body` as the body of a `version` message.
+pub fn version_message(body: &[u8]) -> Result<(), BoxedStdError> {
+    decode_body(b"version\0\0\0\0\0", body)
+}
+
+/// Decode `body` as the body of an `addr` message.
+pub fn addr_message(body: &[u8]) -> Result<(), BoxedStdError> {
+    decode_body(b"addr\0\0\0\0\0\0\0\0", body)
+}
+
+/// Decode `body` as the body of a `command` message, with a valid header, then
+/// re-encode the decoded message.
+fn decode_body(command: &[u8; 12], body: &[u8]) -> Result<(), BoxedStdError> {
+    let mut src = BytesMut::with_capacity(HEADER_LEN + body.len());
+    src.extend_from_slice(&constants::magics::MAINNET.0);
+    src.extend_from_slice(command);
+    src.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    src.extend_from_slice(&Sha256dChecksum::from(body).0);
+    src.extend_from_slice(body);
+
+    let mut codec = Codec::builder().finish();
+    let message = codec.decode(&mut src)?.ok_or("message was incomplete")?;
+    codec.encode(message, &mut BytesMut::new())?;
+
+    Ok(())
+}
+
+/// Run an inbound handshake with a remote peer that sends `input`, then closes
+/// its end of the connection.
+///
+/// `input` is the raw byte stream, including message headers.
+pub fn handshake(input: &[u8]) -> Result<(), BoxedStdError> {
+    let mut runtime = runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    let input = input.to_vec();
+
+    runtime.block_on(async move {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let listen_addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            let mut remote = TcpStream::connect(listen_addr).await?;
+            remote.write_all(&input).await?;
+            remote.shutdown(Shutdown::Write)?;
+            // Keep reading until the handshake closes the connection
+            remote.read_to_end(&mut Vec::new()).await?;
+            Ok::<(), std::io::Error>(())
+        });
+        let (stream, addr) = listener.accept().await?;
+
+        let inbound = service_fn(|_: Request| async { Ok::<_, BoxedStdError>(Response::Nil) });
+        let (timestamp_collector, _timestamps) = mpsc::channel(100);
+        let address_book = Arc::new(Mutex::new(AddressBook::new(tracing::Span::none())));
+        let mut handshake = Handshake::new(
+            Config::default(),
+            inbound,
+            timestamp_collector,
+            address_book,
+        );
+
+        timeout(HANDSHAKE_TIMEOUT, handshake.call((stream, addr))).await??;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        protocol::external::{types::Nonce, Message},
+        types::{MetaAddr, PeerServices},
+    };
+
+    /// Returns the body of `message`, encoded without its header.
+    fn encoded_body(message: Message) -> Vec<u8> {
+        let mut bytes = BytesMut::new();
+        Codec::builder()
+            .finish()
+            .encode(message, &mut bytes)
+            .expect("message encodes");
+        bytes[HEADER_LEN..].to_vec()
+    }
+
+    #[test]
+    fn fuzz_entry_points_accept_valid_messages() {
+        zebra_test::init();
+
+        let addr = "203.0.113.6:8233".parse().unwrap();
+        let version = Message::Version {
+            version: constants::CURRENT_VERSION,
+            services: PeerServices::NODE_NETWORK,
+            timestamp: Utc.timestamp(1_568_000_000, 0),
+            address_recv: (PeerServices::NODE_NETWORK, addr),
+            address_from: (PeerServices::NODE_NETWORK, addr),
+            nonce: Nonce(0x9082_4908_8927_9238),
+            user_agent: "Zebra".to_owned(),
+            start_height: zebra_chain::types::BlockHeight(540_000),
+            relay: true,
+        };
+        let addrs = Message::Addr(vec![MetaAddr {
+            addr,
+            services: PeerServices::NODE_NETWORK,
+            last_seen: Utc.timestamp(1_568_000_000, 0),
+        }]);
+
+        version_message(&encoded_body(version)).expect("valid version decodes");
+        addr_message(&encoded_body(addrs)).expect("valid addr decodes");
+
+        assert!(version_message(&[]).is_err());
+        assert!(handshake(&[]).is_err());
+    }
+}
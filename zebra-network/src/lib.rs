@@ -57,11 +57,15 @@ pub type BoxedStdError = Box<dyn std::error::Error + Send + Sync + 'static>;
 mod address_book;
 mod config;
 mod constants;
+#[doc(hidden)]
+pub mod fuzz;
 mod meta_addr;
 mod peer;
 mod peer_set;
 mod policies;
 mod protocol;
+pub mod recoverable;
+mod redact;
 mod timestamp_collector;
 
 pub use crate::{
@@ -70,6 +74,7 @@ pub use crate::{
     peer_set::init,
     policies::{RetryErrors, RetryLimit},
     protocol::internal::{Request, Response},
+    redact::PeerAddrRedactor,
 };
 
 /// Types used in the definition of [`Request`] and [`Response`] messages.
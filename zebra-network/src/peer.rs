@@ -1,5 +1,7 @@
 //! Peer handling.
 
+/// Adaptive block request batch sizes.
+mod block_batch;
 /// Handles outbound requests from our node to the network.
 mod client;
 /// The per-peer connection state machine.
@@ -10,7 +12,10 @@ mod connector;
 mod error;
 /// Performs peer handshakes.
 mod handshake;
+/// Peer set keys, with the services and height each peer advertised.
+mod key;
 
+use block_batch::BlockBatchSize;
 use client::ClientRequest;
 use error::ErrorSlot;
 
@@ -19,3 +24,4 @@ pub use connection::Connection;
 pub use connector::Connector;
 pub use error::{HandshakeError, PeerError, SharedPeerError};
 pub use handshake::Handshake;
+pub use key::PeerKey;
@@ -0,0 +1,95 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::constants;
+
+/// The number of blocks requested in each `getdata` message to a peer.
+///
+/// Starts at the configured minimum. Grows by one block after each batch
+/// that arrives in time, and halves after each failed batch, such as a
+/// timeout. Always stays within the configured bounds.
+#[derive(Clone, Debug)]
+pub(super) struct BlockBatchSize {
+    current: Arc<AtomicUsize>,
+    min: usize,
+    max: usize,
+}
+
+impl BlockBatchSize {
+    /// Returns a new batch size, with bounds `min` and `max`.
+    ///
+    /// Batches always contain at least one block, and the maximum is raised
+    /// to the minimum if needed.
+    pub(super) fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+
+        BlockBatchSize {
+            current: Arc::new(AtomicUsize::new(min)),
+            min,
+            max,
+        }
+    }
+
+    /// Returns the current batch size.
+    pub(super) fn get(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Update the batch size after a batch that took `elapsed`, and
+    /// succeeded if `success` is true.
+    pub(super) fn update(&self, success: bool, elapsed: Duration) {
+        let current = self.get();
+        let next = if !success {
+            (current / 2).max(self.min)
+        } else if elapsed <= constants::TIMELY_BLOCK_BATCH {
+            (current + 1).min(self.max)
+        } else {
+            current
+        };
+
+        if next != current {
+            trace!(current, next, "updated block batch size");
+            self.current.store(next, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_size_grows_and_shrinks_within_bounds() {
+        zebra_test::init();
+
+        let fast = Duration::from_millis(100);
+        let slow = constants::TIMELY_BLOCK_BATCH + Duration::from_secs(1);
+        let size = BlockBatchSize::new(2, 5);
+        assert_eq!(size.get(), 2);
+
+        size.update(true, fast);
+        assert_eq!(size.get(), 3);
+        size.update(true, slow);
+        assert_eq!(size.get(), 3);
+        for _ in 0..10 {
+            size.update(true, fast);
+        }
+        assert_eq!(size.get(), 5);
+
+        size.update(false, fast);
+        assert_eq!(size.get(), 2);
+        size.update(false, fast);
+        assert_eq!(size.get(), 2);
+
+        let size = BlockBatchSize::new(0, 0);
+        assert_eq!(size.get(), 1);
+        size.update(true, fast);
+        assert_eq!(size.get(), 1);
+    }
+}
@@ -1,18 +1,25 @@
 use std::{
+    collections::HashSet,
     future::Future,
     pin::Pin,
     task::{Context, Poll},
+    time::Instant,
 };
 
 use futures::{
     channel::{mpsc, oneshot},
-    future, ready,
+    future, ready, SinkExt,
 };
 use tower::Service;
 
-use crate::protocol::internal::{Request, Response};
+use zebra_chain::{block::BlockHeaderHash, types::BlockHeight};
 
-use super::{ErrorSlot, SharedPeerError};
+use crate::protocol::{
+    internal::{Request, Response},
+    types::PeerServices,
+};
+
+use super::{BlockBatchSize, ErrorSlot, SharedPeerError};
 
 /// The "client" duplex half of a peer connection.
 pub struct Client {
@@ -21,6 +28,13 @@ pub struct Client {
     pub(super) shutdown_tx: Option<oneshot::Sender<()>>,
     pub(super) server_tx: mpsc::Sender<ClientRequest>,
     pub(super) error_slot: ErrorSlot,
+    /// The services the remote peer advertised in its handshake.
+    pub(super) remote_services: PeerServices,
+    /// The best block height the remote peer advertised in its handshake.
+    pub(super) remote_height: BlockHeight,
+    /// The number of blocks to request from the remote peer in each
+    /// `getdata` message.
+    pub(super) block_batch_size: BlockBatchSize,
 }
 
 /// A message from the `peer::Client` to the `peer::Server`.
@@ -54,6 +68,21 @@ impl Service<Request> for Client {
     }
 
     fn call(&mut self, request: Request) -> Self::Future {
+        match request {
+            Request::BlocksByHash(hashes) | Request::BlocksByHashFromHeight { hashes, .. } => {
+                self.call_blocks(hashes)
+            }
+            request => self.send(request),
+        }
+    }
+}
+
+impl Client {
+    /// Send `request` to the connection, and return a future for its
+    /// response.
+    ///
+    /// The caller must have called `poll_ready`.
+    fn send(&mut self, request: Request) -> <Self as Service<Request>>::Future {
         use futures::future::FutureExt;
 
         let (tx, rx) = oneshot::channel();
@@ -87,6 +116,81 @@ impl Service<Request> for Client {
             }
         }
     }
+
+    /// Request the blocks with `hashes`, in batches of the current block
+    /// batch size.
+    ///
+    /// Each batch is sent after the previous batch arrives, and the batch
+    /// size is updated after each batch.
+    fn call_blocks(
+        &mut self,
+        hashes: HashSet<BlockHeaderHash>,
+    ) -> <Self as Service<Request>>::Future {
+        use futures::future::FutureExt;
+
+        let batch_size = self.block_batch_size.clone();
+        let mut hashes: Vec<BlockHeaderHash> = hashes.into_iter().collect();
+        let first_batch = next_batch(&mut hashes, batch_size.get());
+        let mut sent_at = Instant::now();
+        let mut response = self.send(Request::BlocksByHash(first_batch));
+
+        // Only clone the request channel if we need it for later batches,
+        // see constants.rs for more.
+        let mut server_tx = if hashes.is_empty() {
+            None
+        } else {
+            Some(self.server_tx.clone())
+        };
+        let error_slot = self.error_slot.clone();
+        let span = tracing::Span::current();
+
+        async move {
+            let mut blocks = Vec::new();
+            loop {
+                let result = response.await;
+                batch_size.update(result.is_ok(), sent_at.elapsed());
+                match result {
+                    Ok(Response::Blocks(batch)) => blocks.extend(batch),
+                    Ok(_) => {
+                        unreachable!("BlocksByHash requests can only result in Response::Blocks")
+                    }
+                    Err(error) => return Err(error),
+                }
+                if hashes.is_empty() {
+                    return Ok(Response::Blocks(blocks));
+                }
+
+                let (tx, rx) = oneshot::channel();
+                let request = ClientRequest {
+                    request: Request::BlocksByHash(next_batch(&mut hashes, batch_size.get())),
+                    tx,
+                    span: span.clone(),
+                };
+                sent_at = Instant::now();
+                let server_tx = server_tx
+                    .as_mut()
+                    .expect("the channel is cloned when there are more batches");
+                if server_tx.send(request).await.is_err() {
+                    return Err(error_slot
+                        .try_get_error()
+                        .expect("failed servers must set their error slot"));
+                }
+                response = rx
+                    .map(|oneshot_recv_result| {
+                        oneshot_recv_result
+                            .expect("ClientRequest oneshot sender must not be dropped before send")
+                    })
+                    .boxed();
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Remove up to `size` hashes from `hashes`, and return them.
+fn next_batch(hashes: &mut Vec<BlockHeaderHash>, size: usize) -> HashSet<BlockHeaderHash> {
+    let start = hashes.len().saturating_sub(size);
+    hashes.drain(start..).collect()
 }
 
 impl Drop for Client {
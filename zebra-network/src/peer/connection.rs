@@ -38,7 +38,7 @@ use crate::{
         external::{types::Nonce, InventoryHash, Message},
         internal::{Request, Response},
     },
-    BoxedStdError,
+    recoverable, BoxedStdError,
 };
 
 use super::{ClientRequest, ErrorSlot, PeerError, SharedPeerError};
@@ -53,6 +53,7 @@ pub(super) enum Handler {
         blocks: Vec<Arc<Block>>,
     },
     FindBlocks,
+    FindHeaders,
 }
 
 impl Handler {
@@ -107,6 +108,16 @@ impl Handler {
                     })
                     .collect(),
             ))),
+            (FindHeaders, Message::Headers(headers)) => {
+                let is_chain = headers
+                    .windows(2)
+                    .all(|pair| pair[1].previous_block_hash == BlockHeaderHash::from(&pair[0]));
+                if is_chain {
+                    Finished(Ok(Response::BlockHeaders(headers)))
+                } else {
+                    Finished(Err(PeerError::WrongHeaders.into()))
+                }
+            }
             // By default, messages are not responses.
             (state, msg) => {
                 trace!(?msg, "did not interpret message as response");
@@ -366,7 +377,8 @@ where
                     tx,
                     span,
                 }),
-            (AwaitingRequest, BlocksByHash(hashes)) => self
+            (AwaitingRequest, BlocksByHash(hashes))
+            | (AwaitingRequest, BlocksByHashFromHeight { hashes, .. }) => self
                 .peer_tx
                 .send(Message::GetData(
                     hashes.iter().map(|h| (*h).into()).collect(),
@@ -394,6 +406,30 @@ where
                     tx,
                     span,
                 }),
+            (AwaitingRequest, FindHeaders { known_blocks, stop }) => self
+                .peer_tx
+                .send(Message::GetHeaders {
+                    block_locator_hashes: known_blocks,
+                    hash_stop: stop.unwrap_or(BlockHeaderHash([0; 32])),
+                })
+                .await
+                .map_err(|e| e.into())
+                .map(|()| AwaitingResponse {
+                    handler: Handler::FindHeaders,
+                    tx,
+                    span,
+                }),
+            (AwaitingRequest, PushTransaction(transaction)) => self
+                .peer_tx
+                .send(Message::Tx(transaction))
+                .await
+                .map_err(|e| e.into())
+                .map(|()| {
+                    // Peers don't respond to `tx` messages, so there's no
+                    // response to wait for
+                    let _ = tx.send(Ok(Response::Nil));
+                    AwaitingRequest
+                }),
         } {
             Ok(new_state) => {
                 self.state = new_state;
@@ -449,6 +485,35 @@ where
                 None
             }
             Message::GetAddr => Some(Request::Peers),
+            Message::GetBlocks {
+                block_locator_hashes,
+                hash_stop,
+            } => Some(Request::FindBlocks {
+                known_blocks: block_locator_hashes,
+                stop: stop_hash(hash_stop),
+            }),
+            Message::GetHeaders {
+                block_locator_hashes,
+                hash_stop,
+            } => Some(Request::FindHeaders {
+                known_blocks: block_locator_hashes,
+                stop: stop_hash(hash_stop),
+            }),
+            Message::GetData(items) => {
+                let hashes: HashSet<_> = items
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        InventoryHash::Block(hash) => Some(hash),
+                        _ => None,
+                    })
+                    .collect();
+                if hashes.is_empty() {
+                    debug!("ignoring getdata message without blocks");
+                    None
+                } else {
+                    Some(Request::BlocksByHash(hashes))
+                }
+            }
             _ => {
                 debug!("unhandled message type");
                 None
@@ -474,7 +539,7 @@ where
             self.fail_with(PeerError::Overloaded);
         }
 
-        let rsp = match self.svc.call(req).await {
+        let rsp = match recoverable::unrecoverable(self.svc.call(req)).await {
             Err(e) => {
                 if e.is::<Overloaded>() {
                     self.fail_with(PeerError::Overloaded);
@@ -502,6 +567,21 @@ where
                     }
                 }
             }
+            Response::RawBlocks(blocks) => {
+                // Send the stored bytes, without serializing the blocks again
+                for bytes in blocks.into_iter() {
+                    if let Err(e) = self.peer_tx.send(Message::RawBlock(bytes)).await {
+                        self.fail_with(e.into());
+                    }
+                }
+            }
+            Response::BlockHeaders(headers) => {
+                // Always respond, even if there are no headers, because
+                // peers wait for a `headers` message
+                if let Err(e) = self.peer_tx.send(Message::Headers(headers)).await {
+                    self.fail_with(e.into())
+                }
+            }
             Response::BlockHeaderHashes(hashes) => {
                 if let Err(e) = self
                     .peer_tx
@@ -514,3 +594,13 @@ where
         }
     }
 }
+
+/// Returns the stop hash in a `getblocks` or `getheaders` message, or `None`
+/// if it is zero, which means "as many as possible".
+fn stop_hash(hash_stop: BlockHeaderHash) -> Option<BlockHeaderHash> {
+    if hash_stop == BlockHeaderHash([0; 32]) {
+        None
+    } else {
+        Some(hash_stop)
+    }
+}
@@ -11,7 +11,7 @@ use tower::{discover::Change, Service, ServiceExt};
 
 use crate::{BoxedStdError, Request, Response};
 
-use super::{Client, Handshake};
+use super::{Client, Handshake, PeerKey};
 
 /// A wrapper around [`peer::Handshake`] that opens a TCP connection before
 /// forwarding to the inner handshake service. Writing this as its own
@@ -39,7 +39,7 @@ where
     S: Service<Request, Response = Response, Error = BoxedStdError> + Clone + Send + 'static,
     S::Future: Send,
 {
-    type Response = Change<SocketAddr, Client>;
+    type Response = Change<PeerKey, Client>;
     type Error = BoxedStdError;
     type Future =
         Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
@@ -54,7 +54,7 @@ where
             let stream = TcpStream::connect(addr).await?;
             hs.ready_and().await?;
             let client = hs.call((stream, addr)).await?;
-            Ok(Change::Insert(addr, client))
+            Ok(Change::Insert(PeerKey::new(addr, &client), client))
         }
         .boxed()
     }
@@ -55,6 +55,9 @@ pub enum PeerError {
     /// The remote peer responded with a block we didn't ask for.
     #[error("Remote peer responded with a block we didn't ask for.")]
     WrongBlock,
+    /// The remote peer responded with headers that don't form a chain.
+    #[error("Remote peer responded with headers that don't form a chain.")]
+    WrongHeaders,
 }
 
 #[derive(Default, Clone)]
@@ -26,11 +26,12 @@ use crate::{
         external::{types::*, Codec, Message},
         internal::{Request, Response},
     },
+    recoverable::recoverable,
     types::MetaAddr,
-    BoxedStdError, Config,
+    AddressBook, BoxedStdError, Config, PeerAddrRedactor,
 };
 
-use super::{Client, Connection, ErrorSlot, HandshakeError};
+use super::{BlockBatchSize, Client, Connection, ErrorSlot, HandshakeError};
 
 /// A [`Service`] that handshakes with a remote peer and constructs a
 /// client/server pair.
@@ -38,6 +39,7 @@ pub struct Handshake<S> {
     config: Config,
     internal_service: S,
     timestamp_collector: mpsc::Sender<MetaAddr>,
+    address_book: Arc<Mutex<AddressBook>>,
     nonces: Arc<Mutex<HashSet<Nonce>>>,
 }
 
@@ -47,6 +49,7 @@ impl<S: Clone> Clone for Handshake<S> {
             config: self.config.clone(),
             internal_service: self.internal_service.clone(),
             timestamp_collector: self.timestamp_collector.clone(),
+            address_book: self.address_book.clone(),
             nonces: self.nonces.clone(),
         }
     }
@@ -62,6 +65,7 @@ where
         config: Config,
         internal_service: S,
         timestamp_collector: mpsc::Sender<MetaAddr>,
+        address_book: Arc<Mutex<AddressBook>>,
     ) -> Self {
         // XXX this function has too many parameters, but it's not clear how to
         // do a nice builder as all fields are mandatory. Could have Builder1,
@@ -72,6 +76,7 @@ where
             config,
             internal_service,
             timestamp_collector,
+            address_book,
             nonces: Arc::new(Mutex::new(HashSet::new())),
         }
     }
@@ -94,18 +99,29 @@ where
     fn call(&mut self, req: (TcpStream, SocketAddr)) -> Self::Future {
         let (tcp_stream, addr) = req;
 
-        let connector_span = span!(Level::INFO, "connector", addr = ?addr);
+        let redactor = PeerAddrRedactor::new(&self.config);
+        let log_addr = redactor.log(&addr);
+        let metric_addr = redactor.metric_label(&addr);
+
+        let connector_span = span!(Level::INFO, "connector", addr = %log_addr);
         // set parent: None for the peer connection span, as it should exist
         // independently of its creation source (inbound connection, crawler,
         // initial peer, ...)
-        let connection_span = span!(parent: None, Level::INFO, "peer", addr = ?addr);
+        let connection_span = span!(parent: None, Level::INFO, "peer", addr = %log_addr);
 
         // Clone these upfront, so they can be moved into the future.
         let nonces = self.nonces.clone();
         let internal_service = self.internal_service.clone();
         let timestamp_collector = self.timestamp_collector.clone();
+        let address_book = self.address_book.clone();
         let user_agent = self.config.user_agent.clone();
         let network = self.config.network;
+        let external_addr = self.config.external_addr;
+        let services = self.config.services;
+        let block_batch_size = BlockBatchSize::new(
+            self.config.min_block_batch_size,
+            self.config.max_block_batch_size,
+        );
 
         let fut = async move {
             debug!("connecting to remote peer");
@@ -121,12 +137,13 @@ where
 
             let version = Message::Version {
                 version: constants::CURRENT_VERSION,
-                services: PeerServices::NODE_NETWORK,
+                services,
                 timestamp: Utc::now(),
                 address_recv: (PeerServices::NODE_NETWORK, addr),
-                // TODO: when we've implemented block and transaction relaying,
-                //       send our configured address to the peer
-                address_from: (PeerServices::NODE_NETWORK, "0.0.0.0:8233".parse().unwrap()),
+                address_from: (
+                    services,
+                    external_addr.unwrap_or_else(|| "0.0.0.0:8233".parse().unwrap()),
+                ),
                 nonce: local_nonce,
                 user_agent,
                 // XXX eventually the `PeerConnector` will need to have a handle
@@ -147,17 +164,19 @@ where
 
             // Check that we got a Version and destructure its fields into the local scope.
             debug!(?remote_msg, "got message from remote peer");
-            let (remote_nonce, remote_services, remote_version) = if let Message::Version {
-                nonce,
-                services,
-                version,
-                ..
-            } = remote_msg
-            {
-                (nonce, services, version)
-            } else {
-                return Err(HandshakeError::UnexpectedMessage(Box::new(remote_msg)));
-            };
+            let (remote_nonce, remote_services, remote_version, remote_height) =
+                if let Message::Version {
+                    nonce,
+                    services,
+                    version,
+                    start_height,
+                    ..
+                } = remote_msg
+                {
+                    (nonce, services, version, start_height)
+                } else {
+                    return Err(HandshakeError::UnexpectedMessage(Box::new(remote_msg)));
+                };
 
             // Check for nonce reuse, indicating self-connection.
             let nonce_reuse = {
@@ -183,6 +202,19 @@ where
                 return Err(HandshakeError::UnexpectedMessage(Box::new(remote_msg)));
             }
 
+            // Advertise our external address, so the peer can gossip it to
+            // other nodes.
+            if let Some(external_addr) = external_addr {
+                let local_addr = MetaAddr {
+                    addr: external_addr,
+                    services,
+                    last_seen: Utc::now(),
+                };
+                stream
+                    .send(Message::Addr(vec![local_addr.sanitize()]))
+                    .await?;
+            }
+
             // XXX in zcashd remote peer can only send one version message and
             // we would disconnect here if it received a second one. Is it even possible
             // for that to happen to us here?
@@ -220,6 +252,12 @@ where
             let bare_codec = stream.codec_mut();
             bare_codec.reconfigure_version(negotiated_version);
 
+            // Used to estimate how far our chain tip is behind the network
+            address_book
+                .lock()
+                .expect("mutex should be unpoisoned")
+                .record_height(addr, remote_height);
+
             debug!("constructing client, spawning server");
 
             // These channels should not be cloned more than they are
@@ -232,16 +270,24 @@ where
                 shutdown_tx: Some(shutdown_tx),
                 server_tx: server_tx.clone(),
                 error_slot: slot.clone(),
+                remote_services,
+                remote_height,
+                block_batch_size,
             };
 
             let (peer_tx, peer_rx) = stream.split();
 
             // Instrument the peer's rx and tx streams.
 
+            let outbound_metric_addr = metric_addr.clone();
             let peer_tx = peer_tx.with(move |msg: Message| {
                 // Add a metric for outbound messages.
                 // XXX add a dimension tagging message metrics by type
-                metrics::counter!("peer.outbound_messages", 1, "addr" => addr.to_string());
+                metrics::counter!(
+                    "peer.outbound_messages",
+                    1,
+                    "addr" => outbound_metric_addr.clone(),
+                );
                 // We need to use future::ready rather than an async block here,
                 // because we need the sink to be Unpin, and the With<Fut, ...>
                 // returned by .with is Unpin only if Fut is Unpin, and the
@@ -253,13 +299,14 @@ where
                 .then(move |msg| {
                     // Add a metric for inbound messages and fire a timestamp event.
                     let mut timestamp_collector = timestamp_collector.clone();
+                    let metric_addr = metric_addr.clone();
                     async move {
                         if msg.is_ok() {
                             // XXX add a dimension tagging message metrics by type
                             metrics::counter!(
                                 "inbound_messages",
                                 1,
-                                "addr" => addr.to_string(),
+                                "addr" => metric_addr,
                             );
                             use futures::sink::SinkExt;
                             let _ = timestamp_collector
@@ -285,16 +332,16 @@ where
                 request_timer: None,
             };
 
+            // A panic in a connection only closes that connection
             tokio::spawn(
-                server
-                    .run(peer_rx)
+                recoverable(server.run(peer_rx))
                     .instrument(connection_span.clone())
                     .boxed(),
             );
 
             let heartbeat_span = tracing::debug_span!(parent: connection_span, "heartbeat");
             tokio::spawn(
-                async move {
+                recoverable(async move {
                     use super::client::ClientRequest;
                     use futures::future::Either;
 
@@ -324,7 +371,7 @@ where
                             Either::Right(_) => return, // got shutdown signal
                         }
                     }
-                }
+                })
                 .instrument(heartbeat_span)
                 .boxed(),
             );
@@ -333,7 +380,7 @@ where
         };
 
         // Spawn a new task to drive this handshake.
-        tokio::spawn(fut.instrument(connector_span))
+        tokio::spawn(recoverable(fut).instrument(connector_span))
             // This is required to get error types to line up.
             // Probably there's a nicer way to express this using combinators.
             .map(|x| match x {
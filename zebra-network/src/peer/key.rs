@@ -0,0 +1,63 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+};
+
+use zebra_chain::types::BlockHeight;
+
+use crate::protocol::types::PeerServices;
+
+use super::Client;
+
+/// The key for a connected peer in the peer set.
+///
+/// Keys also contain the services and best block height that the peer
+/// advertised in its `version` message, so the peer set can route requests
+/// to peers that can answer them.
+///
+/// Keys are compared and hashed by address, so a new connection to the same
+/// address replaces the old connection.
+#[derive(Copy, Clone, Debug)]
+pub struct PeerKey {
+    /// The address of the peer
+    pub addr: SocketAddr,
+    /// The services advertised by the peer
+    pub services: PeerServices,
+    /// The best block height advertised by the peer
+    ///
+    /// Peers don't update their height after the handshake, so long-lived
+    /// connections can have a much higher best block height.
+    pub start_height: BlockHeight,
+}
+
+impl PeerKey {
+    /// Returns the key for `client`, which is connected to `addr`.
+    pub fn new(addr: SocketAddr, client: &Client) -> Self {
+        PeerKey {
+            addr,
+            services: client.remote_services,
+            start_height: client.remote_height,
+        }
+    }
+}
+
+impl PartialEq for PeerKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
+    }
+}
+
+impl Eq for PeerKey {}
+
+impl Hash for PeerKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state)
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
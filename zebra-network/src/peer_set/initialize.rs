@@ -4,8 +4,11 @@
 // which is (c) 2019 Tower Contributors (MIT licensed).
 
 use std::{
+    io,
     net::SocketAddr,
+    panic::AssertUnwindSafe,
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
 use futures::{
@@ -14,6 +17,7 @@ use futures::{
     sink::SinkExt,
     stream::{FuturesUnordered, StreamExt},
 };
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::{TcpListener, TcpStream};
 use tower::{
     buffer::Buffer,
@@ -24,8 +28,8 @@ use tower::{
 use tower_load::{peak_ewma::PeakEwmaDiscover, NoInstrument};
 
 use crate::{
-    peer, timestamp_collector::TimestampCollector, AddressBook, BoxedStdError, Config, Request,
-    Response,
+    constants, peer, recoverable::recoverable, timestamp_collector::TimestampCollector,
+    AddressBook, BoxedStdError, Config, PeerAddrRedactor, Request, Response,
 };
 
 use zebra_chain::Network::*;
@@ -33,7 +37,7 @@ use zebra_chain::Network::*;
 use super::CandidateSet;
 use super::PeerSet;
 
-type PeerChange = Result<Change<SocketAddr, peer::Client>, BoxedStdError>;
+type PeerChange = Result<Change<peer::PeerKey, peer::Client>, BoxedStdError>;
 
 /// Initialize a peer set with the given `config`, forwarding peer requests to the `inbound_service`.
 pub async fn init<S>(
@@ -55,6 +59,7 @@ where
     S::Future: Send + 'static,
 {
     let (address_book, timestamp_collector) = TimestampCollector::spawn();
+    let redactor = PeerAddrRedactor::new(&config);
 
     // Construct services that handle inbound handshakes and perform outbound
     // handshakes. These use the same handshake service internally to detect
@@ -63,7 +68,12 @@ where
     let (listener, connector) = {
         use tower::timeout::TimeoutLayer;
         let hs_timeout = TimeoutLayer::new(config.handshake_timeout);
-        let hs = peer::Handshake::new(config.clone(), inbound_service, timestamp_collector);
+        let hs = peer::Handshake::new(
+            config.clone(),
+            inbound_service,
+            timestamp_collector,
+            address_book.clone(),
+        );
         (
             hs_timeout.layer(hs.clone()),
             hs_timeout.layer(peer::Connector::new(hs)),
@@ -90,6 +100,7 @@ where
         ),
         demand_tx.clone(),
         handle_rx,
+        redactor,
     );
     let peer_set = Buffer::new(peer_set, config.peerset_request_buffer_size);
 
@@ -100,6 +111,7 @@ where
         config.initial_peers(),
         connector.clone(),
         peerset_tx.clone(),
+        redactor,
     ));
 
     // 2. Incoming peer connections, via a listener.
@@ -111,16 +123,33 @@ where
         Mainnet => (Testnet, 18233),
         Testnet => (Mainnet, 8233),
     };
-    if config.listen_addr.port() == wrong_net_port {
-        warn!(
-            "We are configured with port {} for {:?}, but that port is the default port for {:?}",
-            config.listen_addr.port(),
-            config.network,
-            wrong_net
-        );
-    }
+    let listen_addrs: Vec<_> = config.listen_addrs().collect();
+    let mut listen_guards = Vec::new();
+    for &listen_addr in &listen_addrs {
+        if listen_addr.port() == wrong_net_port {
+            warn!(
+                "We are configured with port {} for {:?}, but that port is the default port for {:?}",
+                listen_addr.port(),
+                config.network,
+                wrong_net
+            );
+        }
 
-    let listen_guard = tokio::spawn(listen(config.listen_addr, listener, peerset_tx.clone()));
+        // Listen for IPv4 and IPv6 connections on separate sockets, if both
+        // are configured with the same port
+        let only_v6 = listen_addr.is_ipv6()
+            && listen_addrs
+                .iter()
+                .any(|other| other.is_ipv4() && other.port() == listen_addr.port());
+
+        listen_guards.push(tokio::spawn(listen(
+            listen_addr,
+            only_v6,
+            listener.clone(),
+            peerset_tx.clone(),
+            redactor,
+        )));
+    }
 
     // 3. Outgoing peers we connect to in response to load.
     let mut candidates = CandidateSet::new(address_book.clone(), peer_set.clone());
@@ -137,36 +166,43 @@ where
         let _ = demand_tx.try_send(());
     }
 
-    let crawl_guard = tokio::spawn(crawl_and_dial(
+    let crawl_guard = tokio::spawn(supervise_crawler(
         config.new_peer_interval,
+        constants::MIN_CRAWLER_RESTART_DELAY,
         demand_tx,
         demand_rx,
         candidates,
         connector,
         peerset_tx,
+        redactor,
     ));
 
-    handle_tx
-        .send(vec![add_guard, listen_guard, crawl_guard])
-        .unwrap();
+    let mut guards = vec![add_guard, crawl_guard];
+    guards.extend(listen_guards);
+    handle_tx.send(guards).unwrap();
 
     (peer_set, address_book)
 }
 
 /// Use the provided `handshaker` to connect to `initial_peers`, then send
 /// the results over `tx`.
-#[instrument(skip(initial_peers, connector, tx))]
+#[instrument(skip(initial_peers, connector, tx, redactor))]
 async fn add_initial_peers<S>(
     initial_peers: std::collections::HashSet<SocketAddr>,
     connector: S,
     mut tx: mpsc::Sender<PeerChange>,
+    redactor: PeerAddrRedactor,
 ) -> Result<(), BoxedStdError>
 where
-    S: Service<SocketAddr, Response = Change<SocketAddr, peer::Client>, Error = BoxedStdError>
+    S: Service<SocketAddr, Response = Change<peer::PeerKey, peer::Client>, Error = BoxedStdError>
         + Clone,
     S::Future: Send + 'static,
 {
-    info!(?initial_peers, "Connecting to initial peer set");
+    let logged_peers: Vec<_> = initial_peers
+        .iter()
+        .map(|addr| redactor.log(addr))
+        .collect();
+    info!(initial_peers = ?logged_peers, "Connecting to initial peer set");
     use tower::util::CallAllUnordered;
     let addr_stream = futures::stream::iter(initial_peers.into_iter());
     let mut handshakes = CallAllUnordered::new(connector, addr_stream);
@@ -180,20 +216,24 @@ where
 
 /// Bind to `addr`, listen for peers using `handshaker`, then send the
 /// results over `tx`.
-#[instrument(skip(tx, handshaker))]
+///
+/// If `only_v6` is true, the listener only accepts IPv6 connections.
+#[instrument(skip(tx, handshaker, redactor))]
 async fn listen<S>(
     addr: SocketAddr,
+    only_v6: bool,
     mut handshaker: S,
     tx: mpsc::Sender<PeerChange>,
+    redactor: PeerAddrRedactor,
 ) -> Result<(), BoxedStdError>
 where
     S: Service<(TcpStream, SocketAddr), Response = peer::Client, Error = BoxedStdError> + Clone,
     S::Future: Send + 'static,
 {
-    let mut listener = TcpListener::bind(addr).await?;
+    let mut listener = TcpListener::from_std(bind_listener(addr, only_v6)?)?;
     loop {
         if let Ok((tcp_stream, addr)) = listener.accept().await {
-            debug!(?addr, "got incoming connection");
+            debug!(addr = %redactor.log(&addr), "got incoming connection");
             handshaker.ready_and().await?;
             // Construct a handshake future but do not drive it yet....
             let handshake = handshaker.call((tcp_stream, addr));
@@ -201,33 +241,131 @@ where
             let mut tx2 = tx.clone();
             tokio::spawn(async move {
                 if let Ok(client) = handshake.await {
-                    let _ = tx2.send(Ok(Change::Insert(addr, client))).await;
+                    let key = peer::PeerKey::new(addr, &client);
+                    let _ = tx2.send(Ok(Change::Insert(key, client))).await;
                 }
             });
         }
     }
 }
 
-/// Given a channel that signals a need for new peers, try to connect to a peer
-/// and send the resulting `peer::Client` through a channel.
+/// Open a TCP socket that listens on `addr`.
+///
+/// If `only_v6` is true, the socket only accepts IPv6 connections, so an
+/// IPv4 socket can listen on the same port.
+fn bind_listener(addr: SocketAddr, only_v6: bool) -> io::Result<std::net::TcpListener> {
+    let domain = if addr.is_ipv6() {
+        Domain::ipv6()
+    } else {
+        Domain::ipv4()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    // Match the behaviour of `tokio::net::TcpListener::bind`, so restarted
+    // nodes can listen on the same port
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into_tcp_listener())
+}
+
+/// Run the peer crawler, restarting it with backoff if it fails or panics.
+///
+/// The first restart happens after `min_delay`. The crawler exits when the
+/// peer set is dropped, or the demand channel is closed. Restarts are counted
+/// in the `component.restart.count` metric.
 #[instrument(skip(
     new_peer_interval,
+    min_delay,
     demand_tx,
     demand_rx,
     candidates,
     connector,
-    success_tx
+    success_tx,
+    redactor
 ))]
-async fn crawl_and_dial<C, S>(
+async fn supervise_crawler<C, S>(
     new_peer_interval: std::time::Duration,
+    min_delay: std::time::Duration,
     mut demand_tx: mpsc::Sender<()>,
     mut demand_rx: mpsc::Receiver<()>,
     mut candidates: CandidateSet<S>,
-    mut connector: C,
+    connector: C,
     mut success_tx: mpsc::Sender<PeerChange>,
+    redactor: PeerAddrRedactor,
 ) -> Result<(), BoxedStdError>
 where
-    C: Service<SocketAddr, Response = Change<SocketAddr, peer::Client>, Error = BoxedStdError>
+    C: Service<SocketAddr, Response = Change<peer::PeerKey, peer::Client>, Error = BoxedStdError>
+        + Clone,
+    C::Future: Send + 'static,
+    S: Service<Request, Response = Response, Error = BoxedStdError>,
+    S::Future: Send + 'static,
+{
+    let mut delay = min_delay;
+
+    loop {
+        let started = Instant::now();
+        let crawler = crawl_and_dial(
+            new_peer_interval,
+            &mut demand_tx,
+            &mut demand_rx,
+            &mut candidates,
+            connector.clone(),
+            &mut success_tx,
+            &redactor,
+        );
+        // Crawler panics are recoverable, because the crawler only uses the
+        // address book and the peer set
+        match AssertUnwindSafe(recoverable(crawler)).catch_unwind().await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(error)) => warn!(?error, "peer crawler failed"),
+            Err(_) => error!("peer crawler panicked"),
+        }
+
+        if success_tx.is_closed() {
+            return Ok(());
+        }
+
+        if started.elapsed() > constants::MAX_CRAWLER_RESTART_DELAY {
+            delay = min_delay;
+        }
+        info!(?delay, "restarting peer crawler");
+        tokio::time::delay_for(delay).await;
+        delay = (delay * 2).min(constants::MAX_CRAWLER_RESTART_DELAY);
+
+        metrics::counter!("component.restart.count", 1, "component" => "crawler");
+    }
+}
+
+/// Given a channel that signals a need for new peers, try to connect to a peer
+/// and send the resulting `peer::Client` through a channel.
+#[instrument(skip(
+    new_peer_interval,
+    demand_tx,
+    demand_rx,
+    candidates,
+    connector,
+    success_tx,
+    redactor
+))]
+async fn crawl_and_dial<C, S>(
+    new_peer_interval: std::time::Duration,
+    demand_tx: &mut mpsc::Sender<()>,
+    demand_rx: &mut mpsc::Receiver<()>,
+    candidates: &mut CandidateSet<S>,
+    mut connector: C,
+    success_tx: &mut mpsc::Sender<PeerChange>,
+    redactor: &PeerAddrRedactor,
+) -> Result<(), BoxedStdError>
+where
+    C: Service<SocketAddr, Response = Change<peer::PeerKey, peer::Client>, Error = BoxedStdError>
         + Clone,
     C::Future: Send + 'static,
     S: Service<Request, Response = Response, Error = BoxedStdError>,
@@ -266,7 +404,10 @@ where
                     continue;
                 }
                 if let Some(candidate) = candidates.next() {
-                    debug!(?candidate.addr, "attempting outbound connection in response to demand");
+                    debug!(
+                        candidate.addr = %redactor.log(&candidate.addr),
+                        "attempting outbound connection in response to demand"
+                    );
                     connector.ready_and().await?;
                     handshakes.push(
                         connector
@@ -290,13 +431,13 @@ where
             }
             Right((Some(Ok(change)), _)) => {
                 // in fact all changes are Insert so this branch is always taken
-                if let Change::Insert(ref addr, _) = change {
-                    debug!(candidate.addr = ?addr, "successfully dialed new peer");
+                if let Change::Insert(ref key, _) = change {
+                    debug!(candidate.addr = %redactor.log(key), "successfully dialed new peer");
                 }
                 success_tx.send(Ok(change)).await?;
             }
             Right((Some(Err(candidate)), _)) => {
-                debug!(?candidate.addr, "failed to connect to peer");
+                debug!(candidate.addr = %redactor.log(&candidate.addr), "failed to connect to peer");
                 candidates.report_failed(candidate);
                 // The demand signal that was taken out of the queue
                 // to attempt to connect to the failed candidate never
@@ -309,3 +450,66 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::runtime::Runtime;
+    use tower::service_fn;
+
+    #[test]
+    fn panicked_crawlers_are_restarted() {
+        zebra_test::init();
+
+        let mut rt = Runtime::new().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        // The first crawler panics the first time it asks for peers
+        let peer_calls = calls.clone();
+        let peer_service = service_fn(move |_req: Request| {
+            if peer_calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("peer crawler panicked");
+            }
+            async { Ok::<_, BoxedStdError>(Response::Peers(Vec::new())) }
+        });
+        let connector = service_fn(|_addr: SocketAddr| async {
+            Err::<Change<peer::PeerKey, peer::Client>, BoxedStdError>("no peers".into())
+        });
+
+        let address_book = Arc::new(Mutex::new(AddressBook::new(span!(
+            tracing::Level::TRACE,
+            "test peers"
+        ))));
+        let candidates = CandidateSet::new(address_book, peer_service);
+        let (demand_tx, demand_rx) = mpsc::channel(1);
+        let (peerset_tx, _peerset_rx) = mpsc::channel::<PeerChange>(1);
+
+        rt.block_on(async {
+            tokio::spawn(supervise_crawler(
+                Duration::from_secs(60 * 60),
+                Duration::from_millis(1),
+                demand_tx,
+                demand_rx,
+                candidates,
+                connector,
+                peerset_tx,
+                PeerAddrRedactor::new(&Config::default()),
+            ));
+
+            // The restarted crawler asks for peers again, as soon as it starts
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while calls.load(Ordering::SeqCst) < 2 {
+                    tokio::time::delay_for(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .expect("the crawler is restarted after it panics");
+        });
+    }
+}
@@ -22,9 +22,12 @@ use tower::{
 };
 use tower_load::Load;
 
+use zebra_chain::types::BlockHeight;
+
 use crate::{
+    peer::PeerKey,
     protocol::internal::{Request, Response},
-    BoxedStdError,
+    BoxedStdError, PeerAddrRedactor,
 };
 
 use super::unready_service::{Error as UnreadyError, UnreadyService};
@@ -88,12 +91,13 @@ where
     /// These guards are checked for errors as part of `poll_ready` which lets
     /// the `PeerSet` propagate errors from background tasks back to the user
     guards: futures::stream::FuturesUnordered<JoinHandle<Result<(), BoxedStdError>>>,
+    /// Formats peer keys for logs and metrics
+    redactor: PeerAddrRedactor,
 }
 
 impl<D> PeerSet<D>
 where
-    D: Discover + Unpin,
-    D::Key: Clone + Debug,
+    D: Discover<Key = PeerKey> + Unpin,
     D::Service: Service<Request, Response = Response> + Load,
     D::Error: Into<BoxedStdError>,
     <D::Service as Service<Request>>::Error: Into<BoxedStdError> + 'static,
@@ -105,6 +109,7 @@ where
         discover: D,
         demand_signal: mpsc::Sender<()>,
         handle_rx: tokio::sync::oneshot::Receiver<Vec<JoinHandle<Result<(), BoxedStdError>>>>,
+        redactor: PeerAddrRedactor,
     ) -> Self {
         Self {
             discover,
@@ -115,6 +120,7 @@ where
             demand_signal,
             guards: futures::stream::FuturesUnordered::new(),
             handle_rx,
+            redactor,
         }
     }
 
@@ -123,11 +129,11 @@ where
         loop {
             match ready!(Pin::new(&mut self.discover).poll_discover(cx)).map_err(Into::into)? {
                 Change::Remove(key) => {
-                    trace!(?key, "got Change::Remove from Discover");
+                    trace!(key = %self.redactor.log(&key), "got Change::Remove from Discover");
                     self.remove(&key);
                 }
                 Change::Insert(key, svc) => {
-                    trace!(?key, "got Change::Insert from Discover");
+                    trace!(key = %self.redactor.log(&key), "got Change::Insert from Discover");
                     self.remove(&key);
                     self.push_unready(key, svc);
                 }
@@ -198,13 +204,13 @@ where
             match Pin::new(&mut self.unready_services).poll_next(cx) {
                 Poll::Pending | Poll::Ready(None) => return,
                 Poll::Ready(Some(Ok((key, svc)))) => {
-                    trace!(?key, "service became ready");
+                    trace!(key = %self.redactor.log(&key), "service became ready");
                     let _cancel = self.cancel_handles.remove(&key);
                     assert!(_cancel.is_some(), "missing cancel handle");
                     self.ready_services.insert(key, svc);
                 }
                 Poll::Ready(Some(Err((key, UnreadyError::Canceled)))) => {
-                    trace!(?key, "service was canceled");
+                    trace!(key = %self.redactor.log(&key), "service was canceled");
                     // This debug assert is invalid because we can have a
                     // service be canceled due us connecting to the same service
                     // twice.
@@ -245,6 +251,75 @@ where
         }
     }
 
+    /// Selects a ready service that can serve blocks at `min_height`, using
+    /// the heights and services that peers advertised in their handshakes.
+    ///
+    /// Peers qualify if their advertised best block height is at least
+    /// `min_height`. Qualifying peers whose advertised services include the
+    /// blocks are preferred: `NODE_NETWORK` peers serve every block, and
+    /// `NODE_NETWORK_LIMITED` peers only serve blocks within
+    /// `HISTORICAL_BLOCK_DEPTH` of their advertised height. Performs P2C on
+    /// the preferred services, unless the `preselected` service is one of
+    /// them.
+    ///
+    /// Returns `preselected` if no ready services qualify.
+    fn select_ready_index_for_height(&self, preselected: usize, min_height: BlockHeight) -> usize {
+        let mut candidates: Vec<usize> = (0..self.ready_services.len())
+            .filter(|&index| self.ready_index_key(index).start_height >= min_height)
+            .collect();
+        let serving: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|&index| {
+                let key = self.ready_index_key(index);
+                key.services.serves_block(min_height, key.start_height)
+            })
+            .collect();
+        let preferred = !serving.is_empty();
+        if preferred {
+            candidates = serving;
+        }
+
+        let selected = match candidates.len() {
+            0 => {
+                trace!(
+                    ?min_height,
+                    "no ready services at height, using preselected service"
+                );
+                preselected
+            }
+            _ if candidates.contains(&preselected) => preselected,
+            1 => candidates[0],
+            len => {
+                let idxs = rand::seq::index::sample(&mut rand::thread_rng(), len, 2);
+                let (a, b) = (candidates[idxs.index(0)], candidates[idxs.index(1)]);
+
+                if self.ready_index_load(a) <= self.ready_index_load(b) {
+                    a
+                } else {
+                    b
+                }
+            }
+        };
+
+        trace!(
+            ?min_height,
+            preferred,
+            candidates = candidates.len(),
+            preselected,
+            selected,
+            "selected service by advertised height"
+        );
+
+        selected
+    }
+
+    /// Accesses a ready endpoint by index and returns its key.
+    fn ready_index_key(&self, index: usize) -> &PeerKey {
+        let (key, _) = self.ready_services.get_index(index).expect("invalid index");
+        key
+    }
+
     /// Accesses a ready endpoint by index and returns its current load.
     fn ready_index_load(&self, index: usize) -> <D::Service as Load>::Metric {
         let (_, svc) = self.ready_services.get_index(index).expect("invalid index");
@@ -254,8 +329,7 @@ where
 
 impl<D> Service<Request> for PeerSet<D>
 where
-    D: Discover + Unpin,
-    D::Key: Clone + Debug + ToString,
+    D: Discover<Key = PeerKey> + Unpin,
     D::Service: Service<Request, Response = Response> + Load,
     D::Error: Into<BoxedStdError>,
     <D::Service as Service<Request>>::Error: Into<BoxedStdError> + 'static,
@@ -292,7 +366,7 @@ where
                     .ready_services
                     .get_index_mut(index)
                     .expect("preselected index must be valid");
-                trace!(preselected_index = index, ?key);
+                trace!(preselected_index = index, key = %self.redactor.log(key));
                 match service.poll_ready(cx) {
                     Poll::Ready(Ok(())) => return Poll::Ready(Ok(())),
                     Poll::Pending => {
@@ -325,20 +399,28 @@ where
     }
 
     fn call(&mut self, req: Request) -> Self::Future {
-        let index = self
+        let preselected = self
             .next_idx
             .take()
             .expect("ready service must have valid preselected index");
+        // Services stay ready until they are called, so we can route the
+        // request to any ready service.
+        let index = match req {
+            Request::BlocksByHashFromHeight { min_height, .. } => {
+                self.select_ready_index_for_height(preselected, min_height)
+            }
+            _ => preselected,
+        };
         let (key, mut svc) = self
             .ready_services
             .swap_remove_index(index)
-            .expect("preselected index must be valid");
+            .expect("selected index must be valid");
 
         // XXX add a dimension tagging request metrics by type
         metrics::counter!(
             "outbound_requests",
             1,
-            "key" => key.to_string(),
+            "key" => self.redactor.metric_label(&key),
         );
 
         let fut = svc.call(req);
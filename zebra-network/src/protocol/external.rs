@@ -1,5 +1,7 @@
 /// A Tokio codec that transforms an `AsyncRead` into a `Stream` of `Message`s.
 pub mod codec;
+/// Message framing for byte stream transports.
+pub mod framing;
 /// Inventory items.
 mod inv;
 /// An enum of all supported Bitcoin message types.
@@ -9,7 +9,7 @@ use chrono::{TimeZone, Utc};
 use tokio_util::codec::{Decoder, Encoder};
 
 use zebra_chain::{
-    block::{Block, BlockHeaderHash},
+    block::{Block, BlockHeader, BlockHeaderHash},
     serialization::{
         ReadZcashExt, SerializationError as Error, WriteZcashExt, ZcashDeserialize, ZcashSerialize,
     },
@@ -21,81 +21,98 @@ use zebra_chain::{
 use crate::constants;
 
 use super::{
+    framing::{BitcoinFraming, FrameHeader, Framing},
     message::{Message, RejectReason},
     types::*,
 };
 
-/// The length of a Bitcoin message header.
-const HEADER_LEN: usize = 24usize;
-
-/// Maximum size of a protocol message body.
-const MAX_PROTOCOL_MESSAGE_LEN: usize = 2 * 1024 * 1024;
-
 /// A codec which produces Bitcoin messages from byte streams and vice versa.
-pub struct Codec {
-    builder: Builder,
+///
+/// Message bodies are serialized by the codec, and framed by `F`.
+pub struct Codec<F = BitcoinFraming> {
+    framing: F,
+    /// The protocol version to speak when encoding/decoding.
+    version: Version,
     state: DecodeState,
 }
 
 /// A builder for specifying [`Codec`] options.
-pub struct Builder {
-    /// The network magic to use in encoding.
-    network: Network,
+pub struct Builder<F = BitcoinFraming> {
+    /// The framing to use for messages.
+    framing: F,
     /// The protocol version to speak when encoding/decoding.
     version: Version,
-    /// The maximum allowable message length.
-    max_len: usize,
 }
 
 impl Codec {
     /// Return a builder for constructing a [`Codec`].
     pub fn builder() -> Builder {
         Builder {
-            network: Network::Mainnet,
+            framing: BitcoinFraming::new(Network::Mainnet),
             version: constants::CURRENT_VERSION,
-            max_len: MAX_PROTOCOL_MESSAGE_LEN,
         }
     }
+}
 
+impl<F: Framing> Codec<F> {
     /// Reconfigure the version used by the codec, e.g., after completing a handshake.
     pub fn reconfigure_version(&mut self, version: Version) {
-        self.builder.version = version;
+        self.version = version;
     }
 }
 
-impl Builder {
+impl<F: Framing> Builder<F> {
     /// Finalize the builder and return a [`Codec`].
-    pub fn finish(self) -> Codec {
+    pub fn finish(self) -> Codec<F> {
         Codec {
-            builder: self,
+            framing: self.framing,
+            version: self.version,
             state: DecodeState::Head,
         }
     }
 
+    /// Configure the codec for the given [`Version`].
+    #[allow(dead_code)]
+    pub fn for_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Configure the codec to use `framing`, rather than the Bitcoin framing.
+    #[allow(dead_code)]
+    pub fn with_framing<G: Framing>(self, framing: G) -> Builder<G> {
+        Builder {
+            framing,
+            version: self.version,
+        }
+    }
+}
+
+impl Builder {
     /// Configure the codec for the given [`Network`].
     pub fn for_network(mut self, network: Network) -> Self {
-        self.network = network;
+        self.framing.set_magic(Magic::from(network));
         self
     }
 
-    /// Configure the codec for the given [`Version`].
+    /// Configure the codec for the network identified by `magic`.
     #[allow(dead_code)]
-    pub fn for_version(mut self, version: Version) -> Self {
-        self.version = version;
+    pub fn with_magic(mut self, magic: Magic) -> Self {
+        self.framing.set_magic(magic);
         self
     }
 
     /// Configure the codec's maximum accepted payload size, in bytes.
     #[allow(dead_code)]
     pub fn with_max_body_len(mut self, len: usize) -> Self {
-        self.max_len = len;
+        self.framing.set_max_body_len(len);
         self
     }
 }
 
 // ======== Encoding =========
 
-impl Encoder for Codec {
+impl<F: Framing> Encoder for Codec<F> {
     type Item = Message;
     type Error = Error;
 
@@ -109,7 +126,7 @@ impl Encoder for Codec {
         let mut body = Vec::new();
         self.write_body(&item, &mut body)?;
 
-        if body.len() > self.builder.max_len {
+        if body.len() > self.framing.max_body_len() {
             return Err(Parse("body length exceeded maximum size"));
         }
 
@@ -127,7 +144,7 @@ impl Encoder for Codec {
             Reject { .. } => b"reject\0\0\0\0\0\0",
             Addr { .. } => b"addr\0\0\0\0\0\0\0\0",
             GetAddr { .. } => b"getaddr\0\0\0\0\0",
-            Block { .. } => b"block\0\0\0\0\0\0\0",
+            Block { .. } | RawBlock { .. } => b"block\0\0\0\0\0\0\0",
             GetBlocks { .. } => b"getblocks\0\0\0",
             Headers { .. } => b"headers\0\0\0\0\0",
             GetHeaders { .. } => b"getheaders\0\0",
@@ -142,24 +159,15 @@ impl Encoder for Codec {
         };
         trace!(?item, len = body.len());
 
-        // XXX this should write directly into the buffer,
-        // but leave it for now until we fix the issue above.
-        let mut header = [0u8; HEADER_LEN];
-        let mut header_writer = Cursor::new(&mut header[..]);
-        header_writer.write_all(&Magic::from(self.builder.network).0[..])?;
-        header_writer.write_all(command)?;
-        header_writer.write_u32::<LittleEndian>(body.len() as u32)?;
-        header_writer.write_all(&Sha256dChecksum::from(&body[..]).0)?;
-
-        dst.reserve(HEADER_LEN + body.len());
-        dst.extend_from_slice(&header);
+        dst.reserve(self.framing.header_len() + body.len());
+        self.framing.write_header(command, &body, dst)?;
         dst.extend_from_slice(&body);
 
         Ok(())
     }
 }
 
-impl Codec {
+impl<F> Codec<F> {
     /// Write the body of the message into the given writer. This allows writing
     /// the message body prior to writing the header, so that the header can
     /// contain a checksum of the message body.
@@ -214,11 +222,12 @@ impl Codec {
             Message::Addr(addrs) => addrs.zcash_serialize(&mut writer)?,
             Message::GetAddr => { /* Empty payload -- no-op */ }
             Message::Block(block) => block.zcash_serialize(&mut writer)?,
+            Message::RawBlock(bytes) => writer.write_all(bytes)?,
             Message::GetBlocks {
                 block_locator_hashes,
                 hash_stop,
             } => {
-                writer.write_u32::<LittleEndian>(self.builder.version.0)?;
+                writer.write_u32::<LittleEndian>(self.version.0)?;
                 block_locator_hashes.zcash_serialize(&mut writer)?;
                 hash_stop.zcash_serialize(&mut writer)?;
             }
@@ -226,11 +235,23 @@ impl Codec {
                 block_locator_hashes,
                 hash_stop,
             } => {
-                writer.write_u32::<LittleEndian>(self.builder.version.0)?;
+                writer.write_u32::<LittleEndian>(self.version.0)?;
                 block_locator_hashes.zcash_serialize(&mut writer)?;
                 hash_stop.zcash_serialize(&mut writer)?;
             }
-            Message::Headers(headers) => headers.zcash_serialize(&mut writer)?,
+            Message::Headers(headers) => {
+                if headers.len() > constants::MAX_HEADERS_PER_MESSAGE {
+                    return Err(Error::Parse("headers message contains too many headers"));
+                }
+
+                // Each header is followed by a zero transaction count, like
+                // an empty block
+                writer.write_compactsize(headers.len() as u64)?;
+                for header in headers {
+                    header.zcash_serialize(&mut writer)?;
+                    writer.write_compactsize(0)?;
+                }
+            }
             Message::Inv(hashes) => hashes.zcash_serialize(&mut writer)?,
             Message::GetData(hashes) => hashes.zcash_serialize(&mut writer)?,
             Message::NotFound(hashes) => hashes.zcash_serialize(&mut writer)?,
@@ -285,7 +306,7 @@ impl fmt::Debug for DecodeState {
     }
 }
 
-impl Decoder for Codec {
+impl<F: Framing> Decoder for Codec<F> {
     type Item = Message;
     type Error = Error;
 
@@ -294,39 +315,27 @@ impl Decoder for Codec {
         match self.state {
             DecodeState::Head => {
                 // First check that the src buffer contains an entire header.
-                if src.len() < HEADER_LEN {
+                let header_len = self.framing.header_len();
+                if src.len() < header_len {
                     trace!(?self.state, "src buffer does not have an entire header, waiting");
                     // Signal that decoding requires more data.
                     return Ok(None);
                 }
 
                 // Now that we know that src contains a header, split off the header section.
-                let header = src.split_to(HEADER_LEN);
-
-                // Create a cursor over the header and parse its fields.
-                let mut header_reader = Cursor::new(&header);
-                let magic = Magic(header_reader.read_4_bytes()?);
-                let command = header_reader.read_12_bytes()?;
-                let body_len = header_reader.read_u32::<LittleEndian>()? as usize;
-                let checksum = Sha256dChecksum(header_reader.read_4_bytes()?);
-                trace!(
-                    ?self.state,
-                    ?magic,
-                    command = %String::from_utf8_lossy(&command),
+                let header = src.split_to(header_len);
+                let FrameHeader {
+                    command,
                     body_len,
-                    ?checksum,
-                    "read header from src buffer"
-                );
+                    checksum,
+                } = self.framing.read_header(&header)?;
 
-                if magic != Magic::from(self.builder.network) {
-                    return Err(Parse("supplied magic did not meet expectations"));
-                }
-                if body_len > self.builder.max_len {
+                if body_len > self.framing.max_body_len() {
                     return Err(Parse("body length exceeded maximum size"));
                 }
 
                 // Reserve buffer space for the expected body and the following header.
-                src.reserve(body_len + HEADER_LEN);
+                src.reserve(body_len + header_len);
 
                 self.state = DecodeState::Body {
                     body_len,
@@ -354,11 +363,12 @@ impl Decoder for Codec {
                 let body = src.split_to(body_len);
                 self.state = DecodeState::Head;
 
-                if checksum != Sha256dChecksum::from(&body[..]) {
-                    return Err(Parse(
-                        "supplied message checksum does not match computed checksum",
-                    ));
-                }
+                let header = FrameHeader {
+                    command,
+                    body_len,
+                    checksum,
+                };
+                self.framing.check_body(&header, &body)?;
 
                 let body_reader = Cursor::new(&body);
                 match &command {
@@ -369,7 +379,7 @@ impl Decoder for Codec {
                     b"reject\0\0\0\0\0\0" => self.read_reject(body_reader),
                     b"addr\0\0\0\0\0\0\0\0" => self.read_addr(body_reader),
                     b"getaddr\0\0\0\0\0" => self.read_getaddr(body_reader),
-                    b"block\0\0\0\0\0\0\0" => self.read_block(body_reader),
+                    b"block\0\0\0\0\0\0\0" => self.read_block(&body),
                     b"getblocks\0\0\0" => self.read_getblocks(body_reader),
                     b"headers\0\0\0\0\0" => self.read_headers(body_reader),
                     b"getheaders\0\0" => self.read_getheaders(body_reader),
@@ -394,7 +404,7 @@ impl Decoder for Codec {
     }
 }
 
-impl Codec {
+impl<F> Codec<F> {
     fn read_version<R: Read>(&self, mut reader: R) -> Result<Message, Error> {
         Ok(Message::Version {
             version: Version(reader.read_u32::<LittleEndian>()?),
@@ -460,12 +470,14 @@ impl Codec {
         Ok(Message::GetAddr)
     }
 
-    fn read_block<R: Read>(&self, reader: R) -> Result<Message, Error> {
-        Ok(Message::Block(Block::zcash_deserialize(reader)?.into()))
+    /// Parse a block, keeping its bytes, so the state doesn't have to
+    /// serialize it again.
+    fn read_block(&self, body: &[u8]) -> Result<Message, Error> {
+        Ok(Message::Block(Block::from_bytes(body)?.into()))
     }
 
     fn read_getblocks<R: Read>(&self, mut reader: R) -> Result<Message, Error> {
-        if self.builder.version == Version(reader.read_u32::<LittleEndian>()?) {
+        if self.version == Version(reader.read_u32::<LittleEndian>()?) {
             Ok(Message::GetBlocks {
                 block_locator_hashes: Vec::zcash_deserialize(&mut reader)?,
                 hash_stop: BlockHeaderHash::zcash_deserialize(&mut reader)?,
@@ -481,11 +493,26 @@ impl Codec {
     ///
     /// [Zcash block header](https://zips.z.cash/protocol/protocol.pdf#page=84)
     fn read_headers<R: Read>(&self, mut reader: R) -> Result<Message, Error> {
-        Ok(Message::Headers(Vec::zcash_deserialize(&mut reader)?))
+        let count = reader.read_compactsize()?;
+        if count > constants::MAX_HEADERS_PER_MESSAGE as u64 {
+            return Err(Error::Parse("headers message contains too many headers"));
+        }
+
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            headers.push(BlockHeader::zcash_deserialize(&mut reader)?);
+            if reader.read_compactsize()? != 0 {
+                return Err(Error::Parse(
+                    "headers message has a non-zero transaction count",
+                ));
+            }
+        }
+
+        Ok(Message::Headers(headers))
     }
 
     fn read_getheaders<R: Read>(&self, mut reader: R) -> Result<Message, Error> {
-        if self.builder.version == Version(reader.read_u32::<LittleEndian>()?) {
+        if self.version == Version(reader.read_u32::<LittleEndian>()?) {
             Ok(Message::GetHeaders {
                 block_locator_hashes: Vec::zcash_deserialize(&mut reader)?,
                 hash_stop: BlockHeaderHash::zcash_deserialize(&mut reader)?,
@@ -674,6 +701,129 @@ mod tests {
         });
     }
 
+    /// Returns the headers of the mainnet test vector blocks, and the bytes
+    /// of each header followed by a zero transaction count, as `zcashd`
+    /// sends them.
+    fn mainnet_headers() -> Vec<(BlockHeader, Vec<u8>)> {
+        [
+            &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+            &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+            &zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..],
+        ]
+        .iter()
+        .map(|block_bytes| {
+            let header = BlockHeader::zcash_deserialize(*block_bytes).unwrap();
+            let mut serialized = Vec::new();
+            header.zcash_serialize(&mut serialized).unwrap();
+
+            // Check against the original bytes, rather than our serialization
+            let mut bytes = block_bytes[..serialized.len()].to_vec();
+            bytes.push(0);
+            (header, bytes)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn headers_message_matches_zcashd() {
+        zebra_test::init();
+
+        let codec = Codec::builder().finish();
+        let (headers, header_bytes): (Vec<_>, Vec<_>) = mainnet_headers().into_iter().unzip();
+
+        let mut expected = vec![headers.len() as u8];
+        expected.extend(header_bytes.concat());
+
+        let mut body = Vec::new();
+        codec
+            .write_body(&Message::Headers(headers.clone()), &mut body)
+            .unwrap();
+        assert_eq!(body, expected);
+
+        let parsed = codec.read_headers(Cursor::new(&expected)).unwrap();
+        assert_eq!(parsed, Message::Headers(headers));
+
+        // Empty responses are valid
+        let parsed = codec.read_headers(Cursor::new(&[0u8][..])).unwrap();
+        assert_eq!(parsed, Message::Headers(Vec::new()));
+
+        // Headers must not be followed by transactions
+        let mut bad_count = expected.clone();
+        *bad_count.last_mut().unwrap() = 1;
+        codec
+            .read_headers(Cursor::new(&bad_count))
+            .expect_err("headers with transactions should not parse");
+    }
+
+    #[test]
+    fn raw_block_message_matches_block_message() {
+        use std::sync::Arc;
+        use tokio_util::codec::FramedRead;
+        zebra_test::init();
+
+        let block_bytes = &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..];
+        let block: Arc<Block> = Block::zcash_deserialize(block_bytes).unwrap().into();
+
+        let mut encoded = BytesMut::new();
+        Codec::builder()
+            .finish()
+            .encode(Message::RawBlock(block_bytes.into()), &mut encoded)
+            .unwrap();
+        let mut expected = BytesMut::new();
+        Codec::builder()
+            .finish()
+            .encode(Message::Block(block.clone()), &mut expected)
+            .unwrap();
+        assert_eq!(encoded, expected);
+
+        // Raw blocks are parsed like any other block message
+        let mut rt = Runtime::new().unwrap();
+        let parsed = rt.block_on(async {
+            let mut fr = FramedRead::new(Cursor::new(&encoded[..]), Codec::builder().finish());
+            fr.next().await.unwrap().unwrap()
+        });
+        assert_eq!(parsed, Message::Block(block));
+
+        // The parsed block keeps its bytes
+        match parsed {
+            Message::Block(block) => {
+                assert_eq!(block.bytes.get().map(|bytes| &bytes[..]), Some(block_bytes))
+            }
+            _ => unreachable!("the message was compared with a block message"),
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn headers_message_limit(count in 0..=2 * constants::MAX_HEADERS_PER_MESSAGE) {
+            zebra_test::init();
+
+            let codec = Codec::builder().finish();
+            let (header, header_bytes) = mainnet_headers().remove(1);
+            let headers = vec![header; count];
+
+            let mut body = Vec::new();
+            let written = codec.write_body(&Message::Headers(headers.clone()), &mut body);
+            proptest::prop_assert_eq!(
+                written.is_ok(),
+                count <= constants::MAX_HEADERS_PER_MESSAGE
+            );
+
+            let mut bytes = Vec::new();
+            bytes.write_compactsize(count as u64).unwrap();
+            for _ in 0..count {
+                bytes.extend(&header_bytes);
+            }
+            let parsed = codec.read_headers(Cursor::new(&bytes));
+            if count <= constants::MAX_HEADERS_PER_MESSAGE {
+                proptest::prop_assert_eq!(&body, &bytes);
+                proptest::prop_assert_eq!(parsed.unwrap(), Message::Headers(headers));
+            } else {
+                proptest::prop_assert!(parsed.is_err());
+            }
+        }
+    }
+
     #[test]
     fn decode_state_debug() {
         assert_eq!(format!("{:?}", DecodeState::Head), "DecodeState::Head");
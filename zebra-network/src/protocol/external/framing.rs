@@ -0,0 +1,260 @@
+//! Message framing for byte stream transports.
+//!
+//! A [`Framing`] splits a byte stream into message frames, and checks each
+//! frame header. The [`Codec`](super::Codec) serializes message bodies, and
+//! uses its framing for everything else, so alternate transports can reuse
+//! the message serialization.
+
+use std::io::{Cursor, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BytesMut;
+
+use zebra_chain::{
+    serialization::{ReadZcashExt, SerializationError as Error},
+    types::Sha256dChecksum,
+    Network,
+};
+
+use super::types::Magic;
+
+/// The length of a Bitcoin message header.
+pub const BITCOIN_HEADER_LEN: usize = 24;
+
+/// Maximum size of a protocol message body.
+pub const MAX_PROTOCOL_MESSAGE_LEN: usize = 2 * 1024 * 1024;
+
+/// The fields of a message frame header, which are used to decode its body.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    /// The message command, padded with zero bytes.
+    pub command: [u8; 12],
+    /// The length of the message body, in bytes.
+    pub body_len: usize,
+    /// The checksum of the message body.
+    pub checksum: Sha256dChecksum,
+}
+
+/// A framing for messages on a byte stream transport.
+///
+/// Implementations decide how frames are identified, limited, and checked.
+pub trait Framing {
+    /// The length of each frame header, in bytes.
+    fn header_len(&self) -> usize;
+
+    /// The maximum length of a message body, in bytes.
+    fn max_body_len(&self) -> usize;
+
+    /// Write the frame header for a `command` message with `body` to `dst`.
+    fn write_header(
+        &self,
+        command: &[u8; 12],
+        body: &[u8],
+        dst: &mut BytesMut,
+    ) -> Result<(), Error>;
+
+    /// Read a frame header from `header`, which is `header_len` bytes long.
+    ///
+    /// Returns an error if the header is for another network or transport.
+    fn read_header(&self, header: &[u8]) -> Result<FrameHeader, Error>;
+
+    /// Check the frame `body` against its `header`.
+    fn check_body(&self, header: &FrameHeader, body: &[u8]) -> Result<(), Error>;
+}
+
+/// The framing used by the Zcash peer-to-peer protocol, which is the same as
+/// the Bitcoin framing.
+///
+/// Each header contains the network magic, the command, the body length, and
+/// a double-SHA256 checksum of the body.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BitcoinFraming {
+    /// The magic that identifies the network.
+    magic: Magic,
+    /// The maximum allowable message body length.
+    max_body_len: usize,
+}
+
+impl BitcoinFraming {
+    /// Returns a framing for `network`, with the default body length limit.
+    pub fn new(network: Network) -> Self {
+        Self::with_magic(Magic::from(network))
+    }
+
+    /// Returns a framing for the network identified by `magic`, with the
+    /// default body length limit.
+    ///
+    /// Use this framing for private networks with their own magic.
+    pub fn with_magic(magic: Magic) -> Self {
+        Self {
+            magic,
+            max_body_len: MAX_PROTOCOL_MESSAGE_LEN,
+        }
+    }
+
+    /// Set the maximum accepted body length, in bytes.
+    pub fn set_max_body_len(&mut self, len: usize) {
+        self.max_body_len = len;
+    }
+
+    /// Set the magic that identifies the network.
+    pub fn set_magic(&mut self, magic: Magic) {
+        self.magic = magic;
+    }
+}
+
+impl Framing for BitcoinFraming {
+    fn header_len(&self) -> usize {
+        BITCOIN_HEADER_LEN
+    }
+
+    fn max_body_len(&self) -> usize {
+        self.max_body_len
+    }
+
+    fn write_header(
+        &self,
+        command: &[u8; 12],
+        body: &[u8],
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
+        let mut header = [0u8; BITCOIN_HEADER_LEN];
+        let mut header_writer = Cursor::new(&mut header[..]);
+        header_writer.write_all(&self.magic.0[..])?;
+        header_writer.write_all(command)?;
+        header_writer.write_u32::<LittleEndian>(body.len() as u32)?;
+        header_writer.write_all(&Sha256dChecksum::from(body).0)?;
+
+        dst.extend_from_slice(&header);
+        Ok(())
+    }
+
+    fn read_header(&self, header: &[u8]) -> Result<FrameHeader, Error> {
+        let mut header_reader = Cursor::new(header);
+        let magic = Magic(header_reader.read_4_bytes()?);
+        let command = header_reader.read_12_bytes()?;
+        let body_len = header_reader.read_u32::<LittleEndian>()? as usize;
+        let checksum = Sha256dChecksum(header_reader.read_4_bytes()?);
+        trace!(
+            ?magic,
+            command = %String::from_utf8_lossy(&command),
+            body_len,
+            ?checksum,
+            "read header from src buffer"
+        );
+
+        if magic != self.magic {
+            return Err(Error::Parse("supplied magic did not meet expectations"));
+        }
+
+        Ok(FrameHeader {
+            command,
+            body_len,
+            checksum,
+        })
+    }
+
+    fn check_body(&self, header: &FrameHeader, body: &[u8]) -> Result<(), Error> {
+        if header.checksum != Sha256dChecksum::from(body) {
+            return Err(Error::Parse(
+                "supplied message checksum does not match computed checksum",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::protocol::external::{types::Nonce, Codec, Message};
+
+    /// A framing without magic or checksums, like an in-memory transport
+    /// might use.
+    struct UncheckedFraming;
+
+    impl Framing for UncheckedFraming {
+        fn header_len(&self) -> usize {
+            16
+        }
+
+        fn max_body_len(&self) -> usize {
+            MAX_PROTOCOL_MESSAGE_LEN
+        }
+
+        fn write_header(
+            &self,
+            command: &[u8; 12],
+            body: &[u8],
+            dst: &mut BytesMut,
+        ) -> Result<(), Error> {
+            dst.extend_from_slice(command);
+            dst.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            Ok(())
+        }
+
+        fn read_header(&self, header: &[u8]) -> Result<FrameHeader, Error> {
+            let mut header_reader = Cursor::new(header);
+            Ok(FrameHeader {
+                command: header_reader.read_12_bytes()?,
+                body_len: header_reader.read_u32::<LittleEndian>()? as usize,
+                checksum: Sha256dChecksum([0; 4]),
+            })
+        }
+
+        fn check_body(&self, _header: &FrameHeader, _body: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn codec_uses_alternate_framing() {
+        zebra_test::init();
+
+        let message = Message::Ping(Nonce(0x1234));
+        let mut codec = Codec::builder().with_framing(UncheckedFraming).finish();
+
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(message.clone(), &mut bytes)
+            .expect("message encodes");
+        assert_eq!(bytes.len(), 16 + 8);
+        assert_eq!(&bytes[..12], b"ping\0\0\0\0\0\0\0\0");
+
+        let decoded = codec.decode(&mut bytes).expect("message decodes");
+        assert_eq!(decoded, Some(message));
+    }
+
+    #[test]
+    fn bitcoin_framing_rejects_other_magic() {
+        zebra_test::init();
+
+        let private_magic = Magic([0x01, 0x02, 0x03, 0x04]);
+        let message = Message::Ping(Nonce(0x1234));
+
+        let mut bytes = BytesMut::new();
+        Codec::builder()
+            .with_magic(private_magic)
+            .finish()
+            .encode(message.clone(), &mut bytes)
+            .expect("message encodes");
+        assert_eq!(&bytes[..4], &private_magic.0[..]);
+
+        let mut mainnet_bytes = bytes.clone();
+        assert!(Codec::builder()
+            .finish()
+            .decode(&mut mainnet_bytes)
+            .is_err());
+
+        let decoded = Codec::builder()
+            .with_magic(private_magic)
+            .finish()
+            .decode(&mut bytes)
+            .expect("message decodes");
+        assert_eq!(decoded, Some(message));
+    }
+}
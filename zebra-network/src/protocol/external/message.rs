@@ -134,6 +134,15 @@ pub enum Message {
     /// [Bitcoin reference](https://en.bitcoin.it/wiki/Protocol_documentation#block)
     Block(Arc<Block>),
 
+    /// A `block` message, containing a block that is already serialized.
+    ///
+    /// Stored blocks are sent to peers as raw bytes, so they aren't
+    /// serialized again. Received `block` messages are always parsed into
+    /// [`Message::Block`].
+    ///
+    /// [Bitcoin reference](https://en.bitcoin.it/wiki/Protocol_documentation#block)
+    RawBlock(Arc<[u8]>),
+
     /// A `getblocks` message.
     ///
     /// Requests the list of blocks starting right after the last
@@ -173,18 +182,24 @@ pub enum Message {
     ///
     /// Returns block headers in response to a getheaders packet.
     ///
+    /// Contains at most 160 headers. An empty `headers` message means the
+    /// peer has no headers after the requested locator. A full message
+    /// means there might be more headers, so the caller should send another
+    /// `getheaders` starting from the last header.
+    ///
     /// [Bitcoin reference](https://en.bitcoin.it/wiki/Protocol_documentation#headers)
     // Note that the block headers in this packet include a
     // transaction count (a var_int, so there can be more than 81
     // bytes per header) as opposed to the block headers that are
-    // hashed by miners.
+    // hashed by miners. The codec writes and checks a zero transaction
+    // count after each header.
     Headers(Vec<BlockHeader>),
 
     /// A `getheaders` message.
     ///
     /// Requests a series of block headers starting right after the
     /// last known hash in `block_locator_hashes`, up to `hash_stop`
-    /// or 2000 blocks, whichever comes first.
+    /// or 160 blocks, whichever comes first.
     ///
     /// You can send in fewer known hashes down to a minimum of just
     /// one hash. However, the purpose of the block locator object is
@@ -194,18 +209,19 @@ pub enum Message {
     /// if you just send in your last known hash and it is off the
     /// main chain, the peer starts over at block #1.
     ///
+    /// If `block_locator_hashes` is empty, the peer only sends the header
+    /// for `hash_stop`.
+    ///
     /// [Bitcoin reference](https://en.bitcoin.it/wiki/Protocol_documentation#getheaders)
-    // The 2000 headers number is from the Bitcoin docs, we are not
-    // certain (yet) that other implementations of Zcash obey this
-    // restriction, or if they don't, what happens if we send them too
-    // many results.
+    // Bitcoin sends up to 2000 headers, but zcashd's limit is 160, because
+    // Zcash headers contain an Equihash solution.
     GetHeaders {
         /// Block locators, from newest back to genesis block.
         block_locator_hashes: Vec<BlockHeaderHash>,
 
         /// `BlockHeaderHash` of the last desired block header.
         ///
-        /// Set to zero to get as many block headers as possible (2000).
+        /// Set to zero to get as many block headers as possible (160).
         hash_stop: BlockHeaderHash,
     },
 
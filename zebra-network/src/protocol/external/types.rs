@@ -1,6 +1,6 @@
 #![allow(clippy::unit_arg)]
 
-use crate::constants::magics;
+use crate::constants::{magics, HISTORICAL_BLOCK_DEPTH};
 
 use std::fmt;
 
@@ -77,6 +77,41 @@ bitflags! {
         /// blocks, as opposed to a light client that makes network requests but
         /// does not provide network services.
         const NODE_NETWORK = 1;
+        /// NODE_NETWORK_LIMITED means that the node can serve at least the
+        /// last `HISTORICAL_BLOCK_DEPTH` blocks below its tip, but might not
+        /// serve older blocks, as defined in BIP 159.
+        ///
+        /// Pruned nodes advertise this service instead of `NODE_NETWORK`.
+        const NODE_NETWORK_LIMITED = 1 << 10;
+    }
+}
+
+impl PeerServices {
+    /// Returns the block services for a node that keeps the blocks down to
+    /// `kept_depth` below its tip, or every block if `kept_depth` is `None`.
+    ///
+    /// Nodes that keep fewer than `HISTORICAL_BLOCK_DEPTH` blocks don't
+    /// advertise any block services.
+    pub fn for_kept_depth(kept_depth: Option<u32>) -> PeerServices {
+        match kept_depth {
+            None => PeerServices::NODE_NETWORK,
+            Some(depth) if depth >= HISTORICAL_BLOCK_DEPTH => PeerServices::NODE_NETWORK_LIMITED,
+            Some(_) => PeerServices::empty(),
+        }
+    }
+
+    /// Returns true if a node that advertised these services and a best
+    /// block height of `tip_height` can serve the block at `height`.
+    pub fn serves_block(&self, height: BlockHeight, tip_height: BlockHeight) -> bool {
+        if height > tip_height {
+            false
+        } else if self.contains(PeerServices::NODE_NETWORK) {
+            true
+        } else if self.contains(PeerServices::NODE_NETWORK_LIMITED) {
+            tip_height.0 - height.0 <= HISTORICAL_BLOCK_DEPTH
+        } else {
+            false
+        }
     }
 }
 
@@ -135,6 +170,25 @@ mod proptest {
 mod test {
     use super::*;
 
+    #[test]
+    fn limited_services_only_serve_recent_blocks() {
+        let tip = BlockHeight(1_000);
+        let recent = BlockHeight(tip.0 - HISTORICAL_BLOCK_DEPTH);
+        let historical = BlockHeight(recent.0 - 1);
+
+        let full = PeerServices::for_kept_depth(None);
+        assert!(full.serves_block(historical, tip));
+        assert!(!full.serves_block(BlockHeight(tip.0 + 1), tip));
+
+        let limited = PeerServices::for_kept_depth(Some(HISTORICAL_BLOCK_DEPTH));
+        assert_eq!(limited, PeerServices::NODE_NETWORK_LIMITED);
+        assert!(limited.serves_block(recent, tip));
+        assert!(!limited.serves_block(historical, tip));
+
+        let shallow = PeerServices::for_kept_depth(Some(HISTORICAL_BLOCK_DEPTH - 1));
+        assert!(!shallow.serves_block(tip, tip));
+    }
+
     #[test]
     fn version_extremes_mainnet() {
         version_extremes(Mainnet)
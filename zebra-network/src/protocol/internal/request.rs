@@ -1,6 +1,6 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::Arc};
 
-use zebra_chain::block::BlockHeaderHash;
+use zebra_chain::{block::BlockHeaderHash, transaction::Transaction, types::BlockHeight};
 
 use super::super::types::Nonce;
 
@@ -41,9 +41,31 @@ pub enum Request {
     ///
     /// # Returns
     ///
-    /// Returns [`Response::Blocks`](super::Response::Blocks).
+    /// Returns [`Response::Blocks`](super::Response::Blocks). Inbound services
+    /// can also respond with [`Response::RawBlocks`](super::Response::RawBlocks).
     BlocksByHash(HashSet<BlockHeaderHash>),
 
+    /// Request block data by block hashes, from a peer that advertised a best
+    /// block height of at least `min_height`.
+    ///
+    /// The peer set routes this request to a ready peer with a high enough
+    /// advertised height. It prefers peers that advertise services that
+    /// include the blocks: `NODE_NETWORK` for any block, or
+    /// `NODE_NETWORK_LIMITED` for recent blocks. If no ready peers qualify,
+    /// the request is sent to any ready peer. Peers handle this request like
+    /// [`Request::BlocksByHash`].
+    ///
+    /// # Returns
+    ///
+    /// Returns [`Response::Blocks`](super::Response::Blocks).
+    BlocksByHashFromHeight {
+        /// The hashes of the requested blocks.
+        hashes: HashSet<BlockHeaderHash>,
+        /// The minimum advertised best block height of the peer. Usually the
+        /// height of the lowest requested block.
+        min_height: BlockHeight,
+    },
+
     /// Request block hashes of subsequent blocks in the chain, giving hashes of
     /// known blocks.
     ///
@@ -69,4 +91,36 @@ pub enum Request {
         /// Optionally, the last header to request.
         stop: Option<BlockHeaderHash>,
     },
+
+    /// Request block headers of subsequent blocks in the chain, giving hashes
+    /// of known blocks.
+    ///
+    /// # Returns
+    ///
+    /// Returns
+    /// [`Response::BlockHeaders`](super::Response::BlockHeaders).
+    ///
+    /// The response contains at most 160 headers, in chain order. If it is
+    /// full, there might be more headers, so callers should send another
+    /// request with the last header's hash. An empty response means the
+    /// peer has no headers after `known_blocks`.
+    FindHeaders {
+        /// Hashes of known blocks, ordered from highest height to lowest height.
+        known_blocks: Vec<BlockHeaderHash>,
+        /// Optionally, the last header to request.
+        stop: Option<BlockHeaderHash>,
+    },
+
+    /// Push a transaction to a peer, so the peer can verify and relay it.
+    ///
+    /// This is implemented by sending an unsolicited `tx` message. Peers
+    /// don't respond to `tx` messages, so a successful response doesn't mean
+    /// that the peer accepted the transaction. To reach more of the network,
+    /// callers can push the same transaction to multiple peers.
+    ///
+    /// # Returns
+    ///
+    /// Returns [`Response::Nil`](super::Response::Nil), after the message is
+    /// sent.
+    PushTransaction(Arc<Transaction>),
 }
@@ -1,5 +1,5 @@
 // XXX clean module layout of zebra_chain
-use zebra_chain::block::{Block, BlockHeaderHash};
+use zebra_chain::block::{Block, BlockHeader, BlockHeaderHash};
 
 use crate::meta_addr::MetaAddr;
 use std::sync::Arc;
@@ -16,6 +16,13 @@ pub enum Response {
     /// A list of blocks.
     Blocks(Vec<Arc<Block>>),
 
+    /// A list of serialized blocks, used to respond to peer `BlocksByHash`
+    /// requests without serializing the blocks again.
+    RawBlocks(Vec<Arc<[u8]>>),
+
     /// A list of block hashes.
     BlockHeaderHashes(Vec<BlockHeaderHash>),
+
+    /// A list of block headers, in chain order.
+    BlockHeaders(Vec<BlockHeader>),
 }
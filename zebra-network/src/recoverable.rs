@@ -0,0 +1,73 @@
+//! Marks the tasks whose panics Zebra can recover from.
+//!
+//! A panic in a peer connection only closes that connection, and a panic in
+//! the peer crawler restarts the crawler. Applications can call
+//! [`in_recoverable_task`] from a panic hook, and abort the process on other
+//! panics, which might leave the state or consensus code inconsistent.
+
+use std::cell::Cell;
+
+use futures::future::{self, Future};
+
+thread_local! {
+    /// Is this thread polling a recoverable task?
+    static RECOVERABLE: Cell<bool> = Cell::new(false);
+}
+
+/// Restores the previous value of [`RECOVERABLE`] when it is dropped,
+/// including when the polled future panics.
+struct Guard(bool);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        RECOVERABLE.with(|flag| flag.set(self.0));
+    }
+}
+
+/// Poll `task`, setting the recoverable marker to `recoverable` during each
+/// poll.
+fn mark<Fut: Future>(task: Fut, recoverable: bool) -> impl Future<Output = Fut::Output> {
+    let mut task = Box::pin(task);
+    future::poll_fn(move |cx| {
+        let _guard = Guard(RECOVERABLE.with(|flag| flag.replace(recoverable)));
+        task.as_mut().poll(cx)
+    })
+}
+
+/// Run `task`, marking its panics as recoverable.
+///
+/// The caller is responsible for recovering from the panic, for example by
+/// restarting the task when its `JoinHandle` returns an error.
+pub fn recoverable<Fut: Future>(task: Fut) -> impl Future<Output = Fut::Output> {
+    mark(task, true)
+}
+
+/// Run `task`, marking its panics as unrecoverable, even if it is polled by a
+/// recoverable task.
+///
+/// Used for the inbound service, which calls into the state.
+pub fn unrecoverable<Fut: Future>(task: Fut) -> impl Future<Output = Fut::Output> {
+    mark(task, false)
+}
+
+/// Is this thread polling a recoverable task?
+pub fn in_recoverable_task() -> bool {
+    RECOVERABLE.with(Cell::get)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unrecoverable_tasks_can_be_nested() {
+        let task = recoverable(async {
+            assert!(in_recoverable_task());
+            unrecoverable(async { assert!(!in_recoverable_task()) }).await;
+            assert!(in_recoverable_task());
+        });
+
+        futures::executor::block_on(task);
+        assert!(!in_recoverable_task());
+    }
+}
@@ -0,0 +1,103 @@
+//! Peer address redaction for logs and metrics.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hash, Hasher},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::Config;
+
+/// The salt for redacted peer addresses.
+///
+/// Each run uses a new random salt, so redacted addresses can be correlated
+/// within a single run's logs, but not across runs.
+static SALT: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+/// Formats peer addresses for logs, spans, and metrics labels.
+///
+/// If [`Config::redact_peer_addrs`] is set, addresses are replaced with a
+/// salted hash of the address. Operators can then share their logs without
+/// revealing which peers their node is connected to.
+#[derive(Copy, Clone, Debug)]
+pub struct PeerAddrRedactor {
+    redact_logs: bool,
+    redact_metrics: bool,
+}
+
+impl PeerAddrRedactor {
+    /// Returns a redactor for the peer address settings in `config`.
+    pub fn new(config: &Config) -> Self {
+        PeerAddrRedactor {
+            redact_logs: config.redact_peer_addrs,
+            redact_metrics: config.redact_peer_addrs && !config.metrics_peer_addrs,
+        }
+    }
+
+    /// Returns `addr` formatted for logs, spans, and error reports.
+    pub fn log<A: Hash + Display>(&self, addr: &A) -> String {
+        if self.redact_logs {
+            redacted(addr)
+        } else {
+            addr.to_string()
+        }
+    }
+
+    /// Returns `addr` formatted for metrics labels.
+    pub fn metric_label<A: Hash + Display>(&self, addr: &A) -> String {
+        if self.redact_metrics {
+            redacted(addr)
+        } else {
+            addr.to_string()
+        }
+    }
+}
+
+/// Returns a salted hash of `addr`.
+fn redacted<A: Hash>(addr: &A) -> String {
+    let mut hasher = SALT.build_hasher();
+    addr.hash(&mut hasher);
+    format!("redacted-{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    #[test]
+    fn redacted_addrs_are_hidden_but_consistent() {
+        zebra_test::init();
+
+        let addr: SocketAddr = "203.0.113.7:8233".parse().unwrap();
+        let other: SocketAddr = "203.0.113.8:8233".parse().unwrap();
+
+        let config = Config {
+            redact_peer_addrs: true,
+            ..Config::default()
+        };
+        let redactor = PeerAddrRedactor::new(&config);
+
+        assert!(!redactor.log(&addr).contains("203.0.113"));
+        assert_eq!(redactor.log(&addr), redactor.log(&addr));
+        assert_ne!(redactor.log(&addr), redactor.log(&other));
+        assert_eq!(redactor.metric_label(&addr), redactor.log(&addr));
+
+        let config = Config {
+            metrics_peer_addrs: true,
+            ..config
+        };
+        let redactor = PeerAddrRedactor::new(&config);
+
+        assert!(!redactor.log(&addr).contains("203.0.113"));
+        assert_eq!(redactor.metric_label(&addr), addr.to_string());
+
+        let redactor = PeerAddrRedactor::new(&Config::default());
+
+        assert_eq!(redactor.log(&addr), addr.to_string());
+        assert_eq!(redactor.metric_label(&addr), addr.to_string());
+    }
+}
@@ -0,0 +1,281 @@
+//! An opt-in, append-only audit log of administrative and state-mutating RPC
+//! calls.
+//!
+//! Each audited call is written as a single line of JSON, containing the
+//! method, a SHA-256 hash of its parameters, the source address, and the
+//! result. Parameters are hashed rather than logged, so the log doesn't
+//! contain transaction data or secrets. When the log reaches its maximum
+//! size, it is rotated, and the oldest rotated file is removed.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The RPC methods that are written to the audit log.
+///
+/// These methods change the node's state, or its connections to peers.
+/// Read-only methods are not audited.
+pub const AUDITED_METHODS: &[&str] = &[
+    "addnode",
+    "clearbanned",
+    "disconnectnode",
+    "invalidateblock",
+    "reconsiderblock",
+    "sendrawtransaction",
+    "setban",
+    "stop",
+    "submitblock",
+];
+
+/// Returns true if calls to `method` are written to the audit log.
+pub fn is_audited(method: &str) -> bool {
+    AUDITED_METHODS.contains(&method)
+}
+
+/// Audit log configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AuditLogConfig {
+    /// The path of the current audit log file.
+    ///
+    /// Rotated files are stored next to it, with the suffixes `.1`, `.2`,
+    /// and so on, where `.1` is the most recent.
+    pub path: PathBuf,
+
+    /// The size of the audit log file that triggers rotation, in bytes.
+    pub max_file_size: u64,
+
+    /// The number of rotated files that are kept, in addition to the current
+    /// file.
+    ///
+    /// If zero, the current file is truncated when it is rotated.
+    pub max_rotated_files: usize,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("zebra-rpc-audit.log"),
+            max_file_size: 64 * 1024 * 1024,
+            max_rotated_files: 10,
+        }
+    }
+}
+
+/// The outcome of an audited RPC call.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditResult {
+    /// The call succeeded.
+    Success,
+    /// The call failed with an error.
+    Error {
+        /// The error message returned to the caller.
+        message: String,
+    },
+}
+
+/// A single audit log entry.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AuditRecord {
+    /// The time the call was made, in seconds since the UNIX epoch.
+    pub time: u64,
+    /// The RPC method name.
+    pub method: String,
+    /// The hex-encoded SHA-256 hash of the serialized JSON parameters.
+    pub params_hash: String,
+    /// The address the call was made from, if known.
+    pub source: Option<SocketAddr>,
+    /// The outcome of the call.
+    pub result: AuditResult,
+}
+
+impl AuditRecord {
+    /// Create a record of a call to `method` with `params` from `source` at
+    /// `now`.
+    pub fn new(
+        method: impl Into<String>,
+        params: &serde_json::Value,
+        source: Option<SocketAddr>,
+        result: AuditResult,
+        now: SystemTime,
+    ) -> Self {
+        let time = now
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            time,
+            method: method.into(),
+            params_hash: params_hash(params),
+            source,
+            result,
+        }
+    }
+}
+
+/// Returns the hex-encoded SHA-256 hash of `params`, serialized as JSON.
+fn params_hash(params: &serde_json::Value) -> String {
+    let bytes = serde_json::to_vec(params).expect("JSON values are always serializable");
+    hex::encode(Sha256::digest(&bytes))
+}
+
+/// An append-only audit log file, which is rotated when it gets too large.
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditLogConfig,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    /// Open the audit log at `config.path`, appending to any existing log.
+    pub fn open(config: AuditLogConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self { config, file, size })
+    }
+
+    /// The path of the current audit log file.
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// Write `record` to the log, rotating the log first if it would exceed
+    /// the maximum file size.
+    ///
+    /// Records are flushed before this method returns, so callers can
+    /// return the RPC response once the call has been logged.
+    pub fn record(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // Don't rotate an empty file, even if a single record is too large
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Move the current file to `.1`, shift the older rotated files, and
+    /// start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let max = self.config.max_rotated_files;
+        if max > 0 {
+            remove_if_exists(&self.rotated_path(max))?;
+            for index in (1..max).rev() {
+                rename_if_exists(&self.rotated_path(index), &self.rotated_path(index + 1))?;
+            }
+            fs::rename(&self.config.path, self.rotated_path(1))?;
+        } else {
+            remove_if_exists(&self.config.path)?;
+        }
+
+        self.file = open_append(&self.config.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+
+    /// The path of the rotated file with `index`.
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn records_are_appended_and_rotated() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("zebra-rpc-audit-{}", std::process::id()));
+        let path = dir.join("audit.log");
+        let now = SystemTime::now();
+        let source = Some(SocketAddr::from(([127, 0, 0, 1], 8232)));
+
+        let record = AuditRecord::new(
+            "sendrawtransaction",
+            &json!(["00"]),
+            source,
+            AuditResult::Success,
+            now,
+        );
+        let record_len = serde_json::to_vec(&record)?.len() as u64 + 1;
+        assert_ne!(record.params_hash, params_hash(&json!(["01"])));
+
+        let config = AuditLogConfig {
+            path: path.clone(),
+            max_file_size: record_len * 2,
+            max_rotated_files: 1,
+        };
+        let mut log = AuditLog::open(config.clone())?;
+        log.record(&record)?;
+        log.record(&record)?;
+
+        // Reopening appends to the existing file, then rotates it when full
+        let mut log = AuditLog::open(config)?;
+        let failed = AuditRecord {
+            result: AuditResult::Error {
+                message: "rejected".into(),
+            },
+            ..record.clone()
+        };
+        log.record(&failed)?;
+
+        let current = fs::read_to_string(&path)?;
+        let rotated = fs::read_to_string(log.rotated_path(1))?;
+        assert_eq!(current.lines().count(), 1);
+        assert_eq!(rotated.lines().count(), 2);
+        let parsed: AuditRecord = serde_json::from_str(current.trim_end())?;
+        assert_eq!(parsed, failed);
+
+        // Only one rotated file is kept
+        log.record(&record)?;
+        assert!(!log.rotated_path(2).exists());
+        assert_eq!(fs::read_to_string(log.rotated_path(1))?, current);
+
+        assert!(is_audited("submitblock"));
+        assert!(!is_audited("getinfo"));
+
+        fs::remove_dir_all(dir)
+    }
+}
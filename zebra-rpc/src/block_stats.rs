@@ -0,0 +1,369 @@
+//! The fee and size statistics returned by `getblockstats`.
+//!
+//! The field names match Bitcoin Core's `getblockstats` where Zcash has the
+//! same concept, so existing tooling can read them. Sizes are in bytes, fees
+//! are in zatoshis, and fee rates are in zatoshis per byte.
+//!
+//! Like Bitcoin Core, the fee and size statistics exclude the coinbase
+//! transaction. The per-transaction sizes and fees come from the state's
+//! `BlockTransactionInfo` response. Fees are calculated from the transparent
+//! outputs in the state, so pruned nodes can't calculate the fees of some
+//! blocks. Those fields are `null`.
+
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, str::FromStr};
+use thiserror::Error;
+
+/// The percentiles in `feerate_percentiles` and `txsize_percentiles`.
+const PERCENTILES: [u64; 5] = [10, 25, 50, 75, 90];
+
+/// The block argument to `getblockstats`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HashOrHeight {
+    /// A block hash, in internal byte order.
+    Hash([u8; 32]),
+    /// A block height in the best chain.
+    Height(u32),
+}
+
+/// An invalid `getblockstats` block argument.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("expected a block height, or a block hash as 64 hex digits")]
+pub struct InvalidHashOrHeight;
+
+impl FromStr for HashOrHeight {
+    type Err = InvalidHashOrHeight;
+
+    /// Parses a block height, or a block hash as hex in RPC byte order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() == 64 {
+            let mut hash: [u8; 32] = hex::decode(s)
+                .map_err(|_| InvalidHashOrHeight)?
+                .as_slice()
+                .try_into()
+                .map_err(|_| InvalidHashOrHeight)?;
+            hash.reverse();
+            Ok(HashOrHeight::Hash(hash))
+        } else {
+            s.parse()
+                .map(HashOrHeight::Height)
+                .map_err(|_| InvalidHashOrHeight)
+        }
+    }
+}
+
+/// The metadata for a transaction, used to calculate its block's statistics.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TransactionStats {
+    /// The serialized size of the transaction.
+    pub size: u64,
+    /// Is this the coinbase transaction?
+    pub is_coinbase: bool,
+    /// The fee paid by the transaction, or `None` if it is unknown.
+    ///
+    /// Ignored for the coinbase transaction.
+    pub fee: Option<i64>,
+    /// The number of transparent inputs, excluding coinbase inputs.
+    pub transparent_inputs: u64,
+    /// The number of transparent outputs.
+    pub transparent_outputs: u64,
+    /// Does the transaction have any Sapling spends or outputs?
+    pub sapling: bool,
+    /// Does the transaction have any Orchard actions?
+    pub orchard: bool,
+}
+
+/// The statistics for a block, in the `getblockstats` format.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetBlockStats {
+    /// The hash of the block, as hex in RPC byte order.
+    pub blockhash: String,
+    /// The height of the block.
+    pub height: u32,
+    /// The time in the block header, in seconds since the Unix epoch.
+    pub time: i64,
+    /// The number of transactions, including the coinbase transaction.
+    pub txs: u64,
+    /// The number of transparent inputs, excluding coinbase inputs.
+    pub ins: u64,
+    /// The number of transparent outputs, including coinbase outputs.
+    pub outs: u64,
+    /// The number of transactions with transparent inputs or outputs.
+    pub transparent_txs: u64,
+    /// The number of transactions with Sapling spends or outputs.
+    pub sapling_txs: u64,
+    /// The number of transactions with Orchard actions.
+    pub orchard_txs: u64,
+    /// The total size of the transactions.
+    pub total_size: u64,
+    /// The mean transaction size.
+    pub avgtxsize: u64,
+    /// The minimum transaction size.
+    pub mintxsize: u64,
+    /// The maximum transaction size.
+    pub maxtxsize: u64,
+    /// The median transaction size.
+    pub mediantxsize: u64,
+    /// The 10th, 25th, 50th, 75th, and 90th percentile transaction sizes.
+    pub txsize_percentiles: [u64; 5],
+    /// The total fees paid by the transactions.
+    pub totalfee: Option<i64>,
+    /// The mean transaction fee.
+    pub avgfee: Option<i64>,
+    /// The minimum transaction fee.
+    pub minfee: Option<i64>,
+    /// The maximum transaction fee.
+    pub maxfee: Option<i64>,
+    /// The median transaction fee.
+    pub medianfee: Option<i64>,
+    /// The mean fee rate, weighted by transaction size.
+    pub avgfeerate: Option<i64>,
+    /// The minimum transaction fee rate.
+    pub minfeerate: Option<i64>,
+    /// The maximum transaction fee rate.
+    pub maxfeerate: Option<i64>,
+    /// The 10th, 25th, 50th, 75th, and 90th percentile fee rates, weighted by
+    /// transaction size.
+    pub feerate_percentiles: Option<[i64; 5]>,
+}
+
+impl GetBlockStats {
+    /// Returns the statistics for the block at `height`, with `hash` in
+    /// internal byte order, and the header time `time`.
+    ///
+    /// `transactions` are the transactions in the block.
+    pub fn new(
+        height: u32,
+        hash: [u8; 32],
+        time: i64,
+        transactions: impl IntoIterator<Item = TransactionStats>,
+    ) -> Self {
+        let transactions: Vec<TransactionStats> = transactions.into_iter().collect();
+        let count = |predicate: fn(&TransactionStats) -> bool| {
+            transactions.iter().filter(|tx| predicate(tx)).count() as u64
+        };
+
+        let spends: Vec<&TransactionStats> =
+            transactions.iter().filter(|tx| !tx.is_coinbase).collect();
+        let mut sizes: Vec<u64> = spends.iter().map(|tx| tx.size).collect();
+        sizes.sort_unstable();
+        let total_size: u64 = sizes.iter().sum();
+
+        let fees: Option<Vec<(i64, u64)>> =
+            spends.iter().map(|tx| Some((tx.fee?, tx.size))).collect();
+        let fee_stats = fees.map(|fees| FeeStats::new(fees, total_size));
+
+        Self {
+            blockhash: hash
+                .iter()
+                .rev()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            height,
+            time,
+            txs: transactions.len() as u64,
+            ins: spends.iter().map(|tx| tx.transparent_inputs).sum(),
+            outs: transactions.iter().map(|tx| tx.transparent_outputs).sum(),
+            transparent_txs: count(|tx| tx.transparent_inputs > 0 || tx.transparent_outputs > 0),
+            sapling_txs: count(|tx| tx.sapling),
+            orchard_txs: count(|tx| tx.orchard),
+            total_size,
+            avgtxsize: total_size.checked_div(sizes.len() as u64).unwrap_or(0),
+            mintxsize: sizes.first().copied().unwrap_or(0),
+            maxtxsize: sizes.last().copied().unwrap_or(0),
+            mediantxsize: median(&sizes),
+            txsize_percentiles: percentiles(&sizes),
+            totalfee: fee_stats.as_ref().map(|stats| stats.total),
+            avgfee: fee_stats.as_ref().map(|stats| stats.average),
+            minfee: fee_stats.as_ref().map(|stats| stats.min),
+            maxfee: fee_stats.as_ref().map(|stats| stats.max),
+            medianfee: fee_stats.as_ref().map(|stats| stats.median),
+            avgfeerate: fee_stats.as_ref().map(|stats| stats.average_rate),
+            minfeerate: fee_stats.as_ref().map(|stats| stats.min_rate),
+            maxfeerate: fee_stats.as_ref().map(|stats| stats.max_rate),
+            feerate_percentiles: fee_stats.as_ref().map(|stats| stats.rate_percentiles),
+        }
+    }
+}
+
+/// The fee statistics for a block where every fee is known.
+struct FeeStats {
+    total: i64,
+    average: i64,
+    min: i64,
+    max: i64,
+    median: i64,
+    average_rate: i64,
+    min_rate: i64,
+    max_rate: i64,
+    rate_percentiles: [i64; 5],
+}
+
+impl FeeStats {
+    /// Returns the statistics for the `(fee, size)` of each transaction,
+    /// where `total_size` is the sum of the sizes.
+    fn new(fees: Vec<(i64, u64)>, total_size: u64) -> Self {
+        let mut amounts: Vec<i64> = fees.iter().map(|(fee, _)| *fee).collect();
+        amounts.sort_unstable();
+        let total: i64 = amounts.iter().sum();
+
+        let mut rates: Vec<(i64, u64)> = fees
+            .iter()
+            .map(|&(fee, size)| (fee.checked_div(size as i64).unwrap_or(0), size))
+            .collect();
+        rates.sort_unstable();
+
+        Self {
+            total,
+            average: total.checked_div(amounts.len() as i64).unwrap_or(0),
+            min: amounts.first().copied().unwrap_or(0),
+            max: amounts.last().copied().unwrap_or(0),
+            median: median(&amounts),
+            average_rate: total.checked_div(total_size as i64).unwrap_or(0),
+            min_rate: rates.first().map(|(rate, _)| *rate).unwrap_or(0),
+            max_rate: rates.last().map(|(rate, _)| *rate).unwrap_or(0),
+            rate_percentiles: weighted_percentiles(&rates, total_size),
+        }
+    }
+}
+
+/// Returns the median of the sorted `values`, rounded down, or zero if there
+/// are no values.
+fn median<T>(values: &[T]) -> T
+where
+    T: Copy + Default + std::ops::Add<Output = T> + std::ops::Div<Output = T> + From<u8>,
+{
+    let middle = values.len() / 2;
+    match values.len() {
+        0 => T::default(),
+        len if len % 2 == 0 => (values[middle - 1] + values[middle]) / T::from(2),
+        _ => values[middle],
+    }
+}
+
+/// Returns the `PERCENTILES` of the sorted `values`, or zeroes if there are
+/// no values.
+fn percentiles(values: &[u64]) -> [u64; 5] {
+    let mut percentiles = [0; 5];
+    for (percentile, rank) in percentiles.iter_mut().zip(PERCENTILES.iter()) {
+        let index = values.len() as u64 * rank / 100;
+        *percentile = values.get(index as usize).copied().unwrap_or(0);
+    }
+
+    percentiles
+}
+
+/// Returns the `PERCENTILES` of the sorted `(rate, size)` pairs, where each
+/// rate is weighted by its size, like Bitcoin Core.
+fn weighted_percentiles(rates: &[(i64, u64)], total_size: u64) -> [i64; 5] {
+    let mut percentiles = [0; 5];
+    let mut next = 0;
+    let mut cumulative_size = 0;
+
+    for &(rate, size) in rates {
+        cumulative_size += size;
+        while next < PERCENTILES.len() && cumulative_size * 100 >= total_size * PERCENTILES[next] {
+            percentiles[next] = rate;
+            next += 1;
+        }
+    }
+
+    percentiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spend(size: u64, fee: Option<i64>) -> TransactionStats {
+        TransactionStats {
+            size,
+            is_coinbase: false,
+            fee,
+            transparent_inputs: 1,
+            transparent_outputs: 2,
+            sapling: false,
+            orchard: false,
+        }
+    }
+
+    #[test]
+    fn block_stats_exclude_the_coinbase() {
+        let coinbase = TransactionStats {
+            size: 150,
+            is_coinbase: true,
+            fee: None,
+            transparent_inputs: 0,
+            transparent_outputs: 1,
+            sapling: false,
+            orchard: false,
+        };
+        let shielded = TransactionStats {
+            sapling: true,
+            transparent_inputs: 0,
+            transparent_outputs: 0,
+            ..spend(2_000, Some(10_000))
+        };
+        let mut hash = [0; 32];
+        hash[0] = 0xab;
+
+        let stats = GetBlockStats::new(
+            1_000,
+            hash,
+            1_568_000_000,
+            vec![
+                coinbase,
+                spend(200, Some(1_000)),
+                spend(300, Some(300)),
+                shielded,
+            ],
+        );
+        assert!(stats.blockhash.ends_with("ab"));
+        assert_eq!(stats.txs, 4);
+        assert_eq!(stats.ins, 2);
+        assert_eq!(stats.outs, 5);
+        assert_eq!(stats.transparent_txs, 3);
+        assert_eq!(stats.sapling_txs, 1);
+        assert_eq!(stats.orchard_txs, 0);
+        assert_eq!(stats.total_size, 2_500);
+        assert_eq!(stats.mintxsize, 200);
+        assert_eq!(stats.maxtxsize, 2_000);
+        assert_eq!(stats.mediantxsize, 300);
+        assert_eq!(stats.txsize_percentiles, [200, 200, 300, 2_000, 2_000]);
+        assert_eq!(stats.totalfee, Some(11_300));
+        assert_eq!(stats.minfee, Some(300));
+        assert_eq!(stats.medianfee, Some(1_000));
+        assert_eq!(stats.minfeerate, Some(1));
+        assert_eq!(stats.maxfeerate, Some(5));
+        assert_eq!(stats.avgfeerate, Some(4));
+        // The shielded transaction is most of the block by size
+        assert_eq!(stats.feerate_percentiles, Some([1, 5, 5, 5, 5]));
+    }
+
+    #[test]
+    fn unknown_fees_are_null() {
+        let stats = GetBlockStats::new(1, [0; 32], 0, vec![spend(200, None), spend(100, Some(1))]);
+        assert_eq!(stats.totalfee, None);
+        assert_eq!(stats.feerate_percentiles, None);
+        assert_eq!(stats.total_size, 300);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert!(json["maxfee"].is_null());
+    }
+
+    #[test]
+    fn parse_hash_or_height() {
+        assert_eq!("1000".parse(), Ok(HashOrHeight::Height(1_000)));
+
+        let mut hash = [0; 32];
+        hash[31] = 0x01;
+        let hex = format!("01{}", "00".repeat(31));
+        assert_eq!(hex.parse(), Ok(HashOrHeight::Hash(hash)));
+
+        assert_eq!("-1".parse::<HashOrHeight>(), Err(InvalidHashOrHeight));
+        assert_eq!(
+            "zz".repeat(32).parse::<HashOrHeight>(),
+            Err(InvalidHashOrHeight)
+        );
+    }
+}
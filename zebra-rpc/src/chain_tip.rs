@@ -0,0 +1,106 @@
+//! The chain tip status returned by `getbestblockheightandhash`.
+//!
+//! External health checks poll this RPC at a high frequency, so it combines
+//! the tip height, tip hash, and sync progress into a single cheap call,
+//! instead of separate `getblockcount`, `getbestblockhash`, and
+//! `getblockheader` calls.
+
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The status of the best chain tip.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ChainTipStatus {
+    /// The height of the best chain tip.
+    pub height: u32,
+    /// The hash of the best chain tip, as hex in RPC byte order.
+    pub hash: String,
+    /// The time in the tip block header, in seconds since the Unix epoch.
+    pub time: i64,
+    /// The number of seconds since the tip block header time.
+    ///
+    /// Zero if the tip block time is in the future.
+    pub seconds_since_tip: u64,
+    /// The estimated number of blocks between the tip and the peers' chain
+    /// tips.
+    ///
+    /// Zero if there are no peers, or the peers are behind the tip.
+    pub estimated_blocks_behind: u32,
+}
+
+impl ChainTipStatus {
+    /// Returns the status of the tip at `height`, with `hash` in internal
+    /// byte order, and the header time `block_time`.
+    ///
+    /// `peer_heights` are the chain heights advertised by connected peers.
+    /// The estimate uses the median height, so a few peers with incorrect
+    /// heights can't make the node look far behind.
+    pub fn new(
+        height: u32,
+        hash: [u8; 32],
+        block_time: SystemTime,
+        now: SystemTime,
+        peer_heights: impl IntoIterator<Item = u32>,
+    ) -> Self {
+        let time = match block_time.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let seconds_since_tip = now
+            .duration_since(block_time)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        let mut peer_heights: Vec<u32> = peer_heights.into_iter().collect();
+        peer_heights.sort_unstable();
+        let estimated_blocks_behind = peer_heights
+            .get(peer_heights.len() / 2)
+            .map(|peer_height| peer_height.saturating_sub(height))
+            .unwrap_or(0);
+
+        Self {
+            height,
+            hash: hash
+                .iter()
+                .rev()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            time,
+            seconds_since_tip,
+            estimated_blocks_behind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn tip_status_uses_median_peer_height() {
+        let block_time = UNIX_EPOCH + Duration::from_secs(1_568_000_000);
+        let mut hash = [0; 32];
+        hash[0] = 0xab;
+        hash[31] = 0x01;
+
+        let status = ChainTipStatus::new(
+            100,
+            hash,
+            block_time,
+            block_time + Duration::from_secs(75),
+            vec![1_000_000, 90, 110, 112, 0],
+        );
+        assert_eq!(status.height, 100);
+        assert!(status.hash.starts_with("01"));
+        assert!(status.hash.ends_with("ab"));
+        assert_eq!(status.hash.len(), 64);
+        assert_eq!(status.time, 1_568_000_000);
+        assert_eq!(status.seconds_since_tip, 75);
+        assert_eq!(status.estimated_blocks_behind, 10);
+
+        let status = ChainTipStatus::new(100, hash, block_time, UNIX_EPOCH, Vec::new());
+        assert_eq!(status.seconds_since_tip, 0);
+        assert_eq!(status.estimated_blocks_behind, 0);
+    }
+}
@@ -0,0 +1,106 @@
+//! The transaction statistics returned by `getchaintxstats`.
+//!
+//! Monitoring services poll this RPC to chart network usage. The field names
+//! match Bitcoin Core's `getchaintxstats`, and the counts come from the
+//! state's `ChainTxStats` response.
+//!
+//! Bitcoin Core also returns `txcount`, the total number of transactions in
+//! the chain. Zebra doesn't store cumulative transaction counts, so that
+//! optional field is always omitted.
+
+use serde::{Deserialize, Serialize};
+
+/// The transaction statistics for a window of blocks, in the
+/// `getchaintxstats` format.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetChainTxStats {
+    /// The time in the header of the last block in the window, in seconds
+    /// since the Unix epoch.
+    pub time: i64,
+    /// The hash of the last block in the window, as hex in RPC byte order.
+    pub window_final_block_hash: String,
+    /// The height of the last block in the window.
+    pub window_final_block_height: u32,
+    /// The number of blocks in the window.
+    pub window_block_count: u32,
+    /// The number of transactions in the window.
+    ///
+    /// Only returned if the window isn't empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_tx_count: Option<u64>,
+    /// The number of seconds between the header times at the start and end
+    /// of the window.
+    ///
+    /// Only returned if the window isn't empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_interval: Option<i64>,
+    /// The average number of transactions per second in the window.
+    ///
+    /// Only returned if the window interval is positive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txrate: Option<f64>,
+}
+
+impl GetChainTxStats {
+    /// Returns the statistics for the `block_count` blocks that end at the
+    /// block at `height`, with `hash` in internal byte order, and the header
+    /// time `time`.
+    ///
+    /// The window contains `tx_count` transactions, and `interval` seconds
+    /// between the header times at its start and end.
+    pub fn new(
+        height: u32,
+        hash: [u8; 32],
+        time: i64,
+        block_count: u32,
+        tx_count: u64,
+        interval: i64,
+    ) -> Self {
+        let (window_tx_count, window_interval) = if block_count > 0 {
+            (Some(tx_count), Some(interval))
+        } else {
+            (None, None)
+        };
+
+        Self {
+            time,
+            window_final_block_hash: hash
+                .iter()
+                .rev()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            window_final_block_height: height,
+            window_block_count: block_count,
+            window_tx_count,
+            window_interval,
+            txrate: window_interval
+                .filter(|&interval| interval > 0)
+                .map(|interval| tx_count as f64 / interval as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_tx_stats_fields() {
+        let stats = GetChainTxStats::new(1_000, [0; 32], 1_568_000_000, 100, 1_500, 7_500);
+        assert_eq!(stats.window_tx_count, Some(1_500));
+        assert_eq!(stats.window_interval, Some(7_500));
+        assert!((stats.txrate.unwrap() - 0.2).abs() < 1e-9);
+
+        // Empty windows only have the final block fields
+        let stats = GetChainTxStats::new(1_000, [0; 32], 1_568_000_000, 0, 0, 0);
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["window_block_count"], 0);
+        assert!(json.get("window_tx_count").is_none());
+        assert!(json.get("txrate").is_none());
+
+        // Header times can go backwards
+        let stats = GetChainTxStats::new(1_000, [0; 32], 1_568_000_000, 1, 2, -10);
+        assert_eq!(stats.window_interval, Some(-10));
+        assert_eq!(stats.txrate, None);
+    }
+}
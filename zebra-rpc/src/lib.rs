@@ -1,6 +1,78 @@
+//! RPC support for Zebra. 🦓
+//!
+//! `zebrad start` serves Zebra's RPC methods over HTTP, using JSON-RPC, if
+//! `listen_addr` is set. This crate contains the RPC configuration, the
+//! admission controls used by public RPC endpoints, the tracking for locally
+//! submitted transactions, the chain tip status used for monitoring, the
+//! block, chain, and shielded pool statistics used by fee and usage tooling,
+//! and the audit log for administrative calls.
+
 #![doc(html_favicon_url = "https://www.zfnd.org/images/zebra-favicon-128.png")]
 #![doc(html_logo_url = "https://www.zfnd.org/images/zebra-icon.png")]
 #![doc(html_root_url = "https://doc.zebra.zfnd.org/zebra_rpc")]
+#![warn(missing_docs)]
+
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, time::Duration};
+
+pub mod audit_log;
+pub mod block_stats;
+pub mod chain_tip;
+pub mod chain_tx_stats;
+pub mod local_submissions;
+pub mod rate_limit;
+pub mod shielded_counts;
+
+pub use audit_log::{AuditLog, AuditLogConfig, AuditRecord, AuditResult};
+pub use block_stats::{GetBlockStats, HashOrHeight, TransactionStats};
+pub use chain_tip::ChainTipStatus;
+pub use chain_tx_stats::GetChainTxStats;
+pub use local_submissions::{ListedTransaction, LocalStatus, LocalSubmissions, LocalTransaction};
+pub use rate_limit::{FaucetConfig, SubmissionError, SubmissionLimiter};
+pub use shielded_counts::{GetShieldedCounts, PoolCounts};
+
+/// Configuration for the RPC interface.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Config {
+    /// The address that `zebrad start` serves JSON-RPC requests on.
+    ///
+    /// The endpoint doesn't authenticate clients.
+    ///
+    /// If `None`, the RPC endpoint is disabled.
+    pub listen_addr: Option<SocketAddr>,
+
+    /// Enables faucet mode, for public testnet RPC endpoints.
+    ///
+    /// In faucet mode, transactions submitted over RPC are subject to
+    /// per-source rate limits and size caps, and zebrad disables its optional
+    /// indexes, so the node can run on a small instance.
+    ///
+    /// If `None`, faucet mode is disabled.
+    pub faucet_mode: Option<FaucetConfig>,
+
+    /// Enables the audit log of administrative and state-mutating RPC calls.
+    ///
+    /// If `None`, the audit log is disabled.
+    pub audit_log: Option<AuditLogConfig>,
+
+    // Note: due to the way this is rendered by the toml
+    // serializer, the Duration fields should come last.
+    /// How often locally submitted transactions are rebroadcast, until they
+    /// are mined or they expire.
+    pub rebroadcast_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: None,
+            faucet_mode: None,
+            audit_log: None,
+            rebroadcast_interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
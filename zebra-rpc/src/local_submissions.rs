@@ -0,0 +1,318 @@
+//! Rebroadcast and confirmation tracking for locally submitted transactions.
+//!
+//! Transactions submitted via `sendrawtransaction` are recorded in a
+//! persistent table. Pending transactions are rebroadcast periodically, until
+//! they are mined or they expire. The table backs `listlocaltransactions`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The status of a locally submitted transaction.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum LocalStatus {
+    /// The transaction hasn't been mined yet, and is rebroadcast periodically.
+    Pending,
+    /// The transaction was mined in the best chain at `height`.
+    Confirmed {
+        /// The height of the block containing the transaction.
+        height: u32,
+    },
+    /// The transaction's expiry height has passed, and it can no longer be
+    /// mined.
+    Expired,
+}
+
+/// A locally submitted transaction.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LocalTransaction {
+    /// The transaction ID.
+    pub txid: [u8; 32],
+    /// The serialized transaction, as submitted.
+    pub raw: Vec<u8>,
+    /// The last height the transaction can be mined at, or `None` if it
+    /// never expires.
+    pub expiry_height: Option<u32>,
+    /// The time the transaction was submitted.
+    pub submitted_at: SystemTime,
+    /// The time the transaction was last broadcast, if it has been broadcast.
+    pub last_broadcast: Option<SystemTime>,
+    /// The number of times the transaction has been broadcast.
+    pub broadcast_count: u32,
+    /// The current status of the transaction.
+    pub status: LocalStatus,
+}
+
+/// A locally submitted transaction, in the `listlocaltransactions` format.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ListedTransaction {
+    /// The transaction ID, as hex in RPC byte order.
+    pub txid: String,
+    /// The status of the transaction: `pending`, `confirmed`, or `expired`.
+    pub status: String,
+    /// The height of the best chain block containing the transaction.
+    ///
+    /// Only returned for confirmed transactions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// The last height the transaction can be mined at.
+    ///
+    /// Only returned if the transaction has an expiry height.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiryheight: Option<u32>,
+    /// The time the transaction was submitted, in seconds since the Unix
+    /// epoch.
+    pub time: u64,
+    /// The time the transaction was last broadcast, in seconds since the
+    /// Unix epoch.
+    ///
+    /// Only returned if the transaction has been broadcast.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastbroadcast: Option<u64>,
+    /// The number of times the transaction has been broadcast.
+    pub broadcastcount: u32,
+}
+
+impl From<&LocalTransaction> for ListedTransaction {
+    fn from(tx: &LocalTransaction) -> Self {
+        let (status, height) = match tx.status {
+            LocalStatus::Pending => ("pending", None),
+            LocalStatus::Confirmed { height } => ("confirmed", Some(height)),
+            LocalStatus::Expired => ("expired", None),
+        };
+        let unix_time = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0)
+        };
+
+        Self {
+            txid: tx
+                .txid
+                .iter()
+                .rev()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            status: status.into(),
+            height,
+            expiryheight: tx.expiry_height,
+            time: unix_time(tx.submitted_at),
+            lastbroadcast: tx.last_broadcast.map(unix_time),
+            broadcastcount: tx.broadcast_count,
+        }
+    }
+}
+
+/// A persistent table of locally submitted transactions.
+#[derive(Clone, Debug)]
+pub struct LocalSubmissions {
+    path: PathBuf,
+    rebroadcast_interval: Duration,
+    transactions: BTreeMap<[u8; 32], LocalTransaction>,
+}
+
+impl LocalSubmissions {
+    /// Load the table stored at `path`, or create an empty table if `path`
+    /// doesn't exist.
+    ///
+    /// Pending transactions are rebroadcast every `rebroadcast_interval`.
+    pub fn load(path: impl Into<PathBuf>, rebroadcast_interval: Duration) -> io::Result<Self> {
+        let path = path.into();
+        let transactions = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<LocalTransaction>>(&bytes)?
+                .into_iter()
+                .map(|tx| (tx.txid, tx))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path,
+            rebroadcast_interval,
+            transactions,
+        })
+    }
+
+    /// Write the table to its path.
+    ///
+    /// The table is written to a temporary file, then renamed, so a crash
+    /// can't leave a partially written table.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let transactions: Vec<_> = self.transactions.values().collect();
+        let bytes = serde_json::to_vec(&transactions)?;
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    /// The path the table is stored at.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record a transaction submitted at `now`.
+    ///
+    /// Resubmitting a known transaction makes it pending again, so that it is
+    /// rebroadcast.
+    pub fn submit(
+        &mut self,
+        txid: [u8; 32],
+        raw: Vec<u8>,
+        expiry_height: Option<u32>,
+        now: SystemTime,
+    ) {
+        let tx = self.transactions.entry(txid).or_insert(LocalTransaction {
+            txid,
+            raw,
+            expiry_height,
+            submitted_at: now,
+            last_broadcast: None,
+            broadcast_count: 0,
+            status: LocalStatus::Pending,
+        });
+        tx.status = LocalStatus::Pending;
+    }
+
+    /// Returns the pending transactions that are due to be broadcast at
+    /// `now`.
+    pub fn due_for_broadcast(&self, now: SystemTime) -> Vec<&LocalTransaction> {
+        self.transactions
+            .values()
+            .filter(|tx| tx.status == LocalStatus::Pending)
+            .filter(|tx| match tx.last_broadcast {
+                Some(last) => now
+                    .duration_since(last)
+                    .map(|elapsed| elapsed >= self.rebroadcast_interval)
+                    .unwrap_or(false),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Record that the transaction `txid` was broadcast at `now`.
+    pub fn mark_broadcast(&mut self, txid: &[u8; 32], now: SystemTime) {
+        if let Some(tx) = self.transactions.get_mut(txid) {
+            tx.last_broadcast = Some(now);
+            tx.broadcast_count += 1;
+        }
+    }
+
+    /// Record that the transaction `txid` was mined in the best chain at
+    /// `height`.
+    pub fn mark_confirmed(&mut self, txid: &[u8; 32], height: u32) {
+        if let Some(tx) = self.transactions.get_mut(txid) {
+            tx.status = LocalStatus::Confirmed { height };
+        }
+    }
+
+    /// Record that the block containing the transaction `txid` was removed
+    /// from the best chain, so the transaction is pending again.
+    ///
+    /// Expired transactions are marked as expired by the next call to
+    /// `expire`.
+    pub fn mark_unconfirmed(&mut self, txid: &[u8; 32]) {
+        if let Some(tx) = self.transactions.get_mut(txid) {
+            if let LocalStatus::Confirmed { .. } = tx.status {
+                tx.status = LocalStatus::Pending;
+            }
+        }
+    }
+
+    /// Mark the pending transactions that can't be mined after the best chain
+    /// tip at `tip_height` as expired.
+    ///
+    /// Returns the number of newly expired transactions.
+    pub fn expire(&mut self, tip_height: u32) -> usize {
+        let mut expired = 0;
+        for tx in self.transactions.values_mut() {
+            if tx.status != LocalStatus::Pending {
+                continue;
+            }
+            // An expiry height of zero means the transaction never expires.
+            match tx.expiry_height {
+                Some(expiry_height) if expiry_height != 0 && tip_height >= expiry_height => {
+                    tx.status = LocalStatus::Expired;
+                    expired += 1;
+                }
+                _ => {}
+            }
+        }
+        expired
+    }
+
+    /// Forget confirmed and expired transactions that were submitted before
+    /// `cutoff`.
+    pub fn prune(&mut self, cutoff: SystemTime) {
+        self.transactions
+            .retain(|_, tx| tx.status == LocalStatus::Pending || tx.submitted_at >= cutoff);
+    }
+
+    /// Returns the transaction `txid`, if it was submitted locally.
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&LocalTransaction> {
+        self.transactions.get(txid)
+    }
+
+    /// Returns all the locally submitted transactions, for
+    /// `listlocaltransactions`.
+    pub fn list(&self) -> impl Iterator<Item = &LocalTransaction> {
+        self.transactions.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebroadcast_until_confirmed_or_expired() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("zebra-rpc-local-{}", std::process::id()));
+        let path = dir.join("local_submissions.json");
+        let interval = Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        let mut submissions = LocalSubmissions::load(&path, interval)?;
+        submissions.submit([1; 32], vec![1], Some(10), now);
+        submissions.submit([2; 32], vec![2], None, now);
+        assert_eq!(submissions.due_for_broadcast(now).len(), 2);
+
+        submissions.mark_broadcast(&[1; 32], now);
+        submissions.mark_broadcast(&[2; 32], now);
+        assert!(submissions.due_for_broadcast(now).is_empty());
+        assert_eq!(submissions.due_for_broadcast(now + interval).len(), 2);
+
+        submissions.mark_confirmed(&[2; 32], 5);
+        assert_eq!(submissions.expire(9), 0);
+        assert_eq!(submissions.expire(10), 1);
+        assert!(submissions.due_for_broadcast(now + interval).is_empty());
+
+        submissions.save()?;
+        let loaded = LocalSubmissions::load(&path, interval)?;
+        assert_eq!(
+            loaded.list().cloned().collect::<Vec<_>>(),
+            submissions.list().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(loaded.get(&[1; 32]).unwrap().status, LocalStatus::Expired);
+        assert_eq!(
+            loaded.get(&[2; 32]).unwrap().status,
+            LocalStatus::Confirmed { height: 5 }
+        );
+
+        let listed = ListedTransaction::from(loaded.get(&[2; 32]).unwrap());
+        assert_eq!(listed.txid, "02".repeat(32));
+        assert_eq!(listed.status, "confirmed");
+        assert_eq!(listed.height, Some(5));
+        assert_eq!(listed.broadcastcount, 1);
+
+        fs::remove_dir_all(dir)
+    }
+}
@@ -0,0 +1,223 @@
+//! Rate limits for transactions submitted to public RPC endpoints.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    time::Instant,
+};
+use thiserror::Error;
+
+/// The number of tracked sources that triggers pruning of idle sources.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Faucet mode limits for transaction submissions.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct FaucetConfig {
+    /// The maximum number of transactions each source can submit per minute.
+    ///
+    /// Sources can submit this many transactions in a burst, then they are
+    /// limited to a steady rate.
+    pub max_submissions_per_minute: u32,
+
+    /// The maximum serialized size of a submitted transaction, in bytes.
+    pub max_tx_size: usize,
+}
+
+impl Default for FaucetConfig {
+    fn default() -> Self {
+        Self {
+            max_submissions_per_minute: 10,
+            max_tx_size: 10_000,
+        }
+    }
+}
+
+/// A transaction submission rejected by the faucet mode limits.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum SubmissionError {
+    /// The transaction is larger than `max_tx_size`.
+    #[error("transaction size {size} bytes exceeds the maximum of {max_tx_size} bytes")]
+    TooLarge {
+        /// The serialized size of the transaction.
+        size: usize,
+        /// The configured maximum size.
+        max_tx_size: usize,
+    },
+
+    /// The source has exceeded `max_submissions_per_minute`.
+    #[error("too many transactions submitted from {address}, try again later")]
+    RateLimited {
+        /// The rate-limited source address.
+        address: IpAddr,
+    },
+}
+
+/// The remaining submissions for a source, refilled over time.
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Tracks transaction submissions by source address, and enforces the faucet
+/// mode limits.
+///
+/// IPv6 sources are limited by their /64 prefix, because a single user often
+/// controls an entire /64.
+#[derive(Clone, Debug)]
+pub struct SubmissionLimiter {
+    config: FaucetConfig,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl SubmissionLimiter {
+    /// Create a new limiter using `config`.
+    pub fn new(config: FaucetConfig) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Check a transaction of `tx_size` bytes, submitted by `source` at
+    /// `now`.
+    ///
+    /// If the transaction is accepted, counts it against the source's limit.
+    pub fn check(
+        &mut self,
+        source: IpAddr,
+        tx_size: usize,
+        now: Instant,
+    ) -> Result<(), SubmissionError> {
+        if tx_size > self.config.max_tx_size {
+            return Err(SubmissionError::TooLarge {
+                size: tx_size,
+                max_tx_size: self.config.max_tx_size,
+            });
+        }
+
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+
+        let capacity = self.capacity();
+        let rate = self.refill_rate();
+        let bucket = self.buckets.entry(limit_key(source)).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now
+            .checked_duration_since(bucket.last_refill)
+            .unwrap_or_default();
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(SubmissionError::RateLimited { address: source });
+        }
+        bucket.tokens -= 1.0;
+
+        Ok(())
+    }
+
+    /// Forget sources that would have a full bucket at `now`.
+    fn prune(&mut self, now: Instant) {
+        let capacity = self.capacity();
+        let rate = self.refill_rate();
+
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now
+                .checked_duration_since(bucket.last_refill)
+                .unwrap_or_default();
+            bucket.tokens + elapsed.as_secs_f64() * rate < capacity
+        });
+    }
+
+    /// The maximum number of submissions in a burst.
+    fn capacity(&self) -> f64 {
+        f64::from(self.config.max_submissions_per_minute)
+    }
+
+    /// The number of submissions added to each bucket per second.
+    fn refill_rate(&self) -> f64 {
+        self.capacity() / 60.0
+    }
+}
+
+/// Returns the address used to track the limits for `source`.
+fn limit_key(source: IpAddr) -> IpAddr {
+    match source {
+        IpAddr::V4(_) => source,
+        IpAddr::V6(v6) => {
+            let mut segments = v6.segments();
+            for segment in &mut segments[4..] {
+                *segment = 0;
+            }
+            IpAddr::V6(Ipv6Addr::from(segments))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn rate_limits() {
+        let config = FaucetConfig {
+            max_submissions_per_minute: 2,
+            max_tx_size: 100,
+        };
+        let mut limiter = SubmissionLimiter::new(config);
+        let source: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check(source, 101, now),
+            Err(SubmissionError::TooLarge {
+                size: 101,
+                max_tx_size: 100
+            })
+        );
+
+        assert_eq!(limiter.check(source, 100, now), Ok(()));
+        assert_eq!(limiter.check(source, 100, now), Ok(()));
+        assert_eq!(
+            limiter.check(source, 100, now),
+            Err(SubmissionError::RateLimited { address: source })
+        );
+
+        // Other sources have their own limits
+        assert_eq!(limiter.check(other, 100, now), Ok(()));
+
+        // One submission is refilled every 30 seconds
+        let later = now + Duration::from_secs(30);
+        assert_eq!(limiter.check(source, 100, later), Ok(()));
+        assert!(limiter.check(source, 100, later).is_err());
+    }
+
+    #[test]
+    fn ipv6_prefix_limits() {
+        let config = FaucetConfig {
+            max_submissions_per_minute: 1,
+            max_tx_size: 100,
+        };
+        let mut limiter = SubmissionLimiter::new(config);
+        let source: IpAddr = "2001:db8::1".parse().unwrap();
+        let same_prefix: IpAddr = "2001:db8::2".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(source, 100, now), Ok(()));
+        assert_eq!(
+            limiter.check(same_prefix, 100, now),
+            Err(SubmissionError::RateLimited {
+                address: same_prefix
+            })
+        );
+    }
+}
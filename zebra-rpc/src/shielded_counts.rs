@@ -0,0 +1,95 @@
+//! The shielded pool statistics returned by `z_getshieldedcounts`.
+//!
+//! Statistics sites chart the growth of the shielded pools. Like zcashd's
+//! `z_getnotescount`, the response has a field for each pool, but it counts
+//! the note commitments and nullifiers in the chain, rather than the notes in
+//! a wallet. The counts come from the state's `ShieldedCounts` response, at
+//! the best chain tip, or at a requested height.
+//!
+//! Zebra doesn't support Orchard yet, so the Orchard counts are always zero.
+
+use serde::{Deserialize, Serialize};
+
+/// The number of leaves in each Sapling note commitment subtree.
+const SAPLING_SUBTREE_LEAVES: u64 = 1 << 16;
+
+/// The note commitment and nullifier counts for each shielded pool, in the
+/// `z_getshieldedcounts` format.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GetShieldedCounts {
+    /// The height of the block that the counts are for.
+    pub height: u32,
+    /// The Sprout pool counts.
+    pub sprout: PoolCounts,
+    /// The Sapling pool counts.
+    pub sapling: PoolCounts,
+    /// The Orchard pool counts.
+    pub orchard: PoolCounts,
+}
+
+/// The counts for a shielded pool.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PoolCounts {
+    /// The number of leaves in the pool's note commitment tree.
+    pub commitments: u64,
+    /// The number of revealed nullifiers.
+    pub nullifiers: u64,
+    /// The number of complete or partial note commitment subtrees.
+    ///
+    /// Only returned for pools that have subtrees.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subtrees: Option<u64>,
+}
+
+impl GetShieldedCounts {
+    /// Returns the statistics after the block at `height`, which has
+    /// `sprout_commitments` and `sapling_commitments` note commitments, and
+    /// `sprout_nullifiers` and `sapling_nullifiers` nullifiers.
+    pub fn new(
+        height: u32,
+        sprout_commitments: u64,
+        sprout_nullifiers: u64,
+        sapling_commitments: u64,
+        sapling_nullifiers: u64,
+    ) -> Self {
+        Self {
+            height,
+            sprout: PoolCounts {
+                commitments: sprout_commitments,
+                nullifiers: sprout_nullifiers,
+                subtrees: None,
+            },
+            sapling: PoolCounts {
+                commitments: sapling_commitments,
+                nullifiers: sapling_nullifiers,
+                subtrees: Some(
+                    (sapling_commitments + SAPLING_SUBTREE_LEAVES - 1) / SAPLING_SUBTREE_LEAVES,
+                ),
+            },
+            orchard: PoolCounts {
+                subtrees: Some(0),
+                ..PoolCounts::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shielded_counts_fields() {
+        let counts = GetShieldedCounts::new(1_000, 10, 5, (1 << 16) + 1, 7);
+        assert_eq!(counts.sapling.subtrees, Some(2));
+
+        let json = serde_json::to_value(&counts).unwrap();
+        assert_eq!(json["height"], 1_000);
+        assert_eq!(json["sprout"]["commitments"], 10);
+        assert_eq!(json["sprout"]["nullifiers"], 5);
+        assert!(json["sprout"].get("subtrees").is_none());
+        assert_eq!(json["sapling"]["nullifiers"], 7);
+        assert_eq!(json["orchard"]["commitments"], 0);
+        assert_eq!(json["orchard"]["subtrees"], 0);
+    }
+}
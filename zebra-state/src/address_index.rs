@@ -0,0 +1,516 @@
+//! An optional index from transparent addresses to their balances, unspent
+//! outputs, and transactions.
+//!
+//! The index tracks the total value received and spent by each P2PKH and P2SH
+//! address, the outputs paying to each address that are still unspent, and the
+//! transactions that pay to or spend from each address. Outputs with other
+//! scripts are not attributed to any address.
+//!
+//! The index only covers blocks committed while it was enabled.
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::{TryFrom, TryInto},
+    ops::RangeInclusive,
+};
+use zebra_chain::{
+    addresses::transparent::{ScriptKind, TransparentAddress},
+    block::Block,
+    transaction::{OutPoint, TransactionHash, TransparentInput},
+    types::{
+        amount::{Amount, NonNegative},
+        BlockHeight, Script,
+    },
+};
+
+use crate::value_pools::{outpoint_key, transaction_outputs, OUTPOINT_KEY_SIZE};
+
+/// The size of an address index key.
+pub(crate) const ADDRESS_KEY_SIZE: usize = 21;
+
+/// The size of a serialized `AddressTotals`.
+pub(crate) const ADDRESS_TOTALS_SIZE: usize = 16;
+
+/// The size of a serialized `IndexedOutput`.
+pub(crate) const INDEXED_OUTPUT_SIZE: usize = ADDRESS_KEY_SIZE + 4;
+
+/// The size of a UTXO index key.
+pub(crate) const UTXO_KEY_SIZE: usize = INDEXED_OUTPUT_SIZE + OUTPOINT_KEY_SIZE;
+
+/// The size of a transaction location index key.
+pub(crate) const TX_LOCATION_KEY_SIZE: usize = INDEXED_OUTPUT_SIZE + 4;
+
+/// An address index key: a type byte followed by the 20-byte hash.
+///
+/// Keys don't include the network, because each network has its own state.
+pub(crate) type AddressKey = [u8; ADDRESS_KEY_SIZE];
+
+/// A UTXO index key: the address key, the height of the block that created
+/// the output, and the outpoint.
+///
+/// Keys are ordered by address, then by height.
+pub(crate) type UtxoKey = [u8; UTXO_KEY_SIZE];
+
+/// A transaction location index key: the address key, the height of the block
+/// containing the transaction, and the transaction's index in the block.
+///
+/// Keys are ordered by address, then by chain order.
+pub(crate) type TxLocationKey = [u8; TX_LOCATION_KEY_SIZE];
+
+const P2PKH_KEY_TYPE: u8 = 0;
+const P2SH_KEY_TYPE: u8 = 1;
+
+/// The combined balance of a set of transparent addresses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddressBalance {
+    /// The total value of the unspent outputs paying to the addresses
+    pub balance: Amount<NonNegative>,
+    /// The total value ever received by the addresses
+    pub received: Amount<NonNegative>,
+}
+
+/// The total value received and spent by an address, or a change to those
+/// totals.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct AddressTotals {
+    pub(crate) received: i64,
+    pub(crate) spent: i64,
+}
+
+impl AddressTotals {
+    /// Returns the sum of `self` and `other`.
+    pub(crate) fn add(self, other: AddressTotals) -> AddressTotals {
+        AddressTotals {
+            received: self.received + other.received,
+            spent: self.spent + other.spent,
+        }
+    }
+
+    /// Returns the negation of `self`, for undoing a change.
+    pub(crate) fn negate(self) -> AddressTotals {
+        AddressTotals {
+            received: -self.received,
+            spent: -self.spent,
+        }
+    }
+
+    /// Serialize these totals for storage.
+    pub(crate) fn to_bytes(&self) -> [u8; ADDRESS_TOTALS_SIZE] {
+        let mut bytes = [0; ADDRESS_TOTALS_SIZE];
+        bytes[..8].copy_from_slice(&self.received.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.spent.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize totals from storage.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != ADDRESS_TOTALS_SIZE {
+            Err("stored address totals have an invalid length")?;
+        }
+
+        Ok(AddressTotals {
+            received: i64::from_le_bytes(bytes[..8].try_into()?),
+            spent: i64::from_le_bytes(bytes[8..].try_into()?),
+        })
+    }
+}
+
+impl AddressBalance {
+    /// Sum the `totals` for a set of addresses.
+    pub(crate) fn from_totals(
+        totals: impl IntoIterator<Item = AddressTotals>,
+    ) -> Result<Self, Error> {
+        let sum = totals
+            .into_iter()
+            .fold(AddressTotals::default(), AddressTotals::add);
+
+        Ok(AddressBalance {
+            balance: Amount::try_from(sum.received - sum.spent)?,
+            received: Amount::try_from(sum.received)?,
+        })
+    }
+}
+
+/// Returns the index key for `address`.
+pub(crate) fn address_key(address: &TransparentAddress) -> AddressKey {
+    let (key_type, hash) = match address {
+        TransparentAddress::PayToPublicKeyHash { pub_key_hash, .. } => {
+            (P2PKH_KEY_TYPE, pub_key_hash)
+        }
+        TransparentAddress::PayToScriptHash { script_hash, .. } => (P2SH_KEY_TYPE, script_hash),
+    };
+
+    let mut key = [0; ADDRESS_KEY_SIZE];
+    key[0] = key_type;
+    key[1..].copy_from_slice(hash);
+    key
+}
+
+/// Returns the index key for the address paid by `script`, if it is a P2PKH or
+/// P2SH output script.
+pub(crate) fn script_address_key(script: &Script) -> Option<AddressKey> {
+    let (key_type, hash) = match ScriptKind::classify(script) {
+        ScriptKind::PayToPublicKeyHash { pub_key_hash } => (P2PKH_KEY_TYPE, pub_key_hash),
+        ScriptKind::PayToScriptHash { script_hash } => (P2SH_KEY_TYPE, script_hash),
+        ScriptKind::NonStandard => return None,
+    };
+
+    let mut key = [0; ADDRESS_KEY_SIZE];
+    key[0] = key_type;
+    key[1..].copy_from_slice(&hash);
+    Some(key)
+}
+
+/// The address paid by an indexed transparent output, and the height of the
+/// block that created it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IndexedOutput {
+    pub(crate) address: AddressKey,
+    pub(crate) height: BlockHeight,
+}
+
+impl IndexedOutput {
+    /// Serialize this output for storage.
+    pub(crate) fn to_bytes(&self) -> [u8; INDEXED_OUTPUT_SIZE] {
+        let mut bytes = [0; INDEXED_OUTPUT_SIZE];
+        bytes[..ADDRESS_KEY_SIZE].copy_from_slice(&self.address);
+        bytes[ADDRESS_KEY_SIZE..].copy_from_slice(&self.height.0.to_be_bytes());
+        bytes
+    }
+
+    /// Deserialize an output from storage.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != INDEXED_OUTPUT_SIZE {
+            Err("stored indexed output has an invalid length")?;
+        }
+
+        Ok(IndexedOutput {
+            address: bytes[..ADDRESS_KEY_SIZE].try_into()?,
+            height: BlockHeight(u32::from_be_bytes(bytes[ADDRESS_KEY_SIZE..].try_into()?)),
+        })
+    }
+
+    /// Returns the UTXO index key for this output at `outpoint`.
+    pub(crate) fn utxo_key(&self, outpoint: &OutPoint) -> UtxoKey {
+        let mut key = [0; UTXO_KEY_SIZE];
+        key[..INDEXED_OUTPUT_SIZE].copy_from_slice(&self.to_bytes());
+        key[INDEXED_OUTPUT_SIZE..].copy_from_slice(&outpoint_key(outpoint));
+        key
+    }
+}
+
+/// The changes to the address index made by a block.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AddressIndexChanges {
+    /// The changes to each address's totals
+    pub(crate) totals: HashMap<AddressKey, AddressTotals>,
+    /// The outputs created by the block that pay to an address
+    pub(crate) created_outputs: Vec<([u8; OUTPOINT_KEY_SIZE], IndexedOutput)>,
+    /// The unspent outputs created by the block
+    pub(crate) created_utxos: Vec<(UtxoKey, Amount<NonNegative>)>,
+    /// The outputs created by earlier blocks, and spent by the block
+    pub(crate) spent_utxos: Vec<(UtxoKey, Amount<NonNegative>)>,
+    /// The transactions in the block that pay to or spend from an address
+    pub(crate) transactions: Vec<(TxLocationKey, TransactionHash)>,
+}
+
+impl AddressIndexChanges {
+    /// Add the `totals` changes from `other` to `self`, negating them if
+    /// `negate` is true.
+    pub(crate) fn merge_totals(&mut self, other: &AddressIndexChanges, negate: bool) {
+        for (address, change) in &other.totals {
+            let change = if negate { change.negate() } else { *change };
+            let total_change = self.totals.entry(*address).or_default();
+            *total_change = total_change.add(change);
+        }
+    }
+}
+
+/// Returns the changes to the address index made by `block` at `height`.
+///
+/// `prior_output` looks up transparent outputs created by earlier blocks. It
+/// returns `None` for outputs that don't pay to an address, or that were
+/// created before the index was enabled.
+pub(crate) fn block_address_changes<F>(
+    block: &Block,
+    height: BlockHeight,
+    mut prior_output: F,
+) -> Result<AddressIndexChanges, Error>
+where
+    F: FnMut(&OutPoint) -> Result<Option<(IndexedOutput, Amount<NonNegative>)>, Error>,
+{
+    let mut changes = AddressIndexChanges::default();
+    let mut created = HashMap::new();
+
+    for (tx_index, tx) in block.transactions.iter().enumerate() {
+        let mut touched = BTreeSet::new();
+
+        for input in tx.inputs() {
+            if let TransparentInput::PrevOut { outpoint, .. } = input {
+                let key = outpoint_key(outpoint);
+                let (output, value) = match created.remove(&key) {
+                    Some(spent) => spent,
+                    None => match prior_output(outpoint)? {
+                        Some(spent) => {
+                            changes
+                                .spent_utxos
+                                .push((spent.0.utxo_key(outpoint), spent.1));
+                            spent
+                        }
+                        None => continue,
+                    },
+                };
+                changes.totals.entry(output.address).or_default().spent += i64::from(value);
+                touched.insert(output.address);
+            }
+        }
+
+        for ((outpoint, value), output) in transaction_outputs(tx).into_iter().zip(tx.outputs()) {
+            if let Some(address) = script_address_key(&output.pk_script) {
+                let output = IndexedOutput { address, height };
+                changes.totals.entry(address).or_default().received += i64::from(value);
+                changes
+                    .created_outputs
+                    .push((outpoint_key(&outpoint), output));
+                created.insert(outpoint_key(&outpoint), (output, value));
+                touched.insert(address);
+            }
+        }
+
+        if !touched.is_empty() {
+            let hash = TransactionHash::from(tx.clone());
+            for address in touched {
+                let key = tx_location_key(&address, height, tx_index as u32);
+                changes.transactions.push((key, hash));
+            }
+        }
+    }
+
+    // Outputs that were created and spent in this block aren't UTXOs
+    for (key, output) in &changes.created_outputs {
+        if let Some((_, value)) = created.get(key) {
+            let outpoint = outpoint_from_key(key);
+            changes
+                .created_utxos
+                .push((output.utxo_key(&outpoint), *value));
+        }
+    }
+
+    Ok(changes)
+}
+
+/// A transparent output paying to an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressUtxo {
+    /// The address the output pays to
+    pub address: TransparentAddress,
+    /// The location of the output
+    pub outpoint: OutPoint,
+    /// The value of the output
+    pub value: Amount<NonNegative>,
+    /// The output's lock script
+    pub script: Script,
+    /// The height of the block that created the output
+    pub height: BlockHeight,
+}
+
+impl AddressUtxo {
+    /// Create a UTXO paying to `address`, from a UTXO index entry.
+    pub(crate) fn from_entry(
+        address: TransparentAddress,
+        key: &[u8],
+        value: Amount<NonNegative>,
+    ) -> Result<Self, Error> {
+        if key.len() != UTXO_KEY_SIZE {
+            Err("stored UTXO key has an invalid length")?;
+        }
+
+        let output = IndexedOutput::from_bytes(&key[..INDEXED_OUTPUT_SIZE])?;
+        Ok(AddressUtxo {
+            address,
+            outpoint: outpoint_from_key(key[INDEXED_OUTPUT_SIZE..].try_into()?),
+            value,
+            script: address.output_script(),
+            height: output.height,
+        })
+    }
+}
+
+/// Returns the height of the block that created the output in a UTXO index
+/// key.
+pub(crate) fn utxo_key_height(key: &UtxoKey) -> BlockHeight {
+    let mut height = [0; 4];
+    height.copy_from_slice(&key[ADDRESS_KEY_SIZE..INDEXED_OUTPUT_SIZE]);
+    BlockHeight(u32::from_be_bytes(height))
+}
+
+/// Returns the first UTXO index key for `address` at or above `height`.
+pub(crate) fn utxo_range_start(address: &TransparentAddress, height: BlockHeight) -> UtxoKey {
+    let output = IndexedOutput {
+        address: address_key(address),
+        height,
+    };
+    let mut key = [0; UTXO_KEY_SIZE];
+    key[..INDEXED_OUTPUT_SIZE].copy_from_slice(&output.to_bytes());
+    key
+}
+
+/// Returns the transaction location index key for the transaction at
+/// `tx_index` in the block at `height`, which touches `address`.
+fn tx_location_key(address: &AddressKey, height: BlockHeight, tx_index: u32) -> TxLocationKey {
+    let mut key = [0; TX_LOCATION_KEY_SIZE];
+    key[..ADDRESS_KEY_SIZE].copy_from_slice(address);
+    key[ADDRESS_KEY_SIZE..INDEXED_OUTPUT_SIZE].copy_from_slice(&height.0.to_be_bytes());
+    key[INDEXED_OUTPUT_SIZE..].copy_from_slice(&tx_index.to_be_bytes());
+    key
+}
+
+/// Returns the first and last transaction location index keys for `address`
+/// in `heights`.
+pub(crate) fn tx_location_range(
+    address: &TransparentAddress,
+    heights: &RangeInclusive<BlockHeight>,
+) -> RangeInclusive<TxLocationKey> {
+    let address = address_key(address);
+    tx_location_key(&address, *heights.start(), 0)
+        ..=tx_location_key(&address, *heights.end(), u32::MAX)
+}
+
+/// Returns the block height and transaction index in a transaction location
+/// index key.
+pub(crate) fn tx_location(key: &[u8]) -> Result<(BlockHeight, u32), Error> {
+    if key.len() != TX_LOCATION_KEY_SIZE {
+        Err("stored transaction location key has an invalid length")?;
+    }
+
+    Ok((
+        BlockHeight(u32::from_be_bytes(
+            key[ADDRESS_KEY_SIZE..INDEXED_OUTPUT_SIZE].try_into()?,
+        )),
+        u32::from_be_bytes(key[INDEXED_OUTPUT_SIZE..].try_into()?),
+    ))
+}
+
+/// Returns the outpoint for a storage key.
+fn outpoint_from_key(key: &[u8; OUTPOINT_KEY_SIZE]) -> OutPoint {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&key[..32]);
+    let mut index = [0; 4];
+    index.copy_from_slice(&key[32..]);
+
+    OutPoint {
+        hash: TransactionHash(hash),
+        index: u32::from_le_bytes(index),
+    }
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use zebra_chain::{serialization::ZcashDeserialize, Network};
+
+    #[test]
+    fn address_keys_match_scripts() {
+        zebra_test::init();
+
+        let hash = [7; 20];
+
+        let p2pkh_address = TransparentAddress::PayToPublicKeyHash {
+            network: Network::Mainnet,
+            pub_key_hash: hash,
+        };
+        assert_eq!(
+            script_address_key(&p2pkh_address.output_script()),
+            Some(address_key(&p2pkh_address))
+        );
+
+        let p2sh_address = TransparentAddress::PayToScriptHash {
+            network: Network::Testnet,
+            script_hash: hash,
+        };
+        assert_eq!(
+            script_address_key(&p2sh_address.output_script()),
+            Some(address_key(&p2sh_address))
+        );
+
+        assert_ne!(address_key(&p2pkh_address), address_key(&p2sh_address));
+        assert_eq!(script_address_key(&Script(Vec::new())), None);
+    }
+
+    #[test]
+    fn coinbase_outputs_are_received() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        let height = BlockHeight(1);
+
+        let changes = block_address_changes(&block, height, |_| Ok(None))?;
+        let received: i64 = changes.totals.values().map(|totals| totals.received).sum();
+        let spent: i64 = changes.totals.values().map(|totals| totals.spent).sum();
+
+        let indexed_outputs: i64 = block.transactions[0]
+            .outputs()
+            .filter(|output| script_address_key(&output.pk_script).is_some())
+            .map(|output| i64::from(output.value))
+            .sum();
+        assert_eq!(received, indexed_outputs);
+        assert_eq!(spent, 0);
+
+        let totals = changes
+            .totals
+            .values()
+            .fold(AddressTotals::default(), |sum, totals| sum.add(*totals));
+        assert_eq!(AddressTotals::from_bytes(&totals.to_bytes())?, totals);
+        assert_eq!(totals.add(totals.negate()), AddressTotals::default());
+
+        assert_eq!(changes.created_utxos.len(), changes.created_outputs.len());
+        assert_eq!(changes.transactions.len(), changes.totals.len());
+        for (key, hash) in &changes.transactions {
+            assert_eq!(tx_location(key)?, (height, 0));
+            assert_eq!(*hash, TransactionHash::from(block.transactions[0].clone()));
+        }
+        assert!(changes.spent_utxos.is_empty());
+        for (key, output) in &changes.created_outputs {
+            assert_eq!(IndexedOutput::from_bytes(&output.to_bytes())?, *output);
+            assert_eq!(output.height, height);
+
+            let outpoint = outpoint_from_key(key);
+            assert_eq!(outpoint_key(&outpoint), *key);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn utxo_entries_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let address = TransparentAddress::PayToScriptHash {
+            network: Network::Mainnet,
+            script_hash: [3; 20],
+        };
+        let output = IndexedOutput {
+            address: address_key(&address),
+            height: BlockHeight(7),
+        };
+        let outpoint = OutPoint {
+            hash: TransactionHash([5; 32]),
+            index: 2,
+        };
+        let value = Amount::try_from(1000)?;
+
+        let utxo = AddressUtxo::from_entry(address, &output.utxo_key(&outpoint), value)?;
+        assert_eq!(utxo.outpoint, outpoint);
+        assert_eq!(utxo.height, BlockHeight(7));
+        assert_eq!(utxo_key_height(&output.utxo_key(&outpoint)), BlockHeight(7));
+        assert_eq!(script_address_key(&utxo.script), Some(output.address));
+
+        assert!(utxo_range_start(&address, BlockHeight(7))[..] <= output.utxo_key(&outpoint)[..]);
+        assert!(utxo_range_start(&address, BlockHeight(8))[..] > output.utxo_key(&outpoint)[..]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! A size-bounded cache of recently committed and recently read blocks.
+//!
+//! Syncing peers repeatedly request blocks near the tip, so cached blocks are
+//! returned without reading them from storage, or parsing them again.
+use std::collections::{BTreeMap, HashMap};
+use zebra_chain::block::BlockHeaderHash;
+
+use crate::SemanticallyVerifiedBlock;
+
+/// A least-recently-used cache of blocks, bounded by the total size of their
+/// serialized bytes.
+pub(crate) struct BlockCache {
+    /// The maximum total size of the cached blocks, in bytes.
+    max_bytes: usize,
+    /// The total size of the cached blocks, in bytes.
+    bytes: usize,
+    /// The cached blocks, and the tick when each block was last used.
+    blocks: HashMap<BlockHeaderHash, (SemanticallyVerifiedBlock, u64)>,
+    /// The cached block hashes, ordered by the tick when they were last used.
+    recent: BTreeMap<u64, BlockHeaderHash>,
+    /// The tick for the next use of a block.
+    next_tick: u64,
+    /// Incremented whenever blocks are removed from the state.
+    generation: u64,
+}
+
+impl BlockCache {
+    /// Returns an empty cache, which holds at most `max_bytes` of blocks.
+    ///
+    /// If `max_bytes` is zero, no blocks are cached.
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            bytes: 0,
+            blocks: HashMap::new(),
+            recent: BTreeMap::new(),
+            next_tick: 0,
+            generation: 0,
+        }
+    }
+
+    /// Returns the cached block with `hash`, and marks it as recently used.
+    pub(crate) fn get(&mut self, hash: BlockHeaderHash) -> Option<SemanticallyVerifiedBlock> {
+        let tick = self.tick();
+        let (block, last_used) = self.blocks.get_mut(&hash)?;
+        self.recent.remove(last_used);
+        self.recent.insert(tick, hash);
+        *last_used = tick;
+
+        Some(block.clone())
+    }
+
+    /// Add a block that was just committed to the cache, evicting the least
+    /// recently used blocks if the cache is full.
+    pub(crate) fn insert(&mut self, block: SemanticallyVerifiedBlock) {
+        let size = block.bytes().len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let hash = block.hash();
+        self.remove(hash);
+
+        let tick = self.tick();
+        self.bytes += size;
+        self.blocks.insert(hash, (block, tick));
+        self.recent.insert(tick, hash);
+
+        while self.bytes > self.max_bytes {
+            let oldest = *self
+                .recent
+                .values()
+                .next()
+                .expect("the cache is larger than its size limit");
+            self.remove(oldest);
+        }
+    }
+
+    /// Add a block that was read from storage to the cache, unless blocks
+    /// were removed from the state since `generation`.
+    ///
+    /// Removed blocks are never added back, even if they were read before
+    /// they were removed.
+    pub(crate) fn insert_read(&mut self, block: SemanticallyVerifiedBlock, generation: u64) {
+        if generation == self.generation {
+            self.insert(block);
+        }
+    }
+
+    /// Remove the block with `hash`, because it was removed from the state.
+    pub(crate) fn remove_from_state(&mut self, hash: BlockHeaderHash) {
+        self.generation += 1;
+        self.remove(hash);
+    }
+
+    /// Remove every block, because another process might have removed them
+    /// from the state.
+    pub(crate) fn clear(&mut self) {
+        self.generation += 1;
+        self.blocks.clear();
+        self.recent.clear();
+        self.bytes = 0;
+    }
+
+    /// Returns the current generation, which must be passed to `insert_read`.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn remove(&mut self, hash: BlockHeaderHash) {
+        if let Some((block, last_used)) = self.blocks.remove(&hash) {
+            self.bytes -= block.bytes().len();
+            self.recent.remove(&last_used);
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zebra_test::vectors::{
+        BLOCK_MAINNET_1_BYTES, BLOCK_MAINNET_2_BYTES, BLOCK_MAINNET_GENESIS_BYTES,
+    };
+
+    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block0 = SemanticallyVerifiedBlock::from_bytes(&BLOCK_MAINNET_GENESIS_BYTES[..])?;
+        let block1 = SemanticallyVerifiedBlock::from_bytes(&BLOCK_MAINNET_1_BYTES[..])?;
+        let block2 = SemanticallyVerifiedBlock::from_bytes(&BLOCK_MAINNET_2_BYTES[..])?;
+
+        // Room for the two largest blocks, but not all three
+        let mut sizes = vec![
+            block0.bytes().len(),
+            block1.bytes().len(),
+            block2.bytes().len(),
+        ];
+        sizes.sort_unstable();
+        let mut cache = BlockCache::new(sizes[1] + sizes[2]);
+
+        cache.insert(block0.clone());
+        cache.insert(block1.clone());
+        assert_eq!(cache.get(block0.hash()), Some(block0.clone()));
+
+        // block1 is the least recently used block
+        cache.insert(block2.clone());
+        assert_eq!(cache.get(block1.hash()), None);
+        assert_eq!(cache.get(block0.hash()), Some(block0.clone()));
+        assert_eq!(cache.get(block2.hash()), Some(block2.clone()));
+
+        // Blocks read before a removal aren't cached
+        let generation = cache.generation();
+        cache.remove_from_state(block2.hash());
+        cache.insert_read(block2.clone(), generation);
+        assert_eq!(cache.get(block2.hash()), None);
+
+        cache.insert_read(block1.clone(), cache.generation());
+        assert_eq!(cache.get(block1.hash()), Some(block1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn zero_size_cache_is_disabled() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block = SemanticallyVerifiedBlock::from_bytes(&BLOCK_MAINNET_GENESIS_BYTES[..])?;
+        let mut cache = BlockCache::new(0);
+        cache.insert(block.clone());
+        assert_eq!(cache.get(block.hash()), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+//! Per-block metadata, recorded when blocks are committed, and per-transaction
+//! metadata, calculated when it is requested.
+use crate::value_pools::{outpoint_key, sprout_values, transaction_outputs};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
+use zebra_chain::{
+    block::Block,
+    transaction::{OutPoint, Transaction, TransparentInput},
+    types::amount::{Amount, NonNegative},
+};
+
+/// The size of a serialized `BlockInfo`.
+const BLOCK_INFO_SIZE: usize = 17;
+
+/// Metadata about a block in the state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// The serialized size of the block, in bytes
+    pub size: u32,
+    /// The number of transactions in the block, including the coinbase
+    pub tx_count: u32,
+    /// The total fees paid by the non-coinbase transactions in the block
+    ///
+    /// `None` if the block spends transparent outputs that are not in the
+    /// state, so the fees can't be calculated.
+    pub total_fee: Option<Amount<NonNegative>>,
+}
+
+impl BlockInfo {
+    /// Calculate the metadata for `block`, which is `size` bytes long when
+    /// serialized.
+    ///
+    /// `prior_output` looks up the value of transparent outputs created by
+    /// earlier blocks.
+    pub(crate) fn new<F>(block: &Block, size: usize, prior_output: F) -> Result<Self, Error>
+    where
+        F: FnMut(&OutPoint) -> Result<Option<Amount<NonNegative>>, Error>,
+    {
+        Ok(BlockInfo {
+            size: u32::try_from(size)?,
+            tx_count: u32::try_from(block.transactions.len())?,
+            total_fee: total_fee(block, prior_output)?,
+        })
+    }
+
+    /// Serialize this metadata for storage.
+    pub(crate) fn to_bytes(&self) -> [u8; BLOCK_INFO_SIZE] {
+        let mut bytes = [0; BLOCK_INFO_SIZE];
+        bytes[0..4].copy_from_slice(&self.size.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tx_count.to_le_bytes());
+        if let Some(total_fee) = self.total_fee {
+            bytes[8] = 1;
+            bytes[9..17].copy_from_slice(&i64::from(total_fee).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserialize metadata from storage.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != BLOCK_INFO_SIZE {
+            Err("stored block info has an invalid length")?;
+        }
+
+        let total_fee = match bytes[8] {
+            0 => None,
+            1 => Some(crate::value_pools::amount_from_bytes(&bytes[9..17])?),
+            _ => Err("stored block info has an invalid fee flag")?,
+        };
+
+        Ok(BlockInfo {
+            size: u32::from_le_bytes(bytes[0..4].try_into()?),
+            tx_count: u32::from_le_bytes(bytes[4..8].try_into()?),
+            total_fee,
+        })
+    }
+}
+
+/// Metadata about a transaction in a block in the state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TransactionInfo {
+    /// The serialized size of the transaction, in bytes
+    pub size: u32,
+    /// Is this the coinbase transaction?
+    pub is_coinbase: bool,
+    /// The fee paid by the transaction
+    ///
+    /// `None` for the coinbase transaction, and for transactions that spend
+    /// transparent outputs that are not in the state.
+    pub fee: Option<Amount<NonNegative>>,
+    /// The number of transparent inputs, excluding coinbase inputs
+    pub transparent_inputs: u32,
+    /// The number of transparent outputs
+    pub transparent_outputs: u32,
+    /// The number of Sapling spends
+    pub sapling_spends: u32,
+    /// The number of Sapling outputs
+    pub sapling_outputs: u32,
+}
+
+impl TransactionInfo {
+    /// Calculate the metadata for each transaction in `block`, in block
+    /// order.
+    ///
+    /// `prior_output` looks up the value of transparent outputs created by
+    /// earlier blocks.
+    pub(crate) fn for_block<F>(block: &Block, prior_output: F) -> Result<Vec<Self>, Error>
+    where
+        F: FnMut(&OutPoint) -> Result<Option<Amount<NonNegative>>, Error>,
+    {
+        let fees = transaction_fees(block, prior_output)?;
+
+        block
+            .transactions
+            .iter()
+            .zip(fees)
+            .map(|(tx, fee)| {
+                let (sapling_spends, sapling_outputs) = match tx.as_ref() {
+                    Transaction::V4 {
+                        shielded_data: Some(shielded_data),
+                        ..
+                    } => (
+                        shielded_data.spends().count(),
+                        shielded_data.outputs().count(),
+                    ),
+                    _ => (0, 0),
+                };
+                let transparent_inputs = tx
+                    .inputs()
+                    .filter(|input| matches!(input, TransparentInput::PrevOut { .. }))
+                    .count();
+
+                Ok(TransactionInfo {
+                    size: u32::try_from(tx.serialized_size())?,
+                    is_coinbase: tx.contains_coinbase_input(),
+                    fee,
+                    transparent_inputs: u32::try_from(transparent_inputs)?,
+                    transparent_outputs: u32::try_from(tx.outputs().count())?,
+                    sapling_spends: u32::try_from(sapling_spends)?,
+                    sapling_outputs: u32::try_from(sapling_outputs)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Returns the total fees paid by the non-coinbase transactions in `block`,
+/// or `None` if any of the spent transparent outputs are unknown.
+fn total_fee<F>(block: &Block, prior_output: F) -> Result<Option<Amount<NonNegative>>, Error>
+where
+    F: FnMut(&OutPoint) -> Result<Option<Amount<NonNegative>>, Error>,
+{
+    let mut total = 0;
+    for (tx, fee) in block
+        .transactions
+        .iter()
+        .zip(transaction_fees(block, prior_output)?)
+    {
+        if tx.contains_coinbase_input() {
+            continue;
+        }
+        match fee {
+            Some(fee) => total += i64::from(fee),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(Amount::try_from(total)?))
+}
+
+/// Returns the fee paid by each transaction in `block`, in block order.
+///
+/// The fee is `None` for the coinbase transaction, and for transactions that
+/// spend unknown transparent outputs.
+fn transaction_fees<F>(
+    block: &Block,
+    mut prior_output: F,
+) -> Result<Vec<Option<Amount<NonNegative>>>, Error>
+where
+    F: FnMut(&OutPoint) -> Result<Option<Amount<NonNegative>>, Error>,
+{
+    let mut created = HashMap::new();
+    let mut fees = Vec::with_capacity(block.transactions.len());
+
+    for tx in &block.transactions {
+        let fee = transaction_fee(tx, |outpoint| match created.get(&outpoint_key(outpoint)) {
+            Some(&value) => Ok(Some(value)),
+            None => prior_output(outpoint),
+        })?;
+        fees.push(fee);
+
+        for (outpoint, value) in transaction_outputs(tx) {
+            created.insert(outpoint_key(&outpoint), value);
+        }
+    }
+
+    Ok(fees)
+}
+
+/// Returns the fee paid by `tx`.
+///
+/// `output_value` looks up the value of the transparent outputs spent by `tx`.
+/// The fee is `None` for coinbase transactions, and for transactions that
+/// spend unknown transparent outputs.
+pub(crate) fn transaction_fee<F>(
+    tx: &Transaction,
+    mut output_value: F,
+) -> Result<Option<Amount<NonNegative>>, Error>
+where
+    F: FnMut(&OutPoint) -> Result<Option<Amount<NonNegative>>, Error>,
+{
+    if tx.contains_coinbase_input() {
+        return Ok(None);
+    }
+
+    // Value entering the transparent value pool of this transaction
+    let mut value_in = 0;
+    for input in tx.inputs() {
+        if let TransparentInput::PrevOut { outpoint, .. } = input {
+            match output_value(outpoint)? {
+                Some(value) => value_in += i64::from(value),
+                None => return Ok(None),
+            }
+        }
+    }
+    let (vpub_old, vpub_new) = sprout_values(tx)?;
+    let mut shielded_in = i64::from(vpub_new);
+    if let Transaction::V4 { value_balance, .. } = tx {
+        shielded_in += i64::from(*value_balance);
+    }
+
+    // Value leaving the transparent value pool of this transaction
+    let value_out = i64::from(vpub_old)
+        + tx.outputs()
+            .map(|output| i64::from(output.value))
+            .sum::<i64>();
+
+    let fee = value_in + shielded_in - value_out;
+    if fee < 0 {
+        Err("transaction spends more value than its inputs provide")?;
+    }
+    Ok(Some(Amount::try_from(fee)?))
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    #[test]
+    fn coinbase_only_block_info() -> Result<(), Error> {
+        zebra_test::init();
+
+        let bytes = &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..];
+        let block = Block::zcash_deserialize(bytes)?;
+
+        let info = BlockInfo::new(&block, bytes.len(), |_| Ok(None))?;
+        assert_eq!(info.size as usize, bytes.len());
+        assert_eq!(info.tx_count, 1);
+        assert_eq!(info.total_fee, Some(Amount::try_from(0)?));
+
+        assert_eq!(BlockInfo::from_bytes(&info.to_bytes())?, info);
+
+        Ok(())
+    }
+
+    #[test]
+    fn coinbase_transaction_info() -> Result<(), Error> {
+        zebra_test::init();
+
+        let bytes = &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..];
+        let block = Block::zcash_deserialize(bytes)?;
+
+        let info = TransactionInfo::for_block(&block, |_| Ok(None))?;
+        assert_eq!(info.len(), 1);
+        assert!(info[0].is_coinbase);
+        assert_eq!(info[0].fee, None);
+        assert_eq!(info[0].transparent_inputs, 0);
+        assert_eq!(
+            info[0].size as usize,
+            block.transactions[0].serialized_size()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_outputs_have_no_fee() -> Result<(), Error> {
+        zebra_test::init();
+
+        let bytes = &zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..];
+        let block = Block::zcash_deserialize(bytes)?;
+
+        let info = BlockInfo::new(&block, bytes.len(), |_| Ok(None))?;
+        assert_eq!(info.tx_count as usize, block.transactions.len());
+        assert_eq!(info.total_fee, None);
+
+        // Only the transactions that spend unknown outputs have no fee
+        let info = TransactionInfo::for_block(&block, |_| Ok(None))?;
+        assert_eq!(info.len(), block.transactions.len());
+        assert!(info
+            .iter()
+            .all(|tx| tx.fee.is_none() || tx.transparent_inputs == 0));
+
+        assert_eq!(BlockInfo::from_bytes(&info.to_bytes())?, info);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! Transaction counts over a window of best chain blocks.
+use crate::{block_info::BlockInfo, Error, ESTIMATED_BLOCK_SPACING_SECS};
+use zebra_chain::{
+    block::{BlockHeader, BlockHeaderHash},
+    types::BlockHeight,
+};
+
+/// The number of blocks in a `ChainTxStats` window, if the request doesn't
+/// say.
+///
+/// This is about a month of blocks, like the `getchaintxstats` default in
+/// Bitcoin Core.
+pub const DEFAULT_CHAIN_TX_STATS_WINDOW: u32 =
+    (30 * 24 * 60 * 60 / ESTIMATED_BLOCK_SPACING_SECS) as u32;
+
+/// The transactions in a window of best chain blocks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChainTxStats {
+    /// The hash of the last block in the window
+    pub final_hash: BlockHeaderHash,
+    /// The height of the last block in the window
+    pub final_height: BlockHeight,
+    /// The time in the header of the last block in the window, in seconds
+    /// since the Unix epoch
+    pub final_time: i64,
+    /// The number of blocks in the window
+    pub block_count: u32,
+    /// The number of transactions in the window, including coinbase
+    /// transactions
+    pub tx_count: u64,
+    /// The number of seconds between the header times of the block before the
+    /// window and the last block in the window
+    ///
+    /// Zero if the window is empty. Header times can go backwards, so the
+    /// interval can be negative.
+    pub interval: i64,
+}
+
+impl ChainTxStats {
+    /// Count the transactions in the `window` blocks that end at the block
+    /// with `hash`, which must be in the best chain.
+    ///
+    /// Like Bitcoin Core, the window must be smaller than the height of the
+    /// last block. If `window` is `None`, uses `DEFAULT_CHAIN_TX_STATS_WINDOW`,
+    /// or the largest valid window, whichever is smaller.
+    ///
+    /// `best_chain_height` returns the height of a block, if it is in the best
+    /// chain. `best_chain_hash` returns the hash of the best chain block at a
+    /// height, if there is one. `block_info` and `header` return the metadata
+    /// and header of a block, if it is in the state.
+    pub(crate) fn new(
+        hash: BlockHeaderHash,
+        window: Option<u32>,
+        best_chain_height: impl FnOnce(BlockHeaderHash) -> Result<Option<BlockHeight>, Error>,
+        mut best_chain_hash: impl FnMut(BlockHeight) -> Result<Option<BlockHeaderHash>, Error>,
+        mut block_info: impl FnMut(BlockHeaderHash) -> Result<Option<BlockInfo>, Error>,
+        mut header: impl FnMut(BlockHeaderHash) -> Result<Option<BlockHeader>, Error>,
+    ) -> Result<Self, Error> {
+        let final_height =
+            best_chain_height(hash)?.ok_or("block is not in the current best chain")?;
+        let block_count = match window {
+            Some(window) if window > 0 && window >= final_height.0 => Err(format!(
+                "invalid window: must be less than the block height {}",
+                final_height.0
+            ))?,
+            Some(window) => window,
+            None => DEFAULT_CHAIN_TX_STATS_WINDOW.min(final_height.0.saturating_sub(1)),
+        };
+
+        let mut header_time = |hash| -> Result<i64, Error> {
+            Ok(header(hash)?
+                .ok_or("best chain header is missing")?
+                .time
+                .timestamp())
+        };
+        let final_time = header_time(hash)?;
+
+        let mut tx_count = 0;
+        let mut interval = 0;
+        if block_count > 0 {
+            let start_height = BlockHeight(final_height.0 - block_count);
+            for height in start_height.0 + 1..=final_height.0 {
+                let hash =
+                    best_chain_hash(BlockHeight(height))?.ok_or("best chain block is missing")?;
+                let info = block_info(hash)?.ok_or("best chain block info is missing")?;
+                tx_count += u64::from(info.tx_count);
+            }
+
+            let start_hash = best_chain_hash(start_height)?.ok_or("best chain block is missing")?;
+            interval = final_time - header_time(start_hash)?;
+        }
+
+        Ok(ChainTxStats {
+            final_hash: hash,
+            final_height,
+            final_time,
+            block_count,
+            tx_count,
+            interval,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    #[test]
+    fn chain_tx_stats_window() -> Result<(), Error> {
+        zebra_test::init();
+
+        let genesis =
+            BlockHeader::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?;
+        // Block `n` has hash `[n; 32]`, `n` transactions, and time `75 * n`
+        let stats = |hash: u8, window| {
+            ChainTxStats::new(
+                BlockHeaderHash([hash; 32]),
+                window,
+                |hash| Ok(Some(BlockHeight(hash.0[0].into())).filter(|height| height.0 <= 10)),
+                |height| Ok(Some(BlockHeaderHash([height.0 as u8; 32]))),
+                |hash| {
+                    Ok(Some(BlockInfo {
+                        size: 1_000,
+                        tx_count: hash.0[0].into(),
+                        total_fee: None,
+                    }))
+                },
+                |hash| {
+                    Ok(Some(BlockHeader {
+                        time: Utc.timestamp(75 * i64::from(hash.0[0]), 0),
+                        ..genesis
+                    }))
+                },
+            )
+        };
+
+        let window = stats(10, Some(3))?;
+        assert_eq!(window.final_height, BlockHeight(10));
+        assert_eq!(window.final_time, 750);
+        assert_eq!(window.block_count, 3);
+        assert_eq!(window.tx_count, 8 + 9 + 10);
+        assert_eq!(window.interval, 3 * 75);
+
+        // The default window is limited by the height
+        let window = stats(5, None)?;
+        assert_eq!(window.block_count, 4);
+        assert_eq!(window.tx_count, 2 + 3 + 4 + 5);
+
+        let window = stats(5, Some(0))?;
+        assert_eq!(window.tx_count, 0);
+        assert_eq!(window.interval, 0);
+
+        assert!(stats(5, Some(5)).is_err());
+        assert!(stats(11, None).is_err());
+
+        Ok(())
+    }
+}
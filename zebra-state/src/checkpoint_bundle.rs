@@ -0,0 +1,313 @@
+//! Signed header chain checkpoints, for bootstrapping light clients.
+//!
+//! A `CheckpointBundle` commits to the best chain at regular height intervals.
+//! Each checkpoint contains the block hash and the Sapling note commitment tree
+//! size at that height, which is read from the persisted note commitment trees. Light clients that trust the node's key can use a
+//! bundle to start syncing from a recent checkpoint, rather than genesis.
+//!
+//! Bundles are signed using the node's Ed25519 key. Orchard tree sizes are not
+//! included, because Zebra does not support Orchard yet.
+use crate::{Request, Response};
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+};
+use tower::{Service, ServiceExt};
+use zebra_chain::{
+    block::BlockHeaderHash,
+    ed25519_zebra::{Signature, SigningKey, VerificationKey, VerificationKeyBytes},
+    serialization::{
+        ReadZcashExt, SerializationError, WriteZcashExt, ZcashDeserialize, ZcashSerialize,
+    },
+    types::BlockHeight,
+    Network,
+};
+
+/// A header chain commitment at a single height.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeaderCheckpoint {
+    /// The height of the checkpoint block
+    pub height: BlockHeight,
+    /// The hash of the checkpoint block
+    pub hash: BlockHeaderHash,
+    /// The number of note commitments in the Sapling note commitment tree,
+    /// after the checkpoint block
+    pub sapling_tree_size: u64,
+}
+
+/// Header chain commitments at regular height intervals.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointBundle {
+    /// The network of the checkpointed chain
+    pub network: Network,
+    /// The height interval between checkpoints
+    pub interval: u32,
+    /// The checkpoints, in height order
+    pub checkpoints: Vec<HeaderCheckpoint>,
+}
+
+/// A `CheckpointBundle`, signed by a node's key.
+#[derive(Clone, Debug)]
+pub struct SignedCheckpointBundle {
+    /// The signed bundle
+    pub bundle: CheckpointBundle,
+    /// The key that signed the bundle
+    pub verification_key: VerificationKeyBytes,
+    /// The signature on the serialized bundle
+    pub signature: Signature,
+}
+
+impl CheckpointBundle {
+    /// Sign this bundle using `signing_key`.
+    pub fn sign(self, signing_key: &SigningKey) -> SignedCheckpointBundle {
+        let signature = signing_key.sign(&self.to_bytes());
+
+        SignedCheckpointBundle {
+            bundle: self,
+            verification_key: VerificationKey::from(signing_key).into(),
+            signature,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.zcash_serialize(&mut bytes)
+            .expect("serializing into a vec never fails");
+        bytes
+    }
+}
+
+impl SignedCheckpointBundle {
+    /// Check the signature on this bundle, using its verification key.
+    ///
+    /// Callers must also check that they trust the verification key.
+    pub fn verify(&self) -> Result<(), Error> {
+        VerificationKey::try_from(self.verification_key)?
+            .verify(&self.signature, &self.bundle.to_bytes())?;
+        Ok(())
+    }
+}
+
+impl ZcashSerialize for CheckpointBundle {
+    fn zcash_serialize<W: io::Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        let network: u8 = match self.network {
+            Network::Mainnet => 0,
+            Network::Testnet => 1,
+        };
+        writer.write_all(&[network])?;
+        writer.write_all(&self.interval.to_le_bytes())?;
+        writer.write_compactsize(self.checkpoints.len() as u64)?;
+        for checkpoint in &self.checkpoints {
+            writer.write_all(&checkpoint.height.0.to_le_bytes())?;
+            checkpoint.hash.zcash_serialize(&mut writer)?;
+            writer.write_all(&checkpoint.sapling_tree_size.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl ZcashDeserialize for CheckpointBundle {
+    fn zcash_deserialize<R: io::Read>(mut reader: R) -> Result<Self, SerializationError> {
+        let mut network = [0; 1];
+        reader.read_exact(&mut network)?;
+        let network = match network[0] {
+            0 => Network::Mainnet,
+            1 => Network::Testnet,
+            _ => return Err(SerializationError::Parse("unknown checkpoint bundle network")),
+        };
+        let interval = u32::from_le_bytes(reader.read_4_bytes()?);
+
+        let count = reader.read_compactsize()?;
+        let mut checkpoints = Vec::new();
+        for _ in 0..count {
+            let height = BlockHeight(u32::from_le_bytes(reader.read_4_bytes()?));
+            let hash = BlockHeaderHash::zcash_deserialize(&mut reader)?;
+            let mut sapling_tree_size = [0; 8];
+            reader.read_exact(&mut sapling_tree_size)?;
+
+            checkpoints.push(HeaderCheckpoint {
+                height,
+                hash,
+                sapling_tree_size: u64::from_le_bytes(sapling_tree_size),
+            });
+        }
+
+        Ok(CheckpointBundle {
+            network,
+            interval,
+            checkpoints,
+        })
+    }
+}
+
+impl ZcashSerialize for SignedCheckpointBundle {
+    fn zcash_serialize<W: io::Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        self.bundle.zcash_serialize(&mut writer)?;
+        writer.write_all(&<[u8; 32]>::from(self.verification_key))?;
+        writer.write_all(&<[u8; 64]>::from(self.signature))?;
+        Ok(())
+    }
+}
+
+impl ZcashDeserialize for SignedCheckpointBundle {
+    fn zcash_deserialize<R: io::Read>(mut reader: R) -> Result<Self, SerializationError> {
+        let bundle = CheckpointBundle::zcash_deserialize(&mut reader)?;
+        let verification_key = VerificationKeyBytes::from(reader.read_32_bytes()?);
+        let signature = Signature::from(reader.read_64_bytes()?);
+
+        Ok(SignedCheckpointBundle {
+            bundle,
+            verification_key,
+            signature,
+        })
+    }
+}
+
+/// Build a checkpoint bundle for the best chain in `state`, with a
+/// checkpoint every `interval` blocks.
+pub async fn build_checkpoint_bundle<S>(
+    mut state: S,
+    network: Network,
+    interval: u32,
+) -> Result<CheckpointBundle, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+{
+    if interval == 0 {
+        Err("checkpoint interval must be greater than zero")?;
+    }
+
+    let mut height = BlockHeight(0);
+    let mut checkpoints = Vec::new();
+    loop {
+        let hash = match state
+            .ready_and()
+            .await?
+            .call(Request::BestChainBlockHash { height })
+            .await?
+        {
+            Response::BlockHash(Some(hash)) => hash,
+            Response::BlockHash(None) => break,
+            _ => unreachable!("BestChainBlockHash request can only result in Response::BlockHash"),
+        };
+        let trees = match state
+            .ready_and()
+            .await?
+            .call(Request::NoteCommitmentTrees { height })
+            .await?
+        {
+            Response::NoteCommitmentTrees(Some(trees)) => trees,
+            Response::NoteCommitmentTrees(None) => {
+                Err("the best chain is not connected to the genesis block")?
+            }
+            _ => unreachable!(
+                "NoteCommitmentTrees request can only result in Response::NoteCommitmentTrees"
+            ),
+        };
+
+        checkpoints.push(HeaderCheckpoint {
+            height,
+            hash,
+            sapling_tree_size: trees.sapling.size(),
+        });
+
+        height = match height.0.checked_add(interval) {
+            Some(next) => BlockHeight(next),
+            None => break,
+        };
+    }
+
+    Ok(CheckpointBundle {
+        network,
+        interval,
+        checkpoints,
+    })
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{on_disk, Config, SemanticallyVerifiedBlock};
+
+    #[test]
+    fn signed_bundle_round_trip() {
+        zebra_test::init();
+
+        let bundle = CheckpointBundle {
+            network: Network::Testnet,
+            interval: 1000,
+            checkpoints: vec![
+                HeaderCheckpoint {
+                    height: BlockHeight(0),
+                    hash: BlockHeaderHash([0; 32]),
+                    sapling_tree_size: 0,
+                },
+                HeaderCheckpoint {
+                    height: BlockHeight(1000),
+                    hash: BlockHeaderHash([1; 32]),
+                    sapling_tree_size: 42,
+                },
+            ],
+        };
+
+        let signing_key = SigningKey::from([7; 32]);
+        let signed = bundle.clone().sign(&signing_key);
+        signed.verify().expect("signature is valid");
+
+        let mut bytes = Vec::new();
+        signed.zcash_serialize(&mut bytes).unwrap();
+        let parsed = SignedCheckpointBundle::zcash_deserialize(&bytes[..]).unwrap();
+        assert_eq!(parsed.bundle, bundle);
+        assert_eq!(parsed.verification_key, signed.verification_key);
+        parsed.verify().expect("parsed signature is valid");
+
+        let tampered = SignedCheckpointBundle {
+            bundle: CheckpointBundle {
+                interval: 1,
+                ..bundle
+            },
+            ..signed
+        };
+        assert!(tampered.verify().is_err());
+    }
+
+    #[tokio::test]
+    async fn bundles_use_the_stored_tree_sizes() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = Config {
+            ephemeral: true,
+            ..Config::default()
+        };
+        let mut state = on_disk::init(config, Network::Mainnet);
+        let mut hashes = Vec::new();
+        for bytes in &[
+            &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+            &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+        ] {
+            let block = SemanticallyVerifiedBlock::from_bytes(*bytes)?;
+            hashes.push(block.hash());
+            state
+                .ready_and()
+                .await?
+                .call(Request::CommitFinalizedBlock { block })
+                .await?;
+        }
+
+        let bundle = build_checkpoint_bundle(state, Network::Mainnet, 1).await?;
+        let expected: Vec<_> = hashes
+            .into_iter()
+            .enumerate()
+            .map(|(height, hash)| HeaderCheckpoint {
+                height: BlockHeight(height as u32),
+                hash,
+                sapling_tree_size: 0,
+            })
+            .collect();
+        assert_eq!(bundle.checkpoints, expected);
+
+        Ok(())
+    }
+}
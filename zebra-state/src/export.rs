@@ -0,0 +1,579 @@
+//! Chain data export in the formats expected by `zcash_client_backend`.
+//!
+//! Blocks are exported as `CompactBlock` messages, using the protobuf wire
+//! format from lightwalletd's `compact_formats.proto`. This lets Rust wallet
+//! sync code read blocks from a local zebra, without running lightwalletd.
+//!
+//! Completed Sapling note commitment subtrees are exported as `SubtreeRoot`
+//! messages, and nullifier maps are exported as `CompactBlock` messages that
+//! only contain Sapling spends. These are the formats of lightwalletd's
+//! `GetSubtreeRoots` and `GetBlockRangeNullifiers` responses.
+//!
+//! Multiple messages are written as a stream of length-delimited messages:
+//! each message is prefixed with its length, encoded as a protobuf varint.
+//!
+//! Note commitment treestates are not exported. The state only stores the
+//! frontier of each tree, which doesn't include the most recent leaves, so it
+//! can't be written in zcashd's tree encoding.
+//!
+//! The `analytics` module exports normalized tables for data analysis.
+use super::{NoteCommitmentSubtree, Request, Response};
+use std::{convert::TryFrom, io, sync::Arc};
+use tower::{Service, ServiceExt};
+use zebra_chain::{
+    block::{Block, BlockHeaderHash},
+    serialization::ZcashSerialize,
+    transaction::{Output, Spend, Transaction, TransactionHash},
+    types::BlockHeight,
+};
+
+pub mod analytics;
+mod parquet;
+
+/// The `protoVersion` of the exported compact blocks.
+pub const COMPACT_BLOCK_PROTO_VERSION: u32 = 1;
+
+/// The maximum number of subtrees read from the state in each request.
+const SUBTREE_BATCH_SIZE: usize = 1024;
+
+/// The number of bytes of each note ciphertext included in a `CompactOutput`.
+///
+/// This is enough to trial-decrypt the note plaintext, without the memo.
+pub const COMPACT_NOTE_SIZE: usize = 52;
+
+/// A compact representation of the shielded data in a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactBlock {
+    /// The height of the block
+    pub height: BlockHeight,
+    /// The hash of the block, in internal byte order
+    pub hash: BlockHeaderHash,
+    /// The hash of the previous block, in internal byte order
+    pub prev_hash: BlockHeaderHash,
+    /// The block time, as a Unix timestamp
+    pub time: u32,
+    /// The transactions in the block that have Sapling spends or outputs
+    pub vtx: Vec<CompactTx>,
+}
+
+/// A compact representation of the shielded data in a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactTx {
+    /// The index of the transaction in its block
+    pub index: u64,
+    /// The hash of the transaction, in internal byte order
+    pub hash: TransactionHash,
+    /// The Sapling spends in the transaction
+    pub spends: Vec<CompactSpend>,
+    /// The Sapling outputs in the transaction
+    pub outputs: Vec<CompactOutput>,
+}
+
+/// A compact representation of a Sapling spend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactSpend {
+    /// The nullifier of the spent note
+    pub nf: [u8; 32],
+}
+
+/// A compact representation of a Sapling output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactOutput {
+    /// The u-coordinate of the note commitment
+    pub cmu: [u8; 32],
+    /// The ephemeral public key
+    pub epk: [u8; 32],
+    /// The first `COMPACT_NOTE_SIZE` bytes of the note ciphertext
+    pub ciphertext: Vec<u8>,
+}
+
+/// The root of a completed Sapling note commitment subtree, and the block
+/// that completed it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubtreeRoot {
+    /// The root of the subtree
+    pub root_hash: [u8; 32],
+    /// The hash of the block that completed the subtree, in internal byte
+    /// order
+    pub completing_block_hash: BlockHeaderHash,
+    /// The height of the block that completed the subtree
+    pub completing_block_height: BlockHeight,
+}
+
+impl CompactBlock {
+    /// Create a compact block from `block`.
+    ///
+    /// Returns `None` if the block has no coinbase height.
+    pub fn from_block(block: &Block) -> Option<Self> {
+        let height = block.coinbase_height()?;
+
+        let vtx = block
+            .transactions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, tx)| CompactTx::from_transaction(index as u64, tx))
+            .collect();
+
+        Some(CompactBlock {
+            height,
+            hash: block.hash(),
+            prev_hash: block.header.previous_block_hash,
+            time: block.header.time.timestamp() as u32,
+            vtx,
+        })
+    }
+
+    /// Iterate over the nullifiers revealed in this block, along with the
+    /// hash of the transaction that revealed each nullifier.
+    pub fn nullifiers(&self) -> impl Iterator<Item = (&[u8; 32], TransactionHash)> {
+        self.vtx
+            .iter()
+            .flat_map(|tx| tx.spends.iter().map(move |spend| (&spend.nf, tx.hash)))
+    }
+
+    /// Returns the nullifier map of this block: the transactions that have
+    /// Sapling spends, without their outputs.
+    pub fn into_nullifiers(mut self) -> Self {
+        self.vtx.retain(|tx| !tx.spends.is_empty());
+        for tx in &mut self.vtx {
+            tx.outputs.clear();
+        }
+        self
+    }
+
+    /// Encode this block as a `CompactBlock` protobuf message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_uint(&mut buf, 1, COMPACT_BLOCK_PROTO_VERSION.into());
+        put_uint(&mut buf, 2, self.height.0.into());
+        put_bytes(&mut buf, 3, &self.hash.0);
+        put_bytes(&mut buf, 4, &self.prev_hash.0);
+        put_uint(&mut buf, 5, self.time.into());
+        // Field 6 is the optional full block header, which we don't export.
+        for tx in &self.vtx {
+            put_message(&mut buf, 7, &tx.encode());
+        }
+        buf
+    }
+
+    /// Write this block as a length-delimited `CompactBlock` message.
+    pub fn write_delimited<W: io::Write>(&self, writer: W) -> Result<(), io::Error> {
+        write_delimited(writer, &self.encode())
+    }
+}
+
+impl SubtreeRoot {
+    /// Create a subtree root from `subtree`, which was completed by the block
+    /// with `completing_block_hash`.
+    pub fn new(subtree: &NoteCommitmentSubtree, completing_block_hash: BlockHeaderHash) -> Self {
+        SubtreeRoot {
+            root_hash: subtree.root,
+            completing_block_hash,
+            completing_block_height: subtree.end_height,
+        }
+    }
+
+    /// Encode this subtree root as a `SubtreeRoot` protobuf message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Field 1 is unused in lightwalletd's `service.proto`.
+        put_bytes(&mut buf, 2, &self.root_hash);
+        put_bytes(&mut buf, 3, &self.completing_block_hash.0);
+        put_uint(&mut buf, 4, self.completing_block_height.0.into());
+        buf
+    }
+
+    /// Write this subtree root as a length-delimited `SubtreeRoot` message.
+    pub fn write_delimited<W: io::Write>(&self, writer: W) -> Result<(), io::Error> {
+        write_delimited(writer, &self.encode())
+    }
+}
+
+impl CompactTx {
+    /// Create a compact transaction from the transaction at `index` in its
+    /// block.
+    ///
+    /// Returns `None` if the transaction has no Sapling spends or outputs.
+    fn from_transaction(index: u64, tx: &Arc<Transaction>) -> Option<Self> {
+        let shielded_data = match tx.as_ref() {
+            Transaction::V4 {
+                shielded_data: Some(shielded_data),
+                ..
+            } => shielded_data,
+            _ => return None,
+        };
+
+        Some(CompactTx {
+            index,
+            hash: TransactionHash::from(tx.as_ref().clone()),
+            spends: shielded_data.spends().map(CompactSpend::from).collect(),
+            outputs: shielded_data.outputs().map(CompactOutput::from).collect(),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_uint(&mut buf, 1, self.index);
+        put_bytes(&mut buf, 2, &self.hash.0);
+        // Field 3 is the transaction fee, which we can't calculate without
+        // looking up the transparent inputs. lightwalletd also leaves it unset.
+        for spend in &self.spends {
+            let mut spend_buf = Vec::new();
+            put_bytes(&mut spend_buf, 1, &spend.nf);
+            put_message(&mut buf, 4, &spend_buf);
+        }
+        for output in &self.outputs {
+            let mut output_buf = Vec::new();
+            put_bytes(&mut output_buf, 1, &output.cmu);
+            put_bytes(&mut output_buf, 2, &output.epk);
+            put_bytes(&mut output_buf, 3, &output.ciphertext);
+            put_message(&mut buf, 5, &output_buf);
+        }
+        buf
+    }
+}
+
+impl From<&Spend> for CompactSpend {
+    fn from(spend: &Spend) -> Self {
+        let mut nf = [0; 32];
+        spend
+            .nullifier
+            .zcash_serialize(&mut nf[..])
+            .expect("nullifiers are 32 bytes long");
+
+        CompactSpend { nf }
+    }
+}
+
+impl From<&Output> for CompactOutput {
+    fn from(output: &Output) -> Self {
+        CompactOutput {
+            cmu: output.cmu,
+            epk: output.ephemeral_key.to_bytes(),
+            ciphertext: output.enc_ciphertext.0[..COMPACT_NOTE_SIZE].to_vec(),
+        }
+    }
+}
+
+/// Write the compact blocks from `start` to `end` (inclusive) in the best
+/// chain of `state` to `writer`, as length-delimited `CompactBlock` messages.
+///
+/// If `end` is `None`, or above the tip, exports up to the current tip.
+///
+/// Returns the number of blocks written.
+pub async fn write_compact_blocks<S, W>(
+    state: S,
+    start: BlockHeight,
+    end: Option<BlockHeight>,
+    writer: W,
+) -> Result<u32, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+    W: io::Write,
+{
+    write_blocks(state, start, end, writer, |block| block).await
+}
+
+/// Write the nullifier maps of the blocks from `start` to `end` (inclusive) in
+/// the best chain of `state` to `writer`, as length-delimited `CompactBlock`
+/// messages.
+///
+/// If `end` is `None`, or above the tip, exports up to the current tip.
+///
+/// Returns the number of blocks written.
+pub async fn write_nullifier_maps<S, W>(
+    state: S,
+    start: BlockHeight,
+    end: Option<BlockHeight>,
+    writer: W,
+) -> Result<u32, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+    W: io::Write,
+{
+    write_blocks(state, start, end, writer, CompactBlock::into_nullifiers).await
+}
+
+/// Write the completed Sapling note commitment subtrees in the best chain of
+/// `state` to `writer`, as length-delimited `SubtreeRoot` messages.
+///
+/// Returns the number of subtrees written.
+pub async fn write_subtree_roots<S, W>(mut state: S, mut writer: W) -> Result<u32, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+    W: io::Write,
+{
+    let mut count = 0;
+    loop {
+        let start_index = match u16::try_from(count) {
+            Ok(start_index) => start_index,
+            // Every possible subtree has been written
+            Err(_) => break,
+        };
+        let subtrees = match state
+            .ready_and()
+            .await?
+            .call(Request::SaplingSubtrees {
+                start_index,
+                limit: SUBTREE_BATCH_SIZE,
+            })
+            .await?
+        {
+            Response::SaplingSubtrees(subtrees) => subtrees,
+            _ => {
+                unreachable!("SaplingSubtrees request can only result in Response::SaplingSubtrees")
+            }
+        };
+
+        for subtree in &subtrees {
+            let hash = match state
+                .ready_and()
+                .await?
+                .call(Request::BestChainBlockHash {
+                    height: subtree.end_height,
+                })
+                .await?
+            {
+                Response::BlockHash(Some(hash)) => hash,
+                Response::BlockHash(None) => {
+                    Err("the block that completed a subtree is not in the best chain")?
+                }
+                _ => unreachable!(
+                    "BestChainBlockHash request can only result in Response::BlockHash"
+                ),
+            };
+
+            SubtreeRoot::new(subtree, hash).write_delimited(&mut writer)?;
+            count += 1;
+        }
+
+        if subtrees.len() < SUBTREE_BATCH_SIZE {
+            break;
+        }
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Write the compact blocks from `start` to `end` (inclusive) in the best
+/// chain of `state` to `writer`, after converting them using `convert`.
+async fn write_blocks<S, W, F>(
+    mut state: S,
+    start: BlockHeight,
+    end: Option<BlockHeight>,
+    mut writer: W,
+    convert: F,
+) -> Result<u32, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+    W: io::Write,
+    F: Fn(CompactBlock) -> CompactBlock,
+{
+    let hashes = best_chain_hashes(&mut state, start, end).await?;
+
+    let mut count = 0;
+    for hash in hashes {
+        let block = get_block(&mut state, hash).await?;
+        let compact_block = CompactBlock::from_block(&block)
+            .expect("blocks in the state have a coinbase height");
+
+        convert(compact_block).write_delimited(&mut writer)?;
+        count += 1;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Returns the hashes of the blocks from `start` to `end` (inclusive) in the
+/// best chain of `state`, in height order.
+///
+/// If `end` is `None`, or above the tip, returns hashes up to the current tip.
+/// An empty state has no hashes.
+pub(crate) async fn best_chain_hashes<S>(
+    state: &mut S,
+    start: BlockHeight,
+    end: Option<BlockHeight>,
+) -> Result<Vec<BlockHeaderHash>, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+{
+    let tip_hash = match state.ready_and().await?.call(Request::GetTip).await? {
+        Response::Tip { hash } => hash,
+        Response::Empty => return Ok(Vec::new()),
+        _ => unreachable!("GetTip request can only result in Response::Tip or Response::Empty"),
+    };
+
+    // Walk back from the tip, to find the hashes of the blocks in the range
+    let mut hashes = Vec::new();
+    let mut hash = tip_hash;
+    loop {
+        let block = get_block(state, hash).await?;
+        let height = block
+            .coinbase_height()
+            .ok_or("block in state has no coinbase height")?;
+
+        if height < start {
+            break;
+        }
+        if end.map_or(true, |end| height <= end) {
+            hashes.push(hash);
+        }
+        if height == BlockHeight(0) || height == start {
+            break;
+        }
+
+        hash = block.header.previous_block_hash;
+    }
+
+    hashes.reverse();
+    Ok(hashes)
+}
+
+/// Get the block for `hash` from `state`.
+pub(crate) async fn get_block<S>(state: &mut S, hash: BlockHeaderHash) -> Result<Arc<Block>, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+{
+    match state.ready_and().await?.call(Request::GetBlock { hash }).await? {
+        Response::Block { block } => Ok(block),
+        _ => unreachable!("GetBlock request can only result in Response::Block"),
+    }
+}
+
+/// Write `message` to `writer`, prefixed with its length as a protobuf varint.
+fn write_delimited<W: io::Write>(mut writer: W, message: &[u8]) -> Result<(), io::Error> {
+    let mut prefix = Vec::new();
+    put_varint(&mut prefix, message.len() as u64);
+
+    writer.write_all(&prefix)?;
+    writer.write_all(message)
+}
+
+/// Append `value` to `buf` as a protobuf varint.
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Append a protobuf field key to `buf`.
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buf, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+/// Append a varint field to `buf`, omitting the proto3 default value.
+fn put_uint(buf: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        put_key(buf, field, 0);
+        put_varint(buf, value);
+    }
+}
+
+/// Append a bytes field to `buf`, omitting the proto3 default value.
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        put_message(buf, field, bytes);
+    }
+}
+
+/// Append an embedded message field to `buf`.
+///
+/// Embedded messages are always written, so that empty messages in repeated
+/// fields are preserved.
+fn put_message(buf: &mut Vec<u8>, field: u32, message: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, message.len() as u64);
+    buf.extend_from_slice(message);
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    #[test]
+    fn varint_encoding() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn compact_block_encoding() {
+        zebra_test::init();
+
+        let block =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+                .unwrap();
+        let compact_block = CompactBlock::from_block(&block).unwrap();
+
+        // The genesis block has no shielded transactions
+        assert_eq!(compact_block.height, BlockHeight(0));
+        assert!(compact_block.vtx.is_empty());
+
+        let encoded = compact_block.encode();
+        // protoVersion = 1
+        assert_eq!(&encoded[..2], &[0x08, 0x01]);
+        // height = 0 is omitted, so the next field is the 32-byte hash
+        assert_eq!(&encoded[2..4], &[0x1a, 32]);
+        assert_eq!(&encoded[4..36], &block.hash().0[..]);
+
+        let mut delimited = Vec::new();
+        compact_block.write_delimited(&mut delimited).unwrap();
+        assert_eq!(delimited[0] as usize, encoded.len());
+        assert_eq!(&delimited[1..], &encoded[..]);
+    }
+
+    #[test]
+    fn nullifier_maps_only_have_spends() {
+        let tx = |index, spends: usize, outputs: usize| CompactTx {
+            index,
+            hash: TransactionHash([index as u8; 32]),
+            spends: vec![CompactSpend { nf: [1; 32] }; spends],
+            outputs: vec![
+                CompactOutput {
+                    cmu: [2; 32],
+                    epk: [3; 32],
+                    ciphertext: vec![4; COMPACT_NOTE_SIZE],
+                };
+                outputs
+            ],
+        };
+        let block = CompactBlock {
+            height: BlockHeight(1),
+            hash: BlockHeaderHash([5; 32]),
+            prev_hash: BlockHeaderHash([6; 32]),
+            time: 7,
+            vtx: vec![tx(1, 1, 2), tx(2, 0, 1)],
+        };
+
+        let nullifiers = block.into_nullifiers();
+        assert_eq!(nullifiers.vtx, vec![tx(1, 1, 0)]);
+    }
+
+    #[test]
+    fn subtree_root_encoding() {
+        let subtree = NoteCommitmentSubtree {
+            index: 0,
+            root: [1; 32],
+            end_height: BlockHeight(300),
+        };
+        let encoded = SubtreeRoot::new(&subtree, BlockHeaderHash([2; 32])).encode();
+
+        // rootHash
+        assert_eq!(&encoded[..2], &[0x12, 32]);
+        assert_eq!(&encoded[2..34], &[1; 32][..]);
+        // completingBlockHash
+        assert_eq!(&encoded[34..36], &[0x1a, 32]);
+        assert_eq!(&encoded[36..68], &[2; 32][..]);
+        // completingBlockHeight = 300
+        assert_eq!(&encoded[68..], &[0x20, 0xac, 0x02]);
+    }
+}
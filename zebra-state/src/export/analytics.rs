@@ -0,0 +1,342 @@
+//! Normalized chain tables for analytics, exported as Parquet files.
+//!
+//! Each table is written to `<table>.parquet` in the output directory. The
+//! schema below is stable: new columns are only added at the end of a table,
+//! and `ANALYTICS_SCHEMA_VERSION` is incremented if any existing column
+//! changes. The schema version is stored in the key-value metadata of each
+//! file, under `zebra.analytics.schema_version`.
+//!
+//! Hashes are 32-byte fixed length byte arrays, in internal byte order, like
+//! the compact block export. Heights, counts, and sizes are unsigned 32-bit
+//! integers. All columns are required.
+//!
+//! # `blocks`
+//!
+//! One row for each block in the best chain.
+//!
+//! | Column      | Type               | Description                       |
+//! |-------------|--------------------|-----------------------------------|
+//! | `height`    | `UINT_32`          | The block height                  |
+//! | `hash`      | 32 bytes           | The block hash                    |
+//! | `prev_hash` | 32 bytes           | The hash of the previous block    |
+//! | `version`   | `UINT_32`          | The block version                 |
+//! | `time`      | `TIMESTAMP_MILLIS` | The block time                    |
+//! | `bits`      | `UINT_32`          | The compact difficulty target     |
+//! | `size`      | `UINT_32`          | The serialized size of the block  |
+//! | `tx_count`  | `UINT_32`          | The number of transactions        |
+//!
+//! # `transactions`
+//!
+//! One row for each transaction in the best chain.
+//!
+//! | Column          | Type      | Description                               |
+//! |-----------------|-----------|-------------------------------------------|
+//! | `height`        | `UINT_32` | The height of the block                   |
+//! | `tx_index`      | `UINT_32` | The index of the transaction in its block |
+//! | `txid`          | 32 bytes  | The transaction hash                      |
+//! | `version`       | `UINT_32` | The transaction version                   |
+//! | `input_count`   | `UINT_32` | The number of transparent inputs          |
+//! | `output_count`  | `UINT_32` | The number of transparent outputs         |
+//! | `expiry_height` | `UINT_32` | The expiry height, or 0 for no expiry     |
+//! | `size`          | `UINT_32` | The serialized size of the transaction    |
+//!
+//! # `outputs`
+//!
+//! One row for each transparent output in the best chain.
+//!
+//! | Column         | Type         | Description                                |
+//! |----------------|--------------|--------------------------------------------|
+//! | `height`       | `UINT_32`    | The height of the block                    |
+//! | `txid`         | 32 bytes     | The hash of the creating transaction       |
+//! | `output_index` | `UINT_32`    | The index of the output in its transaction |
+//! | `value`        | `INT64`      | The output value, in zatoshis              |
+//! | `script`       | `BYTE_ARRAY` | The output's `scriptPubKey`                |
+use super::{
+    best_chain_hashes, get_block,
+    parquet::{Column, LogicalType, ParquetWriter, PhysicalType, Value},
+    Error,
+};
+use crate::{Request, Response};
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+    str::FromStr,
+};
+use tower::Service;
+use zebra_chain::{
+    block::Block,
+    transaction::{Transaction, TransactionHash},
+    types::BlockHeight,
+};
+
+/// The version of the analytics table schemas.
+pub const ANALYTICS_SCHEMA_VERSION: u32 = 1;
+
+/// The key for the schema version in the Parquet key-value metadata.
+const SCHEMA_VERSION_KEY: &str = "zebra.analytics.schema_version";
+
+/// A table in the analytics export.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Table {
+    /// One row for each block
+    Blocks,
+    /// One row for each transaction
+    Transactions,
+    /// One row for each transparent output
+    Outputs,
+}
+
+impl Table {
+    /// All the analytics tables.
+    pub const ALL: [Table; 3] = [Table::Blocks, Table::Transactions, Table::Outputs];
+
+    /// The name of this table, which is also its file name, without the
+    /// extension.
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Blocks => "blocks",
+            Table::Transactions => "transactions",
+            Table::Outputs => "outputs",
+        }
+    }
+
+    fn schema(self) -> &'static [Column] {
+        match self {
+            Table::Blocks => BLOCKS_SCHEMA,
+            Table::Transactions => TRANSACTIONS_SCHEMA,
+            Table::Outputs => OUTPUTS_SCHEMA,
+        }
+    }
+
+    /// Write the rows for `block` to `writer`.
+    fn write_rows<W: io::Write>(
+        self,
+        block: &Block,
+        writer: &mut ParquetWriter<W>,
+    ) -> Result<(), Error> {
+        let height = block
+            .coinbase_height()
+            .ok_or("block in state has no coinbase height")?;
+
+        match self {
+            Table::Blocks => {
+                let hash = block.hash();
+
+                writer.push_row(&[
+                    uint32(height.0),
+                    Value::Bytes(&hash.0),
+                    Value::Bytes(&block.header.previous_block_hash.0),
+                    uint32(block.header.version),
+                    Value::Int64(block.header.time.timestamp_millis()),
+                    uint32(block.header.bits),
+                    uint32(block.serialized_size() as u32),
+                    uint32(block.transactions.len() as u32),
+                ])?;
+            }
+            Table::Transactions => {
+                for (index, tx) in block.transactions.iter().enumerate() {
+                    let hash = TransactionHash::from(tx.as_ref().clone());
+
+                    writer.push_row(&[
+                        uint32(height.0),
+                        uint32(index as u32),
+                        Value::Bytes(&hash.0),
+                        uint32(transaction_version(tx)),
+                        uint32(tx.inputs().count() as u32),
+                        uint32(tx.outputs().count() as u32),
+                        uint32(tx.expiry_height().map_or(0, |expiry| expiry.0)),
+                        uint32(tx.serialized_size() as u32),
+                    ])?;
+                }
+            }
+            Table::Outputs => {
+                for tx in &block.transactions {
+                    let hash = TransactionHash::from(tx.as_ref().clone());
+
+                    for (index, output) in tx.outputs().enumerate() {
+                        writer.push_row(&[
+                            uint32(height.0),
+                            Value::Bytes(&hash.0),
+                            uint32(index as u32),
+                            Value::Int64(output.value.into()),
+                            Value::Bytes(&output.pk_script.0),
+                        ])?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Table {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Table::ALL
+            .iter()
+            .copied()
+            .find(|table| table.name() == s)
+            .ok_or_else(|| format!("unknown analytics table: {}", s).into())
+    }
+}
+
+const fn column(
+    name: &'static str,
+    physical_type: PhysicalType,
+    logical_type: Option<LogicalType>,
+) -> Column {
+    Column {
+        name,
+        physical_type,
+        logical_type,
+    }
+}
+
+const fn uint32_column(name: &'static str) -> Column {
+    column(name, PhysicalType::Int32, Some(LogicalType::Uint32))
+}
+
+const fn hash_column(name: &'static str) -> Column {
+    column(name, PhysicalType::FixedLenByteArray(32), None)
+}
+
+static BLOCKS_SCHEMA: &[Column] = &[
+    uint32_column("height"),
+    hash_column("hash"),
+    hash_column("prev_hash"),
+    uint32_column("version"),
+    column(
+        "time",
+        PhysicalType::Int64,
+        Some(LogicalType::TimestampMillis),
+    ),
+    uint32_column("bits"),
+    uint32_column("size"),
+    uint32_column("tx_count"),
+];
+
+static TRANSACTIONS_SCHEMA: &[Column] = &[
+    uint32_column("height"),
+    uint32_column("tx_index"),
+    hash_column("txid"),
+    uint32_column("version"),
+    uint32_column("input_count"),
+    uint32_column("output_count"),
+    uint32_column("expiry_height"),
+    uint32_column("size"),
+];
+
+static OUTPUTS_SCHEMA: &[Column] = &[
+    uint32_column("height"),
+    hash_column("txid"),
+    uint32_column("output_index"),
+    column("value", PhysicalType::Int64, None),
+    column("script", PhysicalType::ByteArray, None),
+];
+
+/// Returns a `UINT_32` value, which Parquet stores in an `INT32`.
+fn uint32(value: u32) -> Value<'static> {
+    Value::Int32(value as i32)
+}
+
+/// Returns the version number of `tx`.
+fn transaction_version(tx: &Transaction) -> u32 {
+    match tx {
+        Transaction::V1 { .. } => 1,
+        Transaction::V2 { .. } => 2,
+        Transaction::V3 { .. } => 3,
+        Transaction::V4 { .. } => 4,
+    }
+}
+
+/// Write `tables` for the blocks from `start` to `end` (inclusive) in the
+/// best chain of `state`, as Parquet files in `output_dir`.
+///
+/// If `end` is `None`, or above the tip, exports up to the current tip.
+/// Blocks are read from the state one at a time, so memory use is bounded by
+/// the row group size, not the length of the chain.
+///
+/// Returns the number of blocks exported.
+pub async fn write_analytics_tables<S>(
+    mut state: S,
+    tables: &[Table],
+    start: BlockHeight,
+    end: Option<BlockHeight>,
+    output_dir: &Path,
+) -> Result<u32, Error>
+where
+    S: Service<Request, Response = Response, Error = Error>,
+{
+    let mut tables = tables.to_vec();
+    tables.sort();
+    tables.dedup();
+
+    fs::create_dir_all(output_dir)?;
+    let metadata = vec![(
+        SCHEMA_VERSION_KEY.to_string(),
+        ANALYTICS_SCHEMA_VERSION.to_string(),
+    )];
+    let mut writers = tables
+        .into_iter()
+        .map(|table| {
+            let path = output_dir.join(format!("{}.parquet", table.name()));
+            let file = io::BufWriter::new(File::create(path)?);
+            Ok((
+                table,
+                ParquetWriter::new(file, table.schema(), metadata.clone())?,
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let hashes = best_chain_hashes(&mut state, start, end).await?;
+
+    let mut count = 0;
+    for hash in hashes {
+        let block = get_block(&mut state, hash).await?;
+        for (table, writer) in &mut writers {
+            table.write_rows(&block, writer)?;
+        }
+        count += 1;
+    }
+
+    for (_table, writer) in writers {
+        writer.finish()?;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    #[test]
+    fn table_names() {
+        for &table in &Table::ALL {
+            assert_eq!(table.name().parse::<Table>().unwrap(), table);
+        }
+        assert!("inputs".parse::<Table>().is_err());
+    }
+
+    #[test]
+    fn block_rows() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?;
+
+        for &table in &Table::ALL {
+            let mut writer = ParquetWriter::new(Vec::new(), table.schema(), Vec::new())?;
+            table.write_rows(&block, &mut writer)?;
+            let file = writer.finish()?;
+
+            assert_eq!(&file[..4], b"PAR1");
+            assert_eq!(&file[file.len() - 4..], b"PAR1");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+//! A minimal Apache Parquet file writer, for exporting flat tables.
+//!
+//! Only the features needed by the analytics export are supported: required
+//! columns, `PLAIN` encoding, no compression, and a single data page in each
+//! column chunk. These files can be read by any Parquet reader.
+//!
+//! Parquet metadata is encoded using the Thrift compact protocol. Like the
+//! protobuf encoding in `export`, the few structures we need are encoded by
+//! hand.
+use std::{convert::TryFrom, io};
+
+/// The magic bytes at the start and end of every Parquet file.
+const MAGIC: &[u8; 4] = b"PAR1";
+
+/// The maximum number of rows in each row group.
+const ROW_GROUP_ROWS: usize = 100_000;
+
+/// The `created_by` string in the file metadata.
+const CREATED_BY: &str = concat!("zebra version ", env!("CARGO_PKG_VERSION"));
+
+/// The physical storage type of a column.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum PhysicalType {
+    Int32,
+    Int64,
+    ByteArray,
+    /// A byte array that is always the given length
+    FixedLenByteArray(usize),
+}
+
+/// The logical type of a column, stored as a Parquet `ConvertedType`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum LogicalType {
+    /// An unsigned 32-bit integer, stored as an `Int32`
+    Uint32,
+    /// Milliseconds since the Unix epoch, stored as an `Int64`
+    TimestampMillis,
+}
+
+/// A column in a Parquet schema.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) struct Column {
+    pub(super) name: &'static str,
+    pub(super) physical_type: PhysicalType,
+    pub(super) logical_type: Option<LogicalType>,
+}
+
+/// A single value in a row.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Value<'a> {
+    Int32(i32),
+    Int64(i64),
+    Bytes(&'a [u8]),
+}
+
+/// The metadata for a column chunk that has been written.
+struct ColumnChunk {
+    offset: u64,
+    size: u64,
+}
+
+/// The metadata for a row group that has been written.
+struct RowGroup {
+    columns: Vec<ColumnChunk>,
+    rows: usize,
+}
+
+/// Writes rows to a Parquet file.
+///
+/// Rows are buffered in memory until a row group is full, then written to
+/// the underlying writer. The file metadata is written by `finish`.
+pub(super) struct ParquetWriter<W: io::Write> {
+    writer: W,
+    schema: &'static [Column],
+    /// Key-value metadata, written in the file footer
+    metadata: Vec<(String, String)>,
+    /// The plain-encoded values of each column, in the current row group
+    buffers: Vec<Vec<u8>>,
+    /// The number of rows in the current row group
+    rows: usize,
+    /// The number of bytes written so far
+    offset: u64,
+    row_groups: Vec<RowGroup>,
+}
+
+impl<W: io::Write> ParquetWriter<W> {
+    /// Start writing a Parquet file with `schema` to `writer`.
+    pub(super) fn new(
+        mut writer: W,
+        schema: &'static [Column],
+        metadata: Vec<(String, String)>,
+    ) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+
+        Ok(ParquetWriter {
+            writer,
+            schema,
+            metadata,
+            buffers: vec![Vec::new(); schema.len()],
+            rows: 0,
+            offset: MAGIC.len() as u64,
+            row_groups: Vec::new(),
+        })
+    }
+
+    /// Add a row, which must have a value of the right type for each column
+    /// in the schema.
+    pub(super) fn push_row(&mut self, row: &[Value]) -> io::Result<()> {
+        if row.len() != self.schema.len() {
+            return Err(invalid_input("row has the wrong number of columns"));
+        }
+
+        // Check the whole row first, so invalid rows aren't partially added
+        for (column, value) in self.schema.iter().zip(row) {
+            match (column.physical_type, value) {
+                (PhysicalType::Int32, Value::Int32(_)) => {}
+                (PhysicalType::Int64, Value::Int64(_)) => {}
+                (PhysicalType::ByteArray, Value::Bytes(bytes))
+                    if u32::try_from(bytes.len()).is_ok() => {}
+                (PhysicalType::FixedLenByteArray(len), Value::Bytes(bytes))
+                    if bytes.len() == len => {}
+                _ => return Err(invalid_input("value does not match its column type")),
+            }
+        }
+
+        for ((column, value), buffer) in self.schema.iter().zip(row).zip(&mut self.buffers) {
+            match *value {
+                Value::Int32(value) => buffer.extend_from_slice(&value.to_le_bytes()),
+                Value::Int64(value) => buffer.extend_from_slice(&value.to_le_bytes()),
+                Value::Bytes(bytes) => {
+                    if column.physical_type == PhysicalType::ByteArray {
+                        buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                    }
+                    buffer.extend_from_slice(bytes);
+                }
+            }
+        }
+
+        self.rows += 1;
+        if self.rows >= ROW_GROUP_ROWS {
+            self.write_row_group()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write any buffered rows, and the file metadata.
+    ///
+    /// Returns the underlying writer.
+    pub(super) fn finish(mut self) -> io::Result<W> {
+        self.write_row_group()?;
+
+        let metadata = self.file_metadata();
+        let metadata_len = u32::try_from(metadata.len())
+            .map_err(|_| invalid_input("file metadata is too long"))?;
+
+        self.writer.write_all(&metadata)?;
+        self.writer.write_all(&metadata_len.to_le_bytes())?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    /// Write the buffered rows as a row group, with one column chunk for each
+    /// column.
+    fn write_row_group(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let num_values =
+            i32::try_from(self.rows).map_err(|_| invalid_input("row group is too large"))?;
+
+        let mut columns = Vec::with_capacity(self.buffers.len());
+        for buffer in &mut self.buffers {
+            let page_size =
+                i32::try_from(buffer.len()).map_err(|_| invalid_input("page is too large"))?;
+            let header = page_header(num_values, page_size);
+
+            self.writer.write_all(&header)?;
+            self.writer.write_all(buffer)?;
+
+            let size = (header.len() + buffer.len()) as u64;
+            columns.push(ColumnChunk {
+                offset: self.offset,
+                size,
+            });
+            self.offset += size;
+            buffer.clear();
+        }
+
+        self.row_groups.push(RowGroup {
+            columns,
+            rows: self.rows,
+        });
+        self.rows = 0;
+
+        Ok(())
+    }
+
+    /// Encode the Thrift `FileMetaData` for the written row groups.
+    fn file_metadata(&self) -> Vec<u8> {
+        let mut enc = CompactEncoder::default();
+
+        enc.i32_field(1, 1);
+
+        // The schema is flattened: a root element, followed by its columns
+        enc.list_field(2, STRUCT, self.schema.len() + 1);
+        enc.begin_struct();
+        enc.binary_field(4, b"schema");
+        enc.i32_field(5, self.schema.len() as i32);
+        enc.end_struct();
+        for column in self.schema {
+            enc.begin_struct();
+            enc.i32_field(1, column.physical_type.thrift_id());
+            if let PhysicalType::FixedLenByteArray(len) = column.physical_type {
+                enc.i32_field(2, len as i32);
+            }
+            // REQUIRED
+            enc.i32_field(3, 0);
+            enc.binary_field(4, column.name.as_bytes());
+            if let Some(logical_type) = column.logical_type {
+                enc.i32_field(6, logical_type.thrift_id());
+            }
+            enc.end_struct();
+        }
+
+        let total_rows: usize = self.row_groups.iter().map(|group| group.rows).sum();
+        enc.i64_field(3, total_rows as i64);
+
+        enc.list_field(4, STRUCT, self.row_groups.len());
+        for group in &self.row_groups {
+            enc.begin_struct();
+            enc.list_field(1, STRUCT, group.columns.len());
+            for (column, chunk) in self.schema.iter().zip(&group.columns) {
+                enc.begin_struct();
+                enc.i64_field(2, chunk.offset as i64);
+                enc.begin_struct_field(3);
+                enc.i32_field(1, column.physical_type.thrift_id());
+                // PLAIN
+                enc.list_field(2, I32, 1);
+                enc.i32(0);
+                enc.list_field(3, BINARY, 1);
+                enc.binary(column.name.as_bytes());
+                // UNCOMPRESSED
+                enc.i32_field(4, 0);
+                enc.i64_field(5, group.rows as i64);
+                enc.i64_field(6, chunk.size as i64);
+                enc.i64_field(7, chunk.size as i64);
+                enc.i64_field(9, chunk.offset as i64);
+                enc.end_struct();
+                enc.end_struct();
+            }
+            let total_size: u64 = group.columns.iter().map(|chunk| chunk.size).sum();
+            enc.i64_field(2, total_size as i64);
+            enc.i64_field(3, group.rows as i64);
+            enc.end_struct();
+        }
+
+        if !self.metadata.is_empty() {
+            enc.list_field(5, STRUCT, self.metadata.len());
+            for (key, value) in &self.metadata {
+                enc.begin_struct();
+                enc.binary_field(1, key.as_bytes());
+                enc.binary_field(2, value.as_bytes());
+                enc.end_struct();
+            }
+        }
+
+        enc.binary_field(6, CREATED_BY.as_bytes());
+        enc.end_struct();
+
+        enc.buf
+    }
+}
+
+impl PhysicalType {
+    /// The Parquet `Type` enum value for this type.
+    fn thrift_id(self) -> i32 {
+        match self {
+            PhysicalType::Int32 => 1,
+            PhysicalType::Int64 => 2,
+            PhysicalType::ByteArray => 6,
+            PhysicalType::FixedLenByteArray(_) => 7,
+        }
+    }
+}
+
+impl LogicalType {
+    /// The Parquet `ConvertedType` enum value for this type.
+    fn thrift_id(self) -> i32 {
+        match self {
+            LogicalType::TimestampMillis => 9,
+            LogicalType::Uint32 => 13,
+        }
+    }
+}
+
+/// Encode the Thrift `PageHeader` for a `PLAIN` data page containing
+/// `num_values` values in `page_size` bytes.
+fn page_header(num_values: i32, page_size: i32) -> Vec<u8> {
+    let mut enc = CompactEncoder::default();
+
+    // DATA_PAGE
+    enc.i32_field(1, 0);
+    enc.i32_field(2, page_size);
+    enc.i32_field(3, page_size);
+    enc.begin_struct_field(5);
+    enc.i32_field(1, num_values);
+    // PLAIN values, with RLE levels. Required columns don't have any
+    // levels, but the level encodings are required fields.
+    enc.i32_field(2, 0);
+    enc.i32_field(3, 3);
+    enc.i32_field(4, 3);
+    enc.end_struct();
+    enc.end_struct();
+
+    enc.buf
+}
+
+fn invalid_input(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Thrift compact protocol type IDs.
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+/// An encoder for the subset of the Thrift compact protocol used by Parquet
+/// metadata.
+#[derive(Default)]
+struct CompactEncoder {
+    buf: Vec<u8>,
+    /// The ID of the last field written in the current struct
+    last_field: i16,
+    /// The last field IDs of the enclosing structs
+    stack: Vec<i16>,
+}
+
+impl CompactEncoder {
+    fn field_header(&mut self, id: i16, field_type: u8) {
+        let delta = id - self.last_field;
+        if (1..=15).contains(&delta) {
+            self.buf.push(((delta as u8) << 4) | field_type);
+        } else {
+            self.buf.push(field_type);
+            self.i32(id.into());
+        }
+        self.last_field = id;
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.varint(((value << 1) ^ (value >> 31)) as u32 as u64);
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn binary(&mut self, bytes: &[u8]) {
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn i32_field(&mut self, id: i16, value: i32) {
+        self.field_header(id, I32);
+        self.i32(value);
+    }
+
+    fn i64_field(&mut self, id: i16, value: i64) {
+        self.field_header(id, I64);
+        self.i64(value);
+    }
+
+    fn binary_field(&mut self, id: i16, bytes: &[u8]) {
+        self.field_header(id, BINARY);
+        self.binary(bytes);
+    }
+
+    /// Write the header for a list field with `len` elements of
+    /// `element_type`. The elements must be written next.
+    fn list_field(&mut self, id: i16, element_type: u8, len: usize) {
+        self.field_header(id, LIST);
+        if len < 15 {
+            self.buf.push(((len as u8) << 4) | element_type);
+        } else {
+            self.buf.push(0xf0 | element_type);
+            self.varint(len as u64);
+        }
+    }
+
+    /// Start a struct field. The struct must be closed by `end_struct`.
+    fn begin_struct_field(&mut self, id: i16) {
+        self.field_header(id, STRUCT);
+        self.begin_struct();
+    }
+
+    /// Start a struct list element. The struct must be closed by
+    /// `end_struct`.
+    fn begin_struct(&mut self) {
+        self.stack.push(self.last_field);
+        self.last_field = 0;
+    }
+
+    /// End the current struct, or the top-level struct.
+    fn end_struct(&mut self) {
+        self.buf.push(0);
+        self.last_field = self.stack.pop().unwrap_or(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SCHEMA: &[Column] = &[
+        Column {
+            name: "height",
+            physical_type: PhysicalType::Int32,
+            logical_type: Some(LogicalType::Uint32),
+        },
+        Column {
+            name: "script",
+            physical_type: PhysicalType::ByteArray,
+            logical_type: None,
+        },
+    ];
+
+    #[test]
+    fn compact_encoding() {
+        let mut enc = CompactEncoder::default();
+        enc.i32_field(1, -1);
+        enc.i64_field(3, 300);
+        // Field ID deltas over 15 use the long form
+        enc.i32_field(20, 0);
+        enc.end_struct();
+
+        assert_eq!(
+            enc.buf,
+            vec![0x15, 0x01, 0x26, 0xd8, 0x04, 0x05, 0x28, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn file_layout() -> io::Result<()> {
+        let mut writer = ParquetWriter::new(Vec::new(), SCHEMA, Vec::new())?;
+        writer.push_row(&[Value::Int32(1), Value::Bytes(&[0xac])])?;
+        writer.push_row(&[Value::Int32(2), Value::Bytes(&[])])?;
+        assert!(writer.push_row(&[Value::Int32(3)]).is_err());
+        assert!(writer
+            .push_row(&[Value::Int64(3), Value::Bytes(&[])])
+            .is_err());
+        let file = writer.finish()?;
+
+        assert_eq!(&file[..4], MAGIC);
+        assert_eq!(&file[file.len() - 4..], MAGIC);
+
+        let footer_len = file.len() - 8;
+        let mut len_bytes = [0; 4];
+        len_bytes.copy_from_slice(&file[footer_len..footer_len + 4]);
+        let metadata_len = u32::from_le_bytes(len_bytes) as usize;
+
+        // The height column chunk starts right after the magic bytes, with a
+        // page header followed by two little-endian values
+        let heights = &file[4..file.len() - 8 - metadata_len];
+        let header = page_header(2, 8);
+        assert_eq!(&heights[..header.len()], &header[..]);
+        assert_eq!(
+            &heights[header.len()..header.len() + 8],
+            &[1, 0, 0, 0, 2, 0, 0, 0]
+        );
+
+        Ok(())
+    }
+}
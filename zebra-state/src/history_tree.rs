@@ -0,0 +1,208 @@
+//! ZIP-221 history trees for the best chain.
+//!
+//! After Heartwood activation, the state updates the history tree after every
+//! block, and stores the tree for each best chain height. The stored trees are
+//! used to check the `hashChainHistoryRoot` in the headers of new blocks.
+//!
+//! Each leaf contains the Sapling note commitment tree root after its block,
+//! so history trees are only tracked when the note commitment trees are
+//! known.
+use std::{convert::TryFrom, io::Cursor};
+use zebra_chain::{
+    block::Block,
+    history_tree::{HistoryTree, NodeData},
+    parameters::{ConsensusBranchId, NetworkUpgrade},
+    serialization::{ZcashDeserialize, ZcashSerialize},
+    transaction::Transaction,
+    types::BlockHeight,
+    Network,
+};
+
+use crate::non_finalized::block_work;
+use crate::note_trees::NoteCommitmentTrees;
+
+/// Returns the history tree after `block` at `height`, or `None` if `block`
+/// is before Heartwood activation.
+///
+/// `parent_tree` is the history tree after the parent block, and `trees` are
+/// the note commitment trees after `block`. At each network upgrade
+/// activation height, the parent tree is replaced by a new empty tree.
+///
+/// Also returns `None` if the parent tree is needed but unknown.
+pub(crate) fn next_history_tree(
+    network: Network,
+    block: &Block,
+    height: BlockHeight,
+    parent_tree: Option<HistoryTree>,
+    trees: &NoteCommitmentTrees,
+) -> Result<Option<HistoryTree>, Error> {
+    if height < heartwood_height(network) {
+        return Ok(None);
+    }
+
+    let upgrade = NetworkUpgrade::current(network, height);
+    let branch_id = upgrade
+        .branch_id()
+        .expect("network upgrades after Heartwood have a consensus branch id");
+    let mut tree = if upgrade.activation_height(network) == Some(height) {
+        HistoryTree::new(branch_id)
+    } else {
+        match parent_tree {
+            Some(tree) => tree,
+            None => return Ok(None),
+        }
+    };
+
+    tree.append(leaf(block, height, trees, branch_id)?)?;
+    Ok(Some(tree))
+}
+
+/// Returns the `hashChainHistoryRoot` that the header of the block at
+/// `height` must commit to, or `None` if `height` is before Heartwood
+/// activation.
+///
+/// `parent_tree` is the history tree after the parent block. At each network
+/// upgrade activation height, the header commits to the final tree of the
+/// previous network upgrade. At Heartwood activation, there is no previous
+/// tree, so the header commits to all zeroes.
+///
+/// Also returns `None` if the parent tree is needed but unknown.
+pub(crate) fn expected_history_root(
+    network: Network,
+    height: BlockHeight,
+    parent_tree: Option<&HistoryTree>,
+) -> Option<[u8; 32]> {
+    let heartwood_height = heartwood_height(network);
+
+    if height < heartwood_height {
+        None
+    } else if height == heartwood_height {
+        Some([0; 32])
+    } else {
+        parent_tree.map(HistoryTree::hash)
+    }
+}
+
+/// Check the `hashChainHistoryRoot` in the header of `block` at `height`.
+///
+/// `parent_tree` is the history tree after the parent block. Blocks before
+/// Heartwood activation, and blocks whose parent tree is needed but unknown,
+/// are not checked.
+pub(crate) fn check_history_root(
+    network: Network,
+    block: &Block,
+    height: BlockHeight,
+    parent_tree: Option<&HistoryTree>,
+) -> Result<(), Error> {
+    if let Some(expected) = expected_history_root(network, height, parent_tree) {
+        if block.header.final_sapling_root_hash.0 != expected {
+            Err("block header has an incorrect chain history root")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Serialize `tree` for storage.
+pub(crate) fn to_bytes(tree: &HistoryTree) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    tree.zcash_serialize(&mut bytes)
+        .expect("writing to a Vec never fails");
+    bytes
+}
+
+/// Deserialize a history tree from storage.
+pub(crate) fn from_bytes(bytes: &[u8]) -> Result<HistoryTree, Error> {
+    let mut reader = Cursor::new(bytes);
+    let tree = HistoryTree::zcash_deserialize(&mut reader)?;
+
+    if reader.position() != bytes.len() as u64 {
+        Err("stored history tree has trailing bytes")?;
+    }
+
+    Ok(tree)
+}
+
+/// Returns the Heartwood activation height on `network`.
+fn heartwood_height(network: Network) -> BlockHeight {
+    NetworkUpgrade::Heartwood
+        .activation_height(network)
+        .expect("Heartwood activates on every network")
+}
+
+/// Returns the history tree leaf for `block` at `height`.
+fn leaf(
+    block: &Block,
+    height: BlockHeight,
+    trees: &NoteCommitmentTrees,
+    consensus_branch_id: ConsensusBranchId,
+) -> Result<NodeData, Error> {
+    let time = u32::try_from(block.header.time.timestamp())?;
+    let sapling_root = trees.sapling.root();
+    let sapling_tx = block
+        .transactions
+        .iter()
+        .filter(|tx| {
+            matches!(
+                tx.as_ref(),
+                Transaction::V4 {
+                    shielded_data: Some(_),
+                    ..
+                }
+            )
+        })
+        .count();
+
+    Ok(NodeData {
+        consensus_branch_id,
+        subtree_commitment: block.hash().0,
+        start_time: time,
+        end_time: time,
+        start_target: block.header.bits,
+        end_target: block.header.bits,
+        start_sapling_root: sapling_root,
+        end_sapling_root: sapling_root,
+        subtree_total_work: block_work(block.header.bits)?,
+        start_height: height.0.into(),
+        end_height: height.0.into(),
+        sapling_tx: sapling_tx as u64,
+    })
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_roots_start_at_heartwood() {
+        zebra_test::init();
+
+        for &network in &[Network::Mainnet, Network::Testnet] {
+            let heartwood = heartwood_height(network);
+            let tree = HistoryTree::new(
+                NetworkUpgrade::Heartwood
+                    .branch_id()
+                    .expect("Heartwood has a consensus branch id"),
+            );
+
+            assert_eq!(
+                expected_history_root(network, BlockHeight(heartwood.0 - 1), Some(&tree)),
+                None
+            );
+            assert_eq!(
+                expected_history_root(network, heartwood, None),
+                Some([0; 32])
+            );
+            assert_eq!(
+                expected_history_root(network, BlockHeight(heartwood.0 + 1), None),
+                None
+            );
+            assert_eq!(
+                expected_history_root(network, BlockHeight(heartwood.0 + 1), Some(&tree)),
+                Some(tree.hash())
+            );
+        }
+    }
+}
@@ -1,29 +1,563 @@
-//! A basic implementation of the zebra-state service entirely in memory
+//! An implementation of the zebra-state service entirely in memory
 //!
-//! This service is provided as an independent implementation of the
-//! zebra-state service to use in verifying the correctness of `on_disk`'s
-//! `Service` implementation.
-use super::{Request, Response};
-use futures::prelude::*;
+//! The in-memory state is an ephemeral state backend, which supports every
+//! state request. It is also an independent implementation of the
+//! zebra-state service, which is used to verify the correctness of
+//! `on_disk`'s `Service` implementation.
+//!
+//! The memory used by block bodies can be bounded using
+//! `Config::in_memory_block_bytes`. Headers and indexes are always kept, so
+//! chain queries work for every best chain block, but the bodies of old
+//! finalized blocks can be evicted, like a pruned on-disk state.
+use super::{ChainTip, ChainTipStatus, Config, Request, Response, SyncProgress};
+use crate::address_index::{
+    address_key, block_address_changes, tx_location, tx_location_range, utxo_key_height,
+    utxo_range_start, AddressBalance, AddressIndexChanges, AddressKey, AddressTotals, AddressUtxo,
+    IndexedOutput, TxLocationKey, UtxoKey,
+};
+use crate::block_info::{transaction_fee, BlockInfo, TransactionInfo};
+use crate::chain_tx_stats::ChainTxStats;
+use crate::history_tree;
+use crate::index_coverage::{height_ranges, OptionalIndex};
+use crate::non_finalized::NonFinalizedState;
+use crate::note_trees::{NoteCommitmentSubtree, NoteCommitmentTrees};
+use crate::queued_blocks::QueuedBlocks;
+use crate::shielded_counts::ShieldedCounts;
+use crate::value_pools::{block_outputs, outpoint_key, ValueBalances, OUTPOINT_KEY_SIZE};
+use crate::SemanticallyVerifiedBlock;
+use futures::{channel::oneshot, prelude::*};
 use std::{
+    collections::{BTreeMap, HashMap},
     error,
     future::Future,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::SystemTime,
 };
 use tower::{buffer::Buffer, Service};
-use zebra_chain::block::BlockHeaderHash;
+use zebra_chain::{
+    block::{Block, BlockHeader, BlockHeaderHash},
+    history_tree::HistoryTree,
+    merkle_tree::MerklePath,
+    transaction::{OutPoint, TransactionHash},
+    types::{
+        amount::{Amount, NonNegative},
+        BlockHeight,
+    },
+    Network,
+};
 
 mod block_index;
 
 #[derive(Default)]
 struct InMemoryState {
+    /// The network of the state, if it is known
+    ///
+    /// History trees are only tracked when the network is known.
+    network: Option<Network>,
     index: block_index::BlockIndex,
+    /// Invalid block hashes, and the invalidated block that made them invalid
+    invalid: HashMap<BlockHeaderHash, BlockHeaderHash>,
+    /// Blocks that failed verification, and the verifier version and
+    /// consensus rule of each failure
+    failed_blocks: HashMap<BlockHeaderHash, (u32, String)>,
+    /// The value of each transparent output in the state
+    transparent_outputs: HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+    /// The value pool balances after each block, if they are known
+    value_pools: HashMap<BlockHeaderHash, ValueBalances>,
+    /// The note commitment trees after each block, if they are known
+    note_trees: HashMap<BlockHeaderHash, NoteCommitmentTrees>,
+    /// The history trees after each block, if they are known
+    history_trees: HashMap<BlockHeaderHash, HistoryTree>,
+    /// The shielded pool sizes after each block, if they are known
+    shielded_counts: HashMap<BlockHeaderHash, ShieldedCounts>,
+    /// The metadata for each block
+    block_info: HashMap<BlockHeaderHash, BlockInfo>,
+    /// The address paid by each transparent output that pays to an address
+    ///
+    /// The in-memory state always maintains the address index.
+    output_addresses: HashMap<[u8; OUTPOINT_KEY_SIZE], IndexedOutput>,
+    /// The total value received and spent by each address
+    address_totals: HashMap<AddressKey, AddressTotals>,
+    /// The unspent outputs paying to each address, ordered by address and
+    /// height
+    address_utxos: BTreeMap<UtxoKey, Amount<NonNegative>>,
+    /// The transactions that touch each address, ordered by address and
+    /// chain order
+    address_transactions: BTreeMap<TxLocationKey, TransactionHash>,
+    /// Blocks that are waiting for their parent block to be committed
+    queued: QueuedBlocks,
+    /// The non-finalized blocks of every candidate chain
+    ///
+    /// The best chain in the index always ends with the best non-finalized
+    /// chain.
+    non_finalized: NonFinalizedState,
 }
 
 impl InMemoryState {
-    fn contains(&mut self, _hash: BlockHeaderHash) -> Result<Option<u32>, Error> {
-        todo!()
+    /// Check that `block` is not marked as invalid, and is not the child of
+    /// an invalid block.
+    ///
+    /// Children of invalid blocks are also marked as invalid.
+    fn check_valid(&mut self, block: &Block) -> Result<(), Error> {
+        let hash = block.hash();
+
+        if self.invalid.contains_key(&hash) {
+            Err("block has been marked invalid")?;
+        }
+
+        if let Some(&root) = self.invalid.get(&block.header.previous_block_hash) {
+            self.invalid.insert(hash, root);
+            Err("block is a descendant of a block that has been marked invalid")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value pool balances before `block`, if they are known.
+    fn parent_value_balances(&self, block: &Block) -> Option<ValueBalances> {
+        if block.coinbase_height() == Some(BlockHeight(0)) {
+            Some(ValueBalances::zero())
+        } else {
+            self.value_pools
+                .get(&block.header.previous_block_hash)
+                .cloned()
+        }
+    }
+
+    /// Returns the value pool balances after `block`, or `None` if the
+    /// balances before `block` are unknown.
+    ///
+    /// `pending_outputs` contains transparent outputs that are being committed
+    /// in the same batch as `block`.
+    fn next_value_balances(
+        &self,
+        block: &Block,
+        parent_balances: Option<ValueBalances>,
+        pending_outputs: &HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+    ) -> Result<Option<ValueBalances>, Error> {
+        match parent_balances {
+            Some(parent_balances) => parent_balances
+                .add_block(block, |outpoint| {
+                    Ok(self.output_value(outpoint, pending_outputs))
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the note commitment trees before `block`, if they are known.
+    fn parent_note_trees(&self, block: &Block) -> Option<NoteCommitmentTrees> {
+        if block.coinbase_height() == Some(BlockHeight(0)) {
+            Some(NoteCommitmentTrees::default())
+        } else {
+            self.note_trees
+                .get(&block.header.previous_block_hash)
+                .cloned()
+        }
+    }
+
+    /// Returns the shielded pool sizes after `block`, or `None` if the sizes
+    /// before `block` are unknown.
+    fn next_shielded_counts(&self, block: &Block) -> Option<ShieldedCounts> {
+        let parent_counts = if block.coinbase_height() == Some(BlockHeight(0)) {
+            Some(ShieldedCounts::default())
+        } else {
+            self.shielded_counts
+                .get(&block.header.previous_block_hash)
+                .copied()
+        };
+
+        parent_counts.map(|counts| counts.add_block(block))
+    }
+
+    /// Returns the history tree after `block`, or `None` if the network is
+    /// unknown, `block` is before Heartwood activation, or the trees before
+    /// `block` are unknown.
+    ///
+    /// `parent_tree` is the history tree after the parent block, and `trees`
+    /// are the note commitment trees after `block`.
+    fn next_history_tree(
+        &self,
+        block: &Block,
+        parent_tree: Option<HistoryTree>,
+        trees: Option<&NoteCommitmentTrees>,
+    ) -> Result<Option<HistoryTree>, Error> {
+        match (self.network, trees, block.coinbase_height()) {
+            (Some(network), Some(trees), Some(height)) => {
+                history_tree::next_history_tree(network, block, height, parent_tree, trees)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns up to `limit` complete Sapling subtrees in the best chain,
+    /// starting at the subtree with `start_index`.
+    ///
+    /// The in-memory state doesn't index subtrees, so it recalculates them
+    /// from the genesis block. Returns an error if any block bodies have
+    /// been evicted.
+    fn sapling_subtrees(
+        &self,
+        start_index: u16,
+        limit: usize,
+    ) -> Result<Vec<NoteCommitmentSubtree>, Error> {
+        let mut trees = NoteCommitmentTrees::default();
+        let mut subtrees = Vec::new();
+        let mut height = BlockHeight(0);
+        while let Some(hash) = self.index.hash(height) {
+            if subtrees.len() >= limit {
+                break;
+            }
+            let block = self
+                .index
+                .get(hash)
+                .ok_or("subtrees can't be calculated after block bodies are evicted")?;
+            let (next_trees, block_subtrees) = trees.add_block_with_subtrees(&block, height)?;
+            trees = next_trees;
+            subtrees.extend(
+                block_subtrees
+                    .into_iter()
+                    .filter(|subtree| subtree.index >= start_index),
+            );
+            height = BlockHeight(height.0 + 1);
+        }
+        subtrees.truncate(limit);
+
+        Ok(subtrees)
+    }
+
+    /// Returns the header of the best chain block containing the transaction
+    /// with `txid`, and the merkle path from the transaction to the header's
+    /// merkle root.
+    ///
+    /// The in-memory state doesn't index transactions, so it searches the
+    /// best chain, starting at the tip. Blocks with evicted bodies are
+    /// skipped.
+    fn transaction_merkle_path(&self, txid: TransactionHash) -> Option<(BlockHeader, MerklePath)> {
+        let tip_height = self.tip_height()?;
+
+        (0..=tip_height.0).rev().find_map(|height| {
+            let block = self.index.get(BlockHeight(height))?;
+            let txids: Vec<TransactionHash> = block
+                .transactions
+                .iter()
+                .map(|tx| TransactionHash::from(tx.as_ref().clone()))
+                .collect();
+            let index = txids.iter().position(|hash| *hash == txid)?;
+
+            Some((block.header, MerklePath::new(&txids, index)?))
+        })
+    }
+
+    /// Returns the metadata for `block`.
+    ///
+    /// `pending_outputs` contains transparent outputs that are being committed
+    /// in the same batch as `block`.
+    fn block_info(
+        &self,
+        verified: &SemanticallyVerifiedBlock,
+        pending_outputs: &HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+    ) -> Result<BlockInfo, Error> {
+        BlockInfo::new(verified.block(), verified.bytes().len(), |outpoint| {
+            Ok(self.output_value(outpoint, pending_outputs))
+        })
+    }
+
+    /// Returns the value of the transparent output at `outpoint`, if it is
+    /// in `pending_outputs` or the state.
+    fn output_value(
+        &self,
+        outpoint: &OutPoint,
+        pending_outputs: &HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+    ) -> Option<Amount<NonNegative>> {
+        let key = outpoint_key(outpoint);
+        pending_outputs
+            .get(&key)
+            .or_else(|| self.transparent_outputs.get(&key))
+            .cloned()
+    }
+
+    /// Returns the address index changes made by `block`.
+    ///
+    /// The outputs spent by `block` must still be in the state.
+    fn address_changes(&self, block: &Block) -> AddressIndexChanges {
+        let height = block
+            .coinbase_height()
+            .expect("committed blocks have a coinbase height");
+
+        block_address_changes(block, height, |outpoint| {
+            let key = outpoint_key(outpoint);
+            Ok(self
+                .output_addresses
+                .get(&key)
+                .and_then(|&output| Some((output, *self.transparent_outputs.get(&key)?))))
+        })
+        .expect("in-memory output lookups never fail")
+    }
+
+    /// Apply the `totals` changes in `changes` to the address index.
+    fn apply_address_totals(&mut self, changes: &AddressIndexChanges) {
+        for (address, change) in &changes.totals {
+            let totals = self.address_totals.entry(*address).or_default();
+            *totals = totals.add(*change);
+        }
+    }
+
+    /// Record the transparent outputs, value pool balances, note commitment
+    /// and history trees, shielded pool sizes, metadata, and address index
+    /// changes for `block`.
+    fn commit_metadata(
+        &mut self,
+        block: &Block,
+        balances: Option<ValueBalances>,
+        trees: Option<NoteCommitmentTrees>,
+        history: Option<HistoryTree>,
+        info: BlockInfo,
+    ) {
+        let changes = self.address_changes(block);
+        self.apply_address_totals(&changes);
+        self.output_addresses
+            .extend(changes.created_outputs.iter().cloned());
+        self.address_utxos.extend(changes.created_utxos);
+        for (key, _) in &changes.spent_utxos {
+            self.address_utxos.remove(key);
+        }
+        self.address_transactions.extend(changes.transactions);
+
+        for (outpoint, value) in block_outputs(block) {
+            self.transparent_outputs
+                .insert(outpoint_key(&outpoint), value);
+        }
+        let hash = block.hash();
+        if let Some(balances) = balances {
+            self.value_pools.insert(hash, balances);
+        }
+        if let Some(trees) = trees {
+            self.note_trees.insert(hash, trees);
+        }
+        if let Some(history) = history {
+            self.history_trees.insert(hash, history);
+        }
+        if let Some(counts) = self.next_shielded_counts(block) {
+            self.shielded_counts.insert(hash, counts);
+        }
+        self.block_info.insert(hash, info);
+    }
+
+    /// Remove the transparent outputs, value pool balances, note commitment
+    /// and history trees, shielded pool sizes, metadata, and address index
+    /// changes for the `removed` blocks.
+    ///
+    /// Returns the hashes of the removed blocks.
+    fn remove_metadata(&mut self, removed: &[Arc<Block>]) -> Vec<BlockHeaderHash> {
+        // Find the address changes before removing any outputs, so spends
+        // within the removed blocks are attributed correctly.
+        let mut changes = AddressIndexChanges::default();
+        for block in removed {
+            let block_changes = self.address_changes(block);
+            changes.merge_totals(&block_changes, true);
+            changes.created_utxos.extend(block_changes.created_utxos);
+            changes.spent_utxos.extend(block_changes.spent_utxos);
+            changes.transactions.extend(block_changes.transactions);
+        }
+        self.apply_address_totals(&changes);
+        for (key, _) in &changes.transactions {
+            self.address_transactions.remove(key);
+        }
+        // Remove the created UTXOs first, so outputs that were created and
+        // spent by the removed blocks stay removed
+        for (key, _) in &changes.created_utxos {
+            self.address_utxos.remove(key);
+        }
+        // Restore the UTXOs spent by the removed blocks, unless they were
+        // also created by the removed blocks
+        if let Some(first_removed) = removed.first().and_then(|block| block.coinbase_height()) {
+            for (key, value) in changes.spent_utxos {
+                if utxo_key_height(&key) < first_removed {
+                    self.address_utxos.insert(key, value);
+                }
+            }
+        }
+
+        removed
+            .iter()
+            .map(|block| {
+                for (outpoint, _) in block_outputs(block) {
+                    let key = outpoint_key(&outpoint);
+                    self.transparent_outputs.remove(&key);
+                    self.output_addresses.remove(&key);
+                }
+                let hash = block.hash();
+                self.value_pools.remove(&hash);
+                self.note_trees.remove(&hash);
+                self.history_trees.remove(&hash);
+                self.shielded_counts.remove(&hash);
+                self.block_info.remove(&hash);
+                hash
+            })
+            .collect()
+    }
+
+    /// Commit `verified` once its parent block is in the state.
+    ///
+    /// Returns a receiver for the commit result.
+    fn commit_or_queue(
+        &mut self,
+        verified: SemanticallyVerifiedBlock,
+    ) -> Result<oneshot::Receiver<Result<Response, Error>>, Error> {
+        let hash = verified.hash();
+        let parent = verified.block().header.previous_block_hash;
+
+        if self.queued.contains(&hash) {
+            Err("block is already queued for commit")?;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        if self.index.contains(hash) || self.non_finalized.contains(&hash) {
+            let _ = tx.send(Ok(Response::AlreadyCommitted { hash }));
+            return Ok(rx);
+        }
+
+        crate::check_above_finalized(verified.height(), self.non_finalized.finalized_height())?;
+
+        let parent_committed = crate::is_genesis(&verified)
+            || self.non_finalized.contains(&parent)
+            || self.index.contains(parent);
+        self.queued.queue(verified, tx);
+        if parent_committed {
+            let mut queued = std::mem::take(&mut self.queued);
+            self.commit_queued(&mut queued, parent);
+            self.prune_finalized(&mut queued);
+            self.queued = queued;
+        }
+
+        Ok(rx)
+    }
+
+    /// Returns the height of the best chain tip, if there are any blocks.
+    fn tip_height(&self) -> Option<BlockHeight> {
+        self.index.tip().map(|(height, _)| height)
+    }
+
+    /// Reject the queued blocks at or below the finalized height, because
+    /// they can never be committed.
+    fn prune_finalized(&self, queued: &mut QueuedBlocks) {
+        if let Some(finalized_height) = self.non_finalized.finalized_height() {
+            metrics::gauge!("state.finalized.block.height", finalized_height.0 as i64);
+            queued.prune(finalized_height);
+        }
+    }
+
+    /// Commit the queued descendants of `parent`.
+    fn commit_queued(&mut self, queued: &mut QueuedBlocks, parent: BlockHeaderHash) {
+        queued.commit_descendants(parent, |block| self.commit_block(block));
+    }
+
+    /// Commit `block` to the non-finalized state, after checking that it
+    /// follows its parent block.
+    ///
+    /// If the block's chain becomes the best chain, the block is also added
+    /// to the index.
+    fn commit_block(&mut self, block: SemanticallyVerifiedBlock) -> Result<Response, Error> {
+        let hash = block.hash();
+        let parent_hash = block.block().header.previous_block_hash;
+        let parent_height = self
+            .non_finalized
+            .height(parent_hash)
+            .or_else(|| self.index.best_chain_height(parent_hash));
+        let genesis = self.index.hash(BlockHeight(0));
+        crate::check_contextual(&block, parent_height, genesis, self.tip_height())?;
+        self.check_valid(block.block())?;
+
+        self.non_finalized.commit(block)?;
+        if let Err(error) = self.write_best_chain() {
+            // Keep the index consistent with the non-finalized state
+            self.non_finalized.remove(hash);
+            if let Err(restore_error) = self.write_best_chain() {
+                tracing::warn!(?restore_error, "could not restore the previous best chain");
+            }
+            return Err(error);
+        }
+
+        Ok(Response::Committed { hash })
+    }
+
+    /// Update the best chain in the index, so it ends with the best
+    /// non-finalized chain, then finalize the blocks that are too deep to be
+    /// replaced.
+    fn write_best_chain(&mut self) -> Result<(), Error> {
+        let index = &self.index;
+        let (first_changed, blocks) = self
+            .non_finalized
+            .best_chain_diff(|height| Ok(index.hash(height)))?;
+
+        if self.tip_height() >= Some(first_changed) {
+            let removed = self.index.remove_from(first_changed);
+            self.remove_metadata(&removed);
+        }
+        for block in blocks {
+            self.commit_finalized(block)?;
+        }
+        self.non_finalized.finalize();
+
+        Ok(())
+    }
+
+    /// Replace the non-finalized state with the non-finalized blocks in the
+    /// index.
+    ///
+    /// Side chains aren't in the index, so they are dropped.
+    fn reload_non_finalized(&mut self) -> Result<(), Error> {
+        let index = &self.index;
+        self.non_finalized = NonFinalizedState::load(self.tip_height(), |height| {
+            Ok(index.get_verified(height).cloned())
+        })?;
+
+        Ok(())
+    }
+
+    /// Commit `block`, without checking that it follows its parent block.
+    fn commit_finalized(&mut self, verified: SemanticallyVerifiedBlock) -> Result<Response, Error> {
+        let block = verified.block().clone();
+        self.check_valid(&block)?;
+
+        let parent_balances = self.parent_value_balances(&block);
+        let balances = self.next_value_balances(&block, parent_balances, &HashMap::new())?;
+        let trees = self
+            .parent_note_trees(&block)
+            .map(|trees| trees.add_block(&block))
+            .transpose()?;
+        let parent_history = self
+            .history_trees
+            .get(&block.header.previous_block_hash)
+            .cloned();
+        let history = self.next_history_tree(&block, parent_history, trees.as_ref())?;
+        let info = self.block_info(&verified, &HashMap::new())?;
+        let hash = self.index.insert(verified)?;
+        self.commit_metadata(&block, balances, trees, history, info);
+
+        Ok(Response::Committed { hash })
+    }
+
+    /// Returns the block with `hash`, if it is in the best chain or a side
+    /// chain, and its body has not been evicted.
+    fn get_block(&self, hash: BlockHeaderHash) -> Option<Arc<Block>> {
+        self.index.get(hash).or_else(|| {
+            self.non_finalized
+                .get(&hash)
+                .map(|verified| verified.block().clone())
+        })
+    }
+
+    /// Returns the depth of the block with `hash` below the best chain tip,
+    /// or `None` if it is not in the best chain.
+    fn depth(&self, hash: BlockHeaderHash) -> Option<u32> {
+        let tip_height = self.tip_height()?;
+        let height = self.index.best_chain_height(hash)?;
+
+        Some(tip_height.0.saturating_sub(height.0))
     }
 }
 
@@ -40,47 +574,318 @@ impl Service<Request> for InMemoryState {
     fn call(&mut self, req: Request) -> Self::Future {
         tracing::debug!(?req);
         match req {
-            Request::AddBlock { block } => {
-                let result = self
-                    .index
-                    .insert(block)
-                    .map(|hash| Response::Added { hash });
+            Request::CommitBlock { block } => {
+                let result = self.commit_or_queue(block);
+
+                async move {
+                    result?.await.unwrap_or_else(|_| {
+                        Err("queued block was dropped before it was committed".into())
+                    })
+                }
+                .boxed()
+            }
+            Request::CommitFinalizedBlock { block } => {
+                let hash = block.hash();
+                let genesis = self.index.hash(BlockHeight(0));
+                let result = if self.index.contains(hash) {
+                    Ok(Response::AlreadyCommitted { hash })
+                } else {
+                    let checked = if crate::is_genesis(&block) {
+                        crate::check_genesis(&block, genesis)
+                    } else {
+                        Ok(())
+                    };
+
+                    checked
+                        .and_then(|()| self.commit_finalized(block.clone()))
+                        .and_then(|response| {
+                            self.non_finalized.finalize_to(block.height(), hash);
+                            self.write_best_chain()?;
+
+                            let mut queued = std::mem::take(&mut self.queued);
+                            queued.prune(block.height());
+                            self.commit_queued(&mut queued, hash);
+                            self.queued = queued;
+                            Ok(response)
+                        })
+                };
+
+                async move { result }.boxed()
+            }
+            Request::AddBlockBatch { blocks } => {
+                let result = crate::check_contiguous(&blocks).and_then(|checked| {
+                    for block in &blocks {
+                        self.check_valid(block)?;
+                    }
+
+                    let verified: Vec<_> = blocks
+                        .iter()
+                        .cloned()
+                        .map(SemanticallyVerifiedBlock::new)
+                        .collect::<Result<_, _>>()?;
+
+                    let mut metadata = Vec::with_capacity(blocks.len());
+                    let mut pending_outputs = HashMap::new();
+                    let mut balances = blocks
+                        .first()
+                        .and_then(|block| self.parent_value_balances(block));
+                    let mut trees = blocks
+                        .first()
+                        .and_then(|block| self.parent_note_trees(block));
+                    let mut history = blocks.first().and_then(|block| {
+                        self.history_trees
+                            .get(&block.header.previous_block_hash)
+                            .cloned()
+                    });
+                    for (block, verified) in blocks.iter().zip(&verified) {
+                        balances = self.next_value_balances(block, balances, &pending_outputs)?;
+                        trees = trees.map(|trees| trees.add_block(block)).transpose()?;
+                        history = self.next_history_tree(block, history, trees.as_ref())?;
+                        let info = self.block_info(verified, &pending_outputs)?;
+                        metadata.push((balances, trees.clone(), history.clone(), info));
+                        pending_outputs.extend(
+                            block_outputs(block)
+                                .into_iter()
+                                .map(|(outpoint, value)| (outpoint_key(&outpoint), value)),
+                        );
+                    }
+
+                    self.index.insert_batch(verified, &checked)?;
+                    for (block, (balances, trees, history, info)) in blocks.iter().zip(metadata) {
+                        self.commit_metadata(block, balances, trees, history, info);
+                    }
+                    // The blocks weren't added to the non-finalized state
+                    self.reload_non_finalized()?;
+                    Ok(Response::AddedBatch {
+                        hashes: checked.into_iter().map(|(_, hash)| hash).collect(),
+                    })
+                });
 
                 async { result }.boxed()
             }
+            Request::RollbackToHeight { height } => {
+                let first_removed = BlockHeight(height.0.saturating_add(1));
+                let result = crate::check_above_finalized(
+                    first_removed,
+                    self.non_finalized.finalized_height(),
+                )
+                .and_then(|()| {
+                    let removed = self.index.rollback(height);
+                    let removed = self.remove_metadata(&removed);
+                    self.non_finalized.remove_from(first_removed);
+                    self.write_best_chain()?;
+                    Ok(Response::RolledBack { removed })
+                });
+
+                async move { result }.boxed()
+            }
+            Request::InvalidateBlock { hash } => {
+                let height = self.index.best_chain_height(hash);
+                // Invalidating a finalized block reloads the non-finalized
+                // state below it, which needs the bodies of those blocks
+                if let Some(height) = height.filter(|_| !self.non_finalized.contains(&hash)) {
+                    let first_needed =
+                        crate::finalized_height(BlockHeight(height.0.saturating_sub(1)))
+                            .unwrap_or(BlockHeight(0));
+                    if !self.index.has_bodies_from(first_needed) {
+                        let result: Result<Response, Error> = Err(
+                            "blocks can't be invalidated after their bodies are evicted".into(),
+                        );
+                        return async move { result }.boxed();
+                    }
+                }
+                let removed = match height {
+                    Some(height) => self.index.remove_from(height),
+                    None => Vec::new(),
+                };
+                let removed = self.remove_metadata(&removed);
+
+                let result = if self.non_finalized.contains(&hash) {
+                    // The block and its descendants can be in the best chain
+                    // and any of the side chains
+                    let removed = self.non_finalized.remove(hash);
+                    self.write_best_chain().map(|()| removed)
+                } else if height.is_some() {
+                    // The block is finalized, so all the non-finalized blocks
+                    // are its descendants
+                    self.reload_non_finalized().map(|()| removed)
+                } else {
+                    Ok(removed)
+                };
+
+                self.invalid.insert(hash, hash);
+                if let Ok(removed) = &result {
+                    for &removed_hash in removed {
+                        self.invalid.insert(removed_hash, hash);
+                    }
+                }
+
+                async move { result.map(|removed| Response::Invalidated { removed }) }.boxed()
+            }
+            Request::ReconsiderBlock { hash } => {
+                self.invalid.retain(|_, root| *root != hash);
+
+                async move { Ok(Response::Reconsidered) }.boxed()
+            }
+            Request::RecordFailedBlock {
+                hash,
+                verifier_version,
+                rule,
+            } => {
+                self.failed_blocks
+                    .retain(|_, (version, _)| *version == verifier_version);
+                if self.failed_blocks.len() < crate::MAX_FAILED_BLOCKS {
+                    self.failed_blocks.insert(hash, (verifier_version, rule));
+                }
+
+                async move { Ok(Response::FailedBlockRecorded) }.boxed()
+            }
+            Request::FailedBlock {
+                hash,
+                verifier_version,
+            } => {
+                let rule = self
+                    .failed_blocks
+                    .get(&hash)
+                    .filter(|(version, _)| *version == verifier_version)
+                    .map(|(_, rule)| rule.clone());
+
+                async move { Ok(Response::FailedBlock(rule)) }.boxed()
+            }
             Request::GetBlock { hash } => {
+                self.index.touch(hash);
                 let result = self
-                    .index
-                    .get(hash)
+                    .get_block(hash)
                     .map(|block| Response::Block { block })
                     .ok_or_else(|| "block could not be found".into());
 
                 async move { result }.boxed()
             }
-            Request::GetTip => {
+            Request::GetRawBlock { hash } => {
+                self.index.touch(hash);
+                let bytes = match self.index.get_verified(hash) {
+                    Some(verified) => Some(verified.bytes().to_vec()),
+                    None => self
+                        .non_finalized
+                        .get(&hash)
+                        .map(|verified| verified.bytes().to_vec()),
+                };
+                let result = bytes
+                    .map(|bytes| Response::RawBlock { bytes })
+                    .ok_or_else(|| "block could not be found".into());
+
+                async move { result }.boxed()
+            }
+            Request::BlockInfo { hash } => {
                 let result = self
-                    .index
-                    .get_tip()
-                    .map(|block| block.hash())
-                    .map(|hash| Response::Tip { hash })
-                    .ok_or_else(|| "zebra-state contains no blocks".into());
+                    .block_info
+                    .get(&hash)
+                    .cloned()
+                    .map(Response::BlockInfo)
+                    .ok_or_else(|| "block could not be found".into());
 
                 async move { result }.boxed()
             }
-            Request::GetDepth { hash } => {
-                let res = self.contains(hash);
+            Request::BlockTransactionInfo { hash } => {
+                let result = match self.get_block(hash) {
+                    Some(block) => TransactionInfo::for_block(&block, |outpoint| {
+                        Ok(self.output_value(outpoint, &HashMap::new()))
+                    })
+                    .map(Response::BlockTransactionInfo),
+                    None => Err("block could not be found".into()),
+                };
 
-                async move {
-                    let depth = res?;
+                async move { result }.boxed()
+            }
+            Request::TransactionFee { transaction } => {
+                let result = transaction_fee(&transaction, |outpoint| {
+                    Ok(self.output_value(outpoint, &HashMap::new()))
+                })
+                .map(Response::TransactionFee);
 
-                    Ok(Response::Depth(depth))
-                }
-                .boxed()
+                async move { result }.boxed()
+            }
+            Request::BestChainBlockHash { height } => {
+                let hash = self.index.hash(height);
+
+                async move { Ok(Response::BlockHash(hash)) }.boxed()
+            }
+            Request::AncestorHash { hash, height } => {
+                let ancestor = self.index.ancestor(hash, height);
+
+                async move { Ok(Response::BlockHash(ancestor)) }.boxed()
+            }
+            Request::ChainTxStats { hash, window } => {
+                let index = &self.index;
+                let block_info = &self.block_info;
+                let result = ChainTxStats::new(
+                    hash,
+                    window,
+                    |hash| Ok(index.best_chain_height(hash)),
+                    |height| Ok(index.hash(height)),
+                    |hash| Ok(block_info.get(&hash).cloned()),
+                    |hash| Ok(index.header(hash)),
+                )
+                .map(Response::ChainTxStats);
+
+                async move { result }.boxed()
+            }
+            Request::FindBlockHashes { known_blocks, stop } => {
+                let index = &self.index;
+                let result = crate::find_block_hashes(
+                    &known_blocks,
+                    stop,
+                    |hash| Ok(index.best_chain_height(hash)),
+                    |height| Ok(index.hash(height)),
+                );
+
+                async move { result }.boxed()
+            }
+            Request::FindBlockHeaders { known_blocks, stop } => {
+                let index = &self.index;
+                let result = crate::find_block_headers(
+                    &known_blocks,
+                    stop,
+                    |hash| Ok(index.best_chain_height(hash)),
+                    |height| Ok(index.hash(height)),
+                    |hash| Ok(index.header(hash)),
+                );
+
+                async move { result }.boxed()
+            }
+            Request::GetTip => {
+                let response = self
+                    .index
+                    .tip()
+                    .map(|(_, hash)| Response::Tip { hash })
+                    .unwrap_or(Response::Empty);
+
+                async move { Ok(response) }.boxed()
+            }
+            Request::GetChainTips => {
+                let tips = self
+                    .index
+                    .tip()
+                    .map(|(height, hash)| ChainTip {
+                        hash,
+                        height,
+                        branch_len: 0,
+                        status: ChainTipStatus::Active,
+                    })
+                    .into_iter()
+                    .chain(self.non_finalized.fork_tips())
+                    .collect();
+
+                async move { Ok(Response::ChainTips(tips)) }.boxed()
+            }
+            Request::GetDepth { hash } => {
+                let depth = self.depth(hash);
+
+                async move { Ok(Response::Depth(depth)) }.boxed()
             }
             Request::GetBlockLocator { genesis } => {
-                let tip = self.index.get_tip();
-                let tip = match tip {
-                    Some(tip) => tip,
+                let tip_height = match self.tip_height() {
+                    Some(tip_height) => tip_height,
                     None => {
                         return async move {
                             Ok(Response::BlockLocator {
@@ -91,27 +896,158 @@ impl Service<Request> for InMemoryState {
                     }
                 };
 
-                let tip_height = tip
-                    .coinbase_height()
-                    .expect("tip block will have a coinbase height");
-
                 let block_locator = crate::block_locator_heights(tip_height)
                     .map(|height| {
                         self.index
-                            .get(height)
+                            .hash(height)
                             .expect("there should be no holes in the chain")
-                            .hash()
                     })
                     .collect();
 
                 async move { Ok(Response::BlockLocator { block_locator }) }.boxed()
             }
+            Request::BlockCount => {
+                let count = self.index.len() as u32;
+                let progress = self.index.tip().and_then(|(height, hash)| {
+                    let header = self.index.header(hash)?;
+                    Some(SyncProgress::estimate(height, &header, SystemTime::now()))
+                });
+
+                async move { Ok(Response::BlockCount { count, progress }) }.boxed()
+            }
+            // The in-memory state doesn't need compaction
+            Request::CompactNow => async move { Ok(Response::Compacted) }.boxed(),
+            Request::GetValueBalances => {
+                let balances = self
+                    .index
+                    .tip()
+                    .and_then(|(_, hash)| self.value_pools.get(&hash).cloned());
+
+                async move { Ok(Response::ValueBalances(balances)) }.boxed()
+            }
+            Request::NoteCommitmentTrees { height } => {
+                let trees = self
+                    .index
+                    .hash(height)
+                    .and_then(|hash| self.note_trees.get(&hash).cloned());
+
+                async move { Ok(Response::NoteCommitmentTrees(trees)) }.boxed()
+            }
+            Request::SaplingSubtrees { start_index, limit } => {
+                let result = self
+                    .sapling_subtrees(start_index, limit)
+                    .map(Response::SaplingSubtrees);
+                async move { result }.boxed()
+            }
+            Request::ShieldedCounts { height } => {
+                let block = match height {
+                    Some(height) => self.index.hash(height).map(|hash| (height, hash)),
+                    None => self.index.tip(),
+                };
+                let counts = block.and_then(|(height, hash)| {
+                    self.shielded_counts
+                        .get(&hash)
+                        .map(|&counts| (height, counts))
+                });
+
+                async move { Ok(Response::ShieldedCounts(counts)) }.boxed()
+            }
+            Request::HistoryTree { height } => {
+                let history = self
+                    .index
+                    .hash(height)
+                    .and_then(|hash| self.history_trees.get(&hash).cloned());
+
+                async move { Ok(Response::HistoryTree(history)) }.boxed()
+            }
+            Request::AddressBalance { addresses } => {
+                let result = AddressBalance::from_totals(addresses.iter().map(|address| {
+                    self.address_totals
+                        .get(&address_key(address))
+                        .cloned()
+                        .unwrap_or_default()
+                }))
+                .map(Response::AddressBalance);
+
+                async move { result }.boxed()
+            }
+            Request::UtxosByAddresses {
+                addresses,
+                start,
+                limit,
+            } => {
+                let mut result = Vec::new();
+                for address in &addresses {
+                    let prefix = address_key(address);
+                    for (key, value) in self
+                        .address_utxos
+                        .range(utxo_range_start(address, start)..)
+                        .take_while(|(key, _)| key.starts_with(&prefix))
+                        .take(limit)
+                    {
+                        match AddressUtxo::from_entry(*address, key, *value) {
+                            Ok(utxo) => result.push(utxo),
+                            Err(e) => return async move { Err(e) }.boxed(),
+                        }
+                    }
+                }
+                result.sort_by_key(|utxo| utxo.height);
+                result.truncate(limit);
+
+                async move { Ok(Response::Utxos(result)) }.boxed()
+            }
+            Request::TransactionIdsByAddresses {
+                addresses,
+                height_range,
+            } => {
+                let mut locations = Vec::new();
+                if !height_range.is_empty() {
+                    for address in &addresses {
+                        locations.extend(
+                            self.address_transactions
+                                .range(tx_location_range(address, &height_range))
+                                .map(|(key, hash)| {
+                                    let location =
+                                        tx_location(key).expect("in-memory keys are valid");
+                                    (location, *hash)
+                                }),
+                        );
+                    }
+                }
+                locations.sort_by_key(|(location, _)| *location);
+                locations.dedup();
+                let hashes = locations.into_iter().map(|(_, hash)| hash).collect();
+
+                async move { Ok(Response::TransactionIds(hashes)) }.boxed()
+            }
+            Request::TransactionMerklePath { txid } => {
+                let path = self.transaction_merkle_path(txid);
+                async move { Ok(Response::TransactionMerklePath(path)) }.boxed()
+            }
+            Request::MissingIndexRanges { index } => {
+                // The in-memory state maintains every index, but it can evict
+                // the block bodies used for transaction lookups
+                let ranges = match (index, self.tip_height()) {
+                    (OptionalIndex::Transactions, Some(tip_height)) => height_ranges(
+                        (0..=tip_height.0)
+                            .map(BlockHeight)
+                            .filter(|&height| self.index.get(height).is_none()),
+                    ),
+                    _ => Vec::new(),
+                };
+
+                async move { Ok(Response::MissingIndexRanges(ranges)) }.boxed()
+            }
         }
     }
 }
 
 /// Return's a type that implement's the `zebra_state::Service` entirely in
 /// memory using `HashMaps`
+///
+/// The state doesn't know its network, so it doesn't track history trees,
+/// and it never evicts block bodies. Use `init_with_config` for a state that
+/// supports every request.
 pub fn init() -> impl Service<
     Request,
     Response = Response,
@@ -123,4 +1059,28 @@ pub fn init() -> impl Service<
     Buffer::new(InMemoryState::default(), 1)
 }
 
+/// Returns an in-memory state service for `network`, which keeps about
+/// `config.in_memory_block_bytes` of block bodies in memory.
+///
+/// The other `config` fields are only used by the on-disk state.
+pub fn init_with_config(
+    config: &Config,
+    network: Network,
+) -> impl Service<
+    Request,
+    Response = Response,
+    Error = Error,
+    Future = impl Future<Output = Result<Response, Error>>,
+> + Send
+       + Clone
+       + 'static {
+    let state = InMemoryState {
+        network: Some(network),
+        index: block_index::BlockIndex::new(config.in_memory_block_bytes as usize),
+        ..InMemoryState::default()
+    };
+
+    Buffer::new(state, 1)
+}
+
 type Error = Box<dyn error::Error + Send + Sync + 'static>;
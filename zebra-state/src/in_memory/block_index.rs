@@ -1,50 +1,279 @@
 use std::{
-    collections::{btree_map::Entry, BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap},
     error::Error,
     sync::Arc,
 };
 use zebra_chain::{
-    block::{Block, BlockHeaderHash},
+    block::{Block, BlockHeader, BlockHeaderHash},
     types::BlockHeight,
 };
+
+use crate::SemanticallyVerifiedBlock;
+
+/// The best chain blocks, along with their serialized bytes.
+///
+/// Keeping the bytes means raw block reads don't have to serialize the block
+/// again.
+///
+/// The index can be bounded by the total size of the block bodies it keeps.
+/// When it is over its size limit, the bodies of the least recently used
+/// finalized blocks are evicted. Headers are always kept, so chain queries
+/// work for every best chain block, but block reads return `None` for
+/// evicted blocks.
 #[derive(Default)]
 pub(super) struct BlockIndex {
-    by_hash: HashMap<BlockHeaderHash, Arc<Block>>,
-    by_height: BTreeMap<BlockHeight, Arc<Block>>,
+    /// The height and header of each best chain block.
+    by_hash: HashMap<BlockHeaderHash, (BlockHeight, BlockHeader)>,
+    /// The hash of each best chain block.
+    by_height: BTreeMap<BlockHeight, BlockHeaderHash>,
+    /// The blocks whose bodies are in memory, and the tick when each block
+    /// was last used.
+    bodies: HashMap<BlockHeaderHash, (SemanticallyVerifiedBlock, u64)>,
+    /// The hashes of the blocks in `bodies`, ordered by the tick when they
+    /// were last used.
+    recent: BTreeMap<u64, BlockHeaderHash>,
+    /// The tick for the next use of a block.
+    next_tick: u64,
+    /// The total size of the block bodies in memory, in bytes.
+    body_bytes: usize,
+    /// The maximum total size of the block bodies in memory, in bytes.
+    ///
+    /// If zero, bodies are never evicted.
+    max_body_bytes: usize,
 }
 
 impl BlockIndex {
+    /// Returns an empty index, which keeps about `max_body_bytes` of block
+    /// bodies in memory.
+    ///
+    /// Non-finalized blocks are never evicted, so the index can go over its
+    /// limit. If `max_body_bytes` is zero, bodies are never evicted.
+    pub(super) fn new(max_body_bytes: usize) -> Self {
+        Self {
+            max_body_bytes,
+            ..Self::default()
+        }
+    }
+
     pub(super) fn insert(
         &mut self,
-        block: impl Into<Arc<Block>>,
+        block: SemanticallyVerifiedBlock,
     ) -> Result<BlockHeaderHash, Box<dyn Error + Send + Sync + 'static>> {
-        let block = block.into();
-        let hash = block.as_ref().into();
-        let height = block.coinbase_height().unwrap();
-
-        match self.by_height.entry(height) {
-            Entry::Vacant(entry) => {
-                let _ = entry.insert(block.clone());
-                let _ = self.by_hash.insert(hash, block);
-                Ok(hash)
-            }
-            Entry::Occupied(_) => Err("forks in the chain aren't supported yet")?,
+        let hash = block.hash();
+
+        if self.by_height.contains_key(&block.height()) {
+            Err("forks in the chain aren't supported yet")?;
+        }
+        self.insert_unchecked(block);
+        self.evict();
+
+        Ok(hash)
+    }
+
+    /// Insert a contiguous run of `blocks`, which must already be checked.
+    ///
+    /// If any of the block heights are already occupied, returns an error
+    /// without inserting any blocks.
+    pub(super) fn insert_batch(
+        &mut self,
+        blocks: Vec<SemanticallyVerifiedBlock>,
+        checked: &[(BlockHeight, BlockHeaderHash)],
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        if checked
+            .iter()
+            .any(|(height, _)| self.by_height.contains_key(height))
+        {
+            Err("forks in the chain aren't supported yet")?;
+        }
+
+        for block in blocks {
+            self.insert_unchecked(block);
         }
+        self.evict();
+
+        Ok(())
     }
 
-    pub(super) fn get(&mut self, query: impl Into<BlockQuery>) -> Option<Arc<Block>> {
-        match query.into() {
-            BlockQuery::ByHash(hash) => self.by_hash.get(&hash),
-            BlockQuery::ByHeight(height) => self.by_height.get(&height),
+    fn insert_unchecked(&mut self, block: SemanticallyVerifiedBlock) {
+        let hash = block.hash();
+        let height = block.height();
+
+        let _ = self.by_height.insert(height, hash);
+        let _ = self.by_hash.insert(hash, (height, block.block().header));
+        let tick = self.tick();
+        self.body_bytes += block.bytes().len();
+        let _ = self.recent.insert(tick, hash);
+        let _ = self.bodies.insert(hash, (block, tick));
+    }
+
+    /// Remove all blocks above `height`.
+    ///
+    /// Returns the removed blocks, in height order.
+    pub(super) fn rollback(&mut self, height: BlockHeight) -> Vec<Arc<Block>> {
+        match height.0.checked_add(1) {
+            Some(first_removed) => self.remove_from(BlockHeight(first_removed)),
+            None => Vec::new(),
         }
-        .cloned()
     }
 
-    pub(super) fn get_tip(&self) -> Option<Arc<Block>> {
+    /// Remove the blocks at and above `first_removed`.
+    ///
+    /// Returns the removed blocks, in height order. Evicted blocks are
+    /// removed, but not returned, so callers that need every removed block
+    /// must check `has_bodies_from` first.
+    pub(super) fn remove_from(&mut self, first_removed: BlockHeight) -> Vec<Arc<Block>> {
+        self.by_height
+            .split_off(&first_removed)
+            .into_iter()
+            .filter_map(|(_height, hash)| {
+                let _ = self.by_hash.remove(&hash);
+                self.remove_body(hash)
+            })
+            .collect()
+    }
+
+    /// Returns true if the bodies of the blocks at and above `height` are in
+    /// memory.
+    pub(super) fn has_bodies_from(&self, height: BlockHeight) -> bool {
+        self.by_height
+            .range(height..)
+            .all(|(_height, hash)| self.bodies.contains_key(hash))
+    }
+
+    /// Returns the block for `query`, if it is in the best chain, and its body
+    /// has not been evicted.
+    pub(super) fn get(&self, query: impl Into<BlockQuery>) -> Option<Arc<Block>> {
+        self.get_verified(query).map(|block| block.block().clone())
+    }
+
+    /// Returns the block for `query`, along with its serialized bytes.
+    pub(super) fn get_verified(
+        &self,
+        query: impl Into<BlockQuery>,
+    ) -> Option<&SemanticallyVerifiedBlock> {
+        let hash = match query.into() {
+            BlockQuery::ByHash(hash) => hash,
+            BlockQuery::ByHeight(height) => *self.by_height.get(&height)?,
+        };
+
+        self.bodies.get(&hash).map(|(block, _last_used)| block)
+    }
+
+    /// Mark the block with `hash` as recently used, so its body is evicted
+    /// after less recently used blocks.
+    pub(super) fn touch(&mut self, hash: BlockHeaderHash) {
+        let tick = self.tick();
+        if let Some((_block, last_used)) = self.bodies.get_mut(&hash) {
+            let _ = self.recent.remove(last_used);
+            let _ = self.recent.insert(tick, hash);
+            *last_used = tick;
+        }
+    }
+
+    /// Returns true if the block with `hash` is in the best chain, even if its
+    /// body has been evicted.
+    pub(super) fn contains(&self, hash: BlockHeaderHash) -> bool {
+        self.by_hash.contains_key(&hash)
+    }
+
+    /// Returns the hash of the best chain block at `height`.
+    pub(super) fn hash(&self, height: BlockHeight) -> Option<BlockHeaderHash> {
+        self.by_height.get(&height).copied()
+    }
+
+    /// Returns the header of the best chain block with `hash`.
+    pub(super) fn header(&self, hash: BlockHeaderHash) -> Option<BlockHeader> {
+        self.by_hash.get(&hash).map(|&(_height, header)| header)
+    }
+
+    /// Returns the height of the block with `hash`, if it is in the best
+    /// chain.
+    pub(super) fn best_chain_height(&self, hash: BlockHeaderHash) -> Option<BlockHeight> {
+        self.by_hash.get(&hash).map(|&(height, _header)| height)
+    }
+
+    /// Returns the hash of the ancestor of `hash` at `height`, following
+    /// the parent hashes of the blocks in the index.
+    ///
+    /// Returns `None` if the block or any of its ancestors above `height`
+    /// are missing, or if `height` is above the block.
+    pub(super) fn ancestor(
+        &self,
+        mut hash: BlockHeaderHash,
+        height: BlockHeight,
+    ) -> Option<BlockHeaderHash> {
+        loop {
+            let &(block_height, header) = self.by_hash.get(&hash)?;
+
+            if block_height == height {
+                return Some(hash);
+            }
+            if block_height < height {
+                return None;
+            }
+            hash = header.previous_block_hash;
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.by_height.len()
+    }
+
+    /// Returns the height and hash of the best chain tip.
+    pub(super) fn tip(&self) -> Option<(BlockHeight, BlockHeaderHash)> {
         self.by_height
             .iter()
             .next_back()
-            .map(|(_key, value)| value.clone())
+            .map(|(&height, &hash)| (height, hash))
+    }
+
+    /// Evict the bodies of the least recently used finalized blocks, until
+    /// the index is under its size limit.
+    ///
+    /// The finalized tip is kept, because it is needed to reload the
+    /// non-finalized state.
+    fn evict(&mut self) {
+        if self.max_body_bytes == 0 || self.body_bytes <= self.max_body_bytes {
+            return;
+        }
+        let finalized_height = match self
+            .tip()
+            .and_then(|(tip_height, _)| crate::finalized_height(tip_height))
+        {
+            Some(finalized_height) => finalized_height,
+            None => return,
+        };
+
+        let mut body_bytes = self.body_bytes;
+        let mut evicted = Vec::new();
+        for hash in self.recent.values() {
+            if body_bytes <= self.max_body_bytes {
+                break;
+            }
+            let (height, _header) = self.by_hash[hash];
+            if height < finalized_height {
+                body_bytes -= self.bodies[hash].0.bytes().len();
+                evicted.push(*hash);
+            }
+        }
+
+        for hash in evicted {
+            let _ = self.remove_body(hash);
+        }
+    }
+
+    fn remove_body(&mut self, hash: BlockHeaderHash) -> Option<Arc<Block>> {
+        let (block, last_used) = self.bodies.remove(&hash)?;
+        self.body_bytes -= block.bytes().len();
+        let _ = self.recent.remove(&last_used);
+
+        Some(block.block().clone())
+    }
+
+    fn tick(&mut self) -> u64 {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        tick
     }
 }
 
@@ -0,0 +1,105 @@
+//! The heights covered by the optional and prunable indexes.
+//!
+//! Some indexes don't cover every best chain block: the address index only
+//! covers blocks committed after it was enabled, and pruning removes the
+//! data used for transaction and spent output lookups. Lookups in blocks
+//! that aren't covered return empty results, so callers check the
+//! `MissingIndexRanges` response before trusting an empty result.
+use std::ops::RangeInclusive;
+use zebra_chain::types::BlockHeight;
+
+/// An index that might not cover every best chain block.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OptionalIndex {
+    /// Transaction lookups, which need the block body.
+    ///
+    /// Not available for pruned blocks, or blocks with evicted bodies.
+    Transactions,
+    /// The transparent address index.
+    ///
+    /// Not available for blocks committed while the index was disabled.
+    Addresses,
+    /// The transparent outputs spent by each block, used to calculate fees.
+    ///
+    /// Not available for pruned blocks.
+    SpentOutputs,
+}
+
+impl OptionalIndex {
+    /// Returns the tag byte for this index, in the request recording format.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            OptionalIndex::Transactions => 0,
+            OptionalIndex::Addresses => 1,
+            OptionalIndex::SpentOutputs => 2,
+        }
+    }
+
+    /// Returns the index for a tag byte, in the request recording format.
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(OptionalIndex::Transactions),
+            1 => Some(OptionalIndex::Addresses),
+            2 => Some(OptionalIndex::SpentOutputs),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the heights up to `tip` that are below `covered_from`, as a list
+/// of ranges.
+pub(crate) fn missing_below(
+    covered_from: u32,
+    tip: BlockHeight,
+) -> Vec<RangeInclusive<BlockHeight>> {
+    match covered_from.checked_sub(1) {
+        Some(last) => vec![BlockHeight(0)..=BlockHeight(last.min(tip.0))],
+        None => Vec::new(),
+    }
+}
+
+/// Returns `heights` as a list of ranges of consecutive heights.
+///
+/// `heights` must be in increasing order.
+pub(crate) fn height_ranges(
+    heights: impl IntoIterator<Item = BlockHeight>,
+) -> Vec<RangeInclusive<BlockHeight>> {
+    let mut ranges: Vec<RangeInclusive<BlockHeight>> = Vec::new();
+    for height in heights {
+        match ranges.last_mut() {
+            Some(range) if range.end().0 + 1 == height.0 => *range = *range.start()..=height,
+            _ => ranges.push(height..=height),
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_merge_consecutive_heights() {
+        let heights = [0, 1, 2, 5, 7, 8].iter().map(|&height| BlockHeight(height));
+        assert_eq!(
+            height_ranges(heights),
+            vec![
+                BlockHeight(0)..=BlockHeight(2),
+                BlockHeight(5)..=BlockHeight(5),
+                BlockHeight(7)..=BlockHeight(8),
+            ]
+        );
+
+        assert_eq!(missing_below(0, BlockHeight(10)), Vec::new());
+        assert_eq!(
+            missing_below(3, BlockHeight(10)),
+            vec![BlockHeight(0)..=BlockHeight(2)]
+        );
+        // Indexes can start after the tip
+        assert_eq!(
+            missing_below(12, BlockHeight(10)),
+            vec![BlockHeight(0)..=BlockHeight(10)]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Configurable middleware for Zebra's services.
+//!
+//! The state and consensus services are wrapped in the same stack of tower
+//! layers: an optional concurrency limit and request timeout, request metrics
+//! and tracing spans, and a buffer that makes the service `Clone`. The stack
+//! is configured with a [`LayerConfig`], so `zebrad` and applications that
+//! embed Zebra's services can tune each service separately.
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    error,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tower::{
+    buffer::Buffer, layer::Layer, limit::ConcurrencyLimit, timeout::Timeout, util::BoxService,
+    Service,
+};
+use tracing_futures::Instrument;
+
+/// The configuration of the layers around a service.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LayerConfig {
+    /// The number of requests that can wait for the service to be ready.
+    ///
+    /// Callers wait for a slot in the buffer, so larger buffers let more
+    /// requests queue up behind slow requests.
+    pub buffer_size: usize,
+
+    /// The maximum number of requests that the service handles at the same
+    /// time, or `None` for no limit.
+    pub concurrency_limit: Option<usize>,
+
+    /// Should each request update the `service.request.count` and
+    /// `service.error.count` metrics, labeled with the service name?
+    pub metrics: bool,
+
+    /// Should each request run in a debug-level tracing span, named after the
+    /// service?
+    pub tracing: bool,
+
+    // Note: due to the way this is rendered by the toml
+    // serializer, the Duration fields should come last.
+    /// The maximum time for a service to respond to a request, or `None` for
+    /// no timeout.
+    ///
+    /// Requests that time out return an error, but the work they started
+    /// might still finish.
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 1,
+            concurrency_limit: None,
+            metrics: true,
+            tracing: true,
+            request_timeout: None,
+        }
+    }
+}
+
+impl LayerConfig {
+    /// Returns the configured layers for a service named `name`, which
+    /// handles requests of type `R`.
+    ///
+    /// `name` is used in metric labels and tracing spans.
+    pub fn layers<R>(&self, name: &'static str) -> ServiceLayers<R> {
+        ServiceLayers {
+            config: self.clone(),
+            name,
+            _request: PhantomData,
+        }
+    }
+}
+
+/// A tower [`Layer`] that wraps a service in the layers in a [`LayerConfig`].
+///
+/// The wrapped service is a [`Buffer`], so it can be cloned and shared
+/// between tasks.
+pub struct ServiceLayers<R> {
+    config: LayerConfig,
+    name: &'static str,
+    _request: PhantomData<fn(R)>,
+}
+
+impl<R> Clone for ServiceLayers<R> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            name: self.name,
+            _request: PhantomData,
+        }
+    }
+}
+
+impl<S, R> Layer<S> for ServiceLayers<R>
+where
+    S: Service<R, Error = Error> + Send + 'static,
+    S::Response: Send + 'static,
+    S::Future: Send + 'static,
+    R: Send + 'static,
+{
+    type Service = Buffer<BoxService<R, S::Response, Error>, R>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let mut service = BoxService::new(inner);
+        if let Some(limit) = self.config.concurrency_limit {
+            service = BoxService::new(ConcurrencyLimit::new(service, limit));
+        }
+        if let Some(timeout) = self.config.request_timeout {
+            service = BoxService::new(Timeout::new(service, timeout));
+        }
+        if self.config.metrics || self.config.tracing {
+            service = BoxService::new(Observed {
+                inner: service,
+                name: self.name,
+                metrics: self.config.metrics,
+                tracing: self.config.tracing,
+            });
+        }
+
+        Buffer::new(service, self.config.buffer_size)
+    }
+}
+
+/// A service that records metrics and tracing spans for each request to
+/// `inner`.
+///
+/// It is the outermost layer inside the buffer, so timeouts count as errors.
+struct Observed<S> {
+    inner: S,
+    name: &'static str,
+    metrics: bool,
+    tracing: bool,
+}
+
+impl<S, R> Service<R> for Observed<S>
+where
+    S: Service<R, Error = Error>,
+    S::Response: Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let name = self.name;
+        let record_metrics = self.metrics;
+        if record_metrics {
+            metrics::counter!("service.request.count", 1, "service" => name);
+        }
+        let span = if self.tracing {
+            tracing::debug_span!("service", service = name)
+        } else {
+            tracing::Span::none()
+        };
+
+        let response = span.in_scope(|| self.inner.call(req));
+        async move {
+            let result = response.await;
+            if record_metrics && result.is_err() {
+                metrics::counter!("service.error.count", 1, "service" => name);
+            }
+            result
+        }
+        .instrument(span)
+        .boxed()
+    }
+}
+
+type Error = Box<dyn error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn slow_requests_time_out() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = LayerConfig {
+            request_timeout: Some(Duration::from_millis(50)),
+            concurrency_limit: Some(1),
+            ..LayerConfig::default()
+        };
+        let service = config
+            .layers("test")
+            .layer(service_fn(|delay: u64| async move {
+                tokio::time::delay_for(Duration::from_millis(delay)).await;
+                Ok::<_, Error>(delay)
+            }));
+
+        assert_eq!(service.clone().oneshot(0).await?, 0);
+        assert!(service.clone().oneshot(10_000).await.is_err());
+        // The service still works after a timeout
+        assert_eq!(service.oneshot(1).await?, 1);
+
+        Ok(())
+    }
+}
@@ -8,6 +8,10 @@
 //! * BlockHeight -> Block
 //!
 //! Inserting a block into the service will create a mapping in each tree for that block.
+//!
+//! The trees store the best chain. The most recent blocks of every candidate
+//! chain are also kept in memory, so the state can switch to a side chain
+//! when it has more work than the best chain.
 
 #![doc(html_favicon_url = "https://www.zfnd.org/images/zebra-favicon-128.png")]
 #![doc(html_logo_url = "https://www.zfnd.org/images/zebra-icon.png")]
@@ -18,30 +22,270 @@
 use color_eyre::eyre::{eyre, Report};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use std::{error, iter, sync::Arc};
+use std::{
+    error, iter,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tower::{Service, ServiceExt};
 
 use zebra_chain::{
-    block::{Block, BlockHeaderHash},
-    types::BlockHeight,
+    addresses::transparent::TransparentAddress,
+    block::{Block, BlockHeader, BlockHeaderHash},
+    history_tree::HistoryTree,
+    merkle_tree::MerklePath,
+    transaction::{Transaction, TransactionHash},
+    types::{
+        amount::{Amount, NonNegative},
+        BlockHeight,
+    },
     Network,
     Network::*,
 };
 
+mod address_index;
+mod block_cache;
+mod block_info;
+mod chain_tx_stats;
+pub mod checkpoint_bundle;
+pub mod export;
+mod history_tree;
 pub mod in_memory;
+mod index_coverage;
+pub mod layers;
+mod non_finalized;
+mod note_trees;
+mod nullifiers;
 pub mod on_disk;
+mod queued_blocks;
+pub mod recording;
+mod shielded_counts;
+mod snapshot;
+mod storage;
+mod validate;
+pub mod value_pools;
+mod verified_block;
+
+pub use address_index::{AddressBalance, AddressUtxo};
+pub use block_info::{BlockInfo, TransactionInfo};
+pub use chain_tx_stats::{ChainTxStats, DEFAULT_CHAIN_TX_STATS_WINDOW};
+pub use index_coverage::OptionalIndex;
+pub use note_trees::{NoteCommitmentSubtree, NoteCommitmentTrees};
+pub use on_disk::{NetworkMismatch, OutOfDiskSpace, StateLocked};
+pub use shielded_counts::ShieldedCounts;
+pub use storage::{
+    CorruptValue, Durability, ReadOnlyState, StorageBackendKind, TreeSchema, STATE_FORMAT_VERSION,
+    TREES,
+};
+pub use validate::InvalidConfig;
+pub use value_pools::ValueBalances;
+pub use verified_block::SemanticallyVerifiedBlock;
 
 /// Configuration for the state service.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[serde(deny_unknown_fields, default)]
 pub struct Config {
     /// The root directory for storing cached data.
     ///
     /// Each network has a separate state, which is stored in "mainnet/state"
     /// and "testnet/state" subdirectories.
     pub cache_dir: Option<PathBuf>,
+
+    /// The memory budget of the storage backend, in bytes.
+    ///
+    /// The budget covers the backend's page or block caches, and its write
+    /// buffers, for the state and archive databases together. Larger budgets
+    /// speed up repeated reads of blocks and indexes. On machines with little
+    /// memory, lower the budget so zebrad isn't killed during the initial
+    /// sync.
+    ///
+    /// The block cache and the non-finalized blocks are not included.
+    pub memory_cache_bytes: u64,
+
+    /// The maximum size of recently committed and recently read blocks that
+    /// are kept in memory, in bytes.
+    ///
+    /// Cached blocks are returned without reading or parsing them again,
+    /// which speeds up serving blocks near the tip to syncing peers. Set to 0
+    /// to disable the cache.
+    pub block_cache_bytes: u64,
+
+    /// Should the state maintain an index of transparent address balances,
+    /// unspent outputs, and transactions?
+    ///
+    /// The index is built as blocks are committed, so it only covers blocks
+    /// that were committed while it was enabled. To index the whole chain,
+    /// enable it before syncing.
+    pub address_index: bool,
+
+    /// A secondary directory for old block data, on cheaper storage.
+    ///
+    /// Blocks more than `archive_depth` blocks below the tip are moved to
+    /// this directory by a background task. Reads fall through to the archive
+    /// automatically, so the archive must stay available while Zebra is
+    /// running.
+    ///
+    /// If `None`, all blocks are stored in `cache_dir`.
+    pub archive_dir: Option<PathBuf>,
+
+    /// The minimum depth of blocks that are moved to `archive_dir`.
+    pub archive_depth: u32,
+
+    /// Should the state discard old data that isn't needed to validate new
+    /// blocks?
+    ///
+    /// For blocks more than `prune_depth` blocks below the tip, a background
+    /// task discards block bodies, spent transparent outputs, and note
+    /// commitment and history trees. Headers and unspent outputs are kept.
+    /// Pruned blocks can't be served to peers or returned by block queries.
+    ///
+    /// Pruning can't be undone. To get a full state again, delete the state
+    /// and sync from scratch.
+    pub prune: bool,
+
+    /// The minimum depth of pruned blocks.
+    ///
+    /// Blocks that can still be rolled back are never pruned, so depths of
+    /// `MAX_BLOCK_REORG_HEIGHT` or less are raised above it.
+    pub prune_depth: u32,
+
+    /// The number of blocks at the tip of the best chain that are checked
+    /// when the on-disk state is opened.
+    ///
+    /// If a crash or power loss left one of these blocks partly written, the
+    /// state is rolled back to the highest block below it. Set to 0 to skip
+    /// the check.
+    pub startup_check_depth: u32,
+
+    /// Should the on-disk state recover automatically if it is corrupt when
+    /// it is opened?
+    ///
+    /// If blocks can't be read when the state is opened, the whole best chain
+    /// is checked, and the state is rolled back to the highest block below
+    /// the first corrupt block. If false, Zebra exits with a diagnostic
+    /// instead, so the state can be inspected with `zebrad verify-state`.
+    pub recover_corruption: bool,
+
+    /// The storage engine for the on-disk state.
+    ///
+    /// `"sled"` and `"rocksdb"` store the state in `cache_dir`, in separate
+    /// subdirectories. `"memory"` keeps the state in memory, so it is lost when
+    /// Zebra exits.
+    pub storage_backend: StorageBackendKind,
+
+    /// How often the storage engine syncs committed blocks to disk.
+    ///
+    /// `"full"` syncs each block before its commit finishes. `"batched"`
+    /// syncs in the background, so a crash or power failure can lose the most
+    /// recent blocks. `"async"` only syncs when the state is closed, which
+    /// speeds up the initial sync on spinning disks, but a crash can lose
+    /// many blocks. Lost blocks are downloaded again.
+    pub durability: Durability,
+
+    /// The maximum size of the block bodies kept by the in-memory state, in
+    /// bytes.
+    ///
+    /// When the in-memory state is over this size, the bodies of the least
+    /// recently used finalized blocks are discarded, like pruned blocks. Set
+    /// to 0 to keep every block body.
+    ///
+    /// Only used by `in_memory::init_with_config`.
+    pub in_memory_block_bytes: u64,
+
+    /// The directory for backups of a running node's state.
+    ///
+    /// `zebrad start` backs up the state to a timestamped subdirectory when it
+    /// receives `SIGUSR2`, without stopping block commits. If `None`, backups
+    /// are written to a "backups" subdirectory of `cache_dir`.
+    pub backup_dir: Option<PathBuf>,
+
+    /// Should block bodies be compressed when they are written to disk?
+    ///
+    /// Bodies are compressed with zstd, which uses a little more CPU, but
+    /// typically saves about 30% of the space used by blocks. Changing this
+    /// option only affects new blocks: existing blocks are read either way.
+    pub compress_block_bodies: bool,
+
+    /// The number of background threads that RocksDB uses for compactions
+    /// and flushes.
+    ///
+    /// Fewer threads make compaction less aggressive, so it competes less
+    /// with block commits, but it can fall behind during a fast sync. Only
+    /// used by the RocksDB backend.
+    pub compaction_threads: u32,
+
+    /// Should automatic compaction be paused while the state is far behind
+    /// the estimated network chain tip?
+    ///
+    /// Compactions during the initial sync can stall block commits for
+    /// several seconds. If this is enabled, compaction is paused until the
+    /// state is close to the network tip, and reads are slower until the
+    /// compaction catches up. Send a `CompactNow` request to compact
+    /// immediately. Only used by the RocksDB backend, because sled compacts
+    /// its log as it writes.
+    pub pause_compaction_during_sync: bool,
+
+    /// Log a warning when the free disk space in `cache_dir` is below this
+    /// many bytes.
+    pub disk_space_warning_bytes: u64,
+
+    /// The minimum free disk space in `cache_dir` for committing blocks, in
+    /// bytes.
+    ///
+    /// Storage backends can corrupt the state if the disk fills up during a
+    /// write. So below this limit, the state stops committing blocks, and
+    /// returns an `OutOfDiskSpace` error, until space is freed up. If there
+    /// isn't enough space when Zebra starts, it exits with a diagnostic.
+    pub min_free_disk_bytes: u64,
+
+    /// Should the on-disk state be opened read-only?
+    ///
+    /// Read-only states reject every request that modifies the state with a
+    /// `ReadOnlyState` error, and they don't take the state directory lock.
+    /// Used by tools that inspect a node's state, see
+    /// `on_disk::init_read_only`.
+    pub read_only: bool,
+
+    /// The directory for the files of a secondary state, which follows a
+    /// state that another process is writing.
+    ///
+    /// Only used by read-only RocksDB states, see `on_disk::init_secondary`.
+    /// If `None`, read-only states only see the blocks that were committed
+    /// when they were opened.
+    pub secondary_dir: Option<PathBuf>,
+
+    /// Should the on-disk state be stored in a new temporary directory, and
+    /// deleted when zebrad shuts down?
+    ///
+    /// Ephemeral states ignore `cache_dir`, and store the archive in the
+    /// same temporary directory. Used by integration tests and short-lived
+    /// sessions, which don't need a persistent cache directory.
+    pub ephemeral: bool,
+
+    /// The maximum size of the block commits that are combined into one
+    /// storage write, in bytes.
+    ///
+    /// Writing each block separately limits the speed of the initial sync.
+    /// Combined writes are much faster, but a crash can lose the blocks that
+    /// haven't been written yet, so they are downloaded again. Set to 0 to
+    /// write each block immediately.
+    ///
+    /// Ignored when `durability` is `"full"`, which writes each block
+    /// immediately.
+    pub write_batch_bytes: u64,
+
+    // Note: due to the way this is rendered by the toml
+    // serializer, the Duration fields should come last.
+    /// The maximum time that a block commit waits to be written, if
+    /// `write_batch_bytes` is not 0.
+    pub write_batch_interval: Duration,
 }
 
+/// The archive database gets `1 / ARCHIVE_MEMORY_DIVISOR` of the storage
+/// memory budget, if it is configured.
+const ARCHIVE_MEMORY_DIVISOR: u64 = 8;
+
 impl Config {
     /// Generate the appropriate `sled::Config` for `network`, based on the
     /// provided `zebra_state::Config`.
@@ -51,20 +295,159 @@ impl Config {
     /// This function should panic if the user of `zebra-state` doesn't configure
     /// a directory to store the state.
     pub(crate) fn sled_config(&self, network: Network) -> sled::Config {
-        let net_dir = match network {
-            Mainnet => "mainnet",
-            Testnet => "testnet",
-        };
-        let path = self
-            .cache_dir
+        let path = self.network_cache_dir(network).join("state");
+
+        // sled buffers writes in its page cache, so the cache is the whole
+        // budget
+        sled::Config::default()
+            .path(path)
+            .cache_capacity(self.state_memory_bytes())
+            .flush_every_ms(self.durability.sled_flush_every_ms())
+    }
+
+    /// Generate the `sled::Config` for the archive tier on `network`, if an
+    /// archive directory is configured.
+    pub(crate) fn archive_sled_config(&self, network: Network) -> Option<sled::Config> {
+        let path = self.network_archive_dir(network)?.join("archive");
+
+        Some(
+            sled::Config::default()
+                .path(path)
+                .cache_capacity(self.archive_memory_bytes())
+                .flush_every_ms(self.durability.sled_flush_every_ms()),
+        )
+    }
+
+    /// Returns the RocksDB database path for `network`.
+    ///
+    /// # Details
+    ///
+    /// This function should panic if the user of `zebra-state` doesn't configure
+    /// a directory to store the state.
+    pub(crate) fn rocksdb_path(&self, network: Network) -> PathBuf {
+        self.network_cache_dir(network).join("rocksdb")
+    }
+
+    /// Returns the RocksDB database path for the archive tier on `network`, if
+    /// an archive directory is configured.
+    pub(crate) fn archive_rocksdb_path(&self, network: Network) -> Option<PathBuf> {
+        Some(self.network_archive_dir(network)?.join("rocksdb-archive"))
+    }
+
+    /// Returns the path for the files of a secondary instance of the RocksDB
+    /// database on `network`, if a secondary directory is configured.
+    pub(crate) fn secondary_rocksdb_path(&self, network: Network) -> Option<PathBuf> {
+        Some(self.network_secondary_dir(network)?.join("rocksdb"))
+    }
+
+    /// Returns the path for the files of a secondary instance of the RocksDB
+    /// archive database on `network`, if a secondary directory is
+    /// configured.
+    pub(crate) fn secondary_archive_rocksdb_path(&self, network: Network) -> Option<PathBuf> {
+        Some(self.network_secondary_dir(network)?.join("rocksdb-archive"))
+    }
+
+    /// Returns the path of the state database for `network`, or `None` if the
+    /// storage backend doesn't store the state in `cache_dir`.
+    pub(crate) fn storage_path(&self, network: Network) -> Option<PathBuf> {
+        match self.storage_backend {
+            StorageBackendKind::Sled => Some(self.network_cache_dir(network).join("state")),
+            StorageBackendKind::RocksDb => Some(self.rocksdb_path(network)),
+            StorageBackendKind::Memory => None,
+        }
+    }
+
+    /// Returns the path of the lock file for the on-disk state for `network`,
+    /// or `None` if the state isn't stored on disk.
+    pub(crate) fn lock_path(&self, network: Network) -> Option<PathBuf> {
+        match self.storage_backend {
+            StorageBackendKind::Sled | StorageBackendKind::RocksDb => {
+                Some(self.network_cache_dir(network).join("zebrad.lock"))
+            }
+            StorageBackendKind::Memory => None,
+        }
+    }
+
+    /// Returns the path of the archive database for `network`, if an archive
+    /// directory is configured, and the storage backend supports archives.
+    pub(crate) fn archive_storage_path(&self, network: Network) -> Option<PathBuf> {
+        match self.storage_backend {
+            StorageBackendKind::Sled => Some(self.network_archive_dir(network)?.join("archive")),
+            StorageBackendKind::RocksDb => self.archive_rocksdb_path(network),
+            StorageBackendKind::Memory => None,
+        }
+    }
+
+    /// Returns the path of the table of transactions submitted to the RPC
+    /// endpoint on `network`, or `None` if `cache_dir` isn't set.
+    pub fn local_submissions_path(&self, network: Network) -> Option<PathBuf> {
+        Some(
+            self.cache_dir
+                .as_ref()?
+                .join(net_dir(network))
+                .join("local_submissions.json"),
+        )
+    }
+
+    /// Returns the share of `memory_cache_bytes` used by the state database.
+    pub(crate) fn state_memory_bytes(&self) -> u64 {
+        if self.archive_dir.is_some() {
+            self.memory_cache_bytes - self.archive_memory_bytes()
+        } else {
+            self.memory_cache_bytes
+        }
+    }
+
+    /// Returns the share of `memory_cache_bytes` used by the archive database.
+    ///
+    /// Archived blocks are rarely read, so the archive gets a small share.
+    pub(crate) fn archive_memory_bytes(&self) -> u64 {
+        self.memory_cache_bytes / ARCHIVE_MEMORY_DIVISOR
+    }
+
+    /// Returns the minimum depth of pruned blocks, or `None` if pruning is
+    /// disabled.
+    ///
+    /// Blocks that can still be rolled back are never pruned, because
+    /// rollbacks read the removed blocks.
+    pub fn min_pruned_depth(&self) -> Option<u32> {
+        if self.prune {
+            Some(self.prune_depth.max(MAX_BLOCK_REORG_HEIGHT + 1))
+        } else {
+            None
+        }
+    }
+
+    /// Returns `network`'s subdirectory of `cache_dir`.
+    ///
+    /// # Panics
+    ///
+    /// If `cache_dir` isn't set. [`Config::validate`] reports this error
+    /// without panicking.
+    fn network_cache_dir(&self, network: Network) -> PathBuf {
+        self.cache_dir
             .as_ref()
-            .unwrap_or_else(|| {
-                todo!("create a nice user facing error explaining how to set the cache directory in zebrad.toml:\n[state]\ncache_dir = '/path/to/cache-or-tmp'")
-            })
-            .join(net_dir)
-            .join("state");
+            .unwrap_or_else(|| panic!("{}", InvalidConfig::MissingCacheDir))
+            .join(net_dir(network))
+    }
 
-        sled::Config::default().path(path)
+    /// Returns `network`'s subdirectory of `archive_dir`, if it is configured.
+    fn network_archive_dir(&self, network: Network) -> Option<PathBuf> {
+        Some(self.archive_dir.as_ref()?.join(net_dir(network)))
+    }
+
+    /// Returns `network`'s subdirectory of `secondary_dir`, if it is
+    /// configured.
+    fn network_secondary_dir(&self, network: Network) -> Option<PathBuf> {
+        Some(self.secondary_dir.as_ref()?.join(net_dir(network)))
+    }
+}
+
+/// Returns the directory name for `network`'s data.
+fn net_dir(network: Network) -> &'static str {
+    match network {
+        Mainnet => "mainnet",
+        Testnet => "testnet",
     }
 }
 
@@ -75,68 +458,709 @@ impl Default for Config {
             .ok()
             .or_else(|| dirs::cache_dir().map(|dir| dir.join("zebra")));
 
-        Self { cache_dir }
+        Self {
+            cache_dir,
+            // The sled default
+            memory_cache_bytes: 1024 * 1024 * 1024,
+            block_cache_bytes: 64 * 1024 * 1024,
+            address_index: false,
+            archive_dir: None,
+            archive_depth: 10_000,
+            prune: false,
+            prune_depth: 1_000,
+            startup_check_depth: 10,
+            recover_corruption: true,
+            storage_backend: StorageBackendKind::Sled,
+            durability: Durability::Batched,
+            in_memory_block_bytes: 0,
+            backup_dir: None,
+            compress_block_bodies: false,
+            // The RocksDB default
+            compaction_threads: 2,
+            pause_compaction_during_sync: false,
+            disk_space_warning_bytes: 10 * 1024 * 1024 * 1024,
+            min_free_disk_bytes: 1024 * 1024 * 1024,
+            read_only: false,
+            secondary_dir: None,
+            ephemeral: false,
+            write_batch_bytes: 0,
+            write_batch_interval: Duration::from_secs(1),
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 /// A state request, used to manipulate the zebra-state on disk or in memory
 pub enum Request {
-    // TODO(jlusby): deprecate in the future based on our validation story
-    /// Add a block to the zebra-state
-    AddBlock {
-        /// The block to be added to the state
-        block: Arc<Block>,
+    /// Commit a semantically verified block to the non-finalized part of the
+    /// zebra-state, after checking it against its parent block
+    ///
+    /// If the parent block is not in the state yet, the block is queued, and
+    /// the response is returned after the parent is committed. So blocks can
+    /// be sent in any order. The block is stored using its serialized bytes,
+    /// without serializing it again.
+    ///
+    /// Genesis blocks have an all-zeroes parent hash, so they are committed
+    /// immediately, as long as the state doesn't already have a genesis
+    /// block.
+    ///
+    /// Blocks can fork from any non-finalized block. If the block's chain has
+    /// more work than the best chain, it becomes the best chain.
+    CommitBlock {
+        /// The verified block to be committed to the state
+        block: SemanticallyVerifiedBlock,
+    },
+    /// Commit a block that has been finalized by a checkpoint directly to
+    /// disk, skipping contextual checks
+    ///
+    /// Only use this request for blocks at or below a verified checkpoint.
+    /// Their parents might not be committed yet, because checkpoint
+    /// verification can complete out of order.
+    CommitFinalizedBlock {
+        /// The finalized block to be committed to the state
+        block: SemanticallyVerifiedBlock,
+    },
+    /// Add a contiguous run of blocks to the zebra-state, in a single
+    /// transaction
+    ///
+    /// Either all of the blocks are added, or none of them are.
+    AddBlockBatch {
+        /// The blocks to be added to the state, in height order
+        blocks: Vec<Arc<Block>>,
+    },
+    /// Remove all blocks above `height` from the zebra-state
+    ///
+    /// Either all of the blocks are removed from every index, or none of
+    /// them are. Finalized blocks can't be removed, so `height` must be at
+    /// or above the finalized height.
+    ///
+    /// Side chain blocks above `height` are also removed. If a remaining side
+    /// chain has more work than the best chain, it becomes the best chain.
+    RollbackToHeight {
+        /// The height of the new tip
+        height: BlockHeight,
+    },
+    /// Mark a block as invalid, and remove it and all its descendants from
+    /// the zebra-state
+    ///
+    /// The block and its descendants are rejected if they are added again,
+    /// until the block is reconsidered. If a remaining side chain has more
+    /// work than the best chain, it becomes the best chain.
+    InvalidateBlock {
+        /// The hash of the block to invalidate
+        hash: BlockHeaderHash,
+    },
+    /// Remove the invalid marking from a block, and from the descendants that
+    /// were invalid because of it
+    ///
+    /// Removed blocks are not restored, they must be added again.
+    ReconsiderBlock {
+        /// The hash of the block to reconsider
+        hash: BlockHeaderHash,
+    },
+    /// Record that a block failed verification, because it broke `rule`
+    ///
+    /// The block is rejected without being verified again, until the
+    /// verifier version changes. Records from other verifier versions are
+    /// removed.
+    ///
+    /// The state keeps at most `MAX_FAILED_BLOCKS` records, later failures
+    /// are not recorded.
+    RecordFailedBlock {
+        /// The hash of the block that failed verification
+        hash: BlockHeaderHash,
+        /// The version of the verifier that rejected the block
+        verifier_version: u32,
+        /// The consensus rule that the block broke
+        rule: String,
+    },
+    /// Get the consensus rule broken by a block that failed verification
+    ///
+    /// Failures recorded by other verifier versions are ignored.
+    FailedBlock {
+        /// The hash of the block
+        hash: BlockHeaderHash,
+        /// The version of the current verifier
+        verifier_version: u32,
     },
     /// Get a block from the zebra-state
     GetBlock {
         /// The hash used to identify the block
         hash: BlockHeaderHash,
     },
+    /// Get the serialized bytes of a block from the zebra-state
+    ///
+    /// The bytes are returned exactly as they were committed, without
+    /// deserializing the block.
+    GetRawBlock {
+        /// The hash used to identify the block
+        hash: BlockHeaderHash,
+    },
+    /// Get the hash of the block at `height` in the current best chain
+    BestChainBlockHash {
+        /// The height of the block
+        height: BlockHeight,
+    },
+    /// Get the hash of the ancestor at `height` of the block with `hash`
+    ///
+    /// The block does not need to be in the current best chain, the ancestor
+    /// is on the same chain as the block.
+    AncestorHash {
+        /// The hash of the descendant block
+        hash: BlockHeaderHash,
+        /// The height of the ancestor
+        height: BlockHeight,
+    },
     /// Get a block locator list for the current best chain
     GetBlockLocator {
         /// The genesis block of the current best chain
         genesis: BlockHeaderHash,
     },
+    /// Find the fork point between a peer's chain and the current best chain,
+    /// and get the best chain hashes after it
+    ///
+    /// The fork point is the first block in `known_blocks` that is in the
+    /// best chain, or the genesis block if none of them are.
+    FindBlockHashes {
+        /// Hashes of blocks known to the peer, from highest to lowest,
+        /// usually a block locator
+        known_blocks: Vec<BlockHeaderHash>,
+        /// Stop after this hash, if it is in the best chain after the fork
+        /// point
+        stop: Option<BlockHeaderHash>,
+    },
+    /// Find the fork point between a peer's chain and the current best chain,
+    /// and get the best chain headers after it
+    ///
+    /// Uses the same fork point as `FindBlockHashes`. If `known_blocks` is
+    /// empty, only gets the header of the `stop` block.
+    FindBlockHeaders {
+        /// Hashes of blocks known to the peer, from highest to lowest,
+        /// usually a block locator
+        known_blocks: Vec<BlockHeaderHash>,
+        /// Stop after this hash, if it is in the best chain after the fork
+        /// point
+        stop: Option<BlockHeaderHash>,
+    },
+    /// Get the metadata for a block in the zebra-state
+    BlockInfo {
+        /// The hash used to identify the block
+        hash: BlockHeaderHash,
+    },
+    /// Get the metadata for each transaction in a block in the zebra-state
+    ///
+    /// Fees are calculated from the transparent outputs in the state, so
+    /// pruned states can't calculate some of the fees.
+    BlockTransactionInfo {
+        /// The hash used to identify the block
+        hash: BlockHeaderHash,
+    },
+    /// Calculate the fee paid by a transaction that isn't in a block
+    ///
+    /// Fees are calculated from the transparent outputs in the state, so the
+    /// outputs spent by the transaction must be in the state.
+    TransactionFee {
+        /// The transaction
+        transaction: Arc<Transaction>,
+    },
+    /// Count the transactions in a window of best chain blocks
+    ChainTxStats {
+        /// The hash of the last block in the window, which must be in the
+        /// best chain
+        hash: BlockHeaderHash,
+        /// The number of blocks in the window
+        ///
+        /// If `None`, uses `DEFAULT_CHAIN_TX_STATS_WINDOW`, or every block
+        /// after the genesis block, whichever is smaller.
+        window: Option<u32>,
+    },
     /// Get the block that is the tip of the current chain
+    ///
+    /// Returns `Response::Empty` if the state doesn't have any blocks.
     GetTip,
+    /// Get all the chain tips known to the zebra-state, including side chains
+    GetChainTips,
     /// Ask the state if the given hash is part of the current best chain
     GetDepth {
         /// The hash to check against the current chain
         hash: BlockHeaderHash,
     },
+    /// Get the number of blocks in the zebra-state, and the estimated sync
+    /// progress of the current best chain
+    BlockCount,
+    /// Get the chain value pool balances at the tip of the current best chain
+    GetValueBalances,
+    /// Get the note commitment trees after the block at `height` in the
+    /// current best chain
+    NoteCommitmentTrees {
+        /// The height of the block
+        height: BlockHeight,
+    },
+    /// Get the complete Sapling note commitment subtrees in the current best
+    /// chain, in index order
+    SaplingSubtrees {
+        /// The index of the first subtree to return
+        start_index: u16,
+        /// The maximum number of subtrees to return
+        limit: usize,
+    },
+    /// Get the number of note commitments and nullifiers in each shielded
+    /// pool after a block in the current best chain
+    ShieldedCounts {
+        /// The height of the block
+        ///
+        /// If `None`, uses the tip of the current best chain.
+        height: Option<BlockHeight>,
+    },
+    /// Get the ZIP-221 history tree after the block at `height` in the
+    /// current best chain
+    HistoryTree {
+        /// The height of the block
+        height: BlockHeight,
+    },
+    /// Get the combined balance of a set of transparent addresses
+    ///
+    /// Fails if the address index is disabled.
+    AddressBalance {
+        /// The addresses to look up
+        addresses: Vec<TransparentAddress>,
+    },
+    /// Get the unspent transparent outputs paying to a set of addresses
+    ///
+    /// Fails if the address index is disabled.
+    UtxosByAddresses {
+        /// The addresses to look up
+        addresses: Vec<TransparentAddress>,
+        /// Only return outputs created at or above this height
+        start: BlockHeight,
+        /// The maximum number of outputs to return
+        limit: usize,
+    },
+    /// Get the IDs of the best chain transactions that pay to or spend from a
+    /// set of transparent addresses
+    ///
+    /// Fails if the address index is disabled.
+    TransactionIdsByAddresses {
+        /// The addresses to look up
+        addresses: Vec<TransparentAddress>,
+        /// Only return transactions in blocks in this range of heights
+        height_range: RangeInclusive<BlockHeight>,
+    },
+    /// Get the merkle path that proves a best chain transaction is in its
+    /// block, and the header of that block
+    TransactionMerklePath {
+        /// The ID of the transaction
+        txid: TransactionHash,
+    },
+    /// Get the best chain heights that aren't covered by an optional or
+    /// prunable index
+    ///
+    /// Lookups in these heights return empty results, even if the data is
+    /// in the chain.
+    MissingIndexRanges {
+        /// The index to check
+        index: OptionalIndex,
+    },
+    /// Compact the whole on-disk state now
+    ///
+    /// Operators can compact the state at a quiet time, so compactions don't
+    /// stall block commits later. The response is returned after the
+    /// compaction finishes, which can take several minutes. Storage backends
+    /// that don't need compaction return immediately.
+    CompactNow,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 /// A state response
 pub enum Response {
-    /// The response to a `AddBlock` request indicating a block was successfully
-    /// added to the state
-    Added {
-        /// The hash of the block that was added
+    /// The response to a `CommitBlock` or `CommitFinalizedBlock` request
+    /// indicating a block was successfully committed to the state
+    Committed {
+        /// The hash of the block that was committed
         hash: BlockHeaderHash,
     },
+    /// The response to a `CommitBlock` or `CommitFinalizedBlock` request
+    /// indicating the block was already in the state, so it was not written
+    /// again
+    AlreadyCommitted {
+        /// The hash of the block that was already committed
+        hash: BlockHeaderHash,
+    },
+    /// The response to a `AddBlockBatch` request indicating all the blocks
+    /// were successfully added to the state
+    AddedBatch {
+        /// The hashes of the blocks that were added, in height order
+        hashes: Vec<BlockHeaderHash>,
+    },
+    /// The response to a `RollbackToHeight` request
+    RolledBack {
+        /// The hashes of the removed blocks, in height order
+        removed: Vec<BlockHeaderHash>,
+    },
+    /// The response to a `InvalidateBlock` request
+    Invalidated {
+        /// The hashes of the removed blocks, in height order
+        removed: Vec<BlockHeaderHash>,
+    },
+    /// The response to a `ReconsiderBlock` request
+    Reconsidered,
+    /// The response to a `RecordFailedBlock` request
+    FailedBlockRecorded,
+    /// The response to a `FailedBlock` request
+    FailedBlock(
+        /// The consensus rule that the block broke, or `None` if the block
+        /// hasn't failed verification with the current verifier version
+        Option<String>,
+    ),
     /// The response to a `GetBlock` request by hash
     Block {
         /// The block that was requested
         block: Arc<Block>,
     },
+    /// The response to a `GetRawBlock` request
+    RawBlock {
+        /// The serialized block
+        bytes: Vec<u8>,
+    },
+    /// The response to a `BlockInfo` request
+    BlockInfo(
+        /// The metadata for the requested block
+        BlockInfo,
+    ),
+    /// The response to a `BlockTransactionInfo` request
+    BlockTransactionInfo(
+        /// The metadata for each transaction in the block, in block order
+        Vec<TransactionInfo>,
+    ),
+    /// The response to a `TransactionFee` request
+    TransactionFee(
+        /// The fee paid by the transaction, or `None` if it is a coinbase
+        /// transaction, or it spends transparent outputs that are not in the
+        /// state
+        Option<Amount<NonNegative>>,
+    ),
+    /// The response to a `ChainTxStats` request
+    ChainTxStats(
+        /// The transactions in the requested window
+        ChainTxStats,
+    ),
+    /// The response to a `BestChainBlockHash` or `AncestorHash` request
+    BlockHash(
+        /// The hash of the requested block, or `None` if it is not in the
+        /// state
+        Option<BlockHeaderHash>,
+    ),
     /// The response to a `GetBlockLocator` request
     BlockLocator {
         /// The set of blocks that make up the block locator
         block_locator: Vec<BlockHeaderHash>,
     },
+    /// The response to a `FindBlockHashes` request
+    BlockHashes {
+        /// The height of the fork point
+        fork_height: BlockHeight,
+        /// The hash of the fork point
+        fork_hash: BlockHeaderHash,
+        /// The best chain hashes after the fork point, in height order
+        ///
+        /// Ends at the `stop` hash, the best chain tip, or after
+        /// `MAX_FIND_BLOCK_HASHES_RESULTS` hashes, whichever comes first.
+        hashes: Vec<BlockHeaderHash>,
+    },
+    /// The response to a `FindBlockHeaders` request
+    BlockHeaders(
+        /// The best chain headers after the fork point, in height order
+        ///
+        /// Ends at the `stop` hash, the best chain tip, or after
+        /// `MAX_FIND_BLOCK_HEADERS_RESULTS` headers, whichever comes first.
+        Vec<BlockHeader>,
+    ),
     /// The response to a `GetTip` request
     Tip {
         /// The hash of the block at the tip of the current chain
         hash: BlockHeaderHash,
     },
+    /// The response to a `GetTip` request, if the state doesn't have any
+    /// blocks yet
+    ///
+    /// An empty state accepts the genesis block, which is the block with an
+    /// all-zeroes previous block hash.
+    Empty,
+    /// The response to a `GetChainTips` request
+    ChainTips(
+        /// The known chain tips, starting with the tip of the best chain
+        Vec<ChainTip>,
+    ),
     /// The response to a `Contains` request indicating that the given has is in
     /// the current best chain
     Depth(
         /// The number of blocks above the given block in the current best chain
         Option<u32>,
     ),
+    /// The response to a `BlockCount` request
+    BlockCount {
+        /// The number of blocks committed to the state
+        count: u32,
+        /// The estimated sync progress, or `None` if the state is empty
+        progress: Option<SyncProgress>,
+    },
+    /// The response to a `GetValueBalances` request
+    ValueBalances(
+        /// The value pool balances at the tip, or `None` if the state is empty,
+        /// or the best chain is not connected to the genesis block
+        Option<ValueBalances>,
+    ),
+    /// The response to a `NoteCommitmentTrees` request
+    NoteCommitmentTrees(
+        /// The note commitment trees after the requested block, or `None` if
+        /// the block is not in the best chain, or the best chain is not
+        /// connected to the genesis block
+        Option<NoteCommitmentTrees>,
+    ),
+    /// The response to a `SaplingSubtrees` request
+    SaplingSubtrees(
+        /// The subtrees, in index order, or an empty list if the best chain is
+        /// not connected to the genesis block
+        Vec<NoteCommitmentSubtree>,
+    ),
+    /// The response to a `ShieldedCounts` request
+    ShieldedCounts(
+        /// The height of the requested block, and the counts after it, or
+        /// `None` if the block is not in the best chain, or the best chain is
+        /// not connected to the genesis block
+        Option<(BlockHeight, ShieldedCounts)>,
+    ),
+    /// The response to a `MissingIndexRanges` request
+    MissingIndexRanges(
+        /// The ranges of best chain heights that aren't covered by the index,
+        /// in height order
+        Vec<RangeInclusive<BlockHeight>>,
+    ),
+    /// The response to a `HistoryTree` request
+    HistoryTree(
+        /// The history tree after the requested block, or `None` if the block
+        /// is before Heartwood activation, the block is not in the best chain,
+        /// or the best chain is not connected to the genesis block
+        Option<HistoryTree>,
+    ),
+    /// The response to a `AddressBalance` request
+    AddressBalance(
+        /// The combined balance of the requested addresses
+        AddressBalance,
+    ),
+    /// The response to a `UtxosByAddresses` request
+    Utxos(
+        /// The unspent outputs, ordered by height
+        Vec<AddressUtxo>,
+    ),
+    /// The response to a `TransactionIdsByAddresses` request
+    TransactionIds(
+        /// The transaction IDs, in chain order
+        Vec<TransactionHash>,
+    ),
+    /// The response to a `TransactionMerklePath` request
+    TransactionMerklePath(
+        /// The header of the block containing the transaction, and the merkle
+        /// path from the transaction to the header's merkle root, or `None`
+        /// if the transaction is not in the best chain
+        Option<(BlockHeader, MerklePath)>,
+    ),
+    /// The response to a `CompactNow` request
+    Compacted,
+}
+
+/// A chain tip known to the state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    /// The hash of the tip block
+    pub hash: BlockHeaderHash,
+    /// The height of the tip block
+    pub height: BlockHeight,
+    /// The number of blocks between the tip and the best chain
+    ///
+    /// Zero for the tip of the best chain.
+    pub branch_len: u32,
+    /// The status of the chain ending at this tip
+    pub status: ChainTipStatus,
+}
+
+/// The status of a chain tip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChainTipStatus {
+    /// The tip of the best chain
+    Active,
+    /// The tip of a valid side chain
+    ValidFork,
+    /// The tip of a side chain that contains an invalid block
+    Invalid,
+}
+
+/// The target spacing between blocks, in seconds.
+///
+/// Used to estimate the height of the network's chain tip. This is the
+/// post-Blossom spacing, so progress estimates for pre-Blossom tips are
+/// conservative.
+const ESTIMATED_BLOCK_SPACING_SECS: i64 = 75;
+
+/// An estimate of how much of the network's chain has been verified.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SyncProgress {
+    /// The height of the local chain tip
+    pub tip_height: BlockHeight,
+    /// The estimated height of the network chain tip, based on the local tip's
+    /// block time and the current time
+    pub estimated_height: BlockHeight,
+}
+
+impl SyncProgress {
+    /// Estimate the sync progress for the tip at `tip_height`, with header
+    /// `tip_header`, at the wall clock time `now`.
+    pub(crate) fn estimate(
+        tip_height: BlockHeight,
+        tip_header: &BlockHeader,
+        now: SystemTime,
+    ) -> Self {
+        let now = match now.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(_) => 0,
+        };
+        let elapsed = now.saturating_sub(tip_header.time.timestamp()).max(0);
+        let remaining = elapsed / ESTIMATED_BLOCK_SPACING_SECS;
+        let estimated_height = (tip_height.0 as i64)
+            .saturating_add(remaining)
+            .min(BlockHeight::MAX.0 as i64);
+
+        SyncProgress {
+            tip_height,
+            estimated_height: BlockHeight(estimated_height as u32),
+        }
+    }
+
+    /// Returns the fraction of the estimated chain that has been verified,
+    /// between `0.0` and `1.0`.
+    pub fn fraction(&self) -> f64 {
+        let verified = self.tip_height.0 as f64 + 1.0;
+        let estimated = self.estimated_height.0 as f64 + 1.0;
+
+        (verified / estimated).min(1.0)
+    }
+}
+
+/// The maximum number of hashes returned by a `FindBlockHashes` request.
+///
+/// This is the same as the `getblocks` limit in `zcashd`.
+pub const MAX_FIND_BLOCK_HASHES_RESULTS: u32 = 500;
+
+/// The maximum number of headers returned by a `FindBlockHeaders` request.
+///
+/// This is the same as the `getheaders` limit in `zcashd`.
+pub const MAX_FIND_BLOCK_HEADERS_RESULTS: u32 = 160;
+
+/// Find the fork point between `known_blocks` and the best chain, and the
+/// best chain hashes after it.
+///
+/// `best_chain_height` returns the height of a block, if it is in the best
+/// chain. `best_chain_hash` returns the hash of the best chain block at a
+/// height, if there is one.
+pub(crate) fn find_block_hashes(
+    known_blocks: &[BlockHeaderHash],
+    stop: Option<BlockHeaderHash>,
+    best_chain_height: impl FnMut(BlockHeaderHash) -> Result<Option<BlockHeight>, Error>,
+    best_chain_hash: impl FnMut(BlockHeight) -> Result<Option<BlockHeaderHash>, Error>,
+) -> Result<Response, Error> {
+    let (fork_height, fork_hash, hashes) = find_fork_point(
+        known_blocks,
+        stop,
+        MAX_FIND_BLOCK_HASHES_RESULTS,
+        best_chain_height,
+        best_chain_hash,
+    )?;
+
+    Ok(Response::BlockHashes {
+        fork_height,
+        fork_hash,
+        hashes,
+    })
+}
+
+/// Find the fork point between `known_blocks` and the best chain, and the
+/// best chain headers after it.
+///
+/// `header` returns the header of a block, if it is in the state.
+pub(crate) fn find_block_headers(
+    known_blocks: &[BlockHeaderHash],
+    stop: Option<BlockHeaderHash>,
+    best_chain_height: impl FnMut(BlockHeaderHash) -> Result<Option<BlockHeight>, Error>,
+    best_chain_hash: impl FnMut(BlockHeight) -> Result<Option<BlockHeaderHash>, Error>,
+    mut header: impl FnMut(BlockHeaderHash) -> Result<Option<BlockHeader>, Error>,
+) -> Result<Response, Error> {
+    // An empty locator asks for a single header, like in zcashd
+    if known_blocks.is_empty() {
+        let headers = match stop {
+            Some(stop) => header(stop)?.into_iter().collect(),
+            None => Vec::new(),
+        };
+        return Ok(Response::BlockHeaders(headers));
+    }
+
+    let (_, _, hashes) = find_fork_point(
+        known_blocks,
+        stop,
+        MAX_FIND_BLOCK_HEADERS_RESULTS,
+        best_chain_height,
+        best_chain_hash,
+    )?;
+
+    let mut headers = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        headers.push(header(hash)?.ok_or("best chain block is missing from the state")?);
+    }
+
+    Ok(Response::BlockHeaders(headers))
+}
+
+/// Returns the height and hash of the fork point between `known_blocks` and
+/// the best chain, and up to `limit` best chain hashes after it.
+fn find_fork_point(
+    known_blocks: &[BlockHeaderHash],
+    stop: Option<BlockHeaderHash>,
+    limit: u32,
+    mut best_chain_height: impl FnMut(BlockHeaderHash) -> Result<Option<BlockHeight>, Error>,
+    mut best_chain_hash: impl FnMut(BlockHeight) -> Result<Option<BlockHeaderHash>, Error>,
+) -> Result<(BlockHeight, BlockHeaderHash, Vec<BlockHeaderHash>), Error> {
+    let mut fork_point = None;
+    for &hash in known_blocks {
+        if let Some(height) = best_chain_height(hash)? {
+            fork_point = Some((height, hash));
+            break;
+        }
+    }
+
+    // Peers on a completely different chain start from our genesis block
+    let (fork_height, fork_hash) = match fork_point {
+        Some(fork_point) => fork_point,
+        None => {
+            let genesis = best_chain_hash(BlockHeight(0))?
+                .ok_or("the best chain does not contain a genesis block")?;
+            (BlockHeight(0), genesis)
+        }
+    };
+
+    let mut hashes = Vec::new();
+    if Some(fork_hash) != stop {
+        for height in fork_height.0 + 1..=fork_height.0 + limit {
+            let hash = match best_chain_hash(BlockHeight(height))? {
+                Some(hash) => hash,
+                None => break,
+            };
+            hashes.push(hash);
+            if Some(hash) == stop {
+                break;
+            }
+        }
+    }
+
+    Ok((fork_height, fork_hash, hashes))
 }
 
 /// Get the heights of the blocks for constructing a block_locator list
@@ -147,14 +1171,152 @@ fn block_locator_heights(tip_height: BlockHeight) -> impl Iterator<Item = BlockH
         .chain(iter::once(BlockHeight(0)))
 }
 
+/// Check that `blocks` is a contiguous run of blocks, in height order.
+///
+/// Returns the height and hash of each block, or an error if any block is
+/// missing its coinbase height, or doesn't follow the previous block.
+pub(crate) fn check_contiguous(
+    blocks: &[Arc<Block>],
+) -> Result<Vec<(BlockHeight, BlockHeaderHash)>, Error> {
+    let mut checked: Vec<(BlockHeight, BlockHeaderHash)> = Vec::with_capacity(blocks.len());
+
+    for block in blocks {
+        let height = block
+            .coinbase_height()
+            .ok_or("block in batch has no coinbase height")?;
+        let hash = block.hash();
+
+        if let Some(&(prev_height, prev_hash)) = checked.last() {
+            if Some(height) != prev_height.0.checked_add(1).map(BlockHeight) {
+                Err("block heights in batch are not contiguous")?;
+            }
+            if block.header.previous_block_hash != prev_hash {
+                Err("block in batch does not follow the previous block")?;
+            }
+        }
+
+        checked.push((height, hash));
+    }
+
+    Ok(checked)
+}
+
+/// The maximum number of best chain blocks that a chain reorganisation can
+/// replace.
+///
+/// Blocks that are this far below the best chain tip are finalized, so they
+/// can never be rolled back. This is the same as `MAX_REORG_LENGTH` in
+/// `zcashd`.
+pub const MAX_BLOCK_REORG_HEIGHT: u32 = 99;
+
+/// The maximum number of blocks recorded by `RecordFailedBlock` requests.
+///
+/// The limit stops peers from filling the disk with invalid blocks.
+pub const MAX_FAILED_BLOCKS: usize = 10_000;
+
+/// Returns the height of the highest finalized block, when the best chain tip
+/// is at `tip_height`.
+///
+/// Returns `None` if none of the blocks are finalized yet.
+pub fn finalized_height(tip_height: BlockHeight) -> Option<BlockHeight> {
+    tip_height
+        .0
+        .checked_sub(MAX_BLOCK_REORG_HEIGHT)
+        .map(BlockHeight)
+}
+
+/// Check that `height` is above the finalized blocks, when the best chain tip
+/// is at `tip_height`.
+///
+/// Committing a block at or below the finalized height would need a chain
+/// reorganisation that is deeper than `MAX_BLOCK_REORG_HEIGHT`.
+pub(crate) fn check_finality(
+    height: BlockHeight,
+    tip_height: Option<BlockHeight>,
+) -> Result<(), Error> {
+    check_above_finalized(height, tip_height.and_then(finalized_height))
+}
+
+/// Check that `height` is above `finalized_height`, the height of the highest
+/// finalized block.
+pub(crate) fn check_above_finalized(
+    height: BlockHeight,
+    finalized_height: Option<BlockHeight>,
+) -> Result<(), Error> {
+    match finalized_height {
+        Some(finalized_height) if height <= finalized_height => {
+            Err("block is at or below the finalized height, so it would need a chain reorganisation deeper than MAX_BLOCK_REORG_HEIGHT")?
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The previous block hash of genesis blocks.
+///
+/// Genesis blocks don't have a parent block, so all known networks use the
+/// Bitcoin `null` value, which is all zeroes.
+const GENESIS_PREVIOUS_BLOCK_HASH: BlockHeaderHash = BlockHeaderHash([0; 32]);
+
+/// Is `block` a genesis block?
+pub(crate) fn is_genesis(block: &SemanticallyVerifiedBlock) -> bool {
+    block.block().header.previous_block_hash == GENESIS_PREVIOUS_BLOCK_HASH
+}
+
+/// Check that the genesis `block` can be committed, when the genesis block
+/// in the state is `genesis`.
+///
+/// Genesis blocks can only be committed to a state that doesn't have one.
+pub(crate) fn check_genesis(
+    block: &SemanticallyVerifiedBlock,
+    genesis: Option<BlockHeaderHash>,
+) -> Result<(), Error> {
+    if block.height() != BlockHeight(0) {
+        Err("genesis block does not have height 0")?;
+    }
+    if genesis.is_some() {
+        Err("the state already has a different genesis block")?;
+    }
+
+    Ok(())
+}
+
+/// Check that `block` can be committed on top of its parent block, which is
+/// at `parent_height`.
+///
+/// `parent_height` is `None` if the parent block is not in the state. Genesis
+/// blocks don't have a parent, so they are only checked against `genesis`,
+/// the genesis block in the state.
+///
+/// `tip_height` is the height of the best chain tip, which is used to reject
+/// blocks that would need a reorganisation of finalized blocks.
+pub(crate) fn check_contextual(
+    block: &SemanticallyVerifiedBlock,
+    parent_height: Option<BlockHeight>,
+    genesis: Option<BlockHeaderHash>,
+    tip_height: Option<BlockHeight>,
+) -> Result<(), Error> {
+    check_finality(block.height(), tip_height)?;
+
+    if is_genesis(block) {
+        return check_genesis(block, genesis);
+    }
+
+    let parent_height = parent_height.ok_or("parent block is not in the state")?;
+    if Some(block.height()) != parent_height.0.checked_add(1).map(BlockHeight) {
+        Err("block height does not follow its parent block")?;
+    }
+
+    Ok(())
+}
+
 /// The error type for the State Service.
 // TODO(jlusby): Error = Report ?
 type Error = Box<dyn error::Error + Send + Sync + 'static>;
 
 /// Get the tip block, using `state`.
 ///
-/// If there is no tip, returns `Ok(None)`.
-/// Returns an error if `state.poll_ready` errors.
+/// If the state is empty, returns `Ok(None)`.
+/// Returns an error if the state service errors.
 pub async fn initial_tip<S>(state: S) -> Result<Option<Arc<Block>>, Report>
 where
     S: Service<Request, Response = Response, Error = Error> + Send + Clone + 'static,
@@ -167,11 +1329,13 @@ where
         .map_err(|e| eyre!(e))?
         .call(Request::GetTip)
         .await
-        .map(|response| match response {
-            Response::Tip { hash } => hash,
-            _ => unreachable!("GetTip request can only result in Response::Tip"),
-        })
-        .ok();
+        .map_err(|e| eyre!(e))?;
+
+    let initial_tip_hash = match initial_tip_hash {
+        Response::Tip { hash } => Some(hash),
+        Response::Empty => None,
+        _ => unreachable!("GetTip request can only result in Response::Tip or Response::Empty"),
+    };
 
     let initial_tip_block = match initial_tip_hash {
         Some(hash) => state
@@ -196,7 +1360,8 @@ where
 mod tests {
     use super::*;
 
-    use std::ffi::OsStr;
+    use std::{ffi::OsStr, time::Duration};
+    use zebra_chain::serialization::ZcashDeserialize;
 
     #[test]
     fn test_path_mainnet() {
@@ -208,6 +1373,24 @@ mod tests {
         test_path(Testnet);
     }
 
+    #[test]
+    fn archive_shares_memory_budget() {
+        zebra_test::init();
+
+        let config = Config::default();
+        assert_eq!(config.state_memory_bytes(), config.memory_cache_bytes);
+
+        let config = Config {
+            archive_dir: Some(PathBuf::from("archive")),
+            ..Config::default()
+        };
+        assert!(config.archive_memory_bytes() > 0);
+        assert_eq!(
+            config.state_memory_bytes() + config.archive_memory_bytes(),
+            config.memory_cache_bytes
+        );
+    }
+
     /// Check the sled path for `network`.
     fn test_path(network: Network) {
         zebra_test::init();
@@ -225,6 +1408,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn sync_progress_estimate() {
+        zebra_test::init();
+
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let tip_time = UNIX_EPOCH + Duration::from_secs(block.header.time.timestamp() as u64);
+
+        let progress = SyncProgress::estimate(BlockHeight(1), &block.header, tip_time);
+        assert_eq!(progress.tip_height, BlockHeight(1));
+        assert_eq!(progress.estimated_height, BlockHeight(1));
+        assert!((progress.fraction() - 1.0).abs() < f64::EPSILON);
+
+        // A wall clock before the tip time doesn't reduce the estimate
+        let progress = SyncProgress::estimate(BlockHeight(1), &block.header, UNIX_EPOCH);
+        assert_eq!(progress.estimated_height, BlockHeight(1));
+
+        let later = tip_time + Duration::from_secs(ESTIMATED_BLOCK_SPACING_SECS as u64 * 2);
+        let progress = SyncProgress::estimate(BlockHeight(1), &block.header, later);
+        assert_eq!(progress.estimated_height, BlockHeight(3));
+        assert!((progress.fraction() - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn finalized_height_follows_tip() {
+        zebra_test::init();
+
+        assert_eq!(finalized_height(BlockHeight(0)), None);
+        assert_eq!(
+            finalized_height(BlockHeight(MAX_BLOCK_REORG_HEIGHT - 1)),
+            None
+        );
+        assert_eq!(
+            finalized_height(BlockHeight(MAX_BLOCK_REORG_HEIGHT)),
+            Some(BlockHeight(0))
+        );
+        assert_eq!(
+            finalized_height(BlockHeight(MAX_BLOCK_REORG_HEIGHT + 10)),
+            Some(BlockHeight(10))
+        );
+    }
+
+    #[test]
+    fn deep_reorgs_are_rejected() {
+        zebra_test::init();
+
+        let block =
+            SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap();
+        let parent_height = Some(BlockHeight(0));
+
+        // A fork that replaces MAX_BLOCK_REORG_HEIGHT blocks is accepted
+        let tip_height = Some(BlockHeight(MAX_BLOCK_REORG_HEIGHT));
+        assert!(check_contextual(&block, parent_height, None, tip_height).is_ok());
+
+        // But a deeper fork is rejected
+        let tip_height = Some(BlockHeight(MAX_BLOCK_REORG_HEIGHT + 1));
+        assert!(check_contextual(&block, parent_height, None, tip_height).is_err());
+    }
+
     /// Check what happens when the config is invalid.
     #[test]
     #[should_panic]
@@ -234,7 +1479,10 @@ mod tests {
         //  - implement test log levels in #760
         //  - call `zebra_test::init`
         //  - disable all log output from this test
-        let bad_config = Config { cache_dir: None };
+        let bad_config = Config {
+            cache_dir: None,
+            ..Config::default()
+        };
         let _unreachable = bad_config.sled_config(Mainnet);
     }
 }
@@ -0,0 +1,569 @@
+//! Blocks that can still be replaced by a chain reorganisation.
+//!
+//! The non-finalized state keeps the most recent blocks of every candidate
+//! chain, and selects the chain with the most work as the best chain. The
+//! rest of the state stores the best chain, and switches to a side chain when
+//! it gets more work.
+//!
+//! Once the best chain has more than `MAX_BLOCK_REORG_HEIGHT` non-finalized
+//! blocks, its lowest block is finalized, and side chains that fork below it
+//! are dropped.
+use std::collections::HashSet;
+
+use zebra_chain::{block::BlockHeaderHash, types::BlockHeight};
+
+use crate::{ChainTip, ChainTipStatus, SemanticallyVerifiedBlock, MAX_BLOCK_REORG_HEIGHT};
+
+/// A chain of non-finalized blocks, which starts just above the finalized
+/// tip.
+#[derive(Clone, Debug, Default)]
+struct Chain {
+    /// The blocks in this chain, in height order
+    blocks: Vec<SemanticallyVerifiedBlock>,
+    /// The total work of the blocks in this chain
+    work: u128,
+}
+
+impl Chain {
+    /// Add `block` to the tip of this chain.
+    ///
+    /// Returns an error if the block's difficulty threshold is invalid.
+    fn push(&mut self, block: SemanticallyVerifiedBlock) -> Result<(), Error> {
+        let work = block_work(block.block().header.bits)?;
+
+        self.work = self.work.saturating_add(work);
+        self.blocks.push(block);
+
+        Ok(())
+    }
+
+    /// Returns the tip block of this chain, if it has any blocks.
+    fn tip(&self) -> Option<&SemanticallyVerifiedBlock> {
+        self.blocks.last()
+    }
+
+    /// Returns the index of the block with `hash` in this chain.
+    fn position(&self, hash: &BlockHeaderHash) -> Option<usize> {
+        self.blocks.iter().position(|block| block.hash() == *hash)
+    }
+
+    /// Returns a copy of this chain, which ends at the block with `hash`.
+    fn fork(&self, hash: &BlockHeaderHash) -> Option<Chain> {
+        let position = self.position(hash)?;
+        let mut fork = Chain {
+            blocks: self.blocks[..=position].to_vec(),
+            work: 0,
+        };
+        fork.update_work();
+
+        Some(fork)
+    }
+
+    /// Remove the blocks at and above `index`, and return them.
+    fn split_off(&mut self, index: usize) -> Vec<SemanticallyVerifiedBlock> {
+        let removed = self.blocks.split_off(index.min(self.blocks.len()));
+        self.update_work();
+
+        removed
+    }
+
+    /// Remove the blocks at or below `height`.
+    fn remove_to(&mut self, height: BlockHeight) {
+        self.blocks.retain(|block| block.height() > height);
+        self.update_work();
+    }
+
+    /// Recalculate the total work, after removing blocks.
+    fn update_work(&mut self) {
+        self.work = self
+            .blocks
+            .iter()
+            .map(|block| {
+                block_work(block.block().header.bits)
+                    .expect("difficulty thresholds are checked when blocks are added to a chain")
+            })
+            .fold(0, u128::saturating_add);
+    }
+}
+
+/// Every candidate chain above the finalized tip.
+#[derive(Debug, Default)]
+pub(crate) struct NonFinalizedState {
+    /// The candidate chains, in the order their tips were committed
+    ///
+    /// If several chains have the most work, the first one is the best chain.
+    chains: Vec<Chain>,
+    /// The height and hash of the highest finalized block, if any blocks are
+    /// finalized
+    finalized_tip: Option<(BlockHeight, BlockHeaderHash)>,
+}
+
+impl NonFinalizedState {
+    /// Build a non-finalized state from the stored best chain, which has its
+    /// tip at `tip_height`.
+    ///
+    /// `stored_block` returns the block at a height in the stored best chain.
+    /// Side chains aren't stored, so they are not restored.
+    pub(crate) fn load(
+        tip_height: Option<BlockHeight>,
+        mut stored_block: impl FnMut(BlockHeight) -> Result<Option<SemanticallyVerifiedBlock>, Error>,
+    ) -> Result<Self, Error> {
+        let mut state = Self::default();
+        let tip_height = match tip_height {
+            Some(tip_height) => tip_height,
+            None => return Ok(state),
+        };
+
+        let finalized_height = crate::finalized_height(tip_height);
+        if let Some(finalized_height) = finalized_height {
+            let finalized_tip = stored_block(finalized_height)?
+                .ok_or("the finalized tip block is missing from the best chain")?;
+            state.finalized_tip = Some((finalized_height, finalized_tip.hash()));
+        }
+
+        let mut chain = Chain::default();
+        let first_height = finalized_height.map_or(0, |height| height.0 + 1);
+        for height in first_height..=tip_height.0 {
+            if let Some(block) = stored_block(BlockHeight(height))? {
+                chain.push(block)?;
+            }
+        }
+        if !chain.blocks.is_empty() {
+            state.chains.push(chain);
+        }
+
+        state.update_metrics();
+        Ok(state)
+    }
+
+    /// Returns the height of the highest finalized block, if any blocks are
+    /// finalized.
+    pub(crate) fn finalized_height(&self) -> Option<BlockHeight> {
+        self.finalized_tip.map(|(height, _hash)| height)
+    }
+
+    /// Is the block with `hash` in any of the chains?
+    pub(crate) fn contains(&self, hash: &BlockHeaderHash) -> bool {
+        self.get(hash).is_some()
+    }
+
+    /// Returns the block with `hash`, if it is in any of the chains.
+    pub(crate) fn get(&self, hash: &BlockHeaderHash) -> Option<&SemanticallyVerifiedBlock> {
+        self.chains
+            .iter()
+            .flat_map(|chain| chain.blocks.iter())
+            .find(|block| block.hash() == *hash)
+    }
+
+    /// Returns the height of the block with `hash`, if it is in any of the
+    /// chains, or it is the finalized tip.
+    pub(crate) fn height(&self, hash: BlockHeaderHash) -> Option<BlockHeight> {
+        match self.finalized_tip {
+            Some((height, finalized_hash)) if finalized_hash == hash => Some(height),
+            _ => self.get(&hash).map(|block| block.height()),
+        }
+    }
+
+    /// Add `block` to the chain that ends at its parent, or start a new chain
+    /// that forks at its parent.
+    ///
+    /// The parent block must be in one of the chains, or it must be the
+    /// finalized tip. If none of the blocks are finalized, genesis blocks
+    /// start a new chain.
+    ///
+    /// Doesn't check that the block follows its parent, so callers must check
+    /// it first.
+    pub(crate) fn commit(&mut self, block: SemanticallyVerifiedBlock) -> Result<(), Error> {
+        let parent = block.block().header.previous_block_hash;
+
+        if self.contains(&block.hash()) {
+            Err("block is already in the non-finalized state")?;
+        }
+        // Check the difficulty threshold before changing any chains
+        block_work(block.block().header.bits)?;
+
+        let extended = self
+            .chains
+            .iter()
+            .position(|chain| chain.tip().map(|tip| tip.hash()) == Some(parent));
+        let mut chain = match extended {
+            Some(index) => self.chains.remove(index),
+            None => match self.chains.iter().find_map(|chain| chain.fork(&parent)) {
+                Some(fork) => fork,
+                None if self.is_finalized_tip(&block) => Chain::default(),
+                None => Err("parent block is not in the non-finalized state, so the block would need a chain reorganisation of finalized blocks")?,
+            },
+        };
+
+        chain.push(block)?;
+        self.chains.push(chain);
+
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Is `block` the child of the finalized tip?
+    ///
+    /// If none of the blocks are finalized, genesis blocks are the children
+    /// of the finalized tip.
+    fn is_finalized_tip(&self, block: &SemanticallyVerifiedBlock) -> bool {
+        match self.finalized_tip {
+            Some((_height, hash)) => block.block().header.previous_block_hash == hash,
+            None => crate::is_genesis(block),
+        }
+    }
+
+    /// Returns the best chain, which is the chain with the most work.
+    fn best_chain(&self) -> Option<&Chain> {
+        self.chains.iter().fold(None, |best, chain| match best {
+            Some(best) if best.work >= chain.work => Some(best),
+            _ => Some(chain),
+        })
+    }
+
+    /// Returns the first height where the best chain differs from the stored
+    /// best chain, and the best chain blocks at and above that height.
+    ///
+    /// `stored_hash` returns the hash of the block at a height in the stored
+    /// best chain. Stored blocks at and above the returned height must be
+    /// replaced by the returned blocks.
+    pub(crate) fn best_chain_diff(
+        &self,
+        mut stored_hash: impl FnMut(BlockHeight) -> Result<Option<BlockHeaderHash>, Error>,
+    ) -> Result<(BlockHeight, Vec<SemanticallyVerifiedBlock>), Error> {
+        let blocks = self
+            .best_chain()
+            .map(|chain| &chain.blocks[..])
+            .unwrap_or_default();
+
+        for (index, block) in blocks.iter().enumerate() {
+            if stored_hash(block.height())? != Some(block.hash()) {
+                return Ok((block.height(), blocks[index..].to_vec()));
+            }
+        }
+
+        let next_height = match blocks.last() {
+            Some(tip) => tip.height().0 + 1,
+            None => self.finalized_height().map_or(0, |height| height.0 + 1),
+        };
+        Ok((BlockHeight(next_height), Vec::new()))
+    }
+
+    /// Finalize the lowest blocks in the best chain, until it has at most
+    /// `MAX_BLOCK_REORG_HEIGHT` non-finalized blocks.
+    pub(crate) fn finalize(&mut self) {
+        loop {
+            let root = match self.best_chain() {
+                Some(chain) if chain.blocks.len() > MAX_BLOCK_REORG_HEIGHT as usize => {
+                    (chain.blocks[0].height(), chain.blocks[0].hash())
+                }
+                _ => return,
+            };
+            self.finalize_to(root.0, root.1);
+        }
+    }
+
+    /// Make the block at `height` with `hash` the finalized tip.
+    ///
+    /// Removes the blocks at or below `height`, and drops the chains that
+    /// don't descend from the new finalized tip. Does nothing if `height` is
+    /// at or below the current finalized tip.
+    pub(crate) fn finalize_to(&mut self, height: BlockHeight, hash: BlockHeaderHash) {
+        if self.finalized_height() >= Some(height) {
+            return;
+        }
+        self.finalized_tip = Some((height, hash));
+
+        for chain in &mut self.chains {
+            chain.remove_to(height);
+        }
+        self.chains.retain(|chain| {
+            chain
+                .blocks
+                .first()
+                .map(|root| root.block().header.previous_block_hash == hash)
+                .unwrap_or(false)
+        });
+
+        self.update_metrics();
+    }
+
+    /// Remove the blocks at and above `first_removed` from every chain.
+    pub(crate) fn remove_from(&mut self, first_removed: BlockHeight) {
+        for chain in &mut self.chains {
+            let index = chain
+                .blocks
+                .iter()
+                .position(|block| block.height() >= first_removed)
+                .unwrap_or_else(|| chain.blocks.len());
+            chain.split_off(index);
+        }
+
+        self.drop_duplicate_chains();
+    }
+
+    /// Remove the block with `hash` and all its descendants from every chain.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    pub(crate) fn remove(&mut self, hash: BlockHeaderHash) -> Vec<BlockHeaderHash> {
+        let mut removed = Vec::new();
+        for chain in &mut self.chains {
+            if let Some(index) = chain.position(&hash) {
+                removed.extend(
+                    chain
+                        .split_off(index)
+                        .into_iter()
+                        .map(|block| (block.height(), block.hash())),
+                );
+            }
+        }
+        // Chains can share blocks, so the same block can be removed from
+        // several chains
+        let mut seen = HashSet::new();
+        removed.retain(|(_height, hash)| seen.insert(*hash));
+        removed.sort_by_key(|(height, _hash)| *height);
+
+        self.drop_duplicate_chains();
+        removed.into_iter().map(|(_height, hash)| hash).collect()
+    }
+
+    /// Drop empty chains, and chains that end at the same tip as an earlier
+    /// chain.
+    fn drop_duplicate_chains(&mut self) {
+        let mut tips = HashSet::new();
+        self.chains.retain(|chain| match chain.tip() {
+            Some(tip) => tips.insert(tip.hash()),
+            None => false,
+        });
+
+        self.update_metrics();
+    }
+
+    /// Returns the tips of the side chains.
+    ///
+    /// The tip of the best chain is reported by the stored best chain.
+    pub(crate) fn fork_tips(&self) -> Vec<ChainTip> {
+        let best_chain = match self.best_chain() {
+            Some(best_chain) => best_chain,
+            None => return Vec::new(),
+        };
+
+        self.chains
+            .iter()
+            .filter(|chain| !std::ptr::eq(*chain, best_chain))
+            .filter_map(|chain| {
+                let tip = chain.tip()?;
+                let branch_len = chain
+                    .blocks
+                    .iter()
+                    .filter(|block| best_chain.position(&block.hash()).is_none())
+                    .count();
+
+                Some(ChainTip {
+                    hash: tip.hash(),
+                    height: tip.height(),
+                    branch_len: branch_len as u32,
+                    status: ChainTipStatus::ValidFork,
+                })
+            })
+            .collect()
+    }
+
+    fn update_metrics(&self) {
+        metrics::gauge!("state.non_finalized.chain.count", self.chains.len() as i64);
+    }
+}
+
+/// Returns the work represented by the compact difficulty threshold `bits`.
+///
+/// The work is the expected number of hashes needed to find a block hash at or
+/// below the threshold, which is `2^256 / (threshold + 1)`, rounded down.
+///
+/// Returns an error if `bits` doesn't encode a positive threshold that fits in
+/// 256 bits. Saturates at `u128::MAX` for thresholds below `2^128`, which are
+/// far too difficult to be met.
+pub(crate) fn block_work(bits: u32) -> Result<u128, Error> {
+    let exponent = (bits >> 24) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    if mantissa == 0 || bits & 0x0080_0000 != 0 {
+        Err("block difficulty threshold is zero or negative")?;
+    }
+
+    // The threshold is `mantissa * 2^shift`
+    let shift = 8 * (exponent - 3);
+    let mantissa_bits = 32 - mantissa.leading_zeros() as i32;
+    if mantissa_bits + shift > 256 {
+        Err("block difficulty threshold is larger than 256 bits")?;
+    }
+    if shift < 0 && (mantissa >> -shift) == 0 {
+        Err("block difficulty threshold is zero or negative")?;
+    }
+
+    let work_bits = 256 - shift;
+    if work_bits > 128 {
+        return Ok(u128::MAX);
+    }
+
+    // For thresholds of at least `2^128`, dividing `2^256 - 1` by the
+    // threshold rounds down to the same value as the exact work calculation
+    let numerator = u128::MAX >> (128 - work_bits);
+    Ok(numerator / u128::from(mantissa))
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize};
+
+    fn block(bytes: &[u8]) -> SemanticallyVerifiedBlock {
+        let block = Block::zcash_deserialize(bytes).unwrap();
+        SemanticallyVerifiedBlock::new(block.into()).unwrap()
+    }
+
+    /// Returns a copy of `block` with a different hash, which follows
+    /// `parent`.
+    fn fork(
+        block: &SemanticallyVerifiedBlock,
+        parent: BlockHeaderHash,
+    ) -> SemanticallyVerifiedBlock {
+        let mut block = block.block().as_ref().clone();
+        block.header.previous_block_hash = parent;
+        block.header.nonce[0] ^= 0xff;
+        SemanticallyVerifiedBlock::new(block.into()).unwrap()
+    }
+
+    /// Returns the hash of the best chain tip.
+    fn best_tip(state: &NonFinalizedState) -> Option<BlockHeaderHash> {
+        let (_, blocks) = state.best_chain_diff(|_| Ok(None)).unwrap();
+        blocks.last().map(|block| block.hash())
+    }
+
+    /// Returns the mainnet blocks from genesis to block 3.
+    fn mainnet_blocks() -> Vec<SemanticallyVerifiedBlock> {
+        vec![
+            block(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..]),
+            block(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..]),
+            block(&zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..]),
+            block(&zebra_test::vectors::BLOCK_MAINNET_3_BYTES[..]),
+        ]
+    }
+
+    #[test]
+    fn block_work_from_compact_bits() {
+        zebra_test::init();
+
+        // The Zcash mainnet minimum difficulty
+        assert_eq!(block_work(0x1f07_ffff).unwrap(), 8192);
+        // The Bitcoin genesis block
+        assert_eq!(block_work(0x1d00_ffff).unwrap(), 0x0001_0001_0001);
+        // A threshold that is an exact power of two
+        assert_eq!(block_work(0x1d01_0000).unwrap(), 0xffff_ffff);
+
+        assert!(block_work(0x1d00_0000).is_err());
+        assert!(block_work(0x1d80_ffff).is_err());
+        assert!(block_work(0x2200_ffff).is_err());
+        assert_eq!(block_work(0x0300_ffff).unwrap(), u128::MAX);
+    }
+
+    #[test]
+    fn best_chain_has_the_most_work() {
+        zebra_test::init();
+
+        let blocks = mainnet_blocks();
+        let mut state = NonFinalizedState::default();
+        for block in &blocks[..3] {
+            state.commit(block.clone()).unwrap();
+        }
+
+        let fork1 = fork(&blocks[1], blocks[0].hash());
+        let fork2 = fork(&blocks[2], fork1.hash());
+        state.commit(fork1.clone()).unwrap();
+        state.commit(fork2.clone()).unwrap();
+
+        // The chains have the same work, so the first chain stays the best
+        assert_eq!(best_tip(&state), Some(blocks[2].hash()));
+        assert_eq!(
+            state.fork_tips(),
+            vec![ChainTip {
+                hash: fork2.hash(),
+                height: BlockHeight(2),
+                branch_len: 2,
+                status: ChainTipStatus::ValidFork,
+            }]
+        );
+        assert_eq!(state.height(fork1.hash()), Some(BlockHeight(1)));
+
+        // The side chain gets more work, so it becomes the best chain
+        let fork3 = fork(&blocks[3], fork2.hash());
+        state.commit(fork3.clone()).unwrap();
+        assert_eq!(best_tip(&state), Some(fork3.hash()));
+
+        let (first_changed, new_blocks) = state
+            .best_chain_diff(|height| Ok(blocks.get(height.0 as usize).map(|b| b.hash())))
+            .unwrap();
+        assert_eq!(first_changed, BlockHeight(1));
+        assert_eq!(new_blocks, vec![fork1, fork2, fork3]);
+
+        assert!(state.commit(blocks[2].clone()).is_err());
+    }
+
+    #[test]
+    fn finalizing_drops_side_chains() {
+        zebra_test::init();
+
+        let blocks = mainnet_blocks();
+        let mut state = NonFinalizedState::default();
+        for block in &blocks[..3] {
+            state.commit(block.clone()).unwrap();
+        }
+        let fork1 = fork(&blocks[1], blocks[0].hash());
+        state.commit(fork1.clone()).unwrap();
+
+        state.finalize_to(BlockHeight(1), blocks[1].hash());
+
+        assert_eq!(state.finalized_height(), Some(BlockHeight(1)));
+        assert_eq!(state.height(blocks[1].hash()), Some(BlockHeight(1)));
+        assert!(!state.contains(&blocks[1].hash()));
+        assert!(!state.contains(&fork1.hash()));
+        assert!(state.contains(&blocks[2].hash()));
+        assert!(state.fork_tips().is_empty());
+
+        // Blocks can fork at the finalized tip, but not below it
+        let fork2 = fork(&blocks[2], blocks[1].hash());
+        state.commit(fork2).unwrap();
+        assert_eq!(state.fork_tips().len(), 1);
+        assert!(state.commit(fork1).is_err());
+
+        // Lower blocks can't be finalized again
+        state.finalize_to(BlockHeight(0), blocks[0].hash());
+        assert_eq!(state.finalized_height(), Some(BlockHeight(1)));
+    }
+
+    #[test]
+    fn removed_blocks_leave_every_chain() {
+        zebra_test::init();
+
+        let blocks = mainnet_blocks();
+        let mut state = NonFinalizedState::default();
+        for block in &blocks[..3] {
+            state.commit(block.clone()).unwrap();
+        }
+        let fork1 = fork(&blocks[1], blocks[0].hash());
+        let fork2 = fork(&blocks[2], fork1.hash());
+        state.commit(fork1.clone()).unwrap();
+        state.commit(fork2.clone()).unwrap();
+
+        let removed = state.remove(blocks[1].hash());
+        assert_eq!(removed, vec![blocks[1].hash(), blocks[2].hash()]);
+        assert_eq!(best_tip(&state), Some(fork2.hash()));
+
+        state.remove_from(BlockHeight(1));
+        assert_eq!(best_tip(&state), Some(blocks[0].hash()));
+        assert!(state.fork_tips().is_empty());
+        assert!(!state.contains(&fork1.hash()));
+    }
+}
@@ -0,0 +1,240 @@
+//! Note commitment trees for each shielded pool.
+//!
+//! The state updates the note commitment trees after every block, and stores
+//! the tree frontiers for each best chain height, so historical anchors and
+//! treestates can be served without replaying the chain.
+//!
+//! The state also indexes the roots of complete Sapling subtrees, which each
+//! contain 2^16 note commitments, when they are completed by a block. Light
+//! wallet servers use the subtree roots to sync wallets in parallel.
+//!
+//! Like value pool balances, trees are only tracked for chains that are
+//! connected to the genesis block. Zebra doesn't support Orchard yet, so
+//! there is no Orchard tree.
+use std::{convert::TryFrom, io::Cursor};
+use zebra_chain::{
+    block::Block,
+    note_commitment_tree::{SaplingNoteCommitmentTree, SproutNoteCommitmentTree},
+    proofs::ZkSnarkProof,
+    serialization::{ZcashDeserialize, ZcashSerialize},
+    transaction::{JoinSplitData, Transaction},
+    types::BlockHeight,
+};
+
+/// The level of the Sapling note commitment subtree roots.
+pub(crate) const SAPLING_SUBTREE_LEVEL: usize = 16;
+
+/// The size of a serialized `NoteCommitmentSubtree`, excluding its index.
+const SUBTREE_SIZE: usize = 36;
+
+/// The note commitment trees after a block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NoteCommitmentTrees {
+    /// The Sprout note commitment tree
+    pub sprout: SproutNoteCommitmentTree,
+    /// The Sapling note commitment tree
+    pub sapling: SaplingNoteCommitmentTree,
+}
+
+impl NoteCommitmentTrees {
+    /// Returns the trees after appending the note commitments in `block` to
+    /// `self`.
+    ///
+    /// Fails if a tree is full.
+    pub(crate) fn add_block(&self, block: &Block) -> Result<Self, Error> {
+        let height = block
+            .coinbase_height()
+            .ok_or("block has no coinbase height")?;
+        self.add_block_with_subtrees(block, height)
+            .map(|(trees, _)| trees)
+    }
+
+    /// Returns the trees after appending the note commitments in `block` at
+    /// `height` to `self`, and the Sapling subtrees that `block` completes.
+    ///
+    /// Fails if a tree is full.
+    pub(crate) fn add_block_with_subtrees(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+    ) -> Result<(Self, Vec<NoteCommitmentSubtree>), Error> {
+        let mut trees = self.clone();
+        let mut subtrees = Vec::new();
+
+        for tx in &block.transactions {
+            for commitment in joinsplit_commitments(tx) {
+                trees.sprout.append(commitment)?;
+            }
+
+            if let Transaction::V4 {
+                shielded_data: Some(shielded_data),
+                ..
+            } = tx.as_ref()
+            {
+                for output in shielded_data.outputs() {
+                    let subtree_root = trees
+                        .sapling
+                        .append_with_subtree(output.cmu, SAPLING_SUBTREE_LEVEL)?;
+                    if let Some(root) = subtree_root {
+                        let index = (trees.sapling.size() >> SAPLING_SUBTREE_LEVEL) - 1;
+                        subtrees.push(NoteCommitmentSubtree {
+                            index: u16::try_from(index)?,
+                            root,
+                            end_height: height,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok((trees, subtrees))
+    }
+
+    /// Serialize these trees for storage.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.sprout
+            .zcash_serialize(&mut bytes)
+            .expect("writing to a Vec never fails");
+        self.sapling
+            .zcash_serialize(&mut bytes)
+            .expect("writing to a Vec never fails");
+        bytes
+    }
+
+    /// Deserialize trees from storage.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Cursor::new(bytes);
+        let trees = NoteCommitmentTrees {
+            sprout: SproutNoteCommitmentTree::zcash_deserialize(&mut reader)?,
+            sapling: SaplingNoteCommitmentTree::zcash_deserialize(&mut reader)?,
+        };
+
+        if reader.position() != bytes.len() as u64 {
+            Err("stored note commitment trees have trailing bytes")?;
+        }
+
+        Ok(trees)
+    }
+}
+
+/// A complete Sapling note commitment subtree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NoteCommitmentSubtree {
+    /// The index of the subtree in the Sapling note commitment tree
+    pub index: u16,
+    /// The root of the subtree
+    pub root: [u8; 32],
+    /// The height of the block that completed the subtree
+    pub end_height: BlockHeight,
+}
+
+impl NoteCommitmentSubtree {
+    /// Returns the storage key for this subtree.
+    pub(crate) fn key(&self) -> [u8; 2] {
+        self.index.to_be_bytes()
+    }
+
+    /// Serialize this subtree's root and end height for storage.
+    pub(crate) fn to_bytes(&self) -> [u8; SUBTREE_SIZE] {
+        let mut bytes = [0; SUBTREE_SIZE];
+        bytes[..32].copy_from_slice(&self.root);
+        bytes[32..].copy_from_slice(&self.end_height.0.to_be_bytes());
+        bytes
+    }
+
+    /// Deserialize a subtree from its storage `key` and `value`.
+    pub(crate) fn from_bytes(key: &[u8], value: &[u8]) -> Result<Self, Error> {
+        let index = u16::from_be_bytes(<[u8; 2]>::try_from(key)?);
+        let value = <[u8; SUBTREE_SIZE]>::try_from(value)?;
+
+        let mut root = [0; 32];
+        root.copy_from_slice(&value[..32]);
+        let end_height = <[u8; 4]>::try_from(&value[32..])?;
+
+        Ok(Self {
+            index,
+            root,
+            end_height: BlockHeight(u32::from_be_bytes(end_height)),
+        })
+    }
+}
+
+/// Returns the Sprout note commitments in `tx`, in chain order.
+fn joinsplit_commitments(tx: &Transaction) -> Vec<[u8; 32]> {
+    fn commitments<P: ZkSnarkProof>(joinsplit_data: &JoinSplitData<P>) -> Vec<[u8; 32]> {
+        joinsplit_data
+            .joinsplits()
+            .flat_map(|joinsplit| joinsplit.commitments.iter().copied())
+            .collect()
+    }
+
+    match tx {
+        Transaction::V2 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        }
+        | Transaction::V3 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        } => commitments(joinsplit_data),
+        Transaction::V4 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        } => commitments(joinsplit_data),
+        _ => Vec::new(),
+    }
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn note_trees_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..])?.into();
+        let trees = NoteCommitmentTrees::default().add_block(&block)?;
+
+        assert_eq!(NoteCommitmentTrees::from_bytes(&trees.to_bytes())?, trees);
+        assert_eq!(
+            trees.add_block(&block)?.sapling.size(),
+            2 * trees.sapling.size()
+        );
+        assert_ne!(
+            trees.sapling.root(),
+            NoteCommitmentTrees::default().sapling.root()
+        );
+
+        let mut bytes = trees.to_bytes();
+        bytes.push(0);
+        assert!(NoteCommitmentTrees::from_bytes(&bytes).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn subtree_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let subtree = NoteCommitmentSubtree {
+            index: 0x0102,
+            root: [7; 32],
+            end_height: BlockHeight(1_000_000),
+        };
+        let bytes = subtree.to_bytes();
+
+        assert_eq!(
+            NoteCommitmentSubtree::from_bytes(&subtree.key(), &bytes)?,
+            subtree
+        );
+        assert!(NoteCommitmentSubtree::from_bytes(&subtree.key(), &bytes[1..]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! The nullifiers revealed by the best chain.
+//!
+//! The on-disk state indexes each Sprout and Sapling nullifier revealed by a
+//! best chain block, with the height of that block, so a nullifier can be
+//! found without reading blocks. Zebra doesn't support Orchard yet, so there
+//! are no Orchard nullifiers.
+use zebra_chain::{
+    block::Block,
+    proofs::ZkSnarkProof,
+    serialization::ZcashSerialize,
+    transaction::{JoinSplitData, Transaction},
+};
+
+/// The size of a key in the nullifiers tree: the pool tag, then the
+/// nullifier.
+pub(crate) const NULLIFIER_KEY_SIZE: usize = 33;
+
+/// A shielded pool that reveals nullifiers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum NullifierPool {
+    Sprout,
+    Sapling,
+}
+
+impl NullifierPool {
+    /// Returns the tag byte for this pool, in nullifier keys.
+    fn tag(self) -> u8 {
+        match self {
+            NullifierPool::Sprout => 0,
+            NullifierPool::Sapling => 1,
+        }
+    }
+}
+
+/// Returns the key for `nullifier` from `pool`, in the nullifiers tree.
+pub(crate) fn nullifier_key(
+    pool: NullifierPool,
+    nullifier: &impl ZcashSerialize,
+) -> [u8; NULLIFIER_KEY_SIZE] {
+    let mut key = [0; NULLIFIER_KEY_SIZE];
+    key[0] = pool.tag();
+    nullifier
+        .zcash_serialize(&mut key[1..])
+        .expect("nullifiers are 32 bytes long");
+    key
+}
+
+/// Returns the keys of the nullifiers revealed by `block`.
+pub(crate) fn block_nullifiers(block: &Block) -> Vec<[u8; NULLIFIER_KEY_SIZE]> {
+    let mut keys = Vec::new();
+
+    for tx in &block.transactions {
+        match tx.as_ref() {
+            Transaction::V2 {
+                joinsplit_data: Some(joinsplit_data),
+                ..
+            }
+            | Transaction::V3 {
+                joinsplit_data: Some(joinsplit_data),
+                ..
+            } => keys.extend(sprout_nullifiers(joinsplit_data)),
+            Transaction::V4 {
+                joinsplit_data,
+                shielded_data,
+                ..
+            } => {
+                if let Some(joinsplit_data) = joinsplit_data {
+                    keys.extend(sprout_nullifiers(joinsplit_data));
+                }
+                if let Some(shielded_data) = shielded_data {
+                    keys.extend(
+                        shielded_data
+                            .spends()
+                            .map(|spend| nullifier_key(NullifierPool::Sapling, &spend.nullifier)),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    keys
+}
+
+/// Returns the keys of the Sprout nullifiers in `joinsplit_data`.
+fn sprout_nullifiers<P: ZkSnarkProof>(
+    joinsplit_data: &JoinSplitData<P>,
+) -> impl Iterator<Item = [u8; NULLIFIER_KEY_SIZE]> + '_ {
+    joinsplit_data
+        .joinsplits()
+        .flat_map(|joinsplit| joinsplit.nullifiers.iter())
+        .map(|nullifier| nullifier_key(NullifierPool::Sprout, nullifier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shielded_counts::ShieldedCounts;
+    use std::sync::Arc;
+    use zebra_chain::serialization::{Cached, ZcashDeserialize};
+
+    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+    #[test]
+    fn block_nullifiers_match_the_shielded_counts() -> Result<(), Error> {
+        zebra_test::init();
+
+        let mut block = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?;
+        // Blocks without shielded transactions don't reveal any nullifiers
+        assert!(block_nullifiers(&block).is_empty());
+
+        for bytes in &[
+            &zebra_test::vectors::ZIP143_2[..],
+            &zebra_test::vectors::ZIP243_1[..],
+        ] {
+            block
+                .transactions
+                .push(Arc::new(Transaction::zcash_deserialize(*bytes)?));
+        }
+        block.size = Cached::default();
+        let counts = ShieldedCounts::default().add_block(&block);
+
+        let keys = block_nullifiers(&block);
+        let sprout = keys.iter().filter(|key| key[0] == 0).count() as u64;
+        let sapling = keys.iter().filter(|key| key[0] == 1).count() as u64;
+        assert_eq!(sprout, counts.sprout_nullifiers);
+        assert_eq!(sapling, counts.sapling_nullifiers);
+        assert!(sprout > 0 && sapling > 0);
+
+        Ok(())
+    }
+}
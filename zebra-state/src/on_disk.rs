@@ -1,155 +1,2012 @@
-//! The primary implementation of the `zebra_state::Service` built upon sled
-use super::{Request, Response};
-use crate::Config;
-use futures::prelude::*;
-use std::sync::Arc;
+//! The primary implementation of the `zebra_state::Service`, built upon a
+//! key-value storage backend
+//!
+//! Block headers and bodies are stored in separate trees, so header chain
+//! queries never read block bodies.
+//!
+//! The `by_height` and `by_hash` trees index the best chain in both
+//! directions, and are written in the same batch as the block. Locators,
+//! depths, and tip queries use these indexes, so they don't read any blocks.
+use super::{ChainTip, ChainTipStatus, Request, Response, SyncProgress};
+use crate::address_index::{
+    address_key, block_address_changes, tx_location, tx_location_range, utxo_key_height,
+    utxo_range_start, AddressBalance, AddressIndexChanges, AddressKey, AddressTotals, AddressUtxo,
+    IndexedOutput,
+};
+use crate::block_cache::BlockCache;
+use crate::block_info::{transaction_fee, BlockInfo, TransactionInfo};
+use crate::chain_tx_stats::ChainTxStats;
+use crate::history_tree;
+use crate::non_finalized::NonFinalizedState;
+use crate::note_trees::{NoteCommitmentSubtree, NoteCommitmentTrees, SAPLING_SUBTREE_LEVEL};
+use crate::nullifiers::{block_nullifiers, NULLIFIER_KEY_SIZE};
+use crate::queued_blocks::QueuedBlocks;
+use crate::shielded_counts::ShieldedCounts;
+use crate::snapshot::{ChainSnapshot, SnapshotCell, SNAPSHOT_BLOCKS};
+use crate::storage::{self, all_keys, key_range, tree, ReadOnlyState, StorageBackend, WriteBatch};
+use crate::value_pools::{
+    amount_from_bytes, block_outputs, outpoint_key, ValueBalances, OUTPOINT_KEY_SIZE,
+};
+use crate::{layers::LayerConfig, Config, SemanticallyVerifiedBlock};
+use futures::{channel::oneshot, prelude::*};
+use std::sync::{atomic::AtomicBool, Arc, Mutex, MutexGuard, Weak};
 use std::{
+    collections::HashMap,
+    convert::TryFrom,
     error,
     future::Future,
+    io,
+    ops::{Deref, RangeInclusive},
+    path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
+    time::SystemTime,
 };
-use tower::{buffer::Buffer, Service};
+use tempdir::TempDir;
+use tower::{layer::Layer, Service};
 use zebra_chain::serialization::{ZcashDeserialize, ZcashSerialize};
 use zebra_chain::{
-    block::{Block, BlockHeaderHash},
-    types::BlockHeight,
+    addresses::transparent::TransparentAddress,
+    block::{Block, BlockHeader, BlockHeaderHash},
+    history_tree::HistoryTree,
+    merkle_tree::MerklePath,
+    transaction::{OutPoint, TransactionHash},
+    types::{
+        amount::{Amount, NonNegative},
+        BlockHeight,
+    },
     Network,
 };
 
+mod archive;
+mod backup;
+mod compaction;
+mod compression;
+mod disk_space;
+mod ephemeral;
+mod failed_blocks;
+mod history;
+mod identity;
+mod index_coverage;
+mod lock;
+mod prune;
+mod recovery;
+mod secondary;
+mod snapshot_export;
+mod snapshot_import;
+mod startup_check;
+mod verify;
+
+pub use backup::backup_state;
+pub use disk_space::OutOfDiskSpace;
+pub use history::{database_history, OpenRecord};
+pub use identity::NetworkMismatch;
+pub use lock::StateLocked;
+pub use snapshot_export::{
+    export_snapshot, SnapshotManifest, SnapshotTree, SNAPSHOT_FORMAT_VERSION,
+    SNAPSHOT_MANIFEST_FILE,
+};
+pub use snapshot_import::import_snapshot;
+pub use verify::{verify_state, Inconsistency, VerifyReport};
+
+/// The on-disk state.
+///
+/// Clones share the same databases and caches, which are closed when the
+/// last clone is dropped.
 #[derive(Clone)]
 struct SledState {
-    storage: sled::Db,
+    shared: Arc<SharedState>,
+}
+
+/// The databases and caches shared by the clones of a [`SledState`].
+struct SharedState {
+    /// The lock that stops other processes from opening the state, if it is
+    /// stored on disk.
+    _lock: Option<Arc<lock::StateLock>>,
+    /// The storage backend for every tree, selected by the config.
+    storage: Arc<dyn StorageBackend>,
+    /// A snapshot of the recent best chain, used for lock-free tip queries.
+    snapshot: SnapshotCell,
+    /// The network that the state is for.
+    network: Network,
+    /// Is the transparent address index enabled?
+    address_index: bool,
+    /// Are new block bodies compressed?
+    compress_bodies: bool,
+    /// Is automatic compaction paused, if `pause_compaction_during_sync` is
+    /// enabled?
+    compaction_paused: Option<Arc<AtomicBool>>,
+    /// Checks the free space in the state directory, if the state is stored
+    /// on disk.
+    disk_space: Option<Arc<disk_space::DiskSpaceMonitor>>,
+    /// The archive tier for old blocks, if it is configured.
+    archive: Option<archive::Archive>,
+    /// The minimum depth of pruned blocks, if pruning is enabled.
+    prune_depth: Option<u32>,
+    /// Recently committed and recently read blocks.
+    block_cache: Arc<Mutex<BlockCache>>,
+    /// Blocks that are waiting for their parent block to be committed.
+    queued: Arc<Mutex<QueuedBlocks>>,
+    /// The non-finalized blocks of every candidate chain.
+    ///
+    /// The stored best chain always ends with the best non-finalized chain.
+    non_finalized: Arc<Mutex<NonFinalizedState>>,
+    /// The temporary directory that stores an ephemeral state, which is
+    /// deleted when the last clone of the state is dropped.
+    ///
+    /// Declared last, so the databases are dropped before it is deleted.
+    _ephemeral_dir: Option<Arc<TempDir>>,
+}
+
+impl Deref for SledState {
+    type Target = SharedState;
+
+    fn deref(&self) -> &SharedState {
+        &self.shared
+    }
 }
 
 impl SledState {
+    /// Returns a weak reference to the state, which doesn't keep it open.
+    ///
+    /// Used by background threads, so they stop when the state is dropped.
+    fn downgrade(&self) -> Weak<SharedState> {
+        Arc::downgrade(&self.shared)
+    }
+
+    /// Returns the state that `weak` refers to, if it is still open.
+    fn upgrade(weak: &Weak<SharedState>) -> Option<Self> {
+        weak.upgrade().map(|shared| Self { shared })
+    }
+
     pub(crate) fn new(config: &Config, network: Network) -> Self {
-        let config = config.sled_config(network);
+        let (config, ephemeral_dir) = ephemeral::expect_ephemeral_config(config);
+        let config = &config
+            .into_owned()
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let address_index = config.address_index;
+        let compress_bodies = config.compress_block_bodies;
+        let startup_check_depth = config.startup_check_depth;
+        // Read-only states don't write, so they don't need the lock or any
+        // free space
+        let (lock, disk_space) = if config.read_only {
+            (None, None)
+        } else {
+            let lock = lock::expect_locked(config, network);
+            let disk_space = disk_space::DiskSpaceMonitor::new(config, network);
+            disk_space::expect_space_to_open(disk_space.as_ref());
+            (lock, disk_space)
+        };
+        let archive =
+            recovery::expect_opened(archive::Archive::open(config, network), config, network);
+        let prune_depth = config.min_pruned_depth();
+        let storage = recovery::expect_opened(storage::open(config, network), config, network);
+        identity::expect_identity(
+            storage.as_ref(),
+            network,
+            config.storage_path(network),
+            config.read_only,
+        );
+        if !config.read_only {
+            recovery::expect_opened(history::record_open(storage.as_ref()), config, network);
+        }
+        let snapshot =
+            recovery::expect_opened(Self::load_snapshot(storage.as_ref()), config, network);
+        let block_cache = BlockCache::new(config.block_cache_bytes as usize);
+
+        let mut state = Self {
+            shared: Arc::new(SharedState {
+                _lock: lock.map(Arc::new),
+                storage,
+                snapshot: SnapshotCell::new(snapshot),
+                network,
+                address_index,
+                compress_bodies,
+                compaction_paused: compaction::compaction_pause(
+                    config.pause_compaction_during_sync,
+                ),
+                disk_space: disk_space.map(Arc::new),
+                archive,
+                prune_depth,
+                block_cache: Arc::new(Mutex::new(block_cache)),
+                queued: Default::default(),
+                non_finalized: Default::default(),
+                _ephemeral_dir: ephemeral_dir,
+            }),
+        };
+        recovery::expect_opened(
+            state.check_on_open(
+                startup_check_depth,
+                config.recover_corruption && !config.read_only,
+            ),
+            config,
+            network,
+        );
+        if !config.read_only {
+            recovery::expect_opened(state.record_address_index_start(), config, network);
+            recovery::expect_opened(state.record_nullifier_index_start(), config, network);
+        }
+
+        state
+    }
+
+    /// Replace the non-finalized state with the non-finalized blocks in the
+    /// stored best chain.
+    ///
+    /// Side chains aren't stored, so they are dropped.
+    fn reload_non_finalized(&self) -> Result<(), Error> {
+        let non_finalized =
+            NonFinalizedState::load(self.tip_height(), |height| self.get_verified(height))?;
+
+        *self
+            .non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned") = non_finalized;
+        Ok(())
+    }
+
+    /// Build a snapshot from the highest blocks in `storage`.
+    ///
+    /// Only the block hashes are read.
+    fn load_snapshot(storage: &dyn StorageBackend) -> Result<ChainSnapshot, Error> {
+        let mut snapshot = ChainSnapshot::default();
+
+        for entry in storage
+            .iterate(tree::BY_HEIGHT, all_keys())?
+            .rev()
+            .take(SNAPSHOT_BLOCKS)
+        {
+            let (key, hash) = entry?;
+            let height = u32::from_be_bytes(<[u8; 4]>::try_from(&key[..])?);
+            let hash = BlockHeaderHash(<[u8; 32]>::try_from(&hash[..])?);
 
-        Self {
-            storage: config.open().unwrap(),
+            snapshot.insert(BlockHeight(height), hash);
         }
+
+        Ok(snapshot)
     }
 
+    #[cfg(test)]
     pub(super) fn insert(
         &mut self,
         block: impl Into<Arc<Block>>,
     ) -> Result<BlockHeaderHash, Error> {
-        let block = block.into();
-        let hash: BlockHeaderHash = block.as_ref().into();
-        let height = block.coinbase_height().unwrap();
+        self.insert_verified(SemanticallyVerifiedBlock::new(block.into())?)
+    }
+
+    /// Commit `verified` once its parent block is in the state.
+    ///
+    /// If the parent is already committed, commits the block and its queued
+    /// descendants immediately. Otherwise, queues the block until its parent
+    /// is committed.
+    ///
+    /// Returns a receiver for the commit result.
+    fn commit_or_queue(
+        &mut self,
+        verified: SemanticallyVerifiedBlock,
+    ) -> Result<oneshot::Receiver<Result<Response, Error>>, Error> {
+        let hash = verified.hash();
+        let parent = verified.block().header.previous_block_hash;
+        let queued = self.queued.clone();
+        let mut queued = queued.lock().expect("queue mutex should be unpoisoned");
+
+        if queued.contains(&hash) {
+            Err("block is already queued for commit")?;
+        }
 
-        let by_height = self.storage.open_tree(b"by_height")?;
-        let by_hash = self.storage.open_tree(b"by_hash")?;
+        let (is_non_finalized, parent_is_non_finalized, finalized_height) = {
+            let non_finalized = self
+                .non_finalized
+                .lock()
+                .expect("non-finalized state mutex should be unpoisoned");
+            (
+                non_finalized.contains(&hash),
+                non_finalized.contains(&parent),
+                non_finalized.finalized_height(),
+            )
+        };
+
+        let (tx, rx) = oneshot::channel();
+        if is_non_finalized || self.contains(&hash)? {
+            let _ = tx.send(Ok(Response::AlreadyCommitted { hash }));
+            return Ok(rx);
+        }
+        crate::check_above_finalized(verified.height(), finalized_height)?;
+
+        let parent_committed =
+            crate::is_genesis(&verified) || parent_is_non_finalized || self.contains(&parent)?;
+        queued.queue(verified, tx);
+        if parent_committed {
+            self.commit_queued(&mut queued, parent);
+            self.prune_finalized(&mut queued);
+        }
+
+        Ok(rx)
+    }
+
+    /// Returns the height of the best chain tip, if there are any blocks.
+    fn tip_height(&self) -> Option<BlockHeight> {
+        self.snapshot.load().tip().map(|(height, _hash)| height)
+    }
 
-        let mut bytes = Vec::new();
-        block.zcash_serialize(&mut bytes)?;
+    /// Reject the queued blocks at or below the finalized height, because
+    /// they can never be committed.
+    fn prune_finalized(&self, queued: &mut QueuedBlocks) {
+        let finalized_height = self
+            .non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned")
+            .finalized_height();
 
-        // TODO(jlusby): make this transactional
-        by_height.insert(&height.0.to_be_bytes(), bytes.as_slice())?;
-        by_hash.insert(&hash.0, bytes)?;
+        if let Some(finalized_height) = finalized_height {
+            metrics::gauge!("state.finalized.block.height", finalized_height.0 as i64);
+            queued.prune(finalized_height);
+        }
+    }
+
+    /// Commit the finalized block `verified`, then commit any queued blocks
+    /// that were waiting for it.
+    fn commit_finalized_block(
+        &mut self,
+        verified: SemanticallyVerifiedBlock,
+    ) -> Result<Response, Error> {
+        let hash = verified.hash();
+        let height = verified.height();
+
+        if self.contains(&hash)? {
+            return Ok(Response::AlreadyCommitted { hash });
+        }
+        if crate::is_genesis(&verified) {
+            crate::check_genesis(&verified, self.best_chain_hash(BlockHeight(0))?)?;
+        }
+        let mut marks = WriteBatch::default();
+        if let Err(error) = self.check_valid(verified.block(), &mut marks) {
+            self.storage.write_batch(marks)?;
+            return Err(error);
+        }
+        self.insert_verified(verified)?;
+
+        {
+            let non_finalized = self.non_finalized.clone();
+            let mut non_finalized = non_finalized
+                .lock()
+                .expect("non-finalized state mutex should be unpoisoned");
+            non_finalized.finalize_to(height, hash);
+            self.write_best_chain(&mut non_finalized)?;
+        }
+
+        let queued = self.queued.clone();
+        let mut queued = queued.lock().expect("queue mutex should be unpoisoned");
+        queued.prune(height);
+        self.commit_queued(&mut queued, hash);
+
+        Ok(Response::Committed { hash })
+    }
+
+    /// Commit the queued descendants of `parent`.
+    fn commit_queued(&mut self, queued: &mut QueuedBlocks, parent: BlockHeaderHash) {
+        queued.commit_descendants(parent, |block| {
+            self.commit_block(block)
+                .map(|hash| Response::Committed { hash })
+        });
+    }
+
+    /// Commit `verified` to the non-finalized state, after checking that it
+    /// follows its parent block.
+    ///
+    /// If the block's chain becomes the best chain, the block is also stored.
+    fn commit_block(
+        &mut self,
+        verified: SemanticallyVerifiedBlock,
+    ) -> Result<BlockHeaderHash, Error> {
+        let non_finalized = self.non_finalized.clone();
+        let mut non_finalized = non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned");
+
+        let hash = verified.hash();
+        let parent_hash = verified.block().header.previous_block_hash;
+        let parent_height = match non_finalized.height(parent_hash) {
+            Some(parent_height) => Some(parent_height),
+            // Stored blocks are in the best chain
+            None => self.best_chain_height(parent_hash)?,
+        };
+        crate::check_contextual(
+            &verified,
+            parent_height,
+            self.best_chain_hash(BlockHeight(0))?,
+            self.tip_height(),
+        )?;
+        let mut marks = WriteBatch::default();
+        if let Err(error) = self.check_valid(verified.block(), &mut marks) {
+            self.storage.write_batch(marks)?;
+            return Err(error);
+        }
+        // Side chain blocks are checked against the history tree along their
+        // fork, so a fork with bad history roots can't become the best chain
+        let parent_history =
+            self.fork_parent_history_tree(&non_finalized, verified.block(), verified.height())?;
+        history_tree::check_history_root(
+            self.network,
+            verified.block(),
+            verified.height(),
+            parent_history.as_ref(),
+        )?;
+
+        non_finalized.commit(verified)?;
+        if let Err(error) = self.write_best_chain(&mut non_finalized) {
+            // Keep the stored best chain consistent with the non-finalized
+            // state
+            non_finalized.remove(hash);
+            if let Err(restore_error) = self.write_best_chain(&mut non_finalized) {
+                tracing::warn!(?restore_error, "could not restore the previous best chain");
+            }
+            return Err(error);
+        }
 
         Ok(hash)
     }
 
-    pub(super) fn get(&self, query: impl Into<BlockQuery>) -> Result<Option<Arc<Block>>, Error> {
-        let query = query.into();
-        let value = match query {
-            BlockQuery::ByHash(hash) => {
-                let by_hash = self.storage.open_tree(b"by_hash")?;
-                let key = &hash.0;
-                by_hash.get(key)?
+    /// Update the stored best chain, so it ends with the best chain in
+    /// `non_finalized`, then finalize the blocks that are too deep to be
+    /// replaced.
+    ///
+    /// If the best chain forks below the stored tip, rolls back the stored
+    /// chain to the fork point, then stores the new best chain blocks.
+    fn write_best_chain(&mut self, non_finalized: &mut NonFinalizedState) -> Result<(), Error> {
+        let (first_changed, blocks) =
+            non_finalized.best_chain_diff(|height| self.best_chain_hash(height))?;
+
+        if self.tip_height() >= Some(first_changed) {
+            self.remove_from(first_changed, None)?;
+        }
+        for block in blocks {
+            self.insert_verified(block)?;
+        }
+        non_finalized.finalize();
+
+        Ok(())
+    }
+
+    /// Insert `verified`, storing its serialized bytes verbatim.
+    ///
+    /// Doesn't check that the block follows its parent block, or that it
+    /// isn't marked invalid, so callers must either check it first, or only
+    /// insert finalized blocks.
+    pub(super) fn insert_verified(
+        &mut self,
+        verified: SemanticallyVerifiedBlock,
+    ) -> Result<BlockHeaderHash, Error> {
+        let block = verified.block().clone();
+        let hash = verified.hash();
+        let height = verified.height();
+
+        self.check_disk_space()?;
+
+        let parent_balances = self.parent_value_balances(&block, height)?;
+        let balances = self.next_value_balances(&block, parent_balances, &HashMap::new())?;
+        let counts = self.next_shielded_counts(&block, height)?;
+        let (trees, subtrees) = match self.next_note_trees(&block, height)? {
+            Some((trees, subtrees)) => (Some(trees), subtrees),
+            None => (None, Vec::new()),
+        };
+        let parent_history = self.parent_history_tree(&block, height)?;
+        history_tree::check_history_root(self.network, &block, height, parent_history.as_ref())?;
+        let history = match &trees {
+            Some(trees) => history_tree::next_history_tree(
+                self.network,
+                &block,
+                height,
+                parent_history,
+                trees,
+            )?,
+            None => None,
+        };
+
+        let bytes = verified.bytes();
+        let info = BlockInfo::new(&block, bytes.len(), |outpoint| {
+            self.output_value(outpoint, &HashMap::new())
+        })?;
+        let address_changes =
+            self.address_changes(&block, height, &HashMap::new(), &HashMap::new())?;
+
+        // Write every tree in a single batch, so a crash can't leave the
+        // block partially indexed
+        let (header, body) = split_block(bytes)?;
+        let body = compression::compress_body(body, self.compress_bodies)?;
+        let mut batch = WriteBatch::default();
+        batch.insert(tree::BY_HEIGHT, &height.0.to_be_bytes()[..], &hash.0[..]);
+        batch.insert(tree::BY_HASH, &hash.0[..], &height.0.to_be_bytes()[..]);
+        batch.insert(tree::HEADERS, &hash.0[..], header);
+        batch.insert(tree::BODIES, &hash.0[..], body);
+        batch.insert(tree::BLOCK_INFO, &hash.0[..], &info.to_bytes()[..]);
+        for (txid, location) in transaction_locations(&block, height) {
+            batch.insert(tree::TRANSACTION_LOCATIONS, &txid.0[..], &location[..]);
+        }
+        for key in block_nullifiers(&block) {
+            batch.insert(tree::NULLIFIERS, &key[..], &height.0.to_be_bytes()[..]);
+        }
+        for (outpoint, value) in block_outputs(&block) {
+            batch.insert(
+                tree::TRANSPARENT_OUTPUTS,
+                &outpoint_key(&outpoint)[..],
+                &i64::from(value).to_le_bytes()[..],
+            );
+        }
+        if let Some(balances) = balances {
+            batch.insert(tree::VALUE_POOLS, &hash.0[..], &balances.to_bytes()[..]);
+        }
+        if let Some(counts) = counts {
+            batch.insert(tree::SHIELDED_COUNTS, &hash.0[..], &counts.to_bytes()[..]);
+        }
+        if let Some(trees) = trees {
+            batch.insert(
+                tree::NOTE_COMMITMENT_TREES,
+                &height.0.to_be_bytes()[..],
+                &trees.to_bytes()[..],
+            );
+        }
+        for subtree in &subtrees {
+            batch.insert(
+                tree::SAPLING_SUBTREES,
+                &subtree.key()[..],
+                &subtree.to_bytes()[..],
+            );
+        }
+        if let Some(history) = history {
+            batch.insert(
+                tree::HISTORY_TREES,
+                &height.0.to_be_bytes()[..],
+                &history_tree::to_bytes(&history)[..],
+            );
+        }
+        if self.address_index {
+            for (key, output) in &address_changes.created_outputs {
+                batch.insert(tree::OUTPUT_ADDRESSES, &key[..], &output.to_bytes()[..]);
+            }
+            for (key, hash) in &address_changes.transactions {
+                batch.insert(tree::ADDRESS_TRANSACTIONS, &key[..], &hash.0[..]);
+            }
+            for (key, value) in &address_changes.created_utxos {
+                batch.insert(
+                    tree::ADDRESS_UTXOS,
+                    &key[..],
+                    &i64::from(*value).to_le_bytes()[..],
+                );
+            }
+            for (key, _) in &address_changes.spent_utxos {
+                batch.remove(tree::ADDRESS_UTXOS, &key[..]);
             }
-            BlockQuery::ByHeight(height) => {
-                let by_height = self.storage.open_tree(b"by_height")?;
-                let key = height.0.to_be_bytes();
-                by_height.get(key)?
+            for (address, change) in address_changes.totals {
+                let totals = self.address_totals(&address)?.add(change);
+                batch.insert(tree::ADDRESS_TOTALS, &address[..], &totals.to_bytes()[..]);
             }
+        }
+        self.storage.write_batch(batch)?;
+
+        self.snapshot.commit(&[(height, hash)]);
+        self.update_compaction(height, &block.header);
+        self.block_cache().insert(verified);
+        if let Some(counts) = counts {
+            counts.record_metrics();
+        }
+
+        Ok(hash)
+    }
+
+    /// Insert a contiguous run of `blocks` in a single write batch.
+    ///
+    /// The first block must follow the best chain tip, or be a genesis block
+    /// if the state is empty. If any block can't be inserted, none of the
+    /// blocks are inserted.
+    ///
+    /// The blocks are stored without being added to the non-finalized state,
+    /// so the non-finalized state is reloaded from the stored best chain.
+    pub(super) fn insert_batch(
+        &mut self,
+        blocks: Vec<Arc<Block>>,
+    ) -> Result<Vec<BlockHeaderHash>, Error> {
+        let checked = crate::check_contiguous(&blocks)?;
+        for (_, hash) in &checked {
+            if self.contains(hash)? {
+                Err("block in batch is already in the state")?;
+            }
+        }
+        // Batches can only extend the best chain, so they never replace
+        // stored blocks
+        if let (Some(first), Some(&(height, _))) = (blocks.first(), checked.first()) {
+            match self.snapshot.load().tip() {
+                Some((tip_height, tip_hash)) => {
+                    if first.header.previous_block_hash != tip_hash
+                        || Some(height) != tip_height.0.checked_add(1).map(BlockHeight)
+                    {
+                        Err("first block in batch does not follow the best chain tip")?;
+                    }
+                }
+                None => {
+                    if first.header.previous_block_hash != crate::GENESIS_PREVIOUS_BLOCK_HASH
+                        || height != BlockHeight(0)
+                    {
+                        Err("first block in batch must be a genesis block, because the state is empty")?;
+                    }
+                }
+            }
+        }
+        self.check_disk_space()?;
+        let mut marks = WriteBatch::default();
+        for block in &blocks {
+            if let Err(error) = self.check_valid(block, &mut marks) {
+                self.storage.write_batch(marks)?;
+                return Err(error);
+            }
+        }
+
+        let mut entries = Vec::with_capacity(blocks.len());
+        let mut batch_outputs = HashMap::new();
+        let mut batch_addresses = HashMap::new();
+        let mut address_changes = AddressIndexChanges::default();
+        let mut balances = match checked.first() {
+            Some(&(height, _)) => self.parent_value_balances(&blocks[0], height)?,
+            None => None,
+        };
+        let mut counts = None;
+        let mut trees: Option<NoteCommitmentTrees> = None;
+        let mut subtrees = Vec::new();
+        let mut nullifiers = Vec::new();
+        let mut history: Option<HistoryTree> = None;
+        for (block, &(height, hash)) in blocks.iter().zip(&checked) {
+            balances = self.next_value_balances(block, balances, &batch_outputs)?;
+            counts = match counts {
+                Some(counts) => Some(counts.add_block(block)),
+                None => self.next_shielded_counts(block, height)?,
+            };
+            let next_trees = match trees {
+                Some(trees) => Some(trees.add_block_with_subtrees(block, height)?),
+                None => self.next_note_trees(block, height)?,
+            };
+            trees = match next_trees {
+                Some((next_trees, block_subtrees)) => {
+                    subtrees.extend(block_subtrees);
+                    Some(next_trees)
+                }
+                None => None,
+            };
+            let parent_history = match history.take() {
+                Some(history) => Some(history),
+                // Only the tree before the first block can be stored
+                None => self.parent_history_tree(block, height)?,
+            };
+            history_tree::check_history_root(self.network, block, height, parent_history.as_ref())?;
+            history = match &trees {
+                Some(trees) => history_tree::next_history_tree(
+                    self.network,
+                    block,
+                    height,
+                    parent_history,
+                    trees,
+                )?,
+                None => None,
+            };
+
+            let mut bytes = Vec::new();
+            block.zcash_serialize(&mut bytes)?;
+            let info = BlockInfo::new(block, bytes.len(), |outpoint| {
+                self.output_value(outpoint, &batch_outputs)
+            })?;
+
+            let changes = self.address_changes(block, height, &batch_outputs, &batch_addresses)?;
+            address_changes.merge_totals(&changes, false);
+            batch_addresses.extend(changes.created_outputs.iter().cloned());
+            address_changes
+                .created_outputs
+                .extend(changes.created_outputs);
+            address_changes.created_utxos.extend(changes.created_utxos);
+            address_changes.spent_utxos.extend(changes.spent_utxos);
+            address_changes.transactions.extend(changes.transactions);
+
+            let outputs: Vec<_> = block_outputs(block)
+                .into_iter()
+                .map(|(outpoint, value)| (outpoint_key(&outpoint), value))
+                .collect();
+            batch_outputs.extend(outputs.iter().cloned());
+            nullifiers.extend(
+                block_nullifiers(block)
+                    .into_iter()
+                    .map(|key| (key, height.0.to_be_bytes())),
+            );
+
+            entries.push((
+                height.0.to_be_bytes(),
+                hash.0,
+                bytes,
+                info,
+                outputs,
+                transaction_locations(block, height),
+                balances,
+                counts,
+                trees.as_ref().map(NoteCommitmentTrees::to_bytes),
+                history.as_ref().map(history_tree::to_bytes),
+            ));
+        }
+
+        let mut batch = WriteBatch::default();
+        for (key, output) in &address_changes.created_outputs {
+            batch.insert(tree::OUTPUT_ADDRESSES, &key[..], &output.to_bytes()[..]);
+        }
+        for (key, hash) in &address_changes.transactions {
+            batch.insert(tree::ADDRESS_TRANSACTIONS, &key[..], &hash.0[..]);
+        }
+        // Outputs can be spent later in the batch, so create all the UTXOs
+        // before removing any
+        for (key, value) in &address_changes.created_utxos {
+            batch.insert(
+                tree::ADDRESS_UTXOS,
+                &key[..],
+                &i64::from(*value).to_le_bytes()[..],
+            );
+        }
+        for (key, _) in &address_changes.spent_utxos {
+            batch.remove(tree::ADDRESS_UTXOS, &key[..]);
+        }
+        for (address, change) in &address_changes.totals {
+            let totals = self.address_totals(address)?.add(*change);
+            batch.insert(tree::ADDRESS_TOTALS, &address[..], &totals.to_bytes()[..]);
+        }
+        for subtree in &subtrees {
+            batch.insert(
+                tree::SAPLING_SUBTREES,
+                &subtree.key()[..],
+                &subtree.to_bytes()[..],
+            );
+        }
+        for (key, height) in &nullifiers {
+            batch.insert(tree::NULLIFIERS, &key[..], &height[..]);
+        }
+        for (height, hash, bytes, info, outputs, locations, balances, counts, trees, history) in
+            &entries
+        {
+            let (header, body) = split_block(bytes)?;
+            let body = compression::compress_body(body, self.compress_bodies)?;
+            batch.insert(tree::BY_HEIGHT, &height[..], &hash[..]);
+            batch.insert(tree::BY_HASH, &hash[..], &height[..]);
+            batch.insert(tree::HEADERS, &hash[..], header);
+            batch.insert(tree::BODIES, &hash[..], body);
+            batch.insert(tree::BLOCK_INFO, &hash[..], &info.to_bytes()[..]);
+            for (txid, location) in locations {
+                batch.insert(tree::TRANSACTION_LOCATIONS, &txid.0[..], &location[..]);
+            }
+            for (key, value) in outputs {
+                batch.insert(
+                    tree::TRANSPARENT_OUTPUTS,
+                    &key[..],
+                    &i64::from(*value).to_le_bytes()[..],
+                );
+            }
+            if let Some(balances) = balances {
+                batch.insert(tree::VALUE_POOLS, &hash[..], &balances.to_bytes()[..]);
+            }
+            if let Some(counts) = counts {
+                batch.insert(tree::SHIELDED_COUNTS, &hash[..], &counts.to_bytes()[..]);
+            }
+            if let Some(trees) = trees {
+                batch.insert(tree::NOTE_COMMITMENT_TREES, &height[..], &trees[..]);
+            }
+            if let Some(history) = history {
+                batch.insert(tree::HISTORY_TREES, &height[..], &history[..]);
+            }
+        }
+        self.storage.write_batch(batch)?;
+
+        self.snapshot.commit(&checked);
+        if let (Some(block), Some(&(height, _))) = (blocks.last(), checked.last()) {
+            self.update_compaction(height, &block.header);
+        }
+        if let Some(counts) = counts {
+            counts.record_metrics();
+        }
+        self.reload_non_finalized()?;
+
+        Ok(checked.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// Remove all blocks above `height` from every tree, in a single write
+    /// batch.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    pub(super) fn rollback(&mut self, height: BlockHeight) -> Result<Vec<BlockHeaderHash>, Error> {
+        let first_removed = match height.0.checked_add(1) {
+            Some(first_removed) => BlockHeight(first_removed),
+            None => return Ok(Vec::new()),
         };
 
-        if let Some(bytes) = value {
-            let bytes = bytes.as_ref();
-            let block = ZcashDeserialize::zcash_deserialize(bytes)?;
-            Ok(Some(block))
-        } else {
-            Ok(None)
-        }
-    }
+        let non_finalized = self.non_finalized.clone();
+        let mut non_finalized = non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned");
+        crate::check_above_finalized(first_removed, non_finalized.finalized_height())?;
+
+        let removed = self.remove_from(first_removed, None)?;
+        non_finalized.remove_from(first_removed);
+        self.write_best_chain(&mut non_finalized)?;
+
+        Ok(removed)
+    }
+
+    /// Remove the blocks at and above `first_removed` from every tree, in a
+    /// single write batch.
+    ///
+    /// If `invalid_root` is `Some`, also marks each removed block as invalid,
+    /// because it is `invalid_root` or one of its descendants.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    fn remove_from(
+        &mut self,
+        first_removed: BlockHeight,
+        invalid_root: Option<BlockHeaderHash>,
+    ) -> Result<Vec<BlockHeaderHash>, Error> {
+        self.remove_from_with(first_removed, invalid_root, WriteBatch::default())
+    }
+
+    /// Remove the blocks at and above `first_removed`, like
+    /// [`Self::remove_from`], writing the other changes in `batch` in the
+    /// same write batch.
+    fn remove_from_with(
+        &mut self,
+        first_removed: BlockHeight,
+        invalid_root: Option<BlockHeaderHash>,
+        mut batch: WriteBatch,
+    ) -> Result<Vec<BlockHeaderHash>, Error> {
+        let removed_heights = key_range(first_removed.0.to_be_bytes()..);
+        let mut entries = Vec::new();
+        let mut address_changes = AddressIndexChanges::default();
+        for entry in self
+            .storage
+            .iterate(tree::BY_HEIGHT, removed_heights.clone())?
+        {
+            let (_key, hash) = entry?;
+            let hash = BlockHeaderHash(<[u8; 32]>::try_from(&hash[..])?);
+            let block = self.get(hash)?.ok_or("stored block is missing")?;
+            // The removed outputs are still in the state, so spends within the
+            // removed blocks are attributed correctly.
+            let height = block
+                .coinbase_height()
+                .expect("committed blocks have a coinbase height");
+            let changes = self.address_changes(&block, height, &HashMap::new(), &HashMap::new())?;
+            address_changes.merge_totals(&changes, true);
+            address_changes.created_utxos.extend(changes.created_utxos);
+            address_changes.spent_utxos.extend(changes.spent_utxos);
+            address_changes.transactions.extend(changes.transactions);
+            let outputs: Vec<[u8; OUTPOINT_KEY_SIZE]> = block_outputs(&block)
+                .iter()
+                .map(|(outpoint, _)| outpoint_key(outpoint))
+                .collect();
+            let txids: Vec<TransactionHash> = transaction_locations(&block, height)
+                .into_iter()
+                .map(|(txid, _)| txid)
+                .collect();
+            entries.push((block.hash(), outputs, txids, block_nullifiers(&block)));
+        }
+
+        // Outputs spent by the removed blocks are unspent again, unless they
+        // were also created by the removed blocks.
+        let restored_utxos: Vec<_> = address_changes
+            .spent_utxos
+            .iter()
+            .filter(|(key, _)| utxo_key_height(key) < first_removed)
+            .cloned()
+            .collect();
+
+        for (key, _) in &address_changes.transactions {
+            batch.remove(tree::ADDRESS_TRANSACTIONS, &key[..]);
+        }
+        for (key, _) in &address_changes.created_utxos {
+            batch.remove(tree::ADDRESS_UTXOS, &key[..]);
+        }
+        for (key, value) in &restored_utxos {
+            batch.insert(
+                tree::ADDRESS_UTXOS,
+                &key[..],
+                &i64::from(*value).to_le_bytes()[..],
+            );
+        }
+        for (address, change) in &address_changes.totals {
+            if let Some(bytes) = self.storage.read(tree::ADDRESS_TOTALS, &address[..])? {
+                let totals = AddressTotals::from_bytes(&bytes)?.add(*change);
+                batch.insert(tree::ADDRESS_TOTALS, &address[..], &totals.to_bytes()[..]);
+            }
+        }
+        self.remove_subtrees_from(&mut batch, first_removed)?;
+        batch.delete_range(tree::NOTE_COMMITMENT_TREES, removed_heights.clone());
+        batch.delete_range(tree::HISTORY_TREES, removed_heights.clone());
+        batch.delete_range(tree::BY_HEIGHT, removed_heights);
+        for (hash, outputs, txids, nullifiers) in &entries {
+            batch.remove(tree::BY_HASH, &hash.0[..]);
+            batch.remove(tree::HEADERS, &hash.0[..]);
+            batch.remove(tree::BODIES, &hash.0[..]);
+            batch.remove(tree::BLOCK_INFO, &hash.0[..]);
+            batch.remove(tree::VALUE_POOLS, &hash.0[..]);
+            batch.remove(tree::SHIELDED_COUNTS, &hash.0[..]);
+            for output in outputs {
+                batch.remove(tree::TRANSPARENT_OUTPUTS, &output[..]);
+                batch.remove(tree::OUTPUT_ADDRESSES, &output[..]);
+            }
+            for txid in txids {
+                batch.remove(tree::TRANSACTION_LOCATIONS, &txid.0[..]);
+            }
+            for key in nullifiers {
+                batch.remove(tree::NULLIFIERS, &key[..]);
+            }
+            if let Some(root) = invalid_root {
+                batch.insert(tree::INVALID, &hash.0[..], &root.0[..]);
+            }
+        }
+        self.storage.write_batch(batch)?;
+
+        let mut block_cache = self.block_cache();
+        for (hash, _, _, _) in &entries {
+            block_cache.remove_from_state(*hash);
+        }
+        drop(block_cache);
+
+        self.reset_archived_height(first_removed.0)?;
+        self.snapshot
+            .replace(Self::load_snapshot(self.storage.as_ref())?);
+        if let Some((_, tip_hash)) = self.snapshot.load().tip() {
+            if let Some(counts) = self.shielded_counts(tip_hash)? {
+                counts.record_metrics();
+            }
+        }
+
+        Ok(entries.into_iter().map(|(hash, ..)| hash).collect())
+    }
+
+    /// Add the removal of the sapling subtrees completed by the blocks at and
+    /// above `first_removed` to `batch`.
+    fn remove_subtrees_from(
+        &self,
+        batch: &mut WriteBatch,
+        first_removed: BlockHeight,
+    ) -> Result<(), Error> {
+        // Subtrees are stored by index, so keep the subtrees that are
+        // complete in the trees before the removed blocks
+        let kept_trees = match first_removed.0.checked_sub(1) {
+            Some(kept_height) => self.note_trees(BlockHeight(kept_height))?,
+            None => Some(NoteCommitmentTrees::default()),
+        };
+        if let Some(kept_trees) = kept_trees {
+            let kept_subtrees = kept_trees.sapling.size() >> SAPLING_SUBTREE_LEVEL;
+            if let Ok(first_removed_subtree) = u16::try_from(kept_subtrees) {
+                batch.delete_range(
+                    tree::SAPLING_SUBTREES,
+                    first_removed_subtree.to_be_bytes()..,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mark the block with `hash` as invalid, and remove it and all its
+    /// descendants from the state.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    pub(super) fn invalidate(
+        &mut self,
+        hash: BlockHeaderHash,
+    ) -> Result<Vec<BlockHeaderHash>, Error> {
+        let non_finalized = self.non_finalized.clone();
+        let mut non_finalized = non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned");
+        let height = self.best_chain_height(hash)?;
+
+        if non_finalized.contains(&hash) {
+            // The block and its descendants can be in the best chain and any
+            // of the side chains, so mark them all in the same batch that
+            // removes the stored blocks
+            let removed = non_finalized.remove(hash);
+            let mut marks = WriteBatch::default();
+            for removed_hash in &removed {
+                marks.insert(tree::INVALID, &removed_hash.0[..], &hash.0[..]);
+            }
+            match height {
+                Some(height) => {
+                    self.remove_from_with(height, None, marks)?;
+                }
+                None => self.storage.write_batch(marks)?,
+            }
+            self.write_best_chain(&mut non_finalized)?;
+
+            return Ok(removed);
+        }
+
+        match height {
+            Some(height) => {
+                // The block is finalized, so all the non-finalized blocks are
+                // its descendants
+                let removed = self.remove_from(height, Some(hash))?;
+                drop(non_finalized);
+                self.reload_non_finalized()?;
+                Ok(removed)
+            }
+            None => {
+                // The block isn't in the state, but we still reject it if it
+                // arrives later.
+                self.storage.insert(tree::INVALID, &hash.0, &hash.0[..])?;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Remove the invalid marking from the block with `hash`, and from all
+    /// its descendants that were marked invalid because of it.
+    pub(super) fn reconsider(&mut self, hash: BlockHeaderHash) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        for entry in self.storage.iterate(tree::INVALID, all_keys())? {
+            let (key, root) = entry?;
+            if root[..] == hash.0[..] {
+                batch.remove(tree::INVALID, key);
+            }
+        }
+
+        self.storage.write_batch(batch)
+    }
+
+    /// Returns the value pool balances after the block with `hash`, if they
+    /// are known.
+    fn value_balances(&self, hash: BlockHeaderHash) -> Result<Option<ValueBalances>, Error> {
+        self.storage
+            .read(tree::VALUE_POOLS, &hash.0)?
+            .map(|bytes| ValueBalances::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Returns the value pool balances before `block`, if they are known.
+    fn parent_value_balances(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+    ) -> Result<Option<ValueBalances>, Error> {
+        if height == BlockHeight(0) {
+            Ok(Some(ValueBalances::zero()))
+        } else {
+            self.value_balances(block.header.previous_block_hash)
+        }
+    }
+
+    /// Returns the value pool balances after `block`, or `None` if the
+    /// balances before `block` are unknown.
+    ///
+    /// `pending_outputs` contains transparent outputs that are being committed
+    /// in the same batch as `block`.
+    fn next_value_balances(
+        &self,
+        block: &Block,
+        parent_balances: Option<ValueBalances>,
+        pending_outputs: &HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+    ) -> Result<Option<ValueBalances>, Error> {
+        let parent_balances = match parent_balances {
+            Some(parent_balances) => parent_balances,
+            None => return Ok(None),
+        };
+
+        let balances = parent_balances.add_block(block, |outpoint| {
+            self.output_value(outpoint, pending_outputs)
+        })?;
+
+        Ok(Some(balances))
+    }
+
+    /// Returns the shielded counts after the block with `hash`, if they are
+    /// known.
+    fn shielded_counts(&self, hash: BlockHeaderHash) -> Result<Option<ShieldedCounts>, Error> {
+        self.storage
+            .read(tree::SHIELDED_COUNTS, &hash.0)?
+            .map(|bytes| ShieldedCounts::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Returns the shielded counts after `block` at `height`, or `None` if the
+    /// counts before `block` are unknown.
+    fn next_shielded_counts(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+    ) -> Result<Option<ShieldedCounts>, Error> {
+        let parent_counts = if height == BlockHeight(0) {
+            Some(ShieldedCounts::default())
+        } else {
+            self.shielded_counts(block.header.previous_block_hash)?
+        };
+
+        Ok(parent_counts.map(|counts| counts.add_block(block)))
+    }
+
+    /// Returns the note commitment trees after the best chain block at
+    /// `height`, if they are known.
+    fn note_trees(&self, height: BlockHeight) -> Result<Option<NoteCommitmentTrees>, Error> {
+        self.storage
+            .read(tree::NOTE_COMMITMENT_TREES, &height.0.to_be_bytes())?
+            .map(|bytes| NoteCommitmentTrees::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Returns the note commitment trees after `block` at `height`, and the
+    /// Sapling subtrees that `block` completes, or `None` if the trees before
+    /// `block` are unknown.
+    ///
+    /// The trees are stored by height, so the parent trees are only used if
+    /// the parent block is the best chain block at the previous height.
+    fn next_note_trees(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+    ) -> Result<Option<(NoteCommitmentTrees, Vec<NoteCommitmentSubtree>)>, Error> {
+        let parent_trees = match height.0.checked_sub(1).map(BlockHeight) {
+            None => Some(NoteCommitmentTrees::default()),
+            Some(parent_height)
+                if self.best_chain_hash(parent_height)?
+                    == Some(block.header.previous_block_hash) =>
+            {
+                self.note_trees(parent_height)?
+            }
+            Some(_) => None,
+        };
+
+        parent_trees
+            .map(|trees| trees.add_block_with_subtrees(block, height))
+            .transpose()
+    }
+
+    /// Returns up to `limit` complete Sapling subtrees in the best chain,
+    /// starting at the subtree with `start_index`.
+    fn sapling_subtrees(
+        &self,
+        start_index: u16,
+        limit: usize,
+    ) -> Result<Vec<NoteCommitmentSubtree>, Error> {
+        self.storage
+            .iterate(
+                tree::SAPLING_SUBTREES,
+                key_range(start_index.to_be_bytes()..),
+            )?
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry?;
+                NoteCommitmentSubtree::from_bytes(&key, &value)
+            })
+            .collect()
+    }
+
+    /// Returns the history tree after the best chain block at `height`, if
+    /// it is known.
+    fn history_tree(&self, height: BlockHeight) -> Result<Option<HistoryTree>, Error> {
+        self.storage
+            .read(tree::HISTORY_TREES, &height.0.to_be_bytes())?
+            .map(|bytes| history_tree::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Returns the history tree before `block` at `height`, or `None` if it
+    /// is unknown.
+    ///
+    /// Like note commitment trees, history trees are stored by height, so
+    /// the parent tree is only used if the parent block is the best chain
+    /// block at the previous height.
+    fn parent_history_tree(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+    ) -> Result<Option<HistoryTree>, Error> {
+        match height.0.checked_sub(1).map(BlockHeight) {
+            Some(parent_height)
+                if self.best_chain_hash(parent_height)?
+                    == Some(block.header.previous_block_hash) =>
+            {
+                self.history_tree(parent_height)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the history tree before `block` at `height`, or `None` if it
+    /// is unknown.
+    ///
+    /// If the parent block is in a side chain in `non_finalized`, the tree
+    /// is calculated along the fork, starting with the stored trees at the
+    /// fork point.
+    fn fork_parent_history_tree(
+        &self,
+        non_finalized: &NonFinalizedState,
+        block: &Block,
+        height: BlockHeight,
+    ) -> Result<Option<HistoryTree>, Error> {
+        // The side chain blocks before `block`, newest first
+        let mut fork = Vec::new();
+        let mut parent_hash = block.header.previous_block_hash;
+        let mut fork_height = match height.0.checked_sub(1) {
+            Some(parent_height) => BlockHeight(parent_height),
+            None => return Ok(None),
+        };
+        while self.best_chain_hash(fork_height)? != Some(parent_hash) {
+            let parent = match non_finalized.get(&parent_hash) {
+                Some(parent) => parent,
+                None => return Ok(None),
+            };
+            fork.push(parent.block().clone());
+            parent_hash = parent.block().header.previous_block_hash;
+            fork_height = match fork_height.0.checked_sub(1) {
+                Some(height) => BlockHeight(height),
+                None => return Ok(None),
+            };
+        }
+
+        let mut trees = match self.note_trees(fork_height)? {
+            Some(trees) => trees,
+            None => return Ok(None),
+        };
+        let mut history = self.history_tree(fork_height)?;
+        for (block, height) in fork.iter().rev().zip(fork_height.0 + 1..) {
+            trees = trees.add_block(block)?;
+            history = history_tree::next_history_tree(
+                self.network,
+                block,
+                BlockHeight(height),
+                history,
+                &trees,
+            )?;
+        }
+
+        Ok(history)
+    }
+
+    /// Returns the value of the transparent output at `outpoint`, if it is
+    /// in `pending_outputs` or the state.
+    fn output_value(
+        &self,
+        outpoint: &OutPoint,
+        pending_outputs: &HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+    ) -> Result<Option<Amount<NonNegative>>, Error> {
+        let key = outpoint_key(outpoint);
+        if let Some(&value) = pending_outputs.get(&key) {
+            return Ok(Some(value));
+        }
+
+        self.storage
+            .read(tree::TRANSPARENT_OUTPUTS, &key[..])?
+            .map(|bytes| amount_from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Returns the address index changes made by `block` at `height`, or no
+    /// changes if the address index is disabled.
+    ///
+    /// `pending_outputs` and `pending_addresses` contain transparent outputs
+    /// that are being committed in the same batch as `block`.
+    fn address_changes(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+        pending_outputs: &HashMap<[u8; OUTPOINT_KEY_SIZE], Amount<NonNegative>>,
+        pending_addresses: &HashMap<[u8; OUTPOINT_KEY_SIZE], IndexedOutput>,
+    ) -> Result<AddressIndexChanges, Error> {
+        if !self.address_index {
+            return Ok(AddressIndexChanges::default());
+        }
+
+        block_address_changes(block, height, |outpoint| {
+            let key = outpoint_key(outpoint);
+            let output = match pending_addresses.get(&key) {
+                Some(&output) => output,
+                None => match self.storage.read(tree::OUTPUT_ADDRESSES, &key[..])? {
+                    Some(bytes) => IndexedOutput::from_bytes(&bytes)?,
+                    None => return Ok(None),
+                },
+            };
+
+            Ok(self
+                .output_value(outpoint, pending_outputs)?
+                .map(|value| (output, value)))
+        })
+    }
+
+    /// Returns the stored totals for `address`.
+    fn address_totals(&self, address: &AddressKey) -> Result<AddressTotals, Error> {
+        match self.storage.read(tree::ADDRESS_TOTALS, &address[..])? {
+            Some(bytes) => AddressTotals::from_bytes(&bytes),
+            None => Ok(AddressTotals::default()),
+        }
+    }
+
+    /// Returns the combined balance of `addresses`.
+    fn address_balance(&self, addresses: &[TransparentAddress]) -> Result<AddressBalance, Error> {
+        if !self.address_index {
+            Err("the transparent address index is disabled")?;
+        }
+
+        let totals = addresses
+            .iter()
+            .map(|address| self.address_totals(&address_key(address)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        AddressBalance::from_totals(totals)
+    }
+
+    /// Returns the height of the best chain block that revealed the nullifier
+    /// with `key`, if it is indexed.
+    fn nullifier_height(
+        &self,
+        key: &[u8; NULLIFIER_KEY_SIZE],
+    ) -> Result<Option<BlockHeight>, Error> {
+        let height = match self.storage.read(tree::NULLIFIERS, &key[..])? {
+            Some(height) => <[u8; 4]>::try_from(&height[..])?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(BlockHeight(u32::from_be_bytes(height))))
+    }
+
+    /// Returns the header of the best chain block containing the transaction
+    /// with `txid`, and the merkle path from the transaction to the header's
+    /// merkle root.
+    fn transaction_merkle_path(
+        &self,
+        txid: TransactionHash,
+    ) -> Result<Option<(BlockHeader, MerklePath)>, Error> {
+        let location = match self
+            .storage
+            .read(tree::TRANSACTION_LOCATIONS, &txid.0[..])?
+        {
+            Some(location) => <[u8; TRANSACTION_LOCATION_SIZE]>::try_from(&location[..])?,
+            None => return Ok(None),
+        };
+        let (height, index) = location.split_at(4);
+        let height = BlockHeight(u32::from_be_bytes(<[u8; 4]>::try_from(height)?));
+        let index = u32::from_be_bytes(<[u8; 4]>::try_from(index)?) as usize;
+
+        let block = self
+            .get(height)?
+            .ok_or("best chain block for an indexed transaction is missing")?;
+        let txids: Vec<TransactionHash> = block
+            .transactions
+            .iter()
+            .map(|tx| TransactionHash::from(tx.as_ref().clone()))
+            .collect();
+        if txids.get(index) != Some(&txid) {
+            Err("indexed transaction is not at its stored location")?;
+        }
+        let path = MerklePath::new(&txids, index).expect("index is in range");
+
+        Ok(Some((block.header, path)))
+    }
+
+    /// Returns the IDs of the transactions that touch `addresses`, in blocks
+    /// in `height_range`, in chain order.
+    fn address_transaction_ids(
+        &self,
+        addresses: &[TransparentAddress],
+        height_range: RangeInclusive<BlockHeight>,
+    ) -> Result<Vec<TransactionHash>, Error> {
+        if !self.address_index {
+            Err("the transparent address index is disabled")?;
+        }
+        if height_range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut locations = Vec::new();
+        for address in addresses {
+            let range = key_range(tx_location_range(address, &height_range));
+            for entry in self.storage.iterate(tree::ADDRESS_TRANSACTIONS, range)? {
+                let (key, hash) = entry?;
+                let hash = TransactionHash(<[u8; 32]>::try_from(hash.as_ref())?);
+                locations.push((tx_location(&key)?, hash));
+            }
+        }
+
+        // Transactions that touch multiple addresses are only returned once
+        locations.sort_by_key(|(location, _)| *location);
+        locations.dedup();
+
+        Ok(locations.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    /// Returns up to `limit` unspent outputs paying to `addresses`, created at
+    /// or above `start`, ordered by height.
+    fn address_utxos(
+        &self,
+        addresses: &[TransparentAddress],
+        start: BlockHeight,
+        limit: usize,
+    ) -> Result<Vec<AddressUtxo>, Error> {
+        if !self.address_index {
+            Err("the transparent address index is disabled")?;
+        }
+
+        let mut utxos = Vec::new();
+        for address in addresses {
+            let prefix = address_key(address);
+            // Each address's UTXOs are ordered by height, so we only need the
+            // first `limit` of them.
+            let range = key_range(utxo_range_start(address, start)..);
+            for entry in self
+                .storage
+                .iterate(tree::ADDRESS_UTXOS, range)?
+                .take(limit)
+            {
+                let (key, value) = entry?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                utxos.push(AddressUtxo::from_entry(
+                    *address,
+                    &key,
+                    amount_from_bytes(&value)?,
+                )?);
+            }
+        }
+
+        utxos.sort_by_key(|utxo| utxo.height);
+        utxos.truncate(limit);
+
+        Ok(utxos)
+    }
+
+    /// Returns the metadata for the block with `hash`, if it is in the state.
+    fn block_info(&self, hash: BlockHeaderHash) -> Result<Option<BlockInfo>, Error> {
+        self.storage
+            .read(tree::BLOCK_INFO, &hash.0)?
+            .map(|bytes| BlockInfo::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Returns the metadata for each transaction in the block with `hash`, if
+    /// it is in the state.
+    fn transaction_info(
+        &self,
+        hash: BlockHeaderHash,
+    ) -> Result<Option<Vec<TransactionInfo>>, Error> {
+        self.get(hash)?
+            .map(|block| {
+                TransactionInfo::for_block(&block, |outpoint| {
+                    self.output_value(outpoint, &HashMap::new())
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the hash of the block at `height` in the best chain, if there
+    /// is one.
+    fn best_chain_hash(&self, height: BlockHeight) -> Result<Option<BlockHeaderHash>, Error> {
+        match self.snapshot.load().hash(height) {
+            Some(hash) => Ok(Some(hash)),
+            None => self.stored_hash(height),
+        }
+    }
+
+    /// Returns the hash of the stored block at `height`, using the height
+    /// index, without checking the snapshot.
+    fn stored_hash(&self, height: BlockHeight) -> Result<Option<BlockHeaderHash>, Error> {
+        match self
+            .storage
+            .read(tree::BY_HEIGHT, &height.0.to_be_bytes())?
+        {
+            Some(hash) => Ok(Some(BlockHeaderHash(<[u8; 32]>::try_from(&hash[..])?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the height of the block with `hash`, if it is in the best
+    /// chain.
+    ///
+    /// Only best chain blocks are stored, so every stored block is in the
+    /// best chain.
+    fn best_chain_height(&self, hash: BlockHeaderHash) -> Result<Option<BlockHeight>, Error> {
+        match self.storage.read(tree::BY_HASH, &hash.0)? {
+            Some(height) => {
+                let height = <[u8; 4]>::try_from(&height[..])?;
+                Ok(Some(BlockHeight(u32::from_be_bytes(height))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the hash of the ancestor of `hash` at `height`, if the block
+    /// is in the state.
+    ///
+    /// Stored blocks are in the best chain, so the ancestor is looked up by
+    /// height, without reading any blocks.
+    fn ancestor_hash(
+        &self,
+        hash: BlockHeaderHash,
+        height: BlockHeight,
+    ) -> Result<Option<BlockHeaderHash>, Error> {
+        match self.best_chain_height(hash)? {
+            Some(block_height) if block_height >= height => self.best_chain_hash(height),
+            _ => Ok(None),
+        }
+    }
+
+    /// Check that `block` is not marked as invalid, and is not the child of
+    /// an invalid block.
+    ///
+    /// Children of invalid blocks inherit their parent's invalid mark. The
+    /// inherited mark is added to `marks`, so callers can write it when they
+    /// reject the block.
+    fn check_valid(&self, block: &Block, marks: &mut WriteBatch) -> Result<(), Error> {
+        let hash = block.hash();
+
+        if self.storage.read(tree::INVALID, &hash.0)?.is_some() {
+            Err("block has been marked invalid")?;
+        }
+
+        if let Some(root) = self
+            .storage
+            .read(tree::INVALID, &block.header.previous_block_hash.0)?
+        {
+            marks.insert(tree::INVALID, &hash.0[..], &root[..]);
+            Err("block is a descendant of a block that has been marked invalid")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the side chain block with `hash`, if it is in the
+    /// non-finalized state.
+    ///
+    /// Best chain blocks are also stored, so they should be read from the
+    /// stored chain.
+    fn side_chain_block(&self, hash: BlockHeaderHash) -> Option<SemanticallyVerifiedBlock> {
+        self.non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned")
+            .get(&hash)
+            .cloned()
+    }
+
+    pub(super) fn get(&self, query: impl Into<BlockQuery>) -> Result<Option<Arc<Block>>, Error> {
+        Ok(self
+            .get_verified(query)?
+            .map(|verified| verified.block().clone()))
+    }
+
+    /// Returns the block for `query`, along with its serialized bytes.
+    ///
+    /// Recently committed and recently read blocks are returned from the
+    /// block cache. Other blocks are read from storage, then added to the
+    /// cache.
+    fn get_verified(
+        &self,
+        query: impl Into<BlockQuery>,
+    ) -> Result<Option<SemanticallyVerifiedBlock>, Error> {
+        let hash = match self.query_hash(query)? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+
+        let generation = {
+            let mut block_cache = self.block_cache();
+            if let Some(verified) = block_cache.get(hash) {
+                metrics::counter!("state.block_cache.hit.count", 1);
+                return Ok(Some(verified));
+            }
+            block_cache.generation()
+        };
+        metrics::counter!("state.block_cache.miss.count", 1);
+
+        let verified = match self.read_bytes(hash)? {
+            Some(bytes) => SemanticallyVerifiedBlock::from_bytes(bytes).map_err(|error| {
+                recovery::log_corrupt_block(hash, &error);
+                error
+            })?,
+            None => return Ok(None),
+        };
+        self.block_cache().insert_read(verified.clone(), generation);
+
+        Ok(Some(verified))
+    }
+
+    /// Returns the block cache.
+    fn block_cache(&self) -> MutexGuard<'_, BlockCache> {
+        self.block_cache
+            .lock()
+            .expect("block cache mutex should be unpoisoned")
+    }
+
+    /// Returns the header of the block with `hash`, without reading the
+    /// block body.
+    fn get_header(&self, hash: BlockHeaderHash) -> Result<Option<BlockHeader>, Error> {
+        match self.storage.read(tree::HEADERS, &hash.0)? {
+            Some(bytes) => Ok(Some(BlockHeader::zcash_deserialize(bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the serialized block, exactly as it was committed.
+    fn get_bytes(&self, query: impl Into<BlockQuery>) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .get_verified(query)?
+            .map(|verified| verified.bytes().to_vec()))
+    }
+
+    /// Returns the hash of the block for `query`, if it is in the best chain.
+    fn query_hash(&self, query: impl Into<BlockQuery>) -> Result<Option<BlockHeaderHash>, Error> {
+        match query.into() {
+            BlockQuery::ByHash(hash) => Ok(Some(hash)),
+            BlockQuery::ByHeight(height) => self.best_chain_hash(height),
+        }
+    }
+
+    /// Read the serialized block with `hash` from storage, without using the
+    /// block cache.
+    fn read_bytes(&self, hash: BlockHeaderHash) -> Result<Option<Vec<u8>>, Error> {
+        let mut bytes = match self.storage.read(tree::HEADERS, &hash.0)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let body = match self.storage.read(tree::BODIES, &hash.0)? {
+            Some(body) => body,
+            // Pruned blocks only have a header
+            None if self.is_pruned(hash)? => return Ok(None),
+            None => Err("stored block body is missing")?,
+        };
+        let body = archive::body_bytes(self.archive.as_ref(), body)?;
+        bytes.extend(compression::decompress_body(body)?);
+
+        Ok(Some(bytes))
+    }
+
+    /// Returns the number of blocks in the stored best chain.
+    fn count(&self) -> Result<usize, Error> {
+        // The stored heights are contiguous, so only the lowest and highest
+        // entries need to be read.
+        let mut entries = self.storage.iterate(tree::BY_HEIGHT, all_keys())?;
+        let lowest = match entries.next() {
+            Some(entry) => u32::from_be_bytes(<[u8; 4]>::try_from(&entry?.0[..])?),
+            None => return Ok(0),
+        };
+        let highest = match entries.next_back() {
+            Some(entry) => u32::from_be_bytes(<[u8; 4]>::try_from(&entry?.0[..])?),
+            None => lowest,
+        };
+
+        Ok((highest - lowest) as usize + 1)
+    }
+
+    fn contains(&self, hash: &BlockHeaderHash) -> Result<bool, Error> {
+        Ok(self.storage.read(tree::HEADERS, &hash.0)?.is_some())
+    }
+}
+
+impl Service<Request> for SledState {
+    type Response = Response;
+    type Error = Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        match req {
+            Request::CommitBlock { block } => {
+                // Commit synchronously, so queued blocks see their parents
+                let result = self.commit_or_queue(block);
+
+                async move {
+                    result?.await.unwrap_or_else(|_| {
+                        Err("queued block was dropped before it was committed".into())
+                    })
+                }
+                .boxed()
+            }
+            Request::CommitFinalizedBlock { block } => {
+                let result = self.commit_finalized_block(block);
+
+                async move { result }.boxed()
+            }
+            Request::AddBlockBatch { blocks } => {
+                let mut storage = self.clone();
+
+                async move {
+                    storage
+                        .insert_batch(blocks)
+                        .map(|hashes| Response::AddedBatch { hashes })
+                }
+                .boxed()
+            }
+            Request::RollbackToHeight { height } => {
+                let mut storage = self.clone();
+
+                async move {
+                    storage
+                        .rollback(height)
+                        .map(|removed| Response::RolledBack { removed })
+                }
+                .boxed()
+            }
+            Request::InvalidateBlock { hash } => {
+                let mut storage = self.clone();
+
+                async move {
+                    storage
+                        .invalidate(hash)
+                        .map(|removed| Response::Invalidated { removed })
+                }
+                .boxed()
+            }
+            Request::ReconsiderBlock { hash } => {
+                let mut storage = self.clone();
 
-    pub(super) fn get_tip(&self) -> Result<Option<Arc<Block>>, Error> {
-        let tree = self.storage.open_tree(b"by_height")?;
-        let last_entry = tree.iter().values().next_back();
+                async move {
+                    storage.reconsider(hash)?;
+                    Ok(Response::Reconsidered)
+                }
+                .boxed()
+            }
+            Request::RecordFailedBlock {
+                hash,
+                verifier_version,
+                rule,
+            } => {
+                let storage = self.clone();
 
-        match last_entry {
-            Some(Ok(bytes)) => Ok(Some(ZcashDeserialize::zcash_deserialize(bytes.as_ref())?)),
-            Some(Err(e)) => Err(e)?,
-            None => Ok(None),
+                async move {
+                    storage.record_failed_block(hash, verifier_version, &rule)?;
+                    Ok(Response::FailedBlockRecorded)
+                }
+                .boxed()
+            }
+            req => self.read(req),
         }
     }
-
-    fn contains(&self, hash: &BlockHeaderHash) -> Result<bool, Error> {
-        let by_hash = self.storage.open_tree(b"by_hash")?;
-        let key = &hash.0;
-
-        Ok(by_hash.contains_key(key)?)
-    }
 }
 
-impl Service<Request> for SledState {
-    type Response = Response;
-    type Error = Error;
-    type Future =
-        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
-
-    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
-    }
-
-    fn call(&mut self, req: Request) -> Self::Future {
+impl SledState {
+    /// Answer the read-only request `req`.
+    ///
+    /// Reads use the lock-free chain snapshot and concurrent storage reads, so
+    /// they don't wait for block writes. Side chain queries briefly lock the
+    /// non-finalized state.
+    ///
+    /// Returns an error if `req` would modify the state.
+    fn read(&self, req: Request) -> ResponseFuture {
         match req {
-            Request::AddBlock { block } => {
-                let mut storage = self.clone();
+            Request::CommitBlock { .. }
+            | Request::CommitFinalizedBlock { .. }
+            | Request::AddBlockBatch { .. }
+            | Request::RollbackToHeight { .. }
+            | Request::InvalidateBlock { .. }
+            | Request::ReconsiderBlock { .. }
+            | Request::RecordFailedBlock { .. } => async move { Err(ReadOnlyState.into()) }.boxed(),
+            Request::GetBlock { hash } => {
+                let storage = self.clone();
+                async move {
+                    let block = match storage.get(hash)? {
+                        Some(block) => Some(block),
+                        None => storage
+                            .side_chain_block(hash)
+                            .map(|verified| verified.block().clone()),
+                    };
 
-                async move { storage.insert(block).map(|hash| Response::Added { hash }) }.boxed()
+                    block
+                        .map(|block| Response::Block { block })
+                        .ok_or_else(|| "block could not be found".into())
+                }
+                .boxed()
             }
-            Request::GetBlock { hash } => {
+            Request::GetRawBlock { hash } => {
+                let storage = self.clone();
+                async move {
+                    let bytes = match storage.get_bytes(hash)? {
+                        Some(bytes) => Some(bytes),
+                        None => storage
+                            .side_chain_block(hash)
+                            .map(|verified| verified.bytes().to_vec()),
+                    };
+
+                    bytes
+                        .map(|bytes| Response::RawBlock { bytes })
+                        .ok_or_else(|| "block could not be found".into())
+                }
+                .boxed()
+            }
+            Request::BlockInfo { hash } => {
                 let storage = self.clone();
                 async move {
                     storage
-                        .get(hash)?
-                        .map(|block| Response::Block { block })
+                        .block_info(hash)?
+                        .map(Response::BlockInfo)
                         .ok_or_else(|| "block could not be found".into())
                 }
                 .boxed()
             }
-            Request::GetTip => {
+            Request::BlockTransactionInfo { hash } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .transaction_info(hash)?
+                        .map(Response::BlockTransactionInfo)
+                        .ok_or_else(|| "block could not be found".into())
+                }
+                .boxed()
+            }
+            Request::TransactionFee { transaction } => {
+                let storage = self.clone();
+                async move {
+                    transaction_fee(&transaction, |outpoint| {
+                        storage.output_value(outpoint, &HashMap::new())
+                    })
+                    .map(Response::TransactionFee)
+                }
+                .boxed()
+            }
+            Request::AddressBalance { addresses } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .address_balance(&addresses)
+                        .map(Response::AddressBalance)
+                }
+                .boxed()
+            }
+            Request::UtxosByAddresses {
+                addresses,
+                start,
+                limit,
+            } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .address_utxos(&addresses, start, limit)
+                        .map(Response::Utxos)
+                }
+                .boxed()
+            }
+            Request::TransactionIdsByAddresses {
+                addresses,
+                height_range,
+            } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .address_transaction_ids(&addresses, height_range)
+                        .map(Response::TransactionIds)
+                }
+                .boxed()
+            }
+            Request::TransactionMerklePath { txid } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .transaction_merkle_path(txid)
+                        .map(Response::TransactionMerklePath)
+                }
+                .boxed()
+            }
+            Request::MissingIndexRanges { index } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .missing_index_ranges(index)
+                        .map(Response::MissingIndexRanges)
+                }
+                .boxed()
+            }
+            Request::BestChainBlockHash { height } => {
+                let storage = self.clone();
+                async move { storage.best_chain_hash(height).map(Response::BlockHash) }.boxed()
+            }
+            Request::AncestorHash { hash, height } => {
+                let storage = self.clone();
+                async move { storage.ancestor_hash(hash, height).map(Response::BlockHash) }.boxed()
+            }
+            Request::FailedBlock {
+                hash,
+                verifier_version,
+            } => {
                 let storage = self.clone();
                 async move {
                     storage
-                        .get_tip()?
-                        .map(|block| block.as_ref().into())
-                        .map(|hash| Response::Tip { hash })
-                        .ok_or_else(|| "zebra-state contains no blocks".into())
+                        .failed_block_rule(hash, verifier_version)
+                        .map(Response::FailedBlock)
+                }
+                .boxed()
+            }
+            Request::ChainTxStats { hash, window } => {
+                let storage = self.clone();
+                async move {
+                    ChainTxStats::new(
+                        hash,
+                        window,
+                        |hash| storage.best_chain_height(hash),
+                        |height| storage.best_chain_hash(height),
+                        |hash| storage.block_info(hash),
+                        |hash| storage.get_header(hash),
+                    )
+                    .map(Response::ChainTxStats)
+                }
+                .boxed()
+            }
+            Request::FindBlockHashes { known_blocks, stop } => {
+                let storage = self.clone();
+                async move {
+                    crate::find_block_hashes(
+                        &known_blocks,
+                        stop,
+                        |hash| storage.best_chain_height(hash),
+                        |height| storage.best_chain_hash(height),
+                    )
+                }
+                .boxed()
+            }
+            Request::FindBlockHeaders { known_blocks, stop } => {
+                let storage = self.clone();
+                async move {
+                    crate::find_block_headers(
+                        &known_blocks,
+                        stop,
+                        |hash| storage.best_chain_height(hash),
+                        |height| storage.best_chain_hash(height),
+                        |hash| storage.get_header(hash),
+                    )
+                }
+                .boxed()
+            }
+            Request::GetTip => {
+                let snapshot = self.snapshot.load();
+                async move {
+                    Ok(snapshot
+                        .tip()
+                        .map(|(_height, hash)| Response::Tip { hash })
+                        .unwrap_or(Response::Empty))
                 }
                 .boxed()
             }
+            Request::GetChainTips => {
+                let snapshot = self.snapshot.load();
+                let fork_tips = self
+                    .non_finalized
+                    .lock()
+                    .expect("non-finalized state mutex should be unpoisoned")
+                    .fork_tips();
+
+                let tips = snapshot
+                    .tip()
+                    .map(|(height, hash)| ChainTip {
+                        hash,
+                        height,
+                        branch_len: 0,
+                        status: ChainTipStatus::Active,
+                    })
+                    .into_iter()
+                    .chain(fork_tips)
+                    .collect();
+
+                async move { Ok(Response::ChainTips(tips)) }.boxed()
+            }
             Request::GetDepth { hash } => {
                 let storage = self.clone();
+                let snapshot = self.snapshot.load();
 
                 async move {
-                    if !storage.contains(&hash)? {
-                        return Ok(Response::Depth(None));
-                    }
+                    let tip_height = match snapshot.tip() {
+                        Some((tip_height, _)) => tip_height,
+                        None => return Ok(Response::Depth(None)),
+                    };
 
-                    let block = storage
-                        .get(hash)?
-                        .expect("block must be present if contains returned true");
-                    let tip = storage
-                        .get_tip()?
-                        .expect("storage must have a tip if it contains the previous block");
+                    let height = match snapshot.height(&hash) {
+                        Some(height) => height,
+                        None => match storage.best_chain_height(hash)? {
+                            Some(height) => height,
+                            None => return Ok(Response::Depth(None)),
+                        },
+                    };
 
-                    let depth =
-                        tip.coinbase_height().unwrap().0 - block.coinbase_height().unwrap().0;
+                    // The block may have been committed after we loaded the
+                    // snapshot, so it can be above the snapshot's tip.
+                    let depth = tip_height.0.saturating_sub(height.0);
 
                     Ok(Response::Depth(Some(depth)))
                 }
@@ -157,10 +2014,11 @@ impl Service<Request> for SledState {
             }
             Request::GetBlockLocator { genesis } => {
                 let storage = self.clone();
+                let snapshot = self.snapshot.load();
 
                 async move {
-                    let tip = match storage.get_tip()? {
-                        Some(tip) => tip,
+                    let tip_height = match snapshot.tip() {
+                        Some((tip_height, _)) => tip_height,
                         None => {
                             return Ok(Response::BlockLocator {
                                 block_locator: vec![genesis],
@@ -168,19 +2026,19 @@ impl Service<Request> for SledState {
                         }
                     };
 
-                    let tip_height = tip
-                        .coinbase_height()
-                        .expect("tip of the current chain will have a coinbase height");
-
+                    // Locators are requested whenever the syncer restarts,
+                    // so they only use the snapshot and the height index.
+                    // The snapshot covers the densely spaced heights near
+                    // the tip, so only the sparse deep heights are read from
+                    // the index, and no blocks are read.
                     let heights = crate::block_locator_heights(tip_height);
 
                     let block_locator = heights
-                        .map(|height| {
-                            storage.get(height).map(|block| {
-                                block
-                                    .expect("there should be no holes in the current chain")
-                                    .hash()
-                            })
+                        .map(|height| match snapshot.hash(height) {
+                            Some(hash) => Ok(hash),
+                            None => storage.stored_hash(height).map(|hash| {
+                                hash.expect("there should be no holes in the current chain")
+                            }),
                         })
                         .collect::<Result<_, _>>()?;
 
@@ -188,10 +2046,182 @@ impl Service<Request> for SledState {
                 }
                 .boxed()
             }
+            Request::BlockCount => {
+                let storage = self.clone();
+                let snapshot = self.snapshot.load();
+
+                async move {
+                    let count = storage.count()? as u32;
+                    let progress = match snapshot.tip() {
+                        Some((height, hash)) => storage.get_header(hash)?.map(|header| {
+                            SyncProgress::estimate(height, &header, SystemTime::now())
+                        }),
+                        None => None,
+                    };
+
+                    Ok(Response::BlockCount { count, progress })
+                }
+                .boxed()
+            }
+            Request::GetValueBalances => {
+                let storage = self.clone();
+                let snapshot = self.snapshot.load();
+
+                async move {
+                    let balances = match snapshot.tip() {
+                        Some((_height, hash)) => storage.value_balances(hash)?,
+                        None => None,
+                    };
+
+                    Ok(Response::ValueBalances(balances))
+                }
+                .boxed()
+            }
+            Request::NoteCommitmentTrees { height } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .note_trees(height)
+                        .map(Response::NoteCommitmentTrees)
+                }
+                .boxed()
+            }
+            Request::SaplingSubtrees { start_index, limit } => {
+                let storage = self.clone();
+                async move {
+                    storage
+                        .sapling_subtrees(start_index, limit)
+                        .map(Response::SaplingSubtrees)
+                }
+                .boxed()
+            }
+            Request::ShieldedCounts { height } => {
+                let storage = self.clone();
+                let snapshot = self.snapshot.load();
+
+                async move {
+                    let block = match height {
+                        Some(height) => storage.best_chain_hash(height)?.map(|hash| (height, hash)),
+                        None => snapshot.tip(),
+                    };
+                    let counts = match block {
+                        Some((height, hash)) => storage
+                            .shielded_counts(hash)?
+                            .map(|counts| (height, counts)),
+                        None => None,
+                    };
+
+                    Ok(Response::ShieldedCounts(counts))
+                }
+                .boxed()
+            }
+            Request::HistoryTree { height } => {
+                let storage = self.clone();
+                async move { storage.history_tree(height).map(Response::HistoryTree) }.boxed()
+            }
+            Request::CompactNow => {
+                let compacted = self.compact_in_background();
+                async move {
+                    compacted.await??;
+                    Ok(Response::Compacted)
+                }
+                .boxed()
+            }
         }
     }
 }
 
+/// The size of a location in the `transaction_locations` tree.
+const TRANSACTION_LOCATION_SIZE: usize = 8;
+
+/// Returns the ID of each transaction in `block` at `height`, and its
+/// location in the `transaction_locations` tree, in block order.
+///
+/// Each location is the big-endian block height, followed by the big-endian
+/// index of the transaction in the block.
+fn transaction_locations(
+    block: &Block,
+    height: BlockHeight,
+) -> Vec<(TransactionHash, [u8; TRANSACTION_LOCATION_SIZE])> {
+    block
+        .transactions
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| {
+            let mut location = [0; TRANSACTION_LOCATION_SIZE];
+            location[..4].copy_from_slice(&height.0.to_be_bytes());
+            location[4..].copy_from_slice(&(index as u32).to_be_bytes());
+            (TransactionHash::from(tx.as_ref().clone()), location)
+        })
+        .collect()
+}
+
+/// Split serialized block `bytes` into the header and the body, which
+/// contains the transactions.
+fn split_block(bytes: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let mut reader = io::Cursor::new(bytes);
+    BlockHeader::zcash_deserialize(&mut reader)?;
+    let header_len = usize::try_from(reader.position())?;
+
+    Ok(bytes.split_at(header_len))
+}
+
+/// A cloneable, read-only state service.
+///
+/// Answers block, tip, chain, and index queries concurrently with each other
+/// and with block commits, because it doesn't share the single-request buffer
+/// of the state service that writes blocks.
+///
+/// Returns a [`ReadOnlyState`] error for requests that modify the state.
+#[derive(Clone)]
+pub struct ReadStateService {
+    state: SledState,
+    config: Arc<Config>,
+}
+
+impl ReadStateService {
+    /// Back up the state to the directory at `path`, while the state keeps
+    /// committing blocks.
+    ///
+    /// The backup has the same layout as `cache_dir`, and `archive_dir` if it
+    /// is configured, so it can be used as the state directory of another
+    /// node. `path` must not already contain a state for this network.
+    ///
+    /// Blocks until the backup is written, so async callers should run it on
+    /// a blocking thread.
+    pub fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.state
+            .backup(&backup::backup_config(&self.config, path))
+    }
+
+    /// Read the blocks that the primary state has committed since this state
+    /// was opened, or since it last caught up.
+    ///
+    /// Only states from [`init_secondary`] catch up, other states already
+    /// read every committed block. Blocks until the state has caught up, so
+    /// async callers should run it on a blocking thread.
+    pub fn catch_up(&self) -> Result<(), Error> {
+        self.state.catch_up()
+    }
+}
+
+impl Service<Request> for ReadStateService {
+    type Response = Response;
+    type Error = Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        self.state.read(req)
+    }
+}
+
+/// The future returned by the state services in this module.
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response, Error>> + Send + 'static>>;
+
 /// An alternate repr for `BlockHeight` that implements `AsRef<[u8]>` for usage
 /// with sled
 struct BytesHeight(u32, [u8; 4]);
@@ -226,9 +2256,10 @@ impl From<BlockHeight> for BlockQuery {
     }
 }
 
-/// Returns a type that implements the `zebra_state::Service` using `sled`.
+/// Returns a type that implements the `zebra_state::Service`, using the
+/// storage backend in `config`.
 ///
-/// Each `network` has its own separate sled database.
+/// Each `network` has its own separate database.
 pub fn init(
     config: Config,
     network: Network,
@@ -240,7 +2271,277 @@ pub fn init(
 > + Send
        + Clone
        + 'static {
-    Buffer::new(SledState::new(&config, network), 1)
+    init_with_read_service(config, network).0
+}
+
+/// Returns a `zebra_state::Service` using the storage backend in `config`, and
+/// a [`ReadStateService`] for the same database.
+///
+/// The first service handles every request, one request at a time. Use the
+/// read service for read-heavy workloads, so they don't wait behind block
+/// commits.
+///
+/// Each `network` has its own separate database.
+pub fn init_with_read_service(
+    config: Config,
+    network: Network,
+) -> (
+    impl Service<
+            Request,
+            Response = Response,
+            Error = Error,
+            Future = impl Future<Output = Result<Response, Error>>,
+        > + Send
+        + Clone
+        + 'static,
+    ReadStateService,
+) {
+    init_with_layers(config, network, &LayerConfig::default())
+}
+
+/// Returns a `zebra_state::Service` wrapped in the layers in `layers`, and a
+/// [`ReadStateService`] for the same database.
+///
+/// The layers are named `"state"` in metrics and tracing spans. The read
+/// service isn't wrapped, so it stays cheap to clone and call.
+pub fn init_with_layers(
+    config: Config,
+    network: Network,
+    layers: &LayerConfig,
+) -> (
+    impl Service<
+            Request,
+            Response = Response,
+            Error = Error,
+            Future = impl Future<Output = Result<Response, Error>>,
+        > + Send
+        + Clone
+        + 'static,
+    ReadStateService,
+) {
+    let state = SledState::new(&config, network);
+    storage::spawn_upgrader(state.storage.clone());
+    if let Some(archive) = &state.archive {
+        archive.spawn_upgrader();
+        archive::spawn_archiver(&state);
+    }
+    if state.prune_depth.is_some() {
+        prune::spawn_pruner(&state);
+    }
+    let read_service = ReadStateService {
+        state: state.clone(),
+        config: Arc::new(config),
+    };
+
+    (layers.layers("state").layer(state), read_service)
+}
+
+/// Returns a [`ReadStateService`] for the state in `config`, opened
+/// read-only.
+///
+/// Used by tools that inspect a state without modifying it. Requests that
+/// modify the state return a [`ReadOnlyState`] error. The state isn't locked,
+/// upgraded, or repaired, and its old blocks aren't archived or pruned.
+///
+/// RocksDB states can be opened read-only while a node is using them, but
+/// later changes by the node aren't visible. sled states can only be opened
+/// read-only while no node is using them.
+///
+/// # Panics
+///
+/// If the state doesn't exist yet, or it needs a format upgrade or a repair,
+/// which the node does the next time it opens the state.
+pub fn init_read_only(config: Config, network: Network) -> ReadStateService {
+    let config = Config {
+        read_only: true,
+        ..config
+    };
+    let state = SledState::new(&config, network);
+
+    ReadStateService {
+        state,
+        config: Arc::new(config),
+    }
+}
+
+/// Returns a [`ReadStateService`] that follows the RocksDB state in `config`,
+/// while another process writes to it.
+///
+/// Used by indexers and other tools that follow the chain in a separate
+/// process. The secondary state stores its own files in `secondary_dir`,
+/// which must not be used by any other secondary state.
+///
+/// Every request that reads the state is safe to use. Responses reflect the
+/// blocks that were committed when the state was opened, or when
+/// [`ReadStateService::catch_up`] was last called. Requests that run during a
+/// catch-up might see some of the new blocks, but not others, so callers that
+/// need consistent responses should wait for each catch-up to finish.
+/// Requests that modify the state return a [`ReadOnlyState`] error.
+///
+/// # Panics
+///
+/// If the state doesn't use the RocksDB backend, or it can't be opened
+/// read-only. See [`init_read_only`].
+pub fn init_secondary(
+    config: Config,
+    network: Network,
+    secondary_dir: PathBuf,
+) -> ReadStateService {
+    init_read_only(
+        Config {
+            secondary_dir: Some(secondary_dir),
+            ..config
+        },
+        network,
+    )
 }
 
 type Error = Box<dyn error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zebra_chain::{
+        note_commitment_tree::SaplingNoteTreeRootHash,
+        parameters::NetworkUpgrade,
+        serialization::Cached,
+        transaction::{Transaction, TransparentInput},
+    };
+
+    /// Returns a copy of mainnet block 1 at `height`, which follows `parent`,
+    /// and commits to `history_root`.
+    ///
+    /// Blocks with different `nonce`s have different hashes.
+    fn block_at(
+        height: BlockHeight,
+        parent: BlockHeaderHash,
+        history_root: [u8; 32],
+        nonce: u8,
+    ) -> SemanticallyVerifiedBlock {
+        let mut block = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+            .expect("test vector deserializes");
+        let mut coinbase = (*block.transactions[0]).clone();
+        if let Transaction::V1 { inputs, size, .. } = &mut coinbase {
+            if let TransparentInput::Coinbase {
+                height: coinbase_height,
+                ..
+            } = &mut inputs[0]
+            {
+                *coinbase_height = height;
+            }
+            *size = Cached::default();
+        }
+        block.transactions[0] = coinbase.into();
+        block.size = Cached::default();
+        block.header.previous_block_hash = parent;
+        block.header.final_sapling_root_hash = SaplingNoteTreeRootHash(history_root);
+        block.header.nonce[0] = nonce;
+
+        SemanticallyVerifiedBlock::new(block.into()).expect("block serializes")
+    }
+
+    #[test]
+    fn nullifiers_are_indexed_until_their_block_is_removed() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = Config {
+            ephemeral: true,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+
+        // Block 1 with a transaction that spends Sapling notes. Its parent
+        // isn't stored, so its value pool balances aren't checked.
+        let mut block = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?;
+        let spend = Transaction::zcash_deserialize(&zebra_test::vectors::ZIP243_1[..])?;
+        block.transactions.push(spend.into());
+        block.size = Cached::default();
+        let nullifiers = block_nullifiers(&block);
+        assert!(!nullifiers.is_empty());
+
+        state.insert(block)?;
+        for key in &nullifiers {
+            assert_eq!(state.nullifier_height(key)?, Some(BlockHeight(1)));
+        }
+
+        state.remove_from(BlockHeight(1), None)?;
+        for key in &nullifiers {
+            assert_eq!(state.nullifier_height(key)?, None);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn forks_with_bad_history_roots_are_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        let network = Network::Mainnet;
+        let config = Config {
+            ephemeral: true,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, network);
+
+        // The test vectors don't have any blocks after Heartwood activation,
+        // so store a fake activation block with empty note commitment trees
+        let heartwood = NetworkUpgrade::Heartwood
+            .activation_height(network)
+            .expect("Heartwood activates on mainnet");
+        let activation = block_at(heartwood, BlockHeaderHash([1; 32]), [0; 32], 0);
+        let activation_hash = state.insert_verified(activation.clone())?;
+        let trees = NoteCommitmentTrees::default();
+        let history =
+            history_tree::next_history_tree(network, activation.block(), heartwood, None, &trees)?
+                .expect("the activation block starts a new history tree");
+        state.storage.insert(
+            tree::NOTE_COMMITMENT_TREES,
+            &heartwood.0.to_be_bytes(),
+            &trees.to_bytes(),
+        )?;
+        state.storage.insert(
+            tree::HISTORY_TREES,
+            &heartwood.0.to_be_bytes(),
+            &history_tree::to_bytes(&history),
+        )?;
+        state
+            .non_finalized
+            .lock()
+            .expect("non-finalized state mutex should be unpoisoned")
+            .finalize_to(heartwood, activation_hash);
+
+        let height1 = BlockHeight(heartwood.0 + 1);
+        let height2 = BlockHeight(heartwood.0 + 2);
+        let best1 = block_at(height1, activation_hash, history.hash(), 1);
+        let best1_hash = state.commit_block(best1)?;
+
+        // A side chain block is checked against the stored best chain
+        let fork1 = block_at(height1, activation_hash, history.hash(), 2);
+        let fork1_hash = state.commit_block(fork1.clone())?;
+        assert_eq!(state.best_chain_hash(height1)?, Some(best1_hash));
+
+        // Its child is checked against the history tree along the fork, so it
+        // can't make the fork the best chain
+        let bad_fork2 = block_at(height2, fork1_hash, [0xff; 32], 3);
+        assert!(state.commit_block(bad_fork2).is_err());
+        assert_eq!(state.best_chain_hash(height1)?, Some(best1_hash));
+        assert_eq!(state.best_chain_hash(height2)?, None);
+
+        // A child with the correct history root reorganises the best chain
+        let fork_trees = trees.add_block(fork1.block())?;
+        let fork_history = history_tree::next_history_tree(
+            network,
+            fork1.block(),
+            height1,
+            Some(history),
+            &fork_trees,
+        )?
+        .expect("the history tree is known");
+        let fork2 = block_at(height2, fork1_hash, fork_history.hash(), 4);
+        let fork2_hash = state.commit_block(fork2)?;
+        assert_eq!(state.best_chain_hash(height1)?, Some(fork1_hash));
+        assert_eq!(state.best_chain_hash(height2)?, Some(fork2_hash));
+
+        Ok(())
+    }
+}
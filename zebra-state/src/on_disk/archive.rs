@@ -0,0 +1,242 @@
+//! Cold storage tiering for old block data.
+//!
+//! The bodies of blocks that are deep enough below the tip are moved to a
+//! secondary database, which can be on cheaper storage. The `bodies` tree
+//! keeps their keys, but their values are replaced by the block hash, which is
+//! used to look up the body bytes in the archive.
+//!
+//! Block headers are never archived, so header chain queries don't read the
+//! archive.
+use super::{Error, SledState};
+use crate::{
+    storage::{self, key_range, tree, StorageBackend, WriteBatch},
+    Config,
+};
+use std::{convert::TryInto, path::Path, sync::Arc, thread, time::Duration};
+use zebra_chain::{block::BlockHeaderHash, Network};
+
+/// How often the background task moves old blocks to the archive.
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The maximum number of blocks moved in each pass.
+const ARCHIVE_BATCH_SIZE: usize = 1000;
+
+/// The key for the next height to archive, in the `metadata` tree.
+pub(super) const ARCHIVED_HEIGHT_KEY: &[u8] = b"archived_height";
+
+/// The size of a `bodies` value for an archived block.
+///
+/// Serialized block bodies are always larger than a hash, because they
+/// contain a coinbase transaction. Compressed bodies are never stored with
+/// this size.
+pub(super) const ARCHIVED_VALUE_SIZE: usize = 32;
+
+/// The archive tier of the state.
+#[derive(Clone)]
+pub(super) struct Archive {
+    /// Archived block bodies, by hash, in the `blocks` tree.
+    storage: Arc<dyn StorageBackend>,
+    /// The minimum depth of archived blocks.
+    depth: u32,
+}
+
+impl Archive {
+    /// Open the archive for `network`, if an archive directory is configured,
+    /// and the storage backend supports archives.
+    pub(super) fn open(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        Ok(storage::open_archive(config, network)?.map(|storage| Self {
+            storage,
+            depth: config.archive_depth,
+        }))
+    }
+
+    /// Remove the archived bodies of the blocks with `hashes`.
+    pub(super) fn remove(&self, hashes: &[BlockHeaderHash]) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        for hash in hashes {
+            batch.remove(tree::BLOCKS, &hash.0[..]);
+        }
+        self.storage.write_batch(batch)
+    }
+
+    /// Write a consistent copy of the archive to a new database at `path`.
+    pub(super) fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.storage.backup(path)
+    }
+
+    /// Read the blocks that the primary state has archived, if this is a
+    /// secondary state.
+    pub(super) fn catch_up(&self) -> Result<(), Error> {
+        self.storage.catch_up()
+    }
+
+    /// Spawn a background thread that runs the format upgrades of the
+    /// archive, if there are any.
+    pub(super) fn spawn_upgrader(&self) {
+        storage::spawn_upgrader(self.storage.clone());
+    }
+}
+
+/// Returns the body bytes for a `bodies` value, looking up archived bodies in
+/// `archive`.
+pub(super) fn body_bytes(archive: Option<&Archive>, value: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if value.len() != ARCHIVED_VALUE_SIZE {
+        return Ok(value);
+    }
+
+    let archive = archive.ok_or("block has been archived, but archive_dir is not configured")?;
+    let bytes = archive
+        .storage
+        .read(tree::BLOCKS, &value)?
+        .ok_or("archived block is missing from archive_dir")?;
+    Ok(bytes)
+}
+
+impl SledState {
+    /// Move the bodies of a batch of blocks that are deeper than the archive
+    /// depth to the archive.
+    ///
+    /// Returns the number of blocks that were moved.
+    pub(super) fn archive_old_blocks(&self) -> Result<usize, Error> {
+        let archive = match &self.archive {
+            Some(archive) => archive,
+            None => return Ok(0),
+        };
+        let end = match self.snapshot.load().tip() {
+            Some((tip_height, _)) => match tip_height.0.checked_sub(archive.depth) {
+                Some(end) => end,
+                None => return Ok(0),
+            },
+            None => return Ok(0),
+        };
+
+        let start = self.archived_height()?;
+        if start >= end {
+            return Ok(0);
+        }
+
+        let mut archived = WriteBatch::default();
+        let mut moved = WriteBatch::default();
+        let mut next_height = start;
+        let range = key_range(start.to_be_bytes()..end.to_be_bytes());
+        for entry in self
+            .storage
+            .iterate(tree::BY_HEIGHT, range)?
+            .take(ARCHIVE_BATCH_SIZE)
+        {
+            let (key, hash) = entry?;
+            next_height = u32::from_be_bytes(key.as_slice().try_into()?) + 1;
+            let body = match self.storage.read(tree::BODIES, &hash)? {
+                Some(body) if body.len() != ARCHIVED_VALUE_SIZE => body,
+                _ => continue,
+            };
+
+            archived.insert(tree::BLOCKS, &hash, &body);
+            // Skip blocks that were removed while we were copying
+            moved.replace(tree::BODIES, &hash, &body, &hash);
+        }
+        let count = archived.ops.len();
+
+        // The blocks must be safely in the archive before they are removed
+        // from the hot tier.
+        archive.storage.write_batch(archived)?;
+        archive.storage.flush()?;
+
+        self.storage.write_batch(moved)?;
+        self.storage.insert(
+            tree::METADATA,
+            ARCHIVED_HEIGHT_KEY,
+            &next_height.to_be_bytes(),
+        )?;
+
+        Ok(count)
+    }
+
+    /// Returns the next height to archive.
+    ///
+    /// Blocks below this height have already been moved to the archive.
+    fn archived_height(&self) -> Result<u32, Error> {
+        match self.storage.read(tree::METADATA, ARCHIVED_HEIGHT_KEY)? {
+            Some(bytes) => Ok(u32::from_be_bytes(bytes.as_slice().try_into()?)),
+            None => Ok(0),
+        }
+    }
+
+    /// Make sure blocks at `height` and above are archived again, after they
+    /// were removed from the state.
+    pub(super) fn reset_archived_height(&self, height: u32) -> Result<(), Error> {
+        if self.archive.is_some() && self.archived_height()? > height {
+            self.storage
+                .insert(tree::METADATA, ARCHIVED_HEIGHT_KEY, &height.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Spawn a background thread that periodically moves old blocks from `state`
+/// to the archive.
+///
+/// The thread exits after the last clone of `state` is dropped.
+pub(super) fn spawn_archiver(state: &SledState) {
+    let state = state.downgrade();
+    thread::Builder::new()
+        .name("zebra-state-archive".into())
+        .spawn(move || loop {
+            let result = match SledState::upgrade(&state) {
+                Some(state) => state.archive_old_blocks(),
+                None => return,
+            };
+            match result {
+                Ok(0) => thread::sleep(ARCHIVE_INTERVAL),
+                Ok(count) => tracing::debug!(count, "moved old blocks to archive_dir"),
+                Err(error) => {
+                    tracing::warn!(?error, "failed to move old blocks to archive_dir");
+                    thread::sleep(ARCHIVE_INTERVAL);
+                }
+            }
+        })
+        .expect("spawning the archive thread should succeed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize};
+
+    #[test]
+    fn archived_blocks_are_readable() -> Result<(), Error> {
+        zebra_test::init();
+
+        let hot_dir = TempDir::new("")?;
+        let archive_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(hot_dir.path().to_owned()),
+            archive_dir: Some(archive_dir.path().to_owned()),
+            archive_depth: 0,
+            // Read blocks from storage, rather than the block cache
+            block_cache_bytes: 0,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        let hash0 = state.insert(block0.clone())?;
+        state.insert(block1)?;
+
+        assert_eq!(state.archive_old_blocks()?, 1);
+        assert_eq!(state.archive_old_blocks()?, 0);
+
+        assert_eq!(state.get_header(hash0)?, Some(block0.header));
+        assert_eq!(state.get(hash0)?, Some(block0.clone()));
+        assert_eq!(state.get(zebra_chain::types::BlockHeight(0))?, Some(block0));
+        assert_eq!(state.count()?, 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Backups of the on-disk state, taken while the state is running.
+//!
+//! A backup has the same layout as `cache_dir`, and `archive_dir` if it is
+//! configured, so it can be used as the state directory of another node.
+//! Backups don't block block commits. RocksDB backups are checkpoints. sled
+//! backups are copied while commits continue, then the values that commits
+//! replaced during the copy are restored.
+use super::{Error, SledState};
+use crate::Config;
+use std::{fs, path::Path};
+use zebra_chain::Network;
+
+impl SledState {
+    /// Back up this state to the databases in `backup_config`.
+    pub(super) fn backup(&self, backup_config: &Config) -> Result<(), Error> {
+        let path = backup_config
+            .storage_path(self.network)
+            .ok_or("the memory storage backend can't be backed up")?;
+        create_parent_dir(&path)?;
+        self.storage.backup(&path)?;
+
+        // Bodies are written to the archive before they are removed from the
+        // state, so an archive backup taken after the state backup contains
+        // every body that the state backup has archived.
+        if let Some(archive) = &self.archive {
+            let path = backup_config
+                .archive_storage_path(self.network)
+                .ok_or("the storage backend doesn't support archives")?;
+            create_parent_dir(&path)?;
+            archive.backup(&path)?;
+        }
+
+        tracing::info!(
+            path = ?backup_config.cache_dir,
+            tip_height = ?self.tip_height(),
+            "backed up state"
+        );
+
+        Ok(())
+    }
+}
+
+/// Check that there is no database at `path`, and create its parent
+/// directories.
+fn create_parent_dir(path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        Err(format!(
+            "the backup directory already contains a state: {}",
+            path.display()
+        ))?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the config for a backup in the directory at `path`, of a state
+/// that uses `config`.
+pub(super) fn backup_config(config: &Config, path: &Path) -> Config {
+    Config {
+        cache_dir: Some(path.to_owned()),
+        archive_dir: config.archive_dir.as_ref().map(|_| path.to_owned()),
+        ..config.clone()
+    }
+}
+
+/// Back up the state for `network` to the directory at `path`.
+///
+/// The state is opened by this function, so Zebra must not be running. To
+/// back up a running node, use `ReadStateService::backup`.
+pub fn backup_state(config: &Config, network: Network, path: &Path) -> Result<(), Error> {
+    SledState::new(config, network).backup(&backup_config(config, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, types::BlockHeight};
+
+    #[test]
+    fn backup_contains_committed_blocks() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        state.insert(block0.clone())?;
+
+        let backup_dir = TempDir::new("")?;
+        let backup_config = backup_config(&config, backup_dir.path());
+        state.backup(&backup_config)?;
+
+        // Blocks committed after the backup aren't in the backup
+        state.insert(block1)?;
+        // Existing backups aren't overwritten
+        assert!(state.backup(&backup_config).is_err());
+        drop(state);
+
+        let backup = SledState::new(&backup_config, Network::Mainnet);
+        assert_eq!(backup.tip_height(), Some(BlockHeight(0)));
+        assert_eq!(backup.get(BlockHeight(0))?, Some(block0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Scheduling for storage backend compactions.
+//!
+//! Compactions during the initial sync can stall block commits for several
+//! seconds. If `pause_compaction_during_sync` is enabled, automatic
+//! compaction is paused while the tip is far behind the estimated network
+//! tip, and resumed when the state catches up. Operators can also compact the
+//! state at a quiet time using a `CompactNow` request.
+use super::{Error, SledState};
+use crate::SyncProgress;
+use futures::channel::oneshot;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::SystemTime,
+};
+use zebra_chain::{block::BlockHeader, types::BlockHeight};
+
+/// Compaction is paused while the tip is more than this many blocks behind the
+/// estimated network tip.
+///
+/// This is about a day of blocks, so compaction isn't paused again when the
+/// node restarts after a short outage.
+const PAUSE_COMPACTION_DISTANCE: u32 = 1_000;
+
+impl SledState {
+    /// Pause or resume automatic compaction, after committing the block with
+    /// `header` at `height`.
+    ///
+    /// Does nothing unless `pause_compaction_during_sync` is enabled.
+    pub(super) fn update_compaction(&self, height: BlockHeight, header: &BlockHeader) {
+        let paused = match &self.compaction_paused {
+            Some(paused) => paused,
+            None => return,
+        };
+
+        let progress = SyncProgress::estimate(height, header, SystemTime::now());
+        let behind = progress
+            .estimated_height
+            .0
+            .saturating_sub(progress.tip_height.0);
+        let syncing = behind > PAUSE_COMPACTION_DISTANCE;
+        if paused.swap(syncing, Ordering::SeqCst) == syncing {
+            return;
+        }
+
+        match self.storage.set_auto_compaction(!syncing) {
+            Ok(()) if syncing => tracing::info!(
+                ?height,
+                "pausing automatic compaction until the state is close to the network tip"
+            ),
+            Ok(()) => tracing::info!(?height, "resuming automatic compaction"),
+            Err(error) => {
+                tracing::warn!(?error, "failed to change automatic compaction");
+                // Try again after the next block
+                paused.store(!syncing, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Compact the whole state in a background thread.
+    ///
+    /// Returns a receiver for the result of the compaction.
+    pub(super) fn compact_in_background(&self) -> oneshot::Receiver<Result<(), Error>> {
+        let (tx, rx) = oneshot::channel();
+        let storage = self.storage.clone();
+
+        let spawned = thread::Builder::new()
+            .name("zebra-state-compact".into())
+            .spawn(move || {
+                tracing::info!("compacting the state");
+                let result = storage.compact();
+                tracing::info!(?result, "finished compacting the state");
+                let _ = tx.send(result);
+            });
+        if let Err(error) = spawned {
+            tracing::warn!(?error, "failed to spawn the compaction thread");
+        }
+
+        rx
+    }
+}
+
+/// Returns the initial pause state for `pause_compaction_during_sync`.
+///
+/// Compaction starts enabled, and is paused after the first committed block
+/// if the state is still syncing.
+pub(super) fn compaction_pause(pause_compaction_during_sync: bool) -> Option<Arc<AtomicBool>> {
+    if pause_compaction_during_sync {
+        Some(Arc::new(AtomicBool::new(false)))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, StorageBackendKind};
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, Network};
+
+    #[test]
+    fn compaction_is_paused_while_syncing() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            storage_backend: StorageBackendKind::RocksDb,
+            pause_compaction_during_sync: true,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+
+        // The genesis block is years old, so the state is syncing
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        state.insert(block0)?;
+        let paused = state
+            .compaction_paused
+            .as_ref()
+            .expect("pausing is enabled");
+        assert!(paused.load(Ordering::SeqCst));
+
+        futures::executor::block_on(state.compact_in_background())??;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! Optional compression of stored block bodies.
+//!
+//! If `compress_block_bodies` is enabled, block bodies are compressed with
+//! zstd before they are written to the `bodies` tree. Compressed values start
+//! with a tag byte, so states can contain a mix of compressed and
+//! uncompressed bodies, and the option can be changed at any time.
+use super::{archive::ARCHIVED_VALUE_SIZE, Error};
+use std::borrow::Cow;
+
+/// The zstd compression level for block bodies.
+///
+/// Low levels are nearly as effective as high levels for block data, and
+/// they don't slow down block commits.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The first byte of a compressed body.
+///
+/// Serialized block bodies never start with a zero byte, because their
+/// transaction count includes the coinbase transaction.
+const COMPRESSED_TAG: u8 = 0;
+
+/// Returns the value to store for the serialized block `body`.
+///
+/// If `enabled` is false, or compression doesn't make the body smaller, the
+/// body is stored unchanged. Compressed values never have the size of an
+/// archived value.
+pub(super) fn compress_body(body: &[u8], enabled: bool) -> Result<Cow<'_, [u8]>, Error> {
+    if !enabled {
+        return Ok(Cow::Borrowed(body));
+    }
+
+    let mut value = vec![COMPRESSED_TAG];
+    value.extend(zstd::stream::encode_all(body, COMPRESSION_LEVEL)?);
+    if value.len() >= body.len() || value.len() == ARCHIVED_VALUE_SIZE {
+        return Ok(Cow::Borrowed(body));
+    }
+
+    metrics::counter!(
+        "state.body_compression.saved.bytes",
+        (body.len() - value.len()) as u64
+    );
+    Ok(Cow::Owned(value))
+}
+
+/// Returns the serialized block body for a stored `bodies` value.
+///
+/// Archived values must be looked up in the archive first.
+pub(super) fn decompress_body(value: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match value.split_first() {
+        Some((&COMPRESSED_TAG, compressed)) => Ok(zstd::stream::decode_all(compressed)?),
+        _ => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SledState;
+    use super::*;
+    use crate::Config;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, Network};
+
+    #[test]
+    fn bodies_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let body = [&[1u8][..], &[0xab; 1000][..]].concat();
+        let value = compress_body(&body, true)?;
+        assert_eq!(value[0], COMPRESSED_TAG);
+        assert!(value.len() < body.len());
+        assert_eq!(decompress_body(value.into_owned())?, body);
+
+        // Uncompressed bodies are read unchanged
+        assert_eq!(compress_body(&body, false)?, Cow::Borrowed(&body[..]));
+        assert_eq!(decompress_body(body.clone())?, body);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_blocks_are_readable() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            compress_block_bodies: true,
+            // Read blocks from storage, rather than the block cache
+            block_cache_bytes: 0,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        let hash0 = state.insert(block0.clone())?;
+        let hash1 = state.insert(block1.clone())?;
+
+        assert_eq!(state.get(hash0)?, Some(block0));
+        assert_eq!(state.get(hash1)?, Some(block1));
+
+        Ok(())
+    }
+}
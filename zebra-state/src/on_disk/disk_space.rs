@@ -0,0 +1,220 @@
+//! Free disk space checks for the on-disk state.
+//!
+//! Storage backends can panic or corrupt their files when the disk fills up
+//! in the middle of a write. So the state checks the free space in the state
+//! directory when it is opened, and before it commits blocks, and stops
+//! committing blocks with an [`OutOfDiskSpace`] error while the free space is
+//! below `min_free_disk_bytes`.
+use super::{Error, SledState};
+use crate::Config;
+use std::{
+    error, fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use zebra_chain::Network;
+
+/// The free space is checked at most once per interval while it is above the
+/// warning threshold.
+///
+/// Syncing nodes commit many blocks per second, and each block is small
+/// compared to the warning threshold, so checking before every block isn't
+/// needed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The free space in the state directory is below `min_free_disk_bytes`.
+///
+/// The state doesn't commit any more blocks until there is enough free
+/// space, so the database isn't corrupted by a full disk.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutOfDiskSpace {
+    /// The state directory
+    pub path: PathBuf,
+    /// The free space available to Zebra, in bytes
+    pub available_bytes: u64,
+    /// The minimum free space for block commits, in bytes
+    pub min_free_bytes: u64,
+}
+
+impl fmt::Display for OutOfDiskSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "not enough free disk space for the state in {}: {} bytes are available, \
+             but at least {} bytes are needed. Free up some disk space, \
+             or lower `state.min_free_disk_bytes`",
+            self.path.display(),
+            self.available_bytes,
+            self.min_free_bytes
+        )
+    }
+}
+
+impl error::Error for OutOfDiskSpace {}
+
+impl OutOfDiskSpace {
+    /// Returns the disk space error in `error`, if it is an
+    /// [`OutOfDiskSpace`] error.
+    pub fn from_error(error: &(dyn error::Error + 'static)) -> Option<&OutOfDiskSpace> {
+        error.downcast_ref()
+    }
+}
+
+/// Checks the free disk space in the state directory.
+pub(super) struct DiskSpaceMonitor {
+    /// The state directory, which might not exist yet
+    path: PathBuf,
+    /// Log a warning below this many free bytes
+    warning_bytes: u64,
+    /// Stop committing blocks below this many free bytes
+    min_free_bytes: u64,
+    /// When the free space was last checked, and whether it was above the
+    /// warning threshold
+    last_check: Mutex<Option<(Instant, bool)>>,
+}
+
+impl DiskSpaceMonitor {
+    /// Returns a monitor for the state directory for `network`, or `None` if
+    /// the state isn't stored on disk.
+    pub(super) fn new(config: &Config, network: Network) -> Option<Self> {
+        Some(Self {
+            path: config.storage_path(network)?,
+            warning_bytes: config.disk_space_warning_bytes,
+            min_free_bytes: config.min_free_disk_bytes,
+            last_check: Mutex::new(None),
+        })
+    }
+
+    /// Check the free space now, logging a warning if it is low.
+    ///
+    /// Returns an error if there isn't enough space to commit blocks. If the
+    /// free space can't be checked, logs a warning, and returns `Ok`.
+    pub(super) fn check_now(&self) -> Result<(), Error> {
+        let available_bytes = match available_space(&self.path) {
+            Ok(available_bytes) => available_bytes,
+            Err(error) => {
+                tracing::warn!(?error, path = ?self.path, "failed to check the free disk space");
+                return Ok(());
+            }
+        };
+        metrics::gauge!("state.disk.available.bytes", available_bytes as i64);
+
+        let is_plentiful = available_bytes >= self.warning_bytes;
+        *self.last_check.lock().expect("mutex should be unpoisoned") =
+            Some((Instant::now(), is_plentiful));
+
+        if available_bytes < self.min_free_bytes {
+            tracing::error!(
+                path = ?self.path,
+                available_bytes,
+                min_free_bytes = self.min_free_bytes,
+                "the disk is nearly full, so the state has stopped committing blocks"
+            );
+            Err(OutOfDiskSpace {
+                path: self.path.clone(),
+                available_bytes,
+                min_free_bytes: self.min_free_bytes,
+            })?;
+        } else if !is_plentiful {
+            tracing::warn!(
+                path = ?self.path,
+                available_bytes,
+                min_free_bytes = self.min_free_bytes,
+                "the disk is running out of space, \
+                 the state will stop committing blocks below the minimum"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check the free space, if it is low, or it hasn't been checked
+    /// recently.
+    pub(super) fn check(&self) -> Result<(), Error> {
+        let last_check = *self.last_check.lock().expect("mutex should be unpoisoned");
+        match last_check {
+            Some((checked_at, true)) if checked_at.elapsed() < CHECK_INTERVAL => Ok(()),
+            _ => self.check_now(),
+        }
+    }
+}
+
+/// Returns the free space available to Zebra on the disk that contains
+/// `path`.
+///
+/// If `path` doesn't exist yet, checks the closest directory above it that
+/// does.
+fn available_space(path: &Path) -> Result<u64, Error> {
+    let existing = path
+        .ancestors()
+        .find(|dir| dir.exists())
+        .ok_or_else(|| format!("no directory above {} exists", path.display()))?;
+
+    Ok(fs2::available_space(existing)?)
+}
+
+impl SledState {
+    /// Returns an error if there isn't enough free disk space to commit
+    /// blocks.
+    pub(super) fn check_disk_space(&self) -> Result<(), Error> {
+        match &self.disk_space {
+            Some(monitor) => monitor.check(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Panics with a diagnostic if there isn't enough free disk space to open the
+/// state checked by `monitor`.
+///
+/// Opening the state writes to it, so this check happens before the storage
+/// backend is opened.
+pub(super) fn expect_space_to_open(monitor: Option<&DiskSpaceMonitor>) {
+    if let Some(monitor) = monitor {
+        if let Err(error) = monitor.check_now() {
+            panic!("{}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, types::BlockHeight};
+
+    #[test]
+    fn commits_stop_when_the_disk_is_full() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+
+        // Require more free space than any disk has
+        Arc::get_mut(&mut state.shared)
+            .expect("the state has no other clones")
+            .disk_space = DiskSpaceMonitor::new(
+            &Config {
+                min_free_disk_bytes: u64::MAX,
+                ..config
+            },
+            Network::Mainnet,
+        )
+        .map(Arc::new);
+
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let error = state.insert(block0).unwrap_err();
+        let out_of_space = OutOfDiskSpace::from_error(error.as_ref()).expect("a disk space error");
+        assert_eq!(out_of_space.min_free_bytes, u64::MAX);
+        assert!(state.get(BlockHeight(0))?.is_none());
+
+        Ok(())
+    }
+}
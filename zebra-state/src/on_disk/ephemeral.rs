@@ -0,0 +1,82 @@
+//! Ephemeral states, which are deleted when the state is dropped.
+//!
+//! Integration tests and short-lived sessions don't need to keep the state,
+//! so they can set [`Config::ephemeral`] instead of choosing a `cache_dir`.
+//! The state is stored in a new temporary directory, which is deleted when
+//! every clone of the state has been dropped, on a clean shutdown. If zebrad
+//! is killed, the directory is left in the system's temporary directory.
+use super::Error;
+use crate::{Config, StorageBackendKind};
+use std::{borrow::Cow, sync::Arc};
+use tempdir::TempDir;
+
+/// Returns the config for opening the state in `config`, and the temporary
+/// directory that stores it, if the state is ephemeral.
+///
+/// Ephemeral states are stored in the new temporary directory, so its
+/// `cache_dir`, and `archive_dir` if it is configured, are replaced.
+pub(super) fn ephemeral_config(
+    config: &Config,
+) -> Result<(Cow<'_, Config>, Option<Arc<TempDir>>), Error> {
+    if !config.ephemeral || config.storage_backend == StorageBackendKind::Memory {
+        return Ok((Cow::Borrowed(config), None));
+    }
+    if config.read_only {
+        Err("an ephemeral state is always empty, so it can't be opened read-only")?;
+    }
+
+    let dir = TempDir::new("zebra-state")?;
+    let path = dir.path().to_owned();
+    tracing::info!(path = ?path, "created ephemeral state directory");
+
+    let config = Config {
+        cache_dir: Some(path.clone()),
+        archive_dir: config.archive_dir.as_ref().map(|_| path.join("archive")),
+        ..config.clone()
+    };
+    Ok((Cow::Owned(config), Some(Arc::new(dir))))
+}
+
+/// Returns the config and temporary directory for the state in `config`,
+/// like [`ephemeral_config`].
+///
+/// # Panics
+///
+/// If the temporary directory can't be created.
+pub(super) fn expect_ephemeral_config(config: &Config) -> (Cow<'_, Config>, Option<Arc<TempDir>>) {
+    ephemeral_config(config).expect("the ephemeral state directory should be created")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SledState;
+    use super::*;
+    use zebra_chain::Network;
+
+    #[test]
+    fn ephemeral_states_are_deleted_on_drop() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = Config {
+            cache_dir: None,
+            ephemeral: true,
+            ..Config::default()
+        };
+        let state = SledState::new(&config, Network::Mainnet);
+        let path = state
+            ._ephemeral_dir
+            .as_ref()
+            .expect("the state is ephemeral")
+            .path()
+            .to_owned();
+        assert!(path.join("mainnet").exists());
+
+        let clone = state.clone();
+        drop(state);
+        assert!(path.exists());
+        drop(clone);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Blocks that failed verification.
+//!
+//! The chain verifier records the blocks that break a consensus rule, so they
+//! are rejected without being verified again when zebrad restarts, or when
+//! peers advertise them again. Each record has the version of the verifier
+//! that rejected the block. Records from other verifier versions are ignored,
+//! and removed when the next failure is recorded, so blocks are verified again
+//! after the rules change.
+use super::{Error, SledState};
+use crate::{
+    storage::{all_keys, tree, WriteBatch},
+    MAX_FAILED_BLOCKS,
+};
+use std::{convert::TryInto, str};
+use zebra_chain::block::BlockHeaderHash;
+
+/// The size of the verifier version at the start of each record.
+const VERIFIER_VERSION_LEN: usize = 4;
+
+/// Returns the verifier version in the stored failed block record `bytes`.
+fn stored_verifier_version(bytes: &[u8]) -> Result<u32, Error> {
+    if bytes.len() < VERIFIER_VERSION_LEN {
+        Err("stored failed block record is too short")?;
+    }
+
+    Ok(u32::from_be_bytes(
+        bytes[..VERIFIER_VERSION_LEN].try_into()?,
+    ))
+}
+
+impl SledState {
+    /// Record that the block with `hash` broke `rule`, when it was verified
+    /// by `verifier_version`.
+    ///
+    /// Removes the records from other verifier versions. If there are already
+    /// `MAX_FAILED_BLOCKS` records, the failure isn't recorded.
+    pub(super) fn record_failed_block(
+        &self,
+        hash: BlockHeaderHash,
+        verifier_version: u32,
+        rule: &str,
+    ) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        let mut current = 0;
+        for entry in self.storage.iterate(tree::FAILED_BLOCKS, all_keys())? {
+            let (key, value) = entry?;
+            if stored_verifier_version(&value)? == verifier_version {
+                current += 1;
+            } else {
+                batch.remove(tree::FAILED_BLOCKS, key);
+            }
+        }
+
+        if current < MAX_FAILED_BLOCKS {
+            let mut value = verifier_version.to_be_bytes().to_vec();
+            value.extend_from_slice(rule.as_bytes());
+            batch.insert(tree::FAILED_BLOCKS, &hash.0[..], value);
+        } else {
+            tracing::debug!(?hash, "too many failed blocks, not recording the failure");
+        }
+
+        self.storage.write_batch(batch)
+    }
+
+    /// Returns the consensus rule broken by the block with `hash`, if it
+    /// failed verification with `verifier_version`.
+    pub(super) fn failed_block_rule(
+        &self,
+        hash: BlockHeaderHash,
+        verifier_version: u32,
+    ) -> Result<Option<String>, Error> {
+        let value = match self.storage.read(tree::FAILED_BLOCKS, &hash.0)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if stored_verifier_version(&value)? != verifier_version {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            str::from_utf8(&value[VERIFIER_VERSION_LEN..])?.to_owned(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, StorageBackendKind};
+    use zebra_chain::Network;
+
+    #[test]
+    fn failures_expire_with_the_verifier_version() -> Result<(), Error> {
+        zebra_test::init();
+
+        let state = SledState::new(
+            &Config {
+                storage_backend: StorageBackendKind::Memory,
+                ..Config::default()
+            },
+            Network::Mainnet,
+        );
+        let hash = BlockHeaderHash([1; 32]);
+        let other_hash = BlockHeaderHash([2; 32]);
+
+        state.record_failed_block(hash, 1, "bad coinbase")?;
+        assert_eq!(
+            state.failed_block_rule(hash, 1)?,
+            Some("bad coinbase".to_owned())
+        );
+        assert_eq!(state.failed_block_rule(hash, 2)?, None);
+        assert_eq!(state.failed_block_rule(other_hash, 1)?, None);
+
+        // Recording a failure with a new verifier removes the old records
+        state.record_failed_block(other_hash, 2, "bad height")?;
+        assert_eq!(
+            state
+                .storage
+                .iterate(tree::FAILED_BLOCKS, all_keys())?
+                .count(),
+            1
+        );
+        assert_eq!(
+            state.failed_block_rule(other_hash, 2)?,
+            Some("bad height".to_owned())
+        );
+
+        Ok(())
+    }
+}
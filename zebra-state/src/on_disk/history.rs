@@ -0,0 +1,150 @@
+//! The history of the Zebra versions that have opened the on-disk state.
+//!
+//! Each time the state is opened, the time, the Zebra version, and its state
+//! format version are appended to the `database_history` tree. Format upgrades
+//! show up as a change in the format version between records, so the history
+//! shows which releases and upgrades a corrupt state has been through.
+//!
+//! The history describes the local database, rather than the chain, so it is
+//! not included in snapshots or copied by snapshot imports.
+use super::{lock, Error};
+use crate::{
+    storage::{self, all_keys, tree, StorageBackend},
+    Config, STATE_FORMAT_VERSION,
+};
+use std::{
+    convert::TryInto,
+    str,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use zebra_chain::Network;
+
+/// The Zebra version that is recorded when this code opens the state.
+const ZEBRA_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The size of the fixed fields at the start of each record.
+const FIXED_FIELDS_LEN: usize = 12;
+
+/// A record of a Zebra version that opened the state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpenRecord {
+    /// When the state was opened, rounded down to the second
+    pub opened_at: SystemTime,
+    /// The version of Zebra that opened the state
+    pub zebra_version: String,
+    /// The state format version used by that Zebra version
+    pub format_version: u32,
+}
+
+impl OpenRecord {
+    /// Returns a record of this Zebra version opening the state now.
+    fn now() -> Self {
+        Self {
+            opened_at: SystemTime::now(),
+            zebra_version: ZEBRA_VERSION.to_owned(),
+            format_version: STATE_FORMAT_VERSION,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let opened_at = self
+            .opened_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut bytes = Vec::with_capacity(FIXED_FIELDS_LEN + self.zebra_version.len());
+        bytes.extend_from_slice(&opened_at.to_be_bytes());
+        bytes.extend_from_slice(&self.format_version.to_be_bytes());
+        bytes.extend_from_slice(self.zebra_version.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < FIXED_FIELDS_LEN {
+            Err("stored database history record is too short")?;
+        }
+
+        let opened_at = u64::from_be_bytes(bytes[0..8].try_into()?);
+        Ok(Self {
+            opened_at: UNIX_EPOCH + Duration::from_secs(opened_at),
+            format_version: u32::from_be_bytes(bytes[8..12].try_into()?),
+            zebra_version: str::from_utf8(&bytes[FIXED_FIELDS_LEN..])?.to_owned(),
+        })
+    }
+}
+
+/// Append a record of this Zebra version opening `storage` to its history.
+pub(super) fn record_open(storage: &dyn StorageBackend) -> Result<(), Error> {
+    // Records are numbered from zero, so the next record is numbered by the
+    // count of existing records
+    let sequence = storage.iterate(tree::DATABASE_HISTORY, all_keys())?.count();
+    let sequence: u32 = sequence.try_into()?;
+
+    storage.insert(
+        tree::DATABASE_HISTORY,
+        &sequence.to_be_bytes(),
+        &OpenRecord::now().to_bytes(),
+    )?;
+    storage.flush()
+}
+
+/// Returns the history of `storage`, oldest first.
+fn read_history(storage: &dyn StorageBackend) -> Result<Vec<OpenRecord>, Error> {
+    storage
+        .iterate(tree::DATABASE_HISTORY, all_keys())?
+        .map(|entry| OpenRecord::from_bytes(&entry?.1))
+        .collect()
+}
+
+/// Returns the history of the state for `network`, oldest first.
+///
+/// Returns a `StateLocked` error if the state is in use by another process.
+/// Reading the history doesn't add a record to it.
+pub fn database_history(config: &Config, network: Network) -> Result<Vec<OpenRecord>, Error> {
+    let path = config
+        .storage_path(network)
+        .ok_or("the memory storage backend doesn't keep a database history")?;
+    if !path.exists() {
+        Err(format!("there is no state at {}", path.display()))?;
+    }
+
+    let _lock = lock::lock_state(config, network)?;
+    let storage = storage::open(config, network)?;
+    read_history(storage.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SledState;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn each_open_is_recorded() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        assert!(database_history(&config, Network::Mainnet).is_err());
+
+        drop(SledState::new(&config, Network::Mainnet));
+        drop(SledState::new(&config, Network::Mainnet));
+
+        let history = database_history(&config, Network::Mainnet)?;
+        assert_eq!(history.len(), 2);
+        for record in &history {
+            assert_eq!(record.zebra_version, ZEBRA_VERSION);
+            assert_eq!(record.format_version, STATE_FORMAT_VERSION);
+        }
+        assert!(history[0].opened_at <= history[1].opened_at);
+
+        // Reading the history doesn't add a record
+        assert_eq!(database_history(&config, Network::Mainnet)?.len(), 2);
+
+        Ok(())
+    }
+}
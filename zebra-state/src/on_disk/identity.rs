@@ -0,0 +1,270 @@
+//! A record of the network and Zebra version that created the on-disk state.
+//!
+//! States are stored in a directory named after their network, but users can
+//! move or copy state directories, so the name isn't a reliable guard. When
+//! the state is first opened, the network, the state format version, and the
+//! Zebra version are written to the `metadata` tree. The genesis hash is
+//! added the first time the state is opened after its genesis block has been
+//! committed.
+//!
+//! Each later open checks that the state is for the configured network, and
+//! that its genesis block hasn't changed. The format version is checked by
+//! the storage upgrader, which refuses to open states from newer versions.
+use super::Error;
+use crate::storage::{tree, StorageBackend};
+use crate::STATE_FORMAT_VERSION;
+use std::{convert::TryInto, error, fmt, path::PathBuf, str};
+use zebra_chain::{block::BlockHeaderHash, Network};
+
+/// The `metadata` key for the identity record.
+pub(super) const IDENTITY_KEY: &[u8] = b"state_identity";
+
+/// The Zebra version that is recorded when this code creates the state.
+const ZEBRA_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The size of the fixed fields at the start of the record.
+const FIXED_FIELDS_LEN: usize = 38;
+
+/// The network, genesis block, and versions that created a state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct StateIdentity {
+    /// The network of the blocks in the state
+    network: Network,
+    /// The state format version when the state was created
+    format_version: u32,
+    /// The hash of the genesis block, if it was committed when the state was
+    /// last opened
+    genesis_hash: Option<BlockHeaderHash>,
+    /// The version of Zebra that created the state
+    zebra_version: String,
+}
+
+impl StateIdentity {
+    /// Returns the identity of a new state for `network`.
+    fn new(network: Network) -> Self {
+        Self {
+            network,
+            format_version: STATE_FORMAT_VERSION,
+            genesis_hash: None,
+            zebra_version: ZEBRA_VERSION.to_owned(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let network: u8 = match self.network {
+            Network::Mainnet => 0,
+            Network::Testnet => 1,
+        };
+
+        let mut bytes = Vec::with_capacity(FIXED_FIELDS_LEN + self.zebra_version.len());
+        bytes.push(network);
+        bytes.extend_from_slice(&self.format_version.to_be_bytes());
+        match self.genesis_hash {
+            Some(hash) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&hash.0);
+            }
+            None => bytes.extend_from_slice(&[0; 33]),
+        }
+        bytes.extend_from_slice(self.zebra_version.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < FIXED_FIELDS_LEN {
+            Err("stored state identity record is too short")?;
+        }
+
+        let network = match bytes[0] {
+            0 => Network::Mainnet,
+            1 => Network::Testnet,
+            _ => Err("stored state identity has an unknown network")?,
+        };
+        let genesis_hash = match bytes[5] {
+            0 => None,
+            1 => Some(BlockHeaderHash(bytes[6..38].try_into()?)),
+            _ => Err("stored state identity has an invalid genesis hash flag")?,
+        };
+
+        Ok(Self {
+            network,
+            format_version: u32::from_be_bytes(bytes[1..5].try_into()?),
+            genesis_hash,
+            zebra_version: str::from_utf8(&bytes[FIXED_FIELDS_LEN..])?.to_owned(),
+        })
+    }
+}
+
+/// The on-disk state is for a different network than the configured network.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NetworkMismatch {
+    /// The state directory
+    pub path: PathBuf,
+    /// The network that created the state
+    pub stored: Network,
+    /// The configured network
+    pub configured: Network,
+}
+
+impl fmt::Display for NetworkMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the state in {} contains {:?} blocks, so it can't be opened as a {:?} state. \
+             The state directory might have been moved or copied: \
+             use a different `state.cache_dir`, or change `network.network`",
+            self.path.display(),
+            self.stored,
+            self.configured
+        )
+    }
+}
+
+impl error::Error for NetworkMismatch {}
+
+impl NetworkMismatch {
+    /// Returns the network error in `error`, if it is a [`NetworkMismatch`]
+    /// error.
+    pub fn from_error(error: &(dyn error::Error + 'static)) -> Option<&NetworkMismatch> {
+        error.downcast_ref()
+    }
+}
+
+/// Check that the state in `storage`, at `path`, was created for `network`,
+/// recording its identity if it hasn't been recorded yet.
+///
+/// States created before identities were recorded are assumed to be for
+/// `network`. If `read_only` is true, the identity is checked, but never
+/// recorded.
+pub(super) fn check_identity(
+    storage: &dyn StorageBackend,
+    network: Network,
+    path: PathBuf,
+    read_only: bool,
+) -> Result<(), Error> {
+    let stored = storage
+        .read(tree::METADATA, IDENTITY_KEY)?
+        .map(|bytes| StateIdentity::from_bytes(&bytes))
+        .transpose()?;
+    let is_new = stored.is_none();
+    let mut identity = match stored {
+        Some(identity) if identity.network != network => Err(NetworkMismatch {
+            path,
+            stored: identity.network,
+            configured: network,
+        })?,
+        Some(identity) => identity,
+        None => StateIdentity::new(network),
+    };
+
+    let genesis_hash = storage
+        .read(tree::BY_HEIGHT, &0u32.to_be_bytes())?
+        .map(|hash| -> Result<_, Error> { Ok(BlockHeaderHash(hash[..].try_into()?)) })
+        .transpose()?;
+    if let (Some(recorded), Some(committed)) = (identity.genesis_hash, genesis_hash) {
+        if recorded != committed {
+            Err(format!(
+                "the genesis block in the state in {} has changed from {:?} to {:?}, \
+                 so the state contains blocks from more than one chain",
+                path.display(),
+                recorded,
+                committed
+            ))?;
+        }
+    }
+
+    // Only write the record if it is new, or it is missing the genesis hash
+    if read_only || (!is_new && (identity.genesis_hash.is_some() || genesis_hash.is_none())) {
+        return Ok(());
+    }
+    identity.genesis_hash = genesis_hash;
+
+    storage.insert(tree::METADATA, IDENTITY_KEY, &identity.to_bytes())?;
+    storage.flush()
+}
+
+/// Panics with a diagnostic if the state in `storage` isn't for `network`.
+///
+/// `path` is `None` if the state isn't stored on disk, so it doesn't need
+/// checking.
+pub(super) fn expect_identity(
+    storage: &dyn StorageBackend,
+    network: Network,
+    path: Option<PathBuf>,
+    read_only: bool,
+) {
+    if let Some(path) = path {
+        if let Err(error) = check_identity(storage, network, path, read_only) {
+            panic!("{}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SledState;
+    use super::*;
+    use crate::{storage, Config};
+    use std::fs;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize};
+
+    #[test]
+    fn identity_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let identity = StateIdentity {
+            genesis_hash: Some(BlockHeaderHash([7; 32])),
+            ..StateIdentity::new(Network::Testnet)
+        };
+        assert_eq!(StateIdentity::from_bytes(&identity.to_bytes())?, identity);
+
+        let identity = StateIdentity::new(Network::Mainnet);
+        assert_eq!(StateIdentity::from_bytes(&identity.to_bytes())?, identity);
+
+        Ok(())
+    }
+
+    #[test]
+    fn moved_state_is_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let genesis_hash = {
+            let mut state = SledState::new(&config, Network::Mainnet);
+            state.insert(Block::zcash_deserialize(
+                &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+            )?)?
+        };
+
+        // Reopening records the genesis hash
+        let storage = storage::open(&config, Network::Mainnet)?;
+        let path = cache_dir.path().join("mainnet");
+        check_identity(storage.as_ref(), Network::Mainnet, path.clone(), false)?;
+        let identity = storage
+            .read(tree::METADATA, IDENTITY_KEY)?
+            .map(|bytes| StateIdentity::from_bytes(&bytes))
+            .transpose()?
+            .expect("the identity is recorded on open");
+        assert_eq!(identity.network, Network::Mainnet);
+        assert_eq!(identity.genesis_hash, Some(genesis_hash));
+        drop(storage);
+
+        // Move the mainnet state to the testnet directory
+        let moved = cache_dir.path().join("testnet");
+        fs::rename(&path, &moved)?;
+        let storage = storage::open(&config, Network::Testnet)?;
+        let error = check_identity(storage.as_ref(), Network::Testnet, moved, false)
+            .err()
+            .expect("the state is for another network");
+        let mismatch = NetworkMismatch::from_error(error.as_ref()).expect("a network error");
+        assert_eq!(mismatch.stored, Network::Mainnet);
+        assert_eq!(mismatch.configured, Network::Testnet);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! The heights covered by the on-disk state's optional and prunable indexes.
+//!
+//! The state records the first height covered by the address index in its
+//! metadata, so an index that was enabled on an existing state doesn't look
+//! complete. It also records the first height covered by the nullifier
+//! index, because states created before format version 4 don't index the
+//! nullifiers of their existing blocks.
+use super::{Error, SledState};
+use crate::index_coverage::{missing_below, OptionalIndex};
+use crate::storage::{all_keys, tree};
+use std::{convert::TryInto, ops::RangeInclusive};
+use zebra_chain::types::BlockHeight;
+
+/// The metadata key for the first height covered by the address index.
+///
+/// The value is empty if the index was disabled the last time the state was
+/// opened.
+const ADDRESS_INDEX_START_KEY: &[u8] = b"address_index_start";
+
+/// The metadata key for the first height covered by the nullifier index.
+const NULLIFIER_INDEX_START_KEY: &[u8] = b"nullifier_index_start";
+
+impl SledState {
+    /// Record the first height covered by the address index, when the state
+    /// is opened.
+    pub(super) fn record_address_index_start(&self) -> Result<(), Error> {
+        let value = match self.address_index_start()? {
+            Some(start) => start.0.to_be_bytes().to_vec(),
+            None => Vec::new(),
+        };
+
+        self.storage
+            .insert(tree::METADATA, ADDRESS_INDEX_START_KEY, &value)
+    }
+
+    /// Returns the first height covered by the address index, or `None` if
+    /// the index is disabled.
+    ///
+    /// If the index is enabled on a state that has blocks, it covers the
+    /// blocks after the tip.
+    fn address_index_start(&self) -> Result<Option<BlockHeight>, Error> {
+        if !self.address_index {
+            return Ok(None);
+        }
+        let next_height = self.tip_height().map_or(0, |tip| tip.0 + 1);
+
+        let start = match self.storage.read(tree::METADATA, ADDRESS_INDEX_START_KEY)? {
+            // The index was disabled, so the existing blocks aren't indexed
+            Some(bytes) if bytes.is_empty() => next_height,
+            Some(bytes) => u32::from_be_bytes(bytes.as_slice().try_into()?),
+            // States from before the start was recorded: an index that has
+            // entries was enabled when the state was created
+            None if self
+                .storage
+                .iterate(tree::ADDRESS_TRANSACTIONS, all_keys())?
+                .next()
+                .is_some() =>
+            {
+                0
+            }
+            None => next_height,
+        };
+
+        Ok(Some(BlockHeight(start)))
+    }
+
+    /// Record the first height covered by the nullifier index, the first time
+    /// the state is opened by a release that indexes nullifiers.
+    ///
+    /// New states index every block. Existing states index the blocks after
+    /// their tip.
+    pub(super) fn record_nullifier_index_start(&self) -> Result<(), Error> {
+        if self
+            .storage
+            .read(tree::METADATA, NULLIFIER_INDEX_START_KEY)?
+            .is_none()
+        {
+            let start = self.tip_height().map_or(0, |tip| tip.0 + 1);
+            self.storage.insert(
+                tree::METADATA,
+                NULLIFIER_INDEX_START_KEY,
+                &start.to_be_bytes(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the first height covered by the nullifier index.
+    pub(super) fn nullifier_index_start(&self) -> Result<BlockHeight, Error> {
+        let start = match self
+            .storage
+            .read(tree::METADATA, NULLIFIER_INDEX_START_KEY)?
+        {
+            Some(bytes) => u32::from_be_bytes(bytes.as_slice().try_into()?),
+            // Read-only states that haven't been opened for writing by this
+            // release
+            None => self.tip_height().map_or(0, |tip| tip.0 + 1),
+        };
+
+        Ok(BlockHeight(start))
+    }
+
+    /// Returns the best chain heights that aren't covered by `index`, as a
+    /// list of ranges.
+    pub(super) fn missing_index_ranges(
+        &self,
+        index: OptionalIndex,
+    ) -> Result<Vec<RangeInclusive<BlockHeight>>, Error> {
+        let tip = match self.tip_height() {
+            Some(tip) => tip,
+            None => return Ok(Vec::new()),
+        };
+
+        let covered_from = match index {
+            OptionalIndex::Transactions | OptionalIndex::SpentOutputs => self.pruned_height()?,
+            OptionalIndex::Addresses => match self.address_index_start()? {
+                Some(start) => start.0,
+                None => tip.0 + 1,
+            },
+        };
+
+        Ok(missing_below(covered_from, tip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, Network};
+
+    #[test]
+    fn late_address_index_is_incomplete() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+        state.insert(Block::zcash_deserialize(
+            &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+        )?)?;
+        let whole_chain = vec![BlockHeight(0)..=BlockHeight(0)];
+        assert_eq!(
+            state.missing_index_ranges(OptionalIndex::Addresses)?,
+            whole_chain
+        );
+        assert_eq!(
+            state.missing_index_ranges(OptionalIndex::Transactions)?,
+            Vec::new()
+        );
+        drop(state);
+
+        let config = Config {
+            address_index: true,
+            ..config
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+        state.insert(Block::zcash_deserialize(
+            &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+        )?)?;
+        assert_eq!(
+            state.missing_index_ranges(OptionalIndex::Addresses)?,
+            whole_chain
+        );
+        drop(state);
+
+        // The start is kept when the state is opened again
+        let state = SledState::new(&config, Network::Mainnet);
+        assert_eq!(
+            state.missing_index_ranges(OptionalIndex::Addresses)?,
+            whole_chain
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn upgraded_states_index_nullifiers_after_their_tip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+        assert_eq!(state.nullifier_index_start()?, BlockHeight(0));
+        state.insert(Block::zcash_deserialize(
+            &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+        )?)?;
+
+        // A state from before nullifiers were indexed
+        state
+            .storage
+            .remove(tree::METADATA, NULLIFIER_INDEX_START_KEY)?;
+        drop(state);
+
+        let state = SledState::new(&config, Network::Mainnet);
+        assert_eq!(state.nullifier_index_start()?, BlockHeight(1));
+        drop(state);
+
+        // The start is kept when the state is opened again
+        let state = SledState::new(&config, Network::Mainnet);
+        assert_eq!(state.nullifier_index_start()?, BlockHeight(1));
+
+        Ok(())
+    }
+}
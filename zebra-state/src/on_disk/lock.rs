@@ -0,0 +1,140 @@
+//! A lock that stops two processes from using the same on-disk state.
+//!
+//! Opening a sled or RocksDB database from two processes fails with an opaque
+//! backend error, or deadlocks, depending on the backend and the timing. So
+//! the state takes an advisory lock on a file in the network's state
+//! directory before it opens the database, and writes the process ID to the
+//! file, so the error can name the other process.
+use super::Error;
+use crate::Config;
+use fs2::FileExt;
+use std::{
+    error, fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    process,
+};
+use zebra_chain::Network;
+
+/// Another process is already using the on-disk state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StateLocked {
+    /// The lock file
+    pub path: PathBuf,
+    /// The process ID of the process that holds the lock, if it is known
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for StateLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let process = match self.pid {
+            Some(pid) => format!("another zebrad is already using this state at PID {}", pid),
+            None => "another zebrad is already using this state".to_owned(),
+        };
+
+        write!(
+            f,
+            "{}, so it can't be opened (lock file {}). \
+             Stop the other zebrad, or use a different `state.cache_dir`",
+            process,
+            self.path.display()
+        )
+    }
+}
+
+impl error::Error for StateLocked {}
+
+impl StateLocked {
+    /// Returns the lock error in `error`, if it is a [`StateLocked`] error.
+    pub fn from_error(error: &(dyn error::Error + 'static)) -> Option<&StateLocked> {
+        error.downcast_ref()
+    }
+}
+
+/// An exclusive lock on the on-disk state for a network.
+///
+/// The lock is released when this value is dropped, or when the process
+/// exits.
+pub(super) struct StateLock {
+    _file: File,
+}
+
+/// Lock the on-disk state for `network`.
+///
+/// Returns `None` if the state isn't stored on disk. Returns a
+/// [`StateLocked`] error if another process holds the lock.
+pub(super) fn lock_state(config: &Config, network: Network) -> Result<Option<StateLock>, Error> {
+    let path = match config.lock_path(network) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&path)?;
+    if let Err(error) = file.try_lock_exclusive() {
+        if error.kind() != fs2::lock_contended_error().kind() {
+            return Err(error.into());
+        }
+
+        let mut pid = String::new();
+        let pid = file
+            .read_to_string(&mut pid)
+            .ok()
+            .and_then(|_| pid.trim().parse().ok());
+        Err(StateLocked { path, pid })?;
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", process::id())?;
+    file.sync_all()?;
+
+    Ok(Some(StateLock { _file: file }))
+}
+
+/// Returns the lock for the state for `network`, or panics with a diagnostic
+/// if it can't be locked.
+pub(super) fn expect_locked(config: &Config, network: Network) -> Option<StateLock> {
+    lock_state(config, network).unwrap_or_else(|error| panic!("{}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn second_lock_names_the_holder() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+
+        let lock = lock_state(&config, Network::Mainnet)?;
+        assert!(lock.is_some());
+
+        let error = lock_state(&config, Network::Mainnet)
+            .err()
+            .expect("the state is already locked");
+        let locked = StateLocked::from_error(error.as_ref()).expect("a lock error");
+        assert_eq!(locked.pid, Some(process::id()));
+
+        // Each network has its own lock
+        assert!(lock_state(&config, Network::Testnet)?.is_some());
+
+        drop(lock);
+        assert!(lock_state(&config, Network::Mainnet)?.is_some());
+
+        Ok(())
+    }
+}
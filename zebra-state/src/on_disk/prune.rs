@@ -0,0 +1,199 @@
+//! Optional pruning of old block data.
+//!
+//! Pruned states discard the data for deep blocks that isn't needed to
+//! validate new blocks: block bodies, transparent outputs that have been
+//! spent, and the note commitment and history trees for each height. Headers,
+//! the best chain indexes, and the unspent outputs are kept, so the state can
+//! still check and commit new blocks.
+//!
+//! Pruning can't be undone, so pruned states stay pruned even if pruning is
+//! disabled later.
+use super::{Error, SledState};
+use crate::{
+    storage::{key_range, tree, WriteBatch},
+    value_pools::{block_spends, outpoint_key},
+};
+use std::{convert::TryInto, thread, time::Duration};
+use zebra_chain::{
+    block::{Block, BlockHeaderHash},
+    serialization::ZcashDeserialize,
+};
+
+/// How often the background task prunes old blocks.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The maximum number of blocks pruned in each pass.
+const PRUNE_BATCH_SIZE: usize = 1000;
+
+/// The key for the next height to prune, in the `metadata` tree.
+const PRUNED_HEIGHT_KEY: &[u8] = b"pruned_height";
+
+impl SledState {
+    /// Prune a batch of blocks that are deeper than the prune depth.
+    ///
+    /// Returns the number of blocks that were pruned.
+    pub(super) fn prune_old_blocks(&self) -> Result<usize, Error> {
+        let depth = match self.prune_depth {
+            Some(depth) => depth,
+            None => return Ok(0),
+        };
+        let end = match self.snapshot.load().tip() {
+            Some((tip_height, _)) => match tip_height.0.checked_sub(depth) {
+                Some(end) => end,
+                None => return Ok(0),
+            },
+            None => return Ok(0),
+        };
+
+        let start = self.pruned_height()?;
+        if start >= end {
+            return Ok(0);
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut last_pruned = None;
+        let mut pruned = Vec::new();
+        let range = key_range(start.to_be_bytes()..end.to_be_bytes());
+        for entry in self
+            .storage
+            .iterate(tree::BY_HEIGHT, range)?
+            .take(PRUNE_BATCH_SIZE)
+        {
+            let (key, hash) = entry?;
+            last_pruned = Some(u32::from_be_bytes(key.as_slice().try_into()?));
+            let hash = BlockHeaderHash(hash.as_slice().try_into()?);
+            let bytes = self.read_bytes(hash)?.ok_or("stored block is missing")?;
+            let block = Block::zcash_deserialize(bytes.as_slice())?;
+
+            // Spent outputs can't be spent again, so they aren't needed to
+            // validate new blocks
+            for outpoint in block_spends(&block) {
+                batch.remove(tree::TRANSPARENT_OUTPUTS, &outpoint_key(&outpoint)[..]);
+            }
+            batch.remove(tree::BODIES, &hash.0[..]);
+            pruned.push(hash);
+        }
+
+        // The trees at the tip are used to update the trees for new blocks,
+        // but older trees are only used for historical queries
+        let next_height = match last_pruned {
+            Some(last_pruned) => {
+                let pruned_heights = start.to_be_bytes()..=last_pruned.to_be_bytes();
+                batch.delete_range(tree::NOTE_COMMITMENT_TREES, pruned_heights.clone());
+                batch.delete_range(tree::HISTORY_TREES, pruned_heights);
+                last_pruned + 1
+            }
+            None => start,
+        };
+        batch.insert(
+            tree::METADATA,
+            PRUNED_HEIGHT_KEY,
+            &next_height.to_be_bytes()[..],
+        );
+        self.storage.write_batch(batch)?;
+
+        // Archived bodies are only removed after the hot tier stops pointing
+        // to them.
+        if let Some(archive) = &self.archive {
+            archive.remove(&pruned)?;
+        }
+        let mut block_cache = self.block_cache();
+        for hash in &pruned {
+            block_cache.remove_from_state(*hash);
+        }
+
+        Ok(pruned.len())
+    }
+
+    /// Returns the next height to prune.
+    ///
+    /// Blocks below this height have already been pruned.
+    pub(super) fn pruned_height(&self) -> Result<u32, Error> {
+        match self.storage.read(tree::METADATA, PRUNED_HEIGHT_KEY)? {
+            Some(bytes) => Ok(u32::from_be_bytes(bytes.as_slice().try_into()?)),
+            None => Ok(0),
+        }
+    }
+
+    /// Has the block with `hash` been pruned?
+    pub(super) fn is_pruned(&self, hash: BlockHeaderHash) -> Result<bool, Error> {
+        match self.best_chain_height(hash)? {
+            Some(height) => Ok(height.0 < self.pruned_height()?),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Spawn a background thread that periodically prunes old blocks from
+/// `state`.
+///
+/// The thread exits after the last clone of `state` is dropped.
+pub(super) fn spawn_pruner(state: &SledState) {
+    let state = state.downgrade();
+    thread::Builder::new()
+        .name("zebra-state-prune".into())
+        .spawn(move || loop {
+            let result = match SledState::upgrade(&state) {
+                Some(state) => state.prune_old_blocks(),
+                None => return,
+            };
+            match result {
+                Ok(0) => thread::sleep(PRUNE_INTERVAL),
+                Ok(count) => tracing::debug!(count, "pruned old blocks"),
+                Err(error) => {
+                    tracing::warn!(?error, "failed to prune old blocks");
+                    thread::sleep(PRUNE_INTERVAL);
+                }
+            }
+        })
+        .expect("spawning the prune thread should succeed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use zebra_chain::{types::BlockHeight, Network};
+
+    #[test]
+    fn pruned_blocks_keep_headers() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            prune: true,
+            // Read blocks from storage, rather than the block cache
+            block_cache_bytes: 0,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+        // The test vectors don't have enough contiguous blocks for the
+        // minimum prune depth
+        Arc::get_mut(&mut state.shared)
+            .expect("the state has no other clones")
+            .prune_depth = Some(0);
+
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        let hash0 = state.insert(block0.clone())?;
+        state.insert(block1.clone())?;
+        assert!(state.note_trees(BlockHeight(0))?.is_some());
+
+        assert_eq!(state.prune_old_blocks()?, 1);
+        assert_eq!(state.prune_old_blocks()?, 0);
+
+        assert_eq!(state.get_header(hash0)?, Some(block0.header));
+        assert_eq!(state.get(hash0)?, None);
+        assert_eq!(state.get(BlockHeight(0))?, None);
+        assert_eq!(state.note_trees(BlockHeight(0))?, None);
+        assert_eq!(state.get(BlockHeight(1))?, Some(block1));
+        assert_eq!(state.count()?, 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Automatic recovery from a corrupt state.
+//!
+//! Unclean shutdowns and disk errors can leave blocks that can't be read,
+//! anywhere in the state. When the state is opened, errors from the startup
+//! check or from loading the non-finalized state trigger a full integrity
+//! check, and the state is rolled back to the highest block below the first
+//! corrupt block. The blocks above it are downloaded again by the syncer.
+//!
+//! If the state can't be recovered, Zebra exits with a diagnostic that
+//! describes the recovery options, instead of a deserialization error.
+//! States with an unsupported format are never recovered, because their
+//! blocks would be mistaken for corrupt blocks.
+use super::{Error, SledState};
+use crate::{
+    storage::{key_range, tree, UnsupportedFormat, WriteBatch},
+    Config,
+};
+use std::convert::TryFrom;
+use zebra_chain::{block::BlockHeaderHash, types::BlockHeight, Network};
+
+impl SledState {
+    /// Run the startup check, and load the non-finalized state.
+    ///
+    /// If either fails and `recover` is true, checks the whole state, and
+    /// rolls back to the highest block below the first inconsistent block.
+    pub(super) fn check_on_open(&mut self, depth: u32, recover: bool) -> Result<(), Error> {
+        let error = match self
+            .startup_check(depth)
+            .and_then(|_| self.reload_non_finalized())
+        {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        if !recover {
+            return Err(error);
+        }
+
+        tracing::error!(
+            ?error,
+            "the state is corrupt, checking every stored block to find the last consistent block"
+        );
+        let removed = self.recover()?;
+        tracing::warn!(
+            removed_blocks = removed.len(),
+            tip_height = ?self.tip_height(),
+            "recovered the corrupt state, the removed blocks will be downloaded again"
+        );
+        metrics::counter!("state.recovery.count", 1);
+
+        self.reload_non_finalized()
+    }
+
+    /// Check the whole state, then repair it.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    fn recover(&mut self) -> Result<Vec<BlockHeaderHash>, Error> {
+        let mut report = self.verify()?;
+        for inconsistency in &report.inconsistencies {
+            tracing::warn!(%inconsistency, "found an inconsistency in the state");
+        }
+        self.repair(&mut report)?;
+
+        Ok(report.removed)
+    }
+
+    /// Remove the blocks at and above `first_removed`, without reading them.
+    ///
+    /// Used when the removed blocks are corrupt. The transaction, output,
+    /// nullifier, and address index entries of each block can only be found
+    /// by reading it, so they are left behind. `zebrad verify-state` reports them as
+    /// orphaned entries.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    pub(super) fn truncate(
+        &mut self,
+        first_removed: BlockHeight,
+    ) -> Result<Vec<BlockHeaderHash>, Error> {
+        let removed_heights = key_range(first_removed.0.to_be_bytes()..);
+        let mut removed = Vec::new();
+        for entry in self
+            .storage
+            .iterate(tree::BY_HEIGHT, removed_heights.clone())?
+        {
+            let (_key, hash) = entry?;
+            removed.push(BlockHeaderHash(<[u8; 32]>::try_from(&hash[..])?));
+        }
+
+        let mut batch = WriteBatch::default();
+        self.remove_subtrees_from(&mut batch, first_removed)?;
+        batch.delete_range(tree::NOTE_COMMITMENT_TREES, removed_heights.clone());
+        batch.delete_range(tree::HISTORY_TREES, removed_heights.clone());
+        batch.delete_range(tree::BY_HEIGHT, removed_heights);
+        for hash in &removed {
+            for &tree in &[
+                tree::BY_HASH,
+                tree::HEADERS,
+                tree::BODIES,
+                tree::BLOCK_INFO,
+                tree::VALUE_POOLS,
+                tree::SHIELDED_COUNTS,
+            ] {
+                batch.remove(tree, &hash.0[..]);
+            }
+        }
+        self.storage.write_batch(batch)?;
+
+        let mut block_cache = self.block_cache();
+        for hash in &removed {
+            block_cache.remove_from_state(*hash);
+        }
+        drop(block_cache);
+
+        self.reset_archived_height(first_removed.0)?;
+        self.snapshot
+            .replace(Self::load_snapshot(self.storage.as_ref())?);
+
+        Ok(removed)
+    }
+}
+
+/// Returns the value in `result`, or panics with a diagnostic if the state
+/// for `network` can't be opened, or can't be recovered.
+pub(super) fn expect_opened<T>(result: Result<T, Error>, config: &Config, network: Network) -> T {
+    result.unwrap_or_else(|error| {
+        let location = match config.storage_path(network) {
+            Some(path) => format!(" in {}", path.display()),
+            None => String::new(),
+        };
+
+        if let Some(error) = error.downcast_ref::<UnsupportedFormat>() {
+            panic!(
+                "the {:?} state{} can't be used by this Zebra release: {}\n\
+                 To continue, delete the state directory to sync again, \
+                 or run the Zebra release that created the state",
+                network, location, error
+            )
+        }
+
+        panic!(
+            "the {:?} state{} could not be opened, it might be corrupt: {}\n\
+             To recover, run `zebrad verify-state --repair`, restore a backup or snapshot, \
+             or delete the state directory to sync again",
+            network, location, error
+        )
+    })
+}
+
+/// Log a diagnostic for the stored block with `hash`, which can't be
+/// deserialized because of `error`.
+pub(super) fn log_corrupt_block(hash: BlockHeaderHash, error: &Error) {
+    tracing::error!(
+        ?hash,
+        ?error,
+        "a stored block is corrupt, restart zebrad to recover the state automatically, \
+         or stop it and run `zebrad verify-state --repair`"
+    );
+    metrics::counter!("state.corrupt_block.count", 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        on_disk::Inconsistency,
+        storage::{SledBackend, StorageBackend},
+    };
+    use std::panic;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize};
+
+    #[test]
+    fn corrupt_blocks_are_rolled_back_on_open() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+
+        let hash0 = {
+            let mut state = SledState::new(&config, Network::Mainnet);
+            let hash0 = state.insert(Block::zcash_deserialize(
+                &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+            )?)?;
+            let hash1 = state.insert(Block::zcash_deserialize(
+                &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+            )?)?;
+
+            // Simulate a corrupt block body
+            state.storage.insert(tree::BODIES, &hash1.0, &[0xff; 8])?;
+            state.storage.flush()?;
+
+            hash0
+        };
+
+        let state = SledState::new(&config, Network::Mainnet);
+        assert_eq!(state.tip_height(), Some(BlockHeight(0)));
+        assert_eq!(state.best_chain_hash(BlockHeight(0))?, Some(hash0));
+
+        // The index entries of the corrupt block can't be removed
+        assert!(state
+            .verify()?
+            .inconsistencies
+            .iter()
+            .all(|inconsistency| matches!(inconsistency, Inconsistency::OrphanedEntries { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn old_formats_are_refused_without_changes() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+
+        // States created before format version 3 store whole blocks, and
+        // don't record their format version
+        let block = &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..];
+        {
+            let raw = SledBackend::open(&config, Network::Mainnet)?;
+            raw.insert(tree::BY_HEIGHT, &0u32.to_be_bytes(), block)?;
+            raw.flush()?;
+        }
+
+        let error = panic::catch_unwind(|| SledState::new(&config, Network::Mainnet))
+            .err()
+            .expect("old formats are refused");
+        let message = error
+            .downcast_ref::<String>()
+            .expect("the panic has a formatted message");
+        assert!(message.contains("can't be used by this Zebra release"));
+        assert!(!message.contains("verify-state"));
+
+        // The old state isn't truncated or stamped with a format version
+        let raw = SledBackend::open(&config, Network::Mainnet)?;
+        assert_eq!(
+            raw.read(tree::BY_HEIGHT, &0u32.to_be_bytes())?.as_deref(),
+            Some(block)
+        );
+        assert_eq!(raw.read(tree::METADATA, b"format_version")?, None);
+
+        Ok(())
+    }
+}
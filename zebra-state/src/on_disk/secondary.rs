@@ -0,0 +1,28 @@
+//! Secondary states, which follow a state that another process is writing.
+//!
+//! RocksDB can open a database as a secondary instance, which reads the
+//! primary's table files and write-ahead log, without taking its lock. The
+//! secondary only sees new writes after it catches up. The on-disk state also
+//! keeps a snapshot of the recent best chain, the non-finalized blocks, and a
+//! block cache, so they are reloaded after each catch-up.
+use super::{Error, SledState};
+
+impl SledState {
+    /// Read the changes that the primary state has written since this state
+    /// was opened, or since it last caught up.
+    pub(super) fn catch_up(&self) -> Result<(), Error> {
+        // The primary writes blocks to the archive before it removes them
+        // from the hot tier, so the archive catches up first, and archived
+        // blocks are always found
+        if let Some(archive) = &self.archive {
+            archive.catch_up()?;
+        }
+        self.storage.catch_up()?;
+
+        self.snapshot
+            .replace(Self::load_snapshot(self.storage.as_ref())?);
+        // The primary might have rolled back some of the cached blocks
+        self.block_cache().clear();
+        self.reload_non_finalized()
+    }
+}
@@ -0,0 +1,337 @@
+//! Consistent snapshots of the finalized state, for seeding new nodes.
+//!
+//! A snapshot is a directory containing a gzip-compressed file for each
+//! state tree, and a `manifest.toml` that describes the snapshot. Each tree
+//! file is a sequence of entries, and each entry is a CompactSize-prefixed key
+//! followed by a CompactSize-prefixed value.
+//!
+//! Snapshots are taken from a working copy of the state, so the blocks above
+//! the snapshot height can be removed without changing the original state.
+//! The working copy is deleted after the snapshot is written. Archived block
+//! bodies are copied into the snapshot, so snapshots never need an archive
+//! directory.
+//!
+//! Snapshots are restored by `import_snapshot`.
+use super::{archive, compression, identity, Error, SledState};
+use crate::{
+    storage::{self, all_keys, tree, WriteBatch},
+    Config,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+use zebra_chain::{serialization::WriteZcashExt, types::BlockHeight, Network};
+
+/// The version of the snapshot format written by this crate.
+///
+/// Incremented when the snapshot layout or the format of any state tree
+/// changes.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+/// The name of the manifest file in each snapshot directory.
+pub const SNAPSHOT_MANIFEST_FILE: &str = "manifest.toml";
+
+/// The name of the working copy directory, inside the snapshot directory.
+const WORKING_COPY_DIR: &str = "working-copy";
+
+/// The number of entries copied to the working copy in each write batch.
+const COPY_BATCH_SIZE: usize = 10_000;
+
+/// The trees that are not included in snapshots.
+///
+/// Archived bodies are copied into the `bodies` tree instead. The database
+/// history describes the local database, rather than the chain, and failed
+/// blocks are verified again by the importing node.
+pub(super) const SKIPPED_TREES: &[&str] =
+    &[tree::BLOCKS, tree::DATABASE_HISTORY, tree::FAILED_BLOCKS];
+
+/// A description of a state snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotManifest {
+    /// The snapshot format version
+    pub format_version: u32,
+    /// The network of the snapshotted state
+    pub network: Network,
+    /// The height of the tip of the snapshotted state
+    pub height: BlockHeight,
+    /// The hash of the tip of the snapshotted state, in hex
+    pub tip_hash: String,
+    /// The compressed tree files in the snapshot
+    pub trees: Vec<SnapshotTree>,
+}
+
+impl SnapshotManifest {
+    /// Read the manifest of the snapshot in the directory at `path`.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let manifest = fs::read_to_string(path.join(SNAPSHOT_MANIFEST_FILE))?;
+        Ok(toml::from_str(&manifest)?)
+    }
+}
+
+/// A compressed state tree file in a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotTree {
+    /// The name of the state tree
+    pub name: String,
+    /// The name of the compressed file, in the snapshot directory
+    pub file: String,
+    /// The number of entries in the tree
+    pub entries: u64,
+    /// The SHA-256 hash of the compressed file, in hex
+    pub sha256: String,
+}
+
+impl SledState {
+    /// Write a snapshot of the best chain up to `height` to the directory at
+    /// `path`, using a working copy of the state stored using `work_config`.
+    ///
+    /// The snapshot directory is created if it does not exist.
+    pub(super) fn export_snapshot(
+        &self,
+        work_config: &Config,
+        height: BlockHeight,
+        path: &Path,
+    ) -> Result<SnapshotManifest, Error> {
+        match self.tip_height() {
+            Some(tip_height) if tip_height >= height => {}
+            _ => Err("snapshot height is above the tip of the state")?,
+        }
+        // The blocks above the snapshot height are read to remove them
+        if height.0 < self.pruned_height()? {
+            Err("snapshot height is below the pruned height of the state")?;
+        }
+
+        fs::create_dir_all(path)?;
+        let mut work = SledState::new(work_config, self.network);
+        if work.tip_height().is_some() {
+            Err("the snapshot working copy directory is not empty")?;
+        }
+        self.copy_to(&work)?;
+        work.snapshot
+            .replace(Self::load_snapshot(work.storage.as_ref())?);
+        if let Some(first_removed) = height.0.checked_add(1) {
+            if work.tip_height() >= Some(BlockHeight(first_removed)) {
+                work.remove_from(BlockHeight(first_removed), None)?;
+            }
+        }
+
+        let tip_hash = work
+            .best_chain_hash(height)?
+            .ok_or("snapshot tip is missing from the working copy")?;
+        let trees = work.write_snapshot_trees(path)?;
+        let manifest = SnapshotManifest {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            network: self.network,
+            height,
+            tip_hash: hex::encode(tip_hash.0),
+            trees,
+        };
+        fs::write(
+            path.join(SNAPSHOT_MANIFEST_FILE),
+            toml::to_string(&manifest)?,
+        )?;
+
+        Ok(manifest)
+    }
+
+    /// Copy every snapshot tree in this state to `work`.
+    ///
+    /// Archived bodies are copied to the `bodies` tree, and compressed bodies
+    /// are decompressed, so snapshots don't depend on the state's config.
+    fn copy_to(&self, work: &SledState) -> Result<(), Error> {
+        for tree in tree::names().filter(|tree| !SKIPPED_TREES.contains(tree)) {
+            let mut batch = WriteBatch::default();
+            for entry in self.storage.iterate(tree, all_keys())? {
+                let (key, mut value) = entry?;
+                match tree {
+                    tree::BODIES => {
+                        let body = archive::body_bytes(self.archive.as_ref(), value)?;
+                        value = compression::decompress_body(body)?;
+                    }
+                    tree::METADATA if key == archive::ARCHIVED_HEIGHT_KEY => continue,
+                    tree::METADATA if storage::is_format_metadata(&key) => continue,
+                    _ => {}
+                }
+
+                batch.insert(tree, key, value);
+                if batch.ops.len() >= COPY_BATCH_SIZE {
+                    work.storage.write_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            if !batch.is_empty() {
+                work.storage.write_batch(batch)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write each snapshot tree in this state to a compressed file in `path`.
+    fn write_snapshot_trees(&self, path: &Path) -> Result<Vec<SnapshotTree>, Error> {
+        let mut trees = Vec::new();
+
+        for tree in tree::names().filter(|tree| !SKIPPED_TREES.contains(tree)) {
+            let file = format!("{}.gz", tree);
+            let writer = HashWriter::new(BufWriter::new(File::create(path.join(&file))?));
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+
+            let mut entries = 0;
+            for entry in self.storage.iterate(tree, all_keys())? {
+                let (key, value) = entry?;
+                // The identity record describes the local database, so the
+                // importing state keeps its own record
+                if tree == tree::METADATA && key == identity::IDENTITY_KEY {
+                    continue;
+                }
+                encoder.write_compactsize(key.len() as u64)?;
+                encoder.write_all(&key)?;
+                encoder.write_compactsize(value.len() as u64)?;
+                encoder.write_all(&value)?;
+                entries += 1;
+            }
+
+            let mut writer = encoder.finish()?;
+            writer.flush()?;
+            trees.push(SnapshotTree {
+                name: tree.to_string(),
+                file,
+                entries,
+                sha256: hex::encode(writer.hasher.result()),
+            });
+        }
+
+        Ok(trees)
+    }
+}
+
+/// Write a snapshot of the best chain up to `height` in the state for
+/// `network` to the directory at `path`.
+///
+/// If `height` is `None`, the snapshot is taken at the finalized tip, so
+/// snapshot blocks can't be rolled back by a chain reorganisation.
+///
+/// Zebra must not be running, because the state is opened by this function.
+/// A working copy of the state is stored in the snapshot directory while the
+/// snapshot is written, so the snapshot needs about twice the size of the
+/// state in free space.
+pub fn export_snapshot(
+    config: &Config,
+    network: Network,
+    height: Option<BlockHeight>,
+    path: &Path,
+) -> Result<SnapshotManifest, Error> {
+    let state = SledState::new(config, network);
+    let height = match height {
+        Some(height) => height,
+        None => state
+            .tip_height()
+            .and_then(crate::finalized_height)
+            .ok_or("the state does not have any finalized blocks")?,
+    };
+
+    let work_dir = path.join(WORKING_COPY_DIR);
+    let work_config = Config {
+        cache_dir: Some(work_dir.clone()),
+        archive_dir: None,
+        prune: false,
+        block_cache_bytes: 0,
+        startup_check_depth: 0,
+        ..config.clone()
+    };
+    let result = state.export_snapshot(&work_config, height, path);
+
+    if work_dir.exists() {
+        fs::remove_dir_all(&work_dir)?;
+    }
+    result
+}
+
+/// A writer that calculates the SHA-256 hash of the written bytes.
+pub(super) struct HashWriter<W> {
+    writer: W,
+    pub(super) hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub(super) fn new(writer: W) -> Self {
+        Self {
+            writer,
+            hasher: Sha256::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackendKind;
+    use flate2::read::GzDecoder;
+    use std::{io::Read, sync::Arc};
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize};
+
+    #[test]
+    fn snapshot_contains_blocks_up_to_height() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = Config {
+            storage_backend: StorageBackendKind::Memory,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        let hash0 = state.insert(block0)?;
+        state.insert(block1)?;
+
+        let dir = TempDir::new("")?;
+        let manifest = state.export_snapshot(&config, BlockHeight(0), dir.path())?;
+        assert_eq!(manifest.format_version, SNAPSHOT_FORMAT_VERSION);
+        assert_eq!(manifest.network, Network::Mainnet);
+        assert_eq!(manifest.height, BlockHeight(0));
+        assert_eq!(manifest.tip_hash, hex::encode(hash0.0));
+
+        assert_eq!(SnapshotManifest::read(dir.path())?, manifest);
+
+        for tree in &manifest.trees {
+            let bytes = fs::read(dir.path().join(&tree.file))?;
+            assert_eq!(hex::encode(Sha256::digest(&bytes)), tree.sha256);
+
+            let mut decoded = Vec::new();
+            GzDecoder::new(&bytes[..]).read_to_end(&mut decoded)?;
+            assert_eq!(decoded.is_empty(), tree.entries == 0);
+            if tree.name == tree::BY_HEIGHT || tree.name == tree::HEADERS {
+                assert_eq!(tree.entries, 1);
+            }
+        }
+        assert!(!manifest.trees.iter().any(|tree| tree.name == tree::BLOCKS));
+
+        // Snapshots can't be taken above the tip
+        let dir = TempDir::new("")?;
+        assert!(state
+            .export_snapshot(&config, BlockHeight(2), dir.path())
+            .is_err());
+
+        Ok(())
+    }
+}
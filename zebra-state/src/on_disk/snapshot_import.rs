@@ -0,0 +1,268 @@
+//! Restoring the state from a snapshot, written by `export_snapshot`.
+//!
+//! The snapshot manifest and every tree file are checked before any data is
+//! written, so a snapshot for another network, a different format version, or
+//! with corrupted files is rejected without changing the state.
+//!
+//! The best chain index is written last, so an interrupted import leaves the
+//! state without a tip. Delete the state before retrying a failed import.
+use super::{
+    snapshot_export::{HashWriter, SnapshotManifest, SKIPPED_TREES, SNAPSHOT_FORMAT_VERSION},
+    Error, SledState,
+};
+use crate::{
+    storage::{all_keys, tree, WriteBatch},
+    Config,
+};
+use flate2::read::GzDecoder;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+use zebra_chain::{serialization::ReadZcashExt, Network};
+
+/// The number of entries written to the state in each write batch.
+const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// The tree that is imported last, because it indexes the best chain.
+const BEST_CHAIN_TREE: &str = tree::BY_HEIGHT;
+
+impl SledState {
+    /// Restore this state from the snapshot in the directory at `path`.
+    ///
+    /// The state must be empty.
+    pub(super) fn import_snapshot(&mut self, path: &Path) -> Result<SnapshotManifest, Error> {
+        let manifest = SnapshotManifest::read(path)?;
+        check_manifest(&manifest, self.network, path)?;
+        if self
+            .storage
+            .iterate(BEST_CHAIN_TREE, all_keys())?
+            .next()
+            .is_some()
+        {
+            Err("snapshots can only be imported into an empty state")?;
+        }
+
+        let (best_chain, other_trees): (Vec<_>, Vec<_>) = manifest
+            .trees
+            .iter()
+            .partition(|tree| tree.name == BEST_CHAIN_TREE);
+        for tree in other_trees.into_iter().chain(best_chain) {
+            let name = known_tree(&tree.name)?;
+            let file = BufReader::new(File::open(path.join(&tree.file))?);
+            self.import_tree(name, GzDecoder::new(file), tree.entries)?;
+        }
+        self.storage.flush()?;
+
+        self.snapshot
+            .replace(Self::load_snapshot(self.storage.as_ref())?);
+        self.reload_non_finalized()?;
+        let tip_hash = self
+            .best_chain_hash(manifest.height)?
+            .map(|hash| hex::encode(hash.0));
+        if self.tip_height() != Some(manifest.height)
+            || tip_hash.as_ref() != Some(&manifest.tip_hash)
+        {
+            Err("imported state tip does not match the snapshot manifest")?;
+        }
+
+        Ok(manifest)
+    }
+
+    /// Write the `entries` entries read from `reader` to `tree`.
+    fn import_tree(
+        &self,
+        tree: &'static str,
+        mut reader: impl Read,
+        entries: u64,
+    ) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        for _ in 0..entries {
+            let key = read_bytes(&mut reader)?;
+            let value = read_bytes(&mut reader)?;
+            batch.insert(tree, key, value);
+            if batch.ops.len() >= IMPORT_BATCH_SIZE {
+                self.storage.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        if reader.read(&mut [0])? != 0 {
+            Err("snapshot tree file has more entries than the manifest")?;
+        }
+        if !batch.is_empty() {
+            self.storage.write_batch(batch)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that `manifest` is for `network`, has a supported format version,
+/// and that the checksum of every tree file in `path` matches.
+fn check_manifest(manifest: &SnapshotManifest, network: Network, path: &Path) -> Result<(), Error> {
+    if manifest.format_version != SNAPSHOT_FORMAT_VERSION {
+        Err(format!(
+            "unsupported snapshot format version {}, expected {}",
+            manifest.format_version, SNAPSHOT_FORMAT_VERSION
+        ))?;
+    }
+    if manifest.network != network {
+        Err(format!(
+            "snapshot is for {:?}, but the state is for {:?}",
+            manifest.network, network
+        ))?;
+    }
+
+    for tree in &manifest.trees {
+        known_tree(&tree.name)?;
+        // Only read files in the snapshot directory
+        if tree.file != format!("{}.gz", tree.name) {
+            Err(format!("unexpected snapshot file name: {}", tree.file))?;
+        }
+
+        let mut file = File::open(path.join(&tree.file))?;
+        let mut writer = HashWriter::new(io::sink());
+        io::copy(&mut file, &mut writer)?;
+        if hex::encode(writer.hasher.result()) != tree.sha256 {
+            Err(format!(
+                "snapshot file {} has an invalid checksum",
+                tree.file
+            ))?;
+        }
+    }
+    for tree in tree::names().filter(|tree| !SKIPPED_TREES.contains(tree)) {
+        if !manifest
+            .trees
+            .iter()
+            .any(|snapshot_tree| snapshot_tree.name == tree)
+        {
+            Err(format!("snapshot is missing the {} tree", tree))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the state tree named `name`.
+fn known_tree(name: &str) -> Result<&'static str, Error> {
+    tree::names()
+        .filter(|tree| !SKIPPED_TREES.contains(tree))
+        .find(|&tree| tree == name)
+        .ok_or_else(|| format!("unknown snapshot tree: {}", name).into())
+}
+
+/// Read a CompactSize-prefixed byte string from `reader`.
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let len = reader.read_compactsize()?;
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        Err("snapshot tree file is truncated")?;
+    }
+
+    Ok(bytes)
+}
+
+/// Restore the state for `network` from the snapshot in the directory at
+/// `path`.
+///
+/// The manifest and the checksums of the snapshot files are checked before
+/// the state is changed. The state must be empty, and Zebra must not be
+/// running, because the state is opened by this function.
+///
+/// Returns the manifest of the imported snapshot.
+pub fn import_snapshot(
+    config: &Config,
+    network: Network,
+    path: &Path,
+) -> Result<SnapshotManifest, Error> {
+    let manifest = SledState::new(config, network).import_snapshot(path)?;
+
+    tracing::info!(
+        height = manifest.height.0,
+        tip_hash = %manifest.tip_hash,
+        "imported state snapshot"
+    );
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackendKind;
+    use std::{fs, sync::Arc};
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, types::BlockHeight};
+
+    fn memory_config() -> Config {
+        Config {
+            storage_backend: StorageBackendKind::Memory,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn imported_snapshot_matches_exported_state() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = memory_config();
+        let mut state = SledState::new(&config, Network::Mainnet);
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        state.insert(block0.clone())?;
+        state.insert(block1.clone())?;
+
+        let dir = TempDir::new("")?;
+        state.export_snapshot(&config, BlockHeight(1), dir.path())?;
+
+        // Snapshots for other networks are rejected
+        let mut testnet_state = SledState::new(&config, Network::Testnet);
+        assert!(testnet_state.import_snapshot(dir.path()).is_err());
+        assert_eq!(testnet_state.tip_height(), None);
+
+        let mut imported = SledState::new(&config, Network::Mainnet);
+        let manifest = imported.import_snapshot(dir.path())?;
+        assert_eq!(manifest.height, BlockHeight(1));
+        assert_eq!(imported.tip_height(), Some(BlockHeight(1)));
+        assert_eq!(imported.get(BlockHeight(0))?, Some(block0));
+        assert_eq!(imported.get(BlockHeight(1))?, Some(block1));
+
+        // Snapshots can only be imported into empty states
+        assert!(imported.import_snapshot(dir.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_snapshot_is_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        let config = memory_config();
+        let mut state = SledState::new(&config, Network::Mainnet);
+        let block0: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        state.insert(block0)?;
+
+        let dir = TempDir::new("")?;
+        state.export_snapshot(&config, BlockHeight(0), dir.path())?;
+        let headers = dir.path().join("headers.gz");
+        let mut bytes = fs::read(&headers)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&headers, bytes)?;
+
+        let mut imported = SledState::new(&config, Network::Mainnet);
+        assert!(imported.import_snapshot(dir.path()).is_err());
+        assert_eq!(imported.tip_height(), None);
+        assert!(imported
+            .storage
+            .iterate(tree::HEADERS, all_keys())?
+            .next()
+            .is_none());
+
+        Ok(())
+    }
+}
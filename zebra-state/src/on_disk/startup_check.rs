@@ -0,0 +1,153 @@
+//! A quick consistency check of the highest stored blocks.
+//!
+//! Each block is written in a single batch, but a crash or power loss can
+//! still leave the most recent blocks inconsistent, if the storage engine or
+//! filesystem loses writes. The check finds these blocks when the state is
+//! opened, and rolls them back, before the state is used.
+use super::{Error, SledState};
+use crate::storage::tree;
+use zebra_chain::{block::BlockHeaderHash, types::BlockHeight};
+
+impl SledState {
+    /// Check the `depth` highest blocks in the stored best chain, then roll
+    /// back to the highest block below the first inconsistent block.
+    ///
+    /// Checks that each block:
+    /// - is at the height in its coinbase transaction,
+    /// - follows the block at the previous height,
+    /// - has `by_hash`, `headers`, `bodies`, and `block_info` entries.
+    ///
+    /// Also recomputes the tip block's transaction merkle root, and checks it
+    /// against the tip's header.
+    ///
+    /// Returns the hashes of the removed blocks, in height order.
+    pub(super) fn startup_check(&mut self, depth: u32) -> Result<Vec<BlockHeaderHash>, Error> {
+        let tip_height = match self.tip_height() {
+            Some(tip_height) if depth > 0 => tip_height,
+            _ => return Ok(Vec::new()),
+        };
+        // Pruned blocks don't have bodies, so they can't be checked
+        let lowest_height = BlockHeight(
+            tip_height
+                .0
+                .saturating_sub(depth - 1)
+                .max(self.pruned_height()?),
+        );
+
+        let mut parent_hash = match lowest_height.0.checked_sub(1) {
+            Some(parent_height) => self.best_chain_hash(BlockHeight(parent_height))?,
+            None => None,
+        };
+        let mut first_inconsistent = None;
+
+        for height in (lowest_height.0..=tip_height.0).map(BlockHeight) {
+            match self.check_stored_block(height, parent_hash, height == tip_height)? {
+                Some(hash) => parent_hash = Some(hash),
+                None => {
+                    first_inconsistent = Some(height);
+                    break;
+                }
+            }
+        }
+
+        let first_inconsistent = match first_inconsistent {
+            Some(first_inconsistent) => first_inconsistent,
+            None => return Ok(Vec::new()),
+        };
+
+        tracing::warn!(
+            ?first_inconsistent,
+            ?tip_height,
+            "found an inconsistent stored block, rolling back to the block below it"
+        );
+        metrics::counter!("state.startup_check.rollback.count", 1);
+
+        self.remove_from(first_inconsistent, None)
+    }
+
+    /// Check the stored block at `height`, using the hash of the stored block
+    /// below it, if any.
+    ///
+    /// Returns the block's hash if it is consistent, or `None` if it isn't.
+    fn check_stored_block(
+        &self,
+        height: BlockHeight,
+        parent_hash: Option<BlockHeaderHash>,
+        is_tip: bool,
+    ) -> Result<Option<BlockHeaderHash>, Error> {
+        let hash = match self.best_chain_hash(height)? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        if self.best_chain_height(hash)? != Some(height)
+            || self.storage.read(tree::BODIES, &hash.0)?.is_none()
+            || self.block_info(hash)?.is_none()
+        {
+            return Ok(None);
+        }
+
+        let block = match self.get(hash)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        if block.hash() != hash || block.coinbase_height() != Some(height) {
+            return Ok(None);
+        }
+        if let Some(parent_hash) = parent_hash {
+            if block.header.previous_block_hash != parent_hash {
+                return Ok(None);
+            }
+        }
+        if is_tip && block.merkle_root() != block.header.merkle_root_hash {
+            return Ok(None);
+        }
+
+        Ok(Some(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use tempdir::TempDir;
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize, Network};
+
+    #[test]
+    fn partly_written_blocks_are_rolled_back() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+
+        let (hash1, hash2) = {
+            let mut state = SledState::new(&config, Network::Mainnet);
+            state.insert(Block::zcash_deserialize(
+                &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+            )?)?;
+            let hash1 = state.insert(Block::zcash_deserialize(
+                &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+            )?)?;
+            let hash2 = state.insert(Block::zcash_deserialize(
+                &zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..],
+            )?)?;
+
+            // Simulate a crash part way through writing block 2
+            state.storage.remove(tree::BLOCK_INFO, &hash2.0)?;
+            state.storage.flush()?;
+
+            (hash1, hash2)
+        };
+
+        let state = SledState::new(&config, Network::Mainnet);
+
+        assert_eq!(state.best_chain_hash(BlockHeight(1))?, Some(hash1));
+        assert_eq!(state.best_chain_hash(BlockHeight(2))?, None);
+        assert!(!state.contains(&hash2)?);
+
+        Ok(())
+    }
+}
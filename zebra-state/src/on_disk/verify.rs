@@ -0,0 +1,707 @@
+//! A full integrity check of the stored best chain.
+//!
+//! The startup check only checks the highest blocks, so corruption deeper in
+//! the state only shows up as deserialization errors when the blocks are
+//! read. This check walks every block in the best chain, checks the chain
+//! linkage and the block hashes, and cross-checks the transaction location,
+//! transparent output, and nullifier indexes against the block data.
+//! Nullifiers are only checked in the blocks covered by the nullifier index.
+//!
+//! Index entries are derived from the blocks, so incorrect transaction
+//! locations, transparent outputs, and nullifiers are repaired by rewriting
+//! them. Other
+//! inconsistencies are repaired by rolling back to the block below the first
+//! inconsistent block, like the startup check. Corrupt blocks can't be read,
+//! so their index entries are left behind when they are removed.
+//!
+//! Values that fail their checksums are reported like missing values, so
+//! corrupt index entries are rewritten, and corrupt blocks are removed.
+use super::{transaction_locations, Error, SledState};
+use crate::{
+    block_info::BlockInfo,
+    nullifiers::block_nullifiers,
+    storage::{all_keys, tree, WriteBatch},
+    value_pools::{amount_from_bytes, block_outputs, block_spends, outpoint_key},
+    Config, CorruptValue,
+};
+use std::{
+    collections::BTreeSet,
+    convert::{TryFrom, TryInto},
+    fmt,
+};
+use zebra_chain::{
+    block::{Block, BlockHeaderHash},
+    transaction::{OutPoint, TransactionHash},
+    types::BlockHeight,
+    Network,
+};
+
+/// An inconsistency in the stored state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Inconsistency {
+    /// There is no best chain block at this height, but there are blocks
+    /// above it, or the best chain entry for this height is corrupt.
+    MissingHeight(BlockHeight),
+    /// The `by_hash` entry for the block is missing or has another height.
+    HashIndex {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// The block header is missing or can't be deserialized.
+    Header {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// The hash of the stored header doesn't match the hash in the best chain.
+    BlockHash {
+        height: BlockHeight,
+        stored: BlockHeaderHash,
+        computed: BlockHeaderHash,
+    },
+    /// The block doesn't follow the block at the height below it.
+    ParentHash {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// The block info is missing, can't be deserialized, or has the wrong
+    /// transaction count.
+    BlockInfo {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// The block body is missing or can't be deserialized.
+    Block {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+        error: String,
+    },
+    /// The height in the coinbase transaction doesn't match the stored
+    /// height.
+    CoinbaseHeight {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// The transaction merkle root doesn't match the header.
+    MerkleRoot {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// The location of a transaction in the block is missing or incorrect.
+    TransactionLocation {
+        height: BlockHeight,
+        txid: TransactionHash,
+    },
+    /// A transparent output created by the block is missing or has the
+    /// wrong value.
+    TransparentOutput {
+        height: BlockHeight,
+        outpoint: OutPoint,
+    },
+    /// A transparent output spent by the block is missing.
+    SpentOutput {
+        height: BlockHeight,
+        outpoint: OutPoint,
+    },
+    /// A nullifier revealed by the block is missing from the nullifier
+    /// index, or has the wrong height.
+    Nullifier {
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+    },
+    /// There are more entries in an index than the best chain blocks
+    /// create.
+    OrphanedEntries { tree: &'static str, count: u64 },
+}
+
+impl Inconsistency {
+    /// Returns the height of the inconsistent block, if this inconsistency
+    /// is in the block or the best chain index, and can only be repaired by
+    /// removing the block.
+    fn broken_height(&self) -> Option<BlockHeight> {
+        match *self {
+            Inconsistency::MissingHeight(height)
+            | Inconsistency::HashIndex { height, .. }
+            | Inconsistency::Header { height, .. }
+            | Inconsistency::BlockHash { height, .. }
+            | Inconsistency::ParentHash { height, .. }
+            | Inconsistency::BlockInfo { height, .. }
+            | Inconsistency::Block { height, .. }
+            | Inconsistency::CoinbaseHeight { height, .. }
+            | Inconsistency::MerkleRoot { height, .. } => Some(height),
+            Inconsistency::TransactionLocation { .. }
+            | Inconsistency::TransparentOutput { .. }
+            | Inconsistency::SpentOutput { .. }
+            | Inconsistency::Nullifier { .. }
+            | Inconsistency::OrphanedEntries { .. } => None,
+        }
+    }
+
+    /// Returns the height of the block with an incorrect index entry, if the
+    /// entry can be rewritten from the block.
+    fn index_height(&self) -> Option<BlockHeight> {
+        match *self {
+            Inconsistency::TransactionLocation { height, .. }
+            | Inconsistency::TransparentOutput { height, .. }
+            | Inconsistency::Nullifier { height, .. } => Some(height),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::MissingHeight(height) => {
+                write!(f, "{:?}: best chain block is missing", height)
+            }
+            Inconsistency::HashIndex { height, hash } => {
+                write!(f, "{:?}: by_hash entry for {:?} is incorrect", height, hash)
+            }
+            Inconsistency::Header { height, hash } => {
+                write!(f, "{:?}: header of {:?} is unreadable", height, hash)
+            }
+            Inconsistency::BlockHash {
+                height,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "{:?}: header hash is {:?}, but the best chain has {:?}",
+                height, computed, stored
+            ),
+            Inconsistency::ParentHash { height, hash } => write!(
+                f,
+                "{:?}: {:?} doesn't follow the block below it",
+                height, hash
+            ),
+            Inconsistency::BlockInfo { height, hash } => {
+                write!(f, "{:?}: block info of {:?} is incorrect", height, hash)
+            }
+            Inconsistency::Block {
+                height,
+                hash,
+                error,
+            } => write!(f, "{:?}: {:?} is unreadable: {}", height, hash, error),
+            Inconsistency::CoinbaseHeight { height, hash } => write!(
+                f,
+                "{:?}: coinbase height of {:?} doesn't match",
+                height, hash
+            ),
+            Inconsistency::MerkleRoot { height, hash } => write!(
+                f,
+                "{:?}: merkle root of {:?} doesn't match its header",
+                height, hash
+            ),
+            Inconsistency::TransactionLocation { height, txid } => write!(
+                f,
+                "{:?}: location of transaction {:?} is incorrect",
+                height, txid
+            ),
+            Inconsistency::TransparentOutput { height, outpoint } => {
+                write!(f, "{:?}: output {:?} is incorrect", height, outpoint)
+            }
+            Inconsistency::SpentOutput { height, outpoint } => {
+                write!(f, "{:?}: spent output {:?} is missing", height, outpoint)
+            }
+            Inconsistency::Nullifier { height, hash } => write!(
+                f,
+                "{:?}: nullifier index entries of {:?} are incorrect",
+                height, hash
+            ),
+            Inconsistency::OrphanedEntries { tree, count } => {
+                write!(
+                    f,
+                    "{} has {} entries for blocks outside the best chain",
+                    tree, count
+                )
+            }
+        }
+    }
+}
+
+/// The result of an integrity check.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// The number of best chain blocks that were checked
+    pub checked_blocks: u64,
+    /// The inconsistencies that were found, in height order
+    pub inconsistencies: Vec<Inconsistency>,
+    /// The number of index inconsistencies that were repaired by rewriting
+    /// index entries
+    pub rewritten: usize,
+    /// The hashes of the blocks removed by the repair, in height order
+    pub removed: Vec<BlockHeaderHash>,
+}
+
+/// The number of index entries created by a block.
+#[derive(Default)]
+struct IndexCounts {
+    transactions: u64,
+    outputs: u64,
+    nullifiers: u64,
+}
+
+impl SledState {
+    /// Check every block in the stored best chain, and cross-check the block
+    /// indexes against the block data.
+    ///
+    /// Pruned blocks don't have bodies, so only their headers and best
+    /// chain indexes are checked.
+    pub(super) fn verify(&self) -> Result<VerifyReport, Error> {
+        let pruned_height = self.pruned_height()?;
+        let nullifier_start = self.nullifier_index_start()?;
+        let mut report = VerifyReport::default();
+        let mut totals = IndexCounts::default();
+        let mut next_height = BlockHeight(0);
+        let mut parent_hash = None;
+
+        for entry in self.storage.iterate(tree::BY_HEIGHT, all_keys())? {
+            let (key, hash) = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    // The hash is unreadable, so the block at this height is
+                    // missing from the best chain
+                    let key = match CorruptValue::from_error(error.as_ref()) {
+                        Some(corrupt) => corrupt.key.clone(),
+                        None => return Err(error),
+                    };
+                    let height = BlockHeight(u32::from_be_bytes(key.as_slice().try_into()?));
+                    report
+                        .inconsistencies
+                        .push(Inconsistency::MissingHeight(height));
+                    next_height = BlockHeight(height.0 + 1);
+                    parent_hash = None;
+                    continue;
+                }
+            };
+            let height = BlockHeight(u32::from_be_bytes(key.as_slice().try_into()?));
+            let hash = BlockHeaderHash(<[u8; 32]>::try_from(&hash[..])?);
+            if height != next_height {
+                report
+                    .inconsistencies
+                    .push(Inconsistency::MissingHeight(next_height));
+                parent_hash = None;
+            }
+
+            let counts = self.verify_block(
+                height,
+                hash,
+                parent_hash,
+                height.0 < pruned_height,
+                height >= nullifier_start,
+                &mut report.inconsistencies,
+            )?;
+            totals.transactions += counts.transactions;
+            totals.outputs += counts.outputs;
+            totals.nullifiers += counts.nullifiers;
+            report.checked_blocks += 1;
+            next_height = BlockHeight(height.0 + 1);
+            parent_hash = Some(hash);
+        }
+
+        // Pruning removes spent outputs, so only unpruned states have every
+        // output
+        let mut expected = vec![(tree::TRANSACTION_LOCATIONS, totals.transactions)];
+        if pruned_height == 0 {
+            expected.push((tree::TRANSPARENT_OUTPUTS, totals.outputs));
+        }
+        // Pruned blocks don't have bodies, so their nullifiers can't be
+        // counted
+        if pruned_height <= nullifier_start.0 {
+            expected.push((tree::NULLIFIERS, totals.nullifiers));
+        }
+        for (tree, expected) in expected {
+            let mut entries = 0;
+            for entry in self.storage.iterate(tree, all_keys())? {
+                corrupt_as_missing(entry.map(Some))?;
+                entries += 1;
+            }
+            if entries > expected {
+                report.inconsistencies.push(Inconsistency::OrphanedEntries {
+                    tree,
+                    count: entries - expected,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Check the stored block with `hash` at `height`, using the hash of the
+    /// stored block below it, if any.
+    ///
+    /// Adds any inconsistencies to `found`, and returns the number of index
+    /// entries the block creates.
+    fn verify_block(
+        &self,
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+        parent_hash: Option<BlockHeaderHash>,
+        is_pruned: bool,
+        indexes_nullifiers: bool,
+        found: &mut Vec<Inconsistency>,
+    ) -> Result<IndexCounts, Error> {
+        let mut counts = IndexCounts::default();
+
+        if corrupt_as_missing(self.best_chain_height(hash))? != Some(height) {
+            found.push(Inconsistency::HashIndex { height, hash });
+        }
+        let header = match self.get_header(hash) {
+            Ok(Some(header)) => header,
+            Ok(None) | Err(_) => {
+                found.push(Inconsistency::Header { height, hash });
+                return Ok(counts);
+            }
+        };
+        let computed = BlockHeaderHash::from(&header);
+        if computed != hash {
+            found.push(Inconsistency::BlockHash {
+                height,
+                stored: hash,
+                computed,
+            });
+        }
+        if parent_hash.map_or(false, |parent_hash| {
+            header.previous_block_hash != parent_hash
+        }) {
+            found.push(Inconsistency::ParentHash { height, hash });
+        }
+
+        let info = match self.block_info(hash) {
+            Ok(Some(info)) => Some(info),
+            Ok(None) | Err(_) => {
+                found.push(Inconsistency::BlockInfo { height, hash });
+                None
+            }
+        };
+        if let Some(info) = &info {
+            counts.transactions = info.tx_count.into();
+        }
+        if is_pruned {
+            return Ok(counts);
+        }
+
+        let block = match self.get(hash) {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                found.push(Inconsistency::Block {
+                    height,
+                    hash,
+                    error: "the block is missing".to_string(),
+                });
+                return Ok(counts);
+            }
+            Err(error) => {
+                found.push(Inconsistency::Block {
+                    height,
+                    hash,
+                    error: error.to_string(),
+                });
+                return Ok(counts);
+            }
+        };
+        if block.coinbase_height() != Some(height) {
+            found.push(Inconsistency::CoinbaseHeight { height, hash });
+        }
+        if block.merkle_root() != header.merkle_root_hash {
+            found.push(Inconsistency::MerkleRoot { height, hash });
+        }
+        if let Some(BlockInfo { tx_count, .. }) = info {
+            if usize::try_from(tx_count)? != block.transactions.len() {
+                found.push(Inconsistency::BlockInfo { height, hash });
+            }
+        }
+
+        self.verify_indexes(&block, height, found)?;
+        counts.transactions = block.transactions.len() as u64;
+        counts.outputs = block_outputs(&block).len() as u64;
+        if indexes_nullifiers {
+            counts.nullifiers = self.verify_nullifiers(&block, height, hash, found)?;
+        }
+
+        Ok(counts)
+    }
+
+    /// Check the transaction location and transparent output entries for
+    /// `block` at `height`, adding any inconsistencies to `found`.
+    fn verify_indexes(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+        found: &mut Vec<Inconsistency>,
+    ) -> Result<(), Error> {
+        for (txid, location) in transaction_locations(block, height) {
+            let stored =
+                corrupt_as_missing(self.storage.read(tree::TRANSACTION_LOCATIONS, &txid.0[..]))?;
+            if stored.as_deref() != Some(&location[..]) {
+                found.push(Inconsistency::TransactionLocation { height, txid });
+            }
+        }
+        for (outpoint, value) in block_outputs(block) {
+            let stored = corrupt_as_missing(
+                self.storage
+                    .read(tree::TRANSPARENT_OUTPUTS, &outpoint_key(&outpoint)[..]),
+            )?;
+            let stored = stored.map(|bytes| amount_from_bytes(&bytes).map(i64::from).ok());
+            if stored != Some(Some(i64::from(value))) {
+                found.push(Inconsistency::TransparentOutput { height, outpoint });
+            }
+        }
+        for outpoint in block_spends(block) {
+            if corrupt_as_missing(
+                self.storage
+                    .read(tree::TRANSPARENT_OUTPUTS, &outpoint_key(&outpoint)[..]),
+            )?
+            .is_none()
+            {
+                found.push(Inconsistency::SpentOutput { height, outpoint });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the nullifier index entries for `block` with `hash` at `height`,
+    /// adding any inconsistencies to `found`.
+    ///
+    /// Returns the number of nullifiers the block reveals.
+    fn verify_nullifiers(
+        &self,
+        block: &Block,
+        height: BlockHeight,
+        hash: BlockHeaderHash,
+        found: &mut Vec<Inconsistency>,
+    ) -> Result<u64, Error> {
+        let nullifiers = block_nullifiers(block);
+        for key in &nullifiers {
+            if corrupt_as_missing(self.nullifier_height(key))? != Some(height) {
+                found.push(Inconsistency::Nullifier { height, hash });
+                break;
+            }
+        }
+
+        Ok(nullifiers.len() as u64)
+    }
+
+    /// Repair the inconsistencies in `report`.
+    ///
+    /// Rewrites the incorrect index entries of consistent blocks, then
+    /// removes the first inconsistent block and all the blocks above it.
+    /// Spent outputs and orphaned index entries can't be repaired, because
+    /// the state doesn't have the data to rebuild them.
+    pub(super) fn repair(&mut self, report: &mut VerifyReport) -> Result<(), Error> {
+        let first_broken = report
+            .inconsistencies
+            .iter()
+            .filter_map(Inconsistency::broken_height)
+            .min();
+        let index_heights: BTreeSet<BlockHeight> = report
+            .inconsistencies
+            .iter()
+            .filter_map(Inconsistency::index_height)
+            .filter(|&height| first_broken.map_or(true, |first_broken| height < first_broken))
+            .collect();
+
+        let nullifier_start = self.nullifier_index_start()?;
+        let mut batch = WriteBatch::default();
+        for &height in &index_heights {
+            let block = self
+                .get(height)?
+                .ok_or("block with an index inconsistency is missing")?;
+            for (txid, location) in transaction_locations(&block, height) {
+                batch.insert(tree::TRANSACTION_LOCATIONS, &txid.0[..], &location[..]);
+            }
+            if height >= nullifier_start {
+                for key in block_nullifiers(&block) {
+                    batch.insert(tree::NULLIFIERS, &key[..], &height.0.to_be_bytes()[..]);
+                }
+            }
+            for (outpoint, value) in block_outputs(&block) {
+                batch.insert(
+                    tree::TRANSPARENT_OUTPUTS,
+                    &outpoint_key(&outpoint)[..],
+                    &i64::from(value).to_le_bytes()[..],
+                );
+            }
+        }
+        if !batch.is_empty() {
+            self.storage.write_batch(batch)?;
+        }
+        report.rewritten = report
+            .inconsistencies
+            .iter()
+            .filter_map(Inconsistency::index_height)
+            .filter(|height| index_heights.contains(height))
+            .count();
+
+        if let Some(first_broken) = first_broken {
+            tracing::warn!(
+                ?first_broken,
+                "rolling back to the block below the first inconsistent block"
+            );
+            report.removed = match self.remove_from(first_broken, None) {
+                Ok(removed) => removed,
+                Err(error) => {
+                    tracing::warn!(
+                        ?error,
+                        "could not read the removed blocks, removing them without their index entries"
+                    );
+                    self.truncate(first_broken)?
+                }
+            };
+        }
+        self.storage.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Returns `result`, with values that fail their checksums replaced by
+/// `None`, so they are reported as inconsistencies.
+fn corrupt_as_missing<T>(result: Result<Option<T>, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Err(error) if CorruptValue::from_error(error.as_ref()).is_some() => Ok(None),
+        result => result,
+    }
+}
+
+/// Check the integrity of the state for `network`, and report any
+/// inconsistencies. If `repair` is true, also repair them.
+///
+/// The state is opened by this function, so Zebra must not be running. The
+/// startup check is skipped, so inconsistent blocks are reported rather than
+/// silently rolled back.
+pub fn verify_state(
+    config: &Config,
+    network: Network,
+    repair: bool,
+) -> Result<VerifyReport, Error> {
+    let config = Config {
+        prune: false,
+        block_cache_bytes: 0,
+        startup_check_depth: 0,
+        recover_corruption: false,
+        ..config.clone()
+    };
+    let mut state = SledState::new(&config, network);
+    let mut report = state.verify()?;
+
+    tracing::info!(
+        checked_blocks = report.checked_blocks,
+        inconsistencies = report.inconsistencies.len(),
+        "verified state"
+    );
+    if repair && !report.inconsistencies.is_empty() {
+        state.repair(&mut report)?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackendKind;
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    fn state_with_blocks() -> Result<(SledState, Vec<BlockHeaderHash>), Error> {
+        let config = Config {
+            storage_backend: StorageBackendKind::Memory,
+            ..Config::default()
+        };
+        let mut state = SledState::new(&config, Network::Mainnet);
+        let mut hashes = Vec::new();
+        for bytes in &[
+            &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+            &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+            &zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..],
+        ] {
+            hashes.push(state.insert(Block::zcash_deserialize(*bytes)?)?);
+        }
+
+        Ok((state, hashes))
+    }
+
+    #[test]
+    fn consistent_state_has_no_inconsistencies() -> Result<(), Error> {
+        zebra_test::init();
+
+        let (state, _hashes) = state_with_blocks()?;
+        let report = state.verify()?;
+        assert_eq!(report.checked_blocks, 3);
+        assert_eq!(report.inconsistencies, Vec::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn index_inconsistencies_are_rewritten() -> Result<(), Error> {
+        zebra_test::init();
+
+        let (mut state, _hashes) = state_with_blocks()?;
+        let block1 = state.get(BlockHeight(1))?.expect("block 1 is stored");
+        let txid = TransactionHash::from(block1.transactions[0].as_ref().clone());
+        state.storage.remove(tree::TRANSACTION_LOCATIONS, &txid.0)?;
+
+        let mut report = state.verify()?;
+        assert_eq!(
+            report.inconsistencies,
+            vec![Inconsistency::TransactionLocation {
+                height: BlockHeight(1),
+                txid,
+            }]
+        );
+
+        state.repair(&mut report)?;
+        assert_eq!(report.rewritten, 1);
+        assert!(report.removed.is_empty());
+        assert_eq!(state.verify()?.inconsistencies, Vec::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn broken_blocks_are_rolled_back() -> Result<(), Error> {
+        zebra_test::init();
+
+        let (mut state, hashes) = state_with_blocks()?;
+        state.storage.remove(tree::BLOCK_INFO, &hashes[1].0)?;
+
+        let mut report = state.verify()?;
+        assert_eq!(
+            report.inconsistencies,
+            vec![Inconsistency::BlockInfo {
+                height: BlockHeight(1),
+                hash: hashes[1],
+            }]
+        );
+
+        state.repair(&mut report)?;
+        assert_eq!(report.removed, hashes[1..].to_vec());
+        assert_eq!(state.tip_height(), Some(BlockHeight(0)));
+        assert_eq!(state.verify()?.inconsistencies, Vec::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn orphaned_nullifiers_are_reported() -> Result<(), Error> {
+        zebra_test::init();
+
+        // The test blocks don't reveal any nullifiers
+        let (state, _hashes) = state_with_blocks()?;
+        state
+            .storage
+            .insert(tree::NULLIFIERS, &[1; 33], &2u32.to_be_bytes())?;
+
+        assert_eq!(
+            state.verify()?.inconsistencies,
+            vec![Inconsistency::OrphanedEntries {
+                tree: tree::NULLIFIERS,
+                count: 1,
+            }]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+//! Blocks that are waiting for their parent block to be committed.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use futures::channel::oneshot;
+use zebra_chain::{block::BlockHeaderHash, types::BlockHeight};
+
+use crate::{Response, SemanticallyVerifiedBlock};
+
+/// A block that is waiting to be committed, and the channel for its commit
+/// result.
+pub(crate) type QueuedBlock = (
+    SemanticallyVerifiedBlock,
+    oneshot::Sender<Result<Response, Error>>,
+);
+
+/// The maximum number of queued blocks.
+///
+/// This is larger than the syncer's lookahead limit, so blocks from the
+/// syncer are only rejected if a large gap stays in the chain.
+pub(crate) const MAX_QUEUED_BLOCKS: usize = 10_000;
+
+/// Blocks that were sent to the state before their parent block.
+///
+/// Each block is committed as soon as its parent is committed, so callers
+/// can download and verify blocks in any order.
+pub(crate) struct QueuedBlocks {
+    /// The maximum number of queued blocks
+    max_blocks: usize,
+    /// Queued blocks, by hash
+    blocks: HashMap<BlockHeaderHash, QueuedBlock>,
+    /// Hashes of queued blocks, by the hash of their parent block
+    by_parent: HashMap<BlockHeaderHash, HashSet<BlockHeaderHash>>,
+    /// Hashes of queued blocks, by height
+    by_height: BTreeMap<BlockHeight, HashSet<BlockHeaderHash>>,
+}
+
+impl Default for QueuedBlocks {
+    fn default() -> Self {
+        QueuedBlocks {
+            max_blocks: MAX_QUEUED_BLOCKS,
+            blocks: Default::default(),
+            by_parent: Default::default(),
+            by_height: Default::default(),
+        }
+    }
+}
+
+impl QueuedBlocks {
+    /// Is the block with `hash` queued?
+    pub(crate) fn contains(&self, hash: &BlockHeaderHash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Queue `block` until its parent is committed.
+    ///
+    /// Its commit result is sent to `tx`.
+    ///
+    /// If the queue is full, the highest queued block is rejected, so blocks
+    /// near the tip can still be committed. If `block` is at or above the
+    /// highest queued block, `block` is rejected instead.
+    pub(crate) fn queue(
+        &mut self,
+        block: SemanticallyVerifiedBlock,
+        tx: oneshot::Sender<Result<Response, Error>>,
+    ) {
+        if self.blocks.len() >= self.max_blocks {
+            let highest = self
+                .by_height
+                .iter()
+                .next_back()
+                .and_then(|(height, hashes)| Some((*height, *hashes.iter().next()?)));
+            match highest {
+                Some((height, hash)) if height > block.height() => {
+                    // Queued blocks have higher heights than their parents,
+                    // so the highest block doesn't have any queued children
+                    if let Some((_, tx)) = self.remove(&hash) {
+                        let _ = tx.send(Err(QUEUE_FULL.into()));
+                    }
+                }
+                _ => {
+                    let _ = tx.send(Err(QUEUE_FULL.into()));
+                    return;
+                }
+            }
+        }
+
+        let hash = block.hash();
+        let parent = block.block().header.previous_block_hash;
+
+        self.by_parent.entry(parent).or_default().insert(hash);
+        self.by_height
+            .entry(block.height())
+            .or_default()
+            .insert(hash);
+        self.blocks.insert(hash, (block, tx));
+
+        metrics::gauge!("state.queued.block.count", self.blocks.len() as i64);
+    }
+
+    /// Remove and return the queued block with `hash`, without removing its
+    /// children.
+    fn remove(&mut self, hash: &BlockHeaderHash) -> Option<QueuedBlock> {
+        let (block, tx) = self.blocks.remove(hash)?;
+
+        let parent = block.block().header.previous_block_hash;
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.remove(hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        if let Some(hashes) = self.by_height.get_mut(&block.height()) {
+            hashes.remove(hash);
+            if hashes.is_empty() {
+                self.by_height.remove(&block.height());
+            }
+        }
+
+        metrics::gauge!("state.queued.block.count", self.blocks.len() as i64);
+        Some((block, tx))
+    }
+
+    /// Remove and return the queued children of `parent`.
+    fn dequeue_children(&mut self, parent: BlockHeaderHash) -> Vec<QueuedBlock> {
+        let children: Vec<_> = self
+            .by_parent
+            .remove(&parent)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|hash| self.blocks.remove(&hash))
+            .collect();
+
+        for (block, _) in &children {
+            if let Some(hashes) = self.by_height.get_mut(&block.height()) {
+                hashes.remove(&block.hash());
+                if hashes.is_empty() {
+                    self.by_height.remove(&block.height());
+                }
+            }
+        }
+
+        metrics::gauge!("state.queued.block.count", self.blocks.len() as i64);
+        children
+    }
+
+    /// Commit the queued descendants of `parent` using `commit`, and send
+    /// each result to the caller that is waiting for it.
+    ///
+    /// The queued descendants of blocks that fail to commit are rejected,
+    /// because they can never be committed.
+    pub(crate) fn commit_descendants(
+        &mut self,
+        parent: BlockHeaderHash,
+        mut commit: impl FnMut(SemanticallyVerifiedBlock) -> Result<Response, Error>,
+    ) {
+        let mut parents = vec![parent];
+
+        while let Some(parent) = parents.pop() {
+            for (block, tx) in self.dequeue_children(parent) {
+                let hash = block.hash();
+                let result = commit(block);
+                if result.is_ok() {
+                    parents.push(hash);
+                } else {
+                    self.reject_descendants(hash);
+                }
+
+                // The caller might have stopped waiting for the result
+                let _ = tx.send(result);
+            }
+        }
+    }
+
+    /// Reject the queued descendants of `parent`, which failed to commit.
+    fn reject_descendants(&mut self, parent: BlockHeaderHash) {
+        let mut parents = vec![parent];
+
+        while let Some(parent) = parents.pop() {
+            for (block, tx) in self.dequeue_children(parent) {
+                parents.push(block.hash());
+                let _ = tx.send(Err(
+                    "an ancestor of the queued block failed to commit".into()
+                ));
+            }
+        }
+    }
+
+    /// Reject the queued blocks at or below `finalized_height`.
+    ///
+    /// These blocks can never be committed, because finalized blocks can't
+    /// be replaced.
+    pub(crate) fn prune(&mut self, finalized_height: BlockHeight) {
+        let above = self
+            .by_height
+            .split_off(&BlockHeight(finalized_height.0 + 1));
+        let pruned = std::mem::replace(&mut self.by_height, above);
+
+        for hash in pruned.into_iter().flat_map(|(_, hashes)| hashes) {
+            if let Some((block, tx)) = self.blocks.remove(&hash) {
+                let parent = block.block().header.previous_block_hash;
+                if let Some(siblings) = self.by_parent.get_mut(&parent) {
+                    siblings.remove(&hash);
+                    if siblings.is_empty() {
+                        self.by_parent.remove(&parent);
+                    }
+                }
+
+                let _ = tx.send(Err(
+                    "a finalized block was committed at or above the queued block's height".into(),
+                ));
+            }
+        }
+
+        metrics::gauge!("state.queued.block.count", self.blocks.len() as i64);
+    }
+}
+
+/// The error for blocks that are rejected because the queue is full.
+const QUEUE_FULL: &str = "too many blocks are waiting for their parent blocks";
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use zebra_chain::{block::Block, serialization::ZcashDeserialize};
+
+    fn block(bytes: &[u8]) -> SemanticallyVerifiedBlock {
+        let block = Block::zcash_deserialize(bytes).unwrap();
+        SemanticallyVerifiedBlock::new(block.into()).unwrap()
+    }
+
+    #[test]
+    fn queued_blocks_are_committed_in_order() {
+        zebra_test::init();
+
+        let block0 = block(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..]);
+        let block1 = block(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..]);
+        let block2 = block(&zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..]);
+        let (hash0, hash1, hash2) = (block0.hash(), block1.hash(), block2.hash());
+
+        let mut queued = QueuedBlocks::default();
+        let (tx2, mut rx2) = oneshot::channel();
+        queued.queue(block2, tx2);
+        let (tx1, mut rx1) = oneshot::channel();
+        queued.queue(block1, tx1);
+        assert!(queued.contains(&hash1));
+
+        let mut committed = Vec::new();
+        queued.commit_descendants(hash0, |block| {
+            committed.push(block.hash());
+            Ok(Response::Committed { hash: block.hash() })
+        });
+
+        assert_eq!(committed, vec![hash1, hash2]);
+        assert_eq!(
+            rx1.try_recv().unwrap().unwrap().unwrap(),
+            Response::Committed { hash: hash1 }
+        );
+        assert_eq!(
+            rx2.try_recv().unwrap().unwrap().unwrap(),
+            Response::Committed { hash: hash2 }
+        );
+        assert!(!queued.contains(&hash1));
+        assert!(queued.by_height.is_empty());
+    }
+
+    #[test]
+    fn queued_blocks_are_pruned() {
+        zebra_test::init();
+
+        let block1 = block(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..]);
+        let block2 = block(&zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..]);
+        let (hash1, hash2) = (block1.hash(), block2.hash());
+        let parent1 = block1.block().header.previous_block_hash;
+
+        let mut queued = QueuedBlocks::default();
+        let (tx1, mut rx1) = oneshot::channel();
+        queued.queue(block1, tx1);
+        let (tx2, _rx2) = oneshot::channel();
+        queued.queue(block2, tx2);
+
+        queued.prune(BlockHeight(1));
+
+        assert!(rx1.try_recv().unwrap().unwrap().is_err());
+        assert!(!queued.contains(&hash1));
+        assert!(queued.contains(&hash2));
+        assert!(!queued.by_parent.contains_key(&parent1));
+    }
+
+    #[test]
+    fn descendants_of_failed_blocks_are_rejected() {
+        zebra_test::init();
+
+        let block0 = block(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..]);
+        let block1 = block(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..]);
+        let block2 = block(&zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..]);
+        let block3 = block(&zebra_test::vectors::BLOCK_MAINNET_3_BYTES[..]);
+        let hash0 = block0.hash();
+
+        let mut queued = QueuedBlocks::default();
+        let (tx1, mut rx1) = oneshot::channel();
+        queued.queue(block1, tx1);
+        let (tx2, mut rx2) = oneshot::channel();
+        queued.queue(block2, tx2);
+        let (tx3, mut rx3) = oneshot::channel();
+        queued.queue(block3, tx3);
+
+        let mut attempts = 0;
+        queued.commit_descendants(hash0, |_block| {
+            attempts += 1;
+            Err("invalid block".into())
+        });
+
+        assert_eq!(attempts, 1);
+        assert!(rx1.try_recv().unwrap().unwrap().is_err());
+        assert!(rx2.try_recv().unwrap().unwrap().is_err());
+        assert!(rx3.try_recv().unwrap().unwrap().is_err());
+        assert!(queued.blocks.is_empty());
+        assert!(queued.by_parent.is_empty());
+        assert!(queued.by_height.is_empty());
+    }
+
+    #[test]
+    fn full_queues_reject_the_highest_block() {
+        zebra_test::init();
+
+        let block1 = block(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..]);
+        let block2 = block(&zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..]);
+        let block3 = block(&zebra_test::vectors::BLOCK_MAINNET_3_BYTES[..]);
+        let (hash1, hash2, hash3) = (block1.hash(), block2.hash(), block3.hash());
+
+        let mut queued = QueuedBlocks {
+            max_blocks: 1,
+            ..Default::default()
+        };
+
+        let (tx2, mut rx2) = oneshot::channel();
+        queued.queue(block2, tx2);
+        // Higher blocks are rejected
+        let (tx3, mut rx3) = oneshot::channel();
+        queued.queue(block3, tx3);
+        assert!(rx3.try_recv().unwrap().unwrap().is_err());
+        assert!(!queued.contains(&hash3));
+        assert!(rx2.try_recv().unwrap().is_none());
+
+        // Lower blocks replace the highest block
+        let (tx1, mut rx1) = oneshot::channel();
+        queued.queue(block1, tx1);
+        assert!(rx2.try_recv().unwrap().unwrap().is_err());
+        assert!(!queued.contains(&hash2));
+        assert!(queued.contains(&hash1));
+        assert!(rx1.try_recv().unwrap().is_none());
+        assert_eq!(queued.by_height.len(), 1);
+        assert_eq!(queued.by_parent.len(), 1);
+    }
+}
@@ -0,0 +1,832 @@
+//! Recordings of state requests, for benchmark replay.
+//!
+//! A recording captures the type, keys, and timing of each request sent to a
+//! state service, in a compact binary file. The read requests in a recording
+//! can be replayed against a copy of the state, to compare the performance of
+//! different versions using realistic access patterns.
+//!
+//! Requests that commit blocks are recorded using their block hashes, because
+//! storing every block would make recordings as large as the state.
+use crate::{OptionalIndex, Request};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::Service;
+use zebra_chain::{
+    block::BlockHeaderHash,
+    serialization::{
+        ReadZcashExt, SerializationError, WriteZcashExt, ZcashDeserialize, ZcashSerialize,
+    },
+    transaction::{Transaction, TransactionHash},
+    types::BlockHeight,
+};
+
+/// The magic bytes at the start of a recording file.
+const MAGIC: [u8; 8] = *b"zsreqrec";
+
+/// The version of the recording format.
+const VERSION: u8 = 1;
+
+/// The recording is flushed to disk after this many requests.
+const FLUSH_INTERVAL: usize = 64;
+
+/// A recorded state request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordedRequest {
+    /// A request that commits blocks, recorded by the hashes of its blocks
+    ///
+    /// The recording doesn't contain the blocks, so these requests can't be
+    /// replayed.
+    Commit {
+        /// The type of the request
+        kind: RequestKind,
+        /// The hashes of the committed blocks, in request order
+        hashes: Vec<BlockHeaderHash>,
+    },
+    /// Any other request, recorded with all its keys
+    Request(Request),
+}
+
+impl RecordedRequest {
+    /// Returns the recorded form of `request`.
+    pub fn new(request: &Request) -> Self {
+        let kind = RequestKind::of(request);
+        match request {
+            Request::CommitBlock { block } | Request::CommitFinalizedBlock { block } => {
+                RecordedRequest::Commit {
+                    kind,
+                    hashes: vec![block.hash()],
+                }
+            }
+            Request::AddBlockBatch { blocks } => RecordedRequest::Commit {
+                kind,
+                hashes: blocks.iter().map(|block| block.hash()).collect(),
+            },
+            request => RecordedRequest::Request(request.clone()),
+        }
+    }
+
+    /// Returns the type of the recorded request.
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            RecordedRequest::Commit { kind, .. } => *kind,
+            RecordedRequest::Request(request) => RequestKind::of(request),
+        }
+    }
+}
+
+/// A recorded state request, and the time it was made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedEntry {
+    /// The time between the start of the recording and the request
+    pub offset: Duration,
+    /// The request
+    pub request: RecordedRequest,
+}
+
+/// The type of a state request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[allow(missing_docs)]
+pub enum RequestKind {
+    CommitBlock = 0,
+    CommitFinalizedBlock = 1,
+    AddBlockBatch = 2,
+    RollbackToHeight = 3,
+    InvalidateBlock = 4,
+    ReconsiderBlock = 5,
+    GetBlock = 6,
+    GetRawBlock = 7,
+    BestChainBlockHash = 8,
+    AncestorHash = 9,
+    GetBlockLocator = 10,
+    FindBlockHashes = 11,
+    FindBlockHeaders = 12,
+    BlockInfo = 13,
+    GetTip = 14,
+    GetChainTips = 15,
+    GetDepth = 16,
+    BlockCount = 17,
+    GetValueBalances = 18,
+    AddressBalance = 19,
+    UtxosByAddresses = 20,
+    TransactionIdsByAddresses = 21,
+    NoteCommitmentTrees = 22,
+    HistoryTree = 23,
+    TransactionMerklePath = 24,
+    SaplingSubtrees = 25,
+    CompactNow = 26,
+    BlockTransactionInfo = 27,
+    ChainTxStats = 28,
+    RecordFailedBlock = 29,
+    FailedBlock = 30,
+    ShieldedCounts = 31,
+    MissingIndexRanges = 32,
+    TransactionFee = 33,
+}
+
+impl RequestKind {
+    /// Every request type, in tag order.
+    pub const ALL: [RequestKind; 34] = [
+        RequestKind::CommitBlock,
+        RequestKind::CommitFinalizedBlock,
+        RequestKind::AddBlockBatch,
+        RequestKind::RollbackToHeight,
+        RequestKind::InvalidateBlock,
+        RequestKind::ReconsiderBlock,
+        RequestKind::GetBlock,
+        RequestKind::GetRawBlock,
+        RequestKind::BestChainBlockHash,
+        RequestKind::AncestorHash,
+        RequestKind::GetBlockLocator,
+        RequestKind::FindBlockHashes,
+        RequestKind::FindBlockHeaders,
+        RequestKind::BlockInfo,
+        RequestKind::GetTip,
+        RequestKind::GetChainTips,
+        RequestKind::GetDepth,
+        RequestKind::BlockCount,
+        RequestKind::GetValueBalances,
+        RequestKind::AddressBalance,
+        RequestKind::UtxosByAddresses,
+        RequestKind::TransactionIdsByAddresses,
+        RequestKind::NoteCommitmentTrees,
+        RequestKind::HistoryTree,
+        RequestKind::TransactionMerklePath,
+        RequestKind::SaplingSubtrees,
+        RequestKind::CompactNow,
+        RequestKind::BlockTransactionInfo,
+        RequestKind::ChainTxStats,
+        RequestKind::RecordFailedBlock,
+        RequestKind::FailedBlock,
+        RequestKind::ShieldedCounts,
+        RequestKind::MissingIndexRanges,
+        RequestKind::TransactionFee,
+    ];
+
+    /// Returns the type of `request`.
+    pub fn of(request: &Request) -> Self {
+        match request {
+            Request::CommitBlock { .. } => RequestKind::CommitBlock,
+            Request::CommitFinalizedBlock { .. } => RequestKind::CommitFinalizedBlock,
+            Request::AddBlockBatch { .. } => RequestKind::AddBlockBatch,
+            Request::RollbackToHeight { .. } => RequestKind::RollbackToHeight,
+            Request::InvalidateBlock { .. } => RequestKind::InvalidateBlock,
+            Request::ReconsiderBlock { .. } => RequestKind::ReconsiderBlock,
+            Request::GetBlock { .. } => RequestKind::GetBlock,
+            Request::GetRawBlock { .. } => RequestKind::GetRawBlock,
+            Request::BestChainBlockHash { .. } => RequestKind::BestChainBlockHash,
+            Request::AncestorHash { .. } => RequestKind::AncestorHash,
+            Request::GetBlockLocator { .. } => RequestKind::GetBlockLocator,
+            Request::FindBlockHashes { .. } => RequestKind::FindBlockHashes,
+            Request::FindBlockHeaders { .. } => RequestKind::FindBlockHeaders,
+            Request::BlockInfo { .. } => RequestKind::BlockInfo,
+            Request::GetTip => RequestKind::GetTip,
+            Request::GetChainTips => RequestKind::GetChainTips,
+            Request::GetDepth { .. } => RequestKind::GetDepth,
+            Request::BlockCount => RequestKind::BlockCount,
+            Request::GetValueBalances => RequestKind::GetValueBalances,
+            Request::AddressBalance { .. } => RequestKind::AddressBalance,
+            Request::UtxosByAddresses { .. } => RequestKind::UtxosByAddresses,
+            Request::TransactionIdsByAddresses { .. } => RequestKind::TransactionIdsByAddresses,
+            Request::NoteCommitmentTrees { .. } => RequestKind::NoteCommitmentTrees,
+            Request::HistoryTree { .. } => RequestKind::HistoryTree,
+            Request::TransactionMerklePath { .. } => RequestKind::TransactionMerklePath,
+            Request::SaplingSubtrees { .. } => RequestKind::SaplingSubtrees,
+            Request::CompactNow => RequestKind::CompactNow,
+            Request::BlockTransactionInfo { .. } => RequestKind::BlockTransactionInfo,
+            Request::ChainTxStats { .. } => RequestKind::ChainTxStats,
+            Request::RecordFailedBlock { .. } => RequestKind::RecordFailedBlock,
+            Request::FailedBlock { .. } => RequestKind::FailedBlock,
+            Request::ShieldedCounts { .. } => RequestKind::ShieldedCounts,
+            Request::MissingIndexRanges { .. } => RequestKind::MissingIndexRanges,
+            Request::TransactionFee { .. } => RequestKind::TransactionFee,
+        }
+    }
+
+    /// Returns the name of this request type.
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestKind::CommitBlock => "CommitBlock",
+            RequestKind::CommitFinalizedBlock => "CommitFinalizedBlock",
+            RequestKind::AddBlockBatch => "AddBlockBatch",
+            RequestKind::RollbackToHeight => "RollbackToHeight",
+            RequestKind::InvalidateBlock => "InvalidateBlock",
+            RequestKind::ReconsiderBlock => "ReconsiderBlock",
+            RequestKind::GetBlock => "GetBlock",
+            RequestKind::GetRawBlock => "GetRawBlock",
+            RequestKind::BestChainBlockHash => "BestChainBlockHash",
+            RequestKind::AncestorHash => "AncestorHash",
+            RequestKind::GetBlockLocator => "GetBlockLocator",
+            RequestKind::FindBlockHashes => "FindBlockHashes",
+            RequestKind::FindBlockHeaders => "FindBlockHeaders",
+            RequestKind::BlockInfo => "BlockInfo",
+            RequestKind::GetTip => "GetTip",
+            RequestKind::GetChainTips => "GetChainTips",
+            RequestKind::GetDepth => "GetDepth",
+            RequestKind::BlockCount => "BlockCount",
+            RequestKind::GetValueBalances => "GetValueBalances",
+            RequestKind::AddressBalance => "AddressBalance",
+            RequestKind::UtxosByAddresses => "UtxosByAddresses",
+            RequestKind::TransactionIdsByAddresses => "TransactionIdsByAddresses",
+            RequestKind::NoteCommitmentTrees => "NoteCommitmentTrees",
+            RequestKind::HistoryTree => "HistoryTree",
+            RequestKind::TransactionMerklePath => "TransactionMerklePath",
+            RequestKind::SaplingSubtrees => "SaplingSubtrees",
+            RequestKind::CompactNow => "CompactNow",
+            RequestKind::BlockTransactionInfo => "BlockTransactionInfo",
+            RequestKind::ChainTxStats => "ChainTxStats",
+            RequestKind::RecordFailedBlock => "RecordFailedBlock",
+            RequestKind::FailedBlock => "FailedBlock",
+            RequestKind::ShieldedCounts => "ShieldedCounts",
+            RequestKind::MissingIndexRanges => "MissingIndexRanges",
+            RequestKind::TransactionFee => "TransactionFee",
+        }
+    }
+
+    /// Returns true if requests of this type modify the state.
+    pub fn modifies_state(self) -> bool {
+        matches!(
+            self,
+            RequestKind::CommitBlock
+                | RequestKind::CommitFinalizedBlock
+                | RequestKind::AddBlockBatch
+                | RequestKind::RollbackToHeight
+                | RequestKind::InvalidateBlock
+                | RequestKind::ReconsiderBlock
+                | RequestKind::RecordFailedBlock
+        )
+    }
+
+    /// Returns the tag byte for this request type, in the recording format.
+    fn tag(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the request type for a tag byte, in the recording format.
+    fn from_tag(tag: u8) -> Result<Self, SerializationError> {
+        RequestKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.tag() == tag)
+            .ok_or(SerializationError::Parse("unknown recorded request type"))
+    }
+}
+
+impl ZcashSerialize for RecordedEntry {
+    fn zcash_serialize<W: io::Write>(&self, mut writer: W) -> Result<(), io::Error> {
+        writer.write_all(&(self.offset.as_micros() as u64).to_le_bytes())?;
+        writer.write_all(&[self.request.kind().tag()])?;
+
+        let request = match &self.request {
+            RecordedRequest::Commit { hashes, .. } => return hashes.zcash_serialize(writer),
+            RecordedRequest::Request(request) => request,
+        };
+        match request {
+            Request::CommitBlock { block } | Request::CommitFinalizedBlock { block } => {
+                vec![block.hash()].zcash_serialize(&mut writer)?
+            }
+            Request::AddBlockBatch { blocks } => {
+                let hashes: Vec<_> = blocks.iter().map(|block| block.hash()).collect();
+                hashes.zcash_serialize(&mut writer)?
+            }
+            Request::GetTip
+            | Request::GetChainTips
+            | Request::BlockCount
+            | Request::GetValueBalances
+            | Request::CompactNow => {}
+            Request::RollbackToHeight { height }
+            | Request::BestChainBlockHash { height }
+            | Request::NoteCommitmentTrees { height }
+            | Request::HistoryTree { height } => writer.write_all(&height.0.to_le_bytes())?,
+            Request::InvalidateBlock { hash }
+            | Request::ReconsiderBlock { hash }
+            | Request::GetBlock { hash }
+            | Request::GetRawBlock { hash }
+            | Request::GetBlockLocator { genesis: hash }
+            | Request::BlockInfo { hash }
+            | Request::BlockTransactionInfo { hash }
+            | Request::GetDepth { hash } => hash.zcash_serialize(&mut writer)?,
+            Request::AncestorHash { hash, height } => {
+                hash.zcash_serialize(&mut writer)?;
+                writer.write_all(&height.0.to_le_bytes())?;
+            }
+            Request::RecordFailedBlock {
+                hash,
+                verifier_version,
+                rule,
+            } => {
+                hash.zcash_serialize(&mut writer)?;
+                writer.write_all(&verifier_version.to_le_bytes())?;
+                writer.write_string(rule)?;
+            }
+            Request::FailedBlock {
+                hash,
+                verifier_version,
+            } => {
+                hash.zcash_serialize(&mut writer)?;
+                writer.write_all(&verifier_version.to_le_bytes())?;
+            }
+            Request::ChainTxStats { hash, window } => {
+                hash.zcash_serialize(&mut writer)?;
+                match window {
+                    Some(window) => {
+                        writer.write_all(&[1])?;
+                        writer.write_all(&window.to_le_bytes())?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+            }
+            Request::ShieldedCounts { height } => match height {
+                Some(height) => {
+                    writer.write_all(&[1])?;
+                    writer.write_all(&height.0.to_le_bytes())?;
+                }
+                None => writer.write_all(&[0])?,
+            },
+            Request::FindBlockHashes { known_blocks, stop }
+            | Request::FindBlockHeaders { known_blocks, stop } => {
+                known_blocks.zcash_serialize(&mut writer)?;
+                match stop {
+                    Some(stop) => {
+                        writer.write_all(&[1])?;
+                        stop.zcash_serialize(&mut writer)?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+            }
+            Request::AddressBalance { addresses } => addresses.zcash_serialize(&mut writer)?,
+            Request::UtxosByAddresses {
+                addresses,
+                start,
+                limit,
+            } => {
+                addresses.zcash_serialize(&mut writer)?;
+                writer.write_all(&start.0.to_le_bytes())?;
+                writer.write_compactsize(*limit as u64)?;
+            }
+            Request::TransactionIdsByAddresses {
+                addresses,
+                height_range,
+            } => {
+                addresses.zcash_serialize(&mut writer)?;
+                writer.write_all(&height_range.start().0.to_le_bytes())?;
+                writer.write_all(&height_range.end().0.to_le_bytes())?;
+            }
+            Request::TransactionMerklePath { txid } => writer.write_all(&txid.0)?,
+            Request::TransactionFee { transaction } => transaction.zcash_serialize(&mut writer)?,
+            Request::MissingIndexRanges { index } => writer.write_all(&[index.tag()])?,
+            Request::SaplingSubtrees { start_index, limit } => {
+                writer.write_all(&start_index.to_le_bytes())?;
+                writer.write_compactsize(*limit as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ZcashDeserialize for RecordedEntry {
+    fn zcash_deserialize<R: io::Read>(mut reader: R) -> Result<Self, SerializationError> {
+        let mut offset = [0; 8];
+        reader.read_exact(&mut offset)?;
+        let offset = Duration::from_micros(u64::from_le_bytes(offset));
+
+        let mut tag = [0; 1];
+        reader.read_exact(&mut tag)?;
+        let kind = RequestKind::from_tag(tag[0])?;
+
+        let height = |reader: &mut R| -> Result<BlockHeight, SerializationError> {
+            Ok(BlockHeight(u32::from_le_bytes(reader.read_4_bytes()?)))
+        };
+        let hash = |reader: &mut R| BlockHeaderHash::zcash_deserialize(reader);
+
+        let request = match kind {
+            RequestKind::CommitBlock
+            | RequestKind::CommitFinalizedBlock
+            | RequestKind::AddBlockBatch => {
+                let hashes = Vec::zcash_deserialize(&mut reader)?;
+                return Ok(RecordedEntry {
+                    offset,
+                    request: RecordedRequest::Commit { kind, hashes },
+                });
+            }
+            RequestKind::RollbackToHeight => Request::RollbackToHeight {
+                height: height(&mut reader)?,
+            },
+            RequestKind::InvalidateBlock => Request::InvalidateBlock {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::ReconsiderBlock => Request::ReconsiderBlock {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::GetBlock => Request::GetBlock {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::GetRawBlock => Request::GetRawBlock {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::BestChainBlockHash => Request::BestChainBlockHash {
+                height: height(&mut reader)?,
+            },
+            RequestKind::AncestorHash => Request::AncestorHash {
+                hash: hash(&mut reader)?,
+                height: height(&mut reader)?,
+            },
+            RequestKind::GetBlockLocator => Request::GetBlockLocator {
+                genesis: hash(&mut reader)?,
+            },
+            RequestKind::FindBlockHashes | RequestKind::FindBlockHeaders => {
+                let known_blocks = Vec::zcash_deserialize(&mut reader)?;
+                let mut has_stop = [0; 1];
+                reader.read_exact(&mut has_stop)?;
+                let stop = match has_stop[0] {
+                    0 => None,
+                    1 => Some(hash(&mut reader)?),
+                    _ => return Err(SerializationError::Parse("invalid recorded stop hash")),
+                };
+
+                if kind == RequestKind::FindBlockHashes {
+                    Request::FindBlockHashes { known_blocks, stop }
+                } else {
+                    Request::FindBlockHeaders { known_blocks, stop }
+                }
+            }
+            RequestKind::BlockInfo => Request::BlockInfo {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::BlockTransactionInfo => Request::BlockTransactionInfo {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::ChainTxStats => {
+                let hash = hash(&mut reader)?;
+                let mut has_window = [0; 1];
+                reader.read_exact(&mut has_window)?;
+                let window = match has_window[0] {
+                    0 => None,
+                    1 => Some(u32::from_le_bytes(reader.read_4_bytes()?)),
+                    _ => return Err(SerializationError::Parse("invalid recorded window")),
+                };
+
+                Request::ChainTxStats { hash, window }
+            }
+            RequestKind::RecordFailedBlock => Request::RecordFailedBlock {
+                hash: hash(&mut reader)?,
+                verifier_version: u32::from_le_bytes(reader.read_4_bytes()?),
+                rule: reader.read_string()?,
+            },
+            RequestKind::FailedBlock => Request::FailedBlock {
+                hash: hash(&mut reader)?,
+                verifier_version: u32::from_le_bytes(reader.read_4_bytes()?),
+            },
+            RequestKind::GetTip => Request::GetTip,
+            RequestKind::GetChainTips => Request::GetChainTips,
+            RequestKind::GetDepth => Request::GetDepth {
+                hash: hash(&mut reader)?,
+            },
+            RequestKind::BlockCount => Request::BlockCount,
+            RequestKind::GetValueBalances => Request::GetValueBalances,
+            RequestKind::CompactNow => Request::CompactNow,
+            RequestKind::NoteCommitmentTrees => Request::NoteCommitmentTrees {
+                height: height(&mut reader)?,
+            },
+            RequestKind::ShieldedCounts => {
+                let mut has_height = [0; 1];
+                reader.read_exact(&mut has_height)?;
+                let height = match has_height[0] {
+                    0 => None,
+                    1 => Some(height(&mut reader)?),
+                    _ => return Err(SerializationError::Parse("invalid recorded height")),
+                };
+
+                Request::ShieldedCounts { height }
+            }
+            RequestKind::HistoryTree => Request::HistoryTree {
+                height: height(&mut reader)?,
+            },
+            RequestKind::AddressBalance => Request::AddressBalance {
+                addresses: Vec::zcash_deserialize(&mut reader)?,
+            },
+            RequestKind::UtxosByAddresses => Request::UtxosByAddresses {
+                addresses: Vec::zcash_deserialize(&mut reader)?,
+                start: height(&mut reader)?,
+                limit: reader.read_compactsize()? as usize,
+            },
+            RequestKind::TransactionIdsByAddresses => {
+                let addresses = Vec::zcash_deserialize(&mut reader)?;
+                let start = height(&mut reader)?;
+                let end = height(&mut reader)?;
+                Request::TransactionIdsByAddresses {
+                    addresses,
+                    height_range: start..=end,
+                }
+            }
+            RequestKind::TransactionMerklePath => Request::TransactionMerklePath {
+                txid: TransactionHash(reader.read_32_bytes()?),
+            },
+            RequestKind::TransactionFee => Request::TransactionFee {
+                transaction: Transaction::zcash_deserialize(&mut reader)?.into(),
+            },
+            RequestKind::MissingIndexRanges => {
+                let mut index = [0; 1];
+                reader.read_exact(&mut index)?;
+                let index = OptionalIndex::from_tag(index[0])
+                    .ok_or(SerializationError::Parse("invalid recorded index"))?;
+
+                Request::MissingIndexRanges { index }
+            }
+            RequestKind::SaplingSubtrees => {
+                let mut start_index = [0; 2];
+                reader.read_exact(&mut start_index)?;
+                Request::SaplingSubtrees {
+                    start_index: u16::from_le_bytes(start_index),
+                    limit: reader.read_compactsize()? as usize,
+                }
+            }
+        };
+
+        Ok(RecordedEntry {
+            offset,
+            request: RecordedRequest::Request(request),
+        })
+    }
+}
+
+/// Write the recording header to `writer`.
+fn write_header<W: io::Write>(mut writer: W) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])
+}
+
+/// Read the recording header from `reader`, and check that the recording
+/// format is supported.
+fn read_header<R: io::Read>(mut reader: R) -> Result<(), SerializationError> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(SerializationError::Parse("not a state request recording"));
+    }
+
+    let mut version = [0; 1];
+    reader.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        return Err(SerializationError::Parse(
+            "unsupported state request recording version",
+        ));
+    }
+
+    Ok(())
+}
+
+/// The shared state of a [`Recorder`].
+struct RecorderInner {
+    writer: io::BufWriter<File>,
+    start: Instant,
+    unflushed: usize,
+    failed: bool,
+}
+
+/// Appends state requests to a recording file.
+///
+/// Clones of a recorder write to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Mutex<RecorderInner>>,
+}
+
+impl Recorder {
+    /// Create a new recording at `path`, replacing any existing file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        write_header(&mut writer)?;
+        writer.flush()?;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(RecorderInner {
+                writer,
+                start: Instant::now(),
+                unflushed: 0,
+                failed: false,
+            })),
+        })
+    }
+
+    /// Add `request` to the recording.
+    ///
+    /// If writing to the recording fails, logs a warning, and stops
+    /// recording. Recording failures never fail state requests.
+    pub fn record(&self, request: &Request) {
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("recorder mutex should be unpoisoned");
+        if inner.failed {
+            return;
+        }
+
+        let entry = RecordedEntry {
+            offset: inner.start.elapsed(),
+            request: RecordedRequest::new(request),
+        };
+        let inner = &mut *inner;
+        inner.unflushed += 1;
+        let result = entry.zcash_serialize(&mut inner.writer).and_then(|()| {
+            if inner.unflushed >= FLUSH_INTERVAL {
+                inner.unflushed = 0;
+                inner.writer.flush()?;
+            }
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            tracing::warn!(?error, "failed to record state request, stopping recording");
+            inner.failed = true;
+        }
+    }
+}
+
+/// A state service that records the requests it receives, then passes them
+/// to an inner service.
+#[derive(Clone)]
+pub struct Recording<S> {
+    inner: S,
+    recorder: Option<Recorder>,
+}
+
+impl<S> Recording<S> {
+    /// Wrap `inner`, recording its requests using `recorder`.
+    ///
+    /// If `recorder` is `None`, requests are passed through without being
+    /// recorded.
+    pub fn new(inner: S, recorder: Option<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<S> Service<Request> for Recording<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&req);
+        }
+        self.inner.call(req)
+    }
+}
+
+/// Reads the entries in a state request recording, in request order.
+pub struct RecordingReader<R> {
+    reader: R,
+}
+
+impl<R: io::Read> RecordingReader<R> {
+    /// Read the recording header from `reader`, and return a reader for its
+    /// entries.
+    pub fn new(mut reader: R) -> Result<Self, SerializationError> {
+        read_header(&mut reader)?;
+        Ok(Self { reader })
+    }
+}
+
+impl<R: io::Read> Iterator for RecordingReader<R> {
+    type Item = Result<RecordedEntry, SerializationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Check for the end of the recording, without consuming any entry
+        // bytes
+        let mut first = [0; 1];
+        match self.reader.read(&mut first) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(error) => return Some(Err(error.into())),
+        }
+
+        let reader = (&first[..]).chain(&mut self.reader);
+        Some(RecordedEntry::zcash_deserialize(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use tempdir::TempDir;
+    use zebra_chain::block::Block;
+
+    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+    #[test]
+    fn recordings_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+        let hash = block.hash();
+        let requests = vec![
+            Request::AddBlockBatch {
+                blocks: vec![block],
+            },
+            Request::GetBlock { hash },
+            Request::BlockTransactionInfo { hash },
+            Request::ChainTxStats { hash, window: None },
+            Request::RecordFailedBlock {
+                hash,
+                verifier_version: 1,
+                rule: "bad coinbase".to_owned(),
+            },
+            Request::FailedBlock {
+                hash,
+                verifier_version: 1,
+            },
+            Request::ChainTxStats {
+                hash,
+                window: Some(10),
+            },
+            Request::AncestorHash {
+                hash,
+                height: BlockHeight(0),
+            },
+            Request::FindBlockHeaders {
+                known_blocks: vec![hash, hash],
+                stop: Some(hash),
+            },
+            Request::UtxosByAddresses {
+                addresses: Vec::new(),
+                start: BlockHeight(1),
+                limit: 100,
+            },
+            Request::TransactionIdsByAddresses {
+                addresses: Vec::new(),
+                height_range: BlockHeight(1)..=BlockHeight(2),
+            },
+            Request::BlockCount,
+            Request::CompactNow,
+            Request::NoteCommitmentTrees {
+                height: BlockHeight(3),
+            },
+            Request::HistoryTree {
+                height: BlockHeight(4),
+            },
+            Request::ShieldedCounts { height: None },
+            Request::MissingIndexRanges {
+                index: OptionalIndex::SpentOutputs,
+            },
+            Request::ShieldedCounts {
+                height: Some(BlockHeight(5)),
+            },
+            Request::TransactionMerklePath {
+                txid: TransactionHash([7; 32]),
+            },
+            Request::SaplingSubtrees {
+                start_index: 5,
+                limit: 10,
+            },
+            Request::TransactionFee {
+                transaction: Transaction::zcash_deserialize(&zebra_test::vectors::DUMMY_TX1[..])?
+                    .into(),
+            },
+        ];
+
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("requests.rec");
+        let recorder = Recorder::create(&path)?;
+        for request in &requests {
+            recorder.record(request);
+        }
+        drop(recorder);
+
+        let entries = RecordingReader::new(BufReader::new(File::open(&path)?))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries.len(), requests.len());
+        assert_eq!(
+            entries[0].request,
+            RecordedRequest::Commit {
+                kind: RequestKind::AddBlockBatch,
+                hashes: vec![hash],
+            }
+        );
+        for (entry, request) in entries.iter().zip(&requests).skip(1) {
+            assert_eq!(entry.request, RecordedRequest::Request(request.clone()));
+        }
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].offset <= pair[1].offset));
+
+        assert!(RecordingReader::new(&b"not a recording"[..]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Note commitment tree and nullifier set sizes.
+//!
+//! The state tracks the number of note commitments and nullifiers in each
+//! shielded pool after every block, exports the sizes at the best chain tip
+//! as metrics, and returns them in `ShieldedCounts` responses.
+//!
+//! Like value pool balances, sizes are only tracked for chains that are
+//! connected to the genesis block.
+use std::convert::TryInto;
+use zebra_chain::{
+    block::Block,
+    proofs::ZkSnarkProof,
+    transaction::{JoinSplitData, Transaction},
+};
+
+/// The size of a serialized `ShieldedCounts`.
+pub(crate) const SHIELDED_COUNTS_SIZE: usize = 32;
+
+/// The number of leaves in each Sapling note commitment subtree.
+const SAPLING_SUBTREE_LEAVES: u64 = 1 << 16;
+
+/// The number of note commitments and nullifiers in each shielded pool.
+///
+/// Zebra doesn't support Orchard yet, so there are no Orchard counts.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShieldedCounts {
+    /// The number of leaves in the Sprout note commitment tree
+    pub sprout_commitments: u64,
+    /// The number of leaves in the Sapling note commitment tree
+    pub sapling_commitments: u64,
+    /// The number of revealed Sprout nullifiers
+    pub sprout_nullifiers: u64,
+    /// The number of revealed Sapling nullifiers
+    pub sapling_nullifiers: u64,
+}
+
+impl ShieldedCounts {
+    /// Returns the counts after applying `block` to `self`.
+    pub(crate) fn add_block(&self, block: &Block) -> Self {
+        let mut counts = *self;
+
+        for tx in &block.transactions {
+            let (commitments, nullifiers) = joinsplit_counts(tx);
+            counts.sprout_commitments += commitments;
+            counts.sprout_nullifiers += nullifiers;
+
+            if let Transaction::V4 {
+                shielded_data: Some(shielded_data),
+                ..
+            } = tx.as_ref()
+            {
+                counts.sapling_commitments += shielded_data.outputs().count() as u64;
+                counts.sapling_nullifiers += shielded_data.spends().count() as u64;
+            }
+        }
+
+        counts
+    }
+
+    /// The number of complete or partial Sapling note commitment subtrees.
+    pub fn sapling_subtrees(&self) -> u64 {
+        (self.sapling_commitments + SAPLING_SUBTREE_LEAVES - 1) / SAPLING_SUBTREE_LEAVES
+    }
+
+    /// Update the note commitment tree and nullifier set metrics, using the
+    /// counts at the best chain tip.
+    pub(crate) fn record_metrics(&self) {
+        metrics::gauge!(
+            "state.sprout.note_commitment_tree.size",
+            self.sprout_commitments as i64
+        );
+        metrics::gauge!(
+            "state.sapling.note_commitment_tree.size",
+            self.sapling_commitments as i64
+        );
+        metrics::gauge!(
+            "state.sapling.note_commitment_tree.subtrees",
+            self.sapling_subtrees() as i64
+        );
+        metrics::gauge!("state.sprout.nullifiers", self.sprout_nullifiers as i64);
+        metrics::gauge!("state.sapling.nullifiers", self.sapling_nullifiers as i64);
+
+        // Zebra doesn't support Orchard yet, so the Orchard pool is always
+        // empty.
+        metrics::gauge!("state.orchard.note_commitment_tree.size", 0);
+        metrics::gauge!("state.orchard.note_commitment_tree.subtrees", 0);
+        metrics::gauge!("state.orchard.nullifiers", 0);
+    }
+
+    /// Serialize these counts for storage.
+    pub(crate) fn to_bytes(&self) -> [u8; SHIELDED_COUNTS_SIZE] {
+        let mut bytes = [0; SHIELDED_COUNTS_SIZE];
+        let counts = [
+            self.sprout_commitments,
+            self.sapling_commitments,
+            self.sprout_nullifiers,
+            self.sapling_nullifiers,
+        ];
+        for (chunk, count) in bytes.chunks_mut(8).zip(counts.iter()) {
+            chunk.copy_from_slice(&count.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserialize counts from storage.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != SHIELDED_COUNTS_SIZE {
+            Err("stored shielded counts have an invalid length")?;
+        }
+
+        let count = |index: usize| -> Result<u64, Error> {
+            Ok(u64::from_le_bytes(
+                bytes[index * 8..(index + 1) * 8].try_into()?,
+            ))
+        };
+        Ok(ShieldedCounts {
+            sprout_commitments: count(0)?,
+            sapling_commitments: count(1)?,
+            sprout_nullifiers: count(2)?,
+            sapling_nullifiers: count(3)?,
+        })
+    }
+}
+
+/// Returns the number of Sprout note commitments and nullifiers in `tx`.
+fn joinsplit_counts(tx: &Transaction) -> (u64, u64) {
+    fn count<P: ZkSnarkProof>(joinsplit_data: &JoinSplitData<P>) -> (u64, u64) {
+        joinsplit_data
+            .joinsplits()
+            .fold((0, 0), |(commitments, nullifiers), joinsplit| {
+                (
+                    commitments + joinsplit.commitments.len() as u64,
+                    nullifiers + joinsplit.nullifiers.len() as u64,
+                )
+            })
+    }
+
+    match tx {
+        Transaction::V2 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        }
+        | Transaction::V3 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        } => count(joinsplit_data),
+        Transaction::V4 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        } => count(joinsplit_data),
+        _ => (0, 0),
+    }
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    #[test]
+    fn shielded_counts_round_trip() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..])?.into();
+        let counts = ShieldedCounts::default().add_block(&block);
+
+        assert_eq!(ShieldedCounts::from_bytes(&counts.to_bytes())?, counts);
+        assert_eq!(
+            counts.add_block(&block).sapling_commitments,
+            2 * counts.sapling_commitments
+        );
+
+        let full_subtree = ShieldedCounts {
+            sapling_commitments: SAPLING_SUBTREE_LEAVES,
+            ..ShieldedCounts::default()
+        };
+        assert_eq!(full_subtree.sapling_subtrees(), 1);
+        assert_eq!(ShieldedCounts::default().sapling_subtrees(), 0);
+
+        Ok(())
+    }
+}
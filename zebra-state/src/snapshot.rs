@@ -0,0 +1,131 @@
+//! Lock-free snapshots of the best chain tip.
+//!
+//! Tip and recent-chain queries read an immutable `ChainSnapshot`, which is
+//! atomically replaced after each commit. So readers never wait for writers,
+//! and writers never wait for readers.
+use arc_swap::ArcSwap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use zebra_chain::{block::BlockHeaderHash, types::BlockHeight};
+
+/// The maximum number of recent blocks kept in each snapshot.
+pub(crate) const SNAPSHOT_BLOCKS: usize = 100;
+
+/// An immutable view of the tip and the most recent blocks in the best chain.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChainSnapshot {
+    by_height: BTreeMap<BlockHeight, BlockHeaderHash>,
+    by_hash: HashMap<BlockHeaderHash, BlockHeight>,
+}
+
+impl ChainSnapshot {
+    /// Returns the height and hash of the tip, if there are any blocks.
+    pub(crate) fn tip(&self) -> Option<(BlockHeight, BlockHeaderHash)> {
+        self.by_height
+            .iter()
+            .next_back()
+            .map(|(&height, &hash)| (height, hash))
+    }
+
+    /// Returns the height of `hash`, if it is a recent block.
+    pub(crate) fn height(&self, hash: &BlockHeaderHash) -> Option<BlockHeight> {
+        self.by_hash.get(hash).cloned()
+    }
+
+    /// Returns the hash of the block at `height`, if it is a recent block.
+    pub(crate) fn hash(&self, height: BlockHeight) -> Option<BlockHeaderHash> {
+        self.by_height.get(&height).cloned()
+    }
+
+    /// Add a block to this snapshot, then drop the lowest blocks until the
+    /// snapshot is within its size limit.
+    pub(crate) fn insert(&mut self, height: BlockHeight, hash: BlockHeaderHash) {
+        if let Some(old_hash) = self.by_height.insert(height, hash) {
+            self.by_hash.remove(&old_hash);
+        }
+        self.by_hash.insert(hash, height);
+
+        while self.by_height.len() > SNAPSHOT_BLOCKS {
+            let lowest = *self
+                .by_height
+                .keys()
+                .next()
+                .expect("snapshot is larger than its size limit");
+            if let Some(lowest_hash) = self.by_height.remove(&lowest) {
+                self.by_hash.remove(&lowest_hash);
+            }
+        }
+    }
+}
+
+/// A shared `ChainSnapshot`, which is replaced atomically on each commit.
+#[derive(Clone)]
+pub(crate) struct SnapshotCell(Arc<ArcSwap<ChainSnapshot>>);
+
+impl SnapshotCell {
+    /// Create a new cell containing `snapshot`.
+    pub(crate) fn new(snapshot: ChainSnapshot) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(snapshot)))
+    }
+
+    /// Returns the current snapshot, without blocking.
+    pub(crate) fn load(&self) -> Arc<ChainSnapshot> {
+        self.0.load_full()
+    }
+
+    /// Atomically replace the current snapshot with `snapshot`.
+    pub(crate) fn replace(&self, snapshot: ChainSnapshot) {
+        self.0.store(Arc::new(snapshot));
+    }
+
+    /// Atomically replace the current snapshot with a snapshot that also
+    /// contains `blocks`.
+    pub(crate) fn commit(&self, blocks: &[(BlockHeight, BlockHeaderHash)]) {
+        self.0.rcu(|current| {
+            let mut next = ChainSnapshot::clone(current);
+            for &(height, hash) in blocks {
+                next.insert(height, hash);
+            }
+            next
+        });
+    }
+}
+
+impl Default for SnapshotCell {
+    fn default() -> Self {
+        Self::new(ChainSnapshot::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_keeps_recent_blocks() {
+        zebra_test::init();
+
+        let cell = SnapshotCell::default();
+        let old = cell.load();
+
+        let blocks: Vec<_> = (0..(SNAPSHOT_BLOCKS as u32 + 10))
+            .map(|i| (BlockHeight(i), BlockHeaderHash([i as u8; 32])))
+            .collect();
+        cell.commit(&blocks);
+
+        // Existing snapshots are never modified
+        assert_eq!(old.tip(), None);
+
+        let snapshot = cell.load();
+        let (tip_height, tip_hash) = *blocks.last().unwrap();
+        assert_eq!(snapshot.tip(), Some((tip_height, tip_hash)));
+        assert_eq!(snapshot.height(&tip_hash), Some(tip_height));
+
+        // The lowest blocks are pruned
+        assert_eq!(snapshot.hash(BlockHeight(0)), None);
+        assert_eq!(snapshot.hash(BlockHeight(9)), None);
+        assert_eq!(snapshot.hash(BlockHeight(10)), Some(blocks[10].1));
+    }
+}
@@ -0,0 +1,600 @@
+//! Key-value storage backends for the on-disk state.
+//!
+//! The on-disk state stores its data in named trees of byte keys and values,
+//! using a [`StorageBackend`]. The trees are declared in the [`tree`] module.
+//! Each backend stores the trees in a different
+//! storage engine, and the backend is selected by
+//! [`Config::storage_backend`]. The selected backend is wrapped in a
+//! [`Checksummed`] backend, which detects corrupt values, and an
+//! [`Upgrading`] backend, which upgrades values from older state formats.
+//! If [`Config::read_only`] is set, the engine is also wrapped in a
+//! [`ReadOnly`] backend, which rejects writes. Otherwise, the wrapped engine
+//! is wrapped in a [`Buffered`] backend, which combines write batches.
+use serde::{Deserialize, Serialize};
+use std::{
+    error,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::Arc,
+};
+use zebra_chain::Network;
+
+use crate::Config;
+
+mod buffered;
+mod checksum;
+mod memory_backend;
+mod read_only;
+mod rocksdb_backend;
+mod sled_backend;
+pub(crate) mod tree;
+mod upgrade;
+
+pub use checksum::CorruptValue;
+pub use read_only::ReadOnlyState;
+pub use tree::{TreeSchema, STATE_FORMAT_VERSION, TREES};
+
+pub(crate) use buffered::Buffered;
+pub(crate) use checksum::Checksummed;
+pub(crate) use memory_backend::MemoryBackend;
+pub(crate) use read_only::ReadOnly;
+pub(crate) use rocksdb_backend::RocksDbBackend;
+pub(crate) use sled_backend::SledBackend;
+pub(crate) use upgrade::{is_format_metadata, spawn_upgrader, UnsupportedFormat, Upgrading};
+
+/// The storage engines that can store the on-disk state.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    /// Store the state in a sled database in `cache_dir`.
+    Sled,
+    /// Store the state in a RocksDB database in `cache_dir`, with a column
+    /// family for each tree.
+    RocksDb,
+    /// Keep the state in memory, and discard it when Zebra exits.
+    ///
+    /// Intended for tests and short-lived embedded nodes.
+    Memory,
+}
+
+/// How often the storage engine syncs committed blocks to disk.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Sync each write before its block commit finishes.
+    ///
+    /// Committed blocks survive a crash or power failure, but each commit
+    /// waits for the disk.
+    Full,
+    /// Sync writes in the background.
+    ///
+    /// sled syncs its log every 500 milliseconds. RocksDB leaves its log to
+    /// the operating system, which writes it back within a few seconds. A
+    /// crash or power failure can lose the most recent blocks, which are
+    /// downloaded again.
+    Batched,
+    /// Only sync writes when the state is flushed or closed.
+    ///
+    /// sled writes its log when its buffers are full. RocksDB skips its log,
+    /// and keeps writes in its write buffers until they are full. A crash or
+    /// power failure can lose many recent blocks, so this is only
+    /// recommended for the initial sync on slow disks.
+    Async,
+}
+
+impl Durability {
+    /// Returns the interval between sled's background log flushes.
+    pub(crate) fn sled_flush_every_ms(self) -> Option<u64> {
+        match self {
+            Durability::Batched => Some(500),
+            // Full durability flushes after each write
+            Durability::Full | Durability::Async => None,
+        }
+    }
+
+    /// Returns true if each write must be synced before it finishes.
+    pub(crate) fn sync_writes(self) -> bool {
+        self == Durability::Full
+    }
+
+    /// Returns true if RocksDB writes its log before each write finishes.
+    pub(crate) fn rocksdb_write_ahead_log(self) -> bool {
+        self != Durability::Async
+    }
+}
+
+/// A range of keys in a tree.
+pub(crate) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Returns `range` as a [`KeyRange`].
+pub(crate) fn key_range<K: AsRef<[u8]>>(range: impl RangeBounds<K>) -> KeyRange {
+    let to_vec = |bound: Bound<&K>| match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+
+    (to_vec(range.start_bound()), to_vec(range.end_bound()))
+}
+
+/// Returns a [`KeyRange`] that contains every key.
+pub(crate) fn all_keys() -> KeyRange {
+    (Bound::Unbounded, Bound::Unbounded)
+}
+
+/// The entries returned by [`StorageBackend::iterate`], as `(key, value)`
+/// pairs.
+pub(crate) type Entries<'a> =
+    Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>), Error>> + 'a>;
+
+/// A change to a single tree, which is part of a [`WriteBatch`].
+#[derive(Clone, Debug)]
+pub(crate) enum WriteOp {
+    Insert {
+        tree: &'static str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        tree: &'static str,
+        key: Vec<u8>,
+    },
+    Replace {
+        tree: &'static str,
+        key: Vec<u8>,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    DeleteRange {
+        tree: &'static str,
+        range: KeyRange,
+    },
+}
+
+impl WriteOp {
+    /// Returns the name of the tree that this operation changes.
+    pub(crate) fn tree(&self) -> &'static str {
+        match self {
+            WriteOp::Insert { tree, .. }
+            | WriteOp::Remove { tree, .. }
+            | WriteOp::Replace { tree, .. }
+            | WriteOp::DeleteRange { tree, .. } => tree,
+        }
+    }
+}
+
+/// A list of changes that are written to a [`StorageBackend`] atomically.
+///
+/// The changes are applied in order, so later changes to a key replace
+/// earlier changes.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WriteBatch {
+    pub(crate) ops: Vec<WriteOp>,
+}
+
+impl WriteBatch {
+    /// Set the value of `key` in `tree` to `value`.
+    pub(crate) fn insert(
+        &mut self,
+        tree: &'static str,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) {
+        self.ops.push(WriteOp::Insert {
+            tree,
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+    }
+
+    /// Remove `key` from `tree`, if it is present.
+    pub(crate) fn remove(&mut self, tree: &'static str, key: impl AsRef<[u8]>) {
+        self.ops.push(WriteOp::Remove {
+            tree,
+            key: key.as_ref().to_vec(),
+        });
+    }
+
+    /// Set the value of `key` in `tree` to `new`, if its value is `old` when
+    /// the batch is written.
+    ///
+    /// Otherwise, leaves the value unchanged.
+    pub(crate) fn replace(
+        &mut self,
+        tree: &'static str,
+        key: impl AsRef<[u8]>,
+        old: impl AsRef<[u8]>,
+        new: impl AsRef<[u8]>,
+    ) {
+        self.ops.push(WriteOp::Replace {
+            tree,
+            key: key.as_ref().to_vec(),
+            old: old.as_ref().to_vec(),
+            new: new.as_ref().to_vec(),
+        });
+    }
+
+    /// Remove every key in `range` from `tree`.
+    ///
+    /// Each end of `range` can be inclusive, exclusive, or unbounded, so
+    /// callers can pass the first and last removed keys, rather than
+    /// calculating the key after the last removed key.
+    pub(crate) fn delete_range<K: AsRef<[u8]>>(
+        &mut self,
+        tree: &'static str,
+        range: impl RangeBounds<K>,
+    ) {
+        self.ops.push(WriteOp::DeleteRange {
+            tree,
+            range: key_range(range),
+        });
+    }
+
+    /// Returns true if the batch has no changes.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// A storage engine for the on-disk state.
+///
+/// Data is stored in named trees, which map byte keys to byte values. Keys
+/// are ordered by their bytes.
+///
+/// The state service only uses the methods in this trait, so new storage
+/// engines can be added without changing the service.
+pub(crate) trait StorageBackend: Send + Sync + 'static {
+    /// Open the state storage for `network`.
+    fn open(config: &Config, network: Network) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    /// Open the archive storage for `network`, if the backend and `config`
+    /// support an archive tier.
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error>
+    where
+        Self: Sized;
+
+    /// Returns the value of `key` in `tree`, if it is present.
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Returns the entries in `tree` with keys in `range`, ordered by key.
+    ///
+    /// The entries can also be iterated in reverse order.
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error>;
+
+    /// Apply every change in `batch`, in a single atomic write.
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), Error>;
+
+    /// Set the value of `key` in `tree` to `value`.
+    fn insert(&self, tree: &'static str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        batch.insert(tree, key, value);
+        self.write_batch(batch)
+    }
+
+    /// Remove `key` from `tree`, if it is present.
+    fn remove(&self, tree: &'static str, key: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        batch.remove(tree, key);
+        self.write_batch(batch)
+    }
+
+    /// Write any buffered changes to durable storage.
+    fn flush(&self) -> Result<(), Error>;
+
+    /// Compact the whole database, so later reads and writes don't wait for
+    /// compactions.
+    ///
+    /// Backends that don't need compaction do nothing.
+    fn compact(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Enable or disable automatic background compaction.
+    ///
+    /// Backends that can't pause compaction do nothing.
+    fn set_auto_compaction(&self, _enabled: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Upgrade a batch of stored values to the current state format, if a
+    /// format upgrade is in progress.
+    ///
+    /// Returns 0 when there are no upgrades left.
+    fn upgrade_batch(&self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    /// Write a consistent copy of the storage to a new database at `path`.
+    ///
+    /// The state keeps running while the copy is made. Writes can wait for a
+    /// flush when the backup starts, but not for the copy.
+    fn backup(&self, path: &Path) -> Result<(), Error>;
+
+    /// Read the changes that the primary database has written since this
+    /// database was opened, or since it last caught up.
+    ///
+    /// Only secondary databases need to catch up. Other backends do nothing.
+    fn catch_up(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A storage engine, wrapped in the backends that check and upgrade its
+/// values.
+type Wrapped<B> = Upgrading<Checksummed<B>>;
+
+/// Open the state storage for `network`, using the backend in `config`.
+pub(crate) fn open(config: &Config, network: Network) -> Result<Arc<dyn StorageBackend>, Error> {
+    check_secondary(config)?;
+    if config.read_only {
+        return Ok(match config.storage_backend {
+            StorageBackendKind::Sled => {
+                Arc::new(Wrapped::<ReadOnly<SledBackend>>::open(config, network)?)
+            }
+            StorageBackendKind::RocksDb => {
+                Arc::new(Wrapped::<ReadOnly<RocksDbBackend>>::open(config, network)?)
+            }
+            StorageBackendKind::Memory => Err("a memory state can't be opened read-only")?,
+        });
+    }
+
+    Ok(match config.storage_backend {
+        StorageBackendKind::Sled => {
+            Arc::new(Buffered::<Wrapped<SledBackend>>::open(config, network)?)
+        }
+        StorageBackendKind::RocksDb => {
+            Arc::new(Buffered::<Wrapped<RocksDbBackend>>::open(config, network)?)
+        }
+        StorageBackendKind::Memory => {
+            Arc::new(Buffered::<Wrapped<MemoryBackend>>::open(config, network)?)
+        }
+    })
+}
+
+/// Open the archive storage for `network`, using the backend in `config`.
+pub(crate) fn open_archive(
+    config: &Config,
+    network: Network,
+) -> Result<Option<Arc<dyn StorageBackend>>, Error> {
+    check_secondary(config)?;
+    if config.read_only {
+        return Ok(match config.storage_backend {
+            StorageBackendKind::Sled => {
+                Wrapped::<ReadOnly<SledBackend>>::open_archive(config, network)?
+                    .map(|backend| Arc::new(backend) as Arc<dyn StorageBackend>)
+            }
+            StorageBackendKind::RocksDb => {
+                Wrapped::<ReadOnly<RocksDbBackend>>::open_archive(config, network)?
+                    .map(|backend| Arc::new(backend) as Arc<dyn StorageBackend>)
+            }
+            StorageBackendKind::Memory => None,
+        });
+    }
+
+    Ok(match config.storage_backend {
+        StorageBackendKind::Sled => Wrapped::<SledBackend>::open_archive(config, network)?
+            .map(|backend| Arc::new(backend) as Arc<dyn StorageBackend>),
+        StorageBackendKind::RocksDb => Wrapped::<RocksDbBackend>::open_archive(config, network)?
+            .map(|backend| Arc::new(backend) as Arc<dyn StorageBackend>),
+        StorageBackendKind::Memory => Wrapped::<MemoryBackend>::open_archive(config, network)?
+            .map(|backend| Arc::new(backend) as Arc<dyn StorageBackend>),
+    })
+}
+
+/// Check that `config` can open a secondary database, if it sets
+/// `secondary_dir`.
+fn check_secondary(config: &Config) -> Result<(), Error> {
+    if config.secondary_dir.is_none() {
+        return Ok(());
+    }
+    if !config.read_only {
+        Err("secondary states must be opened read-only")?;
+    }
+    if config.storage_backend != StorageBackendKind::RocksDb {
+        Err("only RocksDB states can be opened as a secondary")?;
+    }
+
+    Ok(())
+}
+
+type Error = Box<dyn error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that `backend` reads, iterates, and writes trees.
+    fn check_backend(backend: &dyn StorageBackend) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        for key in 0u8..5 {
+            batch.insert(tree::BY_HEIGHT, [key], [key * 10]);
+        }
+        batch.insert(tree::BLOCK_INFO, [0], [1]);
+        backend.write_batch(batch)?;
+
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[2])?, Some(vec![20]));
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[5])?, None);
+        assert_eq!(backend.read(tree::INVALID, &[0])?, None);
+
+        let keys = |range| -> Result<Vec<Vec<u8>>, Error> {
+            backend
+                .iterate(tree::BY_HEIGHT, range)?
+                .map(|entry| entry.map(|(key, _)| key))
+                .collect()
+        };
+        assert_eq!(keys(key_range([1u8]..[3u8]))?, vec![vec![1u8], vec![2u8]]);
+        assert_eq!(keys(key_range([3u8]..))?, vec![vec![3u8], vec![4u8]]);
+
+        let last = backend.iterate(tree::BY_HEIGHT, all_keys())?.next_back();
+        assert_eq!(last.transpose()?, Some((vec![4], vec![40])));
+
+        // Iterating from both ends stops where the ends meet
+        let mut entries = backend.iterate(tree::BY_HEIGHT, key_range([1u8]..=[2u8]))?;
+        assert_eq!(entries.next().transpose()?, Some((vec![1], vec![10])));
+        assert_eq!(entries.next_back().transpose()?, Some((vec![2], vec![20])));
+        assert!(entries.next().is_none());
+        assert!(entries.next_back().is_none());
+        drop(entries);
+
+        let mut batch = WriteBatch::default();
+        batch.remove(tree::BY_HEIGHT, [0u8]);
+        batch.replace(tree::BY_HEIGHT, [1u8], [10u8], [11u8]);
+        batch.replace(tree::BY_HEIGHT, [2u8], [99u8], [21u8]);
+        batch.delete_range(tree::BY_HEIGHT, [3u8]..);
+        backend.write_batch(batch)?;
+
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[0])?, None);
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, Some(vec![11]));
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[2])?, Some(vec![20]));
+        assert_eq!(keys(all_keys())?, vec![vec![1], vec![2]]);
+        assert_eq!(backend.read(tree::BLOCK_INFO, &[0])?, Some(vec![1]));
+
+        backend.insert(tree::BLOCK_INFO, &[1], &[2])?;
+        backend.remove(tree::BLOCK_INFO, &[0])?;
+        assert_eq!(backend.read(tree::BLOCK_INFO, &[0])?, None);
+        assert_eq!(backend.read(tree::BLOCK_INFO, &[1])?, Some(vec![2]));
+
+        // Inclusive ranges remove their last key
+        let mut batch = WriteBatch::default();
+        for key in 5u8..8 {
+            batch.insert(tree::BY_HEIGHT, [key], [key * 10]);
+        }
+        batch.delete_range(tree::BY_HEIGHT, [2u8]..=[6u8]);
+        backend.write_batch(batch)?;
+        assert_eq!(keys(all_keys())?, vec![vec![1], vec![7]]);
+
+        backend.flush()
+    }
+
+    #[test]
+    fn sled_backend_stores_trees() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = tempdir::TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+
+        check_backend(&SledBackend::open(&config, Network::Mainnet)?)
+    }
+
+    #[test]
+    fn sled_backend_backs_up_trees() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = tempdir::TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let backend = SledBackend::open(&config, Network::Mainnet)?;
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+
+        let backup_dir = tempdir::TempDir::new("")?;
+        let backup_config = Config {
+            cache_dir: Some(backup_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let path = backup_config
+            .storage_path(Network::Mainnet)
+            .expect("sled stores the state in cache_dir");
+        backend.backup(&path)?;
+
+        // Writes after the backup aren't in the backup
+        backend.insert(tree::BY_HEIGHT, &[2], &[20])?;
+        drop(backend);
+
+        let backup = SledBackend::open(&backup_config, Network::Mainnet)?;
+        assert_eq!(backup.read(tree::BY_HEIGHT, &[1])?, Some(vec![10]));
+        assert_eq!(backup.read(tree::BY_HEIGHT, &[2])?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rocksdb_backend_stores_trees() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = tempdir::TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+
+        check_backend(&RocksDbBackend::open(&config, Network::Mainnet)?)
+    }
+
+    #[test]
+    fn durability_options() {
+        zebra_test::init();
+
+        // (sled_flush_every_ms, sync_writes, rocksdb_write_ahead_log)
+        let expected = [
+            (Durability::Full, (None, true, true)),
+            (Durability::Batched, (Some(500), false, true)),
+            (Durability::Async, (None, false, false)),
+        ];
+        for &(durability, options) in expected.iter() {
+            let resolved = (
+                durability.sled_flush_every_ms(),
+                durability.sync_writes(),
+                durability.rocksdb_write_ahead_log(),
+            );
+            assert_eq!(resolved, options, "{:?}", durability);
+        }
+    }
+
+    #[test]
+    fn rocksdb_backend_backs_up_trees() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = tempdir::TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            storage_backend: StorageBackendKind::RocksDb,
+            ..Config::default()
+        };
+        let backend = RocksDbBackend::open(&config, Network::Mainnet)?;
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+
+        let backup_dir = tempdir::TempDir::new("")?;
+        let backup_config = Config {
+            cache_dir: Some(backup_dir.path().to_owned()),
+            ..config
+        };
+        let path = backup_config
+            .storage_path(Network::Mainnet)
+            .expect("RocksDB stores the state in cache_dir");
+        backend.backup(&path)?;
+
+        // Writes after the backup aren't in the backup
+        backend.insert(tree::BY_HEIGHT, &[2], &[20])?;
+        drop(backend);
+
+        let backup = RocksDbBackend::open(&backup_config, Network::Mainnet)?;
+        assert_eq!(backup.read(tree::BY_HEIGHT, &[1])?, Some(vec![10]));
+        assert_eq!(backup.read(tree::BY_HEIGHT, &[2])?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_backend_stores_trees() -> Result<(), Error> {
+        zebra_test::init();
+
+        check_backend(&MemoryBackend::open(&Config::default(), Network::Mainnet)?)
+    }
+
+    #[test]
+    fn checksummed_backend_stores_trees() -> Result<(), Error> {
+        zebra_test::init();
+
+        check_backend(&Checksummed::<MemoryBackend>::open(
+            &Config::default(),
+            Network::Mainnet,
+        )?)
+    }
+}
@@ -0,0 +1,438 @@
+//! Buffered writes, which combine several block commits into one write.
+//!
+//! Each write batch has a fixed cost in the storage engine, so committing
+//! each block in its own batch limits the initial sync. [`Buffered`] appends
+//! each batch to a pending batch, which is written when it is larger than
+//! [`Config::write_batch_bytes`], or older than
+//! [`Config::write_batch_interval`]. Idle states write their pending batch
+//! from a background thread.
+//!
+//! Reads of inserted or removed keys are answered from the pending batch.
+//! Other reads of a tree with pending changes write the pending batch first,
+//! so they always see every change.
+//!
+//! The pending batch is written atomically, so a crash can lose the most
+//! recent blocks, but it can't leave a block partially written. If a write
+//! fails, the batch that started the write is discarded, so its block commit
+//! fails without changing the state. The earlier batches stay pending.
+use super::{Entries, Error, KeyRange, StorageBackend, WriteBatch, WriteOp};
+use crate::Config;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use zebra_chain::Network;
+
+/// Changes that haven't been written to the storage engine yet.
+#[derive(Default)]
+struct Pending {
+    batch: WriteBatch,
+    /// The index of the latest insert or remove of each key in `batch`, by
+    /// tree
+    latest: HashMap<&'static str, HashMap<Vec<u8>, usize>>,
+    /// The trees with changes that can't be read from `latest`
+    unindexed: HashSet<&'static str>,
+    /// The size of the keys and values in `batch`
+    bytes: usize,
+    /// When the first change in `batch` was made
+    since: Option<Instant>,
+}
+
+impl Pending {
+    /// Append the changes in `batch`.
+    fn append(&mut self, batch: WriteBatch) {
+        if self.since.is_none() && !batch.is_empty() {
+            self.since = Some(Instant::now());
+        }
+
+        for op in batch.ops {
+            let index = self.batch.ops.len();
+            self.bytes += op_bytes(&op);
+            match &op {
+                WriteOp::Insert { tree, key, .. } | WriteOp::Remove { tree, key } => {
+                    self.latest
+                        .entry(*tree)
+                        .or_default()
+                        .insert(key.clone(), index);
+                }
+                // The new value depends on the stored value
+                WriteOp::Replace { tree, .. } | WriteOp::DeleteRange { tree, .. } => {
+                    self.unindexed.insert(*tree);
+                }
+            }
+            self.batch.ops.push(op);
+        }
+    }
+
+    /// Returns the pending value of `key` in `tree`, if it has been inserted
+    /// or removed.
+    ///
+    /// Returns `Some(None)` for removed keys.
+    fn get(&self, tree: &str, key: &[u8]) -> Option<Option<&[u8]>> {
+        let index = *self.latest.get(tree)?.get(key)?;
+
+        match &self.batch.ops[index] {
+            WriteOp::Insert { value, .. } => Some(Some(value)),
+            _ => Some(None),
+        }
+    }
+
+    /// Returns true if `tree` has any pending changes.
+    fn changes(&self, tree: &str) -> bool {
+        self.latest.contains_key(tree) || self.unindexed.contains(tree)
+    }
+
+    /// Returns true if the pending changes are older than `interval`.
+    fn is_expired(&self, interval: Duration) -> bool {
+        match self.since {
+            Some(since) => since.elapsed() >= interval,
+            None => false,
+        }
+    }
+}
+
+/// Returns the size of the keys and values in `op`.
+fn op_bytes(op: &WriteOp) -> usize {
+    match op {
+        WriteOp::Insert { key, value, .. } => key.len() + value.len(),
+        WriteOp::Remove { key, .. } => key.len(),
+        WriteOp::Replace { key, new, .. } => key.len() + new.len(),
+        WriteOp::DeleteRange { .. } => 0,
+    }
+}
+
+/// The storage engine and pending changes, shared with the background
+/// thread.
+struct Shared<B: StorageBackend> {
+    inner: B,
+    pending: Mutex<Pending>,
+}
+
+impl<B: StorageBackend> Shared<B> {
+    fn pending(&self) -> MutexGuard<'_, Pending> {
+        self.pending
+            .lock()
+            .expect("pending write mutex should be unpoisoned")
+    }
+
+    /// Write the `pending` changes to the storage engine.
+    ///
+    /// If the write fails, the changes stay pending, so they can be written
+    /// later.
+    fn write_pending(&self, pending: &mut Pending) -> Result<(), Error> {
+        self.write_pending_with(pending, WriteBatch::default())
+    }
+
+    /// Write the `pending` changes and `batch` to the storage engine, in one
+    /// write.
+    ///
+    /// If the write fails, `batch` is discarded, and the `pending` changes
+    /// stay pending.
+    fn write_pending_with(&self, pending: &mut Pending, batch: WriteBatch) -> Result<(), Error> {
+        if pending.batch.is_empty() && batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut write = pending.batch.clone();
+        write.ops.extend(batch.ops);
+        let count = write.ops.len();
+        self.inner.write_batch(write)?;
+        *pending = Pending::default();
+
+        metrics::counter!("state.buffered.writes", 1);
+        tracing::trace!(count, "wrote buffered changes");
+        Ok(())
+    }
+}
+
+impl<B: StorageBackend> Drop for Shared<B> {
+    fn drop(&mut self) {
+        let pending = self
+            .pending
+            .get_mut()
+            .unwrap_or_else(|error| error.into_inner());
+        let mut pending = std::mem::take(pending);
+        if let Err(error) = self.write_pending(&mut pending) {
+            tracing::warn!(?error, "could not write buffered changes");
+        }
+    }
+}
+
+/// A [`StorageBackend`] that combines the batches written to `B`.
+pub(crate) struct Buffered<B: StorageBackend> {
+    shared: Arc<Shared<B>>,
+    /// The pending size that starts a write, or 0 to write each batch
+    /// immediately
+    max_bytes: usize,
+    /// The pending age that starts a write
+    interval: Duration,
+}
+
+impl<B: StorageBackend> Buffered<B> {
+    /// Wrap `inner`, combining batches until they are `max_bytes` in size,
+    /// or `interval` old.
+    ///
+    /// If `max_bytes` is 0, batches are written immediately.
+    fn new(inner: B, max_bytes: usize, interval: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            inner,
+            pending: Default::default(),
+        });
+        if max_bytes > 0 {
+            spawn_writer(Arc::downgrade(&shared), interval);
+        }
+
+        Self {
+            shared,
+            max_bytes,
+            interval,
+        }
+    }
+
+    /// Write the pending changes, if `tree` has any.
+    fn write_changes(&self, tree: &str) -> Result<(), Error> {
+        let mut pending = self.shared.pending();
+        if pending.changes(tree) {
+            self.shared.write_pending(&mut pending)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write every pending change.
+    fn write_all(&self) -> Result<(), Error> {
+        self.shared.write_pending(&mut self.shared.pending())
+    }
+}
+
+/// Returns the pending size that starts a write for `config`.
+///
+/// Full durability syncs each block commit before it finishes, so it writes
+/// each batch immediately, even if `write_batch_bytes` is set.
+fn max_bytes(config: &Config) -> usize {
+    if config.durability.sync_writes() {
+        0
+    } else {
+        config.write_batch_bytes as usize
+    }
+}
+
+/// Write the pending changes in `shared` when they are older than
+/// `interval`, until the backend is dropped.
+fn spawn_writer<B: StorageBackend>(shared: Weak<Shared<B>>, interval: Duration) {
+    thread::Builder::new()
+        .name("zebra-state-writer".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut pending = shared.pending();
+            if pending.is_expired(interval) {
+                if let Err(error) = shared.write_pending(&mut pending) {
+                    tracing::warn!(?error, "failed to write buffered changes, retrying");
+                }
+            }
+        })
+        .expect("spawning the writer thread should succeed");
+}
+
+impl<B: StorageBackend> StorageBackend for Buffered<B> {
+    fn open(config: &Config, network: Network) -> Result<Self, Error> {
+        Ok(Self::new(
+            B::open(config, network)?,
+            max_bytes(config),
+            config.write_batch_interval,
+        ))
+    }
+
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        Ok(B::open_archive(config, network)?
+            .map(|inner| Self::new(inner, max_bytes(config), config.write_batch_interval)))
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        {
+            let mut pending = self.shared.pending();
+            if pending.unindexed.contains(tree) {
+                self.shared.write_pending(&mut pending)?;
+            } else if let Some(value) = pending.get(tree, key) {
+                return Ok(value.map(<[u8]>::to_vec));
+            }
+        }
+
+        self.shared.inner.read(tree, key)
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        self.write_changes(tree)?;
+        self.shared.inner.iterate(tree, range)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        if self.max_bytes == 0 {
+            return self.shared.inner.write_batch(batch);
+        }
+
+        let mut pending = self.shared.pending();
+        let bytes = pending.bytes + batch.ops.iter().map(op_bytes).sum::<usize>();
+        if bytes >= self.max_bytes || pending.is_expired(self.interval) {
+            // Don't leave a failed batch pending, because the caller doesn't
+            // expect it to be written
+            self.shared.write_pending_with(&mut pending, batch)
+        } else {
+            pending.append(batch);
+            Ok(())
+        }
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.write_all()?;
+        self.shared.inner.flush()
+    }
+
+    fn compact(&self) -> Result<(), Error> {
+        self.write_all()?;
+        self.shared.inner.compact()
+    }
+
+    fn set_auto_compaction(&self, enabled: bool) -> Result<(), Error> {
+        self.shared.inner.set_auto_compaction(enabled)
+    }
+
+    fn upgrade_batch(&self) -> Result<usize, Error> {
+        self.shared.inner.upgrade_batch()
+    }
+
+    fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.write_all()?;
+        self.shared.inner.backup(path)
+    }
+
+    fn catch_up(&self) -> Result<(), Error> {
+        self.shared.inner.catch_up()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{all_keys, tree, Durability, MemoryBackend};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A memory backend whose writes fail while `fail` is set.
+    #[derive(Clone, Default)]
+    struct FailingBackend {
+        inner: MemoryBackend,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl StorageBackend for FailingBackend {
+        fn open(_config: &Config, _network: Network) -> Result<Self, Error> {
+            Ok(Self::default())
+        }
+
+        fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+            self.inner.read(tree, key)
+        }
+
+        fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+            self.inner.iterate(tree, range)
+        }
+
+        fn write_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err("write failed".into());
+            }
+            self.inner.write_batch(batch)
+        }
+
+        fn flush(&self) -> Result<(), Error> {
+            self.inner.flush()
+        }
+
+        fn backup(&self, path: &Path) -> Result<(), Error> {
+            self.inner.backup(path)
+        }
+    }
+
+    #[test]
+    fn reads_see_buffered_writes() -> Result<(), Error> {
+        zebra_test::init();
+
+        // Memory backend clones share their trees, so `raw` only sees the
+        // changes that have been written
+        let raw = MemoryBackend::default();
+        let backend = Buffered::new(raw.clone(), 1024, Duration::from_secs(3600));
+
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+        backend.insert(tree::BY_HEIGHT, &[2], &[20])?;
+        backend.remove(tree::BY_HEIGHT, &[1])?;
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, None);
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[2])?, Some(vec![20]));
+        assert_eq!(raw.read(tree::BY_HEIGHT, &[2])?, None);
+
+        // Iterating needs every change in the tree
+        assert_eq!(backend.iterate(tree::BY_HEIGHT, all_keys())?.count(), 1);
+        assert_eq!(raw.read(tree::BY_HEIGHT, &[2])?, Some(vec![20]));
+
+        // Large batches are written immediately
+        backend.insert(tree::BODIES, &[3], &[0; 1024])?;
+        assert_eq!(raw.read(tree::BODIES, &[3])?, Some(vec![0; 1024]));
+
+        // Dropping the backend writes the pending changes
+        backend.insert(tree::BY_HEIGHT, &[4], &[40])?;
+        assert_eq!(raw.read(tree::BY_HEIGHT, &[4])?, None);
+        drop(backend);
+        assert_eq!(raw.read(tree::BY_HEIGHT, &[4])?, Some(vec![40]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batches_are_not_written_later() -> Result<(), Error> {
+        zebra_test::init();
+
+        let raw = FailingBackend::default();
+        let backend = Buffered::new(raw.clone(), 1024, Duration::from_secs(3600));
+
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+
+        // The failed batch starts a write, which fails
+        raw.fail.store(true, Ordering::SeqCst);
+        assert!(backend.insert(tree::BODIES, &[2], &[0; 1024]).is_err());
+        assert_eq!(backend.read(tree::BODIES, &[2])?, None);
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, Some(vec![10]));
+
+        // Only the earlier batch is written
+        raw.fail.store(false, Ordering::SeqCst);
+        backend.flush()?;
+        assert_eq!(raw.read(tree::BODIES, &[2])?, None);
+        assert_eq!(raw.read(tree::BY_HEIGHT, &[1])?, Some(vec![10]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_durability_writes_immediately() {
+        zebra_test::init();
+
+        let config = Config {
+            durability: Durability::Full,
+            write_batch_bytes: 1024,
+            ..Config::default()
+        };
+        assert_eq!(max_bytes(&config), 0);
+
+        let config = Config {
+            durability: Durability::Batched,
+            ..config
+        };
+        assert_eq!(max_bytes(&config), 1024);
+    }
+}
@@ -0,0 +1,286 @@
+//! Checksums for stored values.
+//!
+//! Each value is stored with a CRC-32 checksum of its key and value, which
+//! is checked whenever the value is read. Disks can silently flip bits in old
+//! data, so a failed check returns a [`CorruptValue`] error, rather than
+//! deserializing the corrupt bytes.
+//!
+//! States created before checksums were added don't have them. Their values
+//! are read and written without checksums, until the state is synced from
+//! scratch.
+use super::{all_keys, tree, Entries, Error, KeyRange, StorageBackend, WriteBatch, WriteOp};
+use crate::Config;
+use std::{convert::TryInto, error, fmt, path::Path};
+use zebra_chain::Network;
+
+/// The length of the checksum at the end of each stored value.
+const CHECKSUM_LEN: usize = 4;
+
+/// The key that marks a state with value checksums, in the `metadata` tree.
+const CHECKSUMS_KEY: &[u8] = b"value_checksums";
+
+/// A stored value that doesn't match its checksum.
+///
+/// The value has been corrupted on disk. Block and index values can be
+/// recovered by rolling back the state, or by rewriting the index entry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CorruptValue {
+    /// The tree that contains the value
+    pub tree: String,
+    /// The key of the value
+    pub key: Vec<u8>,
+}
+
+impl fmt::Display for CorruptValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the value of {} in the {} tree doesn't match its checksum",
+            hex::encode(&self.key),
+            self.tree
+        )
+    }
+}
+
+impl error::Error for CorruptValue {}
+
+impl CorruptValue {
+    /// Returns the corrupt value in `error`, if it is a [`CorruptValue`]
+    /// error.
+    pub fn from_error(error: &(dyn error::Error + 'static)) -> Option<&CorruptValue> {
+        error.downcast_ref()
+    }
+}
+
+/// A [`StorageBackend`] that adds a checksum to each value in `B`.
+pub(crate) struct Checksummed<B> {
+    inner: B,
+    /// False if the state was created before checksums were added.
+    enabled: bool,
+}
+
+impl<B: StorageBackend> Checksummed<B> {
+    /// Wrap `inner`, adding checksums if the state has them, or if it is
+    /// empty.
+    ///
+    /// Empty states record that they have checksums, unless they are
+    /// `read_only`. The process that writes their first block records it
+    /// instead.
+    fn new(inner: B, read_only: bool) -> Result<Self, Error> {
+        let enabled = if inner.read(tree::METADATA, CHECKSUMS_KEY)?.is_some() {
+            true
+        } else if is_empty(&inner)? {
+            if !read_only {
+                inner.insert(tree::METADATA, CHECKSUMS_KEY, &seal(CHECKSUMS_KEY, &[1]))?;
+            }
+            true
+        } else {
+            tracing::warn!(
+                "the state was created without value checksums, \
+                 so on-disk corruption can't be detected until it is synced from scratch"
+            );
+            false
+        };
+
+        Ok(Self { inner, enabled })
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for Checksummed<B> {
+    fn open(config: &Config, network: Network) -> Result<Self, Error> {
+        Self::new(B::open(config, network)?, config.read_only)
+    }
+
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        B::open_archive(config, network)?
+            .map(|inner| Self::new(inner, config.read_only))
+            .transpose()
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let value = self.inner.read(tree, key)?;
+        if !self.enabled {
+            return Ok(value);
+        }
+
+        value.map(|value| unseal(tree, key, value)).transpose()
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        let entries = self.inner.iterate(tree, range)?;
+        if !self.enabled {
+            return Ok(entries);
+        }
+
+        let tree = tree.to_owned();
+        Ok(Box::new(entries.map(move |entry| {
+            let (key, value) = entry?;
+            let value = unseal(&tree, &key, value)?;
+            Ok((key, value))
+        })))
+    }
+
+    fn write_batch(&self, mut batch: WriteBatch) -> Result<(), Error> {
+        if self.enabled {
+            for op in batch.ops.iter_mut() {
+                match op {
+                    WriteOp::Insert { key, value, .. } => *value = seal(key, value),
+                    WriteOp::Replace { key, old, new, .. } => {
+                        *old = seal(key, old);
+                        *new = seal(key, new);
+                    }
+                    WriteOp::Remove { .. } | WriteOp::DeleteRange { .. } => {}
+                }
+            }
+        }
+
+        self.inner.write_batch(batch)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    fn compact(&self) -> Result<(), Error> {
+        self.inner.compact()
+    }
+
+    fn set_auto_compaction(&self, enabled: bool) -> Result<(), Error> {
+        self.inner.set_auto_compaction(enabled)
+    }
+
+    /// Backups are copied without checking or removing the checksums, so
+    /// they can be opened like the original state.
+    fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.inner.backup(path)
+    }
+
+    fn catch_up(&self) -> Result<(), Error> {
+        self.inner.catch_up()
+    }
+}
+
+/// Returns true if every tree in `backend` is empty.
+fn is_empty(backend: &dyn StorageBackend) -> Result<bool, Error> {
+    for name in tree::names() {
+        if backend.iterate(name, all_keys())?.next().is_some() {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns the checksum of `value` stored at `key`.
+///
+/// The key is included, so values that are written to the wrong key also
+/// fail their checks.
+fn checksum(key: &[u8], value: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().to_le_bytes()
+}
+
+/// Returns `value` followed by its checksum.
+fn seal(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(value.len() + CHECKSUM_LEN);
+    sealed.extend_from_slice(value);
+    sealed.extend_from_slice(&checksum(key, value));
+    sealed
+}
+
+/// Check and remove the checksum from the `stored` value of `key` in `tree`.
+fn unseal(tree: &str, key: &[u8], mut stored: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let stored_checksum: Option<[u8; CHECKSUM_LEN]> = stored
+        .len()
+        .checked_sub(CHECKSUM_LEN)
+        .and_then(|len| stored[len..].try_into().ok());
+
+    match stored_checksum {
+        Some(stored_checksum)
+            if stored_checksum == checksum(key, &stored[..stored.len() - CHECKSUM_LEN]) =>
+        {
+            stored.truncate(stored.len() - CHECKSUM_LEN);
+            Ok(stored)
+        }
+        _ => {
+            metrics::counter!("state.corrupt_value.count", 1);
+            let error = CorruptValue {
+                tree: tree.to_owned(),
+                key: key.to_vec(),
+            };
+            tracing::warn!(%error, "corrupt value in the state");
+            Err(error.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    #[test]
+    fn corrupt_values_fail_their_checksums() -> Result<(), Error> {
+        zebra_test::init();
+
+        // Memory backend clones share their trees, so `raw` reads and writes
+        // the values without checksums
+        let raw = MemoryBackend::default();
+        let backend = Checksummed::new(raw.clone(), false)?;
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+        backend.insert(tree::BY_HEIGHT, &[2], &[20])?;
+
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, Some(vec![10]));
+        assert_eq!(
+            raw.read(tree::BY_HEIGHT, &[1])?.map(|value| value.len()),
+            Some(1 + CHECKSUM_LEN)
+        );
+
+        let mut corrupt = raw.read(tree::BY_HEIGHT, &[2])?.expect("value was written");
+        corrupt[0] ^= 1;
+        raw.insert(tree::BY_HEIGHT, &[2], &corrupt)?;
+
+        let error = backend
+            .read(tree::BY_HEIGHT, &[2])
+            .expect_err("corrupt value fails its checksum");
+        assert_eq!(
+            CorruptValue::from_error(error.as_ref()),
+            Some(&CorruptValue {
+                tree: tree::BY_HEIGHT.to_owned(),
+                key: vec![2],
+            })
+        );
+
+        let entries: Vec<bool> = backend
+            .iterate(tree::BY_HEIGHT, all_keys())?
+            .map(|entry| entry.is_ok())
+            .collect();
+        assert_eq!(entries, vec![true, false]);
+
+        // Values moved to another key also fail their checksums
+        let moved = raw.read(tree::BY_HEIGHT, &[1])?.expect("value was written");
+        raw.insert(tree::BY_HEIGHT, &[3], &moved)?;
+        assert!(backend.read(tree::BY_HEIGHT, &[3]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn states_without_checksums_are_read_unchanged() -> Result<(), Error> {
+        zebra_test::init();
+
+        let raw = MemoryBackend::default();
+        raw.insert(tree::BY_HEIGHT, &[1], &[10])?;
+
+        let backend = Checksummed::new(raw.clone(), false)?;
+        assert!(!backend.enabled);
+        backend.insert(tree::BY_HEIGHT, &[2], &[20])?;
+
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, Some(vec![10]));
+        assert_eq!(raw.read(tree::BY_HEIGHT, &[2])?, Some(vec![20]));
+
+        Ok(())
+    }
+}
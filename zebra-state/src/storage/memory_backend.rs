@@ -0,0 +1,106 @@
+//! A storage backend that keeps every tree in memory.
+use super::{Entries, Error, KeyRange, StorageBackend, WriteBatch, WriteOp};
+use crate::Config;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::RangeBounds,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+use zebra_chain::Network;
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A [`StorageBackend`] that stores its trees in memory.
+///
+/// Clones share the same trees. The trees are dropped with the last clone.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryBackend {
+    trees: Arc<RwLock<HashMap<&'static str, Tree>>>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open(_config: &Config, _network: Network) -> Result<Self, Error> {
+        Ok(Self::default())
+    }
+
+    /// The memory backend doesn't have an archive tier, because there is no
+    /// cheaper storage to move old blocks to.
+    fn open_archive(_config: &Config, _network: Network) -> Result<Option<Self>, Error> {
+        Ok(None)
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let trees = self
+            .trees
+            .read()
+            .expect("storage lock should be unpoisoned");
+
+        Ok(trees.get(tree).and_then(|tree| tree.get(key)).cloned())
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        let trees = self
+            .trees
+            .read()
+            .expect("storage lock should be unpoisoned");
+
+        // Copy the entries, so the lock isn't held while they are iterated.
+        // `BTreeMap::range` panics on inverted ranges, so filter instead.
+        let entries: Vec<_> = trees
+            .get(tree)
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| range.contains(*key))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        let mut trees = self
+            .trees
+            .write()
+            .expect("storage lock should be unpoisoned");
+
+        for op in batch.ops {
+            let tree = trees.entry(op.tree()).or_default();
+            match op {
+                WriteOp::Insert { key, value, .. } => {
+                    tree.insert(key, value);
+                }
+                WriteOp::Remove { key, .. } => {
+                    tree.remove(&key);
+                }
+                WriteOp::Replace { key, old, new, .. } => {
+                    if tree.get(&key) == Some(&old) {
+                        tree.insert(key, new);
+                    }
+                }
+                WriteOp::DeleteRange { range, .. } => {
+                    let keys: Vec<_> = tree
+                        .keys()
+                        .filter(|key| range.contains(*key))
+                        .cloned()
+                        .collect();
+                    for key in keys {
+                        tree.remove(&key);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The memory backend is discarded when Zebra exits, so it can't be
+    /// backed up.
+    fn backup(&self, _path: &Path) -> Result<(), Error> {
+        Err("the memory storage backend can't be backed up")?
+    }
+}
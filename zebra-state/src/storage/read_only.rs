@@ -0,0 +1,142 @@
+//! Read-only access to the state storage.
+//!
+//! Explorers, exporters, and debuggers can open a state with
+//! [`Config::read_only`], so they can't modify it by accident. Every write is
+//! rejected with a [`ReadOnlyState`] error, before it reaches the storage
+//! engine.
+//!
+//! RocksDB databases are opened in RocksDB's read-only mode, so they can be
+//! inspected while a node is using them. Each open reads the state as it was
+//! when it was opened. sled databases can only be opened by one process at a
+//! time, so the node that uses them must be stopped first. RocksDB states that
+//! don't exist yet, and states that need a format upgrade, can't be opened
+//! read-only.
+use super::{Entries, Error, KeyRange, StorageBackend, WriteBatch};
+use crate::Config;
+use std::{error, fmt, path::Path};
+use zebra_chain::Network;
+
+/// The state was opened read-only, so it can't be modified.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ReadOnlyState;
+
+impl fmt::Display for ReadOnlyState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the state was opened read-only, so it can't be modified")
+    }
+}
+
+impl error::Error for ReadOnlyState {}
+
+impl ReadOnlyState {
+    /// Returns the read-only error in `error`, if it is a [`ReadOnlyState`]
+    /// error.
+    pub fn from_error(error: &(dyn error::Error + 'static)) -> Option<&ReadOnlyState> {
+        error.downcast_ref()
+    }
+}
+
+/// A [`StorageBackend`] that rejects every write to `B`.
+pub(crate) struct ReadOnly<B> {
+    inner: B,
+}
+
+impl<B: StorageBackend> StorageBackend for ReadOnly<B> {
+    fn open(config: &Config, network: Network) -> Result<Self, Error> {
+        Ok(Self {
+            inner: B::open(config, network)?,
+        })
+    }
+
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        Ok(B::open_archive(config, network)?.map(|inner| Self { inner }))
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.read(tree, key)
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        self.inner.iterate(tree, range)
+    }
+
+    fn write_batch(&self, _batch: WriteBatch) -> Result<(), Error> {
+        Err(ReadOnlyState)?
+    }
+
+    /// There are never any changes to flush.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), Error> {
+        Err(ReadOnlyState)?
+    }
+
+    fn set_auto_compaction(&self, _enabled: bool) -> Result<(), Error> {
+        Err(ReadOnlyState)?
+    }
+
+    fn upgrade_batch(&self) -> Result<usize, Error> {
+        Err(ReadOnlyState)?
+    }
+
+    /// Backups only read the state, so they are allowed.
+    fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.inner.backup(path)
+    }
+
+    fn catch_up(&self) -> Result<(), Error> {
+        self.inner.catch_up()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{self, tree, SledBackend};
+    use tempdir::TempDir;
+
+    #[test]
+    fn writes_are_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        storage::open(&config, Network::Mainnet)?.insert(tree::BY_HEIGHT, &[0], &[1])?;
+
+        let config = Config {
+            read_only: true,
+            ..config
+        };
+        let backend = ReadOnly::<SledBackend>::open(&config, Network::Mainnet)?;
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[0])?, Some(vec![1]));
+
+        let error = backend
+            .insert(tree::BY_HEIGHT, &[0], &[2])
+            .expect_err("read-only backends reject writes");
+        assert!(ReadOnlyState::from_error(error.as_ref()).is_some());
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[0])?, Some(vec![1]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_states_are_opened() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            read_only: true,
+            ..Config::default()
+        };
+        let backend = storage::open(&config, Network::Mainnet)?;
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[0])?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+//! A storage backend that stores each tree in a RocksDB column family.
+use super::{tree, Durability, Entries, Error, KeyRange, StorageBackend, WriteBatch, WriteOp};
+use crate::Config;
+use rocksdb::{
+    checkpoint::Checkpoint, BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DBRawIterator,
+    Options, WriteOptions, DB,
+};
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use zebra_chain::Network;
+
+/// RocksDB databases use `1 / WRITE_BUFFER_DIVISOR` of their memory budget for
+/// write buffers, and the rest for their block cache.
+const WRITE_BUFFER_DIVISOR: u64 = 4;
+
+/// How a RocksDB database is opened.
+#[derive(Copy, Clone, Debug)]
+enum AccessMode<'a> {
+    /// Reads and writes, which need exclusive access to the database.
+    ReadWrite,
+    /// Reads from the database as it was when it was opened.
+    ReadOnly,
+    /// Reads from a database that another process is writing, storing the
+    /// secondary's own files at the path.
+    Secondary(&'a Path),
+}
+
+impl<'a> AccessMode<'a> {
+    /// Returns the access mode for `config`, using `secondary_path` if it
+    /// opens a secondary database.
+    fn of(config: &Config, secondary_path: Option<&'a Path>) -> Self {
+        match (config.read_only, secondary_path) {
+            (false, _) => AccessMode::ReadWrite,
+            (true, None) => AccessMode::ReadOnly,
+            (true, Some(path)) => AccessMode::Secondary(path),
+        }
+    }
+}
+
+/// A [`StorageBackend`] for a RocksDB database.
+///
+/// Each state tree is a column family, so each tree can be compacted and
+/// cached separately.
+#[derive(Clone)]
+pub(crate) struct RocksDbBackend {
+    db: Arc<DB>,
+    /// Serializes batch writes, so conditional changes see a consistent view
+    /// of the database.
+    write_lock: Arc<Mutex<()>>,
+    /// Is this a secondary database, which can catch up with its primary?
+    is_secondary: bool,
+    /// When each batch is synced to disk.
+    durability: Durability,
+}
+
+impl RocksDbBackend {
+    /// Open or create the database at `path`, with a column family for each
+    /// tree.
+    ///
+    /// The column families share a block cache and write buffers, which use
+    /// at most `memory_bytes` together. Flushes and compactions run on up to
+    /// `compaction_threads` background threads.
+    ///
+    /// Read-only and secondary databases don't conflict with a process that
+    /// is writing to the database.
+    ///
+    /// Writes are synced to disk as configured by `durability`.
+    fn open_path(
+        path: &Path,
+        memory_bytes: u64,
+        compaction_threads: u32,
+        mode: AccessMode<'_>,
+        durability: Durability,
+    ) -> Result<Self, Error> {
+        let write_buffer_bytes = memory_bytes / WRITE_BUFFER_DIVISOR;
+        let cache_bytes = memory_bytes - write_buffer_bytes;
+        let tree_count = tree::names().count() as u64;
+
+        let mut block_options = BlockBasedOptions::default();
+        block_options.set_lru_cache(cache_bytes as usize);
+        // Keep index and filter blocks in the block cache, so they count
+        // towards the budget
+        block_options.set_cache_index_and_filter_blocks(true);
+        let options = || {
+            let mut options = Options::default();
+            options.set_block_based_table_factory(&block_options);
+            options.set_write_buffer_size((write_buffer_bytes / tree_count) as usize);
+            options
+        };
+
+        let mut db_options = options();
+        db_options.create_if_missing(true);
+        db_options.create_missing_column_families(true);
+        // Flush the largest write buffers when all the column families
+        // together are over their share of the budget
+        db_options.set_db_write_buffer_size(write_buffer_bytes as usize);
+        db_options.set_max_background_jobs(compaction_threads.max(1) as i32);
+        // Without the log, recent writes are only in the write buffers, so
+        // flush every column family together, to keep the trees consistent
+        // after a crash
+        db_options.set_atomic_flush(!durability.rocksdb_write_ahead_log());
+        let db = match mode {
+            AccessMode::ReadWrite => {
+                let column_families =
+                    tree::names().map(|name| ColumnFamilyDescriptor::new(name, options()));
+                DB::open_cf_descriptors(&db_options, path, column_families)?
+            }
+            AccessMode::ReadOnly => {
+                DB::open_cf_for_read_only(&db_options, path, tree::names(), false)?
+            }
+            AccessMode::Secondary(secondary_path) => {
+                // Secondaries must keep every table file open, so they can
+                // read files that the primary has deleted
+                db_options.set_max_open_files(-1);
+                DB::open_cf_as_secondary(&db_options, path, secondary_path, tree::names())?
+            }
+        };
+
+        Ok(Self {
+            db: Arc::new(db),
+            write_lock: Default::default(),
+            is_secondary: matches!(mode, AccessMode::Secondary(_)),
+            durability,
+        })
+    }
+
+    /// Returns the column family for `tree`.
+    fn column_family(&self, tree: &str) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(tree)
+            .ok_or_else(|| format!("{} is not a state tree", tree).into())
+    }
+}
+
+impl StorageBackend for RocksDbBackend {
+    fn open(config: &Config, network: Network) -> Result<Self, Error> {
+        let secondary_path = config.secondary_rocksdb_path(network);
+        Self::open_path(
+            &config.rocksdb_path(network),
+            config.state_memory_bytes(),
+            config.compaction_threads,
+            AccessMode::of(config, secondary_path.as_deref()),
+            config.durability,
+        )
+    }
+
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        config
+            .archive_rocksdb_path(network)
+            .map(|path| {
+                let secondary_path = config.secondary_archive_rocksdb_path(network);
+                Self::open_path(
+                    &path,
+                    config.archive_memory_bytes(),
+                    config.compaction_threads,
+                    AccessMode::of(config, secondary_path.as_deref()),
+                    config.durability,
+                )
+            })
+            .transpose()
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.db.get_cf(self.column_family(tree)?, key)?)
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        let column_family = self.column_family(tree)?;
+
+        Ok(Box::new(RangeIter {
+            front: self.db.raw_iterator_cf(column_family),
+            back: self.db.raw_iterator_cf(column_family),
+            range,
+            front_key: None,
+            back_key: None,
+            done: false,
+        }))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        let _guard = self
+            .write_lock
+            .lock()
+            .expect("write lock should be unpoisoned");
+
+        // RocksDB batches are write-only, so conditional and range changes
+        // are resolved here, including earlier changes in the same batch.
+        let mut pending: HashMap<(&'static str, Vec<u8>), Option<Vec<u8>>> = HashMap::new();
+        let mut writes = rocksdb::WriteBatch::default();
+        for op in batch.ops {
+            let tree = op.tree();
+            let column_family = self.column_family(tree)?;
+            match op {
+                WriteOp::Insert { key, value, .. } => {
+                    writes.put_cf(column_family, &key, &value);
+                    pending.insert((tree, key), Some(value));
+                }
+                WriteOp::Remove { key, .. } => {
+                    writes.delete_cf(column_family, &key);
+                    pending.insert((tree, key), None);
+                }
+                WriteOp::Replace { key, old, new, .. } => {
+                    let current = match pending.get(&(tree, key.clone())) {
+                        Some(value) => value.clone(),
+                        None => self.db.get_cf(column_family, &key)?,
+                    };
+                    if current.as_ref() == Some(&old) {
+                        writes.put_cf(column_family, &key, &new);
+                        pending.insert((tree, key), Some(new));
+                    }
+                }
+                WriteOp::DeleteRange { range, .. } => {
+                    let mut keys = self
+                        .iterate(tree, range.clone())?
+                        .map(|entry| entry.map(|(key, _)| key))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    keys.extend(
+                        pending
+                            .keys()
+                            .filter(|(pending_tree, key)| {
+                                *pending_tree == tree && range.contains(key)
+                            })
+                            .map(|(_, key)| key.clone()),
+                    );
+                    for key in keys {
+                        writes.delete_cf(column_family, &key);
+                        pending.insert((tree, key), None);
+                    }
+                }
+            }
+        }
+
+        let mut options = WriteOptions::default();
+        options.set_sync(self.durability.sync_writes());
+        options.disable_wal(!self.durability.rocksdb_write_ahead_log());
+        self.db.write_opt(writes, &options)?;
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        for name in tree::names() {
+            self.db.flush_cf(self.column_family(name)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), Error> {
+        for name in tree::names() {
+            self.db
+                .compact_range_cf::<&[u8], &[u8]>(self.column_family(name)?, None, None);
+        }
+
+        Ok(())
+    }
+
+    /// While automatic compaction is paused, the level 0 write slowdown and
+    /// stop triggers are raised, so writes aren't throttled by the files
+    /// waiting for compaction.
+    fn set_auto_compaction(&self, enabled: bool) -> Result<(), Error> {
+        let options: &[(&str, &str)] = if enabled {
+            &[
+                ("disable_auto_compactions", "false"),
+                ("level0_slowdown_writes_trigger", "20"),
+                ("level0_stop_writes_trigger", "36"),
+            ]
+        } else {
+            &[
+                ("disable_auto_compactions", "true"),
+                ("level0_slowdown_writes_trigger", "1000000"),
+                ("level0_stop_writes_trigger", "1000000"),
+            ]
+        };
+        for name in tree::names() {
+            self.db.set_options_cf(self.column_family(name)?, options)?;
+        }
+
+        Ok(())
+    }
+
+    /// RocksDB checkpoints hard link the table files, so they are quick, and
+    /// don't block writes.
+    fn backup(&self, path: &Path) -> Result<(), Error> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+
+        Ok(())
+    }
+
+    fn catch_up(&self) -> Result<(), Error> {
+        if self.is_secondary {
+            self.db.try_catch_up_with_primary()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A double-ended iterator over the entries of a column family in a key range.
+///
+/// RocksDB iterators only move in one direction at a time, so each end has its
+/// own iterator. Iteration stops when the two ends meet.
+struct RangeIter<'a> {
+    front: DBRawIterator<'a>,
+    back: DBRawIterator<'a>,
+    range: KeyRange,
+    /// The last key returned from the front, if any.
+    front_key: Option<Vec<u8>>,
+    /// The last key returned from the back, if any.
+    back_key: Option<Vec<u8>>,
+    /// Have all the entries in the range been returned?
+    done: bool,
+}
+
+impl RangeIter<'_> {
+    /// Returns `entry` if it is in the range and hasn't been returned from the
+    /// other end. Otherwise, finishes the iteration.
+    fn accept(
+        &mut self,
+        entry: Option<Result<(Vec<u8>, Vec<u8>), Error>>,
+        forward: bool,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>), Error>> {
+        let (key, value) = match entry {
+            Some(Ok(entry)) => entry,
+            other => {
+                self.done = true;
+                return other;
+            }
+        };
+
+        let crossed = if forward {
+            self.back_key.as_ref().map_or(false, |back| key >= *back)
+        } else {
+            self.front_key.as_ref().map_or(false, |front| key <= *front)
+        };
+        if crossed || !self.range.contains(&key) {
+            self.done = true;
+            return None;
+        }
+
+        if forward {
+            self.front_key = Some(key.clone());
+        } else {
+            self.back_key = Some(key.clone());
+        }
+        Some(Ok((key, value)))
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if self.front_key.is_none() {
+            match &self.range.0 {
+                Bound::Included(start) => self.front.seek(start),
+                Bound::Excluded(start) => {
+                    self.front.seek(start);
+                    if self.front.valid() && self.front.key() == Some(&start[..]) {
+                        self.front.next();
+                    }
+                }
+                Bound::Unbounded => self.front.seek_to_first(),
+            }
+        } else {
+            self.front.next();
+        }
+
+        let entry = current_entry(&self.front);
+        self.accept(entry, true)
+    }
+}
+
+impl DoubleEndedIterator for RangeIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if self.back_key.is_none() {
+            match &self.range.1 {
+                Bound::Included(end) => self.back.seek_for_prev(end),
+                Bound::Excluded(end) => {
+                    self.back.seek_for_prev(end);
+                    if self.back.valid() && self.back.key() == Some(&end[..]) {
+                        self.back.prev();
+                    }
+                }
+                Bound::Unbounded => self.back.seek_to_last(),
+            }
+        } else {
+            self.back.prev();
+        }
+
+        let entry = current_entry(&self.back);
+        self.accept(entry, false)
+    }
+}
+
+/// Returns the entry at the current position of `iter`, or `None` if it has
+/// moved past the first or last entry.
+fn current_entry(iter: &DBRawIterator<'_>) -> Option<Result<(Vec<u8>, Vec<u8>), Error>> {
+    if !iter.valid() {
+        return iter.status().err().map(|error| Err(error.into()));
+    }
+
+    match (iter.key(), iter.value()) {
+        (Some(key), Some(value)) => Some(Ok((key.to_vec(), value.to_vec()))),
+        _ => None,
+    }
+}
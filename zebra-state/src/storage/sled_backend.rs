@@ -0,0 +1,343 @@
+//! A storage backend that stores each tree in a sled database tree.
+use super::{Entries, Error, KeyRange, StorageBackend, WriteBatch, WriteOp};
+use crate::Config;
+use sled::transaction::{TransactionError, TransactionResult, Transactional};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+};
+use zebra_chain::Network;
+
+/// The number of entries copied to a backup in each sled batch.
+const BACKUP_BATCH_SIZE: usize = 10_000;
+
+/// The values that writes have replaced since a backup started, by tree and
+/// key. `None` means that the key was absent.
+type BackupJournal = HashMap<(&'static str, Vec<u8>), Option<sled::IVec>>;
+
+/// A [`StorageBackend`] for a sled database.
+#[derive(Clone)]
+pub(crate) struct SledBackend {
+    db: sled::Db,
+    /// Held for reading by each batch write, and for writing while a backup
+    /// starts.
+    write_gate: Arc<RwLock<()>>,
+    /// The values replaced since the current backup started, if there is one.
+    ///
+    /// sled can't read a consistent view of several trees while they are
+    /// being written, so backups copy the trees while writes continue, then
+    /// restore the values in this journal.
+    backup_journal: Arc<Mutex<Option<BackupJournal>>>,
+    /// Is each batch flushed to disk before its write finishes?
+    sync_writes: bool,
+}
+
+impl SledBackend {
+    fn new(db: sled::Db, sync_writes: bool) -> Self {
+        Self {
+            db,
+            write_gate: Default::default(),
+            backup_journal: Default::default(),
+            sync_writes,
+        }
+    }
+
+    fn backup_journal(&self) -> MutexGuard<'_, Option<BackupJournal>> {
+        self.backup_journal
+            .lock()
+            .expect("backup journal mutex should be unpoisoned")
+    }
+
+    /// Flush the database, and start recording the values that writes
+    /// replace, so a copy can be restored to this point.
+    ///
+    /// Writes only wait for the flush.
+    fn start_backup(&self) -> Result<(), Error> {
+        let _gate = self
+            .write_gate
+            .write()
+            .expect("write gate should be unpoisoned");
+        self.db.flush()?;
+
+        let mut journal = self.backup_journal();
+        if journal.is_some() {
+            Err("a backup of this database is already in progress")?;
+        }
+        *journal = Some(BackupJournal::default());
+
+        Ok(())
+    }
+
+    /// Copy the database to a new database at `path`, and restore the values
+    /// that were replaced since the backup started.
+    fn finish_backup(&self, path: &Path) -> Result<(), Error> {
+        let copied = self.copy_trees(path);
+        let journal = self
+            .backup_journal()
+            .take()
+            .expect("the journal is recorded until the backup finishes");
+        let backup = copied?;
+
+        for ((name, key), value) in journal {
+            let backup_tree = backup.open_tree(name)?;
+            match value {
+                Some(value) => backup_tree.insert(key, value)?,
+                None => backup_tree.remove(key)?,
+            };
+        }
+        backup.flush()?;
+
+        Ok(())
+    }
+
+    /// Copy every tree to a new database at `path`, while writes continue.
+    fn copy_trees(&self, path: &Path) -> Result<sled::Db, Error> {
+        let backup = sled::Config::default().path(path).open()?;
+
+        for name in self.db.tree_names() {
+            let tree = self.db.open_tree(&name)?;
+            let backup_tree = backup.open_tree(&name)?;
+
+            let mut batch = sled::Batch::default();
+            let mut batch_len = 0;
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                batch.insert(key, value);
+                batch_len += 1;
+                if batch_len >= BACKUP_BATCH_SIZE {
+                    backup_tree.apply_batch(std::mem::take(&mut batch))?;
+                    batch_len = 0;
+                }
+            }
+            backup_tree.apply_batch(batch)?;
+        }
+
+        Ok(backup)
+    }
+}
+
+impl StorageBackend for SledBackend {
+    fn open(config: &Config, network: Network) -> Result<Self, Error> {
+        Ok(Self::new(
+            config.sled_config(network).open()?,
+            config.durability.sync_writes(),
+        ))
+    }
+
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        match config.archive_sled_config(network) {
+            Some(archive_config) => Ok(Some(Self::new(
+                archive_config.open()?,
+                config.durability.sync_writes(),
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let tree = self.db.open_tree(tree)?;
+
+        Ok(tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        let tree = self.db.open_tree(tree)?;
+
+        Ok(Box::new(tree.range(range).map(|entry| {
+            let (key, value) = entry?;
+            Ok((key.to_vec(), value.to_vec()))
+        })))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let _gate = self
+            .write_gate
+            .read()
+            .expect("write gate should be unpoisoned");
+
+        let mut names: Vec<&'static str> = Vec::new();
+        for op in &batch.ops {
+            if !names.contains(&op.tree()) {
+                names.push(op.tree());
+            }
+        }
+        let trees = names
+            .iter()
+            .map(|name| self.db.open_tree(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let index = |name: &str| {
+            names
+                .iter()
+                .position(|&tree| tree == name)
+                .expect("every tree in the batch is open")
+        };
+
+        // Find the keys in each range outside the transaction, because sled
+        // transactions don't support iteration.
+        let mut range_keys = Vec::new();
+        for op in &batch.ops {
+            if let WriteOp::DeleteRange { tree, range } = op {
+                let keys = trees[index(tree)]
+                    .range(range.clone())
+                    .keys()
+                    .collect::<Result<Vec<_>, _>>()?;
+                range_keys.push(keys);
+            }
+        }
+
+        // Record the values that this batch replaces, if a backup is being
+        // copied. The first write to each key records the value it had when
+        // the backup started.
+        if let Some(journal) = self.backup_journal().as_mut() {
+            let mut record = |name: &'static str, key: &[u8]| -> Result<(), Error> {
+                if let Entry::Vacant(entry) = journal.entry((name, key.to_vec())) {
+                    entry.insert(trees[index(name)].get(key)?);
+                }
+                Ok(())
+            };
+            let mut range_keys = range_keys.iter();
+            for op in &batch.ops {
+                match op {
+                    WriteOp::Insert { tree, key, .. }
+                    | WriteOp::Remove { tree, key }
+                    | WriteOp::Replace { tree, key, .. } => record(*tree, key)?,
+                    WriteOp::DeleteRange { tree, .. } => {
+                        let keys = range_keys.next().expect("every range has a key list");
+                        for key in keys {
+                            record(*tree, key)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        let result: TransactionResult<()> = trees.as_slice().transaction(|trees| {
+            let mut range_keys = range_keys.iter();
+            for op in &batch.ops {
+                let tree = &trees[index(op.tree())];
+                match op {
+                    WriteOp::Insert { key, value, .. } => {
+                        tree.insert(&key[..], &value[..])?;
+                    }
+                    WriteOp::Remove { key, .. } => {
+                        tree.remove(&key[..])?;
+                    }
+                    WriteOp::Replace { key, old, new, .. } => {
+                        if tree.get(&key[..])?.as_deref() == Some(&old[..]) {
+                            tree.insert(&key[..], &new[..])?;
+                        }
+                    }
+                    WriteOp::DeleteRange { .. } => {
+                        let keys = range_keys.next().expect("every range has a key list");
+                        for key in keys {
+                            tree.remove(key.clone())?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+
+        result.map_err(|error| match error {
+            TransactionError::Storage(e) => Error::from(e),
+            TransactionError::Abort(()) => {
+                unreachable!("zebra-state transactions are never aborted")
+            }
+        })?;
+
+        if self.sync_writes {
+            self.db.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.start_backup()?;
+        self.finish_backup(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{tree, Durability};
+
+    /// Returns the number of bytes that a flush writes after a key is
+    /// inserted into a sled backend with `durability`.
+    fn unflushed_bytes(durability: Durability) -> Result<usize, Error> {
+        let cache_dir = tempdir::TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            durability,
+            ..Config::default()
+        };
+        let backend = SledBackend::open(&config, Network::Mainnet)?;
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+
+        Ok(backend.db.flush()?)
+    }
+
+    #[test]
+    fn backups_restore_values_written_during_the_copy() -> Result<(), Error> {
+        zebra_test::init();
+
+        let cache_dir = tempdir::TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Config::default()
+        };
+        let backend = SledBackend::open(&config, Network::Mainnet)?;
+        backend.insert(tree::BY_HEIGHT, &[1], &[10])?;
+        backend.insert(tree::BY_HEIGHT, &[2], &[20])?;
+
+        backend.start_backup()?;
+        // Writes don't wait for the copy
+        backend.insert(tree::BY_HEIGHT, &[1], &[11])?;
+        backend.insert(tree::BY_HEIGHT, &[1], &[12])?;
+        backend.remove(tree::BY_HEIGHT, &[2])?;
+        backend.insert(tree::BY_HEIGHT, &[3], &[30])?;
+        // Only one backup can be copied at a time
+        assert!(backend.start_backup().is_err());
+
+        let backup_dir = tempdir::TempDir::new("")?;
+        let path = backup_dir.path().join("state");
+        backend.finish_backup(&path)?;
+        backend.insert(tree::BY_HEIGHT, &[4], &[40])?;
+
+        // The backup has the values from when it started
+        let backup = sled::Config::default().path(&path).open()?;
+        let backup_tree = backup.open_tree(tree::BY_HEIGHT)?;
+        assert_eq!(backup_tree.get([1])?.as_deref(), Some(&[10][..]));
+        assert_eq!(backup_tree.get([2])?.as_deref(), Some(&[20][..]));
+        assert_eq!(backup_tree.get([3])?, None);
+        assert_eq!(backup_tree.get([4])?, None);
+        // The database has the later writes
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, Some(vec![12]));
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[3])?, Some(vec![30]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_durability_flushes_each_write() -> Result<(), Error> {
+        zebra_test::init();
+
+        // Full durability has already flushed the write
+        assert_eq!(unflushed_bytes(Durability::Full)?, 0);
+        // Async durability leaves it in sled's buffers
+        assert!(unflushed_bytes(Durability::Async)? > 0);
+
+        Ok(())
+    }
+}
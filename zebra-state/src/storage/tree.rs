@@ -0,0 +1,256 @@
+//! The trees in the on-disk state, and the format of their keys and values.
+//!
+//! The storage code names its trees using the constants in this module, and
+//! [`TREES`] describes each tree. `zebrad database schema` renders the table
+//! as documentation, so keep the descriptions up to date when a format
+//! changes, and increment [`STATE_FORMAT_VERSION`].
+
+/// The version of the on-disk format described by [`TREES`].
+pub const STATE_FORMAT_VERSION: u32 = 4;
+
+/// A tree in the on-disk state.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TreeSchema {
+    /// The name of the tree, which is also the RocksDB column family name
+    pub name: &'static str,
+    /// The format of the keys in the tree
+    pub key: &'static str,
+    /// The format of the values in the tree
+    pub value: &'static str,
+    /// What the tree contains
+    pub description: &'static str,
+    /// True if the tree is stored in the archive database in `archive_dir`,
+    /// rather than the state database
+    pub archive: bool,
+}
+
+pub(crate) const BY_HEIGHT: &str = "by_height";
+pub(crate) const BY_HASH: &str = "by_hash";
+pub(crate) const HEADERS: &str = "headers";
+pub(crate) const BODIES: &str = "bodies";
+pub(crate) const BLOCK_INFO: &str = "block_info";
+pub(crate) const TRANSPARENT_OUTPUTS: &str = "transparent_outputs";
+pub(crate) const VALUE_POOLS: &str = "value_pools";
+pub(crate) const SHIELDED_COUNTS: &str = "shielded_counts";
+pub(crate) const NOTE_COMMITMENT_TREES: &str = "note_commitment_trees";
+pub(crate) const SAPLING_SUBTREES: &str = "sapling_subtrees";
+pub(crate) const HISTORY_TREES: &str = "history_trees";
+pub(crate) const TRANSACTION_LOCATIONS: &str = "transaction_locations";
+pub(crate) const NULLIFIERS: &str = "nullifiers";
+pub(crate) const OUTPUT_ADDRESSES: &str = "output_addresses";
+pub(crate) const ADDRESS_TOTALS: &str = "address_totals";
+pub(crate) const ADDRESS_UTXOS: &str = "address_utxos";
+pub(crate) const ADDRESS_TRANSACTIONS: &str = "address_transactions";
+pub(crate) const INVALID: &str = "invalid";
+pub(crate) const FAILED_BLOCKS: &str = "failed_blocks";
+pub(crate) const METADATA: &str = "metadata";
+pub(crate) const BLOCKS: &str = "blocks";
+pub(crate) const DATABASE_HISTORY: &str = "database_history";
+
+/// Every tree used by the state, including the archive.
+///
+/// Backends that need to declare their trees up front, like RocksDB, create
+/// these trees when they are opened.
+pub const TREES: &[TreeSchema] = &[
+    TreeSchema {
+        name: BY_HEIGHT,
+        key: "block height (u32, big-endian)",
+        value: "block hash (32 bytes)",
+        description: "The best chain, by height",
+        archive: false,
+    },
+    TreeSchema {
+        name: BY_HASH,
+        key: "block hash (32 bytes)",
+        value: "block height (u32, big-endian)",
+        description: "The height of each best chain block",
+        archive: false,
+    },
+    TreeSchema {
+        name: HEADERS,
+        key: "block hash (32 bytes)",
+        value: "serialized block header",
+        description: "The header of each best chain block",
+        archive: false,
+    },
+    TreeSchema {
+        name: BODIES,
+        key: "block hash (32 bytes)",
+        value: "serialized transaction count and transactions, \
+                or a zero byte then the zstd-compressed body, \
+                or the block hash (32 bytes) if the body is in the archive",
+        description: "The body of each best chain block. \
+                      Pruned blocks don't have bodies",
+        archive: false,
+    },
+    TreeSchema {
+        name: BLOCK_INFO,
+        key: "block hash (32 bytes)",
+        value: "size (u32, little-endian), transaction count (u32, little-endian), \
+                fee flag (u8), total fee (i64, little-endian)",
+        description: "Metadata for each best chain block. \
+                      The fee flag is 0 if the total fee is unknown",
+        archive: false,
+    },
+    TreeSchema {
+        name: TRANSPARENT_OUTPUTS,
+        key: "outpoint: transaction hash (32 bytes), output index (u32, little-endian)",
+        value: "value in zatoshis (i64, little-endian)",
+        description: "Transparent outputs. \
+                      Pruned states only keep unspent outputs below the prune depth",
+        archive: false,
+    },
+    TreeSchema {
+        name: VALUE_POOLS,
+        key: "block hash (32 bytes)",
+        value: "transparent, Sprout, Sapling, and Orchard pool balances \
+                (4 x i64, little-endian)",
+        description: "The chain value pool balances after each best chain block",
+        archive: false,
+    },
+    TreeSchema {
+        name: SHIELDED_COUNTS,
+        key: "block hash (32 bytes)",
+        value: "Sprout and Sapling note commitment counts, \
+                then Sprout and Sapling nullifier counts (4 x u64, little-endian)",
+        description: "The shielded counts after each best chain block",
+        archive: false,
+    },
+    TreeSchema {
+        name: NOTE_COMMITMENT_TREES,
+        key: "block height (u32, big-endian)",
+        value: "serialized Sprout and Sapling note commitment trees",
+        description: "The note commitment trees after each best chain block",
+        archive: false,
+    },
+    TreeSchema {
+        name: SAPLING_SUBTREES,
+        key: "subtree index (u16, big-endian)",
+        value: "subtree root (32 bytes), end height (u32, big-endian)",
+        description: "Completed Sapling note commitment subtrees",
+        archive: false,
+    },
+    TreeSchema {
+        name: HISTORY_TREES,
+        key: "block height (u32, big-endian)",
+        value: "serialized history tree",
+        description: "The history tree after each best chain block, \
+                      from Heartwood activation",
+        archive: false,
+    },
+    TreeSchema {
+        name: TRANSACTION_LOCATIONS,
+        key: "transaction hash (32 bytes)",
+        value: "block height (u32, big-endian), transaction index (u32, big-endian)",
+        description: "The location of each best chain transaction",
+        archive: false,
+    },
+    TreeSchema {
+        name: NULLIFIERS,
+        key: "pool (u8, 0 for Sprout or 1 for Sapling), nullifier (32 bytes)",
+        value: "block height (u32, big-endian)",
+        description: "The Sprout and Sapling nullifiers revealed by best chain blocks. \
+                      States created before format version 4 only index the blocks \
+                      committed after they were upgraded",
+        archive: false,
+    },
+    TreeSchema {
+        name: OUTPUT_ADDRESSES,
+        key: "outpoint: transaction hash (32 bytes), output index (u32, little-endian)",
+        value: "address: type (u8), hash (20 bytes); \
+                then the creation height (u32, big-endian)",
+        description: "The address paid by each transparent output. \
+                      Only written if the address index is enabled",
+        archive: false,
+    },
+    TreeSchema {
+        name: ADDRESS_TOTALS,
+        key: "address: type (u8), hash (20 bytes)",
+        value: "total received, total spent (2 x i64, little-endian)",
+        description: "The totals for each transparent address. \
+                      Only written if the address index is enabled",
+        archive: false,
+    },
+    TreeSchema {
+        name: ADDRESS_UTXOS,
+        key: "address (21 bytes), creation height (u32, big-endian), outpoint (36 bytes)",
+        value: "value in zatoshis (i64, little-endian)",
+        description: "The unspent transparent outputs of each address. \
+                      Only written if the address index is enabled",
+        archive: false,
+    },
+    TreeSchema {
+        name: ADDRESS_TRANSACTIONS,
+        key: "address (21 bytes), block height (u32, big-endian), \
+              transaction index (u32, big-endian)",
+        value: "transaction hash (32 bytes)",
+        description: "The transactions that touch each address. \
+                      Only written if the address index is enabled",
+        archive: false,
+    },
+    TreeSchema {
+        name: INVALID,
+        key: "block hash (32 bytes)",
+        value: "hash of the invalid block that caused the marking (32 bytes)",
+        description: "Blocks that were marked invalid, and their descendants",
+        archive: false,
+    },
+    TreeSchema {
+        name: FAILED_BLOCKS,
+        key: "block hash (32 bytes)",
+        value: "verifier version (u32, big-endian), then the broken consensus rule (UTF-8)",
+        description: "Blocks that failed verification. Not included in snapshots",
+        archive: false,
+    },
+    TreeSchema {
+        name: METADATA,
+        key: "ASCII name: `pruned_height`, `archived_height`, `nullifier_index_start`, \
+              `value_checksums`, `format_version`, `state_identity`, \
+              or `upgrade_cursor/<version>/<tree>`",
+        value: "block height (u32, big-endian), a marker byte, \
+                format version (u32, big-endian), the state identity, \
+                or the first key that hasn't been upgraded",
+        description: "Pruning, archive, and format upgrade progress, \
+                      the first height in the nullifier index, \
+                      the value checksum marker, the state format version, \
+                      and the network, genesis hash, and versions that created the state",
+        archive: false,
+    },
+    TreeSchema {
+        name: BLOCKS,
+        key: "block hash (32 bytes)",
+        value: "serialized transaction count and transactions, \
+                or a zero byte then the zstd-compressed body",
+        description: "Archived block bodies",
+        archive: true,
+    },
+    TreeSchema {
+        name: DATABASE_HISTORY,
+        key: "record number (u32, big-endian)",
+        value: "open time in seconds since the UNIX epoch (u64, big-endian), \
+                state format version (u32, big-endian), then the Zebra version (UTF-8)",
+        description: "Each Zebra version that has opened the state, from format version 2. \
+                      Not included in snapshots",
+        archive: false,
+    },
+];
+
+/// Returns the names of every tree used by the state.
+pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+    TREES.iter().map(|tree| tree.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn tree_names_are_unique() {
+        let names: HashSet<&str> = names().collect();
+        assert_eq!(names.len(), TREES.len());
+        assert!(TREES
+            .iter()
+            .all(|tree| !tree.key.is_empty() && !tree.value.is_empty()));
+    }
+}
@@ -0,0 +1,506 @@
+//! Format upgrades that re-encode stored values while the state is running.
+//!
+//! Upgrading a large tree can take hours, so upgrades run in a background
+//! thread, and the state keeps serving blocks and indexes while they run.
+//! Each upgrade has a cursor in the `metadata` tree, which is the first key
+//! that hasn't been upgraded yet. The [`Upgrading`] backend selects the
+//! encoding of each value by its key: values before the cursor are already
+//! in the new encoding, and values at or after the cursor are upgraded when
+//! they are read. So readers only ever see the new encoding.
+//!
+//! Values that are written during an upgrade are already in the new
+//! encoding, so [`FormatUpgrade::upgrade`] must return them unchanged.
+use super::{
+    all_keys, key_range, tree, Entries, Error, KeyRange, StorageBackend, WriteBatch,
+    STATE_FORMAT_VERSION, TREES,
+};
+use crate::Config;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    error, fmt,
+    path::Path,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+use zebra_chain::Network;
+
+/// A change to the encoding of the values in a tree.
+pub(crate) struct FormatUpgrade {
+    /// The tree whose values are re-encoded
+    pub(crate) tree: &'static str,
+    /// The state format version that introduced the new encoding
+    pub(crate) version: u32,
+    /// Returns a stored value in the new encoding.
+    ///
+    /// Values that are already in the new encoding must be returned
+    /// unchanged.
+    pub(crate) upgrade: fn(&[u8]) -> Result<Vec<u8>, Error>,
+}
+
+/// The format upgrades, in the order they are applied.
+///
+/// When an encoding changes, add a [`FormatUpgrade`] here, and increment
+/// [`STATE_FORMAT_VERSION`].
+pub(crate) const UPGRADES: &[FormatUpgrade] = &[];
+
+/// The key for the format version of the state, in the `metadata` tree.
+const FORMAT_VERSION_KEY: &[u8] = b"format_version";
+
+/// The state has a format that this Zebra release can't read or upgrade.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum UnsupportedFormat {
+    /// The state doesn't record its format version.
+    ///
+    /// Format version 3 is the first version that records it. Earlier states
+    /// store whole blocks in the `by_height` and `by_hash` trees, which can't
+    /// be upgraded in place.
+    Unrecorded,
+    /// The state has a newer format version.
+    Newer(u32),
+}
+
+impl fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsupportedFormat::Unrecorded => write!(
+                f,
+                "the state was created by an older Zebra release, \
+                 and it can't be upgraded to format version {}",
+                STATE_FORMAT_VERSION
+            ),
+            UnsupportedFormat::Newer(version) => write!(
+                f,
+                "the state has format version {}, but this Zebra release only supports \
+                 format version {} or earlier",
+                version, STATE_FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl error::Error for UnsupportedFormat {}
+
+/// The maximum number of values upgraded in each batch.
+const UPGRADE_BATCH_SIZE: usize = 1000;
+
+/// How long the background task waits before retrying a failed batch.
+const UPGRADE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The start of the keys for upgrade cursors, in the `metadata` tree.
+const CURSOR_KEY_PREFIX: &str = "upgrade_cursor/";
+
+/// Returns the key for the cursor of `upgrade`, in the `metadata` tree.
+fn cursor_key(upgrade: &FormatUpgrade) -> Vec<u8> {
+    format!("{}{}/{}", CURSOR_KEY_PREFIX, upgrade.version, upgrade.tree).into_bytes()
+}
+
+/// Returns true if `key` in the `metadata` tree describes the format of the
+/// local database, rather than the chain.
+///
+/// Values are always read in the current format, so copies of the state
+/// don't need these keys.
+pub(crate) fn is_format_metadata(key: &[u8]) -> bool {
+    key == FORMAT_VERSION_KEY || key.starts_with(CURSOR_KEY_PREFIX.as_bytes())
+}
+
+/// A [`StorageBackend`] that upgrades the values in `B` to the current
+/// format version.
+pub(crate) struct Upgrading<B> {
+    inner: B,
+    /// The upgrades of the trees in this database
+    upgrades: Vec<&'static FormatUpgrade>,
+    /// The cursor of each upgrade that is in progress, by its index in
+    /// `upgrades`
+    cursors: RwLock<HashMap<usize, Vec<u8>>>,
+}
+
+impl<B: StorageBackend> Upgrading<B> {
+    /// Wrap `inner`, starting the `upgrades` that are newer than its format
+    /// version.
+    ///
+    /// New states already use the current format, so they don't start any
+    /// upgrades.
+    fn new(
+        inner: B,
+        upgrades: impl IntoIterator<Item = &'static FormatUpgrade>,
+    ) -> Result<Self, Error> {
+        let upgrades: Vec<_> = upgrades.into_iter().collect();
+        let version = match inner.read(tree::METADATA, FORMAT_VERSION_KEY)? {
+            Some(bytes) => u32::from_be_bytes(bytes.as_slice().try_into()?),
+            None if is_new(&inner)? => STATE_FORMAT_VERSION,
+            // Refuse old states, rather than stamping them with the current
+            // format version
+            None => Err(UnsupportedFormat::Unrecorded)?,
+        };
+        if version > STATE_FORMAT_VERSION {
+            Err(UnsupportedFormat::Newer(version))?;
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut cursors = HashMap::new();
+        for (index, upgrade) in upgrades.iter().enumerate() {
+            let cursor = match inner.read(tree::METADATA, &cursor_key(upgrade))? {
+                Some(cursor) => cursor,
+                None if upgrade.version > version => {
+                    batch.insert(tree::METADATA, cursor_key(upgrade), b"");
+                    Vec::new()
+                }
+                None => continue,
+            };
+
+            tracing::info!(
+                tree = upgrade.tree,
+                version = upgrade.version,
+                "upgrading the state format in the background"
+            );
+            cursors.insert(index, cursor);
+        }
+        if version < STATE_FORMAT_VERSION {
+            batch.insert(
+                tree::METADATA,
+                FORMAT_VERSION_KEY,
+                STATE_FORMAT_VERSION.to_be_bytes(),
+            );
+        }
+        if !batch.is_empty() {
+            inner.write_batch(batch)?;
+        }
+
+        Ok(Self {
+            inner,
+            upgrades,
+            cursors: RwLock::new(cursors),
+        })
+    }
+
+    /// Returns the upgrades of `tree` that are in progress, and their
+    /// cursors, in the order they are applied.
+    fn upgrades_of(&self, tree: &str) -> Vec<(&'static FormatUpgrade, Vec<u8>)> {
+        let cursors = self
+            .cursors
+            .read()
+            .expect("upgrade lock should be unpoisoned");
+
+        self.upgrades
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, upgrade)| upgrade.tree == tree)
+            .filter_map(|(index, upgrade)| Some((upgrade, cursors.get(&index)?.clone())))
+            .collect()
+    }
+
+    /// Returns the first upgrade that is in progress, its index, and its
+    /// cursor.
+    fn next_upgrade(&self) -> Option<(usize, &'static FormatUpgrade, Vec<u8>)> {
+        let cursors = self
+            .cursors
+            .read()
+            .expect("upgrade lock should be unpoisoned");
+
+        self.upgrades
+            .iter()
+            .copied()
+            .enumerate()
+            .find_map(|(index, upgrade)| Some((index, upgrade, cursors.get(&index)?.clone())))
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for Upgrading<B> {
+    fn open(config: &Config, network: Network) -> Result<Self, Error> {
+        let upgrades = UPGRADES
+            .iter()
+            .filter(|upgrade| !is_archive_tree(upgrade.tree));
+        Self::new(B::open(config, network)?, upgrades)
+    }
+
+    fn open_archive(config: &Config, network: Network) -> Result<Option<Self>, Error> {
+        let upgrades = UPGRADES
+            .iter()
+            .filter(|upgrade| is_archive_tree(upgrade.tree));
+        B::open_archive(config, network)?
+            .map(|inner| Self::new(inner, upgrades))
+            .transpose()
+    }
+
+    fn read(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let value = self.inner.read(tree, key)?;
+        let upgrades = self.upgrades_of(tree);
+        if upgrades.is_empty() {
+            return Ok(value);
+        }
+
+        value
+            .map(|value| upgrade_value(&upgrades, key, value))
+            .transpose()
+    }
+
+    fn iterate(&self, tree: &str, range: KeyRange) -> Result<Entries<'_>, Error> {
+        let entries = self.inner.iterate(tree, range)?;
+        let upgrades = self.upgrades_of(tree);
+        if upgrades.is_empty() {
+            return Ok(entries);
+        }
+
+        Ok(Box::new(entries.map(move |entry| {
+            let (key, value) = entry?;
+            let value = upgrade_value(&upgrades, &key, value)?;
+            Ok((key, value))
+        })))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        self.inner.write_batch(batch)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    fn compact(&self) -> Result<(), Error> {
+        self.inner.compact()
+    }
+
+    fn set_auto_compaction(&self, enabled: bool) -> Result<(), Error> {
+        self.inner.set_auto_compaction(enabled)
+    }
+
+    fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.inner.backup(path)
+    }
+
+    // The cursors aren't reloaded, because upgrading a value that is already
+    // in the new encoding doesn't change it
+    fn catch_up(&self) -> Result<(), Error> {
+        self.inner.catch_up()
+    }
+
+    fn upgrade_batch(&self) -> Result<usize, Error> {
+        let (index, upgrade, cursor) = match self.next_upgrade() {
+            Some(next) => next,
+            None => return Ok(0),
+        };
+
+        let mut batch = WriteBatch::default();
+        let mut last_key = None;
+        let mut count = 0;
+        for entry in self
+            .inner
+            .iterate(upgrade.tree, key_range(cursor..))?
+            .take(UPGRADE_BATCH_SIZE)
+        {
+            let (key, old) = entry?;
+            let new = (upgrade.upgrade)(&old)?;
+            if new != old {
+                // Skip values that were changed while we were upgrading
+                batch.replace(upgrade.tree, &key, &old, &new);
+            }
+            last_key = Some(key);
+            count += 1;
+        }
+
+        let next_cursor = match last_key {
+            // The next possible key after `key`
+            Some(mut key) if count == UPGRADE_BATCH_SIZE => {
+                key.push(0);
+                batch.insert(tree::METADATA, cursor_key(upgrade), &key);
+                Some(key)
+            }
+            _ => {
+                batch.remove(tree::METADATA, cursor_key(upgrade));
+                None
+            }
+        };
+        self.inner.write_batch(batch)?;
+
+        let mut cursors = self
+            .cursors
+            .write()
+            .expect("upgrade lock should be unpoisoned");
+        match next_cursor {
+            Some(next_cursor) => {
+                cursors.insert(index, next_cursor);
+            }
+            None => {
+                tracing::info!(
+                    tree = upgrade.tree,
+                    version = upgrade.version,
+                    "finished upgrading the state format"
+                );
+                cursors.remove(&index);
+            }
+        }
+
+        // Finishing an upgrade doesn't upgrade any values, but the next
+        // upgrade might still have work to do
+        Ok(count.max(1))
+    }
+}
+
+/// Returns `value` of `key` in the current encoding, using the `upgrades`
+/// of its tree.
+fn upgrade_value(
+    upgrades: &[(&FormatUpgrade, Vec<u8>)],
+    key: &[u8],
+    mut value: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    for (upgrade, cursor) in upgrades {
+        if key >= cursor.as_slice() {
+            value = (upgrade.upgrade)(&value)?;
+        }
+    }
+
+    Ok(value)
+}
+
+/// Returns true if `backend` has no data, apart from its metadata.
+fn is_new(backend: &dyn StorageBackend) -> Result<bool, Error> {
+    for name in tree::names().filter(|&name| name != tree::METADATA) {
+        if backend.iterate(name, all_keys())?.next().is_some() {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns true if `name` is a tree in the archive database.
+fn is_archive_tree(name: &str) -> bool {
+    TREES.iter().any(|tree| tree.name == name && tree.archive)
+}
+
+/// Spawn a background thread that runs the format upgrades that are in
+/// progress in `storage`, until they are finished.
+pub(crate) fn spawn_upgrader(storage: Arc<dyn StorageBackend>) {
+    thread::Builder::new()
+        .name("zebra-state-upgrade".into())
+        .spawn(move || loop {
+            match storage.upgrade_batch() {
+                Ok(0) => return,
+                Ok(count) => tracing::debug!(count, "upgraded stored values"),
+                Err(error) => {
+                    tracing::warn!(?error, "failed to upgrade the state format, retrying");
+                    thread::sleep(UPGRADE_RETRY_INTERVAL);
+                }
+            }
+        })
+        .expect("spawning the upgrade thread should succeed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    /// Appends a marker byte to old values, which don't end with it.
+    fn add_marker(value: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut value = value.to_vec();
+        if value.last() != Some(&0xff) {
+            value.push(0xff);
+        }
+        Ok(value)
+    }
+
+    const TEST_UPGRADES: &[FormatUpgrade] = &[FormatUpgrade {
+        tree: tree::BY_HEIGHT,
+        version: STATE_FORMAT_VERSION,
+        upgrade: add_marker,
+    }];
+
+    #[test]
+    fn values_are_upgraded_while_they_are_read() -> Result<(), Error> {
+        zebra_test::init();
+
+        // Memory backend clones share their trees, so `raw` reads and writes
+        // the values without upgrading them
+        let raw = MemoryBackend::default();
+        raw.insert(tree::BY_HEIGHT, &[1], &[10])?;
+        raw.insert(tree::BY_HEIGHT, &[2], &[20])?;
+        raw.insert(tree::BY_HEIGHT, &[3], &[30])?;
+        raw.insert(
+            tree::METADATA,
+            FORMAT_VERSION_KEY,
+            &(STATE_FORMAT_VERSION - 1).to_be_bytes(),
+        )?;
+
+        let backend = Upgrading::new(raw.clone(), TEST_UPGRADES)?;
+        assert_eq!(backend.read(tree::BY_HEIGHT, &[1])?, Some(vec![10, 0xff]));
+
+        // Values written during the upgrade use the new encoding
+        backend.insert(tree::BY_HEIGHT, &[4], &[40, 0xff])?;
+
+        let values = |backend: &dyn StorageBackend| -> Result<Vec<Vec<u8>>, Error> {
+            backend
+                .iterate(tree::BY_HEIGHT, all_keys())?
+                .map(|entry| entry.map(|(_, value)| value))
+                .collect()
+        };
+        let upgraded = vec![
+            vec![10, 0xff],
+            vec![20, 0xff],
+            vec![30, 0xff],
+            vec![40, 0xff],
+        ];
+        assert_eq!(values(&backend)?, upgraded);
+        assert_ne!(values(&raw)?, upgraded);
+
+        while backend.upgrade_batch()? > 0 {}
+        assert_eq!(values(&raw)?, upgraded);
+        assert_eq!(values(&backend)?, upgraded);
+        assert!(raw
+            .read(tree::METADATA, &cursor_key(&TEST_UPGRADES[0]))?
+            .is_none());
+
+        // Finished upgrades aren't started again
+        let backend = Upgrading::new(raw, TEST_UPGRADES)?;
+        assert!(backend.next_upgrade().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn newer_formats_are_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        let raw = MemoryBackend::default();
+        raw.insert(
+            tree::METADATA,
+            FORMAT_VERSION_KEY,
+            &(STATE_FORMAT_VERSION + 1).to_be_bytes(),
+        )?;
+        assert!(Upgrading::new(raw, TEST_UPGRADES).is_err());
+
+        // New states start at the current format version, without upgrades
+        let raw = MemoryBackend::default();
+        let backend = Upgrading::new(raw.clone(), TEST_UPGRADES)?;
+        assert!(backend.next_upgrade().is_none());
+        assert_eq!(
+            raw.read(tree::METADATA, FORMAT_VERSION_KEY)?,
+            Some(STATE_FORMAT_VERSION.to_be_bytes().to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unrecorded_formats_are_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        // States created before format version 3 don't record their format
+        let raw = MemoryBackend::default();
+        raw.insert(tree::BY_HEIGHT, &[0, 0, 0, 0], &[0; 80])?;
+
+        let error = Upgrading::new(raw.clone(), TEST_UPGRADES)
+            .err()
+            .expect("unrecorded formats are rejected");
+        assert_eq!(
+            error.downcast_ref::<UnsupportedFormat>(),
+            Some(&UnsupportedFormat::Unrecorded)
+        );
+        assert_eq!(raw.read(tree::METADATA, FORMAT_VERSION_KEY)?, None);
+
+        Ok(())
+    }
+}
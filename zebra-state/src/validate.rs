@@ -0,0 +1,215 @@
+//! Validation of the state config.
+//!
+//! zebrad validates the state config when it starts, so a missing or
+//! unusable state directory is reported before any service is started. The
+//! on-disk state also validates its config when it is opened, for
+//! applications that embed `zebra-state`.
+use crate::{Config, StorageBackendKind};
+use std::{error, fmt, fs, path::PathBuf};
+use tempdir::TempDir;
+
+/// The state config can't be used.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InvalidConfig {
+    /// The state is stored on disk, but `cache_dir` isn't set.
+    MissingCacheDir,
+    /// A configured state directory can't be used.
+    UnusableDir {
+        /// The config field, like `"cache_dir"`
+        field: &'static str,
+        /// The configured path
+        path: PathBuf,
+        /// Why the directory can't be used
+        reason: String,
+    },
+}
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidConfig::MissingCacheDir => f.write_str(
+                "the state cache directory isn't set, and there is no default cache directory \
+                 on this system. Add a cache directory to zebrad.toml:\n\
+                 [state]\n\
+                 cache_dir = '/path/to/cache-or-tmp'\n\
+                 or use a temporary state, which is deleted on shutdown:\n\
+                 [state]\n\
+                 ephemeral = true",
+            ),
+            InvalidConfig::UnusableDir {
+                field,
+                path,
+                reason,
+            } => write!(
+                f,
+                "the state {} can't be used: {} ({}). \
+                 Change `state.{}` in zebrad.toml",
+                field,
+                reason,
+                path.display(),
+                field,
+            ),
+        }
+    }
+}
+
+impl error::Error for InvalidConfig {}
+
+impl InvalidConfig {
+    /// Returns the config error in `error`, if it is an [`InvalidConfig`]
+    /// error.
+    pub fn from_error(error: &(dyn error::Error + 'static)) -> Option<&InvalidConfig> {
+        error.downcast_ref()
+    }
+}
+
+impl Config {
+    /// Check that the state directories in this config can be used, and
+    /// return the config with canonical paths.
+    ///
+    /// The state directories are created if they don't exist, and checked
+    /// for write access. Read-only states are only checked, because they
+    /// don't create or modify their directories.
+    ///
+    /// Ephemeral and memory states don't need `cache_dir`, but the other
+    /// configured directories are still checked.
+    pub fn validate(self) -> Result<Self, InvalidConfig> {
+        let needs_cache_dir = !self.ephemeral && self.storage_backend != StorageBackendKind::Memory;
+        if needs_cache_dir && self.cache_dir.is_none() {
+            return Err(InvalidConfig::MissingCacheDir);
+        }
+
+        let read_only = self.read_only;
+        Ok(Self {
+            cache_dir: check_dir("cache_dir", self.cache_dir, read_only)?,
+            archive_dir: check_dir("archive_dir", self.archive_dir, read_only)?,
+            backup_dir: check_dir("backup_dir", self.backup_dir, false)?,
+            secondary_dir: check_dir("secondary_dir", self.secondary_dir, false)?,
+            ..self
+        })
+    }
+}
+
+/// Returns the canonical path of the directory at `path`, if it is set.
+///
+/// Unless `read_only` is true, creates the directory, and checks that it is
+/// writable.
+fn check_dir(
+    field: &'static str,
+    path: Option<PathBuf>,
+    read_only: bool,
+) -> Result<Option<PathBuf>, InvalidConfig> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let unusable = |reason: String| InvalidConfig::UnusableDir {
+        field,
+        path: path.clone(),
+        reason,
+    };
+
+    if !read_only {
+        fs::create_dir_all(&path)
+            .map_err(|error| unusable(format!("it can't be created: {}", error)))?;
+        TempDir::new_in(&path, "write-check")
+            .map_err(|error| unusable(format!("it isn't writable: {}", error)))?;
+    }
+
+    let canonical = path
+        .canonicalize()
+        .map_err(|error| unusable(format!("it can't be opened: {}", error)))?;
+    if !canonical.is_dir() {
+        return Err(unusable("it isn't a directory".to_owned()));
+    }
+
+    Ok(Some(canonical))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_cache_dir_is_invalid() {
+        zebra_test::init();
+
+        let config = Config {
+            cache_dir: None,
+            ..Config::default()
+        };
+        assert_eq!(
+            config.clone().validate().unwrap_err(),
+            InvalidConfig::MissingCacheDir
+        );
+
+        let ephemeral = Config {
+            ephemeral: true,
+            ..config.clone()
+        };
+        assert!(ephemeral.validate().is_ok());
+        let memory = Config {
+            storage_backend: StorageBackendKind::Memory,
+            ..config
+        };
+        assert!(memory.validate().is_ok());
+    }
+
+    #[test]
+    fn dirs_are_created_and_canonicalized() -> Result<(), Box<dyn error::Error>> {
+        zebra_test::init();
+
+        let root = TempDir::new("")?;
+        let config = Config {
+            cache_dir: Some(root.path().join("new").join("..").join("cache")),
+            ..Config::default()
+        }
+        .validate()?;
+
+        let cache_dir = root.path().canonicalize()?.join("cache");
+        assert_eq!(config.cache_dir, Some(cache_dir.clone()));
+        assert!(cache_dir.is_dir());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unusable_dirs_are_invalid() -> Result<(), Box<dyn error::Error>> {
+        zebra_test::init();
+
+        let root = TempDir::new("")?;
+        let file = root.path().join("file");
+        fs::write(&file, b"")?;
+
+        let error = Config {
+            cache_dir: Some(file.clone()),
+            ..Config::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            InvalidConfig::UnusableDir {
+                field: "cache_dir",
+                ..
+            }
+        ));
+
+        let error = Config {
+            cache_dir: Some(root.path().join("missing")),
+            read_only: true,
+            ..Config::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            InvalidConfig::UnusableDir {
+                field: "cache_dir",
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! Chain value pool balances.
+//!
+//! The state tracks the total value in each pool after every block. A block
+//! that would make any pool balance negative is rejected, as required by the
+//! turnstile consensus rules.
+//!
+//! Balances are only tracked for chains that are connected to the genesis
+//! block, because the balances before any other block are unknown.
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
+use zebra_chain::{
+    block::Block,
+    transaction::{OutPoint, Transaction, TransactionHash, TransparentInput},
+    types::{
+        amount::{Amount, NegativeAllowed, NonNegative},
+        value_pool::{Orchard, PoolValue, Sapling, Sprout, Transparent, ValuePool},
+    },
+};
+
+/// The size of a serialized `ValueBalances`.
+pub(crate) const VALUE_BALANCES_SIZE: usize = 32;
+
+/// The size of a serialized `OutPoint` key.
+pub(crate) const OUTPOINT_KEY_SIZE: usize = 36;
+
+/// The total value in each chain value pool.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ValueBalances {
+    /// The value in unspent transparent outputs
+    pub transparent: PoolValue<Transparent>,
+    /// The value in the Sprout shielded pool
+    pub sprout: PoolValue<Sprout>,
+    /// The value in the Sapling shielded pool
+    pub sapling: PoolValue<Sapling>,
+    /// The value in the Orchard shielded pool
+    ///
+    /// Always zero, because Zebra doesn't support Orchard yet.
+    pub orchard: PoolValue<Orchard>,
+}
+
+impl ValueBalances {
+    /// The balances before the genesis block.
+    pub fn zero() -> Self {
+        ValueBalances {
+            transparent: PoolValue::zero(),
+            sprout: PoolValue::zero(),
+            sapling: PoolValue::zero(),
+            orchard: PoolValue::zero(),
+        }
+    }
+
+    /// Returns the balances after applying `block` to `self`.
+    ///
+    /// `prior_output` looks up the value of transparent outputs created by
+    /// earlier blocks. Outputs created earlier in `block` are looked up
+    /// automatically.
+    ///
+    /// Returns an error if `block` spends an unknown transparent output, or if
+    /// it would make any pool balance negative.
+    pub(crate) fn add_block<F>(&self, block: &Block, mut prior_output: F) -> Result<Self, Error>
+    where
+        F: FnMut(&OutPoint) -> Result<Option<Amount<NonNegative>>, Error>,
+    {
+        // The running totals can be temporarily negative, because spends are
+        // applied before the outputs that fund them
+        let mut transparent: PoolValue<Transparent, NegativeAllowed> =
+            self.transparent.constrain()?;
+        let mut sprout: PoolValue<Sprout, NegativeAllowed> = self.sprout.constrain()?;
+        let mut sapling: PoolValue<Sapling, NegativeAllowed> = self.sapling.constrain()?;
+
+        let mut created = HashMap::new();
+        for tx in &block.transactions {
+            for input in tx.inputs() {
+                if let TransparentInput::PrevOut { outpoint, .. } = input {
+                    let value = match created.get(&outpoint_key(outpoint)) {
+                        Some(&value) => value,
+                        None => prior_output(outpoint)?
+                            .ok_or("block spends an unknown transparent output")?,
+                    };
+                    transparent = (transparent - pool_change(value)?)?;
+                }
+            }
+
+            for (outpoint, value) in transaction_outputs(tx) {
+                transparent = (transparent + pool_change(value)?)?;
+                created.insert(outpoint_key(&outpoint), value);
+            }
+
+            let (vpub_old, vpub_new) = sprout_values(tx)?;
+            sprout = ((sprout + pool_change(vpub_old)?)? - pool_change(vpub_new)?)?;
+
+            if let Transaction::V4 { value_balance, .. } = tx.as_ref() {
+                sapling = (sapling - PoolValue::new(*value_balance))?;
+            }
+        }
+
+        Ok(ValueBalances {
+            transparent: pool_balance(transparent)?,
+            sprout: pool_balance(sprout)?,
+            sapling: pool_balance(sapling)?,
+            orchard: self.orchard,
+        })
+    }
+
+    /// Serialize these balances for storage.
+    pub(crate) fn to_bytes(&self) -> [u8; VALUE_BALANCES_SIZE] {
+        let mut bytes = [0; VALUE_BALANCES_SIZE];
+        let pools = [
+            self.transparent.amount(),
+            self.sprout.amount(),
+            self.sapling.amount(),
+            self.orchard.amount(),
+        ];
+        for (chunk, value) in bytes.chunks_mut(8).zip(pools.iter()) {
+            chunk.copy_from_slice(&i64::from(*value).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserialize balances from storage.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != VALUE_BALANCES_SIZE {
+            Err("stored value balances have an invalid length")?;
+        }
+
+        let mut pools = bytes.chunks(8).map(amount_from_bytes);
+        Ok(ValueBalances {
+            transparent: PoolValue::new(pools.next().expect("length was checked")?),
+            sprout: PoolValue::new(pools.next().expect("length was checked")?),
+            sapling: PoolValue::new(pools.next().expect("length was checked")?),
+            orchard: PoolValue::new(pools.next().expect("length was checked")?),
+        })
+    }
+}
+
+/// Returns the transparent outputs created by `block`, and their values.
+pub(crate) fn block_outputs(block: &Block) -> Vec<(OutPoint, Amount<NonNegative>)> {
+    block
+        .transactions
+        .iter()
+        .flat_map(|tx| transaction_outputs(tx))
+        .collect()
+}
+
+/// Returns the transparent outputs spent by `block`.
+pub(crate) fn block_spends(block: &Block) -> Vec<OutPoint> {
+    block
+        .transactions
+        .iter()
+        .flat_map(|tx| tx.inputs())
+        .filter_map(|input| match input {
+            TransparentInput::PrevOut { outpoint, .. } => Some(*outpoint),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the transparent outputs created by `tx`, and their values.
+pub(crate) fn transaction_outputs(tx: &Transaction) -> Vec<(OutPoint, Amount<NonNegative>)> {
+    let hash = TransactionHash::from(tx.clone());
+
+    tx.outputs()
+        .enumerate()
+        .map(|(index, output)| {
+            let outpoint = OutPoint {
+                hash,
+                index: index as u32,
+            };
+            (outpoint, output.value)
+        })
+        .collect()
+}
+
+/// Returns the total `vpub_old` and `vpub_new` values in the JoinSplits in
+/// `tx`.
+///
+/// Returns an error if either total is not a valid amount.
+pub(crate) fn sprout_values(
+    tx: &Transaction,
+) -> Result<(Amount<NonNegative>, Amount<NonNegative>), Error> {
+    fn sum(
+        values: impl Iterator<Item = (Amount<NonNegative>, Amount<NonNegative>)>,
+    ) -> Result<(Amount<NonNegative>, Amount<NonNegative>), Error> {
+        let (vpub_old, vpub_new): (Vec<_>, Vec<_>) = values.unzip();
+        let vpub_old: Result<Amount<NonNegative>, _> = vpub_old.into_iter().sum();
+        let vpub_new: Result<Amount<NonNegative>, _> = vpub_new.into_iter().sum();
+
+        Ok((vpub_old?, vpub_new?))
+    }
+
+    match tx {
+        Transaction::V2 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        }
+        | Transaction::V3 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        } => sum(joinsplit_data
+            .joinsplits()
+            .map(|joinsplit| (joinsplit.vpub_old, joinsplit.vpub_new))),
+        Transaction::V4 {
+            joinsplit_data: Some(joinsplit_data),
+            ..
+        } => sum(joinsplit_data
+            .joinsplits()
+            .map(|joinsplit| (joinsplit.vpub_old, joinsplit.vpub_new))),
+        _ => Ok((Amount::zero(), Amount::zero())),
+    }
+}
+
+/// Returns the storage key for `outpoint`.
+pub(crate) fn outpoint_key(outpoint: &OutPoint) -> [u8; OUTPOINT_KEY_SIZE] {
+    let mut key = [0; OUTPOINT_KEY_SIZE];
+    key[..32].copy_from_slice(&outpoint.hash.0);
+    key[32..].copy_from_slice(&outpoint.index.to_le_bytes());
+    key
+}
+
+/// Deserialize a stored transparent output value.
+pub(crate) fn amount_from_bytes(bytes: &[u8]) -> Result<Amount<NonNegative>, Error> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| "stored value has an invalid length")?;
+    Ok(Amount::try_from(i64::from_le_bytes(bytes))?)
+}
+
+/// Returns a change to the value pool `P`, which adds `value` to the pool.
+fn pool_change<P: ValuePool>(
+    value: Amount<NonNegative>,
+) -> Result<PoolValue<P, NegativeAllowed>, Error> {
+    Ok(PoolValue::new(value.constrain()?))
+}
+
+/// Check that a pool balance is a valid, non-negative amount.
+fn pool_balance<P: ValuePool>(value: PoolValue<P, NegativeAllowed>) -> Result<PoolValue<P>, Error> {
+    value
+        .constrain()
+        .map_err(|_| format!("block would make the {} value pool negative", P::NAME).into())
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use zebra_chain::serialization::ZcashDeserialize;
+
+    fn output_total(block: &Block) -> i64 {
+        block_outputs(block)
+            .into_iter()
+            .map(|(_, value)| i64::from(value))
+            .sum()
+    }
+
+    #[test]
+    fn value_balances_track_coinbase_outputs() -> Result<(), Error> {
+        zebra_test::init();
+
+        let genesis: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+        let block1: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+
+        let no_outputs = |_: &OutPoint| Ok(None);
+        let balances = ValueBalances::zero()
+            .add_block(&genesis, no_outputs)?
+            .add_block(&block1, no_outputs)?;
+
+        assert_eq!(
+            i64::from(balances.transparent.amount()),
+            output_total(&genesis) + output_total(&block1)
+        );
+        assert_eq!(i64::from(balances.sprout.amount()), 0);
+        assert_eq!(i64::from(balances.sapling.amount()), 0);
+
+        assert_eq!(ValueBalances::from_bytes(&balances.to_bytes())?, balances);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_outputs_are_rejected() -> Result<(), Error> {
+        zebra_test::init();
+
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..])?.into();
+
+        let result = ValueBalances::zero().add_block(&block, |_| Ok(None));
+        assert!(result.is_err());
+
+        Ok(())
+    }
+}
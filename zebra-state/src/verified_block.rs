@@ -0,0 +1,132 @@
+//! Verified blocks, ready to be committed to the state.
+use std::{fmt, sync::Arc};
+use zebra_chain::{
+    block::{Block, BlockHeader, BlockHeaderHash},
+    serialization::{ZcashDeserialize, ZcashSerialize},
+    types::BlockHeight,
+};
+
+/// A block that has passed semantic verification, along with its serialized
+/// bytes and the metadata the state needs to commit it.
+///
+/// The state stores the serialized bytes verbatim, so it doesn't have to
+/// serialize the block again in its single writer task. If the block was
+/// parsed from bytes, use `from_bytes` or `with_bytes` to keep the original
+/// bytes, so the block is never re-serialized.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SemanticallyVerifiedBlock {
+    block: Arc<Block>,
+    hash: BlockHeaderHash,
+    height: BlockHeight,
+    bytes: Arc<[u8]>,
+}
+
+impl SemanticallyVerifiedBlock {
+    /// Prepare `block` for commit, by serializing it.
+    ///
+    /// Returns an error if the block has no coinbase height.
+    pub fn new(block: Arc<Block>) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        block.zcash_serialize(&mut bytes)?;
+
+        Self::prepare(block, bytes.into())
+    }
+
+    /// Prepare a block for commit, by parsing its serialized `bytes`.
+    ///
+    /// Returns an error if the bytes are not a valid block, or the block has
+    /// no coinbase height.
+    pub fn from_bytes(bytes: impl Into<Arc<[u8]>>) -> Result<Self, Error> {
+        let bytes = bytes.into();
+        let block = Block::zcash_deserialize(&bytes[..])?;
+
+        Self::prepare(block.into(), bytes)
+    }
+
+    /// Prepare `block` for commit, using `bytes`, which must be the
+    /// serialized block.
+    ///
+    /// Only the block header is checked against `bytes`, so callers must not
+    /// modify the block after parsing it.
+    ///
+    /// Returns an error if the header in `bytes` doesn't match the block, or
+    /// the block has no coinbase height.
+    pub fn with_bytes(block: Arc<Block>, bytes: impl Into<Arc<[u8]>>) -> Result<Self, Error> {
+        let bytes = bytes.into();
+        let header = BlockHeader::zcash_deserialize(&bytes[..])?;
+        if BlockHeaderHash::from(&header) != block.hash() {
+            Err("serialized block does not match the parsed block")?;
+        }
+
+        Self::prepare(block, bytes)
+    }
+
+    fn prepare(block: Arc<Block>, bytes: Arc<[u8]>) -> Result<Self, Error> {
+        let height = block
+            .coinbase_height()
+            .ok_or("verified block has no coinbase height")?;
+
+        Ok(SemanticallyVerifiedBlock {
+            hash: block.hash(),
+            height,
+            block,
+            bytes,
+        })
+    }
+
+    /// The parsed block.
+    pub fn block(&self) -> &Arc<Block> {
+        &self.block
+    }
+
+    /// The hash of the block.
+    pub fn hash(&self) -> BlockHeaderHash {
+        self.hash
+    }
+
+    /// The height of the block.
+    pub fn height(&self) -> BlockHeight {
+        self.height
+    }
+
+    /// The serialized block.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for SemanticallyVerifiedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The block and its bytes are too large to be useful in logs
+        f.debug_struct("SemanticallyVerifiedBlock")
+            .field("hash", &self.hash)
+            .field("height", &self.height)
+            .field("size", &self.bytes.len())
+            .finish()
+    }
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verified_block_bytes() -> Result<(), Error> {
+        zebra_test::init();
+
+        let bytes = &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..];
+        let parsed = SemanticallyVerifiedBlock::from_bytes(bytes)?;
+        assert_eq!(parsed.bytes(), bytes);
+        assert_eq!(parsed.height(), BlockHeight(1));
+
+        let serialized = SemanticallyVerifiedBlock::new(parsed.block().clone())?;
+        assert_eq!(serialized, parsed);
+
+        let other = &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..];
+        assert!(SemanticallyVerifiedBlock::with_bytes(parsed.block().clone(), other).is_err());
+
+        Ok(())
+    }
+}
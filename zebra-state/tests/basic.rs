@@ -1,27 +1,131 @@
-use color_eyre::eyre::Report;
+use color_eyre::eyre::{eyre, Report};
 use once_cell::sync::Lazy;
-use std::sync::Arc;
+use std::{convert::TryFrom, sync::Arc};
 use tempdir::TempDir;
+use tower::{Service, ServiceExt};
 
-use zebra_chain::{block::Block, serialization::ZcashDeserialize, Network, Network::*};
+use zebra_chain::{
+    addresses::transparent::TransparentAddress,
+    block::{Block, BlockHeaderHash},
+    merkle_tree::MerklePath,
+    serialization::{Cached, ZcashDeserialize},
+    transaction::{OutPoint, Transaction, TransactionHash, TransparentInput, TransparentOutput},
+    types::{amount::Amount, value_pool::PoolValue, BlockHeight, LockTime, Script},
+    Network,
+    Network::*,
+};
 use zebra_test::transcript::Transcript;
 
 use zebra_state::*;
 
-static ADD_BLOCK_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
-    let block: Arc<_> =
-        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..])
-            .unwrap()
-            .into();
+/// Returns `block`, prepared for commit.
+fn verified(block: Arc<Block>) -> SemanticallyVerifiedBlock {
+    SemanticallyVerifiedBlock::new(block).unwrap()
+}
+
+static COMMIT_FINALIZED_BLOCK_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let bytes = &zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..];
+    let block: Arc<_> = Block::zcash_deserialize(bytes).unwrap().into();
     let hash = block.as_ref().into();
+    let info = BlockInfo {
+        size: bytes.len() as u32,
+        tx_count: block.transactions.len() as u32,
+        // The spent outputs aren't in the state
+        total_fee: None,
+    };
     vec![
+        // The parent block isn't in the state, but finalized blocks aren't
+        // contextually checked
         (
-            Request::AddBlock {
-                block: block.clone(),
+            Request::CommitFinalizedBlock {
+                block: verified(block.clone()),
+            },
+            Response::Committed { hash },
+        ),
+        (
+            Request::CommitFinalizedBlock {
+                block: verified(block.clone()),
             },
-            Response::Added { hash },
+            Response::AlreadyCommitted { hash },
         ),
         (Request::GetBlock { hash }, Response::Block { block }),
+        (
+            Request::GetRawBlock { hash },
+            Response::RawBlock {
+                bytes: bytes.to_vec(),
+            },
+        ),
+        (Request::BlockInfo { hash }, Response::BlockInfo(info)),
+    ]
+});
+
+static COMMIT_BLOCK_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let bytes = &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..];
+    let block = SemanticallyVerifiedBlock::from_bytes(bytes).unwrap();
+    let hash = block.hash();
+    let coinbase = TransactionInfo {
+        size: block.block().transactions[0].serialized_size() as u32,
+        is_coinbase: true,
+        fee: None,
+        transparent_inputs: 0,
+        transparent_outputs: 1,
+        sapling_spends: 0,
+        sapling_outputs: 0,
+    };
+    vec![
+        (
+            Request::CommitBlock {
+                block: block.clone(),
+            },
+            Response::Committed { hash },
+        ),
+        (
+            Request::CommitBlock {
+                block: block.clone(),
+            },
+            Response::AlreadyCommitted { hash },
+        ),
+        (
+            Request::GetBlock { hash },
+            Response::Block {
+                block: block.block().clone(),
+            },
+        ),
+        (
+            Request::GetRawBlock { hash },
+            Response::RawBlock {
+                bytes: bytes.to_vec(),
+            },
+        ),
+        (
+            Request::BlockTransactionInfo { hash },
+            Response::BlockTransactionInfo(vec![coinbase]),
+        ),
+    ]
+});
+
+static GENESIS_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let hash0 = block0.as_ref().into();
+    vec![
+        (Request::GetTip, Response::Empty),
+        // Genesis blocks don't need a parent block
+        (
+            Request::CommitBlock {
+                block: verified(block0.clone()),
+            },
+            Response::Committed { hash: hash0 },
+        ),
+        (Request::GetTip, Response::Tip { hash: hash0 }),
+        (
+            Request::CommitBlock {
+                block: verified(block0),
+            },
+            Response::AlreadyCommitted { hash: hash0 },
+        ),
     ]
 });
 
@@ -35,20 +139,789 @@ static GET_TIP_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
         .into();
     let hash0 = block0.as_ref().into();
     let hash1 = block1.as_ref().into();
+    let (header0, header1) = (block0.header, block1.header);
     vec![
         // Insert higher block first, lower block second
         (
-            Request::AddBlock { block: block1 },
-            Response::Added { hash: hash1 },
+            Request::CommitFinalizedBlock {
+                block: verified(block1),
+            },
+            Response::Committed { hash: hash1 },
+        ),
+        (
+            Request::CommitFinalizedBlock {
+                block: verified(block0),
+            },
+            Response::Committed { hash: hash0 },
+        ),
+        (Request::GetTip, Response::Tip { hash: hash1 }),
+        (
+            Request::GetChainTips,
+            Response::ChainTips(vec![ChainTip {
+                hash: hash1,
+                height: BlockHeight(1),
+                branch_len: 0,
+                status: ChainTipStatus::Active,
+            }]),
+        ),
+        (
+            Request::BestChainBlockHash {
+                height: BlockHeight(0),
+            },
+            Response::BlockHash(Some(hash0)),
+        ),
+        (
+            Request::BestChainBlockHash {
+                height: BlockHeight(2),
+            },
+            Response::BlockHash(None),
+        ),
+        (
+            Request::AncestorHash {
+                hash: hash1,
+                height: BlockHeight(0),
+            },
+            Response::BlockHash(Some(hash0)),
+        ),
+        (
+            Request::AncestorHash {
+                hash: hash1,
+                height: BlockHeight(1),
+            },
+            Response::BlockHash(Some(hash1)),
+        ),
+        (
+            Request::AncestorHash {
+                hash: hash0,
+                height: BlockHeight(1),
+            },
+            Response::BlockHash(None),
+        ),
+        (
+            Request::FindBlockHashes {
+                known_blocks: vec![hash1, hash0],
+                stop: None,
+            },
+            Response::BlockHashes {
+                fork_height: BlockHeight(1),
+                fork_hash: hash1,
+                hashes: Vec::new(),
+            },
+        ),
+        // Unknown blocks are skipped
+        (
+            Request::FindBlockHashes {
+                known_blocks: vec![BlockHeaderHash([0xff; 32]), hash0],
+                stop: None,
+            },
+            Response::BlockHashes {
+                fork_height: BlockHeight(0),
+                fork_hash: hash0,
+                hashes: vec![hash1],
+            },
+        ),
+        // Peers with no known blocks start from genesis
+        (
+            Request::FindBlockHashes {
+                known_blocks: Vec::new(),
+                stop: Some(hash1),
+            },
+            Response::BlockHashes {
+                fork_height: BlockHeight(0),
+                fork_hash: hash0,
+                hashes: vec![hash1],
+            },
+        ),
+        (
+            Request::FindBlockHashes {
+                known_blocks: vec![hash0],
+                stop: Some(hash0),
+            },
+            Response::BlockHashes {
+                fork_height: BlockHeight(0),
+                fork_hash: hash0,
+                hashes: Vec::new(),
+            },
+        ),
+        (
+            Request::FindBlockHeaders {
+                known_blocks: vec![hash0],
+                stop: None,
+            },
+            Response::BlockHeaders(vec![header1]),
+        ),
+        (
+            Request::FindBlockHeaders {
+                known_blocks: vec![hash1],
+                stop: None,
+            },
+            Response::BlockHeaders(Vec::new()),
+        ),
+        // An empty locator only gets the stop header
+        (
+            Request::FindBlockHeaders {
+                known_blocks: Vec::new(),
+                stop: Some(hash0),
+            },
+            Response::BlockHeaders(vec![header0]),
+        ),
+        (
+            Request::FindBlockHeaders {
+                known_blocks: Vec::new(),
+                stop: None,
+            },
+            Response::BlockHeaders(Vec::new()),
+        ),
+    ]
+});
+
+static DEPTH_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let block1: Arc<_> = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+        .unwrap()
+        .into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+    vec![
+        (
+            Request::GetBlockLocator { genesis: hash0 },
+            Response::BlockLocator {
+                block_locator: vec![hash0],
+            },
+        ),
+        (Request::GetDepth { hash: hash0 }, Response::Depth(None)),
+        (
+            Request::CommitFinalizedBlock {
+                block: verified(block0),
+            },
+            Response::Committed { hash: hash0 },
+        ),
+        (
+            Request::CommitFinalizedBlock {
+                block: verified(block1),
+            },
+            Response::Committed { hash: hash1 },
+        ),
+        (Request::GetDepth { hash: hash0 }, Response::Depth(Some(1))),
+        (Request::GetDepth { hash: hash1 }, Response::Depth(Some(0))),
+        (
+            Request::GetDepth {
+                hash: BlockHeaderHash([0xff; 32]),
+            },
+            Response::Depth(None),
+        ),
+        (
+            Request::GetBlockLocator { genesis: hash0 },
+            Response::BlockLocator {
+                block_locator: vec![hash0, hash0],
+            },
+        ),
+        (
+            Request::HistoryTree {
+                height: BlockHeight(1),
+            },
+            Response::HistoryTree(None),
+        ),
+    ]
+});
+
+static ADD_BLOCK_BATCH_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let block1: Arc<_> = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+        .unwrap()
+        .into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+    vec![
+        (
+            Request::AddBlockBatch {
+                blocks: vec![block0.clone(), block1],
+            },
+            Response::AddedBatch {
+                hashes: vec![hash0, hash1],
+            },
+        ),
+        (Request::GetTip, Response::Tip { hash: hash1 }),
+        (
+            Request::GetBlock { hash: hash0 },
+            Response::Block { block: block0 },
+        ),
+    ]
+});
+
+static ROLLBACK_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let block1: Arc<_> = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+        .unwrap()
+        .into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+    vec![
+        (
+            Request::AddBlockBatch {
+                blocks: vec![block0, block1],
+            },
+            Response::AddedBatch {
+                hashes: vec![hash0, hash1],
+            },
+        ),
+        (
+            Request::RollbackToHeight {
+                height: BlockHeight(0),
+            },
+            Response::RolledBack {
+                removed: vec![hash1],
+            },
+        ),
+        (Request::GetTip, Response::Tip { hash: hash0 }),
+    ]
+});
+
+static INVALIDATE_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let block1: Arc<_> = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+        .unwrap()
+        .into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+    vec![
+        (
+            Request::AddBlockBatch {
+                blocks: vec![block0, block1.clone()],
+            },
+            Response::AddedBatch {
+                hashes: vec![hash0, hash1],
+            },
+        ),
+        (
+            Request::InvalidateBlock { hash: hash1 },
+            Response::Invalidated {
+                removed: vec![hash1],
+            },
+        ),
+        (Request::GetTip, Response::Tip { hash: hash0 }),
+        (
+            Request::ReconsiderBlock { hash: hash1 },
+            Response::Reconsidered,
         ),
         (
-            Request::AddBlock { block: block0 },
-            Response::Added { hash: hash0 },
+            Request::CommitBlock {
+                block: verified(block1),
+            },
+            Response::Committed { hash: hash1 },
         ),
         (Request::GetTip, Response::Tip { hash: hash1 }),
     ]
 });
 
+static FAILED_BLOCK_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let hash = BlockHeaderHash([1; 32]);
+    vec![
+        (
+            Request::FailedBlock {
+                hash,
+                verifier_version: 1,
+            },
+            Response::FailedBlock(None),
+        ),
+        (
+            Request::RecordFailedBlock {
+                hash,
+                verifier_version: 1,
+                rule: "coinbase must be first".to_owned(),
+            },
+            Response::FailedBlockRecorded,
+        ),
+        (
+            Request::FailedBlock {
+                hash,
+                verifier_version: 1,
+            },
+            Response::FailedBlock(Some("coinbase must be first".to_owned())),
+        ),
+        // Failures are forgotten when the verifier changes
+        (
+            Request::FailedBlock {
+                hash,
+                verifier_version: 2,
+            },
+            Response::FailedBlock(None),
+        ),
+    ]
+});
+
+/// Returns a copy of `block` with a different hash, which follows `parent`.
+fn fork(block: &Block, parent: BlockHeaderHash) -> Arc<Block> {
+    let mut block = block.clone();
+    block.header.previous_block_hash = parent;
+    block.header.nonce[0] ^= 0xff;
+    block.into()
+}
+
+static REORG_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let blocks: Vec<Arc<Block>> = [
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_2_BYTES[..],
+        &zebra_test::vectors::BLOCK_MAINNET_3_BYTES[..],
+    ]
+    .iter()
+    .map(|bytes| Block::zcash_deserialize(*bytes).unwrap().into())
+    .collect();
+    let hashes: Vec<BlockHeaderHash> = blocks.iter().map(|block| block.hash()).collect();
+
+    // A side chain that forks after the genesis block
+    let fork1 = fork(&blocks[1], hashes[0]);
+    let fork2 = fork(&blocks[2], fork1.hash());
+    let fork3 = fork(&blocks[3], fork2.hash());
+    let fork_hashes = vec![fork1.hash(), fork2.hash(), fork3.hash()];
+
+    let mut transcript: Vec<_> = blocks[..3]
+        .iter()
+        .chain(&[fork1.clone(), fork2])
+        .map(|block| {
+            (
+                Request::CommitBlock {
+                    block: verified(block.clone()),
+                },
+                Response::Committed { hash: block.hash() },
+            )
+        })
+        .collect();
+    transcript.extend(vec![
+        // The chains have the same work, so the first chain stays the best
+        (Request::GetTip, Response::Tip { hash: hashes[2] }),
+        (
+            Request::GetChainTips,
+            Response::ChainTips(vec![
+                ChainTip {
+                    hash: hashes[2],
+                    height: BlockHeight(2),
+                    branch_len: 0,
+                    status: ChainTipStatus::Active,
+                },
+                ChainTip {
+                    hash: fork_hashes[1],
+                    height: BlockHeight(2),
+                    branch_len: 2,
+                    status: ChainTipStatus::ValidFork,
+                },
+            ]),
+        ),
+        (
+            Request::GetBlock {
+                hash: fork_hashes[0],
+            },
+            Response::Block { block: fork1 },
+        ),
+        // The side chain gets more work, so it becomes the best chain
+        (
+            Request::CommitBlock {
+                block: verified(fork3),
+            },
+            Response::Committed {
+                hash: fork_hashes[2],
+            },
+        ),
+        (
+            Request::GetTip,
+            Response::Tip {
+                hash: fork_hashes[2],
+            },
+        ),
+        (
+            Request::BestChainBlockHash {
+                height: BlockHeight(1),
+            },
+            Response::BlockHash(Some(fork_hashes[0])),
+        ),
+        (
+            Request::GetChainTips,
+            Response::ChainTips(vec![
+                ChainTip {
+                    hash: fork_hashes[2],
+                    height: BlockHeight(3),
+                    branch_len: 0,
+                    status: ChainTipStatus::Active,
+                },
+                ChainTip {
+                    hash: hashes[2],
+                    height: BlockHeight(2),
+                    branch_len: 2,
+                    status: ChainTipStatus::ValidFork,
+                },
+            ]),
+        ),
+        (
+            Request::GetBlock { hash: hashes[1] },
+            Response::Block {
+                block: blocks[1].clone(),
+            },
+        ),
+        // Invalidating the side chain switches back to the original chain
+        (
+            Request::InvalidateBlock {
+                hash: fork_hashes[0],
+            },
+            Response::Invalidated {
+                removed: fork_hashes,
+            },
+        ),
+        (Request::GetTip, Response::Tip { hash: hashes[2] }),
+        (
+            Request::BestChainBlockHash {
+                height: BlockHeight(1),
+            },
+            Response::BlockHash(Some(hashes[1])),
+        ),
+    ]);
+
+    transcript
+});
+
+/// Returns the total value of the transparent outputs in `blocks`.
+fn transparent_output_total(blocks: &[&Block]) -> i64 {
+    blocks
+        .iter()
+        .flat_map(|block| block.transactions.iter())
+        .flat_map(|tx| tx.outputs())
+        .map(|output| i64::from(output.value))
+        .sum()
+}
+
+/// Returns a transaction that spends the first output of `txid`, and pays
+/// `value` to an empty script.
+fn spend(txid: TransactionHash, value: i64) -> Arc<Transaction> {
+    Transaction::V1 {
+        inputs: vec![TransparentInput::PrevOut {
+            outpoint: OutPoint {
+                hash: txid,
+                index: 0,
+            },
+            script: Script(Vec::new()),
+            sequence: u32::MAX,
+        }],
+        outputs: vec![TransparentOutput {
+            value: Amount::try_from(value).unwrap(),
+            pk_script: Script(Vec::new()),
+        }],
+        lock_time: LockTime::Height(BlockHeight(0)),
+        size: Cached::default(),
+    }
+    .into()
+}
+
+static VALUE_BALANCES_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let block1: Arc<_> = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+        .unwrap()
+        .into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+    let header1 = block1.header;
+    let txid1 = TransactionHash::from(block1.transactions[0].as_ref().clone());
+    let coinbase_value1 = i64::from(block1.transactions[0].outputs().next().unwrap().value);
+
+    let balances0 = ValueBalances {
+        transparent: PoolValue::new(
+            Amount::try_from(transparent_output_total(&[&block0])).unwrap(),
+        ),
+        ..ValueBalances::zero()
+    };
+    let balances1 = ValueBalances {
+        transparent: PoolValue::new(
+            Amount::try_from(transparent_output_total(&[&block0, &block1])).unwrap(),
+        ),
+        ..ValueBalances::zero()
+    };
+
+    vec![
+        (Request::GetValueBalances, Response::ValueBalances(None)),
+        (
+            Request::ShieldedCounts { height: None },
+            Response::ShieldedCounts(None),
+        ),
+        (
+            Request::CommitBlock {
+                block: verified(block0),
+            },
+            Response::Committed { hash: hash0 },
+        ),
+        (
+            Request::GetValueBalances,
+            Response::ValueBalances(Some(balances0)),
+        ),
+        (
+            Request::CommitBlock {
+                block: verified(block1),
+            },
+            Response::Committed { hash: hash1 },
+        ),
+        (
+            Request::GetValueBalances,
+            Response::ValueBalances(Some(balances1)),
+        ),
+        // Transaction fees are calculated from the outputs in the state
+        (
+            Request::TransactionFee {
+                transaction: spend(txid1, coinbase_value1 - 1_000),
+            },
+            Response::TransactionFee(Some(Amount::try_from(1_000).unwrap())),
+        ),
+        (
+            Request::TransactionFee {
+                transaction: spend(TransactionHash([0; 32]), 1_000),
+            },
+            Response::TransactionFee(None),
+        ),
+        // The first blocks don't have any shielded outputs
+        (
+            Request::NoteCommitmentTrees {
+                height: BlockHeight(1),
+            },
+            Response::NoteCommitmentTrees(Some(NoteCommitmentTrees::default())),
+        ),
+        (
+            Request::ShieldedCounts { height: None },
+            Response::ShieldedCounts(Some((BlockHeight(1), ShieldedCounts::default()))),
+        ),
+        (
+            Request::ShieldedCounts {
+                height: Some(BlockHeight(0)),
+            },
+            Response::ShieldedCounts(Some((BlockHeight(0), ShieldedCounts::default()))),
+        ),
+        (
+            Request::MissingIndexRanges {
+                index: OptionalIndex::SpentOutputs,
+            },
+            Response::MissingIndexRanges(Vec::new()),
+        ),
+        // History trees start at Heartwood activation
+        (
+            Request::HistoryTree {
+                height: BlockHeight(1),
+            },
+            Response::HistoryTree(None),
+        ),
+        (
+            Request::SaplingSubtrees {
+                start_index: 0,
+                limit: 10,
+            },
+            Response::SaplingSubtrees(Vec::new()),
+        ),
+        // Block 1 only has a coinbase transaction, so its path is empty
+        (
+            Request::TransactionMerklePath { txid: txid1 },
+            Response::TransactionMerklePath(Some((
+                header1,
+                MerklePath {
+                    index: 0,
+                    branch: Vec::new(),
+                },
+            ))),
+        ),
+        (
+            Request::RollbackToHeight {
+                height: BlockHeight(0),
+            },
+            Response::RolledBack {
+                removed: vec![hash1],
+            },
+        ),
+        (
+            Request::GetValueBalances,
+            Response::ValueBalances(Some(balances0)),
+        ),
+        (
+            Request::NoteCommitmentTrees {
+                height: BlockHeight(1),
+            },
+            Response::NoteCommitmentTrees(None),
+        ),
+        (
+            Request::ShieldedCounts {
+                height: Some(BlockHeight(1)),
+            },
+            Response::ShieldedCounts(None),
+        ),
+        (
+            Request::TransactionMerklePath { txid: txid1 },
+            Response::TransactionMerklePath(None),
+        ),
+    ]
+});
+
+/// Returns the address paid by `script`, if it is a P2PKH or P2SH script.
+fn script_address(script: &[u8]) -> Option<TransparentAddress> {
+    let mut hash = [0; 20];
+    match script {
+        [0x76, 0xa9, 20, .., 0x88, 0xac] if script.len() == 25 => {
+            hash.copy_from_slice(&script[3..23]);
+            Some(TransparentAddress::PayToPublicKeyHash {
+                network: Mainnet,
+                pub_key_hash: hash,
+            })
+        }
+        [0xa9, 20, .., 0x87] if script.len() == 23 => {
+            hash.copy_from_slice(&script[2..22]);
+            Some(TransparentAddress::PayToScriptHash {
+                network: Mainnet,
+                script_hash: hash,
+            })
+        }
+        _ => None,
+    }
+}
+
+static ADDRESS_BALANCE_TRANSCRIPT: Lazy<Vec<(Request, Response)>> = Lazy::new(|| {
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])
+            .unwrap()
+            .into();
+    let block1: Arc<_> = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+        .unwrap()
+        .into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+
+    // Block 1 pays the founders' reward to a P2SH address
+    let coinbase = &block1.transactions[0];
+    let coinbase_hash = TransactionHash::from(coinbase.clone());
+    let utxos: Vec<_> = coinbase
+        .outputs()
+        .enumerate()
+        .filter_map(|(index, output)| {
+            script_address(&output.pk_script.0).map(|address| AddressUtxo {
+                address,
+                outpoint: OutPoint {
+                    hash: coinbase_hash,
+                    index: index as u32,
+                },
+                value: output.value,
+                script: output.pk_script.clone(),
+                height: BlockHeight(1),
+            })
+        })
+        .collect();
+    assert!(!utxos.is_empty());
+    let addresses: Vec<_> = utxos.iter().map(|utxo| utxo.address).collect();
+
+    let zero = Amount::try_from(0).unwrap();
+    let received = utxos.iter().map(|utxo| i64::from(utxo.value)).sum::<i64>();
+    let received = Amount::try_from(received).unwrap();
+
+    vec![
+        (
+            Request::CommitBlock {
+                block: verified(block0),
+            },
+            Response::Committed { hash: hash0 },
+        ),
+        (
+            Request::CommitBlock {
+                block: verified(block1),
+            },
+            Response::Committed { hash: hash1 },
+        ),
+        (
+            Request::AddressBalance {
+                addresses: addresses.clone(),
+            },
+            Response::AddressBalance(AddressBalance {
+                balance: received,
+                received,
+            }),
+        ),
+        (
+            Request::UtxosByAddresses {
+                addresses: addresses.clone(),
+                start: BlockHeight(0),
+                limit: 100,
+            },
+            Response::Utxos(utxos.clone()),
+        ),
+        (
+            Request::UtxosByAddresses {
+                addresses: addresses.clone(),
+                start: BlockHeight(2),
+                limit: 100,
+            },
+            Response::Utxos(Vec::new()),
+        ),
+        (
+            Request::UtxosByAddresses {
+                addresses: addresses.clone(),
+                start: BlockHeight(0),
+                limit: 1,
+            },
+            Response::Utxos(utxos[..1].to_vec()),
+        ),
+        (
+            Request::TransactionIdsByAddresses {
+                addresses: addresses.clone(),
+                height_range: BlockHeight(0)..=BlockHeight(1),
+            },
+            Response::TransactionIds(vec![coinbase_hash]),
+        ),
+        (
+            Request::TransactionIdsByAddresses {
+                addresses: addresses.clone(),
+                height_range: BlockHeight(2)..=BlockHeight(10),
+            },
+            Response::TransactionIds(Vec::new()),
+        ),
+        (
+            Request::RollbackToHeight {
+                height: BlockHeight(0),
+            },
+            Response::RolledBack {
+                removed: vec![hash1],
+            },
+        ),
+        (
+            Request::AddressBalance {
+                addresses: addresses.clone(),
+            },
+            Response::AddressBalance(AddressBalance {
+                balance: zero,
+                received: zero,
+            }),
+        ),
+        (
+            Request::UtxosByAddresses {
+                addresses: addresses.clone(),
+                start: BlockHeight(0),
+                limit: 100,
+            },
+            Response::Utxos(Vec::new()),
+        ),
+        (
+            Request::TransactionIdsByAddresses {
+                addresses,
+                height_range: BlockHeight(0)..=BlockHeight(1),
+            },
+            Response::TransactionIds(Vec::new()),
+        ),
+    ]
+});
+
 #[tokio::test]
 async fn check_transcripts_mainnet() -> Result<(), Report> {
     check_transcripts(Mainnet).await
@@ -59,20 +932,562 @@ async fn check_transcripts_testnet() -> Result<(), Report> {
     check_transcripts(Testnet).await
 }
 
+#[tokio::test]
+async fn commit_block_queues_children() -> Result<(), Report> {
+    zebra_test::init();
+
+    check_queued_children(in_memory::init()).await?;
+
+    let storage_guard = TempDir::new("")?;
+    let service = on_disk::init(
+        Config {
+            cache_dir: Some(storage_guard.path().to_owned()),
+            ..Config::default()
+        },
+        Mainnet,
+    );
+    check_queued_children(service).await?;
+
+    Ok(())
+}
+
+/// Check that `service` queues a block until its parent is committed.
+async fn check_queued_children<S>(mut service: S) -> Result<(), Report>
+where
+    S: Service<Request, Response = Response, Error = Box<dyn std::error::Error + Send + Sync>>,
+{
+    let block0 = SemanticallyVerifiedBlock::from_bytes(
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+    )
+    .map_err(|e| eyre!(e))?;
+    let block1 =
+        SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+            .map_err(|e| eyre!(e))?;
+    let (hash0, hash1) = (block0.hash(), block1.hash());
+
+    let child = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block1 });
+    let parent = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block0 });
+
+    assert_eq!(
+        parent.await.map_err(|e| eyre!(e))?,
+        Response::Committed { hash: hash0 }
+    );
+    assert_eq!(
+        child.await.map_err(|e| eyre!(e))?,
+        Response::Committed { hash: hash1 }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_state_service_reads_committed_blocks() -> Result<(), Report> {
+    zebra_test::init();
+
+    let storage_guard = TempDir::new("")?;
+    let (mut service, mut read_service) = on_disk::init_with_read_service(
+        Config {
+            cache_dir: Some(storage_guard.path().to_owned()),
+            ..Config::default()
+        },
+        Mainnet,
+    );
+
+    let block0 = SemanticallyVerifiedBlock::from_bytes(
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+    )
+    .map_err(|e| eyre!(e))?;
+    let hash0 = block0.hash();
+
+    let response = read_service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Empty);
+
+    let response = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block0 })
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Committed { hash: hash0 });
+
+    let response = read_service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Tip { hash: hash0 });
+
+    let response = read_service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::BestChainBlockHash {
+            height: BlockHeight(0),
+        })
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::BlockHash(Some(hash0)));
+
+    let result = read_service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::InvalidateBlock { hash: hash0 })
+        .await;
+    assert!(result.is_err(), "read service must not modify the state");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_only_state_reads_a_running_state() -> Result<(), Report> {
+    zebra_test::init();
+
+    let storage_guard = TempDir::new("")?;
+    let config = Config {
+        cache_dir: Some(storage_guard.path().to_owned()),
+        storage_backend: StorageBackendKind::RocksDb,
+        ..Config::default()
+    };
+    let mut service = on_disk::init(config.clone(), Mainnet);
+
+    let block0 = SemanticallyVerifiedBlock::from_bytes(
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+    )
+    .map_err(|e| eyre!(e))?;
+    let hash0 = block0.hash();
+    service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block0 })
+        .await
+        .map_err(|e| eyre!(e))?;
+
+    // The writing service still holds the state lock
+    let mut read_only = on_disk::init_read_only(config, Mainnet);
+    let response = read_only
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Tip { hash: hash0 });
+
+    for request in vec![
+        Request::InvalidateBlock { hash: hash0 },
+        Request::RollbackToHeight {
+            height: BlockHeight(0),
+        },
+        Request::CompactNow,
+    ] {
+        let error = read_only
+            .ready_and()
+            .await
+            .map_err(|e| eyre!(e))?
+            .call(request.clone())
+            .await
+            .expect_err("read-only states reject changes");
+        assert!(
+            ReadOnlyState::from_error(error.as_ref()).is_some(),
+            "unexpected error for {:?}: {}",
+            request,
+            error
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn secondary_state_catches_up() -> Result<(), Report> {
+    zebra_test::init();
+
+    let storage_guard = TempDir::new("")?;
+    let secondary_guard = TempDir::new("")?;
+    let config = Config {
+        cache_dir: Some(storage_guard.path().to_owned()),
+        storage_backend: StorageBackendKind::RocksDb,
+        ..Config::default()
+    };
+    let mut service = on_disk::init(config.clone(), Mainnet);
+
+    let block0 = SemanticallyVerifiedBlock::from_bytes(
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+    )
+    .map_err(|e| eyre!(e))?;
+    let block1 =
+        SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+            .map_err(|e| eyre!(e))?;
+    let (hash0, hash1) = (block0.hash(), block1.hash());
+    service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block0 })
+        .await
+        .map_err(|e| eyre!(e))?;
+
+    let mut secondary = on_disk::init_secondary(config, Mainnet, secondary_guard.path().to_owned());
+    let response = secondary
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Tip { hash: hash0 });
+
+    service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block1 })
+        .await
+        .map_err(|e| eyre!(e))?;
+
+    // New blocks are only visible after catching up
+    let response = secondary
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Tip { hash: hash0 });
+
+    secondary.catch_up().map_err(|e| eyre!(e))?;
+    let response = secondary
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(response, Response::Tip { hash: hash1 });
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn second_genesis_block_is_rejected() -> Result<(), Report> {
+    zebra_test::init();
+
+    check_second_genesis(in_memory::init()).await?;
+
+    let storage_guard = TempDir::new("")?;
+    let service = on_disk::init(
+        Config {
+            cache_dir: Some(storage_guard.path().to_owned()),
+            ..Config::default()
+        },
+        Mainnet,
+    );
+    check_second_genesis(service).await?;
+
+    Ok(())
+}
+
+/// Check that `service` rejects a genesis block, once it has a different
+/// genesis block.
+async fn check_second_genesis<S>(mut service: S) -> Result<(), Report>
+where
+    S: Service<Request, Response = Response, Error = Box<dyn std::error::Error + Send + Sync>>,
+{
+    let block0 = Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?;
+    let mut other0 = block0.clone();
+    other0.header.nonce[0] ^= 0xff;
+
+    service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock {
+            block: verified(block0.into()),
+        })
+        .await
+        .map_err(|e| eyre!(e))?;
+
+    let commit = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock {
+            block: verified(other0.clone().into()),
+        })
+        .await;
+    assert!(commit.is_err());
+
+    let commit_finalized = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitFinalizedBlock {
+            block: verified(other0.into()),
+        })
+        .await;
+    assert!(commit_finalized.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn deep_reorgs_are_rejected() -> Result<(), Report> {
+    zebra_test::init();
+
+    check_deep_reorgs(in_memory::init()).await?;
+
+    let storage_guard = TempDir::new("")?;
+    let service = on_disk::init(
+        Config {
+            cache_dir: Some(storage_guard.path().to_owned()),
+            ..Config::default()
+        },
+        Mainnet,
+    );
+    check_deep_reorgs(service).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_memory_state_evicts_finalized_block_bodies() -> Result<(), Report> {
+    zebra_test::init();
+
+    let block0 = SemanticallyVerifiedBlock::from_bytes(
+        &zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..],
+    )
+    .map_err(|e| eyre!(e))?;
+    let block1 =
+        SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+            .map_err(|e| eyre!(e))?;
+    let tip =
+        SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..])
+            .map_err(|e| eyre!(e))?;
+    let (hash0, hash1, tip_hash) = (block0.hash(), block1.hash(), tip.hash());
+
+    // Only the non-finalized tip fits in memory
+    let mut service = in_memory::init_with_config(
+        &Config {
+            in_memory_block_bytes: tip.bytes().len() as u64,
+            ..Config::default()
+        },
+        Mainnet,
+    );
+    for block in vec![block0, block1, tip] {
+        service
+            .ready_and()
+            .await
+            .map_err(|e| eyre!(e))?
+            .call(Request::CommitFinalizedBlock { block })
+            .await
+            .map_err(|e| eyre!(e))?;
+    }
+
+    // Evicted blocks can't be read, but are still in the best chain
+    for hash in &[hash0, hash1] {
+        let read = service
+            .ready_and()
+            .await
+            .map_err(|e| eyre!(e))?
+            .call(Request::GetBlock { hash: *hash })
+            .await;
+        assert!(read.is_err());
+    }
+    let depth = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetDepth { hash: hash1 })
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(depth, Response::Depth(Some(415_000 - 1)));
+    let hash = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::BestChainBlockHash {
+            height: BlockHeight(0),
+        })
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(hash, Response::BlockHash(Some(hash0)));
+
+    // Non-finalized blocks are kept
+    let read = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetBlock { hash: tip_hash })
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert!(matches!(read, Response::Block { .. }));
+
+    // Finalized blocks with evicted bodies can't be invalidated
+    let invalidate = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::InvalidateBlock { hash: hash1 })
+        .await;
+    assert!(invalidate.is_err());
+
+    Ok(())
+}
+
+/// Check that `service` rejects commits and rollbacks below the finalized
+/// height.
+async fn check_deep_reorgs<S>(mut service: S) -> Result<(), Report>
+where
+    S: Service<Request, Response = Response, Error = Box<dyn std::error::Error + Send + Sync>>,
+{
+    let tip =
+        SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_415000_BYTES[..])
+            .map_err(|e| eyre!(e))?;
+    let block1 =
+        SemanticallyVerifiedBlock::from_bytes(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+            .map_err(|e| eyre!(e))?;
+
+    service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitFinalizedBlock { block: tip })
+        .await
+        .map_err(|e| eyre!(e))?;
+
+    let commit = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::CommitBlock { block: block1 })
+        .await;
+    assert!(commit.is_err());
+
+    let rollback = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::RollbackToHeight {
+            height: BlockHeight(1),
+        })
+        .await;
+    assert!(rollback.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn batches_that_dont_extend_the_tip_are_rejected() -> Result<(), Report> {
+    zebra_test::init();
+
+    let block0: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES[..])?.into();
+    let block1: Arc<_> =
+        Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])?.into();
+    let hash0 = block0.as_ref().into();
+    let hash1 = block1.as_ref().into();
+
+    let storage_guard = TempDir::new("")?;
+    let mut service = on_disk::init(
+        Config {
+            cache_dir: Some(storage_guard.path().to_owned()),
+            ..Config::default()
+        },
+        Mainnet,
+    );
+
+    // An empty state needs a genesis block
+    assert!(add_batch(&mut service, vec![block1.clone()]).await.is_err());
+
+    add_batch(&mut service, vec![block0.clone(), block1.clone()]).await?;
+
+    // Stored blocks can't be added again
+    assert!(add_batch(&mut service, vec![block0]).await.is_err());
+
+    // Batches can't replace the best chain tip
+    assert!(add_batch(&mut service, vec![fork(&block1, hash0)])
+        .await
+        .is_err());
+
+    let tip = service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::GetTip)
+        .await
+        .map_err(|e| eyre!(e))?;
+    assert_eq!(tip, Response::Tip { hash: hash1 });
+
+    Ok(())
+}
+
+/// Send an `AddBlockBatch` request for `blocks` to `service`.
+async fn add_batch<S>(service: &mut S, blocks: Vec<Arc<Block>>) -> Result<Response, Report>
+where
+    S: Service<Request, Response = Response, Error = Box<dyn std::error::Error + Send + Sync>>,
+{
+    service
+        .ready_and()
+        .await
+        .map_err(|e| eyre!(e))?
+        .call(Request::AddBlockBatch { blocks })
+        .await
+        .map_err(|e| eyre!(e))
+}
+
 #[spandoc::spandoc]
 async fn check_transcripts(network: Network) -> Result<(), Report> {
     zebra_test::init();
 
-    for transcript_data in &[&ADD_BLOCK_TRANSCRIPT, &GET_TIP_TRANSCRIPT] {
+    for transcript_data in &[
+        &COMMIT_FINALIZED_BLOCK_TRANSCRIPT,
+        &COMMIT_BLOCK_TRANSCRIPT,
+        &GENESIS_TRANSCRIPT,
+        &GET_TIP_TRANSCRIPT,
+        &DEPTH_TRANSCRIPT,
+        &ADD_BLOCK_BATCH_TRANSCRIPT,
+        &ROLLBACK_TRANSCRIPT,
+        &INVALIDATE_TRANSCRIPT,
+        &FAILED_BLOCK_TRANSCRIPT,
+        &REORG_TRANSCRIPT,
+        &VALUE_BALANCES_TRANSCRIPT,
+        &ADDRESS_BALANCE_TRANSCRIPT,
+    ] {
         let service = in_memory::init();
         let transcript = Transcript::from(transcript_data.iter().cloned());
         /// SPANDOC: check the in memory service against the transcript
         transcript.check(service).await?;
 
+        let service = in_memory::init_with_config(&Config::default(), network);
+        let transcript = Transcript::from(transcript_data.iter().cloned());
+        /// SPANDOC: check the in memory service for the network against the transcript
+        transcript.check(service).await?;
+
         let storage_guard = TempDir::new("")?;
         let service = on_disk::init(
             Config {
                 cache_dir: Some(storage_guard.path().to_owned()),
+                address_index: true,
+                ..Config::default()
             },
             network,
         );
@@ -81,6 +1496,19 @@ async fn check_transcripts(network: Network) -> Result<(), Report> {
         transcript.check(service).await?;
         // Delete the contents of the temp directory before going to the next case.
         std::mem::drop(storage_guard);
+
+        let service = on_disk::init(
+            Config {
+                cache_dir: None,
+                address_index: true,
+                storage_backend: StorageBackendKind::Memory,
+                ..Config::default()
+            },
+            network,
+        );
+        let transcript = Transcript::from(transcript_data.iter().cloned());
+        /// SPANDOC: check the on disk service with the memory backend against the transcript
+        transcript.check(service).await?;
     }
 
     Ok(())
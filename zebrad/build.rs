@@ -0,0 +1,58 @@
+//! Embeds the build provenance in `zebrad`, see `src/release.rs`.
+//!
+//! Reproducible builds, and builds from source archives without a git
+//! repository, can set `ZEBRAD_GIT_COMMIT` and `ZEBRAD_GIT_DIRTY` in the
+//! environment, so the build doesn't depend on the local checkout.
+
+use std::{env, process::Command};
+
+/// Returns the trimmed output of `git args`, or `None` if git fails.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|output| output.trim().to_owned())
+}
+
+fn main() {
+    let commit = env::var("ZEBRAD_GIT_COMMIT")
+        .ok()
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_default();
+    let dirty = env::var("ZEBRAD_GIT_DIRTY").ok().or_else(|| {
+        git(&["status", "--porcelain", "--untracked-files=no"])
+            .map(|status| (!status.is_empty()).to_string())
+    });
+
+    // Cargo sets `CARGO_FEATURE_<NAME>` for each enabled feature
+    let mut features: Vec<_> = env::vars()
+        .filter(|(key, _)| key.starts_with("CARGO_FEATURE_"))
+        .map(|(key, _)| {
+            key["CARGO_FEATURE_".len()..]
+                .to_lowercase()
+                .replace('_', "-")
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=ZEBRAD_GIT_COMMIT={}", commit);
+    println!(
+        "cargo:rustc-env=ZEBRAD_GIT_DIRTY={}",
+        dirty.unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=ZEBRAD_BUILD_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+    println!("cargo:rustc-env=ZEBRAD_FEATURES={}", features.join(","));
+
+    println!("cargo:rerun-if-env-changed=ZEBRAD_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=ZEBRAD_GIT_DIRTY");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-changed=src");
+}
@@ -1,6 +1,10 @@
 //! Zebrad Abscissa Application
 
-use crate::{commands::ZebradCmd, config::ZebradConfig};
+use crate::{
+    commands::ZebradCmd,
+    components::{diagnostics::RecentEvents, sampling::SpanSampler},
+    config::ZebradConfig,
+};
 use abscissa_core::{
     application::{self, AppCell},
     config,
@@ -39,6 +43,12 @@ pub struct ZebradApp {
 
     /// Application state.
     state: application::State<Self>,
+
+    /// The most recent tracing events, for diagnostics dumps.
+    recent_events: RecentEvents,
+
+    /// Samples high-frequency spans, once the config is loaded.
+    span_sampler: SpanSampler,
 }
 
 /// Initialize a new application instance.
@@ -50,6 +60,8 @@ impl Default for ZebradApp {
         Self {
             config: None,
             state: application::State::default(),
+            recent_events: RecentEvents::default(),
+            span_sampler: SpanSampler::default(),
         }
     }
 }
@@ -135,12 +147,28 @@ impl Application for ZebradApp {
         self.config = Some(config);
 
         if ZebradApp::command_is_server(&command) {
+            crate::components::supervisor::install_panic_policy();
+
             let level = self.level(command);
             self.state
                 .components
                 .get_downcast_mut::<Tracing>()
                 .expect("Tracing component should be available")
                 .reload_filter(level);
+            self.span_sampler.configure(
+                &self
+                    .config
+                    .as_ref()
+                    .expect("config was set to Some earlier in this function")
+                    .tracing
+                    .sampling,
+            );
+
+            // Tracing is only enabled for server commands
+            self.config
+                .as_ref()
+                .expect("config was set to Some earlier in this function")
+                .log_migrations();
 
             // Work around some issues with dependency injection and configs
             let config = self
@@ -165,6 +193,10 @@ impl Application for ZebradApp {
                 .get_downcast_ref::<MetricsEndpoint>()
                 .expect("Metrics endpoint should be available")
                 .open_endpoint(&config.metrics, tokio_component);
+        } else if let Some(config) = &self.config {
+            for migration in &config.migrations {
+                eprintln!("Warning: migrated config: {}", migration);
+            }
         }
 
         Ok(())
@@ -172,6 +204,11 @@ impl Application for ZebradApp {
 }
 
 impl ZebradApp {
+    /// Returns the most recent tracing events.
+    pub fn recent_events(&self) -> RecentEvents {
+        self.recent_events.clone()
+    }
+
     fn level(&self, command: &EntryPoint<ZebradCmd>) -> String {
         // `None` outputs zebrad usage information to stdout
         let command_uses_stdout = match &command.command {
@@ -200,7 +237,7 @@ impl ZebradApp {
             tracing:
                 crate::config::TracingSection {
                     filter: Some(filter),
-                    endpoint_addr: _,
+                    ..
                 },
             ..
         }) = &self.config
@@ -223,6 +260,8 @@ impl ZebradApp {
         builder
             .finish()
             .with(tracing_error::ErrorLayer::default())
+            .with(self.recent_events.clone())
+            .with(self.span_sampler.clone())
             .init();
 
         filter_handle.into()
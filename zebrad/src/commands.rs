@@ -1,16 +1,28 @@
 //! Zebrad Subcommands
 
+mod backup_state;
+mod bench_replay;
+mod checkpoint_bundle;
 mod connect;
+mod database;
+mod export;
+mod export_analytics;
+mod export_state;
 mod generate;
+mod import_state;
 mod revhex;
 mod seed;
 mod start;
+mod verify_state;
 mod version;
 
 use self::ZebradCmd::*;
 use self::{
-    connect::ConnectCmd, generate::GenerateCmd, revhex::RevhexCmd, seed::SeedCmd, start::StartCmd,
-    version::VersionCmd,
+    backup_state::BackupStateCmd, bench_replay::BenchReplayCmd,
+    checkpoint_bundle::CheckpointBundleCmd, connect::ConnectCmd, database::DatabaseCmd,
+    export::ExportCmd, export_analytics::ExportAnalyticsCmd, export_state::ExportStateCmd,
+    generate::GenerateCmd, import_state::ImportStateCmd, revhex::RevhexCmd, seed::SeedCmd,
+    start::StartCmd, verify_state::VerifyStateCmd, version::VersionCmd,
 };
 
 use crate::config::ZebradConfig;
@@ -26,18 +38,52 @@ pub const CONFIG_FILE: &str = "zebrad.toml";
 /// Zebrad Subcommands
 #[derive(Command, Debug, Options, Runnable)]
 pub enum ZebradCmd {
+    /// The `backup-state` subcommand
+    #[options(
+        help = "back up the state of a stopped node; send SIGUSR2 to back up a running node"
+    )]
+    BackupState(BackupStateCmd),
+
+    /// The `bench-replay` subcommand
+    #[options(help = "replay recorded state requests against the local state, for benchmarks")]
+    BenchReplay(BenchReplayCmd),
+
     /// The `generate` subcommand
     #[options(help = "generate a skeleton configuration")]
     Generate(GenerateCmd),
 
+    /// The `checkpoint-bundle` subcommand
+    #[options(help = "export signed header checkpoints from the local state, for light clients")]
+    CheckpointBundle(CheckpointBundleCmd),
+
     /// The `connect` subcommand
     #[options(help = "testing stub for dumping network messages")]
     Connect(ConnectCmd),
 
+    /// The `database` subcommand
+    #[options(help = "describe the state database: `database schema [--markdown]`")]
+    Database(DatabaseCmd),
+
+    /// The `export` subcommand
+    #[options(help = "export compact blocks and subtree roots from the local state, for wallets")]
+    Export(ExportCmd),
+
+    /// The `export-analytics` subcommand
+    #[options(help = "export normalized chain tables from the local state, for data analysis")]
+    ExportAnalytics(ExportAnalyticsCmd),
+
+    /// The `export-state` subcommand
+    #[options(help = "export a snapshot of the finalized state, for seeding new nodes")]
+    ExportState(ExportStateCmd),
+
     /// The `help` subcommand
     #[options(help = "get usage information")]
     Help(Help<Self>),
 
+    /// The `import-state` subcommand
+    #[options(help = "restore the state from a snapshot written by export-state")]
+    ImportState(ImportStateCmd),
+
     /// The `revhex` subcommand
     #[options(help = "reverses the endianness of a hex string, like a block or transaction hash")]
     Revhex(RevhexCmd),
@@ -50,6 +96,10 @@ pub enum ZebradCmd {
     #[options(help = "start the application")]
     Start(StartCmd),
 
+    /// The `verify-state` subcommand
+    #[options(help = "check the integrity of the state of a stopped node")]
+    VerifyState(VerifyStateCmd),
+
     /// The `version` subcommand
     #[options(help = "display version information")]
     Version(VersionCmd),
@@ -66,8 +116,10 @@ impl ZebradCmd {
     pub(crate) fn uses_stdout(&self) -> bool {
         match self {
             // List all the commands, so new commands have to make a choice here
-            Generate(_) | Help(_) | Revhex(_) | Version(_) => true,
-            Connect(_) | Seed(_) | Start(_) => false,
+            BenchReplay(_) | CheckpointBundle(_) | Database(_) | Export(_) | Generate(_)
+            | Help(_) | Revhex(_) | Version(_) => true,
+            BackupState(_) | Connect(_) | ExportAnalytics(_) | ExportState(_) | ImportState(_)
+            | Seed(_) | Start(_) | VerifyState(_) => false,
         }
     }
 
@@ -82,7 +134,9 @@ impl ZebradCmd {
         match self {
             // List all the commands, so new commands have to make a choice here
             Connect(_) | Seed(_) | Start(_) => true,
-            Generate(_) | Help(_) | Revhex(_) | Version(_) => false,
+            BackupState(_) | BenchReplay(_) | CheckpointBundle(_) | Database(_) | Export(_)
+            | ExportAnalytics(_) | ExportState(_) | Generate(_) | Help(_) | ImportState(_)
+            | Revhex(_) | VerifyState(_) | Version(_) => false,
         }
     }
 }
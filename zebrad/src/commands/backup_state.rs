@@ -0,0 +1,45 @@
+//! `backup-state` subcommand - backs up the state of a stopped node.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use std::path::Path;
+
+use zebra_state::on_disk::backup_state;
+
+/// `backup-state` subcommand
+///
+/// The state is opened by this command, so the node must be stopped. To back
+/// up a running node, send `SIGUSR2` to `zebrad start`.
+#[derive(Command, Debug, Default, Options)]
+pub struct BackupStateCmd {
+    /// The directory to write the backup to.
+    #[options(free)]
+    path: String,
+}
+
+impl BackupStateCmd {
+    fn backup(&self) -> Result<(), Report> {
+        if self.path.is_empty() {
+            return Err(eyre!("missing backup directory path"));
+        }
+
+        let config = app_config();
+        backup_state(&config.state, config.network.network, Path::new(&self.path))
+            .map_err(|e| eyre!(e))
+    }
+}
+
+impl Runnable for BackupStateCmd {
+    /// Back up the local state.
+    fn run(&self) {
+        match self.backup() {
+            Ok(()) => eprintln!("Backed up the state to {}", Path::new(&self.path).display()),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! `bench-replay` subcommand - replays recorded state requests, for
+//! benchmarks.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+
+use zebra_state::recording::{RecordedRequest, RecordingReader, RequestKind};
+
+/// `bench-replay` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct BenchReplayCmd {
+    /// The state request recording to replay.
+    #[options(free)]
+    recording: String,
+
+    /// Wait between requests, using the recorded timing.
+    #[options(help = "replay requests using the recorded timing (default: as fast as possible)")]
+    realtime: bool,
+}
+
+/// The replay latencies for a single request type.
+#[derive(Debug, Default)]
+struct KindStats {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+impl KindStats {
+    /// Returns the latency at `quantile`, which must be between 0 and 1.
+    ///
+    /// `latencies` must be sorted.
+    fn quantile(&self, quantile: f64) -> Duration {
+        let index = ((self.latencies.len() - 1) as f64 * quantile).round() as usize;
+        self.latencies[index]
+    }
+
+    fn mean(&self) -> Duration {
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+}
+
+/// The results of a replay.
+#[derive(Debug, Default)]
+struct ReplayReport {
+    stats: BTreeMap<RequestKind, KindStats>,
+    skipped: usize,
+    elapsed: Duration,
+}
+
+impl BenchReplayCmd {
+    /// Replay the read requests in the recording against the configured
+    /// state.
+    ///
+    /// Requests that modify the state are skipped, so replays can be
+    /// repeated against the same state copy.
+    async fn replay(&self) -> Result<ReplayReport, Report> {
+        let config = app_config();
+        let (_, read_state) = zebra_state::on_disk::init_with_read_service(
+            config.state.clone(),
+            config.network.network,
+        );
+
+        let file = BufReader::new(File::open(&self.recording)?);
+        let entries = RecordingReader::new(file).map_err(|e| eyre!(e))?;
+
+        let mut report = ReplayReport::default();
+        let start = Instant::now();
+        for entry in entries {
+            let entry = entry.map_err(|e| eyre!(e))?;
+            let request = match entry.request {
+                RecordedRequest::Request(request)
+                    if !RequestKind::of(&request).modifies_state() =>
+                {
+                    request
+                }
+                _ => {
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+
+            if self.realtime {
+                let target = start + entry.offset;
+                tokio::time::delay_for(target.saturating_duration_since(Instant::now())).await;
+            }
+
+            let kind = RequestKind::of(&request);
+            let sent = Instant::now();
+            let result = read_state.clone().oneshot(request).await;
+            let stats = report.stats.entry(kind).or_default();
+            stats.latencies.push(sent.elapsed());
+            if result.is_err() {
+                stats.errors += 1;
+            }
+        }
+        report.elapsed = start.elapsed();
+
+        for stats in report.stats.values_mut() {
+            stats.latencies.sort_unstable();
+        }
+
+        Ok(report)
+    }
+}
+
+impl ReplayReport {
+    /// Print the per-request latency table, and the replay totals.
+    fn print(&self) {
+        println!(
+            "{:<26} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "request", "count", "errors", "mean_us", "p50_us", "p99_us", "max_us"
+        );
+        for (kind, stats) in &self.stats {
+            println!(
+                "{:<26} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+                kind.name(),
+                stats.latencies.len(),
+                stats.errors,
+                stats.mean().as_micros(),
+                stats.quantile(0.5).as_micros(),
+                stats.quantile(0.99).as_micros(),
+                stats.quantile(1.0).as_micros(),
+            );
+        }
+
+        let replayed: usize = self.stats.values().map(|stats| stats.latencies.len()).sum();
+        println!(
+            "Replayed {} requests in {:.3}s, skipped {} requests that modify the state",
+            replayed,
+            self.elapsed.as_secs_f64(),
+            self.skipped,
+        );
+    }
+}
+
+impl Runnable for BenchReplayCmd {
+    /// Replay a state request recording, and print the request latencies.
+    fn run(&self) {
+        let mut rt = Runtime::new().expect("runtime should be created");
+
+        match rt.block_on(self.replay()) {
+            Ok(report) => report.print(),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
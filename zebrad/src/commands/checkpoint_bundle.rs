@@ -0,0 +1,131 @@
+//! `checkpoint-bundle` subcommand - exports signed header checkpoints for
+//! light clients.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use rand::Rng;
+use std::{
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+use tokio::runtime::Runtime;
+
+use zebra_chain::{ed25519_zebra::SigningKey, serialization::ZcashSerialize};
+use zebra_state::checkpoint_bundle::{build_checkpoint_bundle, SignedCheckpointBundle};
+
+/// The default height interval between checkpoints.
+const DEFAULT_INTERVAL: u32 = 10_000;
+
+/// `checkpoint-bundle` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct CheckpointBundleCmd {
+    /// The height interval between checkpoints.
+    #[options(help = "the height interval between checkpoints (default: 10000)")]
+    interval: Option<u32>,
+
+    /// The file containing the node's signing key.
+    #[options(
+        required,
+        help = "the file containing the 32-byte node signing key (created if missing)"
+    )]
+    key_file: String,
+
+    /// The file to write the bundle to.
+    #[options(help = "the file to write the signed bundle to (stdout if unspecified)")]
+    output_file: Option<String>,
+}
+
+impl CheckpointBundleCmd {
+    /// Load the signing key from `key_file`, or generate and save a new key
+    /// if the file does not exist.
+    fn signing_key(&self) -> Result<SigningKey, Report> {
+        let path = Path::new(&self.key_file);
+
+        let seed = if path.exists() {
+            let bytes = fs::read(path)?;
+            <[u8; 32]>::try_from(&bytes[..])
+                .map_err(|_| eyre!("signing key file must contain exactly 32 bytes"))?
+        } else {
+            let mut seed = [0u8; 32];
+            rand::thread_rng().fill(&mut seed);
+            // Fail if another process created the file since we checked
+            key_file_options().open(path)?.write_all(&seed)?;
+            eprintln!("Generated a new signing key in {}", self.key_file);
+            seed
+        };
+
+        Ok(SigningKey::from(seed))
+    }
+
+    async fn bundle(&self) -> Result<SignedCheckpointBundle, Report> {
+        let config = app_config();
+        let network = config.network.network;
+        let state = zebra_state::on_disk::init(config.state.clone(), network);
+
+        let interval = self.interval.unwrap_or(DEFAULT_INTERVAL);
+        let bundle = build_checkpoint_bundle(state, network, interval)
+            .await
+            .map_err(|e| eyre!(e))?;
+
+        Ok(bundle.sign(&self.signing_key()?))
+    }
+
+    fn write(&self, bundle: &SignedCheckpointBundle) -> Result<(), Report> {
+        match self.output_file {
+            Some(ref output_file) => {
+                let mut file = io::BufWriter::new(File::create(output_file)?);
+                bundle.zcash_serialize(&mut file)?;
+                file.flush()?;
+            }
+            None => {
+                let stdout = io::stdout();
+                let mut stdout = io::BufWriter::new(stdout.lock());
+                bundle.zcash_serialize(&mut stdout)?;
+                stdout.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the options for creating a new signing key file.
+///
+/// On Unix, the file is only readable and writable by its owner.
+fn key_file_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+impl Runnable for CheckpointBundleCmd {
+    /// Write a signed checkpoint bundle for the best chain in the local state.
+    fn run(&self) {
+        let mut rt = Runtime::new().expect("runtime should be created");
+
+        let result = rt
+            .block_on(self.bundle())
+            .and_then(|bundle| self.write(&bundle).map(|()| bundle));
+
+        match result {
+            Ok(bundle) => eprintln!(
+                "Exported {} checkpoints, signed by {}",
+                bundle.bundle.checkpoints.len(),
+                hex::encode(<[u8; 32]>::from(bundle.verification_key)),
+            ),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -195,11 +195,15 @@ where
                     for block in blocks {
                         self.downloaded_block_heights
                             .insert(block.coinbase_height().unwrap());
+                        // Blocks are downloaded out of order, so they can't
+                        // be contextually checked
+                        let block = zebra_state::SemanticallyVerifiedBlock::new(block)
+                            .map_err(|e| eyre!(e))?;
                         self.state
                             .ready_and()
                             .await
                             .map_err(|e| eyre!(e))?
-                            .call(zebra_state::Request::AddBlock { block })
+                            .call(zebra_state::Request::CommitFinalizedBlock { block })
                             .await
                             .map_err(|e| eyre!(e))?;
                     }
@@ -0,0 +1,174 @@
+//! `database` subcommand - describes the on-disk state database.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use chrono::{DateTime, Utc};
+
+use zebra_chain::Network;
+use zebra_state::{
+    on_disk::{database_history, OpenRecord},
+    TreeSchema, STATE_FORMAT_VERSION, TREES,
+};
+
+/// `database` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct DatabaseCmd {
+    /// The database subcommand to run.
+    #[options(command)]
+    cmd: Option<DatabaseSubCmd>,
+}
+
+/// The subcommands of `database`
+#[derive(Debug, Options)]
+enum DatabaseSubCmd {
+    /// The `schema` subcommand
+    #[options(help = "print the on-disk format of the state, for external tools")]
+    Schema(SchemaCmd),
+
+    /// The `info` subcommand
+    #[options(help = "print the Zebra versions that have opened the local state")]
+    Info(InfoCmd),
+}
+
+/// `database schema` subcommand
+#[derive(Debug, Default, Options)]
+struct SchemaCmd {
+    /// Print the schema as a markdown document.
+    #[options(help = "print the schema as a markdown document")]
+    markdown: bool,
+}
+
+/// `database info` subcommand
+#[derive(Debug, Default, Options)]
+struct InfoCmd {}
+
+impl Runnable for DatabaseCmd {
+    /// Run the database subcommand.
+    fn run(&self) {
+        match &self.cmd {
+            Some(DatabaseSubCmd::Schema(cmd)) => {
+                let schema = if cmd.markdown {
+                    markdown_schema(TREES)
+                } else {
+                    text_schema(TREES)
+                };
+                print!("{}", schema);
+            }
+            Some(DatabaseSubCmd::Info(_)) => {
+                let config = app_config();
+                let network = config.network.network;
+                match database_history(&config.state, network) {
+                    Ok(history) => print!("{}", history_text(network, &history)),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => {
+                eprintln!("Usage: zebrad database schema [--markdown] | info");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// The notes that apply to every tree.
+const NOTES: &[&str] = &[
+    "Each tree maps byte keys to byte values. Keys are ordered by their bytes.",
+    "In states created by format version 1 or later, each value is followed by a \
+     CRC-32 checksum of its key and value (u32, little-endian).",
+    "Sled states keep each tree in a sled tree. \
+     RocksDB states keep each tree in a column family with the same name.",
+    "Archive trees are stored in a separate database in `archive_dir`.",
+];
+
+/// Returns `trees` as a markdown document.
+fn markdown_schema(trees: &[TreeSchema]) -> String {
+    let mut doc = format!(
+        "# Zebra state database format\n\nFormat version: {}\n\n",
+        STATE_FORMAT_VERSION
+    );
+    for note in NOTES {
+        doc.push_str(&format!("- {}\n", note));
+    }
+
+    doc.push_str("\n| Tree | Database | Key | Value | Description |\n");
+    doc.push_str("|------|----------|-----|-------|-------------|\n");
+    for tree in trees {
+        doc.push_str(&format!(
+            "| `{}` | {} | {} | {} | {} |\n",
+            tree.name,
+            database(tree),
+            escape(tree.key),
+            escape(tree.value),
+            escape(tree.description),
+        ));
+    }
+
+    doc
+}
+
+/// Returns `trees` as plain text.
+fn text_schema(trees: &[TreeSchema]) -> String {
+    let mut doc = format!("Zebra state format version {}\n\n", STATE_FORMAT_VERSION);
+    for tree in trees {
+        doc.push_str(&format!(
+            "{} ({})\n  key:   {}\n  value: {}\n  {}\n\n",
+            tree.name,
+            database(tree),
+            tree.key,
+            tree.value,
+            tree.description,
+        ));
+    }
+    for note in NOTES {
+        doc.push_str(&format!("{}\n", note));
+    }
+
+    doc
+}
+
+/// Returns the database `history` for `network` as plain text.
+fn history_text(network: Network, history: &[OpenRecord]) -> String {
+    let mut doc = format!(
+        "{:?} state, format version {} (this release)\n\n",
+        network, STATE_FORMAT_VERSION
+    );
+    if history.is_empty() {
+        doc.push_str("No recorded opens: the state was created before format version 2\n");
+    }
+
+    let mut last_format = None;
+    for record in history {
+        let opened_at: DateTime<Utc> = record.opened_at.into();
+        doc.push_str(&format!(
+            "{}  zebrad {}  format version {}",
+            opened_at.to_rfc3339(),
+            record.zebra_version,
+            record.format_version,
+        ));
+        if let Some(last_format) = last_format.filter(|&last| last != record.format_version) {
+            doc.push_str(&format!(" (upgraded from {})", last_format));
+        }
+        doc.push('\n');
+        last_format = Some(record.format_version);
+    }
+
+    doc
+}
+
+/// Returns the database that contains `tree`.
+fn database(tree: &TreeSchema) -> &'static str {
+    if tree.archive {
+        "archive"
+    } else {
+        "state"
+    }
+}
+
+/// Escape `text` for a markdown table cell.
+fn escape(text: &str) -> String {
+    text.replace('|', "\\|")
+}
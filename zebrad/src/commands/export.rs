@@ -0,0 +1,122 @@
+//! `export` subcommand - exports compact blocks, nullifier maps, and subtree
+//! roots for wallet sync.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use std::{
+    fs::File,
+    io::{self, Write},
+    str::FromStr,
+};
+use tokio::runtime::Runtime;
+
+use zebra_chain::types::BlockHeight;
+
+/// The data written by the `export` subcommand.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExportData {
+    /// `CompactBlock` messages for each block
+    Blocks,
+    /// `CompactBlock` messages that only contain the Sapling spends of each
+    /// block
+    Nullifiers,
+    /// `SubtreeRoot` messages for each completed Sapling subtree
+    SubtreeRoots,
+}
+
+impl FromStr for ExportData {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(ExportData::Blocks),
+            "nullifiers" => Ok(ExportData::Nullifiers),
+            "subtree-roots" => Ok(ExportData::SubtreeRoots),
+            _ => Err(format!(
+                "unknown export data {:?}, expected \"blocks\", \"nullifiers\", or \"subtree-roots\"",
+                s
+            )),
+        }
+    }
+}
+
+/// `export` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct ExportCmd {
+    /// The data to export.
+    #[options(
+        help = "the data to export: \"blocks\", \"nullifiers\", or \"subtree-roots\" (default: blocks)"
+    )]
+    data: Option<ExportData>,
+
+    /// The height of the first block to export.
+    #[options(help = "the height of the first block to export (default: 0)")]
+    start: Option<u32>,
+
+    /// The height of the last block to export.
+    #[options(help = "the height of the last block to export (default: the tip)")]
+    end: Option<u32>,
+
+    /// The file to write the exported messages to.
+    #[options(help = "the file to write the exported messages to (stdout if unspecified)")]
+    output_file: Option<String>,
+}
+
+impl ExportCmd {
+    async fn export(&self) -> Result<u32, Report> {
+        match self.output_file {
+            Some(ref output_file) => {
+                let mut file = io::BufWriter::new(File::create(output_file)?);
+                let count = self.write(&mut file).await?;
+                file.flush()?;
+                Ok(count)
+            }
+            None => {
+                let stdout = io::stdout();
+                let mut stdout = io::BufWriter::new(stdout.lock());
+                let count = self.write(&mut stdout).await?;
+                stdout.flush()?;
+                Ok(count)
+            }
+        }
+    }
+
+    /// Write the exported messages to `writer`, and return their count.
+    async fn write<W: Write>(&self, writer: W) -> Result<u32, Report> {
+        let config = app_config();
+        let state = zebra_state::on_disk::init(config.state.clone(), config.network.network);
+
+        let start = BlockHeight(self.start.unwrap_or(0));
+        let end = self.end.map(BlockHeight);
+
+        match self.data.unwrap_or(ExportData::Blocks) {
+            ExportData::Blocks => {
+                zebra_state::export::write_compact_blocks(state, start, end, writer).await
+            }
+            ExportData::Nullifiers => {
+                zebra_state::export::write_nullifier_maps(state, start, end, writer).await
+            }
+            ExportData::SubtreeRoots => {
+                zebra_state::export::write_subtree_roots(state, writer).await
+            }
+        }
+        .map_err(|e| eyre!(e))
+    }
+}
+
+impl Runnable for ExportCmd {
+    /// Write length-delimited protobuf messages from the local state.
+    fn run(&self) {
+        let mut rt = Runtime::new().expect("runtime should be created");
+
+        match rt.block_on(self.export()) {
+            Ok(count) => eprintln!("Exported {} messages", count),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! `export-analytics` subcommand - exports normalized chain tables for data
+//! analysis.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use std::path::Path;
+use tokio::runtime::Runtime;
+
+use zebra_chain::types::BlockHeight;
+use zebra_state::export::analytics::{write_analytics_tables, Table};
+
+/// `export-analytics` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct ExportAnalyticsCmd {
+    /// The output file format.
+    #[options(help = "the output file format (default: parquet)")]
+    format: Option<String>,
+
+    /// The tables to export.
+    #[options(
+        help = "a comma-separated list of tables to export: blocks, transactions, outputs (default: all)"
+    )]
+    tables: Option<String>,
+
+    /// The height of the first block to export.
+    #[options(help = "the height of the first block to export (default: 0)")]
+    start: Option<u32>,
+
+    /// The height of the last block to export.
+    #[options(help = "the height of the last block to export (default: the tip)")]
+    end: Option<u32>,
+
+    /// The directory to write the tables to.
+    #[options(required, help = "the directory to write the table files to")]
+    output_dir: String,
+}
+
+impl ExportAnalyticsCmd {
+    /// Returns the tables selected by the `tables` option.
+    fn tables(&self) -> Result<Vec<Table>, Report> {
+        match self.tables {
+            Some(ref tables) => tables
+                .split(',')
+                .map(|table| table.trim().parse().map_err(|e| eyre!("{}", e)))
+                .collect(),
+            None => Ok(Table::ALL.to_vec()),
+        }
+    }
+
+    async fn export(&self) -> Result<u32, Report> {
+        match self.format.as_deref().unwrap_or("parquet") {
+            "parquet" => {}
+            format => return Err(eyre!("unsupported export format: {}", format)),
+        }
+        let tables = self.tables()?;
+
+        let config = app_config();
+        let state = zebra_state::on_disk::init(config.state.clone(), config.network.network);
+
+        let start = BlockHeight(self.start.unwrap_or(0));
+        let end = self.end.map(BlockHeight);
+
+        write_analytics_tables(state, &tables, start, end, Path::new(&self.output_dir))
+            .await
+            .map_err(|e| eyre!(e))
+    }
+}
+
+impl Runnable for ExportAnalyticsCmd {
+    /// Write analytics tables from the local state.
+    fn run(&self) {
+        let mut rt = Runtime::new().expect("runtime should be created");
+
+        match rt.block_on(self.export()) {
+            Ok(count) => eprintln!(
+                "Exported {} blocks to {}",
+                count,
+                Path::new(&self.output_dir).display()
+            ),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
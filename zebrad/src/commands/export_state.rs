@@ -0,0 +1,59 @@
+//! `export-state` subcommand - exports a snapshot of the finalized state, for
+//! seeding new nodes.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use std::path::Path;
+
+use zebra_chain::types::BlockHeight;
+use zebra_state::on_disk::{export_snapshot, SnapshotManifest};
+
+/// `export-state` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct ExportStateCmd {
+    /// The directory to write the snapshot to.
+    #[options(free)]
+    path: String,
+
+    /// The height of the snapshot tip.
+    #[options(help = "the height of the snapshot tip (default: the finalized tip)")]
+    height: Option<u32>,
+}
+
+impl ExportStateCmd {
+    fn export(&self) -> Result<SnapshotManifest, Report> {
+        if self.path.is_empty() {
+            return Err(eyre!("missing snapshot directory path"));
+        }
+
+        let config = app_config();
+        export_snapshot(
+            &config.state,
+            config.network.network,
+            self.height.map(BlockHeight),
+            Path::new(&self.path),
+        )
+        .map_err(|e| eyre!(e))
+    }
+}
+
+impl Runnable for ExportStateCmd {
+    /// Write a snapshot of the local state.
+    fn run(&self) {
+        match self.export() {
+            Ok(manifest) => eprintln!(
+                "Exported a {:?} state snapshot at height {} ({}) to {}",
+                manifest.network,
+                manifest.height.0,
+                manifest.tip_hash,
+                Path::new(&self.path).display()
+            ),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -15,10 +15,16 @@ impl Runnable for GenerateCmd {
     /// Start the application.
     fn run(&self) {
         let default_config = ZebradConfig {
+            config_version: crate::config::CURRENT_CONFIG_VERSION,
+            release_channel: Default::default(),
+            mempool: Default::default(),
             metrics: Default::default(),
             network: Default::default(),
+            rpc: Default::default(),
+            services: Default::default(),
             state: Default::default(),
             tracing: crate::config::TracingSection::populated(),
+            migrations: Vec::new(),
         };
         let mut output = r"# Default configuration for zebrad.
 #
@@ -0,0 +1,49 @@
+//! `import-state` subcommand - restores the state from a snapshot, written by
+//! `export-state`.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+use std::path::Path;
+
+use zebra_state::on_disk::{import_snapshot, SnapshotManifest};
+
+/// `import-state` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct ImportStateCmd {
+    /// The snapshot directory to import.
+    #[options(free)]
+    path: String,
+}
+
+impl ImportStateCmd {
+    fn import(&self) -> Result<SnapshotManifest, Report> {
+        if self.path.is_empty() {
+            return Err(eyre!("missing snapshot directory path"));
+        }
+
+        let config = app_config();
+        import_snapshot(&config.state, config.network.network, Path::new(&self.path))
+            .map_err(|e| eyre!(e))
+    }
+}
+
+impl Runnable for ImportStateCmd {
+    /// Restore the local state from a snapshot.
+    fn run(&self) {
+        match self.import() {
+            Ok(manifest) => eprintln!(
+                "Imported a {:?} state snapshot at height {} ({}) from {}",
+                manifest.network,
+                manifest.height.0,
+                manifest.tip_hash,
+                Path::new(&self.path).display()
+            ),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
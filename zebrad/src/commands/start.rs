@@ -8,6 +8,10 @@
 //!    * primary interface to the node
 //!    * handles all external network requests for the Zcash protocol
 //!      * via zebra_network::Message and zebra_network::Response
+//!      * answers `getblocks` requests using the block hashes in zebra-state,
+//!      via its read-only state service
+//!      * answers `getdata` block requests with the stored block bytes, without
+//!      serializing the blocks again
 //!    * provides an interface to the rest of the network for other services and
 //!    tasks running within this node
 //!      * via zebra_network::Request
@@ -18,22 +22,58 @@
 //!  * Sync Task
 //!    * This task runs in the background and continuously queries the network for
 //!    new blocks to be verified and added to the local state
+//!  * Diagnostics Task
+//!    * On Unix, writes a diagnostics dump to a file when the node receives
+//!    `SIGUSR1`
+//!  * Backup Task
+//!    * On Unix, backs up the state when the node receives `SIGUSR2`, without
+//!    stopping block commits
+//!  * RPC Endpoint
+//!    * If `rpc.listen_addr` is set, serves JSON-RPC requests, using the
+//!    read-only state service and the peer address book
+//!    * pushes submitted transactions to peers, using the network service
+//!    * if `rpc.audit_log` is set, writes state-mutating calls to the audit
+//!    log
+//!  * Rebroadcast Task
+//!    * If the RPC endpoint is enabled, pushes submitted transactions to peers
+//!    again, until they are mined or they expire
 
 use crate::config::ZebradConfig;
 use crate::{components::tokio::TokioComponent, prelude::*};
 
 use abscissa_core::{config, Command, FrameworkError, Options, Runnable};
-use color_eyre::eyre::Report;
-use tower::{buffer::Buffer, service_fn};
+use color_eyre::eyre::{eyre, Report};
+use std::sync::{Arc, Mutex};
+use tower::{buffer::Buffer, service_fn, Service, ServiceExt};
+use zebra_network::types::PeerServices;
+use zebra_rpc::{AuditLog, LocalSubmissions, SubmissionLimiter};
+use zebra_state::recording::{Recorder, Recording};
 
+mod backup;
+mod diagnostics;
+mod profile;
+mod rpc;
 mod sync;
 
+use backup::Backups;
+use diagnostics::Diagnostics;
+use profile::Profile;
+use rpc::Rpc;
+
 /// `start` subcommand
 #[derive(Command, Debug, Options)]
 pub struct StartCmd {
     /// Filter strings
     #[options(free)]
     filters: Vec<String>,
+
+    /// A configuration profile, applied on top of the config file.
+    #[options(help = "a configuration profile for this node: \"seeder\"")]
+    profile: Option<Profile>,
+
+    /// A file to record state requests to, for `bench-replay`.
+    #[options(help = "record state requests to this file, for bench-replay")]
+    record_state_requests: Option<String>,
 }
 
 impl StartCmd {
@@ -41,25 +81,179 @@ impl StartCmd {
         info!(?self, "starting to connect to the network");
 
         let config = app_config();
-        let state = zebra_state::on_disk::init(config.state.clone(), config.network.network);
-        let verifier = zebra_consensus::chain::init(config.network.network, state.clone()).await;
+        crate::release::check_release_channel(config.release_channel, config.network.network);
+        // Report config errors before starting any services
+        let state_config = config.state.clone().validate()?;
+        let (state, read_state) = zebra_state::on_disk::init_with_layers(
+            state_config.clone(),
+            config.network.network,
+            &config.services.state,
+        );
+        let recorder = self
+            .record_state_requests
+            .as_ref()
+            .map(Recorder::create)
+            .transpose()?;
+        let state = Recording::new(state, recorder.clone());
+        let backups = Backups {
+            dir: state_config
+                .backup_dir
+                .clone()
+                .or_else(|| {
+                    state_config
+                        .cache_dir
+                        .as_ref()
+                        .map(|dir| dir.join("backups"))
+                })
+                .unwrap_or_else(std::env::temp_dir),
+            state: read_state.clone(),
+        };
+        let read_state = Recording::new(read_state, recorder);
+        let verifier = zebra_consensus::chain::init_with_layers(
+            config.network.network,
+            state.clone(),
+            &config.services.verifier,
+        )
+        .await;
 
-        // The service that our node uses to respond to requests by peers
+        // The service that our node uses to respond to requests by peers.
+        // Peer requests only read the state, so they don't wait for block
+        // commits.
+        let inbound_state = read_state.clone();
         let node = Buffer::new(
-            service_fn(|req| async move {
-                info!(?req);
-                Ok::<zebra_network::Response, Report>(zebra_network::Response::Nil)
-            }),
-            1,
+            service_fn(move |req| inbound(inbound_state.clone(), req)),
+            config.network.inbound_request_buffer_size,
         );
-        let (peer_set, _address_book) = zebra_network::init(config.network.clone(), node).await;
+        // Pruned nodes only advertise the recent blocks they can serve
+        let mut network_config = config.network.clone();
+        network_config.services = PeerServices::for_kept_depth(config.state.min_pruned_depth());
+        info!(services = ?network_config.services, "advertising block services to peers");
+        let (peer_set, address_book) = zebra_network::init(network_config, node).await;
 
-        let mut syncer = sync::Syncer::new(config.network.network, peer_set, state, verifier);
+        if let Some(listen_addr) = config.rpc.listen_addr {
+            let rpc = Rpc {
+                state: read_state,
+                peer_set: peer_set.clone(),
+                address_book: address_book.clone(),
+                submission_limiter: config
+                    .rpc
+                    .faucet_mode
+                    .clone()
+                    .map(|faucet| Arc::new(Mutex::new(SubmissionLimiter::new(faucet)))),
+                relay_policy: config.mempool.relay_policy.clone(),
+                local_submissions: Arc::new(Mutex::new(LocalSubmissions::load(
+                    state_config
+                        .local_submissions_path(config.network.network)
+                        .unwrap_or_else(|| {
+                            std::env::temp_dir().join("zebrad-local-submissions.json")
+                        }),
+                    config.rpc.rebroadcast_interval,
+                )?)),
+                audit_log: config
+                    .rpc
+                    .audit_log
+                    .clone()
+                    .map(AuditLog::open)
+                    .transpose()?
+                    .map(|audit_log| Arc::new(Mutex::new(audit_log))),
+            };
+            rpc::spawn_rebroadcast(rpc.clone(), config.rpc.rebroadcast_interval);
+            rpc::spawn(rpc, listen_addr);
+        }
+
+        let mut syncer = sync::Syncer::new(
+            config.network.network,
+            peer_set,
+            state,
+            verifier,
+            config.network.max_block_batch_size,
+        );
+
+        diagnostics::spawn_signal_handler(Diagnostics {
+            dir: config
+                .tracing
+                .diagnostics_dir
+                .clone()
+                .unwrap_or_else(std::env::temp_dir),
+            recent_events: app_reader().recent_events(),
+            address_book,
+            redactor: zebra_network::PeerAddrRedactor::new(&config.network),
+            sync_status: syncer.status(),
+        });
+        backup::spawn_signal_handler(backups);
 
         syncer.sync().await
     }
 }
 
+/// Respond to a request from a peer, using `state`.
+async fn inbound<S>(
+    state: S,
+    req: zebra_network::Request,
+) -> Result<zebra_network::Response, Report>
+where
+    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = BoxError> + Clone,
+{
+    match req {
+        zebra_network::Request::FindBlocks { known_blocks, stop } => {
+            let response = state
+                .oneshot(zebra_state::Request::FindBlockHashes { known_blocks, stop })
+                .await
+                .map_err(|e| eyre!(e))?;
+
+            match response {
+                zebra_state::Response::BlockHashes { hashes, .. } => {
+                    Ok(zebra_network::Response::BlockHeaderHashes(hashes))
+                }
+                _ => {
+                    unreachable!("FindBlockHashes request can only result in Response::BlockHashes")
+                }
+            }
+        }
+        zebra_network::Request::FindHeaders { known_blocks, stop } => {
+            let response = state
+                .oneshot(zebra_state::Request::FindBlockHeaders { known_blocks, stop })
+                .await
+                .map_err(|e| eyre!(e))?;
+
+            match response {
+                zebra_state::Response::BlockHeaders(headers) => {
+                    Ok(zebra_network::Response::BlockHeaders(headers))
+                }
+                _ => unreachable!(
+                    "FindBlockHeaders request can only result in Response::BlockHeaders"
+                ),
+            }
+        }
+        zebra_network::Request::BlocksByHash(hashes) => {
+            let mut blocks = Vec::with_capacity(hashes.len());
+            for hash in hashes {
+                match state
+                    .clone()
+                    .oneshot(zebra_state::Request::GetRawBlock { hash })
+                    .await
+                {
+                    Ok(zebra_state::Response::RawBlock { bytes }) => blocks.push(bytes.into()),
+                    Ok(_) => {
+                        unreachable!("GetRawBlock request can only result in Response::RawBlock")
+                    }
+                    // Like zcashd, skip blocks that we don't have
+                    Err(error) => debug!(?hash, ?error, "could not send requested block"),
+                }
+            }
+
+            Ok(zebra_network::Response::RawBlocks(blocks))
+        }
+        _ => {
+            info!(?req);
+            Ok(zebra_network::Response::Nil)
+        }
+    }
+}
+
+/// A type-erased error.
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
 impl Runnable for StartCmd {
     /// Start the application.
     fn run(&self) {
@@ -94,6 +288,16 @@ impl config::Override<ZebradConfig> for StartCmd {
             config.tracing.filter = Some(self.filters.join(","));
         }
 
+        if let Some(profile) = self.profile {
+            profile.apply(&mut config);
+        }
+
+        // Faucet mode is for small public instances, so it disables the
+        // expensive optional indexes.
+        if config.rpc.faucet_mode.is_some() {
+            config.state.address_index = false;
+        }
+
         Ok(config)
     }
 }
@@ -0,0 +1,71 @@
+//! State backups for a running node.
+//!
+//! When `zebrad start` receives `SIGUSR2`, it backs up the state to a
+//! timestamped directory, while it keeps syncing and committing blocks. Each
+//! backup can be used as the `cache_dir` of another node.
+
+use chrono::{DateTime, Utc};
+use std::{path::PathBuf, thread};
+
+use zebra_state::on_disk::ReadStateService;
+
+/// Takes backups of the state of a running node.
+#[derive(Clone)]
+pub struct Backups {
+    /// The directory that backups are written to
+    pub dir: PathBuf,
+    /// The state that is backed up
+    pub state: ReadStateService,
+}
+
+impl Backups {
+    /// Returns the directory for a backup taken at `now`.
+    fn backup_dir(&self, now: DateTime<Utc>) -> PathBuf {
+        self.dir.join(format!(
+            "zebrad-state-backup-{}",
+            now.format("%Y%m%dT%H%M%S%.3fZ")
+        ))
+    }
+
+    /// Back up the state on a separate thread, so the backup doesn't block
+    /// the async tasks of the node.
+    fn spawn_backup(&self, now: DateTime<Utc>) {
+        let path = self.backup_dir(now);
+        let state = self.state.clone();
+
+        let spawned = thread::Builder::new()
+            .name("zebrad-state-backup".into())
+            .spawn(move || match state.backup(&path) {
+                Ok(()) => info!(?path, "wrote state backup"),
+                Err(error) => warn!(?path, ?error, "could not write state backup"),
+            });
+        if let Err(error) = spawned {
+            warn!(?error, "could not start state backup");
+        }
+    }
+}
+
+/// Spawn a task that backs up the state each time the process receives
+/// `SIGUSR2`.
+#[cfg(unix)]
+pub fn spawn_signal_handler(backups: Backups) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut signals = match signal(SignalKind::user_defined2()) {
+            Ok(signals) => signals,
+            Err(error) => {
+                warn!(?error, "could not listen for state backup signals");
+                return;
+            }
+        };
+
+        while signals.recv().await.is_some() {
+            backups.spawn_backup(Utc::now());
+        }
+    });
+}
+
+/// State backup signals are only supported on Unix.
+#[cfg(not(unix))]
+pub fn spawn_signal_handler(_backups: Backups) {}
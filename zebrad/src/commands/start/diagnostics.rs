@@ -0,0 +1,132 @@
+//! Diagnostics dumps for a running node.
+//!
+//! When `zebrad start` receives `SIGUSR1`, it writes a snapshot of its
+//! internal state to a timestamped file, so operators can inspect a wedged
+//! node without attaching a debugger:
+//!
+//! * the sync pipeline: prospective tips and in-flight block tasks,
+//! * the peer address book, with addresses redacted if the network config
+//!   redacts peer addresses,
+//! * the most recent tracing events.
+//!
+//! `zebrad start` doesn't run a mempool yet, so there are no mempool sizes
+//! in the dump.
+
+use crate::components::diagnostics::RecentEvents;
+
+use super::sync::{SyncStatus, LOOKAHEAD_LIMIT};
+
+use chrono::{DateTime, Utc};
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use zebra_network::{AddressBook, PeerAddrRedactor};
+
+/// The shared state that is written to diagnostics dumps.
+#[derive(Clone)]
+pub struct Diagnostics {
+    /// The directory that dumps are written to
+    pub dir: PathBuf,
+    pub recent_events: RecentEvents,
+    pub address_book: Arc<Mutex<AddressBook>>,
+    pub redactor: PeerAddrRedactor,
+    pub sync_status: Arc<Mutex<SyncStatus>>,
+}
+
+impl Diagnostics {
+    /// Write a diagnostics dump taken at `now` to a timestamped file.
+    ///
+    /// Returns the path of the file.
+    pub fn write_dump(&self, now: DateTime<Utc>) -> io::Result<PathBuf> {
+        let dump = self.dump(now);
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "zebrad-diagnostics-{}.txt",
+            now.format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        fs::write(&path, dump)?;
+
+        Ok(path)
+    }
+
+    /// Returns the diagnostics dump text.
+    fn dump(&self, now: DateTime<Utc>) -> String {
+        // Writing to a String can't fail
+        let mut dump = String::new();
+        let _ = writeln!(dump, "zebrad diagnostics dump");
+        let _ = writeln!(dump, "time: {}", now.to_rfc3339());
+        let _ = writeln!(dump, "version: {}", env!("CARGO_PKG_VERSION"));
+
+        let sync_status = self
+            .sync_status
+            .lock()
+            .expect("sync status mutex should be unpoisoned")
+            .clone();
+        let _ = writeln!(dump, "\n[sync]");
+        let _ = writeln!(dump, "prospective tips: {}", sync_status.prospective_tips);
+        let _ = writeln!(
+            dump,
+            "pending block tasks: {} (lookahead limit {})",
+            sync_status.pending_blocks, LOOKAHEAD_LIMIT
+        );
+
+        // Most recently seen peers first
+        let peers: Vec<_> = self
+            .address_book
+            .lock()
+            .expect("address book mutex should be unpoisoned")
+            .peers()
+            .collect();
+        let _ = writeln!(dump, "\n[peers]");
+        let _ = writeln!(dump, "address book entries: {}", peers.len());
+        for peer in peers {
+            let _ = writeln!(
+                dump,
+                "{} services={:?} last_seen={}",
+                self.redactor.log(&peer.addr),
+                peer.services,
+                peer.last_seen.to_rfc3339()
+            );
+        }
+
+        let _ = writeln!(dump, "\n[recent events]");
+        for event in self.recent_events.snapshot() {
+            let _ = writeln!(dump, "{}", event);
+        }
+
+        dump
+    }
+}
+
+/// Spawn a task that writes a diagnostics dump each time the process
+/// receives `SIGUSR1`.
+#[cfg(unix)]
+pub fn spawn_signal_handler(diagnostics: Diagnostics) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(error) => {
+                warn!(?error, "could not listen for diagnostics dump signals");
+                return;
+            }
+        };
+
+        while signals.recv().await.is_some() {
+            match diagnostics.write_dump(Utc::now()) {
+                Ok(path) => info!(?path, "wrote diagnostics dump"),
+                Err(error) => warn!(?error, "could not write diagnostics dump"),
+            }
+        }
+    });
+}
+
+/// Diagnostics dump signals are only supported on Unix.
+#[cfg(not(unix))]
+pub fn spawn_signal_handler(_diagnostics: Diagnostics) {}
@@ -0,0 +1,122 @@
+//! Named configuration profiles for `zebrad start`.
+//!
+//! A profile adjusts a loaded config for a particular kind of node, so that
+//! operators don't have to tune each setting individually. Profiles only
+//! raise or lower limits; settings that are already more generous in the
+//! config file are kept.
+
+use crate::config::ZebradConfig;
+use std::{str::FromStr, time::Duration};
+
+/// The target number of peers for seeder nodes.
+const SEEDER_TARGET_PEERS: usize = 200;
+
+/// The buffer size for requests from peers on seeder nodes.
+const SEEDER_INBOUND_BUFFER_SIZE: usize = 50;
+
+/// The state page cache size for seeder nodes, in bytes.
+const SEEDER_MEMORY_CACHE_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The block cache size for seeder nodes, in bytes.
+const SEEDER_BLOCK_CACHE_BYTES: u64 = 512 * 1024 * 1024;
+
+/// The multiplier applied to the mempool minimum fee rate on seeder nodes.
+const SEEDER_FEE_RATE_MULTIPLIER: u64 = 10;
+
+/// The minimum interval between rebroadcasts of transactions submitted over
+/// RPC on seeder nodes.
+const SEEDER_REBROADCAST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A named configuration profile.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Profile {
+    /// An archival node that contributes bandwidth to the network.
+    ///
+    /// Seeders connect to more peers, so they can serve historical blocks to
+    /// more syncing nodes. They queue more block and header requests from
+    /// peers, keep more of the state in memory, and never prune blocks.
+    ///
+    /// Seeders only relay transactions with high fees, including
+    /// transactions submitted over RPC, and rebroadcast RPC submissions less
+    /// often, so block serving gets more resources.
+    ///
+    /// Zebra doesn't limit inbound connections yet, so this profile doesn't
+    /// change the inbound limit.
+    Seeder,
+}
+
+impl Profile {
+    /// Apply this profile to `config`.
+    pub fn apply(self, config: &mut ZebradConfig) {
+        match self {
+            Profile::Seeder => {
+                let network = &mut config.network;
+                network.peerset_initial_target_size =
+                    network.peerset_initial_target_size.max(SEEDER_TARGET_PEERS);
+                network.inbound_request_buffer_size = network
+                    .inbound_request_buffer_size
+                    .max(SEEDER_INBOUND_BUFFER_SIZE);
+
+                config.state.memory_cache_bytes = config
+                    .state
+                    .memory_cache_bytes
+                    .max(SEEDER_MEMORY_CACHE_BYTES);
+                config.state.block_cache_bytes =
+                    config.state.block_cache_bytes.max(SEEDER_BLOCK_CACHE_BYTES);
+                // Seeders serve historical blocks, so they can't prune them
+                config.state.prune = false;
+
+                let relay_policy = &mut config.mempool.relay_policy;
+                relay_policy.min_fee_rate = relay_policy
+                    .min_fee_rate
+                    .map(|rate| rate.saturating_mul(SEEDER_FEE_RATE_MULTIPLIER));
+                config.rpc.rebroadcast_interval = config
+                    .rpc
+                    .rebroadcast_interval
+                    .max(SEEDER_REBROADCAST_INTERVAL);
+            }
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seeder" => Ok(Profile::Seeder),
+            _ => Err(format!("unknown profile {:?}, expected \"seeder\"", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeder_profile_raises_limits() {
+        let default_config = ZebradConfig::default();
+        let mut config = default_config.clone();
+        "seeder".parse::<Profile>().unwrap().apply(&mut config);
+
+        assert!(
+            config.network.peerset_initial_target_size
+                > default_config.network.peerset_initial_target_size
+        );
+        assert!(
+            config.network.inbound_request_buffer_size
+                > default_config.network.inbound_request_buffer_size
+        );
+        assert!(config.state.memory_cache_bytes > default_config.state.memory_cache_bytes);
+        assert!(config.state.block_cache_bytes > default_config.state.block_cache_bytes);
+        assert!(
+            config.mempool.relay_policy.min_fee_rate
+                > default_config.mempool.relay_policy.min_fee_rate
+        );
+        assert!(config.rpc.rebroadcast_interval > default_config.rpc.rebroadcast_interval);
+        assert!(!config.state.prune);
+
+        assert!("archive".parse::<Profile>().is_err());
+    }
+}
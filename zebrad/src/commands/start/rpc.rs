@@ -0,0 +1,1419 @@
+//! The JSON-RPC endpoint of a running node.
+//!
+//! When `rpc.listen_addr` is set, `zebrad start` serves these methods over
+//! HTTP. Requests and responses use the Bitcoin JSON-RPC format, so tools
+//! written for `zcashd` can call them:
+//!
+//! * `getbestblockheightandhash`: the best chain tip, and how far it is behind
+//!   the tips advertised by peers, for health checks
+//! * `getblockstats`: the fee and size statistics of a best chain block, by
+//!   hash or height. Like Bitcoin Core, every statistic is returned, so the
+//!   optional list of statistics is ignored
+//! * `getchaintxstats`: the transaction count and rate over a window of best
+//!   chain blocks, which ends at the tip by default
+//! * `z_getshieldedcounts`: the number of note commitments and nullifiers in
+//!   each shielded pool, at the tip or at a best chain height
+//! * `sendrawtransaction`: push a transaction to peers, if it meets the
+//!   node's relay policy. Zebra doesn't verify mempool transactions yet, so
+//!   peers verify the transaction. In faucet mode, submissions are rate
+//!   limited by source address
+//! * `listlocaltransactions`: the transactions submitted to this node, and
+//!   whether they were mined or expired
+//!
+//! Submitted transactions are pushed to peers again every
+//! `rpc.rebroadcast_interval`, until they are mined in the best chain or
+//! they expire.
+//!
+//! If `rpc.audit_log` is set, calls to state-mutating methods are written to
+//! the audit log before the response is sent.
+
+use std::{
+    convert::TryFrom,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower::{Service, ServiceExt};
+
+use zebra_chain::{
+    block::{BlockHeader, BlockHeaderHash},
+    serialization::ZcashDeserialize,
+    transaction::{Transaction, TransactionHash},
+    types::BlockHeight,
+};
+use zebra_consensus::mempool::RelayPolicy;
+use zebra_network::{self as zn, AddressBook};
+use zebra_rpc::{
+    audit_log, AuditLog, AuditRecord, AuditResult, ChainTipStatus, GetBlockStats, GetChainTxStats,
+    GetShieldedCounts, HashOrHeight, ListedTransaction, LocalStatus, LocalSubmissions,
+    SubmissionLimiter, TransactionStats,
+};
+use zebra_state as zs;
+
+use crate::components::supervisor::spawn_supervised_task;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The request is not valid JSON-RPC.
+const PARSE_ERROR: i64 = -32700;
+/// The method doesn't exist.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// An unexpected error.
+const MISC_ERROR: i64 = -1;
+/// A parameter is missing, or has the wrong type or value.
+const INVALID_PARAMETER: i64 = -8;
+/// The requested block or transaction doesn't exist.
+const INVALID_ADDRESS_OR_KEY: i64 = -5;
+/// The transaction can't be deserialized.
+const DESERIALIZATION_ERROR: i64 = -22;
+/// The transaction was rejected by the node's limits or policy.
+const TRANSACTION_REJECTED: i64 = -26;
+/// The node is still starting up.
+const IN_WARMUP: i64 = -28;
+
+/// An error returned in the `error` field of a JSON-RPC response.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RpcError {
+    /// The Bitcoin RPC error code
+    pub code: i64,
+    /// A description of the error
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<BoxError> for RpcError {
+    fn from(error: BoxError) -> Self {
+        Self::new(MISC_ERROR, error.to_string())
+    }
+}
+
+/// A JSON-RPC request.
+#[derive(Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+/// A JSON-RPC response.
+///
+/// Like `zcashd`, responses have a `result` and an `error` field, and one of
+/// them is always `null`.
+#[derive(Serialize)]
+struct RpcResponse {
+    result: Value,
+    error: Option<RpcError>,
+    id: Value,
+}
+
+/// The number of peers that each submitted transaction is pushed to.
+const TRANSACTION_FANOUT: usize = 3;
+
+/// The RPC methods, and the services they use.
+#[derive(Clone)]
+pub struct Rpc<ZS, ZN> {
+    /// The state that the methods read from
+    pub state: ZS,
+    /// The peer set, for pushing submitted transactions to peers
+    pub peer_set: ZN,
+    /// The peer address book, for the heights advertised by peers
+    pub address_book: Arc<Mutex<AddressBook>>,
+    /// The faucet mode limits for submitted transactions, or `None` if
+    /// faucet mode is disabled
+    pub submission_limiter: Option<Arc<Mutex<SubmissionLimiter>>>,
+    /// The relay policy for submitted transactions
+    pub relay_policy: RelayPolicy,
+    /// The transactions submitted to this node, which are rebroadcast until
+    /// they are mined or they expire
+    pub local_submissions: Arc<Mutex<LocalSubmissions>>,
+    /// The audit log for state-mutating calls, or `None` if the audit log
+    /// is disabled
+    pub audit_log: Option<Arc<Mutex<AuditLog>>>,
+}
+
+impl<ZS, ZN> Rpc<ZS, ZN>
+where
+    ZS: Service<zs::Request, Response = zs::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZS::Future: Send,
+    ZN: Service<zn::Request, Response = zn::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZN::Future: Send,
+{
+    /// Returns the serialized response to the serialized JSON-RPC `request`
+    /// from `source`.
+    pub async fn respond(&self, request: &[u8], source: SocketAddr) -> Vec<u8> {
+        let response = match serde_json::from_slice::<RpcRequest>(request) {
+            Ok(request) => {
+                let audited = self.audit_log.is_some() && audit_log::is_audited(&request.method);
+                let params = if audited {
+                    Value::Array(request.params.clone())
+                } else {
+                    Value::Null
+                };
+
+                let result = self.call(&request.method, request.params, source).await;
+                if audited {
+                    self.audit(&request.method, &params, source, &result);
+                }
+
+                let (result, error) = match result {
+                    Ok(result) => (result, None),
+                    Err(error) => (Value::Null, Some(error)),
+                };
+                RpcResponse {
+                    result,
+                    error,
+                    id: request.id,
+                }
+            }
+            Err(error) => RpcResponse {
+                result: Value::Null,
+                error: Some(RpcError::new(PARSE_ERROR, error.to_string())),
+                id: Value::Null,
+            },
+        };
+
+        serde_json::to_vec(&response).expect("JSON values are always serializable")
+    }
+
+    /// Write a call to `method` with `params` from `source`, which returned
+    /// `result`, to the audit log.
+    fn audit(
+        &self,
+        method: &str,
+        params: &Value,
+        source: SocketAddr,
+        result: &Result<Value, RpcError>,
+    ) {
+        let audit_log = match &self.audit_log {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+        let result = match result {
+            Ok(_) => AuditResult::Success,
+            Err(error) => AuditResult::Error {
+                message: error.message.clone(),
+            },
+        };
+        let record = AuditRecord::new(method, params, Some(source), result, SystemTime::now());
+
+        let mut audit_log = audit_log.lock().expect("mutex should be unpoisoned");
+        if let Err(error) = audit_log.record(&record) {
+            error!(
+                ?error,
+                path = ?audit_log.path(),
+                ?method,
+                "could not write to the RPC audit log"
+            );
+        }
+    }
+
+    /// Call `method` with `params`, for a client at `source`.
+    async fn call(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+        source: SocketAddr,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "getbestblockheightandhash" => to_value(self.get_best_block_height_and_hash().await?),
+            "getblockstats" => {
+                let block = hash_or_height_param(params.get(0))?;
+                to_value(self.get_block_stats(block).await?)
+            }
+            "getchaintxstats" => {
+                let window = optional_u32_param(params.get(0))?;
+                let hash = match params.get(1) {
+                    None | Some(Value::Null) => None,
+                    param => match hash_or_height_param(param)? {
+                        HashOrHeight::Hash(hash) => Some(BlockHeaderHash(hash)),
+                        HashOrHeight::Height(_) => Err(RpcError::new(
+                            INVALID_PARAMETER,
+                            "expected a block hash parameter",
+                        ))?,
+                    },
+                };
+                to_value(self.get_chain_tx_stats(window, hash).await?)
+            }
+            "z_getshieldedcounts" => {
+                let height = optional_u32_param(params.get(0))?.map(BlockHeight);
+                to_value(self.get_shielded_counts(height).await?)
+            }
+            "sendrawtransaction" => {
+                let bytes = hex_param(params.get(0))?;
+                to_value(self.send_raw_transaction(bytes, source).await?)
+            }
+            "listlocaltransactions" => to_value(self.list_local_transactions()),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method not found: {}", method),
+            )),
+        }
+    }
+
+    async fn get_best_block_height_and_hash(&self) -> Result<ChainTipStatus, RpcError> {
+        let tip = self.best_tip().await?;
+        let header = self.header(tip.hash).await?;
+        let peer_heights: Vec<u32> = self
+            .address_book
+            .lock()
+            .expect("mutex should be unpoisoned")
+            .peer_heights()
+            .map(|height| height.0)
+            .collect();
+
+        Ok(ChainTipStatus::new(
+            tip.height.0,
+            tip.hash.0,
+            header.time.into(),
+            SystemTime::now(),
+            peer_heights,
+        ))
+    }
+
+    async fn get_block_stats(&self, block: HashOrHeight) -> Result<GetBlockStats, RpcError> {
+        let (height, hash) = self.best_chain_block(block).await?;
+        let header = self.header(hash).await?;
+        let transactions = match self
+            .state(zs::Request::BlockTransactionInfo { hash })
+            .await?
+        {
+            zs::Response::BlockTransactionInfo(transactions) => transactions,
+            _ => unreachable!(
+                "BlockTransactionInfo request can only result in Response::BlockTransactionInfo"
+            ),
+        };
+
+        let transactions = transactions.into_iter().map(|tx| TransactionStats {
+            size: tx.size.into(),
+            is_coinbase: tx.is_coinbase,
+            fee: tx.fee.map(i64::from),
+            transparent_inputs: tx.transparent_inputs.into(),
+            transparent_outputs: tx.transparent_outputs.into(),
+            sapling: tx.sapling_spends > 0 || tx.sapling_outputs > 0,
+            // Zebra doesn't support Orchard yet
+            orchard: false,
+        });
+
+        Ok(GetBlockStats::new(
+            height.0,
+            hash.0,
+            header.time.timestamp(),
+            transactions,
+        ))
+    }
+
+    async fn get_chain_tx_stats(
+        &self,
+        window: Option<u32>,
+        hash: Option<BlockHeaderHash>,
+    ) -> Result<GetChainTxStats, RpcError> {
+        let hash = match hash {
+            Some(hash) => hash,
+            None => self.best_tip().await?.hash,
+        };
+        let stats = match self
+            .state(zs::Request::ChainTxStats { hash, window })
+            .await?
+        {
+            zs::Response::ChainTxStats(stats) => stats,
+            _ => unreachable!("ChainTxStats request can only result in Response::ChainTxStats"),
+        };
+
+        Ok(GetChainTxStats::new(
+            stats.final_height.0,
+            stats.final_hash.0,
+            stats.final_time,
+            stats.block_count,
+            stats.tx_count,
+            stats.interval,
+        ))
+    }
+
+    async fn get_shielded_counts(
+        &self,
+        height: Option<BlockHeight>,
+    ) -> Result<GetShieldedCounts, RpcError> {
+        let counts = match self.state(zs::Request::ShieldedCounts { height }).await? {
+            zs::Response::ShieldedCounts(counts) => counts,
+            _ => unreachable!("ShieldedCounts request can only result in Response::ShieldedCounts"),
+        };
+        let (height, counts) = counts.ok_or_else(|| {
+            RpcError::new(
+                INVALID_PARAMETER,
+                "block height out of range, or the state is still syncing the genesis block",
+            )
+        })?;
+
+        Ok(GetShieldedCounts::new(
+            height.0,
+            counts.sprout_commitments,
+            counts.sprout_nullifiers,
+            counts.sapling_commitments,
+            counts.sapling_nullifiers,
+        ))
+    }
+
+    /// Push the transaction serialized as `bytes`, which was submitted by
+    /// `source`, to peers, and return its transaction ID.
+    async fn send_raw_transaction(
+        &self,
+        bytes: Vec<u8>,
+        source: SocketAddr,
+    ) -> Result<String, RpcError> {
+        let transaction: Arc<Transaction> = Transaction::zcash_deserialize(&bytes[..])
+            .map_err(|error| {
+                RpcError::new(
+                    DESERIALIZATION_ERROR,
+                    format!("transaction decode failed: {}", error),
+                )
+            })?
+            .into();
+
+        if let Some(limiter) = &self.submission_limiter {
+            limiter
+                .lock()
+                .expect("mutex should be unpoisoned")
+                .check(source.ip(), bytes.len(), Instant::now())
+                .map_err(|error| RpcError::new(TRANSACTION_REJECTED, error.to_string()))?;
+        }
+
+        let request = zs::Request::TransactionFee {
+            transaction: transaction.clone(),
+        };
+        let fee = match self.state(request).await {
+            Ok(zs::Response::TransactionFee(fee)) => fee,
+            Ok(_) => {
+                unreachable!("TransactionFee request can only result in Response::TransactionFee")
+            }
+            Err(error) => Err(RpcError::new(TRANSACTION_REJECTED, error.message))?,
+        };
+        let fee = fee.ok_or_else(|| {
+            RpcError::new(
+                TRANSACTION_REJECTED,
+                "coinbase transaction, or missing transparent inputs",
+            )
+        })?;
+        self.relay_policy
+            .check(&transaction, fee)
+            .map_err(|error| RpcError::new(TRANSACTION_REJECTED, error.to_string()))?;
+
+        let hash = TransactionHash::from(transaction.as_ref().clone());
+        let expiry_height = transaction.expiry_height().map(|height| height.0);
+        let now = SystemTime::now();
+        {
+            let mut submissions = self.local_submissions();
+            submissions.submit(hash.0, bytes, expiry_height, now);
+            submissions.mark_broadcast(&hash.0, now);
+        }
+        self.save_local_submissions();
+
+        info!(?hash, ?source, "pushing submitted transaction to peers");
+        tokio::spawn(push_transaction(self.peer_set.clone(), transaction));
+
+        Ok(rpc_hex(hash.0))
+    }
+
+    fn list_local_transactions(&self) -> Vec<ListedTransaction> {
+        self.local_submissions()
+            .list()
+            .map(ListedTransaction::from)
+            .collect()
+    }
+
+    /// Update the status of the locally submitted transactions, then push
+    /// the pending transactions that are due to peers again.
+    ///
+    /// Transactions in the best chain are marked as confirmed, and
+    /// transactions that were removed from the best chain are pending again.
+    async fn rebroadcast(&self) -> Result<(), RpcError> {
+        let tip = self.best_tip().await?;
+
+        let tracked: Vec<[u8; 32]> = self
+            .local_submissions()
+            .list()
+            .filter(|tx| tx.status != LocalStatus::Expired)
+            .map(|tx| tx.txid)
+            .collect();
+        for txid in tracked {
+            let height = self.confirmed_height(TransactionHash(txid)).await?;
+            let mut submissions = self.local_submissions();
+            match height {
+                Some(height) => submissions.mark_confirmed(&txid, height.0),
+                None => submissions.mark_unconfirmed(&txid),
+            }
+        }
+
+        let now = SystemTime::now();
+        let due: Vec<([u8; 32], Vec<u8>)> = {
+            let mut submissions = self.local_submissions();
+            let expired = submissions.expire(tip.height.0);
+            if expired > 0 {
+                info!(expired, "local transactions expired before they were mined");
+            }
+            submissions
+                .due_for_broadcast(now)
+                .into_iter()
+                .map(|tx| (tx.txid, tx.raw.clone()))
+                .collect()
+        };
+        for (txid, raw) in due {
+            match Transaction::zcash_deserialize(&raw[..]) {
+                Ok(transaction) => {
+                    push_transaction(self.peer_set.clone(), transaction.into()).await
+                }
+                Err(error) => warn!(?error, "could not deserialize a local transaction"),
+            }
+            self.local_submissions().mark_broadcast(&txid, now);
+        }
+        self.save_local_submissions();
+
+        Ok(())
+    }
+
+    /// Returns the height of the best chain block that contains the
+    /// transaction `txid`, if there is one.
+    async fn confirmed_height(
+        &self,
+        txid: TransactionHash,
+    ) -> Result<Option<BlockHeight>, RpcError> {
+        let header = match self
+            .state(zs::Request::TransactionMerklePath { txid })
+            .await?
+        {
+            zs::Response::TransactionMerklePath(path) => path.map(|(header, _)| header),
+            _ => unreachable!(
+                "TransactionMerklePath request can only result in Response::TransactionMerklePath"
+            ),
+        };
+
+        match header {
+            Some(header) => {
+                let hash = BlockHeaderHash::from(&header);
+                let (height, _) = self.best_chain_block(HashOrHeight::Hash(hash.0)).await?;
+                Ok(Some(height))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Lock the local submissions table.
+    fn local_submissions(&self) -> MutexGuard<'_, LocalSubmissions> {
+        self.local_submissions
+            .lock()
+            .expect("mutex should be unpoisoned")
+    }
+
+    /// Write the local submissions table to disk, logging any errors.
+    fn save_local_submissions(&self) {
+        let submissions = self.local_submissions();
+        if let Err(error) = submissions.save() {
+            warn!(?error, path = ?submissions.path(), "could not save local transactions");
+        }
+    }
+
+    /// Send `request` to the state.
+    async fn state(&self, request: zs::Request) -> Result<zs::Response, RpcError> {
+        Ok(self.state.clone().oneshot(request).await?)
+    }
+
+    /// Returns the tip of the best chain.
+    async fn best_tip(&self) -> Result<zs::ChainTip, RpcError> {
+        match self.state(zs::Request::GetChainTips).await? {
+            zs::Response::ChainTips(tips) => tips
+                .into_iter()
+                .next()
+                .ok_or_else(|| RpcError::new(IN_WARMUP, "the state doesn't have any blocks yet")),
+            _ => unreachable!("GetChainTips request can only result in Response::ChainTips"),
+        }
+    }
+
+    /// Returns the height and hash of the best chain `block`.
+    async fn best_chain_block(
+        &self,
+        block: HashOrHeight,
+    ) -> Result<(BlockHeight, BlockHeaderHash), RpcError> {
+        let hash = match block {
+            HashOrHeight::Height(height) => {
+                let height = BlockHeight(height);
+                return match self.best_chain_hash(height).await? {
+                    Some(hash) => Ok((height, hash)),
+                    None => Err(RpcError::new(
+                        INVALID_PARAMETER,
+                        "block height out of range",
+                    )),
+                };
+            }
+            HashOrHeight::Hash(hash) => BlockHeaderHash(hash),
+        };
+
+        let tip = self.best_tip().await?;
+        let depth = match self.state(zs::Request::GetDepth { hash }).await? {
+            zs::Response::Depth(depth) => depth,
+            _ => unreachable!("GetDepth request can only result in Response::Depth"),
+        };
+        let depth = depth.ok_or_else(|| {
+            RpcError::new(INVALID_ADDRESS_OR_KEY, "block not found in the best chain")
+        })?;
+
+        // The tip can change between the tip and depth requests, so check
+        // that the height is still correct
+        match tip.height.0.checked_sub(depth).map(BlockHeight) {
+            Some(height) if self.best_chain_hash(height).await? == Some(hash) => Ok((height, hash)),
+            _ => Err(RpcError::new(
+                MISC_ERROR,
+                "the best chain changed during the request, try again",
+            )),
+        }
+    }
+
+    /// Returns the hash of the best chain block at `height`, if there is one.
+    async fn best_chain_hash(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Option<BlockHeaderHash>, RpcError> {
+        match self
+            .state(zs::Request::BestChainBlockHash { height })
+            .await?
+        {
+            zs::Response::BlockHash(hash) => Ok(hash),
+            _ => unreachable!("BestChainBlockHash request can only result in Response::BlockHash"),
+        }
+    }
+
+    /// Returns the header of the best chain block with `hash`.
+    async fn header(&self, hash: BlockHeaderHash) -> Result<BlockHeader, RpcError> {
+        let request = zs::Request::FindBlockHeaders {
+            known_blocks: Vec::new(),
+            stop: Some(hash),
+        };
+        match self.state(request).await? {
+            zs::Response::BlockHeaders(headers) => headers
+                .into_iter()
+                .next()
+                .ok_or_else(|| RpcError::new(INVALID_ADDRESS_OR_KEY, "block not found")),
+            _ => unreachable!("FindBlockHeaders request can only result in Response::BlockHeaders"),
+        }
+    }
+}
+
+/// Push `transaction` to `TRANSACTION_FANOUT` peers.
+///
+/// The peer set usually routes each request to a different ready peer.
+async fn push_transaction<ZN>(peer_set: ZN, transaction: Arc<Transaction>)
+where
+    ZN: Service<zn::Request, Response = zn::Response, Error = BoxError> + Clone,
+{
+    for _ in 0..TRANSACTION_FANOUT {
+        let request = zn::Request::PushTransaction(transaction.clone());
+        if let Err(error) = peer_set.clone().oneshot(request).await {
+            info!(?error, "failed to push transaction to a peer");
+        }
+    }
+}
+
+/// Spawn a task that rebroadcasts the transactions submitted to `rpc` every
+/// `interval`.
+pub fn spawn_rebroadcast<ZS, ZN>(rpc: Rpc<ZS, ZN>, interval: Duration)
+where
+    ZS: Service<zs::Request, Response = zs::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZS::Future: Send,
+    ZN: Service<zn::Request, Response = zn::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZN::Future: Send,
+{
+    spawn_supervised_task("local_rebroadcast", move || {
+        rebroadcast_every(rpc.clone(), interval)
+    });
+}
+
+/// Rebroadcast the transactions submitted to `rpc` every `interval`.
+///
+/// Failed rebroadcasts are logged, so this function only exits if it panics.
+async fn rebroadcast_every<ZS, ZN>(rpc: Rpc<ZS, ZN>, interval: Duration) -> Result<(), BoxError>
+where
+    ZS: Service<zs::Request, Response = zs::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZS::Future: Send,
+    ZN: Service<zn::Request, Response = zn::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZN::Future: Send,
+{
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+
+        if let Err(error) = rpc.rebroadcast().await {
+            warn!(?error, "could not rebroadcast local transactions");
+        }
+    }
+}
+
+/// Returns `bytes` as hex, in the reversed byte order used by RPCs for
+/// hashes.
+fn rpc_hex(mut bytes: [u8; 32]) -> String {
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+/// Parse a hex data parameter.
+fn hex_param(param: Option<&Value>) -> Result<Vec<u8>, RpcError> {
+    match param {
+        Some(Value::String(data)) => hex::decode(data)
+            .map_err(|error| RpcError::new(INVALID_PARAMETER, format!("invalid hex: {}", error))),
+        _ => Err(RpcError::new(
+            INVALID_PARAMETER,
+            "expected a hex data parameter",
+        )),
+    }
+}
+
+/// Parse a block hash or height parameter.
+///
+/// Like `zcashd`, heights can be numbers or strings.
+fn hash_or_height_param(param: Option<&Value>) -> Result<HashOrHeight, RpcError> {
+    match param {
+        Some(Value::Number(height)) => height
+            .as_u64()
+            .and_then(|height| u32::try_from(height).ok())
+            .map(HashOrHeight::Height)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMETER, "block height out of range")),
+        Some(Value::String(block)) => HashOrHeight::from_str(block)
+            .map_err(|error| RpcError::new(INVALID_PARAMETER, error.to_string())),
+        _ => Err(RpcError::new(
+            INVALID_PARAMETER,
+            "expected a block hash or height parameter",
+        )),
+    }
+}
+
+/// Parse an optional number parameter.
+fn optional_u32_param(param: Option<&Value>) -> Result<Option<u32>, RpcError> {
+    match param {
+        None | Some(Value::Null) => Ok(None),
+        Some(param) => param
+            .as_u64()
+            .and_then(|value| u32::try_from(value).ok())
+            .map(Some)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMETER, "expected a number parameter")),
+    }
+}
+
+/// Serialize a method's result.
+fn to_value<T: Serialize>(result: T) -> Result<Value, RpcError> {
+    Ok(serde_json::to_value(result).expect("RPC results are always serializable"))
+}
+
+/// Spawn the JSON-RPC endpoint on `listen_addr`.
+///
+/// If the endpoint fails, it is restarted with backoff.
+pub fn spawn<ZS, ZN>(rpc: Rpc<ZS, ZN>, listen_addr: SocketAddr)
+where
+    ZS: Service<zs::Request, Response = zs::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZS::Future: Send,
+    ZN: Service<zn::Request, Response = zn::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZN::Future: Send,
+{
+    info!(?listen_addr, "opening RPC endpoint");
+
+    spawn_supervised_task("rpc_endpoint", move || {
+        let rpc = rpc.clone();
+        async move {
+            let service = make_service_fn(move |conn: &AddrStream| {
+                let rpc = rpc.clone();
+                let source = conn.remote_addr();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        request_handler(rpc.clone(), source, req)
+                    }))
+                }
+            });
+
+            // try_bind uses the tokio runtime, so we
+            // need to construct it inside the task.
+            Server::try_bind(&listen_addr)?.serve(service).await?;
+
+            Ok(())
+        }
+    });
+}
+
+async fn request_handler<ZS, ZN>(
+    rpc: Rpc<ZS, ZN>,
+    source: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error>
+where
+    ZS: Service<zs::Request, Response = zs::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZS::Future: Send,
+    ZN: Service<zn::Request, Response = zn::Response, Error = BoxError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    ZN::Future: Send,
+{
+    if req.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::from("JSON-RPC requests must be POSTed"))
+            .expect("response with known status cannot fail"));
+    }
+
+    let request = hyper::body::to_bytes(req.into_body()).await?;
+    let response = rpc.respond(&request, source).await;
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(response))
+        .expect("response with known headers cannot fail"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use chrono::Utc;
+    use futures::{channel::mpsc, future::Future, stream::StreamExt};
+    use serde_json::json;
+    use tower::service_fn;
+
+    use zebra_chain::{block::Block, merkle_tree::MerklePath, types::amount::Amount};
+    use zebra_network::types::{MetaAddr, PeerServices};
+    use zebra_rpc::FaucetConfig;
+
+    /// Returns an empty local submissions table in a new temporary file,
+    /// which rebroadcasts pending transactions on every tick.
+    fn local_submissions() -> Arc<Mutex<LocalSubmissions>> {
+        static NEXT_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "zebrad-rpc-local-{}-{}.json",
+            std::process::id(),
+            NEXT_TABLE.fetch_add(1, Ordering::SeqCst)
+        ));
+        let submissions = LocalSubmissions::load(path, Duration::from_secs(0)).unwrap();
+        Arc::new(Mutex::new(submissions))
+    }
+
+    /// Returns an RPC handler for a state that has `block` at `height` as its
+    /// only chain tip, and a receiver for the transactions it pushes to peers.
+    ///
+    /// The tip block has a coinbase transaction, and a transaction with a
+    /// fee and Sapling outputs. Only the first transaction in `block` is in
+    /// the best chain's transaction index.
+    fn rpc_with_tip(
+        block: Arc<Block>,
+        height: BlockHeight,
+    ) -> (
+        Rpc<
+            impl Service<
+                    zs::Request,
+                    Response = zs::Response,
+                    Error = BoxError,
+                    Future = impl Future<Output = Result<zs::Response, BoxError>> + Send,
+                > + Clone
+                + Send
+                + Sync
+                + 'static,
+            impl Service<
+                    zn::Request,
+                    Response = zn::Response,
+                    Error = BoxError,
+                    Future = impl Future<Output = Result<zn::Response, BoxError>> + Send,
+                > + Clone
+                + Send
+                + Sync
+                + 'static,
+        >,
+        mpsc::UnboundedReceiver<Arc<Transaction>>,
+    ) {
+        let state = service_fn(move |request| {
+            let block = block.clone();
+            async move {
+                Ok::<_, BoxError>(match request {
+                    zs::Request::GetChainTips => zs::Response::ChainTips(vec![zs::ChainTip {
+                        hash: block.hash(),
+                        height,
+                        branch_len: 0,
+                        status: zs::ChainTipStatus::Active,
+                    }]),
+                    zs::Request::FindBlockHeaders { .. } => {
+                        zs::Response::BlockHeaders(vec![block.header.clone()])
+                    }
+                    zs::Request::BestChainBlockHash { height: request } => {
+                        zs::Response::BlockHash(Some(block.hash()).filter(|_| request == height))
+                    }
+                    zs::Request::GetDepth { hash } => {
+                        zs::Response::Depth(Some(0).filter(|_| hash == block.hash()))
+                    }
+                    zs::Request::BlockTransactionInfo { .. } => {
+                        zs::Response::BlockTransactionInfo(vec![
+                            zs::TransactionInfo {
+                                size: 100,
+                                is_coinbase: true,
+                                fee: None,
+                                transparent_inputs: 0,
+                                transparent_outputs: 1,
+                                sapling_spends: 0,
+                                sapling_outputs: 0,
+                            },
+                            zs::TransactionInfo {
+                                size: 250,
+                                is_coinbase: false,
+                                fee: Some(Amount::try_from(1_000).unwrap()),
+                                transparent_inputs: 1,
+                                transparent_outputs: 0,
+                                sapling_spends: 0,
+                                sapling_outputs: 2,
+                            },
+                        ])
+                    }
+                    zs::Request::TransactionFee { .. } => {
+                        zs::Response::TransactionFee(Some(Amount::try_from(1_000).unwrap()))
+                    }
+                    zs::Request::TransactionMerklePath { txid } => {
+                        let first = TransactionHash::from(block.transactions[0].as_ref().clone());
+                        zs::Response::TransactionMerklePath(
+                            MerklePath::new(&[first], 0)
+                                .map(|path| (block.header.clone(), path))
+                                .filter(|_| txid == first),
+                        )
+                    }
+                    zs::Request::ChainTxStats { hash, window } => {
+                        if hash != block.hash() {
+                            Err("block is not in the current best chain")?
+                        }
+                        let block_count = window.unwrap_or(height.0);
+                        zs::Response::ChainTxStats(zs::ChainTxStats {
+                            final_hash: hash,
+                            final_height: height,
+                            final_time: block.header.time.timestamp(),
+                            block_count,
+                            tx_count: 2 * u64::from(block_count),
+                            interval: 150 * i64::from(block_count),
+                        })
+                    }
+                    zs::Request::ShieldedCounts { height: request } => {
+                        zs::Response::ShieldedCounts(
+                            Some((
+                                height,
+                                zs::ShieldedCounts {
+                                    sprout_commitments: 3,
+                                    sapling_commitments: 5,
+                                    sprout_nullifiers: 2,
+                                    sapling_nullifiers: 4,
+                                },
+                            ))
+                            .filter(|_| request.map_or(true, |request| request == height)),
+                        )
+                    }
+                    _ => unreachable!("unexpected state request {:?}", request),
+                })
+            }
+        });
+
+        let (pushed_tx, pushed_rx) = mpsc::unbounded();
+        let peer_set = service_fn(move |request| {
+            let pushed_tx = pushed_tx.clone();
+            async move {
+                match request {
+                    zn::Request::PushTransaction(transaction) => {
+                        let _ = pushed_tx.unbounded_send(transaction);
+                        Ok::<_, BoxError>(zn::Response::Nil)
+                    }
+                    _ => unreachable!("unexpected network request {:?}", request),
+                }
+            }
+        });
+
+        let rpc = Rpc {
+            state,
+            peer_set,
+            address_book: Arc::new(Mutex::new(AddressBook::new(tracing::Span::none()))),
+            submission_limiter: None,
+            relay_policy: RelayPolicy::default(),
+            local_submissions: local_submissions(),
+            audit_log: None,
+        };
+        (rpc, pushed_rx)
+    }
+
+    /// Returns the serialized bytes of a transparent transaction.
+    fn transaction_hex() -> String {
+        hex::encode(&*zebra_test::vectors::DUMMY_TX1)
+    }
+
+    /// Returns the parsed response to `request`.
+    async fn call<ZS, ZN>(rpc: &Rpc<ZS, ZN>, request: Value) -> Value
+    where
+        ZS: Service<zs::Request, Response = zs::Response, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        ZS::Future: Send,
+        ZN: Service<zn::Request, Response = zn::Response, Error = BoxError>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        ZN::Future: Send,
+    {
+        let source = SocketAddr::from(([127, 0, 0, 1], 8232));
+        let response = rpc
+            .respond(&serde_json::to_vec(&request).unwrap(), source)
+            .await;
+        serde_json::from_slice(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn best_block_height_and_hash() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, _pushed) = rpc_with_tip(block.clone(), BlockHeight(1));
+
+        let peer: SocketAddr = "192.0.2.1:8233".parse().unwrap();
+        {
+            let mut address_book = rpc.address_book.lock().unwrap();
+            address_book.update(MetaAddr {
+                addr: peer,
+                services: PeerServices::NODE_NETWORK,
+                last_seen: Utc::now(),
+            });
+            address_book.record_height(peer, BlockHeight(11));
+        }
+
+        let response = call(
+            &rpc,
+            json!({"jsonrpc": "1.0", "id": 7, "method": "getbestblockheightandhash", "params": []}),
+        )
+        .await;
+        assert_eq!(response["id"], 7);
+        assert!(response["error"].is_null());
+
+        let status: ChainTipStatus = serde_json::from_value(response["result"].clone()).unwrap();
+        assert_eq!(status.height, 1);
+        let mut hash = block.hash().0;
+        hash.reverse();
+        assert_eq!(status.hash, hex::encode(hash));
+        assert_eq!(status.time, block.header.time.timestamp());
+        assert_eq!(status.estimated_blocks_behind, 10);
+    }
+
+    #[tokio::test]
+    async fn block_stats_by_hash_or_height() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, _pushed) = rpc_with_tip(block.clone(), BlockHeight(1));
+
+        let mut hash = block.hash().0;
+        hash.reverse();
+        let hash = hex::encode(hash);
+
+        for param in &[json!(1), json!("1"), json!(hash)] {
+            let response = call(
+                &rpc,
+                json!({"id": 1, "method": "getblockstats", "params": [param]}),
+            )
+            .await;
+            assert!(response["error"].is_null(), "{}", response);
+
+            let stats: GetBlockStats = serde_json::from_value(response["result"].clone()).unwrap();
+            assert_eq!(stats.height, 1);
+            assert_eq!(stats.blockhash, hash);
+            assert_eq!(stats.time, block.header.time.timestamp());
+            assert_eq!(stats.txs, 2);
+            assert_eq!(stats.ins, 1);
+            assert_eq!(stats.outs, 1);
+            assert_eq!(stats.sapling_txs, 1);
+            assert_eq!(stats.orchard_txs, 0);
+            assert_eq!(stats.total_size, 250);
+            assert_eq!(stats.totalfee, Some(1_000));
+            assert_eq!(stats.avgfeerate, Some(4));
+        }
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "getblockstats", "params": [2]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMETER);
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "getblockstats", "params": [hex::encode([0; 32])]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], INVALID_ADDRESS_OR_KEY);
+
+        let response = call(&rpc, json!({"id": 1, "method": "getblockstats"})).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMETER);
+    }
+
+    #[tokio::test]
+    async fn chain_tx_stats_defaults_to_the_tip() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, _pushed) = rpc_with_tip(block.clone(), BlockHeight(1));
+
+        let mut hash = block.hash().0;
+        hash.reverse();
+        let hash = hex::encode(hash);
+
+        for params in &[json!([]), json!([null, hash]), json!([1, hash])] {
+            let response = call(
+                &rpc,
+                json!({"id": 1, "method": "getchaintxstats", "params": params}),
+            )
+            .await;
+            assert!(response["error"].is_null(), "{}", response);
+
+            let stats: GetChainTxStats =
+                serde_json::from_value(response["result"].clone()).unwrap();
+            assert_eq!(stats.window_final_block_height, 1);
+            assert_eq!(stats.window_final_block_hash, hash);
+            assert_eq!(stats.time, block.header.time.timestamp());
+            assert_eq!(stats.window_block_count, 1);
+            assert_eq!(stats.window_tx_count, Some(2));
+            assert_eq!(stats.window_interval, Some(150));
+        }
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "getchaintxstats", "params": [1, hex::encode([0; 32])]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], MISC_ERROR);
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "getchaintxstats", "params": [1, 1]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMETER);
+    }
+
+    #[tokio::test]
+    async fn shielded_counts_at_the_tip_or_a_height() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, _pushed) = rpc_with_tip(block, BlockHeight(1));
+
+        for params in &[json!([]), json!([1])] {
+            let response = call(
+                &rpc,
+                json!({"id": 1, "method": "z_getshieldedcounts", "params": params}),
+            )
+            .await;
+            assert!(response["error"].is_null(), "{}", response);
+
+            let counts: GetShieldedCounts =
+                serde_json::from_value(response["result"].clone()).unwrap();
+            assert_eq!(counts, GetShieldedCounts::new(1, 3, 2, 5, 4));
+        }
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "z_getshieldedcounts", "params": [2]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMETER);
+    }
+
+    #[tokio::test]
+    async fn submitted_transactions_are_pushed_to_peers() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, mut pushed) = rpc_with_tip(block, BlockHeight(1));
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "sendrawtransaction", "params": [transaction_hex()]}),
+        )
+        .await;
+        assert!(response["error"].is_null(), "{}", response);
+
+        let transaction =
+            Transaction::zcash_deserialize(&zebra_test::vectors::DUMMY_TX1[..]).unwrap();
+        let hash = TransactionHash::from(transaction.clone());
+        assert_eq!(response["result"], rpc_hex(hash.0));
+        for _ in 0..TRANSACTION_FANOUT {
+            assert_eq!(*pushed.next().await.unwrap(), transaction);
+        }
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "sendrawtransaction", "params": ["0000"]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], DESERIALIZATION_ERROR);
+    }
+
+    #[tokio::test]
+    async fn submitted_transactions_are_rebroadcast_until_mined() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, mut pushed) = rpc_with_tip(block.clone(), BlockHeight(1));
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "sendrawtransaction", "params": [transaction_hex()]}),
+        )
+        .await;
+        assert!(response["error"].is_null(), "{}", response);
+        for _ in 0..TRANSACTION_FANOUT {
+            pushed.next().await.unwrap();
+        }
+
+        // A transaction that was submitted earlier, and is now in the tip block
+        let mined = TransactionHash::from(block.transactions[0].as_ref().clone());
+        rpc.local_submissions
+            .lock()
+            .unwrap()
+            .submit(mined.0, Vec::new(), None, SystemTime::now());
+
+        rpc.rebroadcast().await.unwrap();
+
+        // Only the pending transaction is pushed again
+        let transaction =
+            Transaction::zcash_deserialize(&zebra_test::vectors::DUMMY_TX1[..]).unwrap();
+        for _ in 0..TRANSACTION_FANOUT {
+            assert_eq!(*pushed.next().await.unwrap(), transaction);
+        }
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "listlocaltransactions", "params": []}),
+        )
+        .await;
+        assert!(response["error"].is_null(), "{}", response);
+
+        let listed: Vec<ListedTransaction> =
+            serde_json::from_value(response["result"].clone()).unwrap();
+        assert_eq!(listed.len(), 2);
+        let pending = listed
+            .iter()
+            .find(|tx| tx.status == "pending")
+            .expect("the submitted transaction is still pending");
+        assert_eq!(pending.txid, rpc_hex(TransactionHash::from(transaction).0));
+        assert_eq!(pending.broadcastcount, 2);
+        let confirmed = listed
+            .iter()
+            .find(|tx| tx.status == "confirmed")
+            .expect("the mined transaction is confirmed");
+        assert_eq!(confirmed.txid, rpc_hex(mined.0));
+        assert_eq!(confirmed.height, Some(1));
+
+        // The table is saved after each rebroadcast
+        let path = rpc.local_submissions.lock().unwrap().path().to_owned();
+        let saved = LocalSubmissions::load(&path, Duration::from_secs(0)).unwrap();
+        assert_eq!(saved.list().count(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn faucet_mode_limits_submissions() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (mut rpc, mut pushed) = rpc_with_tip(block, BlockHeight(1));
+        rpc.submission_limiter = Some(Arc::new(Mutex::new(SubmissionLimiter::new(FaucetConfig {
+            max_submissions_per_minute: 1,
+            max_tx_size: 1_000,
+        }))));
+        let request =
+            json!({"id": 1, "method": "sendrawtransaction", "params": [transaction_hex()]});
+
+        let response = call(&rpc, request.clone()).await;
+        assert!(response["error"].is_null(), "{}", response);
+        for _ in 0..TRANSACTION_FANOUT {
+            pushed.next().await.unwrap();
+        }
+
+        // The second submission from the same source is rate limited
+        let response = call(&rpc, request).await;
+        assert_eq!(response["error"]["code"], TRANSACTION_REJECTED);
+
+        // Oversized transactions are rejected
+        rpc.submission_limiter = Some(Arc::new(Mutex::new(SubmissionLimiter::new(FaucetConfig {
+            max_submissions_per_minute: 10,
+            max_tx_size: 10,
+        }))));
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "sendrawtransaction", "params": [transaction_hex()]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], TRANSACTION_REJECTED);
+
+        // Rejected transactions aren't pushed to peers
+        drop(rpc);
+        assert!(pushed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn relay_policy_rejects_low_fee_transactions() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (mut rpc, mut pushed) = rpc_with_tip(block, BlockHeight(1));
+        // The mock state's fee is below this fee rate
+        rpc.relay_policy.min_fee_rate = Some(1_000_000);
+
+        let response = call(
+            &rpc,
+            json!({"id": 1, "method": "sendrawtransaction", "params": [transaction_hex()]}),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], TRANSACTION_REJECTED);
+        let message = response["error"]["message"].as_str().unwrap();
+        assert!(message.contains("relay policy minimum"), "{}", message);
+
+        // Rejected transactions aren't pushed to peers
+        drop(rpc);
+        assert!(pushed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn state_mutating_calls_are_audited() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (mut rpc, _pushed) = rpc_with_tip(block, BlockHeight(1));
+
+        let dir = std::env::temp_dir().join(format!("zebrad-rpc-audit-{}", std::process::id()));
+        let path = dir.join("audit.log");
+        rpc.audit_log = Some(Arc::new(Mutex::new(
+            AuditLog::open(zebra_rpc::AuditLogConfig {
+                path: path.clone(),
+                ..Default::default()
+            })
+            .unwrap(),
+        )));
+
+        for params in &[json!([transaction_hex()]), json!(["0000"])] {
+            call(
+                &rpc,
+                json!({"id": 1, "method": "sendrawtransaction", "params": params}),
+            )
+            .await;
+        }
+        // Read-only calls aren't audited
+        call(
+            &rpc,
+            json!({"id": 1, "method": "getbestblockheightandhash", "params": []}),
+        )
+        .await;
+
+        let records: Vec<AuditRecord> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert!(records
+            .iter()
+            .all(|record| record.method == "sendrawtransaction"));
+        assert_eq!(
+            records[0].source,
+            Some(SocketAddr::from(([127, 0, 0, 1], 8232)))
+        );
+        assert_eq!(records[0].result, AuditResult::Success);
+        match &records[1].result {
+            AuditResult::Error { message } => {
+                assert!(message.contains("transaction decode failed"), "{}", message)
+            }
+            result => panic!("expected an error result, got {:?}", result),
+        }
+        assert_ne!(records[0].params_hash, records[1].params_hash);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_are_returned_in_the_response() {
+        let block: Arc<Block> =
+            Block::zcash_deserialize(&zebra_test::vectors::BLOCK_MAINNET_1_BYTES[..])
+                .unwrap()
+                .into();
+        let (rpc, _pushed) = rpc_with_tip(block, BlockHeight(1));
+
+        let response = call(&rpc, json!({"id": 1, "method": "getinfo"})).await;
+        assert_eq!(response["id"], 1);
+        assert!(response["result"].is_null());
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        let response = call(&rpc, json!(["not", "a", "request"])).await;
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+    }
+}
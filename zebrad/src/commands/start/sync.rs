@@ -1,13 +1,22 @@
-use std::{collections::HashSet, iter, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use color_eyre::eyre::{eyre, Report};
-use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{
+    channel::oneshot,
+    stream::{FuturesUnordered, StreamExt},
+};
 use tokio::{task::JoinHandle, time::delay_for};
 use tower::{retry::Retry, Service, ServiceExt};
 use tracing_futures::{Instrument, Instrumented};
 
 use zebra_chain::{
     block::{Block, BlockHeaderHash},
+    types::BlockHeight,
     Network,
 };
 use zebra_consensus::checkpoint;
@@ -22,6 +31,15 @@ const FANOUT: usize = checkpoint::MAX_QUEUED_BLOCKS_PER_HEIGHT;
 /// checkpoint distance.
 pub const LOOKAHEAD_LIMIT: usize = checkpoint::MAX_CHECKPOINT_HEIGHT_GAP * 2;
 
+/// The current state of the sync pipeline, for diagnostics.
+#[derive(Clone, Debug, Default)]
+pub struct SyncStatus {
+    /// The number of prospective tips that haven't been extended yet
+    pub prospective_tips: usize,
+    /// The number of block download and verify tasks that haven't finished
+    pub pending_blocks: usize,
+}
+
 #[derive(Debug)]
 pub struct Syncer<ZN, ZS, ZV>
 where
@@ -36,12 +54,23 @@ where
     tip_network: ZN,
     /// Used to download blocks, with retry logic.
     block_network: Retry<RetryLimit, ZN>,
+    /// The maximum number of blocks in each download request.
+    ///
+    /// Each peer splits requests into smaller batches, based on how quickly
+    /// it responds.
+    blocks_per_request: usize,
     state: ZS,
     verifier: ZV,
     prospective_tips: HashSet<BlockHeaderHash>,
     pending_blocks:
         Pin<Box<FuturesUnordered<Instrumented<JoinHandle<Result<BlockHeaderHash, Error>>>>>>,
     genesis_hash: BlockHeaderHash,
+    /// The height of the verified tip, the last time we checked sync progress.
+    ///
+    /// Used to route block downloads to peers that have the requested blocks.
+    tip_height: Option<BlockHeight>,
+    /// The current pipeline state, shared with diagnostics dumps.
+    status: Arc<Mutex<SyncStatus>>,
 }
 
 impl<ZN, ZS, ZV> Syncer<ZN, ZS, ZV>
@@ -58,19 +87,51 @@ where
     ///  - peers: the zebra-network peers to contact for downloads
     ///  - state: the zebra-state that stores the chain
     ///  - verifier: the zebra-consensus verifier that checks the chain
-    pub fn new(chain: Network, peers: ZN, state: ZS, verifier: ZV) -> Self {
+    ///  - blocks_per_request: the maximum number of blocks in each download
+    ///    request
+    pub fn new(
+        chain: Network,
+        peers: ZN,
+        state: ZS,
+        verifier: ZV,
+        blocks_per_request: usize,
+    ) -> Self {
         let retry_peers = Retry::new(RetryLimit::new(3), peers.clone());
         Self {
             tip_network: peers,
             block_network: retry_peers,
+            blocks_per_request: blocks_per_request.max(1),
             state,
             verifier,
             prospective_tips: HashSet::new(),
             pending_blocks: Box::pin(FuturesUnordered::new()),
             genesis_hash: parameters::genesis_hash(chain),
+            tip_height: None,
+            status: Arc::new(Mutex::new(SyncStatus::default())),
         }
     }
 
+    /// Returns a shared handle to the current sync pipeline state.
+    pub fn status(&self) -> Arc<Mutex<SyncStatus>> {
+        self.status.clone()
+    }
+
+    /// Update the sync pipeline metrics and status.
+    fn update_status(&self) {
+        metrics::gauge!(
+            "sync.prospective_tips.len",
+            self.prospective_tips.len() as i64
+        );
+        metrics::gauge!("sync.pending_blocks.len", self.pending_blocks.len() as i64);
+
+        let mut status = self
+            .status
+            .lock()
+            .expect("sync status mutex should be unpoisoned");
+        status.prospective_tips = self.prospective_tips.len();
+        status.pending_blocks = self.pending_blocks.len();
+    }
+
     #[instrument(skip(self))]
     pub async fn sync(&mut self) -> Result<(), Report> {
         // We can't download the genesis block using our normal algorithm,
@@ -78,12 +139,13 @@ where
         self.request_genesis().await?;
 
         loop {
+            // Progress is only logged, so a failed state request doesn't
+            // stop the sync
+            if let Err(e) = self.log_progress().await {
+                tracing::warn!(?e, "could not log sync progress");
+            }
             self.obtain_tips().await?;
-            metrics::gauge!(
-                "sync.prospective_tips.len",
-                self.prospective_tips.len() as i64
-            );
-            metrics::gauge!("sync.pending_blocks.len", self.pending_blocks.len() as i64);
+            self.update_status();
 
             // ObtainTips Step 6
             //
@@ -93,11 +155,7 @@ where
 
                 self.extend_tips().await?;
 
-                metrics::gauge!(
-                    "sync.prospective_tips.len",
-                    self.prospective_tips.len() as i64
-                );
-                metrics::gauge!("sync.pending_blocks.len", self.pending_blocks.len() as i64);
+                self.update_status();
                 tracing::debug!(
                     pending.len = self.pending_blocks.len(),
                     limit = LOOKAHEAD_LIMIT
@@ -130,6 +188,7 @@ where
                         // earlier request in favor of the later one.
                         Err(e) => tracing::error!(?e, "potentially transient error"),
                     };
+                    self.update_status();
                 }
             }
 
@@ -137,6 +196,38 @@ where
         }
     }
 
+    /// Log the number of blocks in the state, and the estimated sync progress.
+    async fn log_progress(&mut self) -> Result<(), Report> {
+        let (count, progress) = match self
+            .state
+            .ready_and()
+            .await
+            .map_err(|e| eyre!(e))?
+            .call(zebra_state::Request::BlockCount)
+            .await
+            .map_err(|e| eyre!(e))?
+        {
+            zs::Response::BlockCount { count, progress } => (count, progress),
+            _ => unreachable!("BlockCount request can only result in Response::BlockCount"),
+        };
+
+        metrics::gauge!("sync.block_count", count as i64);
+        self.tip_height = progress.map(|progress| progress.tip_height);
+
+        match progress {
+            Some(progress) => tracing::info!(
+                count,
+                tip_height = ?progress.tip_height,
+                estimated_height = ?progress.estimated_height,
+                progress = %format_args!("{:.2}%", progress.fraction() * 100.0),
+                "estimated sync progress"
+            ),
+            None => tracing::info!(count, "state is empty, starting sync"),
+        }
+
+        Ok(())
+    }
+
     /// Given a block_locator list fan out request for subsequent hashes to
     /// multiple peers
     #[instrument(skip(self))]
@@ -357,8 +448,7 @@ where
         Ok(())
     }
 
-    /// Queue a download for the genesis block, if it isn't currently known to
-    /// our node.
+    /// Queue a download for the genesis block, if the state is empty.
     async fn request_genesis(&mut self) -> Result<(), Report> {
         // Due to Bitcoin protocol limitations, we can't request the genesis
         // block using our standard tip-following algorithm:
@@ -368,18 +458,16 @@ where
         //
         // So we just queue the genesis block here.
 
-        let state_has_genesis = self
+        let tip = self
             .state
             .ready_and()
             .await
             .map_err(|e| eyre!(e))?
-            .call(zebra_state::Request::GetBlock {
-                hash: self.genesis_hash,
-            })
+            .call(zebra_state::Request::GetTip)
             .await
-            .is_ok();
+            .map_err(|e| eyre!(e))?;
 
-        if !state_has_genesis {
+        if tip == zebra_state::Response::Empty {
             self.request_blocks(vec![self.genesis_hash]).await?;
         }
 
@@ -389,7 +477,13 @@ where
     /// Queue downloads for each block that isn't currently known to our node
     async fn request_blocks(&mut self, hashes: Vec<BlockHeaderHash>) -> Result<(), Report> {
         tracing::debug!(hashes.len = hashes.len(), "requesting blocks");
-        for hash in hashes.into_iter() {
+        // The blocks we request are above our verified tip, so we only need
+        // peers that advertised a higher height
+        let min_height = match self.tip_height {
+            Some(tip_height) => BlockHeight(tip_height.0 + 1),
+            None => BlockHeight(0),
+        };
+        for chunk in hashes.chunks(self.blocks_per_request) {
             // We construct the block download requests sequentially, waiting
             // for the peer set to be ready to process each request. This
             // ensures that we start block downloads in the order we want them
@@ -403,24 +497,55 @@ where
                 .ready_and()
                 .await
                 .map_err(|e| eyre!(e))?
-                .call(zn::Request::BlocksByHash(iter::once(hash).collect()));
-            let span = tracing::info_span!("block_fetch_verify", ?hash);
-            let mut verifier = self.verifier.clone();
-            let task = tokio::spawn(async move {
-                let block = match block_req.await {
-                    Ok(zn::Response::Blocks(blocks)) => blocks
-                        .into_iter()
-                        .next()
-                        .expect("successful response has the block in it"),
-                    Ok(_) => unreachable!("wrong response to block request"),
-                    Err(e) => return Err(e),
-                };
-                metrics::counter!("sync.downloaded_blocks", 1);
+                .call(zn::Request::BlocksByHashFromHeight {
+                    hashes: chunk.iter().cloned().collect(),
+                    min_height,
+                });
+
+            // Each block is verified in its own task, so the pipeline can
+            // track and retry blocks individually.
+            let mut block_txs = HashMap::new();
+            for &hash in chunk {
+                let (block_tx, block_rx) = oneshot::channel::<Result<Arc<Block>, Error>>();
+                block_txs.insert(hash, block_tx);
+
+                let span = tracing::info_span!("block_fetch_verify", ?hash);
+                let mut verifier = self.verifier.clone();
+                let task = tokio::spawn(async move {
+                    let block = match block_rx.await {
+                        Ok(block) => block?,
+                        Err(_) => return Err("block was missing from the download response".into()),
+                    };
+                    metrics::counter!("sync.downloaded_blocks", 1);
 
-                verifier.ready_and().await?.call(block).await
-            })
-            .instrument(span);
-            self.pending_blocks.push(task);
+                    verifier.ready_and().await?.call(block).await
+                })
+                .instrument(span);
+                self.pending_blocks.push(task);
+            }
+
+            let span = tracing::info_span!("block_fetch", hashes.len = chunk.len());
+            tokio::spawn(
+                async move {
+                    match block_req.await {
+                        Ok(zn::Response::Blocks(blocks)) => {
+                            for block in blocks {
+                                if let Some(block_tx) = block_txs.remove(&block.hash()) {
+                                    let _ = block_tx.send(Ok(block));
+                                }
+                            }
+                        }
+                        Ok(_) => unreachable!("wrong response to block request"),
+                        Err(e) => {
+                            let error = e.to_string();
+                            for (_, block_tx) in block_txs {
+                                let _ = block_tx.send(Err(error.clone().into()));
+                            }
+                        }
+                    }
+                }
+                .instrument(span),
+            );
         }
 
         Ok(())
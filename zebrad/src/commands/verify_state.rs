@@ -0,0 +1,57 @@
+//! `verify-state` subcommand - checks the integrity of the state of a stopped
+//! node.
+
+use crate::prelude::*;
+
+use abscissa_core::{Command, Options, Runnable};
+use color_eyre::eyre::{eyre, Report};
+
+use zebra_state::on_disk::{verify_state, VerifyReport};
+
+/// `verify-state` subcommand
+#[derive(Command, Debug, Default, Options)]
+pub struct VerifyStateCmd {
+    /// Repair the inconsistencies that are found.
+    #[options(help = "repair inconsistencies, by rewriting indexes or rolling back blocks")]
+    repair: bool,
+}
+
+impl VerifyStateCmd {
+    fn verify(&self) -> Result<VerifyReport, Report> {
+        let config = app_config();
+        verify_state(&config.state, config.network.network, self.repair).map_err(|e| eyre!(e))
+    }
+}
+
+impl Runnable for VerifyStateCmd {
+    /// Check the integrity of the local state.
+    fn run(&self) {
+        let report = match self.verify() {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+        for inconsistency in &report.inconsistencies {
+            eprintln!("Inconsistency at {}", inconsistency);
+        }
+        eprintln!(
+            "Checked {} blocks, found {} inconsistencies",
+            report.checked_blocks,
+            report.inconsistencies.len()
+        );
+
+        if self.repair {
+            eprintln!(
+                "Rewrote the index entries for {} inconsistencies, and removed {} blocks",
+                report.rewritten,
+                report.removed.len()
+            );
+        } else if !report.inconsistencies.is_empty() {
+            eprintln!("Run `zebrad verify-state --repair` to repair the state");
+            std::process::exit(1);
+        }
+    }
+}
@@ -3,6 +3,7 @@
 #![allow(clippy::never_loop)]
 
 use super::ZebradCmd;
+use crate::release::BuildInfo;
 use abscissa_core::{Command, Options, Runnable};
 
 /// `version` subcommand
@@ -10,8 +11,9 @@ use abscissa_core::{Command, Options, Runnable};
 pub struct VersionCmd {}
 
 impl Runnable for VersionCmd {
-    /// Print version message
+    /// Print version message, and the build provenance
     fn run(&self) {
         println!("{} {}", ZebradCmd::name(), ZebradCmd::version());
+        println!("{}", BuildInfo::current());
     }
 }
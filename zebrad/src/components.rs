@@ -1,3 +1,6 @@
+pub mod diagnostics;
 pub mod metrics;
+pub mod sampling;
+pub mod supervisor;
 pub mod tokio;
 pub mod tracing;
@@ -0,0 +1,96 @@
+//! A tracing layer that keeps the most recent events, for diagnostics dumps.
+
+use chrono::Utc;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// The number of recent events kept for diagnostics dumps.
+pub const RECENT_EVENT_LIMIT: usize = 100;
+
+/// The most recent tracing events, formatted as log lines.
+///
+/// Only events that are enabled by the tracing filter are recorded. To
+/// capture debug events, set the filter to `debug` using the tracing
+/// endpoint. Trace events are never recorded.
+#[derive(Clone, Debug, Default)]
+pub struct RecentEvents(Arc<Mutex<VecDeque<String>>>);
+
+impl RecentEvents {
+    /// Returns the recorded events, oldest first.
+    pub fn snapshot(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .expect("recent events mutex should be unpoisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn push(&self, line: String) {
+        let mut events = self
+            .0
+            .lock()
+            .expect("recent events mutex should be unpoisoned");
+        if events.len() >= RECENT_EVENT_LIMIT {
+            events.pop_front();
+        }
+        events.push_back(line);
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RecentEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::DEBUG {
+            return;
+        }
+
+        let mut line = format!(
+            "{} {:>5} {}:",
+            Utc::now().to_rfc3339(),
+            metadata.level(),
+            metadata.target()
+        );
+        event.record(&mut FieldWriter(&mut line));
+        self.push(line);
+    }
+}
+
+/// Appends the fields of an event to a log line.
+struct FieldWriter<'a>(&'a mut String);
+
+impl Visit for FieldWriter<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // Writing to a String can't fail
+        let _ = if field.name() == "message" {
+            write!(self.0, " {:?}", value)
+        } else {
+            write!(self.0, " {}={:?}", field.name(), value)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_events_are_bounded() {
+        let events = RecentEvents::default();
+        for i in 0..RECENT_EVENT_LIMIT + 10 {
+            events.push(i.to_string());
+        }
+
+        let snapshot = events.snapshot();
+        assert_eq!(snapshot.len(), RECENT_EVENT_LIMIT);
+        assert_eq!(snapshot[0], "10");
+    }
+}
@@ -1,10 +1,32 @@
-//! An HTTP endpoint for metrics collection.
+//! Metrics exporters.
+//!
+//! Metrics are served on an HTTP endpoint for Prometheus scrapes, or pushed to
+//! a statsd server or an OTLP collector.
 
-use crate::{components::tokio::TokioComponent, config::MetricsSection};
+use std::time::Duration;
+
+use crate::{
+    components::{supervisor::spawn_supervised, tokio::TokioComponent},
+    config::{MetricsExporter, MetricsSection},
+};
 
 use abscissa_core::{Component, FrameworkError};
 
-use metrics_runtime::{exporters::HttpExporter, observers::PrometheusBuilder, Receiver};
+use metrics_core::{Key, Observe, Observer};
+use metrics_runtime::{
+    exporters::HttpExporter, observers::PrometheusBuilder, Controller, Receiver,
+};
+
+mod otlp;
+mod statsd;
+
+use otlp::OtlpExporter;
+use statsd::StatsdExporter;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The time resolution of histograms in pushed metrics.
+const HISTOGRAM_GRANULARITY: Duration = Duration::from_secs(1);
 
 /// Abscissa component which runs a metrics endpoint.
 #[derive(Debug, Component)]
@@ -24,31 +46,182 @@ impl MetricsEndpoint {
         Ok(())
     }
 
-    /// Open the metrics endpoint.
+    /// Open the metrics endpoint, or start pushing metrics.
+    ///
+    /// If the endpoint or exporter fails, it is restarted with backoff.
     ///
     /// We can't implement `after_config`, because we use `derive(Component)`.
     /// And the ownership rules might make it hard to access the TokioComponent
     /// from `after_config`.
     pub fn open_endpoint(&self, metrics_config: &MetricsSection, tokio_component: &TokioComponent) {
-        info!("Initializing metrics endpoint");
+        info!(exporter = ?metrics_config.exporter, "Initializing metrics exporter");
 
-        let addr = metrics_config.endpoint_addr;
-
-        // XXX do we need to hold on to the receiver?
-        let receiver = Receiver::builder()
-            .build()
-            .expect("Receiver config should be valid");
-        // XXX ???? connect this ???
-        let _sink = receiver.sink();
-
-        let endpoint = HttpExporter::new(receiver.controller(), PrometheusBuilder::new(), addr);
-
-        tokio_component
+        let rt = tokio_component
             .rt
             .as_ref()
-            .expect("runtime should not be taken")
-            .spawn(endpoint.async_run());
+            .expect("runtime should not be taken");
+
+        let receiver = if metrics_config.exporter == MetricsExporter::Prometheus {
+            let receiver = Receiver::builder()
+                .build()
+                .expect("Receiver config should be valid");
+            let controller = receiver.controller();
+            let addr = metrics_config.endpoint_addr;
+            spawn_supervised(rt, "metrics_endpoint", move || {
+                let endpoint =
+                    HttpExporter::new(controller.clone(), PrometheusBuilder::new(), addr);
+                async move { Ok(endpoint.async_run().await?) }
+            });
+            receiver
+        } else {
+            // Each push contains the histogram values since the previous push
+            let receiver = Receiver::builder()
+                .histogram(metrics_config.push_interval, HISTOGRAM_GRANULARITY)
+                .build()
+                .expect("Receiver config should be valid");
+            let controller = receiver.controller();
+            let metrics_config = metrics_config.clone();
+            spawn_supervised(rt, "metrics_exporter", move || {
+                push_metrics(
+                    controller.clone(),
+                    PushExporter::new(&metrics_config),
+                    metrics_config.push_interval,
+                )
+            });
+            receiver
+        };
 
         metrics::set_boxed_recorder(Box::new(receiver)).expect("XXX FIXME ERROR CONVERSION");
     }
 }
+
+/// Returns the exported name for the metric `name`.
+///
+/// Every exporter uses the names from Prometheus scrapes, so dashboards and
+/// alerts work with any backend.
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | ':' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// The value of a metric at the time it was observed.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    /// The total of a counter since Zebra started.
+    Counter(u64),
+    /// The current value of a gauge.
+    Gauge(i64),
+    /// The histogram values recorded since the previous push.
+    Histogram(Vec<u64>),
+}
+
+/// An observed metric, with its exported name.
+#[derive(Clone, Debug, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: Value,
+}
+
+/// An [`Observer`] that collects every metric as a [`Sample`].
+#[derive(Debug, Default)]
+struct Samples(Vec<Sample>);
+
+impl Samples {
+    fn push(&mut self, key: Key, value: Value) {
+        let (name, labels) = key.into_parts();
+        self.0.push(Sample {
+            name: metric_name(&name),
+            labels: labels
+                .into_iter()
+                .map(|label| {
+                    let (key, value) = label.into_parts();
+                    (key.into_owned(), value.into_owned())
+                })
+                .collect(),
+            value,
+        });
+    }
+}
+
+impl Observer for Samples {
+    fn observe_counter(&mut self, key: Key, value: u64) {
+        self.push(key, Value::Counter(value));
+    }
+
+    fn observe_gauge(&mut self, key: Key, value: i64) {
+        self.push(key, Value::Gauge(value));
+    }
+
+    fn observe_histogram(&mut self, key: Key, values: &[u64]) {
+        self.push(key, Value::Histogram(values.to_vec()));
+    }
+}
+
+/// The backends that metrics are pushed to.
+enum PushExporter {
+    Statsd(StatsdExporter),
+    Otlp(OtlpExporter),
+}
+
+impl PushExporter {
+    /// Returns the push exporter for `metrics_config`.
+    ///
+    /// Panics if the config uses the Prometheus exporter.
+    fn new(metrics_config: &MetricsSection) -> Self {
+        match metrics_config.exporter {
+            MetricsExporter::Statsd => {
+                PushExporter::Statsd(StatsdExporter::new(metrics_config.statsd_addr))
+            }
+            MetricsExporter::Otlp => {
+                PushExporter::Otlp(OtlpExporter::new(&metrics_config.otlp_endpoint))
+            }
+            MetricsExporter::Prometheus => unreachable!("Prometheus metrics are scraped"),
+        }
+    }
+
+    async fn push(&mut self, samples: Vec<Sample>) -> Result<(), BoxError> {
+        match self {
+            PushExporter::Statsd(exporter) => exporter.push(samples).await,
+            PushExporter::Otlp(exporter) => exporter.push(samples).await,
+        }
+    }
+}
+
+/// Push the metrics in `controller` to `exporter` every `interval`.
+///
+/// Failed pushes are logged, so this function only exits if it panics.
+async fn push_metrics(
+    controller: Controller,
+    mut exporter: PushExporter,
+    interval: Duration,
+) -> Result<(), BoxError> {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+
+        let mut samples = Samples::default();
+        controller.observe(&mut samples);
+        if let Err(error) = exporter.push(samples.0).await {
+            warn!(?error, "could not push metrics");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_names_match_prometheus() {
+        assert_eq!(
+            metric_name("state.committed.block.height"),
+            "state_committed_block_height"
+        );
+        assert_eq!(metric_name("peer-set:ready"), "peer_set:ready");
+    }
+}
@@ -0,0 +1,150 @@
+//! Push metrics to an OpenTelemetry collector, using OTLP/HTTP with the JSON
+//! encoding.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request};
+use serde_json::{json, Value as Json};
+
+use super::{BoxError, Sample, Value};
+
+/// The OTLP aggregation temporality for values since the previous push.
+const DELTA: u8 = 1;
+
+/// The OTLP aggregation temporality for values since Zebra started.
+const CUMULATIVE: u8 = 2;
+
+/// Pushes metrics to an OTLP/HTTP collector.
+pub(super) struct OtlpExporter {
+    endpoint: String,
+    client: Client<HttpConnector>,
+    /// The time Zebra started, which is the start of cumulative counters.
+    start_time: SystemTime,
+    /// The time of the previous push, which is the start of histogram values.
+    last_push: SystemTime,
+}
+
+impl OtlpExporter {
+    /// Returns an exporter that sends metrics to the collector URL `endpoint`.
+    pub(super) fn new(endpoint: &str) -> Self {
+        let now = SystemTime::now();
+        Self {
+            endpoint: endpoint.to_owned(),
+            client: Client::new(),
+            start_time: now,
+            last_push: now,
+        }
+    }
+
+    /// Send `samples` to the collector.
+    pub(super) async fn push(&mut self, samples: Vec<Sample>) -> Result<(), BoxError> {
+        let now = SystemTime::now();
+        let body = serde_json::to_vec(&self.request(samples, now))?;
+        self.last_push = now;
+
+        let request = Request::post(self.endpoint.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))?;
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("collector rejected metrics: {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the OTLP export request for `samples`, observed at `now`.
+    fn request(&self, samples: Vec<Sample>, now: SystemTime) -> Json {
+        let start_time = unix_nanos(self.start_time);
+        let last_push = unix_nanos(self.last_push);
+        let now = unix_nanos(now);
+
+        let metrics: Vec<Json> = samples
+            .into_iter()
+            .map(|sample| metric(sample, &start_time, &last_push, &now))
+            .collect();
+
+        json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": "zebrad" } },
+                    ],
+                },
+                "scopeMetrics": [{
+                    "scope": { "name": "zebrad" },
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+}
+
+/// Returns the OTLP metric for `sample`.
+///
+/// Counters start at `start_time`, and histograms start at `last_push`.
+fn metric(sample: Sample, start_time: &str, last_push: &str, now: &str) -> Json {
+    let Sample {
+        name,
+        labels,
+        value,
+    } = sample;
+    let attributes: Vec<Json> = labels
+        .into_iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect();
+
+    match value {
+        Value::Counter(total) => json!({
+            "name": name,
+            "sum": {
+                "dataPoints": [{
+                    "attributes": attributes,
+                    "startTimeUnixNano": start_time,
+                    "timeUnixNano": now,
+                    "asInt": total.to_string(),
+                }],
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+            },
+        }),
+        Value::Gauge(value) => json!({
+            "name": name,
+            "gauge": {
+                "dataPoints": [{
+                    "attributes": attributes,
+                    "timeUnixNano": now,
+                    "asInt": value.to_string(),
+                }],
+            },
+        }),
+        Value::Histogram(values) => {
+            let count = values.len() as u64;
+            let sum: u64 = values.iter().sum();
+            json!({
+                "name": name,
+                "histogram": {
+                    "dataPoints": [{
+                        "attributes": attributes,
+                        "startTimeUnixNano": last_push,
+                        "timeUnixNano": now,
+                        "count": count.to_string(),
+                        "sum": sum as f64,
+                        "bucketCounts": [count.to_string()],
+                        "explicitBounds": [],
+                    }],
+                    "aggregationTemporality": DELTA,
+                },
+            })
+        }
+    }
+}
+
+/// Returns `time` in nanoseconds since the Unix epoch, as an OTLP JSON
+/// integer string.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
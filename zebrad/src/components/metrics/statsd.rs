@@ -0,0 +1,174 @@
+//! Push metrics to a statsd server.
+
+use std::{collections::HashMap, fmt::Display, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+use super::{BoxError, Sample, Value};
+
+/// The maximum size of a statsd packet.
+///
+/// Packets this size aren't fragmented on most networks.
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Pushes metrics to a statsd server over UDP.
+///
+/// Labels are sent as DogStatsD tags.
+pub(super) struct StatsdExporter {
+    addr: SocketAddr,
+    socket: Option<UdpSocket>,
+    /// The counter totals sent in the previous push, because statsd counters
+    /// are incremented by each sent value.
+    counters: HashMap<(String, Vec<(String, String)>), u64>,
+}
+
+impl StatsdExporter {
+    /// Returns an exporter that sends metrics to `addr`.
+    pub(super) fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            socket: None,
+            counters: HashMap::new(),
+        }
+    }
+
+    /// Send `samples` to the statsd server.
+    pub(super) async fn push(&mut self, samples: Vec<Sample>) -> Result<(), BoxError> {
+        let lines = self.lines(samples);
+
+        if self.socket.is_none() {
+            let local_addr: SocketAddr = if self.addr.is_ipv4() {
+                "0.0.0.0:0".parse()?
+            } else {
+                "[::]:0".parse()?
+            };
+            let mut socket = UdpSocket::bind(local_addr).await?;
+            socket.connect(self.addr).await?;
+            self.socket = Some(socket);
+        }
+        let socket = self.socket.as_mut().expect("socket was just created");
+
+        for packet in packets(&lines) {
+            socket.send(packet.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the statsd lines for `samples`.
+    fn lines(&mut self, samples: Vec<Sample>) -> Vec<String> {
+        let mut lines = Vec::new();
+        for Sample {
+            name,
+            labels,
+            value,
+        } in samples
+        {
+            let tags = tags(&labels);
+            match value {
+                Value::Counter(total) => {
+                    let previous = self
+                        .counters
+                        .insert((name.clone(), labels), total)
+                        .unwrap_or(0);
+                    // If the counter was reset, send the new total
+                    let increment = total.checked_sub(previous).unwrap_or(total);
+                    if increment > 0 {
+                        lines.push(line(&name, increment, "c", &tags));
+                    }
+                }
+                Value::Gauge(value) => {
+                    // Signed gauge values change the gauge, so reset it first
+                    if value < 0 {
+                        lines.push(line(&name, 0, "g", &tags));
+                    }
+                    lines.push(line(&name, value, "g", &tags));
+                }
+                Value::Histogram(values) => {
+                    for value in values {
+                        lines.push(line(&name, value, "h", &tags));
+                    }
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Returns a statsd line for a metric.
+fn line(name: &str, value: impl Display, kind: &str, tags: &str) -> String {
+    format!("{}:{}|{}{}", name, value, kind, tags)
+}
+
+/// Returns `labels` as DogStatsD tags, or an empty string if there are no
+/// labels.
+fn tags(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let tags: Vec<_> = labels
+        .iter()
+        .map(|(key, value)| format!("{}:{}", key, value))
+        .collect();
+    format!("|#{}", tags.join(","))
+}
+
+/// Returns `lines` joined into packets of at most [`MAX_PACKET_SIZE`] bytes.
+///
+/// Lines longer than the maximum are sent in their own packet.
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, value: Value) -> Sample {
+        Sample {
+            name: name.to_owned(),
+            labels: vec![("addr".to_owned(), "local".to_owned())],
+            value,
+        }
+    }
+
+    #[test]
+    fn statsd_lines_send_counter_increments() {
+        let mut exporter = StatsdExporter::new("127.0.0.1:8125".parse().unwrap());
+
+        let lines = exporter.lines(vec![
+            sample("peer_inbound_messages", Value::Counter(5)),
+            sample("state_queued_blocks", Value::Gauge(-2)),
+            sample("sync_download_ms", Value::Histogram(vec![7])),
+        ]);
+        assert_eq!(
+            lines,
+            vec![
+                "peer_inbound_messages:5|c|#addr:local",
+                "state_queued_blocks:0|g|#addr:local",
+                "state_queued_blocks:-2|g|#addr:local",
+                "sync_download_ms:7|h|#addr:local",
+            ]
+        );
+
+        let lines = exporter.lines(vec![sample("peer_inbound_messages", Value::Counter(8))]);
+        assert_eq!(lines, vec!["peer_inbound_messages:3|c|#addr:local"]);
+
+        assert_eq!(packets(&lines), lines);
+    }
+}
@@ -0,0 +1,185 @@
+//! A tracing layer that samples high-frequency spans.
+//!
+//! Each sampled span is traced with probability `rate`. Events inside a span
+//! that isn't traced are skipped, unless they are warnings or errors. Spans
+//! that are open for longer than the slow threshold are logged when they
+//! close, so slow requests are visible even if they weren't traced.
+//!
+//! The sampler is installed with the tracing subscriber, before the config is
+//! loaded. So it traces every span until it is configured.
+
+use crate::config::SamplingSection;
+use rand::Rng;
+use std::{
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::Instant,
+};
+use tracing::{
+    callsite,
+    span::{Attributes, Id},
+    subscriber::Interest,
+    Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Layer},
+    registry::LookupSpan,
+};
+
+/// The sampling decision for a sampled span.
+struct Decision {
+    /// Is the span traced?
+    traced: bool,
+    /// When the span was created.
+    opened: Instant,
+}
+
+/// A tracing layer that samples the spans in a [`SamplingSection`].
+#[derive(Clone, Debug, Default)]
+pub struct SpanSampler(Arc<RwLock<Option<SamplingSection>>>);
+
+impl SpanSampler {
+    /// Start sampling with `config`.
+    pub fn configure(&self, config: &SamplingSection) {
+        *self
+            .0
+            .write()
+            .expect("span sampler lock should be unpoisoned") = Some(config.clone());
+
+        // Event callsites need to ask the sampler about each event
+        callsite::rebuild_interest_cache();
+    }
+
+    fn config(&self) -> RwLockReadGuard<'_, Option<SamplingSection>> {
+        self.0
+            .read()
+            .expect("span sampler lock should be unpoisoned")
+    }
+
+    /// Returns true if the sampler is configured, and it skips some spans.
+    fn is_sampling(&self) -> bool {
+        match &*self.config() {
+            Some(config) => config.rate < 1.0,
+            None => false,
+        }
+    }
+}
+
+impl<S> Layer<S> for SpanSampler
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_event() && self.is_sampling() {
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        if metadata.is_span() || *metadata.level() <= Level::WARN {
+            return true;
+        }
+
+        // Use the decision of the innermost sampled span
+        let mut span = ctx.lookup_current();
+        while let Some(current) = span {
+            if let Some(decision) = current.extensions().get::<Decision>() {
+                return decision.traced;
+            }
+            span = current.parent();
+        }
+
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let config = self.config();
+        let config = match &*config {
+            Some(config) if config.rate < 1.0 => config,
+            _ => return,
+        };
+        if !config
+            .spans
+            .iter()
+            .any(|name| name == attrs.metadata().name())
+        {
+            return;
+        }
+
+        let traced = rand::thread_rng().gen_bool(config.rate.max(0.0));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Decision {
+                traced,
+                opened: Instant::now(),
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let slow_threshold = match &*self.config() {
+            Some(config) => config.slow_threshold,
+            None => return,
+        };
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let elapsed = match span.extensions().get::<Decision>() {
+            Some(decision) if !decision.traced => decision.opened.elapsed(),
+            _ => return,
+        };
+        if elapsed >= slow_threshold {
+            tracing::warn!(
+                span = span.name(),
+                ?elapsed,
+                "slow span, which wasn't traced because of sampling"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tracing::Event;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Counts the events that are enabled.
+    #[derive(Clone, Default)]
+    struct EventCount(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for EventCount {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn untraced_spans_only_log_warnings() {
+        let sampler = SpanSampler::default();
+        let count = EventCount::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(sampler.clone())
+            .with(count.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            sampler.configure(&SamplingSection {
+                rate: 0.0,
+                spans: vec!["sampled".to_owned()],
+                ..SamplingSection::default()
+            });
+
+            tracing::info_span!("sampled").in_scope(|| {
+                tracing::info!("skipped");
+                tracing::info_span!("inner").in_scope(|| tracing::info!("skipped"));
+                tracing::warn!("logged");
+            });
+            tracing::info_span!("other").in_scope(|| tracing::info!("logged"));
+        });
+
+        assert_eq!(count.0.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,135 @@
+//! Supervision for restartable components.
+//!
+//! Endpoints and other auxiliary tasks don't affect consensus or the state,
+//! so if they fail or panic, they are restarted with backoff, instead of
+//! being silently lost. Peer connections and the peer crawler recover from
+//! their own panics. Other panics abort the process, because they might leave
+//! the in-memory data of the state or consensus code inconsistent.
+
+use std::{
+    future::Future,
+    panic,
+    time::{Duration, Instant},
+};
+
+use tokio::runtime::Runtime;
+use zebra_network::recoverable::{in_recoverable_task, recoverable};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The delay before the first restart of a failed component.
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay between restarts of a failed component.
+///
+/// Components that run for longer than this delay are restarted after
+/// `MIN_RESTART_DELAY` again.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Spawn `component` on `rt`, restarting it with backoff each time it exits
+/// with an error or panics.
+///
+/// `make_task` creates a new task for each run. Restarts are counted in the
+/// `component.restart.count` metric, labelled with `name`.
+pub fn spawn_supervised<F, Fut>(rt: &Runtime, name: &'static str, make_task: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), BoxError>> + Send + 'static,
+{
+    rt.spawn(supervise(name, make_task, MIN_RESTART_DELAY));
+}
+
+/// Spawn `component` on the current runtime, restarting it with backoff each
+/// time it exits with an error or panics.
+///
+/// Used for components that are started by async code, after the runtime has
+/// been taken from the `TokioComponent`.
+pub fn spawn_supervised_task<F, Fut>(name: &'static str, make_task: F)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), BoxError>> + Send + 'static,
+{
+    tokio::spawn(supervise(name, make_task, MIN_RESTART_DELAY));
+}
+
+/// Run the tasks created by `make_task` until one of them succeeds, waiting
+/// at least `min_delay` before each restart.
+async fn supervise<F, Fut>(name: &'static str, mut make_task: F, min_delay: Duration)
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), BoxError>> + Send + 'static,
+{
+    let mut delay = min_delay;
+    loop {
+        let started = Instant::now();
+        match tokio::spawn(recoverable(make_task())).await {
+            Ok(Ok(())) => {
+                info!(component = name, "component exited");
+                return;
+            }
+            Ok(Err(error)) => warn!(component = name, ?error, "component failed"),
+            Err(error) => error!(component = name, ?error, "component panicked"),
+        }
+
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = min_delay;
+        }
+        info!(component = name, ?delay, "restarting component");
+        tokio::time::delay_for(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+        metrics::counter!("component.restart.count", 1, "component" => name);
+    }
+}
+
+/// Abort the process when code outside a recoverable task panics.
+///
+/// The state and consensus code, and the storage engines and other
+/// dependencies they call, can panic on any thread, so only panics in
+/// supervised components, peer connections, and the peer crawler unwind.
+pub fn install_panic_policy() {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        if !in_recoverable_task() {
+            eprintln!(
+                "a panic outside a recoverable task can leave the state inconsistent, exiting"
+            );
+            std::process::abort();
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn failed_components_are_restarted() {
+        let mut rt = Runtime::new().expect("runtime builds");
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let task_runs = runs.clone();
+        let make_task = move || {
+            let run = task_runs.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match run {
+                    0 => Err("component failed".into()),
+                    1 => panic!("component panicked"),
+                    _ => Ok(()),
+                }
+            }
+        };
+
+        // Supervision finishes when the third run succeeds
+        rt.block_on(supervise("test", make_task, Duration::from_millis(1)));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
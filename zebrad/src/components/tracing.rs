@@ -1,6 +1,10 @@
 //! An HTTP endpoint for dynamically setting tracing filters.
 
-use crate::{components::tokio::TokioComponent, config::TracingSection, prelude::*};
+use crate::{
+    components::{supervisor::spawn_supervised, tokio::TokioComponent},
+    config::TracingSection,
+    prelude::*,
+};
 
 use abscissa_core::{Component, FrameworkError};
 
@@ -37,38 +41,30 @@ impl TracingEndpoint {
 
     /// Open the tracing endpoint.
     ///
+    /// If the endpoint fails, it is restarted with backoff.
+    ///
     /// We can't implement `after_config`, because we use `derive(Component)`.
     /// And the ownership rules might make it hard to access the TokioComponent
     /// from `after_config`.
     pub fn open_endpoint(&self, tracing_config: &TracingSection, tokio_component: &TokioComponent) {
         info!("Initializing tracing endpoint");
 
-        let service =
-            make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(request_handler)) });
-
         let addr = tracing_config.endpoint_addr;
 
-        tokio_component
+        let rt = tokio_component
             .rt
             .as_ref()
-            .expect("runtime should not be taken")
-            .spawn(async move {
-                // try_bind uses the tokio runtime, so we
-                // need to construct it inside the task.
-                let server = match Server::try_bind(&addr) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("Could not open tracing endpoint listener");
-                        error!("Error: {}", e);
-                        return;
-                    }
-                }
-                .serve(service);
-
-                if let Err(e) = server.await {
-                    error!("Server error: {}", e);
-                }
-            });
+            .expect("runtime should not be taken");
+        spawn_supervised(rt, "tracing_endpoint", move || async move {
+            let service =
+                make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(request_handler)) });
+
+            // try_bind uses the tokio runtime, so we
+            // need to construct it inside the task.
+            Server::try_bind(&addr)?.serve(service).await?;
+
+            Ok(())
+        });
     }
 }
 
@@ -4,32 +4,118 @@
 //! application's configuration file and/or command-line options
 //! for specifying it.
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
+use zebra_consensus::mempool::Config as MempoolSection;
 use zebra_network::Config as NetworkSection;
+use zebra_rpc::Config as RpcSection;
+use zebra_state::layers::LayerConfig;
 use zebra_state::Config as StateSection;
 
+use crate::release::ReleaseChannel;
+
+mod migration;
+
+pub use migration::{AppliedMigration, CURRENT_CONFIG_VERSION};
+
 /// Configuration for `zebrad`.
 ///
 /// The `zebrad` config is a TOML-encoded version of this structure. The meaning
 /// of each field is described in the documentation, although it may be necessary
 /// to click through to the sub-structures for each section.
-#[derive(Clone, Default, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields, default)]
+///
+/// Configs from older releases are migrated to the current schema when they
+/// are loaded, based on their `config_version`.
+//
+// `remote = "Self"` makes the derives generate inherent methods, so the
+// trait impls below can migrate the config before it is deserialized.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(remote = "Self", deny_unknown_fields, default)]
 pub struct ZebradConfig {
+    /// The config schema version.
+    ///
+    /// Configs without a version are from releases before schema versioning,
+    /// and have version 0. Loaded configs are always migrated to
+    /// `CURRENT_CONFIG_VERSION`.
+    pub config_version: u32,
+
+    /// The release channel of this build, `"stable"` or `"testing"`.
+    ///
+    /// Testing builds log a warning when they are used on mainnet.
+    pub release_channel: ReleaseChannel,
+
+    /// Mempool configuration
+    pub mempool: MempoolSection,
+
     /// Metrics configuration
     pub metrics: MetricsSection,
 
     /// Networking configuration
     pub network: NetworkSection,
 
+    /// RPC configuration
+    pub rpc: RpcSection,
+
+    /// Service middleware configuration
+    pub services: ServicesSection,
+
     /// State configuration
     pub state: StateSection,
 
     /// Tracing configuration
     pub tracing: TracingSection,
+
+    /// The migrations applied to this config when it was loaded.
+    #[serde(skip)]
+    pub migrations: Vec<AppliedMigration>,
+}
+
+impl Default for ZebradConfig {
+    fn default() -> Self {
+        Self {
+            config_version: CURRENT_CONFIG_VERSION,
+            release_channel: Default::default(),
+            mempool: Default::default(),
+            metrics: Default::default(),
+            network: Default::default(),
+            rpc: Default::default(),
+            services: Default::default(),
+            state: Default::default(),
+            tracing: Default::default(),
+            migrations: Vec::new(),
+        }
+    }
+}
+
+impl ZebradConfig {
+    /// Log the migrations applied to this config when it was loaded.
+    pub fn log_migrations(&self) {
+        for migration in &self.migrations {
+            warn!("migrated config: {}", migration);
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ZebradConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = toml::Value::deserialize(deserializer)?;
+        let migrations = migration::migrate(&mut value).map_err(de::Error::custom)?;
+
+        // Calls the derived inherent method
+        let mut config = ZebradConfig::deserialize(value).map_err(de::Error::custom)?;
+        config.migrations = migrations;
+
+        Ok(config)
+    }
+}
+
+impl Serialize for ZebradConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Calls the derived inherent method
+        ZebradConfig::serialize(self, serializer)
+    }
 }
 
 /// Tracing configuration section.
@@ -41,6 +127,15 @@ pub struct TracingSection {
 
     /// The endpoint address used for tracing.
     pub endpoint_addr: SocketAddr,
+
+    /// The directory for diagnostics dumps.
+    ///
+    /// `zebrad start` writes a diagnostics dump when it receives `SIGUSR1`.
+    /// If `None`, dumps are written to the system temporary directory.
+    pub diagnostics_dir: Option<PathBuf>,
+
+    /// Sampling for high-frequency spans.
+    pub sampling: SamplingSection,
 }
 
 impl Default for TracingSection {
@@ -54,22 +149,114 @@ impl TracingSection {
         Self {
             filter: Some("info".to_owned()),
             endpoint_addr: "0.0.0.0:3000".parse().unwrap(),
+            diagnostics_dir: None,
+            sampling: SamplingSection::default(),
+        }
+    }
+}
+
+/// Tracing sampling configuration section.
+///
+/// High-frequency spans, like the span for each state request or peer
+/// message, make detailed tracing expensive. Sampling only traces a fraction
+/// of these spans, so detailed tracing can stay enabled in production.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SamplingSection {
+    /// The fraction of sampled spans that are traced, from 0.0 to 1.0.
+    ///
+    /// Events in spans that aren't traced are skipped, apart from warnings
+    /// and errors, which are always logged. Use 1.0 to trace every span.
+    pub rate: f64,
+
+    /// The names of the sampled spans.
+    ///
+    /// Other spans are always traced, unless they are inside a sampled span
+    /// that isn't traced.
+    pub spans: Vec<String>,
+
+    // Note: due to the way this is rendered by the toml
+    // serializer, the Duration fields should come last.
+    /// Sampled spans that are open for longer than this are logged at warn
+    /// level, even if they aren't traced.
+    pub slow_threshold: Duration,
+}
+
+impl Default for SamplingSection {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            spans: vec!["service".to_owned(), "handle_message_as_request".to_owned()],
+            slow_threshold: Duration::from_secs(5),
         }
     }
 }
 
+/// Service middleware configuration section.
+///
+/// Each internal service is wrapped in a stack of tower layers, configured
+/// by its subsection.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServicesSection {
+    /// The layers around the state service, which commits blocks and
+    /// answers the syncer's and verifiers' state queries.
+    ///
+    /// Peer requests use the read-only state service, which isn't wrapped.
+    pub state: LayerConfig,
+
+    /// The layers around the chain verifier service.
+    pub verifier: LayerConfig,
+}
+
+/// The backends that can export metrics.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsExporter {
+    /// Serve metrics for Prometheus scrapes on `endpoint_addr`.
+    Prometheus,
+    /// Push metrics to the statsd server at `statsd_addr`, with labels as
+    /// DogStatsD tags.
+    Statsd,
+    /// Push metrics to the OTLP/HTTP collector at `otlp_endpoint`, using the
+    /// JSON encoding.
+    Otlp,
+}
+
 /// Metrics configuration section.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, default)]
 pub struct MetricsSection {
-    /// The endpoint address used for metrics.
+    /// The backend used to export metrics.
+    ///
+    /// Metric names are the same for every backend.
+    pub exporter: MetricsExporter,
+
+    /// The endpoint address used for Prometheus scrapes.
     pub endpoint_addr: SocketAddr,
+
+    /// The statsd server address, used by the `statsd` exporter.
+    pub statsd_addr: SocketAddr,
+
+    /// The OTLP/HTTP metrics URL, used by the `otlp` exporter.
+    ///
+    /// Only `http` URLs are supported.
+    pub otlp_endpoint: String,
+
+    // Note: due to the way this is rendered by the toml
+    // serializer, the Duration fields should come last.
+    /// How often metrics are pushed, by the `statsd` and `otlp` exporters.
+    pub push_interval: Duration,
 }
 
 impl Default for MetricsSection {
     fn default() -> Self {
         Self {
+            exporter: MetricsExporter::Prometheus,
             endpoint_addr: "0.0.0.0:9999".parse().unwrap(),
+            statsd_addr: "127.0.0.1:8125".parse().unwrap(),
+            otlp_endpoint: "http://127.0.0.1:4318/v1/metrics".to_owned(),
+            push_interval: Duration::from_secs(10),
         }
     }
 }
@@ -0,0 +1,220 @@
+//! Migrations for configs written by older `zebrad` releases.
+//!
+//! Each config has a `config_version`. Configs without a version were
+//! written before versioning was added, and have version 0.
+//!
+//! When a field is renamed, or a field or section is moved, add a
+//! [`Migration`] to [`MIGRATIONS`], and increment [`CURRENT_CONFIG_VERSION`].
+//! Older configs are translated to the current schema before they are
+//! deserialized, so they keep working after an upgrade, and each translation
+//! is logged so users can update their configs.
+
+use std::{convert::TryFrom, fmt};
+
+use toml::{value::Table, Value};
+
+/// The config schema version written by this release.
+pub const CURRENT_CONFIG_VERSION: u32 = 1;
+
+/// The name of the config version field.
+pub(super) const CONFIG_VERSION_FIELD: &str = "config_version";
+
+/// A field or section that moved between config versions.
+struct Migration {
+    /// The config version that moved the field or section.
+    ///
+    /// The migration is applied to configs with an older version.
+    version: u32,
+    /// The old path of the field or section.
+    from: &'static [&'static str],
+    /// The new path of the field or section.
+    to: &'static [&'static str],
+}
+
+/// The migrations for older configs, in the order they are applied.
+///
+/// Version 1:
+///   * `state.backend` was renamed to `state.storage_backend`
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    from: &["state", "backend"],
+    to: &["state", "storage_backend"],
+}];
+
+/// A migration that was applied to a config when it was loaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AppliedMigration {
+    /// The version of the loaded config.
+    pub config_version: u32,
+    /// The old path of the field or section.
+    pub from: String,
+    /// The new path of the field or section.
+    pub to: String,
+}
+
+impl fmt::Display for AppliedMigration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "moved `{}` to `{}` in version {} config, update the config to version {}",
+            self.from, self.to, self.config_version, CURRENT_CONFIG_VERSION
+        )
+    }
+}
+
+/// Translate the config in `value` to the current schema.
+///
+/// Returns the migrations that were applied, or an error if the config is
+/// from a newer release, or sets both the old and new path of a migrated
+/// field.
+pub(super) fn migrate(value: &mut Value) -> Result<Vec<AppliedMigration>, String> {
+    let table = value
+        .as_table_mut()
+        .ok_or("the config must be a TOML table")?;
+
+    let config_version = match table.get(CONFIG_VERSION_FIELD) {
+        None => 0,
+        Some(Value::Integer(version)) => {
+            u32::try_from(*version).map_err(|_| format!("invalid config_version: {}", version))?
+        }
+        Some(version) => return Err(format!("invalid config_version: {}", version)),
+    };
+    if config_version > CURRENT_CONFIG_VERSION {
+        return Err(format!(
+            "config_version {} is from a newer zebrad release, this release supports version {}",
+            config_version, CURRENT_CONFIG_VERSION
+        ));
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| config_version < migration.version)
+    {
+        let moved = match remove(table, migration.from) {
+            Some(moved) => moved,
+            None => continue,
+        };
+        if !insert(table, migration.to, moved) {
+            return Err(format!(
+                "the config sets both `{}` and `{}`, remove `{}`",
+                migration.from.join("."),
+                migration.to.join("."),
+                migration.from.join("."),
+            ));
+        }
+
+        applied.push(AppliedMigration {
+            config_version,
+            from: migration.from.join("."),
+            to: migration.to.join("."),
+        });
+    }
+
+    let _ = table.insert(
+        CONFIG_VERSION_FIELD.to_owned(),
+        Value::Integer(CURRENT_CONFIG_VERSION.into()),
+    );
+
+    Ok(applied)
+}
+
+/// Remove and return the value at `path` in `table`.
+fn remove(table: &mut Table, path: &[&str]) -> Option<Value> {
+    match path {
+        [] => None,
+        [key] => table.remove(*key),
+        [key, rest @ ..] => remove(table.get_mut(*key)?.as_table_mut()?, rest),
+    }
+}
+
+/// Insert `value` at `path` in `table`, creating any missing tables.
+///
+/// Returns false without changing `table` if there is already a value at
+/// `path`.
+fn insert(table: &mut Table, path: &[&str], value: Value) -> bool {
+    match path {
+        [] => false,
+        [key] if table.contains_key(*key) => false,
+        [key] => table.insert((*key).to_owned(), value).is_none(),
+        [key, rest @ ..] => match table
+            .entry((*key).to_owned())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(table) => insert(table, rest, value),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::ZebradConfig;
+
+    #[test]
+    fn unversioned_config_is_migrated() {
+        let config: ZebradConfig = toml::from_str(
+            r#"
+            [state]
+            backend = "memory"
+            "#,
+        )
+        .expect("unversioned config is accepted");
+
+        assert_eq!(config.config_version, CURRENT_CONFIG_VERSION);
+        assert_eq!(
+            config.state.storage_backend,
+            zebra_state::StorageBackendKind::Memory
+        );
+        assert_eq!(
+            config.migrations,
+            vec![AppliedMigration {
+                config_version: 0,
+                from: "state.backend".to_owned(),
+                to: "state.storage_backend".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn current_config_is_not_migrated() {
+        let config: ZebradConfig = toml::from_str(
+            r#"
+            config_version = 1
+
+            [state]
+            storage_backend = "memory"
+            "#,
+        )
+        .expect("current config is accepted");
+        assert!(config.migrations.is_empty());
+
+        // Old field names are only migrated in old configs
+        assert!(toml::from_str::<ZebradConfig>(
+            r#"
+            config_version = 1
+
+            [state]
+            backend = "memory"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn conflicting_and_newer_configs_are_rejected() {
+        assert!(toml::from_str::<ZebradConfig>(
+            r#"
+            [state]
+            backend = "memory"
+            storage_backend = "sled"
+            "#,
+        )
+        .is_err());
+
+        let newer = format!("config_version = {}", CURRENT_CONFIG_VERSION + 1);
+        assert!(toml::from_str::<ZebradConfig>(&newer).is_err());
+    }
+}
@@ -32,3 +32,4 @@ pub mod application;
 pub mod commands;
 pub mod config;
 pub mod prelude;
+pub mod release;
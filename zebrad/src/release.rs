@@ -0,0 +1,122 @@
+//! Build provenance and release channels.
+//!
+//! The build script embeds the git commit, the dirty flag, the build profile,
+//! and the enabled features in the binary, so bug reports and monitoring can
+//! identify exactly which code a node is running.
+//!
+//! Operators choose a [`ReleaseChannel`] in the config. Testing builds are
+//! for testnet and pre-release evaluation, so they warn when they are used on
+//! mainnet.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zebra_chain::Network;
+
+/// The release channel of a `zebrad` build.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseChannel {
+    /// Supported releases, for use on any network.
+    Stable,
+    /// Pre-release builds, which aren't supported on mainnet.
+    Testing,
+}
+
+impl Default for ReleaseChannel {
+    fn default() -> Self {
+        ReleaseChannel::Stable
+    }
+}
+
+impl ReleaseChannel {
+    /// Returns true if builds on this channel are supported on `network`.
+    pub fn supports(self, network: Network) -> bool {
+        match (self, network) {
+            (ReleaseChannel::Testing, Network::Mainnet) => false,
+            (ReleaseChannel::Stable, _) | (ReleaseChannel::Testing, Network::Testnet) => true,
+        }
+    }
+}
+
+/// The provenance of this `zebrad` build.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct BuildInfo {
+    /// The crate version.
+    pub version: &'static str,
+    /// The git commit the binary was built from, if it is known.
+    pub git_commit: Option<&'static str>,
+    /// Did the working tree have uncommitted changes, if it is known?
+    pub git_dirty: Option<bool>,
+    /// The cargo build profile, like `"release"` or `"debug"`.
+    pub profile: &'static str,
+    /// The enabled cargo features, in name order.
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// Returns the provenance of this build, embedded by the build script.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: Some(env!("ZEBRAD_GIT_COMMIT")).filter(|commit| !commit.is_empty()),
+            git_dirty: env!("ZEBRAD_GIT_DIRTY").parse().ok(),
+            profile: env!("ZEBRAD_BUILD_PROFILE"),
+            features: env!("ZEBRAD_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        match (self.git_commit, self.git_dirty) {
+            (Some(commit), Some(true)) => writeln!(f, "commit: {} (dirty)", commit)?,
+            (Some(commit), _) => writeln!(f, "commit: {}", commit)?,
+            (None, _) => writeln!(f, "commit: unknown")?,
+        }
+        writeln!(f, "profile: {}", self.profile)?;
+        write!(f, "features: {}", self.features.join(", "))
+    }
+}
+
+/// Log the provenance of this build, and warn if `channel` isn't supported
+/// on `network`.
+pub fn check_release_channel(channel: ReleaseChannel, network: Network) {
+    let build = BuildInfo::current();
+    info!(
+        version = build.version,
+        commit = ?build.git_commit,
+        dirty = ?build.git_dirty,
+        profile = build.profile,
+        ?channel,
+        "zebrad build"
+    );
+
+    if !channel.supports(network) {
+        warn!(
+            ?channel,
+            ?network,
+            "this zebrad build is on the testing release channel, which isn't supported on \
+             mainnet. Use a stable release, or set `release_channel = \"stable\"` if this is a \
+             stable build"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn testing_builds_are_unsupported_on_mainnet() {
+        assert!(ReleaseChannel::Stable.supports(Network::Mainnet));
+        assert!(ReleaseChannel::Testing.supports(Network::Testnet));
+        assert!(!ReleaseChannel::Testing.supports(Network::Mainnet));
+
+        let channel: ReleaseChannel = serde_json::from_str("\"testing\"").unwrap();
+        assert_eq!(channel, ReleaseChannel::Testing);
+    }
+}